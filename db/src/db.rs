@@ -32,6 +32,63 @@ pub fn upper(mut key: Vec<u8>) -> Option<Vec<u8>> {
 const MAX_TAG_VALUE_SIZE: usize = 255;
 const DB_VERSION: &str = "3";
 
+/// Named databases (trees) of the event store
+pub const TREES: u32 = 15;
+
+/// When LMDB flushes commits to disk
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SyncMode {
+    /// Sync data and meta pages on every commit
+    #[default]
+    Full,
+    /// Skip the meta page sync, a system crash may lose the last commit
+    NoMetaSync,
+    /// Leave flushing to the OS, a system crash may lose recent commits
+    NoSync,
+}
+
+impl SyncMode {
+    fn flags(self) -> u32 {
+        match self {
+            SyncMode::Full => 0,
+            SyncMode::NoMetaSync => ffi::MDB_NOMETASYNC,
+            SyncMode::NoSync => ffi::MDB_NOSYNC,
+        }
+    }
+}
+
+/// LMDB environment options
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Options {
+    /// Largest size the database can grow to, writes fail with `MDB_MAP_FULL` beyond it
+    pub map_size: usize,
+    /// Concurrent read transactions
+    pub max_readers: u32,
+    /// Named databases, at least [`TREES`]
+    pub max_dbs: u32,
+    pub sync: SyncMode,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            map_size: 1_000_000_000_000,
+            max_readers: 100,
+            max_dbs: 20,
+            sync: SyncMode::Full,
+        }
+    }
+}
+
+/// Map usage, free pages and entries per tree of the event store
+#[derive(Debug, Clone)]
+pub struct StorageStats {
+    pub info: EnvInfo,
+    pub free_pages: usize,
+    pub trees: Vec<(&'static str, TreeStat)>,
+}
+
 #[derive(Clone)]
 pub struct Db {
     inner: Lmdb,
@@ -329,12 +386,32 @@ pub enum CheckEventResult {
     Ok(usize),
 }
 
+/// Outcome counters of [`Db::batch_put_stats`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BatchPutStats {
+    /// Newly stored events
+    pub inserted: usize,
+    /// Events already in the db or repeated in the input
+    pub duplicate: usize,
+    /// Events rejected as invalid
+    pub invalid: usize,
+    /// Deleted events and outdated replaceable events
+    pub ignored: usize,
+}
+
 impl Db {
     pub fn flush(&self) -> Result<()> {
         self.inner.flush()?;
         Ok(())
     }
 
+    /// Consistent copy of the database into the directory `path` while it stays writable,
+    /// `compact` leaves out free pages
+    pub fn copy_to<P: AsRef<Path>>(&self, path: P, compact: bool) -> Result<()> {
+        self.inner.copy_to(path, compact)?;
+        Ok(())
+    }
+
     /// check db version, return [`Error::VersionMismatch`] when db schema changed
     pub fn check_schema(&self) -> Result<()> {
         let mut writer = self.inner.writer()?;
@@ -351,7 +428,17 @@ impl Db {
     }
 
     pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
-        let inner = Lmdb::open_with(path, Some(20), Some(100), Some(1_000_000_000_000), 0)?;
+        Self::open_with(path, &Options::default())
+    }
+
+    pub fn open_with<P: AsRef<Path>>(path: P, options: &Options) -> Result<Self> {
+        let inner = Lmdb::open_with(
+            path,
+            Some(options.max_dbs.max(TREES)),
+            Some(options.max_readers),
+            Some(options.map_size),
+            options.sync.flags(),
+        )?;
 
         let default_opts = 0;
         // let integer_default_opts = ffi::MDB_INTEGERKEY;
@@ -398,6 +485,40 @@ impl Db {
         Ok(self.inner.reader()?)
     }
 
+    /// Map and reader usage
+    pub fn info(&self) -> Result<EnvInfo> {
+        Ok(self.inner.info()?)
+    }
+
+    /// Map usage, free pages and entries per tree
+    pub fn storage_stats(&self) -> Result<StorageStats> {
+        let reader = self.reader()?;
+        let trees = [
+            ("data", &self.t_data),
+            ("index", &self.t_index),
+            ("id_uid", &self.t_id_uid),
+            ("uid_word", &self.t_uid_word),
+            ("id", &self.t_id),
+            ("pubkey", &self.t_pubkey),
+            ("kind", &self.t_kind),
+            ("pubkey_kind", &self.t_pubkey_kind),
+            ("created_at", &self.t_created_at),
+            ("tag", &self.t_tag),
+            ("deletion", &self.t_deletion),
+            ("replacement", &self.t_replacement),
+            ("expiration", &self.t_expiration),
+            ("word", &self.t_word),
+        ]
+        .into_iter()
+        .map(|(name, tree)| Ok((name, reader.stat(tree)?)))
+        .collect::<Result<Vec<_>>>()?;
+        Ok(StorageStats {
+            info: self.info()?,
+            free_pages: reader.free_pages()?,
+            trees,
+        })
+    }
+
     pub fn commit<T: Transaction>(&self, txn: T) -> Result<()> {
         Ok(txn.commit()?)
     }
@@ -553,6 +674,35 @@ impl Db {
         Ok(count)
     }
 
+    /// Batch put events and report how many were inserted, duplicate, invalid or ignored
+    pub fn batch_put_stats<II, N>(&self, events: II) -> Result<BatchPutStats>
+    where
+        II: IntoIterator<Item = N>,
+        N: AsRef<Event>,
+    {
+        let mut writer = self.inner.writer()?;
+        let mut events = events.into_iter().collect::<Vec<N>>();
+        events.sort_by(|a, b| a.as_ref().id().cmp(b.as_ref().id()));
+        let mut stats = BatchPutStats::default();
+
+        for (i, event) in events.iter().enumerate() {
+            let event = event.as_ref();
+            if i != 0 && event.id() == events[i - 1].as_ref().id() {
+                stats.duplicate += 1;
+                continue;
+            }
+            match self.put(&mut writer, event)? {
+                CheckEventResult::Ok(_) => stats.inserted += 1,
+                CheckEventResult::Duplicate => stats.duplicate += 1,
+                CheckEventResult::Invald(_) => stats.invalid += 1,
+                CheckEventResult::Deleted | CheckEventResult::ReplaceIgnored => stats.ignored += 1,
+            }
+        }
+
+        writer.commit()?;
+        Ok(stats)
+    }
+
     pub fn batch_get<R: FromEventData, II, N>(&self, event_ids: II) -> Result<Vec<R>>
     where
         II: IntoIterator<Item = N>,
@@ -727,6 +877,8 @@ where
     _r: PhantomData<J>,
     // need get index data for filter
     match_index: MatchIndex,
+    // skip events expired before this time
+    expired_before: Option<u64>,
 }
 
 fn create_iter<'a, R: Transaction>(
@@ -768,6 +920,7 @@ where
             // checker: None,
             _r: PhantomData,
             match_index,
+            expired_before: None,
         })
     }
 
@@ -1005,10 +1158,24 @@ where
         }
     }
 
+    fn need_index(&self) -> bool {
+        !matches!(self.match_index, MatchIndex::None) || self.expired_before.is_some()
+    }
+
+    fn index_match(&self, event: &ArchivedEventIndex) -> bool {
+        if let Some(now) = self.expired_before {
+            if event.is_expired(now) {
+                return false;
+            }
+        }
+        matches!(self.match_index, MatchIndex::None)
+            || self.match_index.r#match(&self.filter, event)
+    }
+
     fn next_inner(&mut self) -> Result<Option<J>, Error> {
         while let Some(item) = self.group.next() {
             let key = item?;
-            if matches!(self.match_index, MatchIndex::None) {
+            if !self.need_index() {
                 self.get_data += 1;
                 if let Some(event) = self.document(&key)? {
                     return Ok(Some(event));
@@ -1018,7 +1185,7 @@ where
                 let event = decode_event_index(data)?;
                 self.get_index += 1;
                 if let Some(event) = event {
-                    if self.match_index.r#match(&self.filter, event) {
+                    if self.index_match(event) {
                         self.get_data += 1;
                         if let Some(event) = self.document(&key)? {
                             return Ok(Some(event));
@@ -1052,6 +1219,11 @@ where
         }));
     }
 
+    /// Skip events with a NIP-40 expiration before `now`
+    pub fn skip_expired(&mut self, now: u64) {
+        self.expired_before = Some(now);
+    }
+
     /// The stats after scan
     pub fn stats(&self) -> Stats {
         Stats {
@@ -1066,7 +1238,7 @@ where
         let mut len = 0;
         while let Some(item) = self.group.next() {
             let key = item?;
-            if matches!(self.match_index, MatchIndex::None) {
+            if !self.need_index() {
                 len += 1;
                 if self.limit(len) {
                     break;
@@ -1076,7 +1248,7 @@ where
                 let event = decode_event_index(data)?;
                 self.get_index += 1;
                 if let Some(event) = event {
-                    if self.match_index.r#match(&self.filter, event) {
+                    if self.index_match(event) {
                         len += 1;
                         if self.limit(len) {
                             break;
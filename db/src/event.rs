@@ -507,6 +507,28 @@ impl Event {
         )
     }
 
+    /// nip-13 difficulty, the number of leading zero bits of the id, 255 at most
+    pub fn pow_difficulty(&self) -> u8 {
+        let mut bits: u32 = 0;
+        for byte in self.id() {
+            if *byte == 0 {
+                bits += 8;
+            } else {
+                bits += byte.leading_zeros();
+                break;
+            }
+        }
+        bits.min(u8::MAX as u32) as u8
+    }
+
+    /// nip-13 target difficulty committed in the nonce tag
+    pub fn pow_target(&self) -> Option<u8> {
+        self.tags()
+            .iter()
+            .find(|tag| tag.len() >= 3 && tag[0] == "nonce")
+            .and_then(|tag| tag[2].parse().ok())
+    }
+
     pub fn verify_id(&self) -> Result<(), Error> {
         if &self.hash() == self.id() {
             Ok(())
@@ -557,17 +579,54 @@ impl Event {
     }
 
     pub fn validate(&self, now: u64, older: u64, newer: u64) -> Result<(), Error> {
+        self.validate_unsigned(now, older, newer)?;
+        self.verify_sign()
+    }
+
+    /// validate everything but the signature, left to [`verify_batch`] or a verification worker
+    pub fn validate_unsigned(&self, now: u64, older: u64, newer: u64) -> Result<(), Error> {
         if self.index.is_expired(now) {
             return Err(Error::Invalid("event is expired".to_owned()));
         }
         self.verify_time(now, older, newer)?;
         self.verify_id()?;
-        self.verify_sign()?;
         self.verify_delegation()?;
         Ok(())
     }
 }
 
+/// Batches smaller than this are verified on the calling thread
+const MIN_PARALLEL_BATCH: usize = 64;
+
+/// Verify the ids and signatures of a batch of events, results are in the order of the events
+///
+/// secp256k1 has no batch schnorr verification, the batch is split in chunks
+/// verified on scoped threads sharing the global context.
+pub fn verify_batch(events: &[Event]) -> Vec<Result<(), Error>> {
+    fn verify(events: &[Event]) -> Vec<Result<(), Error>> {
+        events
+            .iter()
+            .map(|event| event.verify_id().and_then(|_| event.verify_sign()))
+            .collect()
+    }
+
+    let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+    if threads == 1 || events.len() < MIN_PARALLEL_BATCH {
+        return verify(events);
+    }
+    let chunk = events.len().div_ceil(threads);
+    std::thread::scope(|scope| {
+        let handles = events
+            .chunks(chunk)
+            .map(|chunk| scope.spawn(move || verify(chunk)))
+            .collect::<Vec<_>>();
+        handles
+            .into_iter()
+            .flat_map(|handle| handle.join().expect("verification thread panicked"))
+            .collect()
+    })
+}
+
 fn verify_delegation(
     event: &Event,
     delegator: &String,
@@ -633,6 +692,33 @@ mod tests {
     use serde_json::Value;
     use std::str::FromStr;
 
+    #[test]
+    fn pow_difficulty() -> Result<()> {
+        // nip-13 example
+        let note = r#"
+        {
+            "id": "000006d8c378af1779d2feebc7603a125d99eca0ccf1085959b307f64e5dd358",
+            "pubkey": "a48380f4cfcc1ad5378294fcac36439770f9c878dd880ffa94bb74ea54a6f243",
+            "created_at": 1651794653,
+            "kind": 1,
+            "tags": [["nonce", "776797", "20"]],
+            "content": "It's just me mining my own business",
+            "sig": "284622fc0a3f4f1303455d5175f7ba962a3300d136085b9566801bc2e0699de0c7e31e44c81fb40ad9049173742e904713c3594a1da0fc5d2382a25c11aba977"
+          }
+        "#;
+        let event = Event::from_str(note)?;
+        assert_eq!(event.pow_difficulty(), 21);
+        assert_eq!(event.pow_target(), Some(20));
+
+        // an all zero id has 256 leading zero bits
+        let zero = note.replace(
+            "000006d8c378af1779d2feebc7603a125d99eca0ccf1085959b307f64e5dd358",
+            &"00".repeat(32),
+        );
+        assert_eq!(Event::from_str(&zero)?.pow_difficulty(), 255);
+        Ok(())
+    }
+
     #[test]
     fn index_event() -> Result<()> {
         let note = r#"
@@ -834,4 +920,26 @@ mod tests {
         assert!(event.verify_id().is_ok());
         Ok(())
     }
+
+    #[test]
+    fn batch_verify() -> Result<()> {
+        let mut rng = thread_rng();
+        let key_pair = Keypair::new_global(&mut rng);
+        let mut events = (0..200)
+            .map(|i| Event::create(&key_pair, i, 1, vec![], i.to_string()))
+            .collect::<Result<Vec<_>, _>>()?;
+        events[150].sig[0] ^= 1;
+        events[3].content = "tampered".to_owned();
+        let results = verify_batch(&events);
+        assert_eq!(results.len(), 200);
+        let failed = results
+            .iter()
+            .enumerate()
+            .filter(|(_, r)| r.is_err())
+            .map(|(i, _)| i)
+            .collect::<Vec<_>>();
+        assert_eq!(failed, vec![3, 150]);
+        assert!(verify_batch(&events[..2]).iter().all(|r| r.is_ok()));
+        Ok(())
+    }
 }
@@ -8,8 +8,10 @@ mod key;
 pub use secp256k1;
 
 pub use {
-    db::CheckEventResult, db::Db, db::Iter, error::Error, event::now, event::ArchivedEventIndex,
-    event::Event, event::EventIndex, event::FromEventData, filter::Filter, filter::SortList,
+    db::BatchPutStats, db::CheckEventResult, db::Db, db::Iter, db::Options, db::StorageStats,
+    db::SyncMode, db::TREES, error::Error, event::now, event::verify_batch,
+    event::ArchivedEventIndex, event::Event, event::EventIndex, event::FromEventData,
+    filter::Filter, filter::SortList,
 };
 
 pub use nostr_kv as kv;
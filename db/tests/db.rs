@@ -121,6 +121,81 @@ pub fn test_events() -> Result<()> {
     Ok(())
 }
 
+#[test]
+pub fn test_batch_put_stats() -> Result<()> {
+    let db = create_db("test_batch_put_stats")?;
+    let prefix = 0;
+    let events: Vec<Event> = vec![
+        MyEvent {
+            id: id(prefix, 1),
+            pubkey: author(1),
+            kind: 1000,
+            ..Default::default()
+        }
+        .into(),
+        MyEvent {
+            id: id(prefix, 2),
+            pubkey: author(1),
+            kind: 1000,
+            ..Default::default()
+        }
+        .into(),
+    ];
+    let stats = db.batch_put_stats(&events)?;
+    assert_eq!(stats.inserted, 2);
+    assert_eq!(stats.duplicate, 0);
+
+    let mut again = events.clone();
+    again.push(
+        MyEvent {
+            id: id(prefix, 3),
+            pubkey: author(1),
+            kind: 1000,
+            ..Default::default()
+        }
+        .into(),
+    );
+    again.push(again[2].clone());
+    let stats = db.batch_put_stats(&again)?;
+    assert_eq!(stats.inserted, 1);
+    assert_eq!(stats.duplicate, 3);
+    assert_eq!(stats.invalid, 0);
+    assert_eq!(stats.ignored, 0);
+    Ok(())
+}
+
+#[test]
+pub fn test_storage_stats() -> Result<()> {
+    let db = create_db("test_storage_stats")?;
+    let events: Vec<Event> = (1..=3)
+        .map(|i| {
+            MyEvent {
+                id: id(0, i),
+                pubkey: author(1),
+                kind: 1000,
+                ..Default::default()
+            }
+            .into()
+        })
+        .collect();
+    db.batch_put(&events)?;
+    let stats = db.storage_stats()?;
+    let entries = |name: &str| {
+        stats
+            .trees
+            .iter()
+            .find(|(n, _)| *n == name)
+            .unwrap()
+            .1
+            .entries
+    };
+    assert_eq!(entries("data"), 3);
+    assert_eq!(entries("kind"), 3);
+    assert_eq!(entries("tag"), 0);
+    assert!(stats.info.used_size > 0);
+    Ok(())
+}
+
 #[test]
 pub fn test_events_unexpected() -> Result<()> {
     let db = create_db("test_events_unexpected")?;
@@ -206,6 +281,49 @@ pub fn test_events_expiration() -> Result<()> {
     Ok(())
 }
 
+#[test]
+pub fn test_query_skip_expired() -> Result<()> {
+    let db = create_db("test_query_skip_expired")?;
+    let prefix = 0;
+    let events: Vec<Event> = vec![
+        MyEvent {
+            id: id(prefix, 1),
+            pubkey: author(1),
+            kind: 1000,
+            tags: vec![vec!["expiration".to_owned(), "10".to_owned()]],
+            ..Default::default()
+        }
+        .into(),
+        MyEvent {
+            id: id(prefix, 2),
+            pubkey: author(1),
+            kind: 1000,
+            ..Default::default()
+        }
+        .into(),
+    ];
+    db.batch_put(&events)?;
+
+    let filter = Filter::default();
+    let reader = db.reader()?;
+    assert_eq!(db.iter::<Event, _>(&reader, &filter)?.count(), 2);
+
+    let mut iter = db.iter::<Event, _>(&reader, &filter)?;
+    iter.skip_expired(20);
+    let ids = iter
+        .map(|e| e.map(|e| *e.id()))
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(ids, vec![id(prefix, 2)]);
+
+    let mut iter = db.iter::<Event, _>(&reader, &filter)?;
+    iter.skip_expired(20);
+    assert_eq!(iter.size()?.0, 1);
+
+    let iter = db.iter_expiration::<Event, _>(&reader, Some(20))?;
+    assert_eq!(iter.count(), 1);
+    Ok(())
+}
+
 #[test]
 pub fn test_events_replace() -> Result<()> {
     let db = create_db("test_events_replace")?;
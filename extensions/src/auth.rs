@@ -1,13 +1,15 @@
 use metrics::{counter, describe_counter};
-use nostr_relay::db::now;
 use nostr_relay::{
-    message::{ClientMessage, IncomingMessage, OutgoingMessage},
+    message::{ClientMessage, IncomingMessage, OutgoingMessage, Prefix},
     setting::SettingWrapper,
     Extension, ExtensionMessageResult, List, Session,
 };
 use serde::Deserialize;
 use uuid::Uuid;
 
+/// Permission error of unauthenticated sessions
+const AUTH_REQUIRED: &str = "NIP-42 auth required";
+
 #[derive(Deserialize, Default, Debug)]
 #[serde(default)]
 pub struct Permission {
@@ -35,26 +37,6 @@ pub struct Auth {
     setting: AuthSetting,
 }
 
-pub enum AuthState {
-    /// The AUTH challenge
-    Challenge(String),
-    /// Authenticated with pubkey
-    Pubkey(String),
-}
-
-impl AuthState {
-    pub fn authed(&self) -> bool {
-        matches!(self, Self::Pubkey(_))
-    }
-
-    pub fn pubkey(&self) -> Option<&String> {
-        match self {
-            Self::Pubkey(p) => Some(p),
-            Self::Challenge(_) => None,
-        }
-    }
-}
-
 impl Auth {
     pub fn new() -> Self {
         describe_counter!(
@@ -115,7 +97,7 @@ impl Auth {
                         return Err("pubkey not in whitelist");
                     }
                 } else {
-                    return Err("NIP-42 auth required");
+                    return Err(AUTH_REQUIRED);
                 }
             }
             if let Some(list) = &permission.pubkey_blacklist {
@@ -124,12 +106,21 @@ impl Auth {
                         return Err("pubkey in blacklist");
                     }
                 } else {
-                    return Err("NIP-42 auth required");
+                    return Err(AUTH_REQUIRED);
                 }
             }
         }
         Ok(())
     }
+
+    /// `auth-required` if authenticating may help, `restricted` otherwise
+    pub fn prefix(err: &str) -> Prefix {
+        if err == AUTH_REQUIRED {
+            Prefix::AuthRequired
+        } else {
+            Prefix::Restricted
+        }
+    }
 }
 
 impl Extension for Auth {
@@ -147,10 +138,7 @@ impl Extension for Auth {
 
     fn connected(&self, session: &mut Session, ctx: &mut <Session as actix::Actor>::Context) {
         if self.setting.enabled {
-            let uuid = Uuid::new_v4().to_string();
-            let state = AuthState::Challenge(uuid.clone());
-            session.set(state);
-            ctx.text(format!(r#"["AUTH", "{uuid}"]"#));
+            session.send_auth_challenge(Uuid::new_v4().to_string(), ctx);
         }
     }
 
@@ -163,67 +151,52 @@ impl Extension for Auth {
         let mut msg = msg;
 
         if self.setting.enabled {
-            let state = session.get::<AuthState>();
             msg.nip70_checked = true;
+            // remote events were authorized on the instance their client is connected to
+            if session.is_remote() {
+                return ExtensionMessageResult::Continue(msg);
+            }
             match &msg.msg {
                 IncomingMessage::Auth(event) => {
-                    if let Some(AuthState::Challenge(challenge)) = state {
-                        if let Err(err) = event.validate(now(), 0, 0) {
-                            return OutgoingMessage::ok(
-                                &event.id_str(),
-                                false,
-                                &format!("auth-required: {}", err),
-                            )
-                            .into();
-                        } else if event.kind() == 22242 {
-                            for tag in event.tags() {
-                                if tag.len() > 1 && tag[0] == "challenge" && &tag[1] == challenge {
-                                    session.set(AuthState::Pubkey(event.pubkey_str()));
-                                    return OutgoingMessage::ok(&event.id_str(), true, "").into();
-                                }
-                            }
-                        }
+                    return match session.authenticate(event) {
+                        Ok(_) => OutgoingMessage::ok(&event.id_str(), true, ""),
+                        Err(err) => OutgoingMessage::rejected(
+                            &event.id_str(),
+                            Prefix::AuthRequired,
+                            &err.to_string(),
+                        ),
                     }
-                    return OutgoingMessage::ok(
-                        &event.id_str(),
-                        false,
-                        "auth-required: need reconnect",
-                    )
                     .into();
                 }
                 IncomingMessage::Event(event) => {
                     if let Err(err) = Self::verify_permission(
                         self.setting.event.as_ref(),
-                        state.and_then(|s| s.pubkey()),
+                        msg.auth_pubkey.as_ref(),
                         Some(&event.pubkey_str()),
                         Some(event.tags()),
                         session.ip(),
                     ) {
                         counter!("nostr_relay_auth_unauthorized", "command" => "EVENT", "reason" => err).increment(1);
-                        return OutgoingMessage::ok(
-                            &event.id_str(),
-                            false,
-                            &format!("auth-required: {}", err),
-                        )
-                        .into();
+                        return OutgoingMessage::rejected(&event.id_str(), Self::prefix(err), err)
+                            .into();
                     } else {
                         // check nip70 protected event
                         for tag in event.tags() {
                             if tag.len() == 1 && tag[0] == "-" {
-                                if let Some(AuthState::Pubkey(pubkey)) = state {
+                                if let Some(pubkey) = &msg.auth_pubkey {
                                     if pubkey != &event.pubkey_str() {
-                                        return OutgoingMessage::ok(
+                                        return OutgoingMessage::rejected(
                                             &event.id_str(),
-                                            false,
-                                            "auth-required: this event may only be published by its author",
+                                            Prefix::Restricted,
+                                            "this event may only be published by its author",
                                         )
                                         .into();
                                     }
                                 } else {
-                                    return OutgoingMessage::ok(
+                                    return OutgoingMessage::rejected(
                                         &event.id_str(),
-                                        false,
-                                        "auth-required: this event require authorization",
+                                        Prefix::AuthRequired,
+                                        "this event require authorization",
                                     )
                                     .into();
                                 }
@@ -235,14 +208,13 @@ impl Extension for Auth {
                 IncomingMessage::Req(sub) | IncomingMessage::Count(sub) => {
                     if let Err(err) = Self::verify_permission(
                         self.setting.req.as_ref(),
-                        state.and_then(|s| s.pubkey()),
+                        msg.auth_pubkey.as_ref(),
                         None,
                         None,
                         session.ip(),
                     ) {
                         counter!("nostr_relay_auth_unauthorized", "command" => "REQ", "reason" => err).increment(1);
-                        let msg = format!("auth-required: {}", err);
-                        return OutgoingMessage::closed(&sub.id, &msg).into();
+                        return OutgoingMessage::refused(&sub.id, Self::prefix(err), err).into();
                     }
                 }
                 _ => {}
@@ -262,6 +234,7 @@ mod tests {
     use futures_util::{SinkExt as _, StreamExt as _};
     use nostr_relay::create_web_app;
     use nostr_relay::db::{
+        now,
         secp256k1::{rand::thread_rng, Keypair, XOnlyPublicKey},
         Event,
     };
@@ -473,6 +446,23 @@ mod tests {
         let notice: (String, String, bool, String) = parse_text(&framed.next().await.unwrap()?)?;
         assert!(notice.3.contains("need"));
 
+        // stale auth event
+        let event = Event::create(
+            &key_pair,
+            now() - 3600,
+            22242,
+            vec![vec!["challenge".to_owned(), state.1.clone()]],
+            "".to_owned(),
+        )?;
+        framed
+            .send(ws::Message::Text(
+                format!(r#"["AUTH", {}]"#, event.to_string()).into(),
+            ))
+            .await?;
+        let notice: (String, String, bool, String) = parse_text(&framed.next().await.unwrap()?)?;
+        assert!(!notice.2);
+        assert!(notice.3.contains("created_at"));
+
         let event = Event::create(
             &key_pair,
             now(),
@@ -541,7 +531,7 @@ mod tests {
 
         let notice: (String, String, String) = parse_text(&framed.next().await.unwrap()?)?;
         assert_eq!(notice.0, "CLOSED");
-        assert!(notice.2.contains("auth-required"));
+        assert!(notice.2.starts_with("auth-required:"));
 
         let event = Event::create(
             &key_pair,
@@ -606,7 +596,7 @@ mod tests {
             ))
             .await?;
         let notice: (String, String, bool, String) = parse_text(&framed.next().await.unwrap()?)?;
-        assert!(notice.3.contains("auth-required"));
+        assert!(notice.3.starts_with("restricted:"));
         assert!(!notice.2);
 
         framed
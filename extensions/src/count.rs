@@ -1,8 +1,8 @@
 use metrics::{describe_histogram, histogram};
 use nostr_relay::{
-    db::{Db, Filter},
+    db::{now, Db, Filter},
     duration::NonZeroDuration,
-    message::{ClientMessage, IncomingMessage, OutgoingMessage},
+    message::{ClientMessage, IncomingMessage, OutgoingMessage, Prefix},
     setting::SettingWrapper,
     Error, Extension, ExtensionMessageResult, Session,
 };
@@ -33,6 +33,7 @@ impl Count {
         let reader = self.db.reader()?;
         let start = Instant::now();
         let mut iter = self.db.iter::<String, _>(&reader, filter)?;
+        iter.skip_expired(now());
         if let Some(time) = timeout {
             iter.scan_time(time.into(), 2000);
         }
@@ -67,14 +68,14 @@ impl Extension for Count {
                     let timeout = session.app.setting.read().data.db_query_timeout;
                     match self.count(&sub.filters[0], timeout) {
                         Ok(size) => {
-                            return ExtensionMessageResult::Stop(OutgoingMessage(format!(
-                                r#"["COUNT","{}",{{"count": {}}}]"#,
-                                sub.id, size
-                            )))
+                            return ExtensionMessageResult::Stop(OutgoingMessage::count(
+                                &sub.id, size,
+                            ))
                         }
                         Err(err) => {
-                            return ExtensionMessageResult::Stop(OutgoingMessage::closed(
+                            return ExtensionMessageResult::Stop(OutgoingMessage::refused(
                                 &sub.id,
+                                Prefix::Error,
                                 &format!("count event error: {}", err),
                             ))
                         }
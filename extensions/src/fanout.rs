@@ -0,0 +1,392 @@
+//! Cross-instance event fanout over Google Cloud Pub/Sub
+//!
+//! With several relay instances behind a load balancer, an event accepted on one
+//! instance must reach subscribers connected to the others. Every instance
+//! publishes accepted events to a shared topic and pulls from its own
+//! subscription. Remote events go through the extension message methods like
+//! client events, then are stored locally so they are dispatched to local
+//! subscribers. Messages carry the origin instance id so an instance skips its own,
+//! and the NIP-42 pubkey of the sending client for the extensions of the others.
+
+use actix::prelude::*;
+use actix_web::web;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use metrics::{counter, describe_counter};
+use nostr_relay::{
+    db::{now, Event},
+    message::{Accepted, RemoteEvent, SetFanout},
+    App, Session,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+const PUBSUB_URL: &str = "https://pubsub.googleapis.com/v1";
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct FanoutSetting {
+    pub enabled: bool,
+    /// defaults to GOOGLE_CLOUD_PROJECT
+    pub project_id: Option<String>,
+    pub topic: String,
+    /// per-instance subscriptions are named `{subscription_prefix}-{instance id}`
+    pub subscription_prefix: String,
+    /// kinds published to other instances, empty publishes all
+    pub kinds: Vec<u16>,
+    /// idle per-instance subscriptions are deleted by Pub/Sub after this time
+    #[serde(deserialize_with = "nostr_relay::duration::deserialize")]
+    pub subscription_ttl: Duration,
+    /// delay after a failed pull
+    #[serde(deserialize_with = "nostr_relay::duration::deserialize")]
+    pub retry_interval: Duration,
+    pub max_messages: u32,
+}
+
+impl Default for FanoutSetting {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            project_id: None,
+            topic: "nostr-events".to_string(),
+            subscription_prefix: "nostr-events".to_string(),
+            kinds: vec![],
+            subscription_ttl: Duration::from_secs(86_400),
+            retry_interval: Duration::from_secs(5),
+            max_messages: 100,
+        }
+    }
+}
+
+impl FanoutSetting {
+    /// Configured project id, falls back to the environment
+    fn project_id(&self) -> Option<String> {
+        self.project_id
+            .clone()
+            .or_else(|| std::env::var("GOOGLE_CLOUD_PROJECT").ok())
+            .or_else(|| std::env::var("GCP_PROJECT").ok())
+    }
+}
+
+/// Pub/Sub REST client authenticated with the Cloud Run metadata server,
+/// or unauthenticated against the emulator when PUBSUB_EMULATOR_HOST is set
+#[derive(Clone)]
+struct PubSubClient {
+    http: reqwest::Client,
+    base_url: String,
+    emulator: bool,
+}
+
+impl PubSubClient {
+    fn new() -> Self {
+        let emulator = std::env::var("PUBSUB_EMULATOR_HOST").ok();
+        Self {
+            http: reqwest::Client::new(),
+            base_url: emulator
+                .as_ref()
+                .map(|host| format!("http://{}/v1", host))
+                .unwrap_or_else(|| PUBSUB_URL.to_string()),
+            emulator: emulator.is_some(),
+        }
+    }
+
+    async fn access_token(&self) -> anyhow::Result<Option<String>> {
+        if self.emulator {
+            return Ok(None);
+        }
+        Ok(Some(crate::gcp::access_token().await?))
+    }
+
+    async fn request(
+        &self,
+        method: reqwest::Method,
+        path: &str,
+        body: Value,
+    ) -> anyhow::Result<reqwest::Response> {
+        let mut req = self
+            .http
+            .request(method, format!("{}/{}", self.base_url, path));
+        if !body.is_null() {
+            req = req.json(&body);
+        }
+        if let Some(token) = self.access_token().await? {
+            req = req.bearer_auth(token);
+        }
+        Ok(req.send().await?)
+    }
+}
+
+/// Encode an event and the auth of its sender as a Pub/Sub message
+fn encode(event: &Event, auth_pubkey: Option<&String>, origin: &str) -> Value {
+    let mut attributes = json!({ "origin": origin });
+    if let Some(auth) = auth_pubkey {
+        attributes["auth"] = json!(auth);
+    }
+    json!({
+        "data": STANDARD.encode(event.to_string()),
+        "attributes": attributes,
+    })
+}
+
+/// Decode the events of a pull response, returns the ack ids and the events of other
+/// instances with the auth of their senders
+fn decode(res: &Value, origin: &str) -> (Vec<String>, Vec<(Event, Option<String>)>) {
+    let mut ack_ids = vec![];
+    let mut events = vec![];
+    let received = res.get("receivedMessages").and_then(|v| v.as_array());
+    for msg in received.into_iter().flatten() {
+        if let Some(ack_id) = msg.get("ackId").and_then(|v| v.as_str()) {
+            ack_ids.push(ack_id.to_string());
+        }
+        let Some(message) = msg.get("message") else {
+            continue;
+        };
+        if message
+            .pointer("/attributes/origin")
+            .and_then(|v| v.as_str())
+            == Some(origin)
+        {
+            continue;
+        }
+        let event = message
+            .get("data")
+            .and_then(|v| v.as_str())
+            .and_then(|data| STANDARD.decode(data).ok())
+            .and_then(|data| serde_json::from_slice::<Event>(&data).ok());
+        let auth = message
+            .pointer("/attributes/auth")
+            .and_then(|v| v.as_str())
+            .map(ToOwned::to_owned);
+        match event {
+            Some(event) => events.push((event, auth)),
+            None => {
+                counter!("nostr_relay_fanout_events", "result" => "invalid").increment(1);
+            }
+        }
+    }
+    (ack_ids, events)
+}
+
+/// Fanout bus actor, receives accepted events from the server
+pub struct Fanout {
+    setting: FanoutSetting,
+    project_id: String,
+    instance_id: String,
+    client: PubSubClient,
+    /// runs remote events through the extensions before they are written
+    remote: Addr<Session>,
+}
+
+impl Fanout {
+    /// Start the bus and register it with the server when enabled in `[fanout]`
+    pub fn start_with(app: &App) -> Option<Addr<Fanout>> {
+        let setting: FanoutSetting = app.setting.read().parse_extension("fanout");
+        if !setting.enabled {
+            return None;
+        }
+        let Some(project_id) = setting.project_id() else {
+            warn!("Fanout enabled without a project id, skipping");
+            return None;
+        };
+        describe_counter!(
+            "nostr_relay_fanout_events",
+            "The total count of events exchanged with other instances by result"
+        );
+        let addr = Fanout {
+            setting,
+            project_id,
+            instance_id: uuid::Uuid::new_v4().simple().to_string(),
+            client: PubSubClient::new(),
+            remote: Session::start_remote(web::Data::new(app.clone())),
+        }
+        .start();
+        app.server.do_send(SetFanout(addr.clone().recipient()));
+        Some(addr)
+    }
+
+    /// Check that the shared topic exists and this instance may use it, for the relay preflight
+    pub async fn check_topic(setting: &FanoutSetting) -> anyhow::Result<String> {
+        let project_id = setting.project_id().ok_or_else(|| {
+            anyhow::anyhow!("no project id, set fanout.project_id or GOOGLE_CLOUD_PROJECT")
+        })?;
+        let topic = format!("projects/{}/topics/{}", project_id, setting.topic);
+        let res = PubSubClient::new()
+            .request(reqwest::Method::GET, &topic, Value::Null)
+            .await?;
+        if !res.status().is_success() {
+            anyhow::bail!("{}: {}", topic, res.status());
+        }
+        Ok(topic)
+    }
+
+    fn topic(&self) -> String {
+        format!("projects/{}/topics/{}", self.project_id, self.setting.topic)
+    }
+
+    fn subscription(&self) -> String {
+        format!(
+            "projects/{}/subscriptions/{}-{}",
+            self.project_id, self.setting.subscription_prefix, self.instance_id
+        )
+    }
+
+    fn pull_loop(&self) -> impl std::future::Future<Output = ()> {
+        let client = self.client.clone();
+        let topic = self.topic();
+        let subscription = self.subscription();
+        let origin = self.instance_id.clone();
+        let remote = self.remote.clone();
+        let setting = self.setting.clone();
+        async move {
+            // create the per-instance subscription, removed by Pub/Sub once the instance is gone
+            loop {
+                let body = json!({
+                    "topic": topic,
+                    "ackDeadlineSeconds": 30,
+                    "messageRetentionDuration": "600s",
+                    "expirationPolicy": { "ttl": format!("{}s", setting.subscription_ttl.as_secs().max(86_400)) },
+                });
+                match client
+                    .request(reqwest::Method::PUT, &subscription, body)
+                    .await
+                {
+                    Ok(res)
+                        if res.status().is_success()
+                            || res.status() == reqwest::StatusCode::CONFLICT =>
+                    {
+                        break
+                    }
+                    Ok(res) => warn!(
+                        "Failed to create fanout subscription {}: {}",
+                        subscription,
+                        res.status()
+                    ),
+                    Err(e) => warn!(
+                        "Failed to create fanout subscription {}: {}",
+                        subscription, e
+                    ),
+                }
+                tokio::time::sleep(setting.retry_interval).await;
+            }
+            info!("Fanout subscribed to {} via {}", topic, subscription);
+
+            loop {
+                let res = client
+                    .request(
+                        reqwest::Method::POST,
+                        &format!("{}:pull", subscription),
+                        json!({ "maxMessages": setting.max_messages }),
+                    )
+                    .await;
+                let res = match res {
+                    Ok(res) if res.status().is_success() => res.json::<Value>().await,
+                    Ok(res) => {
+                        warn!("Fanout pull failed: {}", res.status());
+                        tokio::time::sleep(setting.retry_interval).await;
+                        continue;
+                    }
+                    Err(e) => {
+                        warn!("Fanout pull failed: {}", e);
+                        tokio::time::sleep(setting.retry_interval).await;
+                        continue;
+                    }
+                };
+                let Ok(res) = res else {
+                    continue;
+                };
+                let (ack_ids, events) = decode(&res, &origin);
+                for (event, auth_pubkey) in events {
+                    if event.validate(now(), 0, 0).is_err() {
+                        counter!("nostr_relay_fanout_events", "result" => "invalid").increment(1);
+                        continue;
+                    }
+                    counter!("nostr_relay_fanout_events", "result" => "received").increment(1);
+                    remote.do_send(RemoteEvent { event, auth_pubkey });
+                }
+                if !ack_ids.is_empty() {
+                    if let Err(e) = client
+                        .request(
+                            reqwest::Method::POST,
+                            &format!("{}:acknowledge", subscription),
+                            json!({ "ackIds": ack_ids }),
+                        )
+                        .await
+                    {
+                        debug!("Fanout acknowledge failed: {}", e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Actor for Fanout {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        info!(
+            "Fanout instance {} publishing to {}",
+            self.instance_id,
+            self.topic()
+        );
+        ctx.spawn(self.pull_loop().into_actor(self));
+    }
+}
+
+impl Handler<Accepted> for Fanout {
+    type Result = ();
+
+    fn handle(&mut self, msg: Accepted, ctx: &mut Self::Context) {
+        if !self.setting.kinds.is_empty() && !self.setting.kinds.contains(&msg.event.kind()) {
+            return;
+        }
+        let client = self.client.clone();
+        let path = format!("{}:publish", self.topic());
+        let body = json!({ "messages": [encode(&msg.event, msg.auth_pubkey.as_ref(), &self.instance_id)] });
+        ctx.spawn(
+            async move {
+                match client.request(reqwest::Method::POST, &path, body).await {
+                    Ok(res) if res.status().is_success() => {
+                        counter!("nostr_relay_fanout_events", "result" => "published").increment(1);
+                    }
+                    Ok(res) => {
+                        warn!("Fanout publish failed: {}", res.status());
+                        counter!("nostr_relay_fanout_events", "result" => "error").increment(1);
+                    }
+                    Err(e) => {
+                        warn!("Fanout publish failed: {}", e);
+                        counter!("nostr_relay_fanout_events", "result" => "error").increment(1);
+                    }
+                }
+            }
+            .into_actor(self),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    #[test]
+    fn message_roundtrip() -> Result<()> {
+        let event: Event = serde_json::from_str(
+            r#"{"content":"Good morning everyone 😃","created_at":1680690006,"id":"332747c0fab8a1a92def4b0937e177be6df4382ce6dd7724f86dc4710b7d4d7d","kind":1,"pubkey":"7abf57d516b1ff7308ca3bd5650ea6a4674d469c7c5057b1d005fb13d218bfef","sig":"ef4ff4f69ac387239eb1401fb07d7a44a5d5d57127e0dc3466a0403cf7d5486b668608ebfcbe9ff1f8d3b5d710545999fe08ee767284ec0b474e4cf92537678f","tags":[["t","nostr"]]}"#,
+        )?;
+        let res = json!({
+            "receivedMessages": [
+                { "ackId": "1", "message": encode(&event, None, "a") },
+                { "ackId": "2", "message": encode(&event, Some(&"ab".repeat(32)), "b") },
+                { "ackId": "3", "message": { "data": "invalid" } },
+            ]
+        });
+        let (ack_ids, events) = decode(&res, "a");
+        assert_eq!(ack_ids, vec!["1", "2", "3"]);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].0.id(), event.id());
+        assert_eq!(events[0].1, Some("ab".repeat(32)));
+        Ok(())
+    }
+}
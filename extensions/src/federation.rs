@@ -0,0 +1,282 @@
+//! Relay federation
+//!
+//! Mirror stored MLS events to peer relays so keypackages and welcomes
+//! published to one relay are discoverable on the user's full relay set.
+//! Events are mirrored once written, so events rejected by a later extension
+//! or by the writer never leave the relay.
+//! Each peer has its own worker with a bounded retry queue, and a short-lived
+//! cache of mirrored event ids stops events from bouncing between peers.
+
+use futures::{SinkExt, StreamExt};
+use metrics::{counter, describe_counter, gauge};
+use nostr_relay::db::Event;
+use nostr_relay::{setting::SettingWrapper, Extension};
+use parking_lot::Mutex;
+use serde::Deserialize;
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+use tokio::sync::mpsc;
+use tokio_tungstenite::{connect_async, tungstenite::Message};
+use tracing::{debug, info, warn};
+
+/// How long a mirrored event id is remembered for loop prevention
+const SEEN_TTL: Duration = Duration::from_secs(600);
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct FederationSetting {
+    pub enabled: bool,
+    /// peer relay websocket urls
+    pub peers: Vec<String>,
+    /// kinds mirrored to peers
+    pub kinds: Vec<u16>,
+    /// events buffered per peer while it is unreachable
+    pub queue_size: usize,
+    /// maximum delay between reconnect attempts
+    #[serde(deserialize_with = "nostr_relay::duration::deserialize")]
+    pub max_backoff: Duration,
+}
+
+impl Default for FederationSetting {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            peers: vec![],
+            kinds: vec![443, 445, 1059, 10051],
+            queue_size: 1000,
+            max_backoff: Duration::from_secs(60),
+        }
+    }
+}
+
+struct Peer {
+    url: String,
+    tx: mpsc::Sender<String>,
+}
+
+#[derive(Default)]
+pub struct Federation {
+    setting: FederationSetting,
+    /// started lazily on the first mirrored event, inside the runtime
+    peers: Mutex<Option<Vec<Peer>>>,
+    seen: Mutex<HashMap<[u8; 32], Instant>>,
+}
+
+impl Federation {
+    pub fn new() -> Self {
+        describe_counter!(
+            "nostr_relay_federation_events",
+            "The total count of events mirrored to peer relays by result"
+        );
+        Self::default()
+    }
+
+    /// Returns true the first time an event id is seen within [`SEEN_TTL`]
+    fn first_seen(&self, id: &[u8; 32]) -> bool {
+        let now = Instant::now();
+        let mut seen = self.seen.lock();
+        if seen.len() > self.setting.queue_size.max(1000) * 10 {
+            seen.retain(|_, t| now.duration_since(*t) < SEEN_TTL);
+        }
+        match seen.get(id) {
+            Some(t) if now.duration_since(*t) < SEEN_TTL => false,
+            _ => {
+                seen.insert(*id, now);
+                true
+            }
+        }
+    }
+
+    fn should_mirror(&self, event: &Event) -> bool {
+        self.setting.enabled
+            && !self.setting.peers.is_empty()
+            && self.setting.kinds.contains(&event.kind())
+            && self.first_seen(event.id())
+    }
+
+    fn mirror(&self, event: &Event) {
+        let msg = format!(r#"["EVENT",{}]"#, event.to_string());
+        let mut peers = self.peers.lock();
+        let peers = peers.get_or_insert_with(|| {
+            self.setting
+                .peers
+                .iter()
+                .map(|url| {
+                    let (tx, rx) = mpsc::channel(self.setting.queue_size.max(1));
+                    tokio::spawn(run_peer(
+                        url.clone(),
+                        rx,
+                        self.setting.queue_size.max(1),
+                        self.setting.max_backoff,
+                    ));
+                    Peer {
+                        url: url.clone(),
+                        tx,
+                    }
+                })
+                .collect()
+        });
+        for peer in peers.iter() {
+            if peer.tx.try_send(msg.clone()).is_err() {
+                debug!("federation queue full for {}", peer.url);
+                counter!("nostr_relay_federation_events", "result" => "dropped").increment(1);
+            }
+        }
+    }
+}
+
+impl Extension for Federation {
+    fn name(&self) -> &'static str {
+        "federation"
+    }
+
+    fn setting(&mut self, setting: &SettingWrapper) {
+        let r = setting.read();
+        self.setting = r.parse_extension(self.name());
+        // workers exit once their channel is dropped, restart with the new peers
+        self.peers.lock().take();
+        if self.setting.enabled {
+            info!(
+                "federation peers: {:?}, kinds: {:?}",
+                self.setting.peers, self.setting.kinds
+            );
+        }
+    }
+
+    fn event_stored(&self, event: &Event) {
+        if self.should_mirror(event) {
+            self.mirror(event);
+        }
+    }
+}
+
+fn enqueue(pending: &mut VecDeque<String>, msg: String, queue_size: usize) {
+    if pending.len() >= queue_size {
+        pending.pop_front();
+        counter!("nostr_relay_federation_events", "result" => "dropped").increment(1);
+    }
+    pending.push_back(msg);
+}
+
+/// Keep a connection to a peer open and forward queued events
+async fn run_peer(
+    url: String,
+    mut rx: mpsc::Receiver<String>,
+    queue_size: usize,
+    max_backoff: Duration,
+) {
+    let mut pending = VecDeque::new();
+    let mut backoff = Duration::from_secs(1);
+    loop {
+        match connect_async(url.as_str()).await {
+            Ok((ws, _)) => {
+                info!("federation connected to {}", url);
+                gauge!("nostr_relay_federation_peers_connected").increment(1.0);
+                backoff = Duration::from_secs(1);
+                let result = forward(ws, &mut rx, &mut pending, queue_size).await;
+                gauge!("nostr_relay_federation_peers_connected").decrement(1.0);
+                match result {
+                    Ok(()) => {
+                        info!("federation worker for {} stopped", url);
+                        return;
+                    }
+                    Err(err) => warn!("federation connection to {} lost: {}", url, err),
+                }
+            }
+            Err(err) => warn!("federation connect to {} failed: {}", url, err),
+        }
+
+        let sleep = tokio::time::sleep(backoff);
+        tokio::pin!(sleep);
+        loop {
+            tokio::select! {
+                _ = &mut sleep => break,
+                msg = rx.recv() => match msg {
+                    Some(msg) => enqueue(&mut pending, msg, queue_size),
+                    None => return,
+                },
+            }
+        }
+        backoff = (backoff * 2).min(max_backoff);
+    }
+}
+
+/// Forward events until the channel closes (Ok) or the connection fails (Err)
+async fn forward<S>(
+    ws: tokio_tungstenite::WebSocketStream<S>,
+    rx: &mut mpsc::Receiver<String>,
+    pending: &mut VecDeque<String>,
+    queue_size: usize,
+) -> Result<(), tokio_tungstenite::tungstenite::Error>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    let (mut sink, mut stream) = ws.split();
+    while let Some(msg) = pending.pop_front() {
+        if let Err(err) = sink.send(Message::Text(msg.clone())).await {
+            pending.push_front(msg);
+            return Err(err);
+        }
+        counter!("nostr_relay_federation_events", "result" => "sent").increment(1);
+    }
+    loop {
+        tokio::select! {
+            msg = rx.recv() => match msg {
+                Some(msg) => {
+                    if let Err(err) = sink.send(Message::Text(msg.clone())).await {
+                        enqueue(pending, msg, queue_size);
+                        return Err(err);
+                    }
+                    counter!("nostr_relay_federation_events", "result" => "sent").increment(1);
+                }
+                None => {
+                    let _ = sink.close().await;
+                    return Ok(());
+                }
+            },
+            incoming = stream.next() => match incoming {
+                Some(Ok(Message::Text(text))) => {
+                    // ["OK", id, false, reason]
+                    if let Ok((cmd, id, ok, reason)) = serde_json::from_str::<(String, String, bool, String)>(&text) {
+                        if cmd == "OK" && !ok && !reason.starts_with("duplicate") {
+                            debug!("peer rejected {}: {}", id, reason);
+                            counter!("nostr_relay_federation_events", "result" => "rejected").increment(1);
+                        }
+                    }
+                }
+                Some(Ok(Message::Close(_))) | None => {
+                    return Err(tokio_tungstenite::tungstenite::Error::ConnectionClosed);
+                }
+                Some(Err(err)) => return Err(err),
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    #[test]
+    fn setting() -> Result<()> {
+        let setting: FederationSetting = serde_json::from_str(
+            r#"{"enabled": true, "peers": ["wss://relay.example.com"], "max_backoff": "2m"}"#,
+        )?;
+        assert_eq!(setting.kinds, vec![443, 445, 1059, 10051]);
+        assert_eq!(setting.max_backoff, Duration::from_secs(120));
+        Ok(())
+    }
+
+    #[test]
+    fn loop_prevention() {
+        let federation = Federation::new();
+        let id = [1u8; 32];
+        assert!(federation.first_seen(&id));
+        assert!(!federation.first_seen(&id));
+        assert!(federation.first_seen(&[2u8; 32]));
+    }
+}
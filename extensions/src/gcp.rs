@@ -0,0 +1,120 @@
+//! Google Cloud access shared by the extensions and the admin commands
+//!
+//! One access token of the metadata server is cached for the process and
+//! refreshed a minute before it expires, GOOGLE_OAUTH_ACCESS_TOKEN replaces it
+//! outside Google Cloud. Secret Manager versions are read and added through
+//! [`access_secret`] and [`add_secret_version`].
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde_json::{json, Value};
+use std::time::{Duration, Instant};
+
+pub const SECRET_MANAGER_URL: &str = "https://secretmanager.googleapis.com/v1";
+const METADATA_TOKEN_URL: &str =
+    "http://metadata.google.internal/computeMetadata/v1/instance/service-accounts/default/token";
+
+static HTTP: Lazy<reqwest::Client> = Lazy::new(reqwest::Client::new);
+static TOKEN: Lazy<Mutex<Option<(String, Instant)>>> = Lazy::new(|| Mutex::new(None));
+
+/// Shared HTTP client for Google APIs
+pub fn http() -> reqwest::Client {
+    HTTP.clone()
+}
+
+/// Access token of the instance service account
+pub async fn access_token() -> anyhow::Result<String> {
+    if let Ok(token) = std::env::var("GOOGLE_OAUTH_ACCESS_TOKEN") {
+        return Ok(token);
+    }
+    if let Some((token, expires)) = TOKEN.lock().clone() {
+        if expires > Instant::now() {
+            return Ok(token);
+        }
+    }
+    let res: Value = HTTP
+        .get(METADATA_TOKEN_URL)
+        .header("Metadata-Flavor", "Google")
+        .send()
+        .await
+        .map_err(|e| anyhow::anyhow!("no GOOGLE_OAUTH_ACCESS_TOKEN and no metadata server: {}", e))?
+        .error_for_status()?
+        .json()
+        .await?;
+    let token = res
+        .get("access_token")
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| anyhow::anyhow!("Invalid token response"))?
+        .to_string();
+    let expires_in = res
+        .get("expires_in")
+        .and_then(|v| v.as_u64())
+        .unwrap_or(300);
+    // refresh a minute early
+    let expires = Instant::now() + Duration::from_secs(expires_in.saturating_sub(60));
+    *TOKEN.lock() = Some((token.clone(), expires));
+    Ok(token)
+}
+
+/// Payload of a secret version
+#[derive(Debug, Clone)]
+pub struct SecretPayload {
+    pub data: Vec<u8>,
+    /// full version name, e.g. `projects/p/secrets/s/versions/3`
+    pub version: String,
+}
+
+/// Read a secret version (`projects/<project>/secrets/<secret>/versions/<version>`),
+/// `None` when the secret or the version does not exist
+pub async fn access_secret(version: &str) -> anyhow::Result<Option<SecretPayload>> {
+    let res = HTTP
+        .get(format!("{}/{}:access", SECRET_MANAGER_URL, version))
+        .bearer_auth(access_token().await?)
+        .send()
+        .await?;
+    if res.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    let res: Value = res
+        .error_for_status()
+        .map_err(|e| anyhow::anyhow!("secret {}: {}", version, e))?
+        .json()
+        .await?;
+    let data = res["payload"]["data"]
+        .as_str()
+        .ok_or_else(|| anyhow::anyhow!("secret {} has no payload", version))?;
+    Ok(Some(SecretPayload {
+        data: STANDARD.decode(data)?,
+        version: res["name"].as_str().unwrap_or(version).to_owned(),
+    }))
+}
+
+/// Add a version to a secret (`projects/<project>/secrets/<secret>`), creating
+/// the secret first if needed, returns the full version name
+pub async fn add_secret_version(secret: &str, data: &[u8]) -> anyhow::Result<String> {
+    let token = access_token().await?;
+    let (parent, secret_id) = secret
+        .rsplit_once("/secrets/")
+        .ok_or_else(|| anyhow::anyhow!("invalid secret name {}", secret))?;
+    let res = HTTP
+        .post(format!("{}/{}/secrets", SECRET_MANAGER_URL, parent))
+        .query(&[("secretId", secret_id)])
+        .bearer_auth(&token)
+        .json(&json!({ "replication": { "automatic": {} } }))
+        .send()
+        .await?;
+    if res.status() != reqwest::StatusCode::CONFLICT {
+        res.error_for_status()?;
+    }
+    let res: Value = HTTP
+        .post(format!("{}/{}:addVersion", SECRET_MANAGER_URL, secret))
+        .bearer_auth(&token)
+        .json(&json!({ "payload": { "data": STANDARD.encode(data) } }))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    Ok(res["name"].as_str().unwrap_or(secret).to_owned())
+}
@@ -0,0 +1,297 @@
+//! IP reputation and geo-blocking
+//!
+//! Connections are checked before the websocket handshake against an ordered
+//! list of rules, the first rule matching the client ip decides:
+//! - `allow` accepts the connection, e.g. an office range ahead of a country rule
+//! - `deny` refuses it with http 403
+//! - `tarpit` holds the request for `tarpit_delay` before refusing it, slowing
+//!   down scanners that reconnect in a loop, the request keeps its connection
+//!   slot meanwhile so `network.max_connections` caps the held requests
+//!
+//! A rule matches CIDRs listed inline or in `files` (one per line, `#` starts a
+//! comment, e.g. downloaded Spamhaus DROP or Tor exit lists), and countries
+//! from a MaxMind GeoIP2/GeoLite2 Country database with the `geoip` feature.
+//! Files and the database are read again on setting reload. The client ip is
+//! the one of `network.real_ip_header` behind a proxy, `real_ip_trusted_hops`
+//! entries from the right.
+
+use ipnet::IpNet;
+use metrics::{counter, describe_counter};
+use nostr_relay::{setting::SettingWrapper, ConnectionResult, Extension};
+use serde::Deserialize;
+use std::{collections::HashSet, fs, net::IpAddr, path::PathBuf, time::Duration};
+use tracing::{error, info, warn};
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    #[default]
+    Allow,
+    Deny,
+    Tarpit,
+}
+
+impl Action {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Action::Allow => "allow",
+            Action::Deny => "deny",
+            Action::Tarpit => "tarpit",
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct IpRule {
+    /// rule label in metrics
+    pub name: String,
+    pub action: Action,
+    pub cidrs: Vec<String>,
+    /// files of CIDRs, one per line
+    pub files: Vec<PathBuf>,
+    /// ISO 3166 country codes, needs `geoip_database`
+    pub countries: Vec<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct IpReputationSetting {
+    pub enabled: bool,
+    /// checked in order, the first match decides
+    pub rules: Vec<IpRule>,
+    /// action when no rule matches
+    pub default_action: Action,
+    #[serde(deserialize_with = "nostr_relay::duration::deserialize")]
+    pub tarpit_delay: Duration,
+    /// MaxMind country database (.mmdb)
+    pub geoip_database: Option<PathBuf>,
+}
+
+impl Default for IpReputationSetting {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            rules: vec![],
+            default_action: Action::Allow,
+            tarpit_delay: Duration::from_secs(10),
+            geoip_database: None,
+        }
+    }
+}
+
+/// Rule with its networks parsed
+#[derive(Debug)]
+struct Rule {
+    name: String,
+    action: Action,
+    nets: Vec<IpNet>,
+    countries: HashSet<String>,
+}
+
+impl Rule {
+    fn load(rule: &IpRule) -> Self {
+        let mut nets = parse_cidrs(rule.cidrs.iter().map(String::as_str), &rule.name);
+        for file in &rule.files {
+            match fs::read_to_string(file) {
+                Ok(text) => {
+                    let lines = text
+                        .lines()
+                        .map(|line| line.split(['#', ';']).next().unwrap_or_default().trim())
+                        .filter(|line| !line.is_empty());
+                    nets.extend(parse_cidrs(lines, &rule.name));
+                }
+                Err(e) => error!(
+                    "ip_reputation rule {}: cannot read {}: {}",
+                    rule.name,
+                    file.display(),
+                    e
+                ),
+            }
+        }
+        Self {
+            name: rule.name.clone(),
+            action: rule.action,
+            nets,
+            countries: rule.countries.iter().map(|c| c.to_uppercase()).collect(),
+        }
+    }
+
+    fn matches(&self, ip: &IpAddr, country: Option<&str>) -> bool {
+        self.nets.iter().any(|net| net.contains(ip))
+            || country.map_or(false, |c| self.countries.contains(c))
+    }
+}
+
+/// Networks of CIDRs or single addresses, invalid entries are logged and skipped
+fn parse_cidrs<'a>(cidrs: impl Iterator<Item = &'a str>, rule: &str) -> Vec<IpNet> {
+    cidrs
+        .filter_map(|cidr| match cidr.parse::<IpNet>() {
+            Ok(net) => Some(net),
+            Err(_) => match cidr.parse::<IpAddr>() {
+                Ok(ip) => Some(IpNet::from(ip)),
+                Err(_) => {
+                    warn!("ip_reputation rule {}: invalid CIDR {}", rule, cidr);
+                    None
+                }
+            },
+        })
+        .collect()
+}
+
+#[derive(Default)]
+pub struct IpReputation {
+    setting: IpReputationSetting,
+    rules: Vec<Rule>,
+    #[cfg(feature = "geoip")]
+    geoip: Option<maxminddb::Reader<Vec<u8>>>,
+}
+
+impl IpReputation {
+    pub fn new() -> Self {
+        describe_counter!(
+            "nostr_relay_ip_reputation",
+            "The total count of connections matched by ip reputation rules by rule and action"
+        );
+        Self::default()
+    }
+
+    /// Country of an ip, only looked up when a rule needs it
+    fn country(&self, ip: &IpAddr) -> Option<String> {
+        #[cfg(feature = "geoip")]
+        if let Some(reader) = &self.geoip {
+            return reader
+                .lookup::<maxminddb::geoip2::Country>(*ip)
+                .ok()?
+                .country?
+                .iso_code
+                .map(ToOwned::to_owned);
+        }
+        let _ = ip;
+        None
+    }
+
+    /// Matching rule name and action of an ip
+    fn check(&self, ip: &IpAddr) -> (&str, Action) {
+        let country = if self.rules.iter().any(|r| !r.countries.is_empty()) {
+            self.country(ip)
+        } else {
+            None
+        };
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(ip, country.as_deref()))
+            .map(|rule| (rule.name.as_str(), rule.action))
+            .unwrap_or(("default", self.setting.default_action))
+    }
+}
+
+impl Extension for IpReputation {
+    fn name(&self) -> &'static str {
+        "ip_reputation"
+    }
+
+    fn setting(&mut self, setting: &SettingWrapper) {
+        let r = setting.read();
+        self.setting = r.parse_extension(self.name());
+        drop(r);
+        if !self.setting.enabled {
+            self.rules.clear();
+            return;
+        }
+        self.rules = self.setting.rules.iter().map(Rule::load).collect();
+        #[cfg(feature = "geoip")]
+        {
+            self.geoip = self.setting.geoip_database.as_ref().and_then(|path| {
+                maxminddb::Reader::open_readfile(path)
+                    .map_err(|e| error!("ip_reputation: cannot open {}: {}", path.display(), e))
+                    .ok()
+            });
+        }
+        #[cfg(not(feature = "geoip"))]
+        if self.rules.iter().any(|r| !r.countries.is_empty()) {
+            warn!("ip_reputation country rules need the geoip feature, they match no connection");
+        }
+        info!(
+            "ip_reputation rules: {}, networks: {}",
+            self.rules.len(),
+            self.rules.iter().map(|r| r.nets.len()).sum::<usize>()
+        );
+    }
+
+    fn connection(&self, ip: &str, _req: &actix_web::HttpRequest) -> ConnectionResult {
+        if !self.setting.enabled {
+            return ConnectionResult::Accept;
+        }
+        // unknown address, e.g. a missing real ip header
+        let Ok(addr) = ip.parse::<IpAddr>() else {
+            return ConnectionResult::Accept;
+        };
+        let (rule, action) = self.check(&addr);
+        if rule != "default" || action != Action::Allow {
+            counter!("nostr_relay_ip_reputation", "rule" => rule.to_owned(), "action" => action.as_str())
+                .increment(1);
+        }
+        match action {
+            Action::Allow => ConnectionResult::Accept,
+            Action::Deny => ConnectionResult::Reject,
+            Action::Tarpit => ConnectionResult::Tarpit(self.setting.tarpit_delay),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn first_match() -> anyhow::Result<()> {
+        let mut file = tempfile::NamedTempFile::new()?;
+        writeln!(
+            file,
+            "; Spamhaus DROP style\n192.0.2.0/24 ; SBL1\n\n2001:db8::/32 # docs\nnot-an-ip"
+        )?;
+
+        let setting = IpReputationSetting {
+            enabled: true,
+            rules: vec![
+                IpRule {
+                    name: "office".to_owned(),
+                    action: Action::Allow,
+                    cidrs: vec!["192.0.2.10".to_owned()],
+                    ..Default::default()
+                },
+                IpRule {
+                    name: "drop".to_owned(),
+                    action: Action::Deny,
+                    files: vec![file.path().to_path_buf()],
+                    ..Default::default()
+                },
+                IpRule {
+                    name: "scanners".to_owned(),
+                    action: Action::Tarpit,
+                    cidrs: vec!["198.51.100.0/24".to_owned()],
+                    countries: vec!["kp".to_owned()],
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+        let ext = IpReputation {
+            rules: setting.rules.iter().map(Rule::load).collect(),
+            setting,
+            ..Default::default()
+        };
+        assert_eq!(ext.rules[1].nets.len(), 2);
+        assert!(ext.rules[2].matches(&"203.0.113.1".parse()?, Some("KP")));
+
+        let check = |ip: &str| ext.check(&ip.parse().unwrap());
+        assert_eq!(check("192.0.2.10"), ("office", Action::Allow));
+        assert_eq!(check("192.0.2.11"), ("drop", Action::Deny));
+        assert_eq!(check("2001:db8::1"), ("drop", Action::Deny));
+        assert_eq!(check("198.51.100.7"), ("scanners", Action::Tarpit));
+        assert_eq!(check("203.0.113.1"), ("default", Action::Allow));
+        Ok(())
+    }
+}
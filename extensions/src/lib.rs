@@ -1,6 +1,9 @@
 pub mod auth;
 pub use auth::Auth;
 
+pub mod nip44;
+pub mod nip59;
+
 #[cfg(feature = "metrics")]
 pub mod metrics;
 #[cfg(feature = "metrics")]
@@ -11,6 +14,11 @@ pub mod rate_limiter;
 #[cfg(feature = "rate_limiter")]
 pub use rate_limiter::Ratelimiter;
 
+#[cfg(feature = "spam")]
+pub mod spam;
+#[cfg(feature = "spam")]
+pub use spam::Spam;
+
 #[cfg(feature = "count")]
 pub mod count;
 #[cfg(feature = "count")]
@@ -26,6 +34,43 @@ pub mod mls_gateway;
 #[cfg(feature = "mls_gateway")]
 pub use mls_gateway::MlsGateway;
 
+#[cfg(feature = "federation")]
+pub mod federation;
+#[cfg(feature = "federation")]
+pub use federation::Federation;
+
+#[cfg(feature = "webhook")]
+pub mod webhook;
+#[cfg(feature = "webhook")]
+pub use webhook::Webhook;
+
+#[cfg(feature = "fanout")]
+pub mod fanout;
+#[cfg(feature = "fanout")]
+pub use fanout::Fanout;
+
+#[cfg(feature = "reqwest")]
+pub mod gcp;
+
+#[cfg(feature = "secrets")]
+pub mod secrets;
+
+#[cfg(feature = "object_store")]
+pub mod object_store;
+
+#[cfg(feature = "snapshot")]
+pub mod snapshot;
+
+#[cfg(feature = "ip_reputation")]
+pub mod ip_reputation;
+#[cfg(feature = "ip_reputation")]
+pub use ip_reputation::IpReputation;
+
+#[cfg(feature = "attestation")]
+pub mod attestation;
+#[cfg(feature = "attestation")]
+pub use attestation::Attestation;
+
 #[cfg(feature = "nip_service")]
 pub mod nip_service;
 #[cfg(feature = "nip_service")]
@@ -57,6 +57,19 @@ pub fn describe_metrics() {
         "nostr_relay_session",
         "The number of current active sessions"
     );
+    describe_gauge!(
+        "nostr_relay_session_auth",
+        "The number of current sessions by NIP-42 state, authenticated or anonymous"
+    );
+    describe_gauge!(
+        "nostr_relay_subscriptions",
+        "The number of current subscriptions"
+    );
+    describe_gauge!(
+        "nostr_relay_subscriptions_by_kind",
+        "The number of current subscriptions per requested kind, any for filters without kinds"
+    );
+    describe_histogram!("nostr_relay_req_duration", "The time from REQ to EOSE");
     describe_counter!(
         "nostr_relay_message_total",
         "The total count of message from client"
@@ -64,6 +77,27 @@ pub fn describe_metrics() {
     describe_counter!("nostr_relay_new_event", "The total count of new event");
     describe_histogram!("nostr_relay_db_get", "The time of per filter get");
     describe_histogram!("nostr_relay_db_write", "The time of per write transaction");
+    describe_histogram!("nostr_relay_db_sync", "The time of per interval disk flush");
+    describe_gauge!("nostr_relay_db_map_size_bytes", "The LMDB map size");
+    describe_gauge!(
+        "nostr_relay_db_map_used_bytes",
+        "The bytes of the LMDB map in use"
+    );
+    describe_gauge!("nostr_relay_db_readers", "The LMDB reader slots in use");
+    describe_gauge!("nostr_relay_db_max_readers", "The LMDB reader slots");
+    describe_gauge!(
+        "nostr_relay_db_free_pages",
+        "The LMDB pages freed by earlier transactions and reused before the map grows"
+    );
+    describe_gauge!("nostr_relay_db_entries", "The entries per database index");
+    describe_gauge!(
+        "nostr_relay_db_index_bytes",
+        "The bytes of pages per database index"
+    );
+    describe_counter!(
+        "nostr_relay_db_slow_query",
+        "The total count of filters over the slow query thresholds by kind"
+    );
 }
 
 pub fn create_prometheus_handle() -> PrometheusHandle {
@@ -0,0 +1,473 @@
+//! Authenticated admin REST endpoints for MLS Gateway
+//!
+//! Routes are mounted under `{api_prefix}/admin` only when an admin token is
+//! configured (`admin_token` or `MLS_ADMIN_TOKEN`), and every request must carry
+//! `Authorization: Bearer <token>`.
+
+use super::backfill::{self, Backfill};
+use super::firestore::ModerationRule;
+use super::message_archive::MessageArchive;
+use super::privacy::{self, PrivacyState};
+use super::{moderation, MlsGatewayConfig, StorageBackend};
+use actix_web::{web, HttpRequest, HttpResponse, Result as ActixResult};
+use nostr_relay::App;
+use serde::Deserialize;
+use serde_json::json;
+use tracing::{info, warn};
+
+/// Shared state of the admin scope
+#[derive(Clone)]
+pub struct AdminState {
+    pub token: String,
+    pub config: MlsGatewayConfig,
+    /// Storage of moderation rules, unset until the gateway is initialized
+    pub store: Option<StorageBackend>,
+    /// Message archive for user data export and erasure
+    pub archive: Option<MessageArchive>,
+}
+
+/// Resolve the admin token from config or environment
+pub fn admin_token(config: &MlsGatewayConfig) -> Option<String> {
+    config
+        .admin_token
+        .clone()
+        .or_else(|| std::env::var("MLS_ADMIN_TOKEN").ok())
+        .filter(|t| !t.is_empty())
+}
+
+/// Configure admin routes, must be registered before the general API scope
+pub fn configure_admin_routes(cfg: &mut web::ServiceConfig, prefix: &str, state: AdminState) {
+    let scope = web::scope(&format!("{}/admin", prefix))
+        .app_data(web::Data::new(state))
+        .route("/backfill", web::post().to(post_backfill))
+        .route("/backfill/status", web::get().to(get_backfill_status))
+        .route("/moderation", web::get().to(get_moderation_rules))
+        .route("/moderation", web::post().to(post_moderation_rule))
+        .route(
+            "/moderation/{list}/{target}/{value}",
+            web::delete().to(delete_moderation_rule),
+        )
+        .route("/roster/anomalies", web::get().to(get_roster_anomalies))
+        .route("/roster/pending", web::get().to(get_pending_roster_ops))
+        .route("/users/{pubkey}/export", web::get().to(get_user_export))
+        .route("/users/{pubkey}", web::delete().to(delete_user));
+    #[cfg(feature = "nip_service")]
+    let scope = scope
+        .route(
+            "/nip-service/clients/{client_id}/versions",
+            web::get().to(get_client_versions),
+        )
+        .route(
+            "/nip-service/clients/{client_id}/rotations",
+            web::get().to(get_client_rotations),
+        );
+    cfg.service(scope);
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Check the bearer token of a request
+pub fn authorized(req: &HttpRequest, state: &AdminState) -> bool {
+    req.headers()
+        .get(actix_web::http::header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map_or(false, |t| {
+            constant_time_eq(t.as_bytes(), state.token.as_bytes())
+        })
+}
+
+fn unauthorized() -> HttpResponse {
+    HttpResponse::Unauthorized().json(json!({
+        "ok": false,
+        "error": "unauthorized"
+    }))
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct BackfillRequest {
+    /// Kinds to backfill, defaults to `backfill_kinds`
+    pub kinds: Option<Vec<u32>>,
+    /// Unix timestamp to read from, defaults to the archive TTL window
+    pub since: Option<i64>,
+    /// Upper bound of events, defaults to `backfill_max_events`
+    pub max_events: Option<u32>,
+    /// Resume from the stored checkpoint, defaults to true unless `since` is given
+    pub resume: Option<bool>,
+}
+
+/// Run the Firestore -> LMDB backfill on demand, streaming progress as ndjson
+async fn post_backfill(
+    req: HttpRequest,
+    state: web::Data<AdminState>,
+    app: web::Data<App>,
+    body: Option<web::Json<BackfillRequest>>,
+) -> ActixResult<HttpResponse> {
+    if !authorized(&req, &state) {
+        return Ok(unauthorized());
+    }
+    if backfill::status().running {
+        return Ok(HttpResponse::Conflict().json(json!({
+            "ok": false,
+            "error": "backfill already running"
+        })));
+    }
+
+    let body = body.map(|b| b.into_inner()).unwrap_or_default();
+    let config = &state.config;
+    let kinds = body.kinds.unwrap_or_else(|| config.backfill_kinds.clone());
+    let since = body.since.unwrap_or_else(|| {
+        chrono::Utc::now().timestamp() - (config.message_archive_ttl_days as i64) * 86_400
+    });
+    let max_events = body.max_events.unwrap_or(config.backfill_max_events);
+    let resume = body.resume.unwrap_or(body.since.is_none());
+
+    let archive = match MessageArchive::new().await {
+        Ok(archive) => archive,
+        Err(e) => {
+            return Ok(HttpResponse::InternalServerError().json(json!({
+                "ok": false,
+                "error": format!("Failed to initialize message archive: {}", e)
+            })));
+        }
+    };
+    let data_path = app.setting.read().data.path.clone();
+    let mut runner = Backfill::new(archive, app.db.clone(), data_path);
+
+    info!(
+        "Manual backfill requested: kinds={:?}, since={}, max_events={}, resume={}",
+        kinds, since, max_events, resume
+    );
+
+    let (tx, rx) = tokio::sync::mpsc::unbounded_channel::<web::Bytes>();
+    tokio::spawn(async move {
+        let progress_tx = tx.clone();
+        let result = runner
+            .run_with_progress(&kinds, since, max_events, resume, |report| {
+                let line = json!({ "progress": report }).to_string() + "\n";
+                let _ = progress_tx.send(web::Bytes::from(line));
+            })
+            .await;
+        let line = match result {
+            Ok(report) => json!({ "ok": true, "report": report }),
+            Err(e) => {
+                warn!("Manual backfill failed: {}", e);
+                json!({ "ok": false, "error": e.to_string() })
+            }
+        };
+        let _ = tx.send(web::Bytes::from(line.to_string() + "\n"));
+    });
+
+    let stream = futures::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|b| (Ok::<_, actix_web::Error>(b), rx))
+    });
+    Ok(HttpResponse::Ok()
+        .content_type("application/x-ndjson")
+        .streaming(stream))
+}
+
+/// Report backfill progress of this instance
+async fn get_backfill_status(
+    req: HttpRequest,
+    state: web::Data<AdminState>,
+) -> ActixResult<HttpResponse> {
+    if !authorized(&req, &state) {
+        return Ok(unauthorized());
+    }
+    Ok(HttpResponse::Ok().json(json!({
+        "ok": true,
+        "status": backfill::status()
+    })))
+}
+
+fn storage_unavailable() -> HttpResponse {
+    HttpResponse::ServiceUnavailable().json(json!({
+        "ok": false,
+        "error": "storage not initialized"
+    }))
+}
+
+/// List moderation rules
+async fn get_moderation_rules(
+    req: HttpRequest,
+    state: web::Data<AdminState>,
+) -> ActixResult<HttpResponse> {
+    if !authorized(&req, &state) {
+        return Ok(unauthorized());
+    }
+    let Some(store) = &state.store else {
+        return Ok(storage_unavailable());
+    };
+    match store.list_moderation_rules().await {
+        Ok(rules) => Ok(HttpResponse::Ok().json(json!({ "ok": true, "rules": rules }))),
+        Err(e) => Ok(HttpResponse::InternalServerError().json(json!({
+            "ok": false,
+            "error": e.to_string()
+        }))),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ModerationRuleRequest {
+    /// "allow" or "deny"
+    pub list: String,
+    /// "pubkey" or "kind"
+    pub target: String,
+    pub value: String,
+    pub reason: Option<String>,
+}
+
+/// Add a moderation rule, effective immediately on this instance
+async fn post_moderation_rule(
+    req: HttpRequest,
+    state: web::Data<AdminState>,
+    body: web::Json<ModerationRuleRequest>,
+) -> ActixResult<HttpResponse> {
+    if !authorized(&req, &state) {
+        return Ok(unauthorized());
+    }
+    let Some(store) = &state.store else {
+        return Ok(storage_unavailable());
+    };
+    let (list, target, value) = match moderation::normalize(&body.list, &body.target, &body.value) {
+        Ok(rule) => rule,
+        Err(e) => return Ok(HttpResponse::BadRequest().json(json!({ "ok": false, "error": e }))),
+    };
+    let rule = ModerationRule {
+        list,
+        target,
+        value,
+        reason: body.reason.clone(),
+        created_at: chrono::Utc::now(),
+    };
+    match moderation::add(store, &rule).await {
+        Ok(()) => Ok(HttpResponse::Ok().json(json!({ "ok": true, "rule": rule }))),
+        Err(e) => Ok(HttpResponse::InternalServerError().json(json!({
+            "ok": false,
+            "error": e.to_string()
+        }))),
+    }
+}
+
+/// Remove a moderation rule
+async fn delete_moderation_rule(
+    req: HttpRequest,
+    state: web::Data<AdminState>,
+    path: web::Path<(String, String, String)>,
+) -> ActixResult<HttpResponse> {
+    if !authorized(&req, &state) {
+        return Ok(unauthorized());
+    }
+    let Some(store) = &state.store else {
+        return Ok(storage_unavailable());
+    };
+    let (list, target, value) = path.into_inner();
+    let (list, target, value) = match moderation::normalize(&list, &target, &value) {
+        Ok(rule) => rule,
+        Err(e) => return Ok(HttpResponse::BadRequest().json(json!({ "ok": false, "error": e }))),
+    };
+    match moderation::remove(store, &list, &target, &value).await {
+        Ok(true) => Ok(HttpResponse::Ok().json(json!({ "ok": true }))),
+        Ok(false) => {
+            Ok(HttpResponse::NotFound().json(json!({ "ok": false, "error": "rule not found" })))
+        }
+        Err(e) => Ok(HttpResponse::InternalServerError().json(json!({
+            "ok": false,
+            "error": e.to_string()
+        }))),
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct GroupFilterQuery {
+    pub group_id: Option<String>,
+}
+
+/// Roster sequence gaps and forks, of one group or all groups
+async fn get_roster_anomalies(
+    req: HttpRequest,
+    state: web::Data<AdminState>,
+    query: web::Query<GroupFilterQuery>,
+) -> ActixResult<HttpResponse> {
+    if !authorized(&req, &state) {
+        return Ok(unauthorized());
+    }
+    let Some(store) = &state.store else {
+        return Ok(storage_unavailable());
+    };
+    match store.list_roster_anomalies(query.group_id.as_deref()).await {
+        Ok(anomalies) => Ok(HttpResponse::Ok().json(json!({ "ok": true, "anomalies": anomalies }))),
+        Err(e) => Ok(HttpResponse::InternalServerError().json(json!({
+            "ok": false,
+            "error": e.to_string()
+        }))),
+    }
+}
+
+/// Roster operations waiting for, or closed after, their admin quorum
+async fn get_pending_roster_ops(
+    req: HttpRequest,
+    state: web::Data<AdminState>,
+    query: web::Query<GroupFilterQuery>,
+) -> ActixResult<HttpResponse> {
+    if !authorized(&req, &state) {
+        return Ok(unauthorized());
+    }
+    let Some(store) = &state.store else {
+        return Ok(storage_unavailable());
+    };
+    match store
+        .list_pending_roster_ops(query.group_id.as_deref())
+        .await
+    {
+        Ok(pending) => Ok(HttpResponse::Ok().json(json!({ "ok": true, "pending": pending }))),
+        Err(e) => Ok(HttpResponse::InternalServerError().json(json!({
+            "ok": false,
+            "error": e.to_string()
+        }))),
+    }
+}
+
+/// Storage and pubkey of a user data request, or the error response
+fn privacy_request(state: &AdminState, pubkey: &str) -> Result<PrivacyState, HttpResponse> {
+    let Some(store) = &state.store else {
+        return Err(storage_unavailable());
+    };
+    if !privacy::valid_pubkey(pubkey) {
+        return Err(HttpResponse::BadRequest().json(json!({
+            "ok": false,
+            "error": "pubkey must be 64 lowercase hex characters"
+        })));
+    }
+    Ok(PrivacyState {
+        store: store.clone(),
+        archive: state.archive.clone(),
+    })
+}
+
+/// Export all data held for a pubkey
+async fn get_user_export(
+    req: HttpRequest,
+    state: web::Data<AdminState>,
+    app: web::Data<App>,
+    path: web::Path<String>,
+) -> ActixResult<HttpResponse> {
+    if !authorized(&req, &state) {
+        return Ok(unauthorized());
+    }
+    let pubkey = path.into_inner();
+    match privacy_request(&state, &pubkey) {
+        Ok(privacy) => Ok(privacy::export_response(&privacy, &app, &pubkey).await),
+        Err(resp) => Ok(resp),
+    }
+}
+
+/// Erase all data held for a pubkey
+async fn delete_user(
+    req: HttpRequest,
+    state: web::Data<AdminState>,
+    app: web::Data<App>,
+    path: web::Path<String>,
+) -> ActixResult<HttpResponse> {
+    if !authorized(&req, &state) {
+        return Ok(unauthorized());
+    }
+    let pubkey = path.into_inner();
+    match privacy_request(&state, &pubkey) {
+        Ok(privacy) => {
+            info!("Admin erasure of user data for {}", pubkey);
+            Ok(privacy::erase_response(&privacy, &app, &pubkey).await)
+        }
+        Err(resp) => Ok(resp),
+    }
+}
+
+/// NIP-KR secret version history of a client (hashes and metadata only)
+#[cfg(feature = "nip_service")]
+async fn get_client_versions(
+    req: HttpRequest,
+    state: web::Data<AdminState>,
+    path: web::Path<String>,
+) -> ActixResult<HttpResponse> {
+    use crate::nip_service::store::NipKrStore;
+
+    if !authorized(&req, &state) {
+        return Ok(unauthorized());
+    }
+    let client_id = path.into_inner();
+    match crate::nip_service::store::get_global_store()
+        .list_versions(&client_id)
+        .await
+    {
+        Ok(versions) => Ok(HttpResponse::Ok().json(json!({
+            "ok": true,
+            "client_id": client_id,
+            "versions": versions
+        }))),
+        Err(e) => Ok(HttpResponse::InternalServerError().json(json!({
+            "ok": false,
+            "error": e.to_string()
+        }))),
+    }
+}
+
+/// Rotation audit history of a NIP-KR client: outcomes, quorum progress, timestamps
+#[cfg(feature = "nip_service")]
+async fn get_client_rotations(
+    req: HttpRequest,
+    state: web::Data<AdminState>,
+    path: web::Path<String>,
+) -> ActixResult<HttpResponse> {
+    use crate::nip_service::store::NipKrStore;
+
+    if !authorized(&req, &state) {
+        return Ok(unauthorized());
+    }
+    let client_id = path.into_inner();
+    match crate::nip_service::store::get_global_store()
+        .list_rotations(&client_id)
+        .await
+    {
+        Ok(rotations) => Ok(HttpResponse::Ok().json(json!({
+            "ok": true,
+            "client_id": client_id,
+            "rotations": rotations
+        }))),
+        Err(e) => Ok(HttpResponse::InternalServerError().json(json!({
+            "ok": false,
+            "error": e.to_string()
+        }))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn bearer_token() {
+        let state = AdminState {
+            token: "secret".to_string(),
+            config: MlsGatewayConfig::default(),
+            store: None,
+            archive: None,
+        };
+        let req = TestRequest::default()
+            .insert_header(("Authorization", "Bearer secret"))
+            .to_http_request();
+        assert!(authorized(&req, &state));
+        let req = TestRequest::default()
+            .insert_header(("Authorization", "Bearer secreT"))
+            .to_http_request();
+        assert!(!authorized(&req, &state));
+        let req = TestRequest::default().to_http_request();
+        assert!(!authorized(&req, &state));
+    }
+}
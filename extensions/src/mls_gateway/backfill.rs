@@ -0,0 +1,321 @@
+//! Firestore -> LMDB backfill
+//!
+//! Reconstitutes the local event store from the message archive so clients can
+//! use plain Nostr REQ after a restart. Progress is tracked per kind in a
+//! checkpoint file inside the LMDB directory (`<data.path>/events`), so a
+//! restart only reads documents archived after the last run and a wiped event
+//! store starts from scratch.
+
+use super::message_archive::MessageArchive;
+use anyhow::Result;
+use metrics::counter;
+use nostr_relay::db::{BatchPutStats, Db};
+use once_cell::sync::Lazy;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tracing::{debug, info, warn};
+
+/// Checkpoint file name inside the data directory
+const CHECKPOINT_FILE: &str = "backfill_checkpoint.json";
+
+/// Firestore page size
+const PAGE_SIZE: u32 = 500;
+
+/// Position of the last backfilled event of a kind
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KindCheckpoint {
+    pub created_at: i64,
+    pub id: String,
+}
+
+/// Result of a single backfill run
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BackfillReport {
+    pub started_at: i64,
+    pub finished_at: i64,
+    /// Events read from the archive
+    pub fetched: usize,
+    pub inserted: usize,
+    pub duplicate: usize,
+    pub invalid: usize,
+    pub ignored: usize,
+    pub error: Option<String>,
+}
+
+impl BackfillReport {
+    fn add(&mut self, stats: &BatchPutStats) {
+        self.inserted += stats.inserted;
+        self.duplicate += stats.duplicate;
+        self.invalid += stats.invalid;
+        self.ignored += stats.ignored;
+    }
+}
+
+/// Process-wide backfill status, exposed over REST
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BackfillStatus {
+    pub running: bool,
+    pub runs: u64,
+    pub total_inserted: u64,
+    pub total_duplicate: u64,
+    pub total_invalid: u64,
+    pub last_run: Option<BackfillReport>,
+}
+
+static STATUS: Lazy<RwLock<BackfillStatus>> = Lazy::new(|| RwLock::new(BackfillStatus::default()));
+
+/// Snapshot of the current backfill status
+pub fn status() -> BackfillStatus {
+    STATUS.read().clone()
+}
+
+/// Persisted backfill progress
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BackfillCheckpoint {
+    #[serde(default)]
+    pub kinds: HashMap<u32, KindCheckpoint>,
+    /// Report of the last completed run
+    #[serde(default)]
+    pub last_report: Option<BackfillReport>,
+    #[serde(skip)]
+    path: Option<PathBuf>,
+}
+
+impl BackfillCheckpoint {
+    /// Load the checkpoint from the events directory, a missing or corrupt file starts empty
+    pub fn load<P: AsRef<Path>>(dir: P) -> Self {
+        let path = dir.as_ref().join(CHECKPOINT_FILE);
+        let mut checkpoint = match std::fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice::<BackfillCheckpoint>(&bytes).unwrap_or_else(|e| {
+                warn!("Ignoring corrupt backfill checkpoint {:?}: {}", path, e);
+                BackfillCheckpoint::default()
+            }),
+            Err(_) => BackfillCheckpoint::default(),
+        };
+        checkpoint.path = Some(path);
+        checkpoint
+    }
+
+    /// Write the checkpoint atomically
+    pub fn save(&self) -> Result<()> {
+        if let Some(path) = &self.path {
+            let tmp = path.with_extension("json.tmp");
+            std::fs::write(&tmp, serde_json::to_vec(self)?)?;
+            std::fs::rename(&tmp, path)?;
+        }
+        Ok(())
+    }
+
+    /// Cursor to resume a kind from, ignoring checkpoints older than `since`
+    pub fn cursor(&self, kind: u32, since: i64) -> Option<(i64, String)> {
+        self.kinds
+            .get(&kind)
+            .filter(|c| c.created_at >= since)
+            .map(|c| (c.created_at, c.id.clone()))
+    }
+
+    /// Advance the checkpoint of a kind, never moving it backwards
+    pub fn update(&mut self, kind: u32, created_at: i64, id: String) {
+        let next = KindCheckpoint { created_at, id };
+        match self.kinds.get(&kind) {
+            Some(c) if (c.created_at, &c.id) >= (next.created_at, &next.id) => {}
+            _ => {
+                self.kinds.insert(kind, next);
+            }
+        }
+    }
+}
+
+/// Backfill runner
+pub struct Backfill {
+    archive: MessageArchive,
+    db: Arc<Db>,
+    checkpoint: BackfillCheckpoint,
+}
+
+impl Backfill {
+    /// `data_path` is the configured `data.path`, the checkpoint lives in its events directory
+    pub fn new<P: AsRef<Path>>(archive: MessageArchive, db: Arc<Db>, data_path: P) -> Self {
+        Self {
+            archive,
+            db,
+            checkpoint: BackfillCheckpoint::load(data_path.as_ref().join("events")),
+        }
+    }
+
+    /// Page through the archive for each kind starting at the checkpoint
+    pub async fn run(
+        &mut self,
+        kinds: &[u32],
+        since: i64,
+        max_events: u32,
+    ) -> Result<BackfillReport> {
+        self.run_with_progress(kinds, since, max_events, true, |_| {})
+            .await
+    }
+
+    /// Same as [`Backfill::run`], calling `progress` after every page.
+    /// With `resume` false the checkpoint is ignored and the archive is read from `since`.
+    pub async fn run_with_progress<F: FnMut(&BackfillReport)>(
+        &mut self,
+        kinds: &[u32],
+        since: i64,
+        max_events: u32,
+        resume: bool,
+        mut progress: F,
+    ) -> Result<BackfillReport> {
+        {
+            let mut status = STATUS.write();
+            if status.running {
+                return Err(anyhow::anyhow!("backfill already running"));
+            }
+            status.running = true;
+        }
+        let mut report = BackfillReport {
+            started_at: chrono::Utc::now().timestamp(),
+            ..Default::default()
+        };
+
+        let result = self
+            .run_inner(kinds, since, max_events, resume, &mut report, &mut progress)
+            .await;
+        report.finished_at = chrono::Utc::now().timestamp();
+        if let Err(e) = &result {
+            report.error = Some(e.to_string());
+        }
+
+        counter!("mls_gateway_backfill_events", "result" => "inserted")
+            .increment(report.inserted as u64);
+        counter!("mls_gateway_backfill_events", "result" => "duplicate")
+            .increment(report.duplicate as u64);
+        counter!("mls_gateway_backfill_events", "result" => "invalid")
+            .increment(report.invalid as u64);
+        counter!("mls_gateway_backfill_events", "result" => "ignored")
+            .increment(report.ignored as u64);
+        {
+            let mut status = STATUS.write();
+            status.running = false;
+            status.runs += 1;
+            status.total_inserted += report.inserted as u64;
+            status.total_duplicate += report.duplicate as u64;
+            status.total_invalid += report.invalid as u64;
+            status.last_run = Some(report.clone());
+        }
+        self.checkpoint.last_report = Some(report.clone());
+        if let Err(e) = self.checkpoint.save() {
+            warn!("Failed to save backfill checkpoint: {}", e);
+        }
+
+        result.map(|_| report)
+    }
+
+    async fn run_inner<F: FnMut(&BackfillReport)>(
+        &mut self,
+        kinds: &[u32],
+        since: i64,
+        max_events: u32,
+        resume: bool,
+        report: &mut BackfillReport,
+        progress: &mut F,
+    ) -> Result<()> {
+        for &kind in kinds {
+            let mut cursor = if resume {
+                self.checkpoint.cursor(kind, since)
+            } else {
+                None
+            };
+            loop {
+                let remaining = (max_events as usize).saturating_sub(report.fetched);
+                if remaining == 0 {
+                    info!("Backfill reached max_events={}", max_events);
+                    return Ok(());
+                }
+                let page = self
+                    .archive
+                    .list_events_page(
+                        kind,
+                        since,
+                        None,
+                        cursor.take(),
+                        PAGE_SIZE.min(remaining as u32),
+                    )
+                    .await?;
+                let Some(last) = page.last() else {
+                    break;
+                };
+                let next = (last.created_at() as i64, last.id_str());
+                let len = page.len();
+                let stats = self.db.batch_put_stats(page)?;
+                report.fetched += len;
+                report.add(&stats);
+                debug!("Backfill kind {}: {:?}", kind, stats);
+                progress(report);
+
+                self.checkpoint.update(kind, next.0, next.1.clone());
+                cursor = Some(next);
+                if len < PAGE_SIZE as usize {
+                    break;
+                }
+            }
+            if let Err(e) = self.checkpoint.save() {
+                warn!("Failed to save backfill checkpoint: {}", e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Keep pulling newly archived events (e.g. written by other instances) on an interval
+    pub fn spawn_periodic(
+        mut self,
+        kinds: Vec<u32>,
+        ttl_days: u32,
+        max_events: u32,
+        interval: Duration,
+    ) {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            // the first tick completes immediately, startup backfill already ran
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                let since = chrono::Utc::now().timestamp() - (ttl_days as i64) * 86_400;
+                match self.run(&kinds, since, max_events).await {
+                    Ok(report) if report.inserted > 0 => {
+                        info!("Incremental backfill wrote {} events", report.inserted)
+                    }
+                    Ok(_) => {}
+                    Err(e) => warn!("Incremental backfill failed: {}", e),
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checkpoint_roundtrip() -> Result<()> {
+        let dir = crate::temp_data_path("backfill_checkpoint")?;
+        let mut checkpoint = BackfillCheckpoint::load(dir.path());
+        assert!(checkpoint.kinds.is_empty());
+
+        checkpoint.update(445, 100, "aa".to_string());
+        checkpoint.update(445, 90, "bb".to_string());
+        checkpoint.save()?;
+
+        let loaded = BackfillCheckpoint::load(dir.path());
+        assert_eq!(loaded.cursor(445, 50), Some((100, "aa".to_string())));
+        // checkpoints older than the TTL window are ignored
+        assert_eq!(loaded.cursor(445, 200), None);
+        assert_eq!(loaded.cursor(1059, 0), None);
+        Ok(())
+    }
+}
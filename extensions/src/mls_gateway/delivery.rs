@@ -0,0 +1,206 @@
+//! Recipient-scoped and roster-gated delivery of live events
+//!
+//! With `recipient_only_delivery`, giftwraps (1059) are only broadcast to
+//! sessions authenticated (NIP-42) as their `p` recipient and direct messages
+//! (Noise DMs (446) and other direct `archived_kinds`) to their recipients or
+//! author, so subscribers cannot harvest who is being welcomed into groups.
+//!
+//! With `roster_gated_delivery`, group messages (445) of groups with a roster
+//! (kind 450) are only broadcast to authenticated roster members and the group
+//! owner. Rosters are cached per instance: roster events handled here update
+//! the cache, other groups are loaded from storage the first time one of their
+//! messages is received and refreshed every [`REFRESH_INTERVAL`]. Messages of a
+//! group whose roster is not loaded yet are delivered.
+
+use super::groups::GroupRegistry;
+use super::StorageBackend;
+use anyhow::Result;
+use metrics::counter;
+use nostr_relay::db::Event;
+use once_cell::sync::Lazy;
+use parking_lot::RwLock;
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
+use tracing::warn;
+
+/// Interval between roster reloads from storage
+const REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// group id -> allowed pubkeys, `None` for groups without a roster
+static ROSTERS: Lazy<RwLock<HashMap<String, Option<HashSet<String>>>>> =
+    Lazy::new(|| RwLock::new(HashMap::new()));
+
+fn tag_values<'a>(event: &'a Event, name: &'a str) -> impl Iterator<Item = &'a String> {
+    event
+        .tags()
+        .iter()
+        .filter(move |tag| tag.len() >= 2 && tag[0] == name)
+        .map(|tag| &tag[1])
+}
+
+/// Group id (`h` tag) of an event
+pub fn group_id(event: &Event) -> Option<&String> {
+    tag_values(event, "h").next()
+}
+
+/// Whether a session authenticated as `pubkey` may see a giftwrap (1059) or a direct message
+pub fn visible(event: &Event, pubkey: Option<&String>, direct: bool) -> bool {
+    let giftwrap = super::kinds::canonical(event.kind()) == super::GIFTWRAP_KIND;
+    if !giftwrap && !direct {
+        return true;
+    }
+    let Some(pubkey) = pubkey else {
+        return false;
+    };
+    // giftwraps are signed by a throwaway key, only the recipient counts
+    (!giftwrap && &event.pubkey_str() == pubkey) || tag_values(event, "p").any(|p| p == pubkey)
+}
+
+/// Whether `pubkey` may receive messages of a group, `None` if the roster is not loaded
+pub fn roster_allows(group_id: &str, pubkey: Option<&String>) -> Option<bool> {
+    let rosters = ROSTERS.read();
+    match rosters.get(group_id)? {
+        Some(allowed) => Some(pubkey.is_some_and(|p| allowed.contains(p))),
+        None => Some(true),
+    }
+}
+
+/// Pubkeys allowed to receive messages of a group with this roster history
+fn allowed(
+    owner: Option<&String>,
+    history: &[super::firestore::RosterPolicyDocument],
+) -> HashSet<String> {
+    let mut allowed: HashSet<String> = GroupRegistry::members(history).into_iter().collect();
+    allowed.extend(owner.cloned());
+    allowed
+}
+
+/// Replay the stored roster of a group into the cache
+pub async fn load(store: &StorageBackend, group_id: &str) -> Result<()> {
+    let history = store.list_roster_history(group_id).await?;
+    let roster = if history.is_empty() {
+        None
+    } else {
+        // the latest transfer names the owner, before that the bootstrapping admin
+        let owner = history
+            .iter()
+            .rev()
+            .find_map(|r| match r.operation.as_str() {
+                "transfer" => r.member_pubkeys.first(),
+                "bootstrap" => Some(&r.admin_pubkey),
+                _ => None,
+            });
+        Some(allowed(owner, &history))
+    };
+    ROSTERS.write().insert(group_id.to_string(), roster);
+    Ok(())
+}
+
+/// Load the roster of a group not seen before on this instance
+pub fn ensure_loaded(store: &StorageBackend, group_id: &str) {
+    if ROSTERS.read().contains_key(group_id) {
+        return;
+    }
+    counter!("mls_gateway_roster_cache_miss").increment(1);
+    let store = store.clone();
+    let group_id = group_id.to_string();
+    tokio::spawn(async move {
+        if let Err(e) = load(&store, &group_id).await {
+            warn!("Failed to load roster of group {}: {}", group_id, e);
+        }
+    });
+}
+
+/// Reload the cached rosters
+pub fn spawn_refresh(store: StorageBackend) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(REFRESH_INTERVAL);
+        loop {
+            interval.tick().await;
+            let groups: Vec<String> = ROSTERS.read().keys().cloned().collect();
+            for group_id in groups {
+                if let Err(e) = load(&store, &group_id).await {
+                    warn!("Failed to reload roster of group {}: {}", group_id, e);
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mls_gateway::firestore::RosterPolicyDocument;
+    use nostr_relay::db::secp256k1::{Keypair, SECP256K1};
+
+    fn event(kind: u16, tags: Vec<Vec<String>>) -> Result<Event> {
+        let key = Keypair::from_seckey_str(SECP256K1, &"09".repeat(32))?;
+        Ok(Event::create(
+            &key,
+            nostr_relay::db::now(),
+            kind,
+            tags,
+            "x".to_owned(),
+        )?)
+    }
+
+    fn record(sequence: u64, operation: &str, members: &[&str]) -> RosterPolicyDocument {
+        RosterPolicyDocument {
+            group_id: "g".to_owned(),
+            sequence,
+            operation: operation.to_owned(),
+            member_pubkeys: members.iter().map(|m| m.to_string()).collect(),
+            admin_pubkey: "owner".to_owned(),
+            created_at: 0,
+            updated_at: 0,
+        }
+    }
+
+    #[test]
+    fn recipient_visibility() -> Result<()> {
+        let alice = "aa".repeat(32);
+        let bob = "bb".repeat(32);
+        let giftwrap = event(1059, vec![vec!["p".to_owned(), alice.clone()]])?;
+        assert!(visible(&giftwrap, Some(&alice), false));
+        assert!(!visible(&giftwrap, Some(&bob), false));
+        assert!(!visible(&giftwrap, Some(&giftwrap.pubkey_str()), false));
+        assert!(!visible(&giftwrap, None, false));
+
+        let dm = event(446, vec![vec!["p".to_owned(), alice.clone()]])?;
+        assert!(visible(&dm, Some(&dm.pubkey_str()), true));
+        assert!(visible(&dm, Some(&alice), true));
+        assert!(!visible(&dm, Some(&bob), true));
+        assert!(!visible(&dm, None, true));
+        assert!(visible(&event(445, vec![])?, None, false));
+        Ok(())
+    }
+
+    #[test]
+    fn roster_gate() {
+        let history = vec![
+            record(1, "bootstrap", &["alice"]),
+            record(2, "add", &["bob"]),
+            record(3, "remove", &["alice"]),
+        ];
+        let owner = "owner".to_owned();
+        ROSTERS
+            .write()
+            .insert("g-roster".to_owned(), Some(allowed(Some(&owner), &history)));
+        ROSTERS.write().insert("g-open".to_owned(), None);
+
+        assert_eq!(
+            roster_allows("g-roster", Some(&"bob".to_owned())),
+            Some(true)
+        );
+        assert_eq!(roster_allows("g-roster", Some(&owner)), Some(true));
+        assert_eq!(
+            roster_allows("g-roster", Some(&"alice".to_owned())),
+            Some(false)
+        );
+        assert_eq!(roster_allows("g-roster", None), Some(false));
+        assert_eq!(roster_allows("g-open", None), Some(true));
+        assert_eq!(roster_allows("g-unknown", None), None);
+    }
+}
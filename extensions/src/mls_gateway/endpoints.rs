@@ -1,13 +1,13 @@
 //! REST API endpoints for MLS Gateway mailbox services
 
+use super::message_archive::MessageArchive;
 use actix_web::{web, HttpResponse, Result as ActixResult};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use super::message_archive::MessageArchive;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct MissedMessagesRequest {
-    pub since: i64,  // Unix timestamp
+    pub since: i64, // Unix timestamp
     pub pubkey: String,
     pub limit: Option<u32>,
 }
@@ -132,21 +132,27 @@ async fn get_missed_messages(req: web::Json<MissedMessagesRequest>) -> ActixResu
 
     let limit = req.limit.unwrap_or(100).min(500); // Max 500 messages per request
 
-    match archive.get_missed_messages(&req.pubkey, req.since, limit).await {
+    match archive
+        .get_missed_messages(&req.pubkey, req.since, limit)
+        .await
+    {
         Ok(events) => {
-            let messages: Vec<ArchivedMessage> = events.into_iter().map(|event| {
-                ArchivedMessage {
+            let messages: Vec<ArchivedMessage> = events
+                .into_iter()
+                .map(|event| ArchivedMessage {
                     id: hex::encode(event.id()),
                     kind: event.kind() as u32,
                     content: event.content().to_string(),
-                    tags: event.tags().iter().map(|tag| {
-                        tag.iter().map(|s| s.to_string()).collect()
-                    }).collect(),
+                    tags: event
+                        .tags()
+                        .iter()
+                        .map(|tag| tag.iter().map(|s| s.to_string()).collect())
+                        .collect(),
                     created_at: event.created_at() as i64,
                     pubkey: hex::encode(event.pubkey()),
                     sig: hex::encode(event.sig()),
-                }
-            }).collect();
+                })
+                .collect();
 
             let count = messages.len() as u32;
             let has_more = count >= limit;
@@ -157,11 +163,9 @@ async fn get_missed_messages(req: web::Json<MissedMessagesRequest>) -> ActixResu
                 has_more,
             }))
         }
-        Err(e) => {
-            Ok(HttpResponse::InternalServerError().json(json!({
-                "error": format!("Failed to retrieve missed messages: {}", e)
-            })))
-        }
+        Err(e) => Ok(HttpResponse::InternalServerError().json(json!({
+            "error": format!("Failed to retrieve missed messages: {}", e)
+        }))),
     }
 }
 
@@ -177,21 +181,27 @@ async fn get_group_messages(req: web::Json<GroupMessagesRequest>) -> ActixResult
 
     let limit = req.limit.unwrap_or(100).min(500); // Max 500 messages per request
 
-    match archive.get_group_messages(&req.group_id, req.since, limit).await {
+    match archive
+        .get_group_messages(&req.group_id, req.since, limit)
+        .await
+    {
         Ok(events) => {
-            let messages: Vec<ArchivedMessage> = events.into_iter().map(|event| {
-                ArchivedMessage {
+            let messages: Vec<ArchivedMessage> = events
+                .into_iter()
+                .map(|event| ArchivedMessage {
                     id: hex::encode(event.id()),
                     kind: event.kind() as u32,
                     content: event.content().to_string(),
-                    tags: event.tags().iter().map(|tag| {
-                        tag.iter().map(|s| s.to_string()).collect()
-                    }).collect(),
+                    tags: event
+                        .tags()
+                        .iter()
+                        .map(|tag| tag.iter().map(|s| s.to_string()).collect())
+                        .collect(),
                     created_at: event.created_at() as i64,
                     pubkey: hex::encode(event.pubkey()),
                     sig: hex::encode(event.sig()),
-                }
-            }).collect();
+                })
+                .collect();
 
             let count = messages.len() as u32;
             let has_more = count >= limit;
@@ -202,10 +212,8 @@ async fn get_group_messages(req: web::Json<GroupMessagesRequest>) -> ActixResult
                 has_more,
             }))
         }
-        Err(e) => {
-            Ok(HttpResponse::InternalServerError().json(json!({
-                "error": format!("Failed to retrieve group messages: {}", e)
-            })))
-        }
+        Err(e) => Ok(HttpResponse::InternalServerError().json(json!({
+            "error": format!("Failed to retrieve group messages: {}", e)
+        }))),
     }
 }
@@ -0,0 +1,291 @@
+//! Firestore -> object storage export of archived events
+//!
+//! Firestore keeps archived events for `message_archive_ttl_days`. This job
+//! writes them, one gzipped jsonl object per kind and UTC day, to a GCS
+//! (`gs://bucket`) or S3 (`s3://bucket`) bucket as a long-term compliance
+//! trail. In `expiring` mode a day is exported shortly before its events
+//! expire from Firestore, in `all` mode once the day is over.
+//!
+//! Object names are derived from the kind and day, so instances exporting the
+//! same day write the same object. Progress is kept in a checkpoint object next
+//! to the export, and with `export_retention_days` days past the retention are
+//! deleted again.
+
+use super::message_archive::MessageArchive;
+use crate::object_store::ObjectStore;
+use anyhow::Result;
+use chrono::{DateTime, Datelike, Utc};
+use flate2::{write::GzEncoder, Compression};
+use metrics::counter;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, io::Write, time::Duration};
+use tracing::{debug, info, warn};
+
+/// Firestore page size
+const PAGE_SIZE: u32 = 500;
+
+/// Days before their expiry that events are exported in `expiring` mode
+const EXPIRY_LEAD_DAYS: i64 = 2;
+
+/// Time after the end of a day before it is exported in `all` mode, late archive writes land meanwhile
+const SETTLE_SECS: i64 = 3600;
+
+/// Checkpoint object name, relative to the prefix
+const CHECKPOINT_OBJECT: &str = "export_checkpoint.json";
+
+const DAY: i64 = 86_400;
+
+/// Which archived events are exported
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportMode {
+    /// days about to expire from Firestore
+    #[default]
+    Expiring,
+    /// every day once it is over
+    All,
+}
+
+/// Export settings, read from `[extensions.mls_gateway]`
+#[derive(Debug, Clone)]
+pub struct ExportConfig {
+    /// `gs://bucket[/prefix]` or `s3://bucket[/prefix]`
+    pub bucket: String,
+    /// object name below the prefix, with `{kind}`, `{yyyy}`, `{mm}` and `{dd}`
+    pub layout: String,
+    pub mode: ExportMode,
+    pub kinds: Vec<u32>,
+    pub ttl_days: u32,
+    /// days kept in the bucket, 0 keeps them
+    pub retention_days: u32,
+    /// S3 region and endpoint, the endpoint defaults to AWS
+    pub s3_region: String,
+    pub s3_endpoint: Option<String>,
+}
+
+/// Exported days, per kind the next day to export
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExportCheckpoint {
+    /// kind -> next day (days since the epoch)
+    pub next: HashMap<u32, i64>,
+    /// oldest day that may still have objects in the bucket
+    pub oldest: Option<i64>,
+}
+
+/// Result of an export run
+#[derive(Debug, Clone, Default)]
+pub struct ExportReport {
+    pub days: usize,
+    pub objects: usize,
+    pub events: usize,
+    pub deleted: usize,
+}
+
+/// Export job
+pub struct Exporter {
+    archive: MessageArchive,
+    store: ObjectStore,
+    config: ExportConfig,
+}
+
+/// Object name of a kind and day
+fn object_name(layout: &str, kind: u32, day: i64) -> String {
+    let date = DateTime::<Utc>::from_timestamp(day * DAY, 0).unwrap_or_default();
+    layout
+        .replace("{kind}", &kind.to_string())
+        .replace("{yyyy}", &format!("{:04}", date.year()))
+        .replace("{mm}", &format!("{:02}", date.month()))
+        .replace("{dd}", &format!("{:02}", date.day()))
+}
+
+/// Days `[first, end)` to export at `now`, the first is the oldest day still in Firestore
+fn eligible_days(mode: ExportMode, ttl_days: u32, now: i64) -> (i64, i64) {
+    let today = now.div_euclid(DAY);
+    let first = today - ttl_days as i64;
+    let end = match mode {
+        ExportMode::All => (now - SETTLE_SECS).div_euclid(DAY),
+        ExportMode::Expiring => (first + EXPIRY_LEAD_DAYS + 1).min(today),
+    };
+    (first, end)
+}
+
+/// Gzipped jsonl of events
+fn compress(lines: &[String]) -> Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    for line in lines {
+        encoder.write_all(line.as_bytes())?;
+        encoder.write_all(b"\n")?;
+    }
+    Ok(encoder.finish()?)
+}
+
+impl ExportConfig {
+    /// Export settings of the gateway, `None` when no bucket is configured
+    pub fn from_gateway(config: &super::MlsGatewayConfig) -> Option<Self> {
+        Some(Self {
+            bucket: config.export_bucket.clone()?,
+            layout: config.export_layout.clone(),
+            mode: config.export_mode,
+            kinds: config.export_kinds.clone(),
+            ttl_days: config.message_archive_ttl_days,
+            retention_days: config.export_retention_days,
+            s3_region: config.export_s3_region.clone(),
+            s3_endpoint: config.export_s3_endpoint.clone(),
+        })
+    }
+}
+
+impl Exporter {
+    pub fn new(archive: MessageArchive, config: ExportConfig) -> Result<Self> {
+        let store = ObjectStore::new(
+            &config.bucket,
+            &config.s3_region,
+            config.s3_endpoint.as_deref(),
+        )?;
+        Ok(Self {
+            archive,
+            store,
+            config,
+        })
+    }
+
+    /// Load the checkpoint, a missing object starts empty
+    pub async fn load_checkpoint(&self) -> Result<ExportCheckpoint> {
+        match self.store.get(CHECKPOINT_OBJECT).await? {
+            Some(body) => Ok(serde_json::from_slice(&body)?),
+            None => Ok(ExportCheckpoint::default()),
+        }
+    }
+
+    async fn save_checkpoint(&self, checkpoint: &ExportCheckpoint) -> Result<()> {
+        self.store
+            .put(CHECKPOINT_OBJECT, serde_json::to_vec(checkpoint)?)
+            .await
+    }
+
+    /// Events of a kind created on a day, as json lines
+    async fn day_events(&self, kind: u32, day: i64) -> Result<Vec<String>> {
+        let mut lines = Vec::new();
+        let mut cursor = None;
+        loop {
+            let page = self
+                .archive
+                .list_events_page(
+                    kind,
+                    day * DAY,
+                    Some(day * DAY + DAY - 1),
+                    cursor,
+                    PAGE_SIZE,
+                )
+                .await?;
+            let Some(last) = page.last() else {
+                break;
+            };
+            cursor = Some((last.created_at() as i64, last.id_str()));
+            let full = page.len() as u32 >= PAGE_SIZE;
+            lines.extend(page.iter().map(|e| e.to_string()));
+            if !full {
+                break;
+            }
+        }
+        Ok(lines)
+    }
+
+    /// Export the eligible days not exported yet and apply the retention
+    pub async fn run_once(&self) -> Result<ExportReport> {
+        let mut report = ExportReport::default();
+        let mut checkpoint = self.load_checkpoint().await?;
+        let (first, end) = eligible_days(
+            self.config.mode,
+            self.config.ttl_days,
+            Utc::now().timestamp(),
+        );
+
+        for &kind in &self.config.kinds {
+            let start = checkpoint
+                .next
+                .get(&kind)
+                .copied()
+                .unwrap_or(first)
+                .max(first);
+            for day in start..end {
+                let lines = self.day_events(kind, day).await?;
+                report.days += 1;
+                if !lines.is_empty() {
+                    let name = object_name(&self.config.layout, kind, day);
+                    self.store.put(&name, compress(&lines)?).await?;
+                    debug!("Exported {} kind {} events to {}", lines.len(), kind, name);
+                    report.objects += 1;
+                    report.events += lines.len();
+                    counter!("mls_gateway_archive_exported", "kind" => kind.to_string())
+                        .increment(lines.len() as u64);
+                    checkpoint.oldest = Some(checkpoint.oldest.map_or(day, |d| d.min(day)));
+                }
+                checkpoint.next.insert(kind, day + 1);
+            }
+            self.save_checkpoint(&checkpoint).await?;
+        }
+
+        if self.config.retention_days > 0 {
+            let cutoff = Utc::now().timestamp().div_euclid(DAY) - self.config.retention_days as i64;
+            if let Some(oldest) = checkpoint.oldest.filter(|d| *d < cutoff) {
+                for day in oldest..cutoff {
+                    for &kind in &self.config.kinds {
+                        self.store
+                            .delete(&object_name(&self.config.layout, kind, day))
+                            .await?;
+                        report.deleted += 1;
+                    }
+                }
+                checkpoint.oldest = Some(cutoff);
+                self.save_checkpoint(&checkpoint).await?;
+            }
+        }
+        Ok(report)
+    }
+
+    /// Run the export on an interval in the background
+    pub fn spawn(self, interval: Duration) {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                match self.run_once().await {
+                    Ok(report) if report.objects > 0 || report.deleted > 0 => info!(
+                        "Archive export: {} days, {} objects with {} events, {} expired objects deleted",
+                        report.days, report.objects, report.events, report.deleted
+                    ),
+                    Ok(report) => debug!("Archive export: {} days, nothing to write", report.days),
+                    Err(e) => {
+                        counter!("mls_gateway_archive_export_failed").increment(1);
+                        warn!("Archive export failed: {}", e);
+                    }
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layout() {
+        // 2025-01-19
+        let day = 1_737_244_800 / DAY;
+        assert_eq!(
+            object_name("kind={kind}/{yyyy}/{mm}/{dd}.jsonl.gz", 445, day),
+            "kind=445/2025/01/19.jsonl.gz"
+        );
+    }
+
+    #[test]
+    fn days() {
+        let now = 100 * DAY + 7200;
+        assert_eq!(eligible_days(ExportMode::All, 30, now), (70, 100));
+        assert_eq!(eligible_days(ExportMode::All, 30, 100 * DAY + 60), (70, 99));
+        assert_eq!(eligible_days(ExportMode::Expiring, 30, now), (70, 73));
+        assert_eq!(eligible_days(ExportMode::Expiring, 1, now), (99, 100));
+    }
+}
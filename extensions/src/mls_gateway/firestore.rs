@@ -1,19 +1,19 @@
 //! Firestore storage implementation for MLS Gateway Extension
-//! 
+//!
 //! Provides Firestore-based storage for:
 //! - Group registry metadata
 //! - Key package mailbox  
 //! - Welcome message mailbox
 //! - TTL-based cleanup
 
-use firestore::*;
-use chrono::{DateTime, Utc};
-use serde::{Deserialize, Serialize};
-use tracing::{info, debug, instrument};
-use metrics::counter;
+use crate::mls_gateway::{roster_quorum, MlsStorage};
 use anyhow::Result;
 use async_trait::async_trait;
-use crate::mls_gateway::MlsStorage;
+use chrono::{DateTime, Utc};
+use firestore::*;
+use metrics::counter;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, instrument};
 
 /// Group metadata stored in the registry
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -41,9 +41,23 @@ struct AdminsPatch {
     pub updated_at: DateTime<Utc>,
 }
 
+/// Helper struct for owner reassignment
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct OwnerPatch {
+    pub owner_pubkey: String,
+    #[serde(with = "chrono::serde::ts_seconds")]
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Helper struct for closing a pending roster operation
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StatusPatch {
+    pub status: String,
+}
+
 /// KeyPackage Relays list document (kind 10051)
 #[derive(Debug, Clone, Serialize, Deserialize)]
-struct KeypackageRelays {
+pub struct KeypackageRelays {
     pub owner_pubkey: String,
     #[serde(default)]
     pub relays: Vec<String>,
@@ -53,7 +67,7 @@ struct KeypackageRelays {
 
 /// KeyPackage document structure for Firestore
 #[derive(Debug, Clone, Serialize, Deserialize)]
-struct KeyPackageDoc {
+pub struct KeyPackageDoc {
     pub event_id: String,
     pub owner_pubkey: String,
     pub content: String,
@@ -64,6 +78,9 @@ struct KeyPackageDoc {
     pub created_at: DateTime<Utc>,
     #[serde(with = "chrono::serde::ts_seconds")]
     pub expires_at: DateTime<Utc>,
+    /// sha256 of the decoded KeyPackage bytes (absent on documents predating it)
+    #[serde(default)]
+    pub content_hash: Option<String>,
 }
 
 /// Pending deletion for last resort keypackage mitigation
@@ -86,6 +103,368 @@ pub struct KeyPackageRequestRateLimit {
     pub request_count: u32,
     #[serde(with = "chrono::serde::ts_seconds")]
     pub window_start: DateTime<Utc>,
+    /// Unix times of the requests in the sliding window
+    #[serde(default)]
+    pub request_times: Vec<i64>,
+}
+
+impl KeyPackageRequestRateLimit {
+    /// Document id of a requester/recipient pair
+    pub fn doc_id(requester_pubkey: &str, recipient_pubkey: &str) -> String {
+        format!("{}:{}", requester_pubkey, recipient_pubkey)
+    }
+}
+
+/// Push notification token registered by a client
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PushToken {
+    pub pubkey: String,
+    pub token: String,
+    /// "fcm" or "apns"
+    pub platform: String,
+    #[serde(with = "chrono::serde::ts_seconds")]
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Giftwrap (1059) tracked by the welcome mailbox until acked or expired
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MailboxWelcome {
+    pub event_id: String,
+    pub recipient: String,
+    /// Giftwrap (ephemeral) author, owner of the archived copy
+    pub author: String,
+    /// When the relay received the giftwrap, its NIP-59 created_at is randomized
+    #[serde(with = "chrono::serde::ts_seconds")]
+    pub created_at: DateTime<Utc>,
+    /// created_at plus welcome_ttl
+    #[serde(with = "chrono::serde::ts_seconds")]
+    pub expires_at: DateTime<Utc>,
+    #[serde(default, with = "chrono::serde::ts_seconds_option")]
+    pub acked_at: Option<DateTime<Utc>>,
+}
+
+/// NIP-SERVICE action_id claimed for execution, replays before expires_at are duplicates
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServiceActionClaim {
+    pub action_id: String,
+    pub client_id: String,
+    pub expires_at: i64,
+}
+
+/// Runtime moderation rule, see `moderation`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ModerationRule {
+    /// "allow" or "deny"
+    pub list: String,
+    /// "pubkey" or "kind"
+    pub target: String,
+    /// Hex pubkey or kind number
+    pub value: String,
+    #[serde(default)]
+    pub reason: Option<String>,
+    #[serde(with = "chrono::serde::ts_seconds")]
+    pub created_at: DateTime<Utc>,
+}
+
+impl ModerationRule {
+    /// Document id of a rule
+    pub fn doc_id(list: &str, target: &str, value: &str) -> String {
+        format!("{}:{}:{}", list, target, value)
+    }
+}
+
+/// Pubkey that presented a valid device attestation token, see `attestation`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AttestationRecord {
+    pub pubkey: String,
+    /// Token issuer
+    pub issuer: String,
+    /// Token subject, e.g. the attested app or device
+    pub subject: String,
+    #[serde(with = "chrono::serde::ts_seconds")]
+    pub attested_at: DateTime<Utc>,
+    #[serde(with = "chrono::serde::ts_seconds")]
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Last event delivered to a subscription of an authenticated pubkey, see `resume`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SubscriptionCursor {
+    pub pubkey: String,
+    pub subscription_id: String,
+    /// created_at of the last delivered event
+    pub created_at: u64,
+    pub event_id: String,
+    #[serde(with = "chrono::serde::ts_seconds")]
+    pub updated_at: DateTime<Utc>,
+    #[serde(with = "chrono::serde::ts_seconds")]
+    pub expires_at: DateTime<Utc>,
+}
+
+impl SubscriptionCursor {
+    /// Document id of a cursor, subscription ids may contain characters not allowed in ids
+    pub fn doc_id(pubkey: &str, subscription_id: &str) -> String {
+        use sha2::{Digest, Sha256};
+        format!(
+            "{}:{}",
+            pubkey,
+            hex::encode(Sha256::digest(subscription_id.as_bytes()))
+        )
+    }
+}
+
+/// Roster/policy event whose sequence skipped ahead (gap) or reused a stored
+/// sequence with different content (fork), kept for forensics
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RosterAnomaly {
+    pub group_id: String,
+    pub sequence: u64,
+    /// Last stored sequence when the event arrived
+    #[serde(default)]
+    pub last_sequence: Option<u64>,
+    /// "gap" or "fork"
+    pub kind: String,
+    /// "rejected" or "flagged"
+    pub action: String,
+    pub event_id: String,
+    pub admin_pubkey: String,
+    pub operation: String,
+    #[serde(default)]
+    pub member_pubkeys: Vec<String>,
+    /// Record already stored at the sequence of a fork
+    #[serde(default)]
+    pub stored: Option<RosterPolicyDocument>,
+    pub created_at: i64,
+}
+
+/// Validated roster/policy change, written with `MlsStorage::apply_roster_operation`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RosterOperation {
+    pub group_id: String,
+    pub sequence: u64,
+    pub operation: String,
+    pub member_pubkeys: Vec<String>,
+    pub admin_pubkey: String,
+    /// `role` tag was "admin", promote/demote then change the group admins
+    pub role_admin: bool,
+    pub created_at: i64,
+}
+
+impl RosterOperation {
+    /// Roster/policy record of the operation
+    pub fn record(&self, now: i64) -> RosterPolicyDocument {
+        RosterPolicyDocument {
+            group_id: self.group_id.clone(),
+            sequence: self.sequence,
+            operation: self.operation.clone(),
+            member_pubkeys: self.member_pubkeys.clone(),
+            admin_pubkey: self.admin_pubkey.clone(),
+            created_at: self.created_at,
+            updated_at: now,
+        }
+    }
+
+    /// Registry entry after the operation, `None` when it is left unchanged
+    pub fn apply(&self, group: Option<GroupInfo>, now: DateTime<Utc>) -> Option<GroupInfo> {
+        let create = || GroupInfo {
+            group_id: self.group_id.clone(),
+            display_name: None,
+            owner_pubkey: self.admin_pubkey.clone(),
+            last_epoch: None,
+            admin_pubkeys: Vec::new(),
+            service_member: false,
+            created_at: now,
+            updated_at: now,
+        };
+        let mut group = match self.operation.as_str() {
+            // bootstrap makes the creator owner and initial admin
+            "bootstrap" => {
+                let mut group = group.unwrap_or_else(create);
+                if !group.admin_pubkeys.contains(&self.admin_pubkey) {
+                    group.admin_pubkeys.push(self.admin_pubkey.clone());
+                }
+                group
+            }
+            "add" | "replace" => group.unwrap_or_else(create),
+            "promote" if self.role_admin && !self.member_pubkeys.is_empty() => {
+                let mut group = group?;
+                for p in &self.member_pubkeys {
+                    if !group.admin_pubkeys.contains(p) {
+                        group.admin_pubkeys.push(p.clone());
+                    }
+                }
+                group
+            }
+            "demote" if self.role_admin && !self.member_pubkeys.is_empty() => {
+                let mut group = group?;
+                group
+                    .admin_pubkeys
+                    .retain(|p| !self.member_pubkeys.contains(p));
+                group
+            }
+            // transfer hands the group to its single `p`, who also becomes an admin
+            "transfer" if self.member_pubkeys.len() == 1 => {
+                let mut group = group?;
+                let owner = self.member_pubkeys[0].clone();
+                if !group.admin_pubkeys.contains(&owner) {
+                    group.admin_pubkeys.push(owner.clone());
+                }
+                group.owner_pubkey = owner;
+                group
+            }
+            _ => return None,
+        };
+        group.updated_at = now;
+        Some(group)
+    }
+}
+
+/// Roster/policy event of an operation that needs a quorum of admins, applied
+/// once enough admins approved it, see `roster_quorum`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PendingRosterOp {
+    /// Id of the proposing roster/policy event
+    pub event_id: String,
+    pub group_id: String,
+    pub sequence: u64,
+    pub operation: String,
+    #[serde(default)]
+    pub member_pubkeys: Vec<String>,
+    /// `role` tag of the proposal was "admin"
+    #[serde(default)]
+    pub role_admin: bool,
+    pub proposer: String,
+    /// Approving admins, the proposer first
+    pub approvals: Vec<String>,
+    pub required: u32,
+    /// "pending", "approved" (being applied), "executed", "expired" or "superseded"
+    pub status: String,
+    pub created_at: i64,
+    pub expires_at: i64,
+    /// Signed proposal event, stored and delivered once the operation is applied
+    #[serde(default)]
+    pub event: Option<String>,
+}
+
+impl PendingRosterOp {
+    /// Add an approval, false if the pubkey already approved
+    pub fn approve(&mut self, pubkey: &str) -> bool {
+        if self.approvals.iter().any(|p| p == pubkey) {
+            return false;
+        }
+        self.approvals.push(pubkey.to_string());
+        true
+    }
+
+    pub fn approved(&self) -> bool {
+        self.approvals.len() as u32 >= self.required
+    }
+}
+
+/// Distinct senders tracked per group, `distinct_senders` saturates here
+pub const MAX_TRACKED_SENDERS: usize = 1000;
+
+/// Commits of a group stats update before a concurrent writer wins
+const GROUP_STATS_ATTEMPTS: u32 = 5;
+
+/// Activity counters of a group, updated on every group message (445)
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GroupStats {
+    pub group_id: String,
+    pub message_count: u64,
+    /// Distinct sender pubkeys, at most `MAX_TRACKED_SENDERS`
+    #[serde(default)]
+    pub senders: Vec<String>,
+    #[serde(default)]
+    pub first_epoch: Option<i64>,
+    #[serde(default)]
+    pub last_epoch: Option<i64>,
+    /// Number of times the epoch advanced
+    #[serde(default)]
+    pub epoch_changes: u64,
+    /// Unix timestamps of the first and latest message seen
+    pub first_activity: i64,
+    pub last_activity: i64,
+}
+
+impl GroupStats {
+    pub fn new(group_id: &str, now: i64) -> Self {
+        Self {
+            group_id: group_id.to_string(),
+            first_activity: now,
+            last_activity: now,
+            ..Default::default()
+        }
+    }
+
+    /// Count a message of `sender` at `epoch`
+    pub fn record(&mut self, sender: &str, epoch: Option<i64>, now: i64) {
+        self.message_count += 1;
+        self.last_activity = self.last_activity.max(now);
+        if self.senders.len() < MAX_TRACKED_SENDERS && !self.senders.iter().any(|s| s == sender) {
+            self.senders.push(sender.to_string());
+        }
+        if let Some(epoch) = epoch {
+            self.first_epoch.get_or_insert(epoch);
+            match self.last_epoch {
+                Some(last) if epoch <= last => {}
+                Some(_) => {
+                    self.epoch_changes += 1;
+                    self.last_epoch = Some(epoch);
+                }
+                None => self.last_epoch = Some(epoch),
+            }
+        }
+    }
+}
+
+/// Everything stored for a pubkey, returned by a data export
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct UserData {
+    pub keypackages: Vec<KeyPackageDoc>,
+    pub keypackage_relays: Vec<String>,
+    pub push_tokens: Vec<PushToken>,
+    /// Giftwraps addressed to the pubkey tracked by the welcome mailbox
+    pub welcomes: Vec<MailboxWelcome>,
+    pub pending_deletion: Option<PendingDeletion>,
+    /// KeyPackage request windows the pubkey requested or was requested in
+    pub keypackage_requests: Vec<KeyPackageRequestRateLimit>,
+    /// Groups the pubkey owns or administers
+    pub groups: Vec<GroupInfo>,
+    /// Roster/policy records naming the pubkey as member or admin
+    pub roster: Vec<RosterPolicyDocument>,
+}
+
+/// Documents removed or changed by a user erasure
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct UserErasure {
+    pub keypackages: usize,
+    pub keypackage_relays: usize,
+    pub push_tokens: usize,
+    pub welcomes: usize,
+    pub pending_deletions: usize,
+    pub keypackage_requests: usize,
+    /// Groups the pubkey was removed from as admin
+    pub admin_roles: usize,
+    /// Roster records the pubkey was removed from as member
+    pub roster_memberships: usize,
+    /// Group activity stats the pubkey was removed from as sender
+    pub group_stats: usize,
+    /// Groups owned by the pubkey, kept with the roster records it signed since the other members depend on them
+    pub retained_groups: Vec<String>,
+}
+
+/// Helper struct for removing a member from a roster record
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RosterMembersPatch {
+    pub member_pubkeys: Vec<String>,
+    pub updated_at: i64,
+}
+
+/// Document id of a push token, tokens may contain characters not allowed in ids
+fn push_token_id(token: &str) -> String {
+    use sha2::{Digest, Sha256};
+    hex::encode(Sha256::digest(token.as_bytes()))
 }
 
 /// Firestore storage implementation
@@ -98,11 +477,11 @@ impl FirestoreStorage {
     /// Create a new Firestore store
     pub async fn new(project_id: &str) -> Result<Self> {
         info!("Connecting to Firestore project: {}", project_id);
-        
+
         let db = FirestoreDb::new(project_id).await?;
-        
+
         info!("Firestore connection established successfully");
-        
+
         Ok(Self { db })
     }
 
@@ -110,17 +489,18 @@ impl FirestoreStorage {
     #[instrument(skip(self))]
     pub async fn migrate(&self) -> Result<()> {
         info!("Initializing Firestore collections");
-        
+
         // Firestore collections are created automatically on first write
         // No migration needed, but we can create index files if needed
-        
+
         info!("Firestore collections initialized successfully");
         Ok(())
     }
 
     /// Fetch a group document by ID
     pub async fn fetch_group(&self, group_id: &str) -> Result<Option<GroupInfo>> {
-        let docs = self.db
+        let docs = self
+            .db
             .fluent()
             .select()
             .from("mls_groups")
@@ -131,9 +511,7 @@ impl FirestoreStorage {
 
         let mut groups: Vec<GroupInfo> = docs
             .into_iter()
-            .filter_map(|doc| {
-                firestore::FirestoreDb::deserialize_doc_to::<GroupInfo>(&doc).ok()
-            })
+            .filter_map(|doc| firestore::FirestoreDb::deserialize_doc_to::<GroupInfo>(&doc).ok())
             .collect();
 
         Ok(groups.pop())
@@ -152,15 +530,31 @@ impl FirestoreStorage {
 
         // Preserve existing owner and created_at if the group already exists
         let existing = self.fetch_group(group_id).await?;
-        let (owner_val, created_at_val, existing_admins, existing_display_name, existing_last_epoch, existing_service_member) = if let Some(g) = existing {
-            (g.owner_pubkey, g.created_at, g.admin_pubkeys, g.display_name, g.last_epoch, g.service_member)
+        let (
+            owner_val,
+            created_at_val,
+            existing_admins,
+            existing_display_name,
+            existing_last_epoch,
+            existing_service_member,
+        ) = if let Some(g) = existing {
+            (
+                g.owner_pubkey,
+                g.created_at,
+                g.admin_pubkeys,
+                g.display_name,
+                g.last_epoch,
+                g.service_member,
+            )
         } else {
             (owner_pubkey.to_string(), now, Vec::new(), None, None, false)
         };
 
         let group = GroupInfo {
             group_id: group_id.to_string(),
-            display_name: display_name.map(|s| s.to_string()).or(existing_display_name),
+            display_name: display_name
+                .map(|s| s.to_string())
+                .or(existing_display_name),
             owner_pubkey: owner_val,
             last_epoch: Some(last_epoch).or(existing_last_epoch),
             admin_pubkeys: existing_admins,
@@ -187,7 +581,8 @@ impl FirestoreStorage {
     /// Get database health status
     pub async fn health_check(&self) -> Result<()> {
         // Simple health check - try to query the database
-        let _result: Vec<GroupInfo> = self.db
+        let _result: Vec<GroupInfo> = self
+            .db
             .fluent()
             .select()
             .from("mls_groups")
@@ -198,32 +593,77 @@ impl FirestoreStorage {
 
         Ok(())
     }
-    
+
+    /// Flag the group as containing the relay service member, creating the registry entry if needed
+    #[instrument(skip(self))]
+    pub async fn set_service_member(&self, group_id: &str, inviter_pubkey: &str) -> Result<()> {
+        let now = Utc::now();
+        let group = match self.fetch_group(group_id).await? {
+            Some(g) => GroupInfo {
+                service_member: true,
+                updated_at: now,
+                ..g
+            },
+            None => GroupInfo {
+                group_id: group_id.to_string(),
+                display_name: None,
+                owner_pubkey: inviter_pubkey.to_string(),
+                last_epoch: None,
+                admin_pubkeys: Vec::new(),
+                service_member: true,
+                created_at: now,
+                updated_at: now,
+            },
+        };
+
+        self.db
+            .fluent()
+            .update()
+            .fields(paths!(GroupInfo::{group_id, display_name, owner_pubkey, last_epoch, admin_pubkeys, service_member, created_at, updated_at}))
+            .in_col("mls_groups")
+            .document_id(group_id)
+            .object(&group)
+            .execute::<()>()
+            .await?;
+
+        info!("Flagged service member in group registry: {}", group_id);
+        Ok(())
+    }
+
     /// Returns true if the group is flagged to contain a service member
     pub async fn has_service_member(&self, group_id: &str) -> Result<bool> {
-        Ok(self.fetch_group(group_id).await?.map(|g| g.service_member).unwrap_or(false))
+        Ok(self
+            .fetch_group(group_id)
+            .await?
+            .map(|g| g.service_member)
+            .unwrap_or(false))
     }
-    
+
     /// Clean up expired keypackages and enforce per-user limits - should be run daily
     pub async fn cleanup_expired_keypackages(&self, max_per_user: u32) -> Result<u32> {
         let now = Utc::now();
-        info!("Starting keypackage cleanup - removing expired and enforcing {} per user limit", max_per_user);
-        
+        info!(
+            "Starting keypackage cleanup - removing expired and enforcing {} per user limit",
+            max_per_user
+        );
+
         let mut total_deleted = 0;
-        
+
         // Step 1: Delete expired keypackages
-        let expired_docs = self.db
+        let expired_docs = self
+            .db
             .fluent()
             .select()
             .from("mls_keypackages")
             .filter(|f| f.field("expires_at").less_than_or_equal(now))
             .query()
             .await?;
-        
+
         for doc in expired_docs {
             if let Ok(kp) = firestore::FirestoreDb::deserialize_doc_to::<KeyPackageDoc>(&doc) {
                 // Delete the expired keypackage
-                if let Ok(_) = self.db
+                if let Ok(_) = self
+                    .db
                     .fluent()
                     .delete()
                     .from("mls_keypackages")
@@ -232,58 +672,70 @@ impl FirestoreStorage {
                     .await
                 {
                     total_deleted += 1;
-                    info!("Deleted expired keypackage {} for owner {}", kp.event_id, kp.owner_pubkey);
+                    info!(
+                        "Deleted expired keypackage {} for owner {}",
+                        kp.event_id, kp.owner_pubkey
+                    );
                 }
             }
         }
-        
+
         // Step 2: Enforce per-user limits by pruning oldest keypackages
         let pruned = self.prune_excess_keypackages(max_per_user).await?;
         total_deleted += pruned;
-        
-        info!("Cleanup complete: deleted {} total keypackages ({} expired, {} pruned for limits)",
-              total_deleted, total_deleted - pruned, pruned);
+
+        info!(
+            "Cleanup complete: deleted {} total keypackages ({} expired, {} pruned for limits)",
+            total_deleted,
+            total_deleted - pruned,
+            pruned
+        );
         Ok(total_deleted)
     }
-    
+
     /// Prune excess keypackages to enforce per-user limits
     async fn prune_excess_keypackages(&self, max_per_user: u32) -> Result<u32> {
         // Get all keypackages grouped by owner to find those over limit
-        let all_docs = self.db
+        let all_docs = self
+            .db
             .fluent()
             .select()
             .from("mls_keypackages")
             .query()
             .await?;
-        
+
         // Group by owner_pubkey
         let mut keypackages_by_owner: std::collections::HashMap<String, Vec<KeyPackageDoc>> =
             std::collections::HashMap::new();
-        
+
         for doc in all_docs {
             if let Ok(kp) = firestore::FirestoreDb::deserialize_doc_to::<KeyPackageDoc>(&doc) {
-                keypackages_by_owner.entry(kp.owner_pubkey.clone())
+                keypackages_by_owner
+                    .entry(kp.owner_pubkey.clone())
                     .or_insert_with(Vec::new)
                     .push(kp);
             }
         }
-        
+
         let mut pruned = 0;
-        
+
         // Process each owner who has too many keypackages
         for (owner_pubkey, mut keypackages) in keypackages_by_owner {
             let count = keypackages.len();
             if count > max_per_user as usize {
                 let to_delete = count - max_per_user as usize;
-                info!("User {} has {} keypackages, pruning {} oldest ones",
-                      owner_pubkey, count, to_delete);
-                
+                info!(
+                    "User {} has {} keypackages, pruning {} oldest ones",
+                    owner_pubkey, count, to_delete
+                );
+
                 // Sort by created_at ascending (oldest first)
                 keypackages.sort_by_key(|kp| kp.created_at);
-                
+
                 // Delete the oldest ones
                 for kp in keypackages.into_iter().take(to_delete) {
-                    if let Ok(_) = self.db
+                    if let Ok(_) = self
+                        .db
                         .fluent()
                         .delete()
                         .from("mls_keypackages")
@@ -292,17 +744,20 @@ impl FirestoreStorage {
                         .await
                     {
                         pruned += 1;
-                        debug!("Pruned old keypackage {} for user {}", kp.event_id, owner_pubkey);
+                        debug!(
+                            "Pruned old keypackage {} for user {}",
+                            kp.event_id, owner_pubkey
+                        );
                     }
                 }
             }
         }
-        
+
         if pruned > 0 {
             info!("Pruned {} keypackages to enforce per-user limits", pruned);
             counter!("mls_gateway_keypackages_pruned_for_limit").increment(pruned as u64);
         }
-        
+
         Ok(pruned)
     }
 
@@ -316,19 +771,25 @@ impl FirestoreStorage {
             .object(pending)
             .execute::<()>()
             .await?;
-        
-        info!("Created pending deletion for user {} to delete keypackage {} at {:?}",
-              pending.user_pubkey, pending.old_keypackage_id, pending.deletion_scheduled_at);
+
+        info!(
+            "Created pending deletion for user {} to delete keypackage {} at {:?}",
+            pending.user_pubkey, pending.old_keypackage_id, pending.deletion_scheduled_at
+        );
         Ok(())
     }
 
     /// Get pending deletion for a user
     pub async fn get_pending_deletion(&self, user_pubkey: &str) -> Result<Option<PendingDeletion>> {
-        let docs = self.db
+        let docs = self
+            .db
             .fluent()
             .select()
             .from("mls_pending_deletions")
-            .filter(|f| f.field(firestore::path!(PendingDeletion::user_pubkey)).eq(user_pubkey))
+            .filter(|f| {
+                f.field(firestore::path!(PendingDeletion::user_pubkey))
+                    .eq(user_pubkey)
+            })
             .limit(1)
             .query()
             .await?;
@@ -351,7 +812,7 @@ impl FirestoreStorage {
             .object(pending)
             .execute::<()>()
             .await?;
-        
+
         Ok(())
     }
 
@@ -364,7 +825,7 @@ impl FirestoreStorage {
             .document_id(user_pubkey)
             .execute()
             .await?;
-        
+
         info!("Deleted pending deletion record for user {}", user_pubkey);
         Ok(())
     }
@@ -378,14 +839,15 @@ impl FirestoreStorage {
             .document_id(event_id)
             .execute()
             .await?;
-        
+
         info!("Deleted keypackage {}", event_id);
         Ok(())
     }
 
     /// Check if a keypackage exists
     pub async fn keypackage_exists(&self, event_id: &str) -> Result<bool> {
-        let docs = self.db
+        let docs = self
+            .db
             .fluent()
             .select()
             .from("mls_keypackages")
@@ -393,65 +855,349 @@ impl FirestoreStorage {
             .limit(1)
             .query()
             .await?;
-        
+
         Ok(!docs.is_empty())
     }
 
+    /// Get the owner of a keypackage
+    pub async fn get_keypackage_owner(&self, event_id: &str) -> Result<Option<String>> {
+        let doc: Option<KeyPackageDoc> = self
+            .db
+            .fluent()
+            .select()
+            .by_id_in("mls_keypackages")
+            .obj()
+            .one(event_id)
+            .await?;
+        Ok(doc.map(|d| d.owner_pubkey))
+    }
+
+    /// Register a push token for a pubkey, a token moves to the latest pubkey registering it
+    pub async fn upsert_push_token(&self, pubkey: &str, token: &str, platform: &str) -> Result<()> {
+        let doc = PushToken {
+            pubkey: pubkey.to_string(),
+            token: token.to_string(),
+            platform: platform.to_string(),
+            updated_at: Utc::now(),
+        };
+        self.db
+            .fluent()
+            .update()
+            .in_col("push_tokens")
+            .document_id(push_token_id(token))
+            .object(&doc)
+            .execute::<()>()
+            .await?;
+        debug!("Registered {} push token for {}", platform, pubkey);
+        Ok(())
+    }
+
+    /// Push tokens registered for a pubkey
+    pub async fn get_push_tokens(&self, pubkey: &str) -> Result<Vec<PushToken>> {
+        let docs = self
+            .db
+            .fluent()
+            .select()
+            .from("push_tokens")
+            .filter(|f| f.field("pubkey").eq(pubkey))
+            .query()
+            .await?;
+        Ok(docs
+            .into_iter()
+            .filter_map(|doc| firestore::FirestoreDb::deserialize_doc_to::<PushToken>(&doc).ok())
+            .collect())
+    }
+
+    /// Remove a push token, returns false when it is not registered to the pubkey
+    pub async fn delete_push_token(&self, pubkey: Option<&str>, token: &str) -> Result<bool> {
+        let id = push_token_id(token);
+        if let Some(pubkey) = pubkey {
+            let doc: Option<PushToken> = self
+                .db
+                .fluent()
+                .select()
+                .by_id_in("push_tokens")
+                .obj()
+                .one(&id)
+                .await?;
+            if doc.map_or(true, |d| d.pubkey != pubkey) {
+                return Ok(false);
+            }
+        }
+        self.db
+            .fluent()
+            .delete()
+            .from("push_tokens")
+            .document_id(&id)
+            .execute()
+            .await?;
+        Ok(true)
+    }
+
     /// Get all pending deletions that should be processed
     pub async fn get_expired_pending_deletions(&self) -> Result<Vec<PendingDeletion>> {
         let now = Utc::now();
-        let docs = self.db
+        let docs = self
+            .db
             .fluent()
             .select()
             .from("mls_pending_deletions")
-            .filter(|f| f.field("deletion_scheduled_at").less_than_or_equal(now.timestamp()))
+            .filter(|f| {
+                f.field("deletion_scheduled_at")
+                    .less_than_or_equal(now.timestamp())
+            })
             .query()
             .await?;
 
         let mut expired = Vec::new();
         for doc in docs {
-            if let Ok(pending) = firestore::FirestoreDb::deserialize_doc_to::<PendingDeletion>(&doc) {
+            if let Ok(pending) = firestore::FirestoreDb::deserialize_doc_to::<PendingDeletion>(&doc)
+            {
                 expired.push(pending);
             }
         }
-        
-        Ok(expired)
-    }
-}
 
-#[async_trait]
-impl MlsStorage for FirestoreStorage {
-    async fn migrate(&self) -> anyhow::Result<()> {
-        self.migrate().await
-    }
-    
-    async fn upsert_group(
-        &self,
-        group_id: &str,
-        display_name: Option<&str>,
-        creator_pubkey: &str,
-        epoch: Option<i64>,
-    ) -> anyhow::Result<()> {
-        self.upsert_group(group_id, display_name, creator_pubkey, epoch.unwrap_or(0)).await
-    }
-    
-    async fn health_check(&self) -> anyhow::Result<()> {
-        self.health_check().await
+        Ok(expired)
     }
 
-    async fn group_exists(&self, group_id: &str) -> anyhow::Result<bool> {
-        let docs = self.db
+    /// Documents of a collection whose `field` equals `value`
+    async fn find_eq<T>(&self, collection: &str, field: &str, value: &str) -> Result<Vec<T>>
+    where
+        T: for<'de> Deserialize<'de> + Send,
+    {
+        Ok(self
+            .db
             .fluent()
             .select()
-            .from("mls_groups")
-            .filter(|f| f.field("group_id").eq(group_id))
-            .limit(1)
+            .from(collection)
+            .filter(|f| f.field(field).eq(value))
+            .obj()
             .query()
-            .await?;
-        Ok(!docs.is_empty())
+            .await?)
     }
 
-    async fn is_owner(&self, group_id: &str, pubkey: &str) -> anyhow::Result<bool> {
+    /// Documents of a collection whose array `field` contains `value`
+    async fn find_contains<T>(&self, collection: &str, field: &str, value: &str) -> Result<Vec<T>>
+    where
+        T: for<'de> Deserialize<'de> + Send,
+    {
+        Ok(self
+            .db
+            .fluent()
+            .select()
+            .from(collection)
+            .filter(|f| f.field(field).array_contains(value))
+            .obj()
+            .query()
+            .await?)
+    }
+
+    async fn delete_doc(&self, collection: &str, id: &str) -> Result<()> {
+        self.db
+            .fluent()
+            .delete()
+            .from(collection)
+            .document_id(id)
+            .execute()
+            .await?;
+        Ok(())
+    }
+
+    /// Everything stored for a pubkey, for a data export
+    #[instrument(skip(self))]
+    pub async fn user_data(&self, pubkey: &str) -> Result<UserData> {
+        let mut keypackage_requests: Vec<KeyPackageRequestRateLimit> = self
+            .find_eq(
+                "mls_keypackage_request_rate_limits",
+                "requester_pubkey",
+                pubkey,
+            )
+            .await?;
+        keypackage_requests.extend(
+            self.find_eq::<KeyPackageRequestRateLimit>(
+                "mls_keypackage_request_rate_limits",
+                "recipient_pubkey",
+                pubkey,
+            )
+            .await?
+            .into_iter()
+            .filter(|r| r.requester_pubkey != pubkey),
+        );
+
+        let mut groups: Vec<GroupInfo> = self.find_eq("mls_groups", "owner_pubkey", pubkey).await?;
+        for group in self
+            .find_contains::<GroupInfo>("mls_groups", "admin_pubkeys", pubkey)
+            .await?
+        {
+            if !groups.iter().any(|g| g.group_id == group.group_id) {
+                groups.push(group);
+            }
+        }
+
+        let mut roster: Vec<RosterPolicyDocument> = self
+            .find_contains("roster_policy", "member_pubkeys", pubkey)
+            .await?;
+        for record in self
+            .find_eq::<RosterPolicyDocument>("roster_policy", "admin_pubkey", pubkey)
+            .await?
+        {
+            if !roster
+                .iter()
+                .any(|r| r.group_id == record.group_id && r.sequence == record.sequence)
+            {
+                roster.push(record);
+            }
+        }
+        roster.sort_by(|a, b| (&a.group_id, a.sequence).cmp(&(&b.group_id, b.sequence)));
+
+        Ok(UserData {
+            keypackages: self
+                .find_eq("mls_keypackages", "owner_pubkey", pubkey)
+                .await?,
+            keypackage_relays: MlsStorage::get_keypackage_relays(self, pubkey).await?,
+            push_tokens: self.get_push_tokens(pubkey).await?,
+            welcomes: self.find_eq("welcome_mailbox", "recipient", pubkey).await?,
+            pending_deletion: self.get_pending_deletion(pubkey).await?,
+            keypackage_requests,
+            groups,
+            roster,
+        })
+    }
+
+    /// Erase what is stored for a pubkey. Groups it owns and the roster records
+    /// it signed are kept and reported, removing them would break the group for
+    /// its other members; delete such groups with `rnostr group delete`.
+    #[instrument(skip(self))]
+    pub async fn erase_user(&self, pubkey: &str) -> Result<UserErasure> {
+        let data = self.user_data(pubkey).await?;
+        let mut erased = UserErasure::default();
+
+        for kp in &data.keypackages {
+            self.delete_doc("mls_keypackages", &kp.event_id).await?;
+            erased.keypackages += 1;
+        }
+        if !data.keypackage_relays.is_empty() {
+            self.delete_doc("keypackage_relays", pubkey).await?;
+            erased.keypackage_relays += 1;
+        }
+        for token in &data.push_tokens {
+            self.delete_doc("push_tokens", &push_token_id(&token.token))
+                .await?;
+            erased.push_tokens += 1;
+        }
+        for welcome in &data.welcomes {
+            self.delete_doc("welcome_mailbox", &welcome.event_id)
+                .await?;
+            erased.welcomes += 1;
+        }
+        if data.pending_deletion.is_some() {
+            self.delete_pending_deletion(pubkey).await?;
+            erased.pending_deletions += 1;
+        }
+        for limit in &data.keypackage_requests {
+            let id = KeyPackageRequestRateLimit::doc_id(
+                &limit.requester_pubkey,
+                &limit.recipient_pubkey,
+            );
+            self.delete_doc("mls_keypackage_request_rate_limits", &id)
+                .await?;
+            erased.keypackage_requests += 1;
+        }
+
+        for group in &data.groups {
+            if group.owner_pubkey == pubkey {
+                erased.retained_groups.push(group.group_id.clone());
+            } else {
+                MlsStorage::remove_admins(self, &group.group_id, &[pubkey.to_string()]).await?;
+                erased.admin_roles += 1;
+            }
+        }
+        for record in data
+            .roster
+            .iter()
+            .filter(|r| r.member_pubkeys.iter().any(|m| m == pubkey))
+        {
+            let patch = RosterMembersPatch {
+                member_pubkeys: record
+                    .member_pubkeys
+                    .iter()
+                    .filter(|m| *m != pubkey)
+                    .cloned()
+                    .collect(),
+                updated_at: Utc::now().timestamp(),
+            };
+            self.db
+                .fluent()
+                .update()
+                .fields(paths!(RosterMembersPatch::{member_pubkeys, updated_at}))
+                .in_col("roster_policy")
+                .document_id(format!("{}_{}", record.group_id, record.sequence))
+                .object(&patch)
+                .execute::<()>()
+                .await?;
+            erased.roster_memberships += 1;
+        }
+        for mut stats in self
+            .find_contains::<GroupStats>("mls_group_stats", "senders", pubkey)
+            .await?
+        {
+            stats.senders.retain(|s| s != pubkey);
+            self.db
+                .fluent()
+                .update()
+                .fields(paths!(GroupStats::{senders}))
+                .in_col("mls_group_stats")
+                .document_id(&stats.group_id)
+                .object(&stats)
+                .execute::<()>()
+                .await?;
+            erased.group_stats += 1;
+        }
+
+        info!(
+            "Erased data of {}: {} keypackages, {} push tokens, {} welcomes, {} roster memberships, {} groups retained",
+            pubkey, erased.keypackages, erased.push_tokens, erased.welcomes, erased.roster_memberships,
+            erased.retained_groups.len()
+        );
+        Ok(erased)
+    }
+}
+
+#[async_trait]
+impl MlsStorage for FirestoreStorage {
+    async fn migrate(&self) -> anyhow::Result<()> {
+        self.migrate().await
+    }
+
+    async fn upsert_group(
+        &self,
+        group_id: &str,
+        display_name: Option<&str>,
+        creator_pubkey: &str,
+        epoch: Option<i64>,
+    ) -> anyhow::Result<()> {
+        self.upsert_group(group_id, display_name, creator_pubkey, epoch.unwrap_or(0))
+            .await
+    }
+
+    async fn health_check(&self) -> anyhow::Result<()> {
+        self.health_check().await
+    }
+
+    async fn group_exists(&self, group_id: &str) -> anyhow::Result<bool> {
+        let docs = self
+            .db
+            .fluent()
+            .select()
+            .from("mls_groups")
+            .filter(|f| f.field("group_id").eq(group_id))
+            .limit(1)
+            .query()
+            .await?;
+        Ok(!docs.is_empty())
+    }
+
+    async fn is_owner(&self, group_id: &str, pubkey: &str) -> anyhow::Result<bool> {
         let group = self.fetch_group(group_id).await?;
         Ok(group.map_or(false, |g| g.owner_pubkey == pubkey))
     }
@@ -463,13 +1209,20 @@ impl MlsStorage for FirestoreStorage {
 
     async fn add_admins(&self, group_id: &str, admins: &[String]) -> anyhow::Result<()> {
         let now = Utc::now();
-        let mut current = self.fetch_group(group_id).await?.map(|g| g.admin_pubkeys).unwrap_or_default();
+        let mut current = self
+            .fetch_group(group_id)
+            .await?
+            .map(|g| g.admin_pubkeys)
+            .unwrap_or_default();
         for a in admins {
             if !current.iter().any(|x| x == a) {
                 current.push(a.clone());
             }
         }
-        let patch = AdminsPatch { admin_pubkeys: current, updated_at: now };
+        let patch = AdminsPatch {
+            admin_pubkeys: current,
+            updated_at: now,
+        };
         self.db
             .fluent()
             .update()
@@ -484,9 +1237,16 @@ impl MlsStorage for FirestoreStorage {
 
     async fn remove_admins(&self, group_id: &str, admins: &[String]) -> anyhow::Result<()> {
         let now = Utc::now();
-        let mut current = self.fetch_group(group_id).await?.map(|g| g.admin_pubkeys).unwrap_or_default();
+        let mut current = self
+            .fetch_group(group_id)
+            .await?
+            .map(|g| g.admin_pubkeys)
+            .unwrap_or_default();
         current.retain(|p| !admins.iter().any(|a| a == p));
-        let patch = AdminsPatch { admin_pubkeys: current, updated_at: now };
+        let patch = AdminsPatch {
+            admin_pubkeys: current,
+            updated_at: now,
+        };
         self.db
             .fluent()
             .update()
@@ -498,21 +1258,23 @@ impl MlsStorage for FirestoreStorage {
             .await?;
         Ok(())
     }
-    
+
     async fn get_last_roster_sequence(&self, group_id: &str) -> anyhow::Result<Option<u64>> {
         use firestore::*;
-        
+
         let collection_name = "roster_policy";
-        
+
         // Query for the latest sequence for this group
-        let query = self.db
+        let query = self
+            .db
             .fluent()
             .select()
             .from(collection_name)
             .filter(|f| f.field("group_id").eq(group_id))
-            .order_by([
-                FirestoreQueryOrder::new("sequence".to_string(), FirestoreQueryDirection::Descending)
-            ])
+            .order_by([FirestoreQueryOrder::new(
+                "sequence".to_string(),
+                FirestoreQueryDirection::Descending,
+            )])
             .limit(1);
 
         let docs = query.query().await?;
@@ -523,10 +1285,10 @@ impl MlsStorage for FirestoreStorage {
                 firestore::FirestoreDb::deserialize_doc_to::<RosterPolicyDocument>(&doc).ok()
             })
             .collect();
-        
+
         Ok(roster_docs.first().map(|doc| doc.sequence))
     }
-    
+
     async fn store_roster_policy(
         &self,
         group_id: &str,
@@ -537,17 +1299,18 @@ impl MlsStorage for FirestoreStorage {
         created_at: i64,
     ) -> anyhow::Result<()> {
         let collection = "roster_policy";
-        
+
         // Check if sequence already exists for idempotency
         if let Ok(Some(last_seq)) = self.get_last_roster_sequence(group_id).await {
             if sequence <= last_seq {
                 return Err(anyhow::anyhow!(
                     "Invalid sequence: {} <= last sequence {}",
-                    sequence, last_seq
+                    sequence,
+                    last_seq
                 ));
             }
         }
-        
+
         let doc = RosterPolicyDocument {
             group_id: group_id.to_string(),
             sequence,
@@ -557,9 +1320,9 @@ impl MlsStorage for FirestoreStorage {
             created_at,
             updated_at: chrono::Utc::now().timestamp(),
         };
-        
+
         let doc_id = format!("{}_{}", group_id, sequence);
-        
+
         self.db
             .fluent()
             .insert()
@@ -568,12 +1331,19 @@ impl MlsStorage for FirestoreStorage {
             .object(&doc)
             .execute::<()>()
             .await?;
-            
-        info!("Stored roster/policy event: group={}, seq={}, op={}", group_id, sequence, operation);
+
+        info!(
+            "Stored roster/policy event: group={}, seq={}, op={}",
+            group_id, sequence, operation
+        );
         Ok(())
     }
 
-    async fn upsert_keypackage_relays(&self, owner_pubkey: &str, relays: &[String]) -> anyhow::Result<()> {
+    async fn upsert_keypackage_relays(
+        &self,
+        owner_pubkey: &str,
+        relays: &[String],
+    ) -> anyhow::Result<()> {
         let rec = KeypackageRelays {
             owner_pubkey: owner_pubkey.to_string(),
             relays: relays.to_vec(),
@@ -595,7 +1365,8 @@ impl MlsStorage for FirestoreStorage {
     }
 
     async fn get_keypackage_relays(&self, owner_pubkey: &str) -> anyhow::Result<Vec<String>> {
-        let docs = self.db
+        let docs = self
+            .db
             .fluent()
             .select()
             .from("keypackage_relays")
@@ -606,7 +1377,9 @@ impl MlsStorage for FirestoreStorage {
 
         let mut items: Vec<KeypackageRelays> = docs
             .into_iter()
-            .filter_map(|doc| firestore::FirestoreDb::deserialize_doc_to::<KeypackageRelays>(&doc).ok())
+            .filter_map(|doc| {
+                firestore::FirestoreDb::deserialize_doc_to::<KeypackageRelays>(&doc).ok()
+            })
             .collect();
 
         Ok(items.pop().map(|k| k.relays).unwrap_or_default())
@@ -635,6 +1408,7 @@ impl MlsStorage for FirestoreStorage {
             relays: relays.to_vec(),
             created_at: DateTime::from_timestamp(created_at, 0).unwrap_or_else(Utc::now),
             expires_at: DateTime::from_timestamp(expires_at, 0).unwrap_or_else(Utc::now),
+            content_hash: crate::mls_gateway::keypackage_encoding::content_hash(content).ok(),
         };
 
         self.db
@@ -653,37 +1427,42 @@ impl MlsStorage for FirestoreStorage {
     async fn query_keypackages(
         &self,
         authors: Option<&[String]>,
-        _since: Option<i64>, // Ignored - not needed for keypackage queries
+        since: Option<i64>,
         limit: Option<u32>,
         order_by: Option<&str>,
     ) -> anyhow::Result<Vec<(String, String, String, i64)>> {
-        let mut query = self.db
-            .fluent()
-            .select()
-            .from("mls_keypackages");
+        let mut query = self.db.fluent().select().from("mls_keypackages");
 
-        // Filter by authors if specified
-        if let Some(author_list) = authors {
-            if !author_list.is_empty() {
-                query = query.filter(|f| f.field("owner_pubkey").is_in(author_list));
-            }
+        // Filter by authors if specified, created_at is stored as seconds and
+        // since is inclusive like a REQ filter
+        let author_list = authors.filter(|a| !a.is_empty());
+        if author_list.is_some() || since.is_some() {
+            query = query.filter(|f| {
+                f.for_all([
+                    author_list.and_then(|a| f.field("owner_pubkey").is_in(a)),
+                    since.and_then(|s| f.field("created_at").greater_than_or_equal(s)),
+                ])
+            });
         }
 
         // Apply ordering if specified
         if let Some(order) = order_by {
             use firestore::*;
             let order_clause = match order {
-                "created_at_asc" => vec![
-                    FirestoreQueryOrder::new("created_at".to_string(), FirestoreQueryDirection::Ascending)
-                ],
-                "created_at_desc" => vec![
-                    FirestoreQueryOrder::new("created_at".to_string(), FirestoreQueryDirection::Descending)
-                ],
+                "created_at_asc" => vec![FirestoreQueryOrder::new(
+                    "created_at".to_string(),
+                    FirestoreQueryDirection::Ascending,
+                )],
+                "created_at_desc" => vec![FirestoreQueryOrder::new(
+                    "created_at".to_string(),
+                    FirestoreQueryDirection::Descending,
+                )],
                 _ => {
                     // Default to ascending if unrecognized
-                    vec![
-                        FirestoreQueryOrder::new("created_at".to_string(), FirestoreQueryDirection::Ascending)
-                    ]
+                    vec![FirestoreQueryOrder::new(
+                        "created_at".to_string(),
+                        FirestoreQueryDirection::Ascending,
+                    )]
                 }
             };
             query = query.order_by(order_clause);
@@ -699,8 +1478,16 @@ impl MlsStorage for FirestoreStorage {
         let keypackages: Vec<(String, String, String, i64)> = docs
             .into_iter()
             .filter_map(|doc| {
-                firestore::FirestoreDb::deserialize_doc_to::<KeyPackageDoc>(&doc).ok()
-                    .map(|kp| (kp.event_id, kp.owner_pubkey, kp.content, kp.created_at.timestamp()))
+                firestore::FirestoreDb::deserialize_doc_to::<KeyPackageDoc>(&doc)
+                    .ok()
+                    .map(|kp| {
+                        (
+                            kp.event_id,
+                            kp.owner_pubkey,
+                            kp.content,
+                            kp.created_at.timestamp(),
+                        )
+                    })
             })
             .collect();
 
@@ -709,7 +1496,8 @@ impl MlsStorage for FirestoreStorage {
 
     async fn delete_consumed_keypackage(&self, event_id: &str) -> anyhow::Result<bool> {
         // First get the keypackage to find its owner
-        let docs = self.db
+        let docs = self
+            .db
             .fluent()
             .select()
             .from("mls_keypackages")
@@ -722,13 +1510,16 @@ impl MlsStorage for FirestoreStorage {
             if let Ok(kp) = firestore::FirestoreDb::deserialize_doc_to::<KeyPackageDoc>(&doc) {
                 // Count how many valid keypackages this user has
                 let count = self.count_user_keypackages(&kp.owner_pubkey).await?;
-                
+
                 if count <= 1 {
                     // This is the last keypackage for the user - preserve it
-                    info!("Preserving last remaining keypackage {} for user {}", event_id, kp.owner_pubkey);
+                    info!(
+                        "Preserving last remaining keypackage {} for user {}",
+                        event_id, kp.owner_pubkey
+                    );
                     return Ok(false);
                 }
-                
+
                 // Safe to delete - user has other keypackages
                 self.db
                     .fluent()
@@ -738,8 +1529,12 @@ impl MlsStorage for FirestoreStorage {
                     .execute()
                     .await?;
 
-                info!("Deleted consumed keypackage {} for user {} (remaining: {})",
-                      event_id, kp.owner_pubkey, count - 1);
+                info!(
+                    "Deleted consumed keypackage {} for user {} (remaining: {})",
+                    event_id,
+                    kp.owner_pubkey,
+                    count - 1
+                );
                 return Ok(true);
             }
         }
@@ -749,7 +1544,8 @@ impl MlsStorage for FirestoreStorage {
 
     async fn count_user_keypackages(&self, owner_pubkey: &str) -> anyhow::Result<u32> {
         let now = Utc::now();
-        let docs = self.db
+        let docs = self
+            .db
             .fluent()
             .select()
             .from("mls_keypackages")
@@ -761,46 +1557,788 @@ impl MlsStorage for FirestoreStorage {
         Ok(docs.len() as u32)
     }
 
+    async fn get_keypackage_request_rate_limit(
+        &self,
+        requester_pubkey: &str,
+        recipient_pubkey: &str,
+    ) -> anyhow::Result<Option<KeyPackageRequestRateLimit>> {
+        let doc: Option<KeyPackageRequestRateLimit> = self
+            .db
+            .fluent()
+            .select()
+            .by_id_in("mls_keypackage_request_rate_limits")
+            .obj()
+            .one(&KeyPackageRequestRateLimit::doc_id(
+                requester_pubkey,
+                recipient_pubkey,
+            ))
+            .await?;
+        Ok(doc)
+    }
+
+    async fn put_keypackage_request_rate_limit(
+        &self,
+        limit: &KeyPackageRequestRateLimit,
+    ) -> anyhow::Result<()> {
+        self.db
+            .fluent()
+            .update()
+            .in_col("mls_keypackage_request_rate_limits")
+            .document_id(&KeyPackageRequestRateLimit::doc_id(
+                &limit.requester_pubkey,
+                &limit.recipient_pubkey,
+            ))
+            .object(limit)
+            .execute::<()>()
+            .await?;
+        Ok(())
+    }
+
+    async fn find_keypackage_by_hash(
+        &self,
+        owner_pubkey: &str,
+        content_hash: &str,
+    ) -> anyhow::Result<Option<String>> {
+        let now = Utc::now();
+        let docs = self
+            .db
+            .fluent()
+            .select()
+            .from("mls_keypackages")
+            .filter(|f| f.field("owner_pubkey").eq(owner_pubkey))
+            .filter(|f| f.field("content_hash").eq(content_hash))
+            .query()
+            .await?;
+        Ok(docs
+            .into_iter()
+            .filter_map(|doc| {
+                firestore::FirestoreDb::deserialize_doc_to::<KeyPackageDoc>(&doc).ok()
+            })
+            .find(|kp| kp.expires_at > now)
+            .map(|kp| kp.event_id))
+    }
+
     async fn cleanup_expired_keypackages(&self, max_per_user: u32) -> anyhow::Result<u32> {
         // Delegate to the public method
-        FirestoreStorage::cleanup_expired_keypackages(self, max_per_user).await
+        FirestoreStorage::cleanup_expired_keypackages(self, max_per_user)
+            .await
             .map_err(|e| anyhow::anyhow!(e))
     }
-    
+
     // New methods for pending deletion management
-    
-    async fn create_pending_deletion(&self, pending: &crate::mls_gateway::firestore::PendingDeletion) -> anyhow::Result<()> {
+
+    async fn create_pending_deletion(
+        &self,
+        pending: &crate::mls_gateway::firestore::PendingDeletion,
+    ) -> anyhow::Result<()> {
         self.create_pending_deletion(pending).await
     }
-    
-    async fn get_pending_deletion(&self, user_pubkey: &str) -> anyhow::Result<Option<crate::mls_gateway::firestore::PendingDeletion>> {
+
+    async fn get_pending_deletion(
+        &self,
+        user_pubkey: &str,
+    ) -> anyhow::Result<Option<crate::mls_gateway::firestore::PendingDeletion>> {
         self.get_pending_deletion(user_pubkey).await
     }
-    
-    async fn update_pending_deletion(&self, pending: &crate::mls_gateway::firestore::PendingDeletion) -> anyhow::Result<()> {
+
+    async fn update_pending_deletion(
+        &self,
+        pending: &crate::mls_gateway::firestore::PendingDeletion,
+    ) -> anyhow::Result<()> {
         self.update_pending_deletion(pending).await
     }
-    
+
     async fn delete_pending_deletion(&self, user_pubkey: &str) -> anyhow::Result<()> {
         self.delete_pending_deletion(user_pubkey).await
     }
-    
+
     async fn delete_keypackage_by_id(&self, event_id: &str) -> anyhow::Result<()> {
         self.delete_keypackage_by_id(event_id).await
     }
-    
+
     async fn keypackage_exists(&self, event_id: &str) -> anyhow::Result<bool> {
         self.keypackage_exists(event_id).await
     }
-    
-    async fn get_expired_pending_deletions(&self) -> anyhow::Result<Vec<crate::mls_gateway::firestore::PendingDeletion>> {
+
+    async fn get_keypackage_owner(&self, event_id: &str) -> anyhow::Result<Option<String>> {
+        self.get_keypackage_owner(event_id).await
+    }
+
+    async fn get_expired_pending_deletions(
+        &self,
+    ) -> anyhow::Result<Vec<crate::mls_gateway::firestore::PendingDeletion>> {
         self.get_expired_pending_deletions().await
     }
+
+    async fn upsert_push_token(
+        &self,
+        pubkey: &str,
+        token: &str,
+        platform: &str,
+    ) -> anyhow::Result<()> {
+        self.upsert_push_token(pubkey, token, platform).await
+    }
+
+    async fn get_push_tokens(&self, pubkey: &str) -> anyhow::Result<Vec<PushToken>> {
+        self.get_push_tokens(pubkey).await
+    }
+
+    async fn delete_push_token(&self, pubkey: Option<&str>, token: &str) -> anyhow::Result<bool> {
+        self.delete_push_token(pubkey, token).await
+    }
+
+    async fn get_group(&self, group_id: &str) -> anyhow::Result<Option<GroupInfo>> {
+        self.fetch_group(group_id).await
+    }
+
+    async fn list_roster_history(
+        &self,
+        group_id: &str,
+    ) -> anyhow::Result<Vec<RosterPolicyDocument>> {
+        let docs = self
+            .db
+            .fluent()
+            .select()
+            .from("roster_policy")
+            .filter(|f| f.field("group_id").eq(group_id))
+            .order_by([FirestoreQueryOrder::new(
+                "sequence".to_string(),
+                FirestoreQueryDirection::Ascending,
+            )])
+            .query()
+            .await?;
+
+        Ok(docs
+            .into_iter()
+            .filter_map(|doc| {
+                firestore::FirestoreDb::deserialize_doc_to::<RosterPolicyDocument>(&doc).ok()
+            })
+            .collect())
+    }
+
+    async fn set_group_owner(&self, group_id: &str, owner_pubkey: &str) -> anyhow::Result<()> {
+        if self.fetch_group(group_id).await?.is_none() {
+            return Err(anyhow::anyhow!("Group {} not found", group_id));
+        }
+        let patch = OwnerPatch {
+            owner_pubkey: owner_pubkey.to_string(),
+            updated_at: Utc::now(),
+        };
+        self.db
+            .fluent()
+            .update()
+            .fields(paths!(OwnerPatch::{owner_pubkey, updated_at}))
+            .in_col("mls_groups")
+            .document_id(group_id)
+            .object(&patch)
+            .execute::<()>()
+            .await?;
+
+        info!("Set owner of group {} to {}", group_id, owner_pubkey);
+        Ok(())
+    }
+
+    async fn delete_group(&self, group_id: &str) -> anyhow::Result<u32> {
+        let history = MlsStorage::list_roster_history(self, group_id).await?;
+        let mut deleted = 0;
+        for record in history {
+            let doc_id = format!("{}_{}", group_id, record.sequence);
+            self.db
+                .fluent()
+                .delete()
+                .from("roster_policy")
+                .document_id(&doc_id)
+                .execute()
+                .await?;
+            deleted += 1;
+        }
+
+        self.db
+            .fluent()
+            .delete()
+            .from("mls_groups")
+            .document_id(group_id)
+            .execute()
+            .await?;
+
+        info!(
+            "Deleted group {} and {} roster/policy records",
+            group_id, deleted
+        );
+        Ok(deleted)
+    }
+
+    async fn store_mailbox_welcome(&self, entry: &MailboxWelcome) -> anyhow::Result<()> {
+        self.db
+            .fluent()
+            .update()
+            .in_col("welcome_mailbox")
+            .document_id(&entry.event_id)
+            .object(entry)
+            .execute::<()>()
+            .await?;
+        Ok(())
+    }
+
+    async fn ack_mailbox_welcomes(
+        &self,
+        recipient: &str,
+        event_ids: &[String],
+    ) -> anyhow::Result<Vec<MailboxWelcome>> {
+        let now = Utc::now();
+        let mut acked = Vec::new();
+        for event_id in event_ids {
+            let doc: Option<MailboxWelcome> = self
+                .db
+                .fluent()
+                .select()
+                .by_id_in("welcome_mailbox")
+                .obj()
+                .one(event_id)
+                .await?;
+            // Only the recipient acks, and only once
+            let Some(mut entry) = doc.filter(|e| e.recipient == recipient && e.acked_at.is_none())
+            else {
+                continue;
+            };
+            entry.acked_at = Some(now);
+            self.db
+                .fluent()
+                .update()
+                .fields(paths!(MailboxWelcome::{acked_at}))
+                .in_col("welcome_mailbox")
+                .document_id(event_id)
+                .object(&entry)
+                .execute::<()>()
+                .await?;
+            acked.push(entry);
+        }
+        Ok(acked)
+    }
+
+    async fn list_welcome_tombstones(
+        &self,
+        since: DateTime<Utc>,
+    ) -> anyhow::Result<Vec<(String, i64)>> {
+        let docs = self
+            .db
+            .fluent()
+            .select()
+            .from("welcome_mailbox")
+            .filter(|f| f.field("expires_at").greater_than(since.timestamp()))
+            .query()
+            .await?;
+        let now = Utc::now();
+        Ok(docs
+            .into_iter()
+            .filter_map(|doc| {
+                firestore::FirestoreDb::deserialize_doc_to::<MailboxWelcome>(&doc).ok()
+            })
+            .filter(|e| e.acked_at.is_some() || e.expires_at <= now)
+            .map(|e| (e.event_id, e.expires_at.timestamp()))
+            .collect())
+    }
+
+    async fn cleanup_expired_welcomes(&self, before: DateTime<Utc>) -> anyhow::Result<u32> {
+        let docs = self
+            .db
+            .fluent()
+            .select()
+            .from("welcome_mailbox")
+            .filter(|f| f.field("expires_at").less_than_or_equal(before.timestamp()))
+            .query()
+            .await?;
+        let mut removed = 0;
+        for doc in docs {
+            if let Ok(entry) = firestore::FirestoreDb::deserialize_doc_to::<MailboxWelcome>(&doc) {
+                self.db
+                    .fluent()
+                    .delete()
+                    .from("welcome_mailbox")
+                    .document_id(&entry.event_id)
+                    .execute()
+                    .await?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    async fn claim_service_action(
+        &self,
+        action_id: &str,
+        client_id: &str,
+        expires_at: DateTime<Utc>,
+    ) -> anyhow::Result<bool> {
+        let now = Utc::now().timestamp();
+        // the transaction read locks the claim, concurrent claims of an action_id serialize on it
+        let mut transaction = self.db.begin_transaction().await?;
+        let tx_db =
+            self.db
+                .clone_with_consistency_selector(FirestoreConsistencySelector::Transaction(
+                    transaction.transaction_id().clone(),
+                ));
+        let existing: Option<ServiceActionClaim> = tx_db
+            .fluent()
+            .select()
+            .by_id_in("service_actions")
+            .obj()
+            .one(action_id)
+            .await?;
+        if existing.is_some_and(|claim| claim.expires_at > now) {
+            transaction.rollback().await?;
+            return Ok(false);
+        }
+        let claim = ServiceActionClaim {
+            action_id: action_id.to_string(),
+            client_id: client_id.to_string(),
+            expires_at: expires_at.timestamp(),
+        };
+        self.db
+            .fluent()
+            .update()
+            .in_col("service_actions")
+            .document_id(action_id)
+            .object(&claim)
+            .add_to_transaction(&mut transaction)?;
+        transaction.commit().await?;
+        Ok(true)
+    }
+
+    async fn cleanup_expired_service_actions(&self, before: DateTime<Utc>) -> anyhow::Result<u32> {
+        let claims: Vec<ServiceActionClaim> = self
+            .db
+            .fluent()
+            .select()
+            .from("service_actions")
+            .filter(|f| f.field("expires_at").less_than_or_equal(before.timestamp()))
+            .obj()
+            .query()
+            .await?;
+        for claim in &claims {
+            self.db
+                .fluent()
+                .delete()
+                .from("service_actions")
+                .document_id(&claim.action_id)
+                .execute()
+                .await?;
+        }
+        Ok(claims.len() as u32)
+    }
+
+    async fn list_moderation_rules(&self) -> anyhow::Result<Vec<ModerationRule>> {
+        let docs = self
+            .db
+            .fluent()
+            .select()
+            .from("moderation_rules")
+            .query()
+            .await?;
+        Ok(docs
+            .into_iter()
+            .filter_map(|doc| {
+                firestore::FirestoreDb::deserialize_doc_to::<ModerationRule>(&doc).ok()
+            })
+            .collect())
+    }
+
+    async fn put_moderation_rule(&self, rule: &ModerationRule) -> anyhow::Result<()> {
+        self.db
+            .fluent()
+            .update()
+            .in_col("moderation_rules")
+            .document_id(&ModerationRule::doc_id(
+                &rule.list,
+                &rule.target,
+                &rule.value,
+            ))
+            .object(rule)
+            .execute::<()>()
+            .await?;
+        Ok(())
+    }
+
+    async fn delete_moderation_rule(
+        &self,
+        list: &str,
+        target: &str,
+        value: &str,
+    ) -> anyhow::Result<bool> {
+        let id = ModerationRule::doc_id(list, target, value);
+        let existing: Option<ModerationRule> = self
+            .db
+            .fluent()
+            .select()
+            .by_id_in("moderation_rules")
+            .obj()
+            .one(&id)
+            .await?;
+        if existing.is_none() {
+            return Ok(false);
+        }
+        self.db
+            .fluent()
+            .delete()
+            .from("moderation_rules")
+            .document_id(&id)
+            .execute()
+            .await?;
+        Ok(true)
+    }
+
+    async fn record_group_activity(
+        &self,
+        group_id: &str,
+        sender: &str,
+        epoch: Option<i64>,
+    ) -> anyhow::Result<GroupStats> {
+        let now = Utc::now().timestamp();
+        // the write only applies to the document that was read, a concurrent update makes the commit fail
+        let mut attempt = 1;
+        loop {
+            let mut transaction = self.db.begin_transaction().await?;
+            let tx_db =
+                self.db
+                    .clone_with_consistency_selector(FirestoreConsistencySelector::Transaction(
+                        transaction.transaction_id().clone(),
+                    ));
+            let doc = tx_db
+                .fluent()
+                .select()
+                .by_id_in("mls_group_stats")
+                .one(group_id)
+                .await?;
+            let (mut stats, precondition) = match doc {
+                Some(doc) => {
+                    let stats = FirestoreDb::deserialize_doc_to::<GroupStats>(&doc)?;
+                    let update_time = doc.update_time.ok_or_else(|| {
+                        anyhow::anyhow!("group stats {} without update time", group_id)
+                    })?;
+                    let update_time = firestore::timestamp_utils::from_timestamp(update_time)?;
+                    (stats, FirestoreWritePrecondition::UpdateTime(update_time))
+                }
+                None => (
+                    GroupStats::new(group_id, now),
+                    FirestoreWritePrecondition::Exists(false),
+                ),
+            };
+            stats.record(sender, epoch, now);
+            self.db
+                .fluent()
+                .update()
+                .in_col("mls_group_stats")
+                .precondition(precondition)
+                .document_id(group_id)
+                .object(&stats)
+                .add_to_transaction(&mut transaction)?;
+            match transaction.commit().await {
+                Ok(_) => return Ok(stats),
+                Err(e) if attempt < GROUP_STATS_ATTEMPTS => {
+                    debug!(
+                        "Group stats of {} changed concurrently, retrying: {}",
+                        group_id, e
+                    );
+                    counter!("mls_gateway_group_stats_conflicts").increment(1);
+                    attempt += 1;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    async fn get_group_stats(&self, group_id: &str) -> anyhow::Result<Option<GroupStats>> {
+        let stats: Option<GroupStats> = self
+            .db
+            .fluent()
+            .select()
+            .by_id_in("mls_group_stats")
+            .obj()
+            .one(group_id)
+            .await?;
+        Ok(stats)
+    }
+
+    async fn get_roster_policy(
+        &self,
+        group_id: &str,
+        sequence: u64,
+    ) -> anyhow::Result<Option<RosterPolicyDocument>> {
+        let doc: Option<RosterPolicyDocument> = self
+            .db
+            .fluent()
+            .select()
+            .by_id_in("roster_policy")
+            .obj()
+            .one(&format!("{}_{}", group_id, sequence))
+            .await?;
+        Ok(doc)
+    }
+
+    async fn store_roster_anomaly(&self, anomaly: &RosterAnomaly) -> anyhow::Result<()> {
+        self.db
+            .fluent()
+            .update()
+            .in_col("roster_anomalies")
+            .document_id(&anomaly.event_id)
+            .object(anomaly)
+            .execute::<()>()
+            .await?;
+        Ok(())
+    }
+
+    async fn list_roster_anomalies(
+        &self,
+        group_id: Option<&str>,
+    ) -> anyhow::Result<Vec<RosterAnomaly>> {
+        let mut anomalies: Vec<RosterAnomaly> = match group_id {
+            Some(group_id) => {
+                self.find_eq("roster_anomalies", "group_id", group_id)
+                    .await?
+            }
+            None => {
+                self.db
+                    .fluent()
+                    .select()
+                    .from("roster_anomalies")
+                    .obj()
+                    .query()
+                    .await?
+            }
+        };
+        anomalies.sort_by_key(|a| (a.created_at, a.sequence));
+        Ok(anomalies)
+    }
+
+    async fn apply_roster_operation(&self, op: &RosterOperation) -> anyhow::Result<()> {
+        let mut transaction = self.db.begin_transaction().await?;
+        let tx_db =
+            self.db
+                .clone_with_consistency_selector(FirestoreConsistencySelector::Transaction(
+                    transaction.transaction_id().clone(),
+                ));
+        let group: Option<GroupInfo> = tx_db
+            .fluent()
+            .select()
+            .by_id_in("mls_groups")
+            .obj()
+            .one(&op.group_id)
+            .await?;
+
+        // the sequence is taken by whichever transaction creates its document first
+        self.db
+            .fluent()
+            .update()
+            .in_col("roster_policy")
+            .precondition(FirestoreWritePrecondition::Exists(false))
+            .document_id(format!("{}_{}", op.group_id, op.sequence))
+            .object(&op.record(Utc::now().timestamp()))
+            .add_to_transaction(&mut transaction)?;
+        if let Some(group) = op.apply(group, Utc::now()) {
+            self.db
+                .fluent()
+                .update()
+                .in_col("mls_groups")
+                .document_id(&op.group_id)
+                .object(&group)
+                .add_to_transaction(&mut transaction)?;
+        }
+        transaction.commit().await?;
+        info!(
+            "Applied roster operation {} to group {} at seq {}",
+            op.operation, op.group_id, op.sequence
+        );
+        Ok(())
+    }
+
+    async fn create_pending_roster_op(&self, op: &PendingRosterOp) -> anyhow::Result<()> {
+        self.db
+            .fluent()
+            .update()
+            .in_col("roster_pending_ops")
+            .precondition(FirestoreWritePrecondition::Exists(false))
+            .document_id(&op.event_id)
+            .object(op)
+            .execute::<()>()
+            .await?;
+        Ok(())
+    }
+
+    async fn approve_pending_roster_op(
+        &self,
+        event_id: &str,
+        approver: &str,
+        now: i64,
+    ) -> anyhow::Result<Option<(roster_quorum::Approval, PendingRosterOp)>> {
+        // the read is locked by the transaction, a concurrent approval makes the commit fail
+        let mut transaction = self.db.begin_transaction().await?;
+        let tx_db =
+            self.db
+                .clone_with_consistency_selector(FirestoreConsistencySelector::Transaction(
+                    transaction.transaction_id().clone(),
+                ));
+        let op: Option<PendingRosterOp> = tx_db
+            .fluent()
+            .select()
+            .by_id_in("roster_pending_ops")
+            .obj()
+            .one(event_id)
+            .await?;
+        let Some(mut op) = op else {
+            transaction.rollback().await?;
+            return Ok(None);
+        };
+        let approval = roster_quorum::approve(&mut op, approver, now);
+        if matches!(
+            approval,
+            roster_quorum::Approval::Duplicate | roster_quorum::Approval::Closed
+        ) {
+            transaction.rollback().await?;
+            return Ok(Some((approval, op)));
+        }
+        self.db
+            .fluent()
+            .update()
+            .in_col("roster_pending_ops")
+            .document_id(event_id)
+            .object(&op)
+            .add_to_transaction(&mut transaction)?;
+        transaction.commit().await?;
+        Ok(Some((approval, op)))
+    }
+
+    async fn set_pending_roster_op_status(
+        &self,
+        event_id: &str,
+        status: &str,
+    ) -> anyhow::Result<()> {
+        self.db
+            .fluent()
+            .update()
+            .fields(paths!(StatusPatch::{status}))
+            .in_col("roster_pending_ops")
+            .document_id(event_id)
+            .object(&StatusPatch {
+                status: status.to_string(),
+            })
+            .execute::<()>()
+            .await?;
+        Ok(())
+    }
+
+    async fn get_pending_roster_op(
+        &self,
+        event_id: &str,
+    ) -> anyhow::Result<Option<PendingRosterOp>> {
+        let op: Option<PendingRosterOp> = self
+            .db
+            .fluent()
+            .select()
+            .by_id_in("roster_pending_ops")
+            .obj()
+            .one(event_id)
+            .await?;
+        Ok(op)
+    }
+
+    async fn list_pending_roster_ops(
+        &self,
+        group_id: Option<&str>,
+    ) -> anyhow::Result<Vec<PendingRosterOp>> {
+        let mut ops: Vec<PendingRosterOp> = match group_id {
+            Some(group_id) => {
+                self.find_eq("roster_pending_ops", "group_id", group_id)
+                    .await?
+            }
+            None => {
+                self.db
+                    .fluent()
+                    .select()
+                    .from("roster_pending_ops")
+                    .obj()
+                    .query()
+                    .await?
+            }
+        };
+        ops.sort_by_key(|op| (op.created_at, op.sequence));
+        Ok(ops)
+    }
+
+    async fn list_group_stats(&self) -> anyhow::Result<Vec<GroupStats>> {
+        let docs = self
+            .db
+            .fluent()
+            .select()
+            .from("mls_group_stats")
+            .query()
+            .await?;
+        Ok(docs
+            .into_iter()
+            .filter_map(|doc| firestore::FirestoreDb::deserialize_doc_to::<GroupStats>(&doc).ok())
+            .collect())
+    }
+
+    async fn get_attestation(&self, pubkey: &str) -> anyhow::Result<Option<AttestationRecord>> {
+        let record: Option<AttestationRecord> = self
+            .db
+            .fluent()
+            .select()
+            .by_id_in("attested_pubkeys")
+            .obj()
+            .one(pubkey)
+            .await?;
+        Ok(record)
+    }
+
+    async fn put_attestation(&self, record: &AttestationRecord) -> anyhow::Result<()> {
+        self.db
+            .fluent()
+            .update()
+            .in_col("attested_pubkeys")
+            .document_id(&record.pubkey)
+            .object(record)
+            .execute::<()>()
+            .await?;
+        Ok(())
+    }
+
+    async fn get_subscription_cursor(
+        &self,
+        pubkey: &str,
+        subscription_id: &str,
+    ) -> anyhow::Result<Option<SubscriptionCursor>> {
+        let cursor: Option<SubscriptionCursor> = self
+            .db
+            .fluent()
+            .select()
+            .by_id_in("subscription_cursors")
+            .obj()
+            .one(&SubscriptionCursor::doc_id(pubkey, subscription_id))
+            .await?;
+        Ok(cursor)
+    }
+
+    async fn put_subscription_cursors(&self, cursors: &[SubscriptionCursor]) -> anyhow::Result<()> {
+        for cursor in cursors {
+            self.db
+                .fluent()
+                .update()
+                .in_col("subscription_cursors")
+                .document_id(&SubscriptionCursor::doc_id(
+                    &cursor.pubkey,
+                    &cursor.subscription_id,
+                ))
+                .object(cursor)
+                .execute::<()>()
+                .await?;
+        }
+        Ok(())
+    }
 }
 
 /// Roster/Policy document structure for Firestore
 #[derive(Debug, Clone, Serialize, Deserialize)]
-struct RosterPolicyDocument {
+pub struct RosterPolicyDocument {
     pub group_id: String,
     pub sequence: u64,
     pub operation: String,
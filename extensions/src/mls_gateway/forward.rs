@@ -0,0 +1,185 @@
+//! Giftwrap forwarding to recipients' KeyPackage relays (kind 10051)
+//!
+//! When a giftwrap (1059) arrives for a recipient whose 10051 list names other
+//! relays, the event is republished there so the Welcome reaches the recipient
+//! even if they never connect to this relay. Forwarding is rate limited per
+//! recipient and each event id is forwarded at most once.
+
+use super::{MlsGatewayConfig, StorageBackend};
+use anyhow::Result;
+use futures::{SinkExt, StreamExt};
+use metrics::counter;
+use nostr_relay::db::Event;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+use tokio_tungstenite::{connect_async, tungstenite::Message};
+use tracing::{debug, info, warn};
+
+/// Rate limit window per recipient
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// How long a forwarded event id is remembered
+const SEEN_TTL: Duration = Duration::from_secs(600);
+
+/// Time allowed for connecting to a relay and receiving its OK
+const PUBLISH_TIMEOUT: Duration = Duration::from_secs(10);
+
+static RATE: Lazy<Mutex<HashMap<String, (Instant, u32)>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+static SEEN: Lazy<Mutex<HashMap<[u8; 32], Instant>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Count a forward for the recipient, false once `max_per_minute` is reached
+fn allow(recipient: &str, max_per_minute: u32) -> bool {
+    let now = Instant::now();
+    let mut rate = RATE.lock();
+    rate.retain(|_, (start, _)| now.duration_since(*start) < WINDOW);
+    let (_, count) = rate.entry(recipient.to_string()).or_insert((now, 0));
+    if *count >= max_per_minute {
+        return false;
+    }
+    *count += 1;
+    true
+}
+
+/// Whether an event id was forwarded within [`SEEN_TTL`]
+fn seen(id: &[u8; 32]) -> bool {
+    let now = Instant::now();
+    SEEN.lock()
+        .get(id)
+        .is_some_and(|t| now.duration_since(*t) < SEEN_TTL)
+}
+
+/// Returns true the first time an event id is forwarded within [`SEEN_TTL`]
+fn first_seen(id: &[u8; 32]) -> bool {
+    let now = Instant::now();
+    let mut seen = SEEN.lock();
+    seen.retain(|_, t| now.duration_since(*t) < SEEN_TTL);
+    seen.insert(*id, now).is_none()
+}
+
+fn normalize(url: &str) -> &str {
+    url.trim_end_matches('/')
+}
+
+/// Publish an event to a relay and wait for its OK
+pub async fn publish(url: &str, event: &Event) -> Result<()> {
+    let id = event.id_str();
+    let fut = async {
+        let (mut ws, _) = connect_async(url).await?;
+        ws.send(Message::Text(format!(r#"["EVENT",{}]"#, event)))
+            .await?;
+        while let Some(msg) = ws.next().await {
+            if let Message::Text(text) = msg? {
+                if let Ok((cmd, eid, ok, reason)) =
+                    serde_json::from_str::<(String, String, bool, String)>(&text)
+                {
+                    if cmd == "OK" && eid == id {
+                        let _ = ws.close(None).await;
+                        return if ok || reason.starts_with("duplicate") {
+                            Ok(())
+                        } else {
+                            Err(anyhow::anyhow!("rejected: {}", reason))
+                        };
+                    }
+                }
+            }
+        }
+        Err(anyhow::anyhow!("connection closed before OK"))
+    };
+    tokio::time::timeout(PUBLISH_TIMEOUT, fut)
+        .await
+        .map_err(|_| anyhow::anyhow!("timeout"))?
+}
+
+/// Forward a giftwrap to the 10051 relays of its recipient, returns the number of relays reached
+pub async fn forward_giftwrap(
+    store: &StorageBackend,
+    config: &MlsGatewayConfig,
+    event: &Event,
+) -> Result<usize> {
+    let Some(recipient) = event
+        .tags()
+        .iter()
+        .find(|tag| tag.len() >= 2 && tag[0] == "p")
+        .map(|tag| tag[1].clone())
+    else {
+        return Ok(0);
+    };
+
+    let relays: Vec<String> = store
+        .get_keypackage_relays(&recipient)
+        .await?
+        .into_iter()
+        .filter(|url| url.starts_with("ws://") || url.starts_with("wss://"))
+        .filter(|url| {
+            !config
+                .relay_urls
+                .iter()
+                .any(|own| normalize(own) == normalize(url))
+        })
+        .collect();
+    if relays.is_empty() || seen(event.id()) {
+        return Ok(0);
+    }
+    if !allow(&recipient, config.giftwrap_forward_max_per_minute) {
+        debug!("Giftwrap forwarding rate limited for {}", recipient);
+        counter!("mls_gateway_giftwrap_forwards", "result" => "rate_limited").increment(1);
+        return Ok(0);
+    }
+    // marked only once it passes the rate limit, a rate limited giftwrap is not remembered as forwarded
+    if !first_seen(event.id()) {
+        return Ok(0);
+    }
+
+    let mut reached = 0;
+    for url in relays {
+        match publish(&url, event).await {
+            Ok(()) => {
+                reached += 1;
+                counter!("mls_gateway_giftwrap_forwards", "result" => "ok").increment(1);
+            }
+            Err(e) => {
+                warn!(
+                    "Failed to forward giftwrap {} to {}: {}",
+                    event.id_str(),
+                    url,
+                    e
+                );
+                counter!("mls_gateway_giftwrap_forwards", "result" => "error").increment(1);
+            }
+        }
+    }
+    info!(
+        "Forwarded giftwrap {} for {} to {} relays",
+        event.id_str(),
+        recipient,
+        reached
+    );
+    Ok(reached)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_limit() {
+        assert!(allow("forward-test", 2));
+        assert!(allow("forward-test", 2));
+        assert!(!allow("forward-test", 2));
+        assert!(allow("forward-test-other", 2));
+    }
+
+    #[test]
+    fn forwarded_once() {
+        let id = [7u8; 32];
+        assert!(!seen(&id));
+        assert!(first_seen(&id));
+        assert!(seen(&id));
+        assert!(!first_seen(&id));
+    }
+}
@@ -0,0 +1,292 @@
+//! Giftwrap (1059) replay suppression and per-recipient quotas
+//!
+//! A giftwrap is identified by its recipient and the hash of its ciphertext, so
+//! the same envelope re-signed under fresh ephemeral keys (new event ids) is
+//! still recognized. A stored giftwrap is remembered, its duplicates are rejected
+//! at ingress for `welcome_ttl`, and the archive path claims each (recipient,
+//! digest) once across instances.
+//! Each recipient accepts at most `giftwrap_daily_quota` giftwraps per day: a
+//! recipient over quota is rejected at ingress, and the quota is charged once a
+//! giftwrap is stored, with a transaction in the archive so instances share it.
+//!
+//! Before that the outer envelope must look like NIP-59:
+//! - the ephemeral pubkey is not one of the recipients
+//! - `created_at` lies within `giftwrap_max_backdate_secs` in the past (NIP-59
+//!   randomizes up to two days) and `giftwrap_max_future_secs` in the future
+//! - the content length is within `giftwrap_min_content_bytes` and
+//!   `giftwrap_max_content_bytes`
+//! - with `giftwrap_reject_known_identities`, the pubkey is not a long-term
+//!   identity: configured admin/management pubkeys or owners of KeyPackages or
+//!   KeyPackage relays lists (10051)
+
+use super::{MlsGatewayConfig, StorageBackend};
+use nostr_relay::db::Event;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+/// Quota window per recipient
+const QUOTA_WINDOW: Duration = Duration::from_secs(86400);
+
+static SEEN: Lazy<Mutex<HashMap<(String, [u8; 32]), Instant>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+static QUOTA: Lazy<Mutex<HashMap<String, (Instant, u32)>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Accept,
+    Duplicate,
+    QuotaExceeded,
+}
+
+/// Outer structure check failed by a giftwrap
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StructureIssue {
+    Pubkey,
+    CreatedAt,
+    Size,
+    Identity,
+}
+
+impl StructureIssue {
+    /// Counter label
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StructureIssue::Pubkey => "pubkey",
+            StructureIssue::CreatedAt => "created_at",
+            StructureIssue::Size => "size",
+            StructureIssue::Identity => "identity",
+        }
+    }
+
+    /// Rejection reason sent to the client
+    pub fn reason(&self) -> &'static str {
+        match self {
+            StructureIssue::Pubkey => "giftwrap must be signed by an ephemeral key",
+            StructureIssue::CreatedAt => "giftwrap created_at outside the accepted window",
+            StructureIssue::Size => "giftwrap content size out of bounds",
+            StructureIssue::Identity => "giftwrap signed by a long-term identity",
+        }
+    }
+}
+
+/// Check the outer structure of a giftwrap at `now`, the store lookup of `Identity` is left to `is_known_identity`
+pub fn structure(event: &Event, config: &MlsGatewayConfig, now: u64) -> Result<(), StructureIssue> {
+    let pubkey = event.pubkey_str();
+    if event
+        .tags()
+        .iter()
+        .any(|tag| tag.len() >= 2 && tag[0] == "p" && tag[1] == pubkey)
+    {
+        return Err(StructureIssue::Pubkey);
+    }
+    let created_at = event.created_at();
+    if (config.giftwrap_max_backdate_secs > 0
+        && created_at + config.giftwrap_max_backdate_secs < now)
+        || (config.giftwrap_max_future_secs > 0
+            && created_at > now + config.giftwrap_max_future_secs)
+    {
+        return Err(StructureIssue::CreatedAt);
+    }
+    let size = event.content().len();
+    if size < config.giftwrap_min_content_bytes
+        || (config.giftwrap_max_content_bytes > 0 && size > config.giftwrap_max_content_bytes)
+    {
+        return Err(StructureIssue::Size);
+    }
+    if config.giftwrap_reject_known_identities
+        && (config.admin_pubkeys.contains(&pubkey) || config.management_pubkeys.contains(&pubkey))
+    {
+        return Err(StructureIssue::Identity);
+    }
+    Ok(())
+}
+
+/// Whether `pubkey` published KeyPackages or a KeyPackage relays list, i.e. is a long-term identity
+pub async fn is_known_identity(store: &StorageBackend, pubkey: &str) -> anyhow::Result<bool> {
+    Ok(store.count_user_keypackages(pubkey).await? > 0
+        || !store.get_keypackage_relays(pubkey).await?.is_empty())
+}
+
+/// Recipient (first p tag) of a giftwrap
+pub fn recipient(event: &Event) -> Option<String> {
+    event
+        .tags()
+        .iter()
+        .find(|tag| tag.len() >= 2 && tag[0] == "p")
+        .map(|tag| tag[1].clone())
+}
+
+/// Hash of the giftwrap ciphertext
+pub fn digest(event: &Event) -> [u8; 32] {
+    Sha256::digest(event.content().as_bytes()).into()
+}
+
+/// Check a giftwrap at ingress, it is remembered by `remember` and charged by `charge` once stored
+pub fn check(event: &Event, config: &MlsGatewayConfig) -> Verdict {
+    let Some(recipient) = recipient(event) else {
+        return Verdict::Accept;
+    };
+    let now = Instant::now();
+    let key = (recipient, digest(event));
+    {
+        let ttl = Duration::from_secs(config.welcome_ttl);
+        let mut seen = SEEN.lock();
+        seen.retain(|_, t| now.duration_since(*t) < ttl);
+        if seen.contains_key(&key) {
+            return Verdict::Duplicate;
+        }
+        if config.giftwrap_daily_quota > 0 && charged(&key.0, now) >= config.giftwrap_daily_quota {
+            return Verdict::QuotaExceeded;
+        }
+    }
+    Verdict::Accept
+}
+
+/// Remember a stored giftwrap, a rejected one may be sent again
+pub fn remember(event: &Event) {
+    if let Some(recipient) = recipient(event) {
+        SEEN.lock()
+            .insert((recipient, digest(event)), Instant::now());
+    }
+}
+
+/// Giftwraps charged to the recipient in the current window
+fn charged(recipient: &str, now: Instant) -> u32 {
+    let mut rate = QUOTA.lock();
+    rate.retain(|_, (start, _)| now.duration_since(*start) < QUOTA_WINDOW);
+    rate.get(recipient).map_or(0, |(_, count)| *count)
+}
+
+/// Charge a stored giftwrap to the recipient, false once `quota` is reached (0 disables)
+pub fn charge(recipient: &str, quota: u32) -> bool {
+    if quota == 0 {
+        return true;
+    }
+    let now = Instant::now();
+    let mut rate = QUOTA.lock();
+    rate.retain(|_, (start, _)| now.duration_since(*start) < QUOTA_WINDOW);
+    let (_, count) = rate.entry(recipient.to_string()).or_insert((now, 0));
+    if *count >= quota {
+        return false;
+    }
+    *count += 1;
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use nostr_relay::db::secp256k1::{Keypair, SECP256K1};
+
+    fn wrap(seckey: &str, recipient: &str, content: &str) -> Result<Event> {
+        let key = Keypair::from_seckey_str(SECP256K1, &seckey.repeat(32))?;
+        Ok(Event::create(
+            &key,
+            nostr_relay::db::now(),
+            crate::mls_gateway::GIFTWRAP_KIND,
+            vec![vec!["p".to_owned(), recipient.to_owned()]],
+            content.to_owned(),
+        )?)
+    }
+
+    #[test]
+    fn checks_structure() -> Result<()> {
+        let config = MlsGatewayConfig {
+            giftwrap_min_content_bytes: 4,
+            giftwrap_max_content_bytes: 8,
+            giftwrap_reject_known_identities: true,
+            admin_pubkeys: vec![hex::encode(
+                Keypair::from_seckey_str(SECP256K1, &"08".repeat(32))?
+                    .x_only_public_key()
+                    .0
+                    .serialize(),
+            )],
+            ..Default::default()
+        };
+        let now = nostr_relay::db::now();
+        let recipient = "aa".repeat(32);
+        let event = wrap("06", &recipient, "valid")?;
+        assert_eq!(structure(&event, &config, now), Ok(()));
+        // NIP-59 backdates up to two days
+        assert_eq!(structure(&event, &config, now + 2 * 86400), Ok(()));
+        assert_eq!(
+            structure(&event, &config, now + 4 * 86400),
+            Err(StructureIssue::CreatedAt)
+        );
+        assert_eq!(
+            structure(&event, &config, now - 3600),
+            Err(StructureIssue::CreatedAt)
+        );
+        assert_eq!(
+            structure(&wrap("06", &recipient, "abc")?, &config, now),
+            Err(StructureIssue::Size)
+        );
+        assert_eq!(
+            structure(&wrap("06", &recipient, "too long")?, &config, now),
+            Ok(())
+        );
+        assert_eq!(
+            structure(&wrap("06", &recipient, "far too long")?, &config, now),
+            Err(StructureIssue::Size)
+        );
+        assert_eq!(
+            structure(&wrap("08", &recipient, "valid")?, &config, now),
+            Err(StructureIssue::Identity)
+        );
+
+        let own = event.pubkey_str();
+        assert_eq!(
+            structure(&wrap("06", &own, "valid")?, &config, now),
+            Err(StructureIssue::Pubkey)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_replays_and_enforces_quota() -> Result<()> {
+        let config = MlsGatewayConfig {
+            giftwrap_daily_quota: 2,
+            ..Default::default()
+        };
+        let recipient = "aa".repeat(32);
+        let one = wrap("06", &recipient, "one")?;
+        assert_eq!(check(&one, &config), Verdict::Accept);
+        // a giftwrap rejected after ingress, or not stored, may be retried
+        assert_eq!(check(&one, &config), Verdict::Accept);
+        remember(&one);
+        // same ciphertext under a different ephemeral key
+        assert_eq!(
+            check(&wrap("07", &recipient, "one")?, &config),
+            Verdict::Duplicate
+        );
+        // accepted giftwraps are only charged once stored
+        assert_eq!(
+            check(&wrap("06", &recipient, "two")?, &config),
+            Verdict::Accept
+        );
+        assert_eq!(
+            check(&wrap("06", &recipient, "three")?, &config),
+            Verdict::Accept
+        );
+        assert!(charge(&recipient, 2));
+        assert!(charge(&recipient, 2));
+        assert!(!charge(&recipient, 2));
+        assert_eq!(
+            check(&wrap("06", &recipient, "four")?, &config),
+            Verdict::QuotaExceeded
+        );
+        // other recipients are unaffected
+        assert_eq!(
+            check(&wrap("06", &"bb".repeat(32), "one")?, &config),
+            Verdict::Accept
+        );
+        Ok(())
+    }
+}
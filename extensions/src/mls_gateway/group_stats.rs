@@ -0,0 +1,227 @@
+//! Group activity analytics
+//!
+//! Every group message (445) updates the counters of its group in storage
+//! (`GroupStats`) with atomic increments (Firestore field transforms, one SQL
+//! upsert), so messages recorded concurrently by several instances all count. `GET {api_prefix}/groups/{id}/stats` returns them to the
+//! group owner and admins (NIP-98) or the operator (admin bearer token), and a
+//! periodic job publishes how many groups are active, idle or abandoned plus
+//! the message counts of the busiest groups as Prometheus gauges.
+
+use super::admin::{self, AdminState};
+use super::firestore::{GroupStats, MAX_TRACKED_SENDERS};
+use super::{http_auth, MlsGatewayConfig, StorageBackend};
+use actix_web::{web, HttpRequest, HttpResponse, Result as ActixResult};
+use metrics::gauge;
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use tracing::warn;
+
+/// Activity windows published in `mls_gateway_groups_by_activity`
+const WINDOWS: &[(&str, i64)] = &[("hour", 3_600), ("day", 86_400), ("week", 604_800)];
+
+/// Number of groups per activity window, groups idle for `abandoned_days` are `abandoned`
+pub fn activity_buckets(
+    stats: &[GroupStats],
+    now: i64,
+    abandoned_days: u32,
+) -> Vec<(&'static str, usize)> {
+    let idle = |s: &GroupStats| now - s.last_activity;
+    let mut buckets: Vec<(&'static str, usize)> = WINDOWS
+        .iter()
+        .map(|(name, secs)| (*name, stats.iter().filter(|s| idle(s) <= *secs).count()))
+        .collect();
+    let abandoned = abandoned_days as i64 * 86_400;
+    buckets.push((
+        "abandoned",
+        stats.iter().filter(|s| idle(s) > abandoned).count(),
+    ));
+    buckets
+}
+
+/// Group ids with the most messages, busiest first
+pub fn busiest(stats: &[GroupStats], top: usize) -> Vec<&GroupStats> {
+    let mut sorted: Vec<&GroupStats> = stats.iter().collect();
+    sorted.sort_by(|a, b| {
+        b.message_count
+            .cmp(&a.message_count)
+            .then_with(|| a.group_id.cmp(&b.group_id))
+    });
+    sorted.truncate(top);
+    sorted
+}
+
+/// Counters of a group as returned by the API, without sender pubkeys
+pub fn summary(stats: &GroupStats, now: i64) -> Value {
+    json!({
+        "group_id": stats.group_id,
+        "message_count": stats.message_count,
+        "distinct_senders": stats.senders.len(),
+        "distinct_senders_capped": stats.senders.len() >= MAX_TRACKED_SENDERS,
+        "first_epoch": stats.first_epoch,
+        "last_epoch": stats.last_epoch,
+        "epoch_changes": stats.epoch_changes,
+        "first_activity": stats.first_activity,
+        "last_activity": stats.last_activity,
+        "idle_secs": (now - stats.last_activity).max(0),
+    })
+}
+
+/// Publish activity gauges every `group_stats_interval_secs`
+pub fn spawn_metrics(store: StorageBackend, config: MlsGatewayConfig) {
+    if config.group_stats_interval_secs == 0 {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(
+            config.group_stats_interval_secs,
+        ));
+        // groups published in the previous round, zeroed once they leave the top
+        let mut published: HashSet<String> = HashSet::new();
+        loop {
+            interval.tick().await;
+            let stats = match store.list_group_stats().await {
+                Ok(stats) => stats,
+                Err(e) => {
+                    warn!("Failed to list group stats: {}", e);
+                    continue;
+                }
+            };
+            let now = chrono::Utc::now().timestamp();
+            for (window, count) in activity_buckets(&stats, now, config.group_abandoned_days) {
+                gauge!("mls_gateway_groups_by_activity", "window" => window).set(count as f64);
+            }
+            let mut current = HashSet::new();
+            for s in busiest(&stats, config.group_stats_top) {
+                gauge!("mls_gateway_group_messages", "group_id" => s.group_id.clone())
+                    .set(s.message_count as f64);
+                current.insert(s.group_id.clone());
+            }
+            for group_id in published.difference(&current) {
+                gauge!("mls_gateway_group_messages", "group_id" => group_id.clone()).set(0.0);
+            }
+            published = current;
+        }
+    });
+}
+
+pub struct StatsState {
+    pub store: StorageBackend,
+    /// Admin scope state, its bearer token is accepted for any group
+    pub admin: Option<AdminState>,
+}
+
+pub fn configure_stats_routes(cfg: &mut web::ServiceConfig, prefix: &str, state: StatsState) {
+    cfg.service(
+        web::resource(format!("{}/groups/{{id}}/stats", prefix))
+            .app_data(web::Data::new(state))
+            .route(web::get().to(get_group_stats)),
+    );
+}
+
+/// Whether the request carries the admin token or a NIP-98 auth of a group owner or admin
+async fn authorized(
+    req: &HttpRequest,
+    state: &StatsState,
+    group_id: &str,
+) -> Result<(), HttpResponse> {
+    if state
+        .admin
+        .as_ref()
+        .map_or(false, |admin| admin::authorized(req, admin))
+    {
+        return Ok(());
+    }
+    let pubkey = http_auth::verify(req)
+        .map_err(|e| HttpResponse::Unauthorized().json(json!({ "ok": false, "error": e })))?;
+    let allowed = match state.store.is_owner(group_id, &pubkey).await {
+        Ok(true) => Ok(true),
+        Ok(false) => state.store.is_admin(group_id, &pubkey).await,
+        Err(e) => Err(e),
+    };
+    match allowed {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(HttpResponse::Forbidden().json(json!({
+            "ok": false,
+            "error": "only group owners and admins can read group stats"
+        }))),
+        Err(e) => {
+            warn!("Failed to check group roles of {}: {}", pubkey, e);
+            Err(HttpResponse::InternalServerError()
+                .json(json!({ "ok": false, "error": "storage error" })))
+        }
+    }
+}
+
+/// Activity counters of a group
+async fn get_group_stats(
+    req: HttpRequest,
+    state: web::Data<StatsState>,
+    path: web::Path<String>,
+) -> ActixResult<HttpResponse> {
+    let group_id = path.into_inner();
+    if let Err(resp) = authorized(&req, &state, &group_id).await {
+        return Ok(resp);
+    }
+    match state.store.get_group_stats(&group_id).await {
+        Ok(Some(stats)) => Ok(HttpResponse::Ok().json(json!({
+            "ok": true,
+            "stats": summary(&stats, chrono::Utc::now().timestamp())
+        }))),
+        Ok(None) => {
+            Ok(HttpResponse::NotFound()
+                .json(json!({ "ok": false, "error": "no activity recorded" })))
+        }
+        Err(e) => Ok(HttpResponse::InternalServerError()
+            .json(json!({ "ok": false, "error": e.to_string() }))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats(group_id: &str, message_count: u64, last_activity: i64) -> GroupStats {
+        GroupStats {
+            message_count,
+            last_activity,
+            ..GroupStats::new(group_id, 0)
+        }
+    }
+
+    #[test]
+    fn record_counts_senders_and_epochs() {
+        let mut s = GroupStats::new("g", 100);
+        s.record("alice", Some(1), 100);
+        s.record("bob", Some(1), 110);
+        s.record("alice", Some(3), 120);
+        s.record("bob", Some(2), 105);
+        s.record("carol", None, 130);
+        assert_eq!(s.message_count, 5);
+        assert_eq!(s.senders, vec!["alice", "bob", "carol"]);
+        assert_eq!(
+            (s.first_epoch, s.last_epoch, s.epoch_changes),
+            (Some(1), Some(3), 1)
+        );
+        assert_eq!((s.first_activity, s.last_activity), (100, 130));
+        assert_eq!(summary(&s, 200)["idle_secs"], 70);
+    }
+
+    #[test]
+    fn buckets_and_busiest() {
+        let now = 100 * 86_400;
+        let all = vec![
+            stats("a", 5, now - 60),
+            stats("b", 50, now - 7_200),
+            stats("c", 1, now - 40 * 86_400),
+        ];
+        assert_eq!(
+            activity_buckets(&all, now, 30),
+            vec![("hour", 1), ("day", 2), ("week", 2), ("abandoned", 1)]
+        );
+        let top: Vec<&str> = busiest(&all, 2)
+            .iter()
+            .map(|s| s.group_id.as_str())
+            .collect();
+        assert_eq!(top, vec!["b", "a"]);
+    }
+}
@@ -1,5 +1,8 @@
 //! Group registry services for MLS messaging
 
+use crate::mls_gateway::firestore::RosterPolicyDocument;
+use std::collections::BTreeSet;
+
 /// Group registry service functions
 pub struct GroupRegistry;
 
@@ -8,4 +11,57 @@ impl GroupRegistry {
     pub fn new() -> Self {
         Self
     }
-}
\ No newline at end of file
+
+    /// Replay roster/policy history (ordered by sequence) into the current member set
+    pub fn members(history: &[RosterPolicyDocument]) -> BTreeSet<String> {
+        let mut members = BTreeSet::new();
+        for record in history {
+            match record.operation.as_str() {
+                "bootstrap" | "replace" => {
+                    members = record.member_pubkeys.iter().cloned().collect();
+                }
+                "add" => members.extend(record.member_pubkeys.iter().cloned()),
+                "remove" => {
+                    for pubkey in &record.member_pubkeys {
+                        members.remove(pubkey);
+                    }
+                }
+                // promote/demote change roles, not membership
+                _ => {}
+            }
+        }
+        members
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(sequence: u64, operation: &str, members: &[&str]) -> RosterPolicyDocument {
+        RosterPolicyDocument {
+            group_id: "g".to_string(),
+            sequence,
+            operation: operation.to_string(),
+            member_pubkeys: members.iter().map(|m| m.to_string()).collect(),
+            admin_pubkey: "admin".to_string(),
+            created_at: 0,
+            updated_at: 0,
+        }
+    }
+
+    #[test]
+    fn replay_members() {
+        let history = vec![
+            record(1, "bootstrap", &["a"]),
+            record(2, "add", &["b", "c"]),
+            record(3, "promote", &["b"]),
+            record(4, "remove", &["a"]),
+        ];
+        let members = GroupRegistry::members(&history);
+        assert_eq!(members.into_iter().collect::<Vec<_>>(), vec!["b", "c"]);
+
+        let history = vec![record(1, "add", &["a"]), record(2, "replace", &["d"])];
+        assert_eq!(GroupRegistry::members(&history).len(), 1);
+    }
+}
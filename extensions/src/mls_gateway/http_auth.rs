@@ -0,0 +1,93 @@
+//! NIP-98 HTTP authentication
+//!
+//! Clients prove control of a pubkey on REST calls with a signed kind 27235
+//! event in `Authorization: Nostr <base64(event)>`. The event must name the
+//! request method and path and be created within [`MAX_AGE`] seconds.
+
+use actix_web::HttpRequest;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use nostr_relay::db::{now, Event};
+use sha2::{Digest, Sha256};
+
+/// NIP-98 HTTP auth event kind
+pub const HTTP_AUTH_KIND: u16 = 27235;
+
+/// Allowed clock skew of the auth event in seconds
+pub const MAX_AGE: u64 = 60;
+
+fn tag<'a>(event: &'a Event, name: &str) -> Option<&'a str> {
+    event
+        .tags()
+        .iter()
+        .find(|t| t.len() >= 2 && t[0] == name)
+        .map(|t| t[1].as_str())
+}
+
+/// Path and query of an absolute url
+fn url_path(url: &str) -> &str {
+    let rest = url.split_once("://").map_or(url, |(_, rest)| rest);
+    rest.find('/').map_or("/", |i| &rest[i..])
+}
+
+/// Verify the NIP-98 authorization of a request, returns the hex pubkey
+pub fn verify(req: &HttpRequest) -> Result<String, String> {
+    auth_event(req).map(|event| hex::encode(event.pubkey()))
+}
+
+/// Verify the NIP-98 authorization of a request whose `payload` tag must be the sha256 of `body`
+pub fn verify_with_payload(req: &HttpRequest, body: &[u8]) -> Result<String, String> {
+    let event = auth_event(req)?;
+    let digest = hex::encode(Sha256::digest(body));
+    if !tag(&event, "payload").map_or(false, |p| p.eq_ignore_ascii_case(&digest)) {
+        return Err("auth event payload mismatch".to_string());
+    }
+    Ok(hex::encode(event.pubkey()))
+}
+
+fn auth_event(req: &HttpRequest) -> Result<Event, String> {
+    let header = req
+        .headers()
+        .get(actix_web::http::header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Nostr "))
+        .ok_or("missing Nostr authorization")?;
+    let json = STANDARD
+        .decode(header.trim())
+        .map_err(|_| "invalid authorization encoding")?;
+    let event: Event = serde_json::from_slice(&json).map_err(|_| "invalid authorization event")?;
+    let now = now();
+    verify_event(&event, req.method().as_str(), &req.uri().to_string(), now)?;
+    Ok(event)
+}
+
+/// Check an auth event against the request method and uri
+pub fn verify_event(event: &Event, method: &str, uri: &str, now: u64) -> Result<(), String> {
+    if event.kind() != HTTP_AUTH_KIND {
+        return Err("auth event kind must be 27235".to_string());
+    }
+    if event.created_at().abs_diff(now) > MAX_AGE {
+        return Err("auth event expired".to_string());
+    }
+    if !tag(event, "method").map_or(false, |m| m.eq_ignore_ascii_case(method)) {
+        return Err("auth event method mismatch".to_string());
+    }
+    if tag(event, "u").map(url_path) != Some(url_path(uri)) {
+        return Err("auth event url mismatch".to_string());
+    }
+    event.validate(now, 0, 0).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn path_of_url() {
+        assert_eq!(
+            url_path("https://relay.example.com/api/v1/push?x=1"),
+            "/api/v1/push?x=1"
+        );
+        assert_eq!(url_path("https://relay.example.com"), "/");
+        assert_eq!(url_path("/api/v1/push"), "/api/v1/push");
+    }
+}
@@ -3,14 +3,28 @@
 //! This module implements automatic consumption of KeyPackages when they are
 //! queried via standard REQ messages. No special kind 447 requests are needed.
 
+use crate::mls_gateway::firestore::KeyPackageRequestRateLimit;
 use crate::mls_gateway::StorageBackend;
+use chrono::{DateTime, Utc};
+use metrics::counter;
 use nostr_relay::db::{Event, Filter};
+use once_cell::sync::OnceCell;
 use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::sync::RwLock;
-use chrono::{DateTime, Utc};
-use tracing::{info, error};
-use metrics::counter;
+use tracing::{error, info, warn};
+
+static RATE_LIMITER: OnceCell<KeyPackageRateLimiter> = OnceCell::new();
+
+/// Initialize the global KeyPackage query rate limiter backed by `store`
+pub fn init_rate_limiter(store: StorageBackend) {
+    let _ = RATE_LIMITER.set(KeyPackageRateLimiter::with_store(store));
+}
+
+/// Get the global KeyPackage query rate limiter
+pub fn rate_limiter() -> Option<&'static KeyPackageRateLimiter> {
+    RATE_LIMITER.get()
+}
 
 /// Tracks which events have been delivered to which requesters
 /// This helps us consume KeyPackages after they've been sent
@@ -32,45 +46,50 @@ impl ConsumptionTracker {
             delivered: Arc::new(RwLock::new(HashMap::new())),
         }
     }
-    
+
     /// Record that an event was delivered to a requester
-    pub async fn record_delivery(
-        &self,
-        event_id: &str,
-        requester_pubkey: &str,
-    ) {
+    pub async fn record_delivery(&self, event_id: &str, requester_pubkey: &str) {
         let mut delivered = self.delivered.write().await;
         let record = DeliveryRecord {
             requester_pubkey: requester_pubkey.to_string(),
             delivered_at: Utc::now(),
         };
-        
+
         delivered
             .entry(event_id.to_string())
             .or_insert_with(Vec::new)
             .push(record);
     }
-    
+
     /// Get all event IDs that were delivered to a requester
     pub async fn get_delivered_to(&self, requester_pubkey: &str) -> Vec<String> {
         let delivered = self.delivered.read().await;
         let mut event_ids = Vec::new();
-        
+
         for (event_id, records) in delivered.iter() {
-            if records.iter().any(|r| r.requester_pubkey == requester_pubkey) {
+            if records
+                .iter()
+                .any(|r| r.requester_pubkey == requester_pubkey)
+            {
                 event_ids.push(event_id.clone());
             }
         }
-        
+
         event_ids
     }
 }
 
 /// Rate limiter for KeyPackage queries
+///
+/// Query times are cached in process and written through to storage, so the
+/// sliding window survives restarts; a pair missing from the cache is loaded
+/// from storage on its first query.
 #[derive(Debug, Clone)]
 pub struct KeyPackageRateLimiter {
     /// Map from (requester, author) to query timestamps
     queries: Arc<RwLock<HashMap<(String, String), Vec<DateTime<Utc>>>>>,
+    /// Persistent sliding windows
+    store: Option<StorageBackend>,
     /// Max queries per hour per requester-author pair
     max_queries_per_hour: u32,
     /// Max KeyPackages per query
@@ -81,52 +100,119 @@ impl KeyPackageRateLimiter {
     pub fn new() -> Self {
         Self {
             queries: Arc::new(RwLock::new(HashMap::new())),
+            store: None,
             max_queries_per_hour: 10,
             max_keypackages_per_query: 2,
         }
     }
-    
+
+    /// Rate limiter persisting its windows in `store`
+    pub fn with_store(store: StorageBackend) -> Self {
+        Self {
+            store: Some(store),
+            ..Self::new()
+        }
+    }
+
+    /// Load the stored window of a pair not cached yet
+    async fn load(&self, key: &(String, String)) {
+        let Some(store) = &self.store else {
+            return;
+        };
+        if self.queries.read().await.contains_key(key) {
+            return;
+        }
+        let times = match store
+            .get_keypackage_request_rate_limit(&key.0, &key.1)
+            .await
+        {
+            Ok(Some(limit)) => limit
+                .request_times
+                .iter()
+                .filter_map(|t| DateTime::from_timestamp(*t, 0))
+                .collect(),
+            Ok(None) => Vec::new(),
+            Err(e) => {
+                warn!(
+                    "Failed to load KeyPackage rate limit for {}:{}: {}",
+                    key.0, key.1, e
+                );
+                Vec::new()
+            }
+        };
+        self.queries
+            .write()
+            .await
+            .entry(key.clone())
+            .or_insert(times);
+    }
+
+    /// Write the window of a pair through to storage
+    async fn persist(&self, key: &(String, String), times: &[DateTime<Utc>]) {
+        let Some(store) = &self.store else {
+            return;
+        };
+        let limit = KeyPackageRequestRateLimit {
+            requester_pubkey: key.0.clone(),
+            recipient_pubkey: key.1.clone(),
+            request_count: times.len() as u32,
+            window_start: times.first().copied().unwrap_or_else(Utc::now),
+            request_times: times.iter().map(|t| t.timestamp()).collect(),
+        };
+        if let Err(e) = store.put_keypackage_request_rate_limit(&limit).await {
+            warn!(
+                "Failed to persist KeyPackage rate limit for {}:{}: {}",
+                key.0, key.1, e
+            );
+        }
+    }
+
     /// Check if a query is allowed
-    pub async fn check_rate_limit(
-        &self,
-        requester: &str,
-        author: &str,
-    ) -> Result<bool, String> {
+    pub async fn check_rate_limit(&self, requester: &str, author: &str) -> Result<bool, String> {
+        let key = (requester.to_string(), author.to_string());
+        self.load(&key).await;
+
         let now = Utc::now();
         let hour_ago = now - chrono::Duration::hours(1);
-        
+
         let mut queries = self.queries.write().await;
-        let key = (requester.to_string(), author.to_string());
-        
+
         // Get or create query list
-        let query_list = queries.entry(key).or_insert_with(Vec::new);
-        
+        let query_list = queries.entry(key.clone()).or_insert_with(Vec::new);
+
         // Remove old queries
         query_list.retain(|&t| t > hour_ago);
-        
+
         // Check limit
         if query_list.len() >= self.max_queries_per_hour as usize {
             counter!("mls_gateway_rate_limit_exceeded", 
                      "requester" => requester.to_string(),
                      "author" => author.to_string())
-                .increment(1);
-                
-            let minutes_until_reset = 60 - query_list[0].signed_duration_since(hour_ago).num_minutes();
+            .increment(1);
+
+            let minutes_until_reset =
+                60 - query_list[0].signed_duration_since(hour_ago).num_minutes();
             return Err(format!(
-                "Rate limit exceeded. Try again in {} minutes.", 
+                "Rate limit exceeded. Try again in {} minutes.",
                 minutes_until_reset
             ));
         }
-        
+
         // Record this query
         query_list.push(now);
+        let times = query_list.clone();
+        drop(queries);
+        self.persist(&key, &times).await;
         Ok(true)
     }
 }
 
 /// Helper to check if a filter is querying for KeyPackages
 pub fn is_keypackage_query(filter: &Filter) -> bool {
-    filter.kinds.iter().any(|&k| k == 443)
+    filter
+        .kinds
+        .iter()
+        .any(|&k| k == super::kinds::number(super::KEYPACKAGE_KIND))
 }
 
 /// Extract authors from a KeyPackage query filter
@@ -134,8 +220,10 @@ pub fn extract_keypackage_authors(filter: &Filter) -> Vec<String> {
     if !is_keypackage_query(filter) {
         return vec![];
     }
-    
-    filter.authors.iter()
+
+    filter
+        .authors
+        .iter()
         .map(|author| hex::encode(author))
         .collect()
 }
@@ -148,43 +236,55 @@ pub async fn process_keypackage_delivery(
     author_pubkey: &str,
 ) -> anyhow::Result<()> {
     // Only process KeyPackage events
-    let keypackage_events: Vec<_> = events.iter()
-        .filter(|e| e.kind() == 443)
+    let keypackage_events: Vec<_> = events
+        .iter()
+        .filter(|e| super::kinds::canonical(e.kind()) == super::KEYPACKAGE_KIND)
         .collect();
-    
+
     if keypackage_events.is_empty() {
         return Ok(());
     }
-    
-    info!("Processing delivery of {} KeyPackages from {} to {}",
-          keypackage_events.len(), author_pubkey, requester_pubkey);
-    
+
+    info!(
+        "Processing delivery of {} KeyPackages from {} to {}",
+        keypackage_events.len(),
+        author_pubkey,
+        requester_pubkey
+    );
+
     // Get total count for this author
     let total_count = storage.count_user_keypackages(author_pubkey).await?;
-    
+
     // Determine which KeyPackages to consume
     let mut to_consume = Vec::new();
     for (idx, event) in keypackage_events.iter().enumerate() {
         // Never consume the last KeyPackage
         let would_be_last = (total_count as usize) - to_consume.len() <= 1;
-        
+
         if !would_be_last {
             to_consume.push(event.id_str());
             info!("Marking KeyPackage {} for consumption", event.id_str());
         } else {
-            info!("Preserving last KeyPackage {} for {}", event.id_str(), author_pubkey);
+            info!(
+                "Preserving last KeyPackage {} for {}",
+                event.id_str(),
+                author_pubkey
+            );
         }
     }
-    
+
     // Consume the KeyPackages
     for event_id in &to_consume {
         match storage.delete_consumed_keypackage(event_id).await {
             Ok(deleted) => {
                 if deleted {
-                    info!("Consumed KeyPackage {} after delivery to {}", event_id, requester_pubkey);
+                    info!(
+                        "Consumed KeyPackage {} after delivery to {}",
+                        event_id, requester_pubkey
+                    );
                     counter!("mls_gateway_keypackages_consumed",
                              "owner" => author_pubkey.to_string())
-                        .increment(1);
+                    .increment(1);
                 }
             }
             Err(e) => {
@@ -192,18 +292,20 @@ pub async fn process_keypackage_delivery(
             }
         }
     }
-    
+
     // Update delivery metrics
     counter!("mls_gateway_keypackages_served",
              "requester" => requester_pubkey.to_string(),
              "owner" => author_pubkey.to_string())
-        .increment(keypackage_events.len() as u64);
-    
-    info!("KeyPackage delivery complete: {} delivered, {} consumed, {} remaining",
-          keypackage_events.len(),
-          to_consume.len(),
-          total_count - to_consume.len() as u32);
-    
+    .increment(keypackage_events.len() as u64);
+
+    info!(
+        "KeyPackage delivery complete: {} delivered, {} consumed, {} remaining",
+        keypackage_events.len(),
+        to_consume.len(),
+        total_count - to_consume.len() as u32
+    );
+
     Ok(())
 }
 
@@ -217,61 +319,120 @@ pub async fn consume_keypackage(
 ) -> anyhow::Result<bool> {
     // Check if this would be the last KeyPackage for the user
     let count = storage.count_user_keypackages(owner_pubkey).await?;
-    
+
     if count <= 1 {
-        info!("Not consuming KeyPackage {} - it's the last resort for {}", event_id, owner_pubkey);
+        info!(
+            "Not consuming KeyPackage {} - it's the last resort for {}",
+            event_id, owner_pubkey
+        );
         return Ok(false);
     }
-    
+
     // Consume the KeyPackage
     let deleted = storage.delete_consumed_keypackage(event_id).await?;
-    
+
     if deleted {
-        info!("Consumed KeyPackage {} for owner {}", event_id, owner_pubkey);
-        counter!("mls_gateway_keypackages_consumed", "owner" => owner_pubkey.to_string()).increment(1);
+        info!(
+            "Consumed KeyPackage {} for owner {}",
+            event_id, owner_pubkey
+        );
+        counter!("mls_gateway_keypackages_consumed", "owner" => owner_pubkey.to_string())
+            .increment(1);
     }
-    
+
     Ok(deleted)
 }
 
+/// Referenced KeyPackage of a consumed notice: the `e` tag and the optional `p` owner
+pub fn consumed_reference(event: &Event) -> Result<(String, Option<String>), &'static str> {
+    let mut ids = event
+        .tags()
+        .iter()
+        .filter(|tag| tag.len() >= 2 && tag[0] == "e");
+    let id = match (ids.next(), ids.next()) {
+        (Some(tag), None) => tag[1].to_lowercase(),
+        (None, _) => return Err("missing e tag referencing the consumed keypackage"),
+        (Some(_), Some(_)) => return Err("exactly one e tag expected"),
+    };
+    if id.len() != 64 || hex::decode(&id).is_err() {
+        return Err("e tag is not an event id");
+    }
+    let owner = event
+        .tags()
+        .iter()
+        .find(|tag| tag.len() >= 2 && tag[0] == "p")
+        .map(|tag| tag[1].to_lowercase());
+    Ok((id, owner))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use nostr_relay::db::SortList;
-    
+
     #[tokio::test]
     async fn test_rate_limiter() {
         let limiter = KeyPackageRateLimiter::new();
-        
+
         // First 10 queries should be allowed
         for i in 0..10 {
             let allowed = limiter.check_rate_limit("alice", "bob").await;
             assert!(allowed.is_ok(), "Query {} should be allowed", i);
         }
-        
+
         // 11th query should be rate limited
         let allowed = limiter.check_rate_limit("alice", "bob").await;
         assert!(allowed.is_err(), "11th query should be rate limited");
-        
+
         // Different author should still be allowed
         let allowed = limiter.check_rate_limit("alice", "carol").await;
-        assert!(allowed.is_ok(), "Different author should have separate limit");
+        assert!(
+            allowed.is_ok(),
+            "Different author should have separate limit"
+        );
     }
-    
+
     #[test]
     fn test_keypackage_filter_detection() {
         // Filter with kind 443 should be detected
         let mut filter = Filter::default();
         filter.kinds = SortList::from(vec![443]);
         assert!(is_keypackage_query(&filter));
-        
+
         // Filter without kinds should not be detected
         let filter = Filter::default();
         assert!(!is_keypackage_query(&filter));
-        
+
         // Filter with different kind should not be detected
         let mut filter = Filter::default();
         filter.kinds = SortList::from(vec![1, 2, 3]);
         assert!(!is_keypackage_query(&filter));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_consumed_reference() -> anyhow::Result<()> {
+        use nostr_relay::db::secp256k1::{Keypair, SECP256K1};
+        let key = Keypair::from_seckey_str(SECP256K1, &"09".repeat(32))?;
+        let notice = |tags: Vec<Vec<String>>| Event::create(&key, 1, 449, tags, String::new());
+        let id = "ab".repeat(32);
+        let owner = "cd".repeat(32);
+
+        let event = notice(vec![
+            vec!["e".into(), id.clone()],
+            vec!["p".into(), owner.clone()],
+        ])?;
+        assert_eq!(consumed_reference(&event), Ok((id.clone(), Some(owner))));
+        assert_eq!(
+            consumed_reference(&notice(vec![vec!["e".into(), id.clone()]])?),
+            Ok((id.clone(), None))
+        );
+        assert!(consumed_reference(&notice(vec![])?).is_err());
+        assert!(consumed_reference(&notice(vec![vec!["e".into(), "xyz".into()]])?).is_err());
+        assert!(consumed_reference(&notice(vec![
+            vec!["e".into(), id.clone()],
+            vec!["e".into(), id]
+        ])?)
+        .is_err());
+        Ok(())
+    }
+}
@@ -3,7 +3,9 @@
 //! Stage-1 policy:
 //! - Ingest accepts hex by default (no `encoding` tag) and base64 when `encoding=base64`.
 //! - Firestore stores canonical **standard base64 with padding**.
-//! - Delivery defaults to hex (legacy clients).
+//! - Delivery defaults to hex (legacy clients); a `#f: ["base64"]` REQ filter
+//!   selects base64 for KeyPackages rebuilt from Firestore. Signed events from
+//!   LMDB are served unchanged in the encoding their author chose.
 
 use anyhow::{anyhow, bail, Result};
 
@@ -94,7 +96,10 @@ pub fn encode_hex(bytes: &[u8]) -> String {
 }
 
 /// Convert incoming event (tags + content) into canonical base64 for Firestore storage.
-pub fn canonical_base64_from_event(tags: &[Vec<String>], content: &str) -> Result<(DeclaredEncoding, String)> {
+pub fn canonical_base64_from_event(
+    tags: &[Vec<String>],
+    content: &str,
+) -> Result<(DeclaredEncoding, String)> {
     let declared = declared_encoding_from_tags(tags)?;
     let bytes = decode_keypackage_content(content, declared)?;
     Ok((declared, encode_canonical_base64(&bytes)))
@@ -123,7 +128,9 @@ pub fn hex_from_firestore_content(content: &str) -> Result<String> {
 }
 
 pub fn base64_from_firestore_content(content: &str) -> Result<String> {
-    Ok(encode_canonical_base64(&bytes_from_firestore_content(content)?))
+    Ok(encode_canonical_base64(&bytes_from_firestore_content(
+        content,
+    )?))
 }
 
 #[cfg(test)]
@@ -0,0 +1,116 @@
+//! KeyPackage expiry in the local event store
+//!
+//! KeyPackages (443) carry their expiry in an MLS `exp` tag rather than a
+//! NIP-40 `expiration` tag, so the event store keeps serving them once expired.
+//! Expired KeyPackages are dropped from query results and swept from LMDB on an
+//! interval, the storage backend is cleaned up by the hourly keypackage job.
+
+use super::{kinds, KEYPACKAGE_KIND};
+use anyhow::Result;
+use metrics::counter;
+use nostr_relay::db::{now, Db, Event, Filter, SortList};
+use std::{sync::Arc, time::Duration};
+use tracing::{info, warn};
+
+/// Expiry of a KeyPackage from its `exp` tag, `None` for other events
+pub fn expiry(event: &Event) -> Option<u64> {
+    if kinds::canonical(event.kind()) != KEYPACKAGE_KIND {
+        return None;
+    }
+    event
+        .tags()
+        .iter()
+        .find(|tag| tag.len() > 1 && tag[0] == "exp")
+        .and_then(|tag| tag[1].parse::<u64>().ok())
+}
+
+/// Drop KeyPackages expired before `now` from query results
+pub fn filter_events(events: Vec<Event>, now: u64) -> Vec<Event> {
+    events
+        .into_iter()
+        .filter(|e| expiry(e).map_or(true, |exp| exp > now))
+        .collect()
+}
+
+/// Delete the KeyPackages expired before `now` from LMDB
+pub fn sweep(db: &Db, now: u64) -> Result<usize> {
+    let filter = Filter {
+        kinds: SortList::from(vec![kinds::number(KEYPACKAGE_KIND)]),
+        ..Default::default()
+    };
+    let ids = {
+        let reader = db.reader()?;
+        let mut ids = Vec::new();
+        for event in db.iter::<Event, _>(&reader, &filter)? {
+            let event = event?;
+            if expiry(&event).map_or(false, |exp| exp <= now) {
+                ids.push(event.id().to_vec());
+            }
+        }
+        ids
+    };
+    if !ids.is_empty() {
+        db.batch_del(&ids)?;
+        counter!("mls_gateway_keypackages_expired_swept").increment(ids.len() as u64);
+    }
+    Ok(ids.len())
+}
+
+/// Sweep expired KeyPackages from LMDB every `interval`
+pub fn spawn_sweep(db: Arc<Db>, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            let db = db.clone();
+            match tokio::task::spawn_blocking(move || sweep(&db, now())).await {
+                Ok(Ok(count)) if count > 0 => {
+                    info!("Swept {} expired KeyPackages from LMDB", count)
+                }
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => warn!("KeyPackage sweep failed: {}", e),
+                Err(e) => warn!("KeyPackage sweep panicked: {}", e),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nostr_relay::db::secp256k1::{Keypair, SECP256K1};
+
+    fn event(kind: u16, exp: &str) -> Result<Event> {
+        let key = Keypair::from_seckey_str(SECP256K1, &"09".repeat(32))?;
+        Ok(Event::create(
+            &key,
+            1,
+            kind,
+            vec![vec!["exp".to_owned(), exp.to_owned()]],
+            "x".to_owned(),
+        )?)
+    }
+
+    #[test]
+    fn expired_keypackages() -> Result<()> {
+        let expired = event(KEYPACKAGE_KIND, "10")?;
+        let valid = event(KEYPACKAGE_KIND, "30")?;
+        // exp only means something on keypackages
+        let other = event(1, "10")?;
+        assert_eq!(expiry(&expired), Some(10));
+        assert_eq!(expiry(&other), None);
+        assert_eq!(
+            filter_events(vec![expired.clone(), valid.clone(), other.clone()], 20).len(),
+            2
+        );
+
+        let dir = tempfile::tempdir()?;
+        let db = Db::open(dir.path())?;
+        db.batch_put(vec![expired, valid.clone(), other])?;
+        assert_eq!(sweep(&db, 20)?, 1);
+        assert_eq!(sweep(&db, 20)?, 0);
+        let reader = db.reader()?;
+        assert_eq!(db.iter::<Event, _>(&reader, &Filter::default())?.count(), 2);
+        Ok(())
+    }
+}
@@ -0,0 +1,121 @@
+//! KeyPackage (443) ingress policy: ciphersuites, protocol versions, duplicates.
+//!
+//! KeyPackages declaring a ciphersuite outside `allowed_ciphersuites` or an
+//! `mls_protocol_version` outside `accepted_protocol_versions` are rejected at
+//! ingress and never stored. An empty `allowed_ciphersuites` accepts any suite.
+//!
+//! KeyPackages republishing the bytes of an unexpired KeyPackage of the same
+//! owner are rejected as duplicates; stored content hashes are remembered per
+//! instance for the ingress check, the store lookup covers other instances.
+
+use super::MlsGatewayConfig;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+
+/// (owner, content hash) of stored KeyPackages with their expiry
+static STORED: Lazy<Mutex<HashMap<(String, String), i64>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Remember the content hash of a stored KeyPackage until it expires
+pub fn remember(owner: &str, content_hash: &str, expires_at: i64) {
+    STORED
+        .lock()
+        .insert((owner.to_string(), content_hash.to_string()), expires_at);
+}
+
+/// Whether the owner already has an unexpired KeyPackage with this content hash on this instance
+pub fn is_duplicate(owner: &str, content_hash: &str, now: i64) -> bool {
+    let mut stored = STORED.lock();
+    stored.retain(|_, expires_at| *expires_at > now);
+    stored.contains_key(&(owner.to_string(), content_hash.to_string()))
+}
+
+/// Parse a ciphersuite tag value, `0x0001` or decimal `1`
+pub fn parse_ciphersuite(value: &str) -> Option<u16> {
+    let value = value.trim();
+    match value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+    {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+/// Canonical `0x%04x` form of a ciphersuite tag value, used as metric label
+pub fn canonical_ciphersuite(value: &str) -> Option<String> {
+    parse_ciphersuite(value).map(|cs| format!("0x{:04x}", cs))
+}
+
+fn tag_value<'a>(tags: &'a [Vec<String>], name: &str) -> Option<&'a str> {
+    tags.iter()
+        .find(|tag| tag.len() >= 2 && tag[0] == name)
+        .map(|tag| tag[1].as_str())
+}
+
+/// Check declared ciphersuite and protocol version, returns the rejection reason
+pub fn check(tags: &[Vec<String>], config: &MlsGatewayConfig) -> Result<(), String> {
+    if let Some(version) = tag_value(tags, "mls_protocol_version") {
+        if !config.accepted_protocol_versions.is_empty()
+            && !config
+                .accepted_protocol_versions
+                .iter()
+                .any(|v| v == version)
+        {
+            return Err(format!("unsupported mls_protocol_version {}", version));
+        }
+    }
+    if let Some(suite) = tag_value(tags, "ciphersuite") {
+        if config.allowed_ciphersuites.is_empty() {
+            return Ok(());
+        }
+        let allowed = parse_ciphersuite(suite).is_some_and(|cs| {
+            config
+                .allowed_ciphersuites
+                .iter()
+                .any(|a| parse_ciphersuite(a) == Some(cs))
+        });
+        if !allowed {
+            return Err(format!("unsupported ciphersuite {}", suite));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tags(version: &str, suite: &str) -> Vec<Vec<String>> {
+        vec![
+            vec!["mls_protocol_version".to_owned(), version.to_owned()],
+            vec!["ciphersuite".to_owned(), suite.to_owned()],
+        ]
+    }
+
+    #[test]
+    fn allowlists() {
+        let mut config = MlsGatewayConfig::default();
+        config.allowed_ciphersuites = vec!["0x0001".to_owned(), "3".to_owned()];
+        assert!(check(&tags("1.0", "0x0001"), &config).is_ok());
+        assert!(check(&tags("1.0", "1"), &config).is_ok());
+        assert!(check(&tags("1.0", "0x0003"), &config).is_ok());
+        assert!(check(&tags("1.0", "0x0002"), &config).is_err());
+        assert!(check(&tags("1.0", "nope"), &config).is_err());
+        assert!(check(&tags("2.0", "0x0001"), &config).is_err());
+
+        config.allowed_ciphersuites.clear();
+        assert!(check(&tags("1.0", "0x0002"), &config).is_ok());
+        assert_eq!(canonical_ciphersuite("3").as_deref(), Some("0x0003"));
+    }
+
+    #[test]
+    fn duplicate_content() {
+        remember("owner-a", "h1", 100);
+        assert!(is_duplicate("owner-a", "h1", 50));
+        assert!(!is_duplicate("owner-b", "h1", 50));
+        // forgotten once expired
+        assert!(!is_duplicate("owner-a", "h1", 100));
+    }
+}
@@ -0,0 +1,158 @@
+//! Configurable MLS kind numbers
+//!
+//! The gateway handles events by their MLS role. `[extensions.mls_gateway.kinds]`
+//! sets the kind number of each role, defaulting to the NIP-EE numbers, so
+//! deployments tracking draft renumberings or experimental kind ranges do not
+//! need a fork:
+//!
+//! ```toml
+//! [extensions.mls_gateway.kinds]
+//! keypackage = 30443
+//! group_message = 1445
+//! ```
+//!
+//! Handlers keep matching on the built-in numbers: `canonical` maps a received
+//! kind to the built-in kind of its role and `number` gives the configured
+//! number of a built-in kind for filters and outgoing events. A built-in number
+//! assigned to no role is not handled anymore. Settings keyed by kind
+//! (`archived_kinds`, `backfill_kinds`, `export_kinds`, `validation_mode`) use
+//! configured numbers, the kind lists left at their defaults follow the map.
+
+use super::{
+    GIFTWRAP_KIND, KEYPACKAGE_CONSUMED_KIND, KEYPACKAGE_KIND, KEYPACKAGE_RELAYS_LIST_KIND,
+    MLS_GROUP_MESSAGE_KIND, NOISE_DM_KIND, ROSTER_POLICY_KIND, WELCOME_KIND,
+};
+use once_cell::sync::Lazy;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+
+/// Canonical kind of a built-in number that was moved to another number
+pub const UNHANDLED_KIND: u16 = u16::MAX;
+
+/// Kind number of each MLS role
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct KindMap {
+    /// MLS KeyPackage (443)
+    pub keypackage: u16,
+    /// MLS Welcome, carried inside giftwraps (444)
+    pub welcome: u16,
+    /// MLS group message (445)
+    pub group_message: u16,
+    /// Noise direct message (446)
+    pub noise_dm: u16,
+    /// KeyPackage consumed notice (449)
+    pub keypackage_consumed: u16,
+    /// Roster/policy (450)
+    pub roster_policy: u16,
+    /// KeyPackage relays list (10051)
+    pub keypackage_relays: u16,
+    /// Giftwrap envelope (1059)
+    pub giftwrap: u16,
+}
+
+impl Default for KindMap {
+    fn default() -> Self {
+        Self {
+            keypackage: KEYPACKAGE_KIND,
+            welcome: WELCOME_KIND,
+            group_message: MLS_GROUP_MESSAGE_KIND,
+            noise_dm: NOISE_DM_KIND,
+            keypackage_consumed: KEYPACKAGE_CONSUMED_KIND,
+            roster_policy: ROSTER_POLICY_KIND,
+            keypackage_relays: KEYPACKAGE_RELAYS_LIST_KIND,
+            giftwrap: GIFTWRAP_KIND,
+        }
+    }
+}
+
+impl KindMap {
+    /// (configured, built-in) number of each role
+    fn pairs(&self) -> [(u16, u16); 8] {
+        [
+            (self.keypackage, KEYPACKAGE_KIND),
+            (self.welcome, WELCOME_KIND),
+            (self.group_message, MLS_GROUP_MESSAGE_KIND),
+            (self.noise_dm, NOISE_DM_KIND),
+            (self.keypackage_consumed, KEYPACKAGE_CONSUMED_KIND),
+            (self.roster_policy, ROSTER_POLICY_KIND),
+            (self.keypackage_relays, KEYPACKAGE_RELAYS_LIST_KIND),
+            (self.giftwrap, GIFTWRAP_KIND),
+        ]
+    }
+
+    /// Whether two roles share a number
+    pub fn has_duplicates(&self) -> bool {
+        let pairs = self.pairs();
+        pairs
+            .iter()
+            .enumerate()
+            .any(|(i, (number, _))| pairs[i + 1..].iter().any(|(other, _)| other == number))
+    }
+
+    /// Configured number of a built-in kind
+    pub fn number(&self, builtin: u16) -> u16 {
+        self.pairs()
+            .iter()
+            .find(|(_, b)| *b == builtin)
+            .map_or(builtin, |(number, _)| *number)
+    }
+
+    /// Built-in kind handling `kind`, `kind` itself for kinds without an MLS role
+    pub fn canonical(&self, kind: u16) -> u16 {
+        let pairs = self.pairs();
+        match pairs.iter().find(|(number, _)| *number == kind) {
+            Some((_, builtin)) => *builtin,
+            None if pairs.iter().any(|(_, builtin)| *builtin == kind) => UNHANDLED_KIND,
+            None => kind,
+        }
+    }
+}
+
+static KINDS: Lazy<RwLock<KindMap>> = Lazy::new(Default::default);
+
+/// Install the configured kind numbers
+pub fn configure(map: &KindMap) {
+    *KINDS.write() = map.clone();
+}
+
+/// Configured number of a built-in kind
+pub fn number(builtin: u16) -> u16 {
+    KINDS.read().number(builtin)
+}
+
+/// Built-in kind handling a received kind
+pub fn canonical(kind: u16) -> u16 {
+    KINDS.read().canonical(kind)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_kinds() {
+        let defaults = KindMap::default();
+        assert_eq!(defaults.canonical(445), 445);
+        assert_eq!(defaults.number(443), 443);
+        assert!(!defaults.has_duplicates());
+
+        let map = KindMap {
+            keypackage: 30443,
+            group_message: 1445,
+            ..Default::default()
+        };
+        assert_eq!(map.number(KEYPACKAGE_KIND), 30443);
+        assert_eq!(map.canonical(30443), KEYPACKAGE_KIND);
+        assert_eq!(map.canonical(1445), MLS_GROUP_MESSAGE_KIND);
+        assert_eq!(map.canonical(443), UNHANDLED_KIND);
+        assert_eq!(map.canonical(1059), GIFTWRAP_KIND);
+        assert_eq!(map.canonical(1), 1);
+
+        let clash = KindMap {
+            welcome: 445,
+            ..Default::default()
+        };
+        assert!(clash.has_duplicates());
+    }
+}
@@ -0,0 +1,120 @@
+//! Low KeyPackage notifications
+//!
+//! When an owner's unexpired KeyPackage count drops below
+//! `low_keypackage_threshold` the relay sends a NOTICE to the owner's
+//! authenticated (NIP-42) sessions on this instance, so clients upload more
+//! before invites start failing. Counts are checked after consumption, after
+//! the periodic cleanup for online owners, and when an owner authenticates.
+//! Each owner is notified at most once per [`NOTIFY_INTERVAL`].
+
+use super::{kinds, push, StorageBackend, KEYPACKAGE_KIND};
+use actix::Addr;
+use anyhow::Result;
+use metrics::counter;
+use nostr_relay::{
+    message::{OutgoingMessage, SessionMessage},
+    Server,
+};
+use once_cell::sync::{Lazy, OnceCell};
+use parking_lot::Mutex;
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    time::{Duration, Instant},
+};
+use tracing::{debug, info};
+
+/// Minimum time between notifications of an owner
+const NOTIFY_INTERVAL: Duration = Duration::from_secs(3600);
+
+static SERVER: OnceCell<Addr<Server>> = OnceCell::new();
+static NOTIFIED: Lazy<Mutex<HashMap<String, Instant>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Register the relay server delivering notices to sessions
+pub fn set_server(server: Addr<Server>) {
+    let _ = SERVER.set(server);
+}
+
+/// NOTICE text for an owner with `remaining` KeyPackages
+pub fn notice_text(remaining: u32, threshold: u32) -> String {
+    format!(
+        "keypackages: low: {} remaining (threshold {}), publish more kind {} events",
+        remaining,
+        threshold,
+        kinds::number(KEYPACKAGE_KIND)
+    )
+}
+
+/// Claim the notification slot of an owner, false if notified within [`NOTIFY_INTERVAL`]
+fn claim(owner: &str) -> bool {
+    let now = Instant::now();
+    let mut notified = NOTIFIED.lock();
+    notified.retain(|_, t| now.duration_since(*t) < NOTIFY_INTERVAL);
+    match notified.entry(owner.to_string()) {
+        Entry::Occupied(_) => false,
+        Entry::Vacant(slot) => {
+            slot.insert(now);
+            true
+        }
+    }
+}
+
+/// Notify an owner whose KeyPackage count is below `threshold` (0 disables), returns true if notified
+pub async fn check(store: &StorageBackend, owner: &str, threshold: u32) -> Result<bool> {
+    if threshold == 0 {
+        return Ok(false);
+    }
+    let sessions = push::sessions(owner);
+    let Some(server) = SERVER.get() else {
+        return Ok(false);
+    };
+    if sessions.is_empty() {
+        // checked again when the owner authenticates
+        return Ok(false);
+    }
+    let remaining = store.count_user_keypackages(owner).await?;
+    if remaining >= threshold || !claim(owner) {
+        return Ok(false);
+    }
+    let text = notice_text(remaining, threshold);
+    for id in sessions {
+        server.do_send(SessionMessage {
+            id,
+            msg: OutgoingMessage::notice(&text),
+        });
+    }
+    info!(
+        "Notified {} of low KeyPackages: {} remaining",
+        owner, remaining
+    );
+    counter!("mls_gateway_low_keypackage_notices").increment(1);
+    Ok(true)
+}
+
+/// Check every owner with an authenticated session, e.g. after cleanup
+pub async fn check_online(store: &StorageBackend, threshold: u32) {
+    if threshold == 0 {
+        return;
+    }
+    for owner in push::online_pubkeys() {
+        if let Err(e) = check(store, &owner, threshold).await {
+            debug!("Low KeyPackage check failed for {}: {}", owner, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn notifies_once_per_interval() {
+        assert!(claim("owner-low"));
+        assert!(!claim("owner-low"));
+        assert!(claim("owner-other"));
+        // a refused claim keeps the time of the notification
+        let notified = NOTIFIED.lock()["owner-low"];
+        assert!(!claim("owner-low"));
+        assert_eq!(NOTIFIED.lock()["owner-low"], notified);
+        assert!(notice_text(1, 3).starts_with("keypackages: low: 1 remaining"));
+    }
+}
@@ -1,11 +1,226 @@
-//! Mailbox services for key packages and welcome messages
+//! Welcome mailbox
+//!
+//! Every giftwrap (1059) with a recipient is tracked in a recipient-keyed
+//! mailbox until it is acked or `welcome_ttl` elapses. Recipients ack delivered
+//! giftwraps through `POST {api_prefix}/welcomes/ack`, authenticated with NIP-98;
+//! acked giftwraps are tombstoned, removed from the archive, and no longer
+//! returned to REQs. Expired giftwraps are filtered the same way and their
+//! mailbox entries are cleaned up periodically.
+//!
+//! The TTL runs from when the relay received a giftwrap: NIP-59 randomizes
+//! created_at up to two days into the past, so it only tells that a giftwrap is
+//! certainly expired once that window has also passed. Until then acked and
+//! expired entries are kept as tombstones.
 
-/// Mailbox service functions
-pub struct MailboxService;
+use super::firestore::MailboxWelcome;
+use super::message_archive::MessageArchive;
+use super::{http_auth, kinds, StorageBackend, GIFTWRAP_KIND};
+use actix_web::{web, HttpRequest, HttpResponse, Result as ActixResult};
+use anyhow::Result;
+use chrono::{Duration, Utc};
+use metrics::counter;
+use nostr_relay::db::Event;
+use once_cell::sync::Lazy;
+use parking_lot::RwLock;
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashMap;
+use tracing::{info, warn};
 
-impl MailboxService {
-    /// Create a new mailbox service
-    pub fn new() -> Self {
-        Self
+/// Maximum ids accepted by a single ack request
+const MAX_ACK_IDS: usize = 500;
+
+/// How far NIP-59 backdates the created_at of a giftwrap
+pub const BACKDATE_WINDOW: i64 = 2 * 86400;
+
+/// Acked or expired giftwrap ids with the unix time their tombstone expires
+static DELIVERED: Lazy<RwLock<HashMap<String, i64>>> = Lazy::new(|| RwLock::new(HashMap::new()));
+
+/// Track a giftwrap in its recipient's mailbox
+pub async fn record(store: &StorageBackend, event: &Event, welcome_ttl: u64) -> Result<()> {
+    let Some(recipient) = event
+        .tags()
+        .iter()
+        .find(|tag| tag.len() >= 2 && tag[0] == "p")
+        .map(|tag| tag[1].clone())
+    else {
+        return Ok(());
+    };
+    let received_at = Utc::now();
+    let entry = MailboxWelcome {
+        event_id: event.id_str(),
+        recipient,
+        author: event.pubkey_str(),
+        created_at: received_at,
+        expires_at: received_at + Duration::seconds(welcome_ttl as i64),
+        acked_at: None,
+    };
+    store.store_mailbox_welcome(&entry).await
+}
+
+/// Drop tombstoned giftwraps and giftwraps certainly older than `welcome_ttl` from query results
+pub fn filter_events(events: Vec<Event>, welcome_ttl: u64, now: u64) -> Vec<Event> {
+    if !events
+        .iter()
+        .any(|e| kinds::canonical(e.kind()) == GIFTWRAP_KIND)
+    {
+        return events;
+    }
+    let delivered = DELIVERED.read();
+    events
+        .into_iter()
+        .filter(|e| {
+            kinds::canonical(e.kind()) != GIFTWRAP_KIND
+                || (e
+                    .created_at()
+                    .saturating_add(welcome_ttl)
+                    .saturating_add(BACKDATE_WINDOW as u64)
+                    > now
+                    && !delivered.contains_key(&e.id_str()))
+        })
+        .collect()
+}
+
+/// Hide a giftwrap until `filter_events` drops it by its created_at
+fn tombstone(id: String, expires_at: i64) {
+    DELIVERED
+        .write()
+        .insert(id, expires_at.saturating_add(BACKDATE_WINDOW));
+}
+
+/// Reload tombstones from storage and forget the ones past the backdating window
+pub async fn load_delivered(store: &StorageBackend) -> Result<usize> {
+    let now = Utc::now();
+    let tombstones = store
+        .list_welcome_tombstones(now - Duration::seconds(BACKDATE_WINDOW))
+        .await?;
+    let mut delivered = DELIVERED.write();
+    delivered.retain(|_, until| *until > now.timestamp());
+    for (id, expires_at) in tombstones {
+        delivered.insert(id, expires_at.saturating_add(BACKDATE_WINDOW));
+    }
+    Ok(delivered.len())
+}
+
+/// Periodic mailbox TTL enforcement and tombstone refresh
+pub fn spawn_maintenance(store: StorageBackend) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(300));
+        loop {
+            interval.tick().await;
+            let before = Utc::now() - Duration::seconds(BACKDATE_WINDOW);
+            match store.cleanup_expired_welcomes(before).await {
+                Ok(count) if count > 0 => {
+                    info!("Removed {} expired welcome mailbox entries", count);
+                    counter!("mls_gateway_welcomes_expired").increment(count as u64);
+                }
+                Ok(_) => {}
+                Err(e) => warn!("Welcome mailbox cleanup failed: {}", e),
+            }
+            if let Err(e) = load_delivered(&store).await {
+                warn!("Failed to load acked welcomes: {}", e);
+            }
+        }
+    });
+}
+
+pub struct MailboxState {
+    pub store: StorageBackend,
+    pub archive: Option<MessageArchive>,
+}
+
+pub fn configure_mailbox_routes(cfg: &mut web::ServiceConfig, prefix: &str, state: MailboxState) {
+    cfg.service(
+        web::scope(&format!("{}/welcomes", prefix))
+            .app_data(web::Data::new(state))
+            .route("/ack", web::post().to(ack_welcomes)),
+    );
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AckRequest {
+    /// Giftwrap (1059) event ids
+    pub ids: Vec<String>,
+}
+
+/// Ack giftwraps delivered to the NIP-98 authenticated pubkey
+async fn ack_welcomes(
+    req: HttpRequest,
+    state: web::Data<MailboxState>,
+    body: web::Json<AckRequest>,
+) -> ActixResult<HttpResponse> {
+    let pubkey = match http_auth::verify(&req) {
+        Ok(pubkey) => pubkey,
+        Err(e) => return Ok(HttpResponse::Unauthorized().json(json!({ "ok": false, "error": e }))),
+    };
+    if body.ids.is_empty()
+        || body.ids.len() > MAX_ACK_IDS
+        || body
+            .ids
+            .iter()
+            .any(|id| id.len() != 64 || hex::decode(id).is_err())
+    {
+        return Ok(HttpResponse::BadRequest().json(json!({ "ok": false, "error": "invalid ids" })));
+    }
+    let acked = match state.store.ack_mailbox_welcomes(&pubkey, &body.ids).await {
+        Ok(acked) => acked,
+        Err(e) => {
+            warn!("Failed to ack welcomes for {}: {}", pubkey, e);
+            return Ok(HttpResponse::InternalServerError()
+                .json(json!({ "ok": false, "error": "storage error" })));
+        }
+    };
+    for entry in &acked {
+        tombstone(entry.event_id.clone(), entry.expires_at.timestamp());
+        if let Some(archive) = &state.archive {
+            if let Err(e) = archive
+                .delete_event(
+                    kinds::number(GIFTWRAP_KIND) as u32,
+                    &entry.event_id,
+                    &entry.author,
+                )
+                .await
+            {
+                warn!(
+                    "Failed to delete acked giftwrap {} from archive: {}",
+                    entry.event_id, e
+                );
+            }
+        }
+    }
+    counter!("mls_gateway_welcomes_acked").increment(acked.len() as u64);
+    let ids: Vec<&str> = acked.iter().map(|e| e.event_id.as_str()).collect();
+    Ok(HttpResponse::Ok().json(json!({ "ok": true, "acked": ids })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nostr_relay::db::secp256k1::{Keypair, SECP256K1};
+
+    #[test]
+    fn filters_acked_and_expired_giftwraps() -> Result<()> {
+        let key = Keypair::from_seckey_str(SECP256K1, &"05".repeat(32))?;
+        let fresh = Event::create(&key, 1_000, GIFTWRAP_KIND, vec![], "a".to_owned())?;
+        let acked = Event::create(&key, 1_001, GIFTWRAP_KIND, vec![], "b".to_owned())?;
+        let stale = Event::create(&key, 10, GIFTWRAP_KIND, vec![], "c".to_owned())?;
+        let other = Event::create(&key, 10, 445, vec![], "d".to_owned())?;
+        tombstone(acked.id_str(), i64::MAX);
+
+        let now = 1_200 + BACKDATE_WINDOW as u64;
+        let kept = filter_events(
+            vec![fresh.clone(), acked, stale.clone(), other.clone()],
+            500,
+            now,
+        );
+        let ids: Vec<String> = kept.iter().map(|e| e.id_str()).collect();
+        assert_eq!(ids, vec![fresh.id_str(), other.id_str()]);
+
+        // a backdated created_at alone does not expire a giftwrap
+        let kept = filter_events(vec![stale.clone()], 500, 1_200);
+        assert_eq!(kept.len(), 1);
+        tombstone(stale.id_str(), 1_000);
+        assert!(filter_events(vec![stale], 500, 1_200).is_empty());
+        Ok(())
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,263 @@
+//! NIP-86 relay management API
+//!
+//! JSON-RPC requests are POSTed to the relay url with
+//! `Content-Type: application/nostr+json+rpc` and a NIP-98 authorization whose
+//! `payload` tag hashes the body. Only `management_pubkeys` may call it, and the
+//! API is not mounted while that list is empty. Bans and allows are stored as
+//! moderation rules, so they take effect like rules added via the admin API.
+//! Relay metadata changes apply until the config file is reloaded.
+
+use super::firestore::ModerationRule;
+use super::moderation::{self, ALLOW, DENY, KIND, PUBKEY};
+use super::{http_auth, push, StorageBackend};
+use actix_web::{guard, web, HttpRequest, HttpResponse, Result as ActixResult};
+use metrics::counter;
+use nostr_relay::App;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tracing::{info, warn};
+
+/// Content type of NIP-86 requests and responses
+pub const CONTENT_TYPE: &str = "application/nostr+json+rpc";
+
+const METHODS: &[&str] = &[
+    "supportedmethods",
+    "banpubkey",
+    "unbanpubkey",
+    "listbannedpubkeys",
+    "allowpubkey",
+    "unallowpubkey",
+    "listallowedpubkeys",
+    "allowkind",
+    "disallowkind",
+    "listallowedkinds",
+    "changerelayname",
+    "changerelaydescription",
+    "changerelayicon",
+    "stats",
+];
+
+pub struct ManagementState {
+    pub store: StorageBackend,
+    /// Hex pubkeys allowed to manage the relay
+    pub pubkeys: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Request {
+    pub method: String,
+    #[serde(default)]
+    pub params: Vec<Value>,
+}
+
+/// Mount the management endpoint on the relay url, ahead of the websocket route
+pub fn configure_management_routes(cfg: &mut web::ServiceConfig, state: ManagementState) {
+    cfg.service(
+        web::resource("/")
+            .guard(guard::Post())
+            .guard(guard::fn_guard(|ctx| {
+                ctx.head()
+                    .headers()
+                    .get(actix_web::http::header::CONTENT_TYPE)
+                    .and_then(|v| v.to_str().ok())
+                    .map_or(false, |v| v.starts_with(CONTENT_TYPE))
+            }))
+            .app_data(web::Data::new(state))
+            .route(web::post().to(handle)),
+    );
+}
+
+fn response(result: Result<Value, String>) -> HttpResponse {
+    let body = match result {
+        Ok(result) => json!({ "result": result }),
+        Err(error) => json!({ "result": null, "error": error }),
+    };
+    HttpResponse::Ok().content_type(CONTENT_TYPE).json(body)
+}
+
+async fn handle(
+    req: HttpRequest,
+    body: web::Bytes,
+    state: web::Data<ManagementState>,
+    app: web::Data<App>,
+) -> ActixResult<HttpResponse> {
+    let pubkey = match http_auth::verify_with_payload(&req, &body) {
+        Ok(pubkey) => pubkey,
+        Err(e) => {
+            return Ok(HttpResponse::Unauthorized().json(json!({ "result": null, "error": e })))
+        }
+    };
+    if !state.pubkeys.contains(&pubkey) {
+        return Ok(HttpResponse::Unauthorized()
+            .json(json!({ "result": null, "error": "not a relay admin" })));
+    }
+    let request: Request = match serde_json::from_slice(&body) {
+        Ok(request) => request,
+        Err(e) => return Ok(response(Err(format!("invalid request: {}", e)))),
+    };
+    info!("NIP-86 {} by {}", request.method, pubkey);
+    let result = call(&state.store, &app, &request).await;
+    let outcome = if result.is_ok() { "ok" } else { "error" };
+    counter!("mls_gateway_management_requests", "method" => request.method.clone(), "result" => outcome).increment(1);
+    if let Err(e) = &result {
+        warn!("NIP-86 {} failed: {}", request.method, e);
+    }
+    Ok(response(result))
+}
+
+fn param(params: &[Value], index: usize) -> Result<String, String> {
+    match params.get(index) {
+        Some(Value::String(s)) => Ok(s.clone()),
+        Some(Value::Number(n)) => Ok(n.to_string()),
+        _ => Err(format!("missing parameter {}", index)),
+    }
+}
+
+fn reason(params: &[Value], index: usize) -> Option<String> {
+    params
+        .get(index)
+        .and_then(|v| v.as_str())
+        .map(|s| s.to_string())
+}
+
+async fn add_rule(
+    store: &StorageBackend,
+    list: &str,
+    target: &str,
+    value: &str,
+    reason: Option<String>,
+) -> Result<Value, String> {
+    let (list, target, value) = moderation::normalize(list, target, value)?;
+    let rule = ModerationRule {
+        list,
+        target,
+        value,
+        reason,
+        created_at: chrono::Utc::now(),
+    };
+    moderation::add(store, &rule)
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(json!(true))
+}
+
+async fn remove_rule(
+    store: &StorageBackend,
+    list: &str,
+    target: &str,
+    value: &str,
+) -> Result<Value, String> {
+    let (list, target, value) = moderation::normalize(list, target, value)?;
+    moderation::remove(store, &list, &target, &value)
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(json!(true))
+}
+
+async fn list_rules(
+    store: &StorageBackend,
+    list: &str,
+    target: &str,
+) -> Result<Vec<ModerationRule>, String> {
+    let rules = store
+        .list_moderation_rules()
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(rules
+        .into_iter()
+        .filter(|r| r.list == list && r.target == target)
+        .collect())
+}
+
+async fn call(store: &StorageBackend, app: &App, request: &Request) -> Result<Value, String> {
+    let params = &request.params;
+    match request.method.as_str() {
+        "supportedmethods" => Ok(json!(METHODS)),
+        "banpubkey" => add_rule(store, DENY, PUBKEY, &param(params, 0)?, reason(params, 1)).await,
+        "unbanpubkey" => remove_rule(store, DENY, PUBKEY, &param(params, 0)?).await,
+        "allowpubkey" => {
+            add_rule(store, ALLOW, PUBKEY, &param(params, 0)?, reason(params, 1)).await
+        }
+        "unallowpubkey" => remove_rule(store, ALLOW, PUBKEY, &param(params, 0)?).await,
+        // a deny rule wins over the allow list, so each method drops the opposite rule
+        "allowkind" => {
+            let kind = param(params, 0)?;
+            remove_rule(store, DENY, KIND, &kind).await?;
+            add_rule(store, ALLOW, KIND, &kind, None).await
+        }
+        "disallowkind" => {
+            let kind = param(params, 0)?;
+            remove_rule(store, ALLOW, KIND, &kind).await?;
+            add_rule(store, DENY, KIND, &kind, reason(params, 1)).await
+        }
+        "listbannedpubkeys" | "listallowedpubkeys" => {
+            let list = if request.method == "listbannedpubkeys" {
+                DENY
+            } else {
+                ALLOW
+            };
+            let rules = list_rules(store, list, PUBKEY).await?;
+            Ok(json!(rules
+                .into_iter()
+                .map(|r| json!({ "pubkey": r.value, "reason": r.reason }))
+                .collect::<Vec<_>>()))
+        }
+        "listallowedkinds" => {
+            let rules = list_rules(store, ALLOW, KIND).await?;
+            Ok(json!(rules
+                .iter()
+                .filter_map(|r| r.value.parse::<u16>().ok())
+                .collect::<Vec<_>>()))
+        }
+        "changerelayname" | "changerelaydescription" | "changerelayicon" => {
+            let value = param(params, 0)?;
+            let mut w = app.setting.write();
+            match request.method.as_str() {
+                "changerelayname" => w.information.name = value,
+                "changerelaydescription" => w.information.description = value,
+                _ => w.information.icon = Some(value),
+            }
+            Ok(json!(true))
+        }
+        "stats" => {
+            let rules = store
+                .list_moderation_rules()
+                .await
+                .map_err(|e| e.to_string())?;
+            let count = |list: &str, target: &str| {
+                rules
+                    .iter()
+                    .filter(|r| r.list == list && r.target == target)
+                    .count()
+            };
+            Ok(json!({
+                "authenticated_pubkeys": push::online_pubkeys().len(),
+                "banned_pubkeys": count(DENY, PUBKEY),
+                "allowed_pubkeys": count(ALLOW, PUBKEY),
+                "banned_kinds": count(DENY, KIND),
+                "allowed_kinds": count(ALLOW, KIND),
+            }))
+        }
+        method => Err(format!("unsupported method {}", method)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn params() {
+        let request: Request =
+            serde_json::from_str(r#"{"method": "banpubkey", "params": ["abc", "spam"]}"#).unwrap();
+        assert_eq!(param(&request.params, 0).unwrap(), "abc");
+        assert_eq!(reason(&request.params, 1).as_deref(), Some("spam"));
+        assert!(param(&request.params, 2).is_err());
+
+        let request: Request =
+            serde_json::from_str(r#"{"method": "allowkind", "params": [445]}"#).unwrap();
+        assert_eq!(param(&request.params, 0).unwrap(), "445");
+        let request: Request = serde_json::from_str(r#"{"method": "supportedmethods"}"#).unwrap();
+        assert!(request.params.is_empty());
+    }
+}
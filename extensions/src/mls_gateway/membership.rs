@@ -0,0 +1,114 @@
+//! Group membership check
+//!
+//! `GET {api_prefix}/groups/{id}/members/{pubkey}` tells external services
+//! whether `pubkey` belongs to a group and with which role (`owner`, `admin`
+//! or `member`), from the roster materialized from roster/policy events (450).
+//! Only the group's members, admins and owner (NIP-98) or the operator (admin
+//! bearer token) may ask; anyone else gets the same 403 whether or not the
+//! group exists, so the endpoint reveals neither groups nor rosters.
+
+use super::admin::{self, AdminState};
+use super::roster_snapshot::{self, RosterSnapshot};
+use super::{http_auth, StorageBackend};
+use actix_web::{web, HttpRequest, HttpResponse, Result as ActixResult};
+use serde_json::json;
+use tracing::warn;
+
+/// Role of `pubkey` in a roster, `None` if it is not part of it
+pub fn role(roster: &RosterSnapshot, pubkey: &str) -> Option<&'static str> {
+    if roster.owner.as_deref() == Some(pubkey) {
+        Some("owner")
+    } else if roster.admins.iter().any(|p| p == pubkey) {
+        Some("admin")
+    } else if roster.members.iter().any(|p| p == pubkey) {
+        Some("member")
+    } else {
+        None
+    }
+}
+
+pub struct MembershipState {
+    pub store: StorageBackend,
+    /// Admin scope state, its bearer token may check any group
+    pub admin: Option<AdminState>,
+}
+
+pub fn configure_membership_routes(
+    cfg: &mut web::ServiceConfig,
+    prefix: &str,
+    state: MembershipState,
+) {
+    cfg.service(
+        web::resource(format!("{}/groups/{{id}}/members/{{pubkey}}", prefix))
+            .app_data(web::Data::new(state))
+            .route(web::get().to(get_membership)),
+    );
+}
+
+/// Membership and role of a pubkey in a group
+async fn get_membership(
+    req: HttpRequest,
+    state: web::Data<MembershipState>,
+    path: web::Path<(String, String)>,
+) -> ActixResult<HttpResponse> {
+    let (group_id, pubkey) = path.into_inner();
+    let operator = state
+        .admin
+        .as_ref()
+        .map_or(false, |admin| admin::authorized(&req, admin));
+    let caller = if operator {
+        None
+    } else {
+        match http_auth::verify(&req) {
+            Ok(caller) => Some(caller),
+            Err(e) => {
+                return Ok(HttpResponse::Unauthorized().json(json!({ "ok": false, "error": e })))
+            }
+        }
+    };
+    let roster = match roster_snapshot::load(&state.store, &group_id).await {
+        Ok(roster) => roster,
+        Err(e) => {
+            warn!("Failed to load roster of group {}: {}", group_id, e);
+            return Ok(HttpResponse::InternalServerError()
+                .json(json!({ "ok": false, "error": "storage error" })));
+        }
+    };
+    if let Some(caller) = caller {
+        if role(&roster, &caller).is_none() {
+            return Ok(HttpResponse::Forbidden().json(json!({
+                "ok": false,
+                "error": "only group members can check membership"
+            })));
+        }
+    }
+    let role = role(&roster, &pubkey);
+    Ok(HttpResponse::Ok().json(json!({
+        "ok": true,
+        "group_id": group_id,
+        "pubkey": pubkey,
+        "member": role.is_some(),
+        "role": role,
+        "sequence": roster.sequence,
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roles() {
+        let roster = RosterSnapshot {
+            group_id: "g".to_owned(),
+            sequence: 3,
+            owner: Some("owner".to_owned()),
+            members: vec!["alice".to_owned(), "bob".to_owned(), "owner".to_owned()],
+            admins: vec!["alice".to_owned(), "owner".to_owned()],
+        };
+        assert_eq!(role(&roster, "owner"), Some("owner"));
+        assert_eq!(role(&roster, "alice"), Some("admin"));
+        assert_eq!(role(&roster, "bob"), Some("member"));
+        assert_eq!(role(&roster, "mallory"), None);
+    }
+}
@@ -1,5 +1,5 @@
 //! Message Archive System for Offline Delivery
-//! 
+//!
 //! This module provides message archival functionality to ensure users can retrieve
 //! messages they missed while offline. When the Cloud Run service restarts frequently,
 //! LMDB storage is ephemeral, so we need persistent storage for offline message delivery.
@@ -10,16 +10,17 @@
 //! - Automatic cleanup of expired messages
 //! - Query by recipient pubkey for efficient delivery
 
+use super::DbTimer;
 use anyhow::Result;
 use chrono::Utc;
+use firestore::*;
 use nostr_relay::db::Event;
 use reqwest::Client as HttpClient;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
-use firestore::*;
-use std::env;
 use std::collections::HashSet;
-use tracing::{debug, info, warn, instrument};
+use std::env;
+use tracing::{debug, info, instrument, warn};
 
 /// Archived event data structure for Firestore storage
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -55,6 +56,54 @@ pub struct ArchivedEvent {
     pub expires_at: i64,
 }
 
+/// Giftwrap digest claimed by the archive path (replay suppression across instances)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GiftwrapClaim {
+    pub recipient: String,
+    pub digest: String,
+    pub expires_at: i64,
+}
+
+/// Giftwraps charged to a recipient on a UTC day, shared by all instances
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GiftwrapQuota {
+    pub recipient: String,
+    pub day: String,
+    pub count: u32,
+    pub expires_at: i64,
+}
+
+/// Archived events of a kind held for a recipient, maintained as they are archived.
+/// Expiry does not decrement it, so it may overstate until the next cap scan resets it.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ArchiveUsage {
+    pub kind: u32,
+    pub recipient: String,
+    pub count: u64,
+    pub bytes: u64,
+}
+
+/// Ids of the oldest `(id, created_at, bytes)` entries past the count or byte cap (0 disables a cap)
+fn select_evictions(
+    mut entries: Vec<(String, i64, u64)>,
+    max_count: u32,
+    max_bytes: u64,
+) -> Vec<String> {
+    // newest first, ties broken by id for a stable order
+    entries.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| b.0.cmp(&a.0)));
+    let mut bytes = 0u64;
+    entries
+        .into_iter()
+        .enumerate()
+        .filter_map(|(i, (id, _, size))| {
+            bytes += size;
+            let over_count = max_count > 0 && i >= max_count as usize;
+            let over_bytes = max_bytes > 0 && bytes > max_bytes;
+            (over_count || over_bytes).then_some(id)
+        })
+        .collect()
+}
+
 /// Message Archive client for Firestore operations
 #[derive(Clone)]
 pub struct MessageArchive {
@@ -72,7 +121,10 @@ impl MessageArchive {
             .unwrap_or_else(|_| "loxation-f8e1c".to_string());
 
         let http_client = HttpClient::new();
-        let base_url = format!("https://firestore.googleapis.com/v1/projects/{}/databases/(default)/documents", project_id);
+        let base_url = format!(
+            "https://firestore.googleapis.com/v1/projects/{}/databases/(default)/documents",
+            project_id
+        );
         let db = FirestoreDb::new(&project_id).await?;
 
         info!("Message archive initialized for project: {}", project_id);
@@ -84,48 +136,47 @@ impl MessageArchive {
         })
     }
 
-    /// Get Google Cloud access token using metadata service (for Cloud Run)
-    async fn get_access_token(&self) -> Result<String> {
-        let metadata_url = "http://metadata.google.internal/computeMetadata/v1/instance/service-accounts/default/token";
-        
-        let response = self.http_client
-            .get(metadata_url)
-            .header("Metadata-Flavor", "Google")
-            .send()
+    /// Check the archive collection can be queried
+    pub async fn health_check(&self) -> Result<()> {
+        let _timer = DbTimer::new("health_check", "archive");
+        let _result: Vec<ArchivedEvent> = self
+            .db
+            .fluent()
+            .select()
+            .from("archived_events")
+            .limit(1)
+            .obj()
+            .query()
             .await?;
-
-        if !response.status().is_success() {
-            return Err(anyhow::anyhow!("Failed to get access token from metadata service"));
-        }
-
-        let token_response: Value = response.json().await?;
-        let access_token = token_response
-            .get("access_token")
-            .and_then(|v| v.as_str())
-            .ok_or_else(|| anyhow::anyhow!("Invalid token response"))?;
-
-        Ok(access_token.to_string())
+        Ok(())
     }
 
-    /// Archive a Nostr event for offline delivery
+    /// Archive a Nostr event for offline delivery, returns false when the event has nothing to key it by
     #[instrument(skip(self, event))]
-    pub async fn archive_event(&self, event: &Event, ttl_days: Option<u32>) -> Result<()> {
+    pub async fn archive_event(&self, event: &Event, ttl_days: Option<u32>) -> Result<bool> {
+        let _timer = DbTimer::new("archive_event", "archive");
         let now = Utc::now();
         let ttl_days = ttl_days.unwrap_or(7); // Default 7 days
         let expires_at = now + chrono::Duration::days(ttl_days as i64);
-        
+
         // Extract recipient pubkeys from 'p' tags
-        let recipients: Vec<String> = event.tags().iter()
+        let recipients: Vec<String> = event
+            .tags()
+            .iter()
             .filter(|tag| tag.len() >= 2 && tag[0] == "p")
             .map(|tag| tag[1].clone())
             .collect();
 
         // Extract group id and epoch from tags (for kind 445 MLS group messages or giftwrap scoped to a group)
-        let group_id: Option<String> = event.tags().iter()
+        let group_id: Option<String> = event
+            .tags()
+            .iter()
             .find(|tag| tag.len() >= 2 && tag[0] == "h")
             .map(|tag| tag[1].clone());
 
-        let group_epoch: Option<i64> = event.tags().iter()
+        let group_epoch: Option<i64> = event
+            .tags()
+            .iter()
             .find(|tag| tag.len() >= 2 && tag[0] == "k")
             .and_then(|tag| tag[1].parse::<i64>().ok());
 
@@ -136,16 +187,20 @@ impl MessageArchive {
                 "Skipping archive for event {} - no recipients and no group_id",
                 hex::encode(event.id())
             );
-            return Ok(());
+            return Ok(false);
         }
 
         let archived_event = ArchivedEvent {
             id: hex::encode(event.id()),
             kind: event.kind() as u32,
             content: event.content().to_string(),
-            tags: event.tags().iter().map(|tag| TagMap {
-                values: tag.iter().map(|s| s.to_string()).collect()
-            }).collect(),
+            tags: event
+                .tags()
+                .iter()
+                .map(|tag| TagMap {
+                    values: tag.iter().map(|s| s.to_string()).collect(),
+                })
+                .collect(),
             created_at: event.created_at() as i64,
             pubkey: hex::encode(event.pubkey()),
             sig: hex::encode(event.sig()),
@@ -168,17 +223,135 @@ impl MessageArchive {
             .execute::<()>()
             .await?;
 
-        debug!("Archived event {} with {} recipients, expires at {}",
-               hex::encode(event.id()), recipients.len(), expires_at);
-        Ok(())
+        debug!(
+            "Archived event {} with {} recipients, expires at {}",
+            hex::encode(event.id()),
+            recipients.len(),
+            expires_at
+        );
+        Ok(true)
+    }
+
+    /// Claim a giftwrap (recipient, ciphertext digest) for `ttl_secs`, false when already claimed
+    pub async fn claim_giftwrap(
+        &self,
+        recipient: &str,
+        digest: &str,
+        ttl_secs: u64,
+    ) -> Result<bool> {
+        let _timer = DbTimer::new("claim_giftwrap", "archive");
+        let doc_id = format!("{}-{}", recipient, digest);
+        let now = Utc::now().timestamp();
+        // the transaction read locks the claim, concurrent claims of a digest serialize on it
+        let mut transaction = self.db.begin_transaction().await?;
+        let tx_db =
+            self.db
+                .clone_with_consistency_selector(FirestoreConsistencySelector::Transaction(
+                    transaction.transaction_id().clone(),
+                ));
+        let existing: Option<GiftwrapClaim> = tx_db
+            .fluent()
+            .select()
+            .by_id_in("giftwrap_digests")
+            .obj()
+            .one(&doc_id)
+            .await?;
+        if existing.is_some_and(|claim| claim.expires_at > now) {
+            transaction.rollback().await?;
+            return Ok(false);
+        }
+        let claim = GiftwrapClaim {
+            recipient: recipient.to_string(),
+            digest: digest.to_string(),
+            expires_at: now + ttl_secs as i64,
+        };
+        self.db
+            .fluent()
+            .update()
+            .in_col("giftwrap_digests")
+            .document_id(&doc_id)
+            .object(&claim)
+            .add_to_transaction(&mut transaction)?;
+        transaction.commit().await?;
+        Ok(true)
+    }
+
+    /// Charge a stored giftwrap to the recipient's daily quota, false once `quota` giftwraps were charged today
+    pub async fn charge_giftwrap_quota(&self, recipient: &str, quota: u32) -> Result<bool> {
+        let _timer = DbTimer::new("charge_giftwrap_quota", "archive");
+        let now = Utc::now();
+        let day = now.format("%Y%m%d").to_string();
+        let doc_id = format!("{}-{}", recipient, day);
+        let mut transaction = self.db.begin_transaction().await?;
+        let tx_db =
+            self.db
+                .clone_with_consistency_selector(FirestoreConsistencySelector::Transaction(
+                    transaction.transaction_id().clone(),
+                ));
+        let existing: Option<GiftwrapQuota> = tx_db
+            .fluent()
+            .select()
+            .by_id_in("giftwrap_quotas")
+            .obj()
+            .one(&doc_id)
+            .await?;
+        let count = existing.map_or(0, |q| q.count);
+        if count >= quota {
+            transaction.rollback().await?;
+            return Ok(false);
+        }
+        let charged = GiftwrapQuota {
+            recipient: recipient.to_string(),
+            day,
+            count: count + 1,
+            expires_at: now.timestamp() + 2 * 86400,
+        };
+        self.db
+            .fluent()
+            .update()
+            .in_col("giftwrap_quotas")
+            .document_id(&doc_id)
+            .object(&charged)
+            .add_to_transaction(&mut transaction)?;
+        transaction.commit().await?;
+        Ok(true)
+    }
+
+    /// Returns true if the event already has an archive document
+    pub async fn is_archived(&self, event: &Event) -> Result<bool> {
+        let _timer = DbTimer::new("is_archived", "archive");
+        let doc_id = format!("{}-{}", event.kind(), hex::encode(event.id()));
+        let doc: Option<ArchivedEvent> = self
+            .db
+            .fluent()
+            .select()
+            .by_id_in("archived_events")
+            .obj()
+            .one(&doc_id)
+            .await?;
+        Ok(doc.is_some())
+    }
+
+    /// Returns true if the event would be stored by [`MessageArchive::archive_event`]
+    pub fn is_archivable(event: &Event) -> bool {
+        event
+            .tags()
+            .iter()
+            .any(|tag| tag.len() >= 2 && (tag[0] == "p" || tag[0] == "h"))
     }
 
     /// Get missed messages for a user since a timestamp
     #[instrument(skip(self))]
-    pub async fn get_missed_messages(&self, pubkey: &str, since: i64, limit: u32) -> Result<Vec<Event>> {
-        let access_token = self.get_access_token().await?;
+    pub async fn get_missed_messages(
+        &self,
+        pubkey: &str,
+        since: i64,
+        limit: u32,
+    ) -> Result<Vec<Event>> {
+        let _timer = DbTimer::new("get_missed_messages", "archive");
+        let access_token = crate::gcp::access_token().await?;
         let now = Utc::now().timestamp();
-        
+
         // Build Firestore structured query
         let query = json!({
             "structuredQuery": {
@@ -217,7 +390,8 @@ impl MessageArchive {
         });
 
         let url = format!("{}:runQuery", self.base_url);
-        let response = self.http_client
+        let response = self
+            .http_client
             .post(&url)
             .header("Authorization", format!("Bearer {}", access_token))
             .header("Content-Type", "application/json")
@@ -228,7 +402,11 @@ impl MessageArchive {
         if !response.status().is_success() {
             let status = response.status();
             let error_text = response.text().await.unwrap_or_default();
-            return Err(anyhow::anyhow!("Failed to query missed messages ({}): {}", status, error_text));
+            return Err(anyhow::anyhow!(
+                "Failed to query missed messages ({}): {}",
+                status,
+                error_text
+            ));
         }
 
         let response_json: Value = response.json().await?;
@@ -242,7 +420,10 @@ impl MessageArchive {
                             Ok(archived_event) => {
                                 match self.archived_event_to_nostr_event(&archived_event) {
                                     Ok(event) => events.push(event),
-                                    Err(e) => warn!("Failed to convert archived event to Nostr event: {}", e),
+                                    Err(e) => warn!(
+                                        "Failed to convert archived event to Nostr event: {}",
+                                        e
+                                    ),
                                 }
                             }
                             Err(e) => warn!("Failed to parse archived event: {}", e),
@@ -252,14 +433,25 @@ impl MessageArchive {
             }
         }
 
-        info!("Retrieved {} missed messages for pubkey {} since {}", events.len(), pubkey, since);
+        info!(
+            "Retrieved {} missed messages for pubkey {} since {}",
+            events.len(),
+            pubkey,
+            since
+        );
         Ok(events)
     }
 
     /// Get MLS group messages by group_id since a timestamp
     #[instrument(skip(self))]
-    pub async fn get_group_messages(&self, group_id: &str, since: i64, limit: u32) -> Result<Vec<Event>> {
-        let access_token = self.get_access_token().await?;
+    pub async fn get_group_messages(
+        &self,
+        group_id: &str,
+        since: i64,
+        limit: u32,
+    ) -> Result<Vec<Event>> {
+        let _timer = DbTimer::new("get_group_messages", "archive");
+        let access_token = crate::gcp::access_token().await?;
         let now = Utc::now().timestamp();
 
         // Build Firestore structured query for group-based retrieval
@@ -300,7 +492,8 @@ impl MessageArchive {
         });
 
         let url = format!("{}:runQuery", self.base_url);
-        let response = self.http_client
+        let response = self
+            .http_client
             .post(&url)
             .header("Authorization", format!("Bearer {}", access_token))
             .header("Content-Type", "application/json")
@@ -311,7 +504,11 @@ impl MessageArchive {
         if !response.status().is_success() {
             let status = response.status();
             let error_text = response.text().await.unwrap_or_default();
-            return Err(anyhow::anyhow!("Failed to query group messages ({}): {}", status, error_text));
+            return Err(anyhow::anyhow!(
+                "Failed to query group messages ({}): {}",
+                status,
+                error_text
+            ));
         }
 
         let response_json: Value = response.json().await?;
@@ -325,7 +522,10 @@ impl MessageArchive {
                             Ok(archived_event) => {
                                 match self.archived_event_to_nostr_event(&archived_event) {
                                     Ok(event) => events.push(event),
-                                    Err(e) => warn!("Failed to convert archived event to Nostr event: {}", e),
+                                    Err(e) => warn!(
+                                        "Failed to convert archived event to Nostr event: {}",
+                                        e
+                                    ),
                                 }
                             }
                             Err(e) => warn!("Failed to parse archived group event: {}", e),
@@ -335,7 +535,12 @@ impl MessageArchive {
             }
         }
 
-        info!("Retrieved {} group messages for group {} since {}", events.len(), group_id, since);
+        info!(
+            "Retrieved {} group messages for group {} since {}",
+            events.len(),
+            group_id,
+            since
+        );
         Ok(events)
     }
 
@@ -347,98 +552,163 @@ impl MessageArchive {
         since: i64,
         total_limit: u32,
     ) -> Result<Vec<Event>> {
-        let access_token = self.get_access_token().await?;
-        let now = Utc::now().timestamp();
-
+        let _timer = DbTimer::new("list_recent_events_by_kinds", "archive");
         let mut collected: Vec<Event> = Vec::new();
         let mut seen_ids: HashSet<String> = HashSet::new();
 
         for kind in kinds {
-            if collected.len() as u32 >= total_limit {
-                break;
+            let mut cursor = None;
+            loop {
+                let remaining = total_limit.saturating_sub(collected.len() as u32);
+                if remaining == 0 {
+                    break;
+                }
+                let page_size = remaining.min(500);
+                let page = self
+                    .list_events_page(*kind, since, None, cursor.take(), page_size)
+                    .await?;
+                let Some(last) = page.last() else {
+                    break;
+                };
+                cursor = Some((last.created_at() as i64, last.id_str()));
+                let full_page = page.len() as u32 >= page_size;
+                for event in page {
+                    if seen_ids.insert(event.id_str()) {
+                        collected.push(event);
+                    }
+                }
+                if !full_page {
+                    break;
+                }
             }
-            // Limit per kind to avoid huge reads; Firestore hard-caps at 500 per page here.
-            let per_kind_limit = (total_limit.saturating_sub(collected.len() as u32)).min(500);
-
-            let query = json!({
-                "structuredQuery": {
-                    "from": [{"collectionId": "archived_events"}],
-                    "where": {
-                        "compositeFilter": {
-                            "op": "AND",
-                            "filters": [
-                                {
-                                    "fieldFilter": {
-                                        "field": {"fieldPath": "kind"},
-                                        "op": "EQUAL",
-                                        "value": {"integerValue": kind.to_string()}
-                                    }
-                                },
-                                {
-                                    "fieldFilter": {
-                                        "field": {"fieldPath": "created_at"},
-                                        "op": "GREATER_THAN",
-                                        "value": {"integerValue": since.to_string()}
-                                    }
-                                },
-                                {
-                                    "fieldFilter": {
-                                        "field": {"fieldPath": "expires_at"},
-                                        "op": "GREATER_THAN",
-                                        "value": {"integerValue": now.to_string()}
-                                    }
-                                }
-                            ]
-                        }
-                    },
-                    "orderBy": [{"field": {"fieldPath": "created_at"}, "direction": "ASCENDING"}],
-                    "limit": per_kind_limit
+        }
+
+        collected.sort_by_key(|e| e.created_at() as i64);
+        Ok(collected)
+    }
+
+    /// List one page of non-expired archived events of a kind, ordered by (created_at, id).
+    ///
+    /// Pass the `(created_at, id)` of the last event of the previous page as `cursor` to continue.
+    pub async fn list_events_page(
+        &self,
+        kind: u32,
+        since: i64,
+        until: Option<i64>,
+        cursor: Option<(i64, String)>,
+        page_size: u32,
+    ) -> Result<Vec<Event>> {
+        let _timer = DbTimer::new("list_events_page", "archive");
+        let access_token = crate::gcp::access_token().await?;
+        let now = Utc::now().timestamp();
+
+        let mut filters = vec![
+            json!({
+                "fieldFilter": {
+                    "field": {"fieldPath": "kind"},
+                    "op": "EQUAL",
+                    "value": {"integerValue": kind.to_string()}
                 }
+            }),
+            json!({
+                "fieldFilter": {
+                    "field": {"fieldPath": "created_at"},
+                    "op": "GREATER_THAN_OR_EQUAL",
+                    "value": {"integerValue": since.to_string()}
+                }
+            }),
+            json!({
+                "fieldFilter": {
+                    "field": {"fieldPath": "expires_at"},
+                    "op": "GREATER_THAN",
+                    "value": {"integerValue": now.to_string()}
+                }
+            }),
+        ];
+        if let Some(until) = until {
+            filters.push(json!({
+                "fieldFilter": {
+                    "field": {"fieldPath": "created_at"},
+                    "op": "LESS_THAN_OR_EQUAL",
+                    "value": {"integerValue": until.to_string()}
+                }
+            }));
+        }
+
+        let mut structured_query = json!({
+            "from": [{"collectionId": "archived_events"}],
+            "where": {
+                "compositeFilter": {
+                    "op": "AND",
+                    "filters": filters
+                }
+            },
+            "orderBy": [
+                {"field": {"fieldPath": "created_at"}, "direction": "ASCENDING"},
+                {"field": {"fieldPath": "id"}, "direction": "ASCENDING"}
+            ],
+            "limit": page_size.min(500)
+        });
+        if let Some((created_at, id)) = cursor {
+            structured_query["startAt"] = json!({
+                "values": [
+                    {"integerValue": created_at.to_string()},
+                    {"stringValue": id}
+                ],
+                "before": false
             });
+        }
 
-            let url = format!("{}:runQuery", self.base_url);
-            let response = self.http_client
-                .post(&url)
-                .header("Authorization", format!("Bearer {}", access_token))
-                .header("Content-Type", "application/json")
-                .json(&query)
-                .send()
-                .await?;
+        let url = format!("{}:runQuery", self.base_url);
+        let response = self
+            .http_client
+            .post(&url)
+            .header("Authorization", format!("Bearer {}", access_token))
+            .header("Content-Type", "application/json")
+            .json(&json!({ "structuredQuery": structured_query }))
+            .send()
+            .await?;
 
-            if !response.status().is_success() {
-                let status = response.status();
-                let error_text = response.text().await.unwrap_or_default();
-                return Err(anyhow::anyhow!("Failed to query recent events ({}): {}", status, error_text));
-            }
+        if !response.status().is_success() {
+            let status = response.status();
+            let error_text = response.text().await.unwrap_or_default();
+            return Err(anyhow::anyhow!(
+                "Failed to query archived events ({}): {}",
+                status,
+                error_text
+            ));
+        }
 
-            let response_json: Value = response.json().await?;
-            if let Some(documents) = response_json.as_array() {
-                for doc in documents {
-                    if let Some(document) = doc.get("document") {
-                        if let Some(fields) = document.get("fields") {
-                            if let Ok(archived_event) = self.from_firestore_fields(fields) {
-                                if seen_ids.insert(archived_event.id.clone()) {
-                                    if let Ok(event) = self.archived_event_to_nostr_event(&archived_event) {
-                                        collected.push(event);
-                                    }
+        let response_json: Value = response.json().await?;
+        let mut events = Vec::new();
+        if let Some(documents) = response_json.as_array() {
+            for doc in documents {
+                if let Some(fields) = doc.get("document").and_then(|d| d.get("fields")) {
+                    match self.from_firestore_fields(fields) {
+                        Ok(archived_event) => {
+                            match self.archived_event_to_nostr_event(&archived_event) {
+                                Ok(event) => events.push(event),
+                                Err(e) => {
+                                    warn!("Failed to convert archived event to Nostr event: {}", e)
                                 }
                             }
                         }
+                        Err(e) => warn!("Failed to parse archived event: {}", e),
                     }
                 }
             }
         }
 
-        collected.sort_by_key(|e| e.created_at() as i64);
-        Ok(collected)
+        Ok(events)
     }
 
     /// Clean up expired archived events
     #[instrument(skip(self))]
     pub async fn cleanup_expired(&self) -> Result<u64> {
-        let access_token = self.get_access_token().await?;
+        let _timer = DbTimer::new("cleanup_expired", "archive");
+        let access_token = crate::gcp::access_token().await?;
         let now = Utc::now().timestamp();
-        
+
         // Query for expired documents
         let query = json!({
             "structuredQuery": {
@@ -455,7 +725,8 @@ impl MessageArchive {
         });
 
         let url = format!("{}:runQuery", self.base_url);
-        let response = self.http_client
+        let response = self
+            .http_client
             .post(&url)
             .header("Authorization", format!("Bearer {}", access_token))
             .header("Content-Type", "application/json")
@@ -466,7 +737,11 @@ impl MessageArchive {
         if !response.status().is_success() {
             let status = response.status();
             let error_text = response.text().await.unwrap_or_default();
-            return Err(anyhow::anyhow!("Failed to query expired events ({}): {}", status, error_text));
+            return Err(anyhow::anyhow!(
+                "Failed to query expired events ({}): {}",
+                status,
+                error_text
+            ));
         }
 
         let response_json: Value = response.json().await?;
@@ -476,7 +751,8 @@ impl MessageArchive {
             for doc in documents {
                 if let Some(document) = doc.get("document") {
                     if let Some(name) = document.get("name").and_then(|v| v.as_str()) {
-                        let delete_response = self.http_client
+                        let delete_response = self
+                            .http_client
                             .delete(&format!("https://firestore.googleapis.com/v1/{}", name))
                             .header("Authorization", format!("Bearer {}", access_token))
                             .send()
@@ -499,14 +775,311 @@ impl MessageArchive {
         Ok(deleted_count)
     }
 
+    /// Delete a single archived event if it was authored by `author`, returns true if deleted
+    #[instrument(skip(self))]
+    pub async fn delete_event(&self, kind: u32, event_id: &str, author: &str) -> Result<bool> {
+        let _timer = DbTimer::new("delete_event", "archive");
+        let doc_id = format!("{}-{}", kind, event_id);
+        let doc: Option<ArchivedEvent> = self
+            .db
+            .fluent()
+            .select()
+            .by_id_in("archived_events")
+            .obj()
+            .one(&doc_id)
+            .await?;
+        match doc {
+            Some(archived) if archived.pubkey == author => {
+                self.db
+                    .fluent()
+                    .delete()
+                    .from("archived_events")
+                    .document_id(&doc_id)
+                    .execute()
+                    .await?;
+                Ok(true)
+            }
+            Some(archived) => {
+                warn!(
+                    "Ignoring deletion of archived event {} by {}, authored by {}",
+                    doc_id, author, archived.pubkey
+                );
+                Ok(false)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Add an archived event of `bytes` content to the recipient's usage, returns the updated usage
+    async fn charge_archive_usage(
+        &self,
+        kind: u32,
+        recipient: &str,
+        bytes: u64,
+    ) -> Result<ArchiveUsage> {
+        let doc_id = format!("{}-{}", kind, recipient);
+        let mut transaction = self.db.begin_transaction().await?;
+        let tx_db =
+            self.db
+                .clone_with_consistency_selector(FirestoreConsistencySelector::Transaction(
+                    transaction.transaction_id().clone(),
+                ));
+        let existing: Option<ArchiveUsage> = tx_db
+            .fluent()
+            .select()
+            .by_id_in("archive_usage")
+            .obj()
+            .one(&doc_id)
+            .await?;
+        let mut usage = existing.unwrap_or_else(|| ArchiveUsage {
+            kind,
+            recipient: recipient.to_string(),
+            ..Default::default()
+        });
+        usage.count += 1;
+        usage.bytes += bytes;
+        self.db
+            .fluent()
+            .update()
+            .in_col("archive_usage")
+            .document_id(&doc_id)
+            .object(&usage)
+            .add_to_transaction(&mut transaction)?;
+        transaction.commit().await?;
+        Ok(usage)
+    }
+
+    /// Charge a newly archived event of `bytes` content to the recipient and evict the oldest
+    /// archived events of `kind` beyond `max_count` or `max_bytes` of content (0 disables a cap),
+    /// returns the number evicted. The archive is only scanned once the usage counter passes a cap.
+    #[instrument(skip(self))]
+    pub async fn enforce_recipient_cap(
+        &self,
+        kind: u32,
+        recipient: &str,
+        bytes: u64,
+        max_count: u32,
+        max_bytes: u64,
+    ) -> Result<u64> {
+        let _timer = DbTimer::new("enforce_recipient_cap", "archive");
+        if max_count == 0 && max_bytes == 0 {
+            return Ok(0);
+        }
+        let usage = self.charge_archive_usage(kind, recipient, bytes).await?;
+        let over_count = max_count > 0 && usage.count > max_count as u64;
+        let over_bytes = max_bytes > 0 && usage.bytes > max_bytes;
+        if !over_count && !over_bytes {
+            return Ok(0);
+        }
+        let archived: Vec<ArchivedEvent> = self
+            .db
+            .fluent()
+            .select()
+            .from("archived_events")
+            .filter(|f| f.field("recipients").array_contains(recipient))
+            .obj()
+            .query()
+            .await?;
+        let entries = archived
+            .into_iter()
+            .filter(|a| a.kind == kind)
+            .map(|a| (a.id, a.created_at, a.content.len() as u64))
+            .collect::<Vec<_>>();
+        let mut usage = ArchiveUsage {
+            kind,
+            recipient: recipient.to_string(),
+            count: entries.len() as u64,
+            bytes: entries.iter().map(|(_, _, size)| size).sum(),
+        };
+
+        let mut evicted = 0;
+        let sizes: std::collections::HashMap<String, u64> = entries
+            .iter()
+            .map(|(id, _, size)| (id.clone(), *size))
+            .collect();
+        for id in select_evictions(entries, max_count, max_bytes) {
+            self.db
+                .fluent()
+                .delete()
+                .from("archived_events")
+                .document_id(format!("{}-{}", kind, id))
+                .execute()
+                .await?;
+            usage.count -= 1;
+            usage.bytes -= sizes.get(&id).copied().unwrap_or_default();
+            evicted += 1;
+        }
+        // the scan is exact, expired events no longer counted
+        self.db
+            .fluent()
+            .update()
+            .in_col("archive_usage")
+            .document_id(format!("{}-{}", kind, recipient))
+            .object(&usage)
+            .execute::<()>()
+            .await?;
+        if evicted > 0 {
+            info!(
+                "Evicted {} archived kind {} events for recipient {}",
+                evicted, kind, recipient
+            );
+        }
+        Ok(evicted)
+    }
+
+    /// Delete all archived events belonging to a group (used when a group is removed)
+    #[instrument(skip(self))]
+    pub async fn delete_group_events(&self, group_id: &str) -> Result<u64> {
+        let _timer = DbTimer::new("delete_group_events", "archive");
+        let mut deleted_count = 0;
+
+        loop {
+            let batch: Vec<ArchivedEvent> = self
+                .db
+                .fluent()
+                .select()
+                .from("archived_events")
+                .filter(|f| f.field("group_id").eq(group_id))
+                .limit(500)
+                .obj()
+                .query()
+                .await?;
+
+            if batch.is_empty() {
+                break;
+            }
+
+            for archived in batch {
+                let doc_id = format!("{}-{}", archived.kind, archived.id);
+                self.db
+                    .fluent()
+                    .delete()
+                    .from("archived_events")
+                    .document_id(&doc_id)
+                    .execute()
+                    .await?;
+                deleted_count += 1;
+            }
+        }
+
+        info!(
+            "Deleted {} archived events for group {}",
+            deleted_count, group_id
+        );
+        Ok(deleted_count)
+    }
+
+    /// Archived events authored by or addressed (`p` tag) to a pubkey
+    async fn archived_for(&self, pubkey: &str) -> Result<Vec<ArchivedEvent>> {
+        let mut archived: Vec<ArchivedEvent> = self
+            .db
+            .fluent()
+            .select()
+            .from("archived_events")
+            .filter(|f| f.field("recipients").array_contains(pubkey))
+            .obj()
+            .query()
+            .await?;
+        let authored: Vec<ArchivedEvent> = self
+            .db
+            .fluent()
+            .select()
+            .from("archived_events")
+            .filter(|f| f.field("pubkey").eq(pubkey))
+            .obj()
+            .query()
+            .await?;
+        for event in authored {
+            if !archived
+                .iter()
+                .any(|a| a.kind == event.kind && a.id == event.id)
+            {
+                archived.push(event);
+            }
+        }
+        Ok(archived)
+    }
+
+    /// Archived events authored by or addressed to a pubkey, for a data export
+    #[instrument(skip(self))]
+    pub async fn list_for_pubkey(&self, pubkey: &str) -> Result<Vec<Event>> {
+        let _timer = DbTimer::new("list_for_pubkey", "archive");
+        let mut events = Vec::new();
+        for archived in self.archived_for(pubkey).await? {
+            match self.archived_event_to_nostr_event(&archived) {
+                Ok(event) => events.push(event),
+                Err(e) => warn!("Failed to convert archived event {}: {}", archived.id, e),
+            }
+        }
+        events.sort_by_key(|e| e.created_at());
+        Ok(events)
+    }
+
+    /// Delete the archived events authored by a pubkey, the giftwraps addressed
+    /// to it and its giftwrap claims, returns the number of archived events deleted.
+    /// Messages of other senders that only name the pubkey as a recipient are kept.
+    #[instrument(skip(self))]
+    pub async fn erase_pubkey(&self, pubkey: &str) -> Result<u64> {
+        let _timer = DbTimer::new("erase_pubkey", "archive");
+        let giftwrap = super::kinds::number(super::GIFTWRAP_KIND) as u32;
+        let mut deleted = 0;
+        for archived in self.archived_for(pubkey).await? {
+            if archived.pubkey != pubkey && archived.kind != giftwrap {
+                continue;
+            }
+            self.db
+                .fluent()
+                .delete()
+                .from("archived_events")
+                .document_id(format!("{}-{}", archived.kind, archived.id))
+                .execute()
+                .await?;
+            deleted += 1;
+        }
+        let claims: Vec<GiftwrapClaim> = self
+            .db
+            .fluent()
+            .select()
+            .from("giftwrap_digests")
+            .filter(|f| f.field("recipient").eq(pubkey))
+            .obj()
+            .query()
+            .await?;
+        for claim in claims {
+            self.db
+                .fluent()
+                .delete()
+                .from("giftwrap_digests")
+                .document_id(format!("{}-{}", claim.recipient, claim.digest))
+                .execute()
+                .await?;
+        }
+        let usages: Vec<ArchiveUsage> = self
+            .db
+            .fluent()
+            .select()
+            .from("archive_usage")
+            .filter(|f| f.field("recipient").eq(pubkey))
+            .obj()
+            .query()
+            .await?;
+        for usage in usages {
+            self.db
+                .fluent()
+                .delete()
+                .from("archive_usage")
+                .document_id(format!("{}-{}", usage.kind, usage.recipient))
+                .execute()
+                .await?;
+        }
+        info!("Erased {} archived events of {}", deleted, pubkey);
+        Ok(deleted)
+    }
+
     /// Convert archived event back to Nostr event
     fn archived_event_to_nostr_event(&self, archived: &ArchivedEvent) -> Result<Event> {
         // Reconstruct tags as array-of-arrays for Nostr event shape
-        let tags: Vec<Vec<String>> = archived
-            .tags
-            .iter()
-            .map(|tm| tm.values.clone())
-            .collect();
+        let tags: Vec<Vec<String>> = archived.tags.iter().map(|tm| tm.values.clone()).collect();
 
         let event_json = json!({
             "id": archived.id,
@@ -522,11 +1095,11 @@ impl MessageArchive {
         Ok(event)
     }
 
-
     /// Convert Firestore fields to ArchivedEvent
     fn from_firestore_fields(&self, fields: &Value) -> Result<ArchivedEvent> {
         let get_string = |field: &str| -> Result<String> {
-            fields.get(field)
+            fields
+                .get(field)
                 .and_then(|v| v.get("stringValue"))
                 .and_then(|v| v.as_str())
                 .map(|s| s.to_string())
@@ -534,7 +1107,8 @@ impl MessageArchive {
         };
 
         let get_int = |field: &str| -> Result<i64> {
-            fields.get(field)
+            fields
+                .get(field)
                 .and_then(|v| v.get("integerValue"))
                 .and_then(|v| v.as_str())
                 .and_then(|s| s.parse().ok())
@@ -542,7 +1116,8 @@ impl MessageArchive {
         };
 
         let get_string_array = |field: &str| -> Result<Vec<String>> {
-            let array = fields.get(field)
+            let array = fields
+                .get(field)
                 .and_then(|v| v.get("arrayValue"))
                 .and_then(|v| v.get("values"))
                 .and_then(|v| v.as_array())
@@ -589,12 +1164,14 @@ impl MessageArchive {
         };
 
         // Optional fields for group catch-up
-        let group_id = fields.get("group_id")
+        let group_id = fields
+            .get("group_id")
             .and_then(|v| v.get("stringValue"))
             .and_then(|v| v.as_str())
             .map(|s| s.to_string());
 
-        let group_epoch = fields.get("group_epoch")
+        let group_epoch = fields
+            .get("group_epoch")
             .and_then(|v| v.get("integerValue"))
             .and_then(|v| v.as_str())
             .and_then(|s| s.parse::<i64>().ok());
@@ -615,3 +1192,25 @@ impl MessageArchive {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_oldest_past_caps() {
+        let entries = vec![
+            ("a".to_owned(), 1, 10),
+            ("c".to_owned(), 3, 10),
+            ("b".to_owned(), 2, 10),
+            ("d".to_owned(), 4, 10),
+        ];
+        assert_eq!(select_evictions(entries.clone(), 2, 0), vec!["b", "a"]);
+        assert_eq!(select_evictions(entries.clone(), 0, 25), vec!["b", "a"]);
+        assert_eq!(
+            select_evictions(entries.clone(), 3, 15),
+            vec!["c", "b", "a"]
+        );
+        assert!(select_evictions(entries, 0, 0).is_empty());
+    }
+}
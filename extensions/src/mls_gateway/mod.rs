@@ -8,17 +8,42 @@
 //! - REST API endpoints for auxiliary flows
 //! - Cloud SQL integration for MLS-specific metadata
 
-pub mod storage;
+pub mod admin;
+pub mod backfill;
+pub mod delivery;
 pub mod endpoints;
-pub mod mailbox;
+pub mod export;
+pub mod forward;
+pub mod giftwrap_guard;
+pub mod group_stats;
 pub mod groups;
-pub mod message_archive;
+pub mod http_auth;
+pub mod keypackage_consumer;
 pub mod keypackage_delivery;
+pub mod keypackage_expiry;
+pub mod kinds;
+pub mod low_keypackages;
+pub mod mailbox;
+pub mod management;
+pub mod membership;
+pub mod message_archive;
+pub mod moderation;
+pub mod nip29;
+pub mod privacy;
+pub mod push;
+pub mod reconcile;
 pub mod req_interceptor;
-pub mod keypackage_consumer;
+pub mod resume;
+pub mod roster_auth;
+pub mod roster_quorum;
+pub mod roster_sequence;
+pub mod roster_snapshot;
+pub mod storage;
 pub mod test_keypackage_flow;
 
 mod keypackage_encoding;
+mod keypackage_policy;
+pub mod validation;
 
 #[cfg(test)]
 pub mod test_req_interception;
@@ -37,25 +62,33 @@ pub use storage::SqlStorage;
 
 pub use message_archive::MessageArchive;
 
+use crate::mls_gateway::keypackage_delivery::init_delivery_store;
 use actix_web::web::ServiceConfig;
-use nostr_relay::{Extension, Session, ExtensionMessageResult, ExtensionReqResult, PostProcessResult};
-use nostr_relay::db::Event;
-use nostr_relay::message::Subscription;
+use metrics::{counter, describe_counter, describe_gauge, describe_histogram, histogram};
+use nostr_relay::db::{Event, SortList};
+use nostr_relay::message::{OutgoingMessage, Prefix, Subscription};
+use nostr_relay::{
+    Extension, ExtensionMessageResult, ExtensionReqResult, PostProcessResult, ReadyFuture, Session,
+    SessionInfo, ShutdownFuture,
+};
 use serde::{Deserialize, Serialize};
-use std::sync::Arc;
-use tracing::{info, warn, error};
-use metrics::{counter, describe_counter, describe_histogram};
-use crate::mls_gateway::keypackage_delivery::init_delivery_store;
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+use tracing::{debug, error, info, warn};
 
 // MLS and Noise event kinds as per specification
-const KEYPACKAGE_KIND: u16 = 443;         // MLS KeyPackage
-const WELCOME_KIND: u16 = 444;            // MLS Welcome (embedded in 1059)
-const MLS_GROUP_MESSAGE_KIND: u16 = 445;  // MLS Group Message
-const NOISE_DM_KIND: u16 = 446;           // Noise Direct Message
-// Note: Kind 447 (KeyPackage Request) is deprecated - use REQ queries for kind 443 instead
-const ROSTER_POLICY_KIND: u16 = 450;      // Roster/Policy (Admin-signed membership control)
+const KEYPACKAGE_KIND: u16 = 443; // MLS KeyPackage
+const WELCOME_KIND: u16 = 444; // MLS Welcome (embedded in 1059)
+const MLS_GROUP_MESSAGE_KIND: u16 = 445; // MLS Group Message
+const NOISE_DM_KIND: u16 = 446; // Noise Direct Message
+                                // Note: Kind 447 (KeyPackage Request) is deprecated - use REQ queries for kind 443 instead
+const KEYPACKAGE_CONSUMED_KIND: u16 = 449; // KeyPackage consumed notice (signed by the Welcome sender)
+const ROSTER_POLICY_KIND: u16 = 450; // Roster/Policy (Admin-signed membership control)
 const KEYPACKAGE_RELAYS_LIST_KIND: u16 = 10051; // KeyPackage Relays List
-const GIFTWRAP_KIND: u16 = 1059;          // Giftwrap envelope for Welcome
+const GIFTWRAP_KIND: u16 = 1059; // Giftwrap envelope for Welcome
+const DELETION_KIND: u16 = 5; // NIP-09 Event Deletion
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum KeyPackageOutputEncoding {
@@ -79,6 +112,57 @@ fn keypackage_output_encoding(subscription: &Subscription) -> KeyPackageOutputEn
     KeyPackageOutputEncoding::Hex
 }
 
+/// Whether a subscription queries KeyPackages (kind 443)
+fn queries_keypackages(subscription: &Subscription) -> bool {
+    subscription.filters.iter().any(|filter| {
+        filter
+            .kinds
+            .iter()
+            .any(|&k| k == kinds::number(KEYPACKAGE_KIND))
+    })
+}
+
+/// Cap the limit of kind 443 filters at the per query KeyPackage maximum (at most 2 per NIP-EE)
+///
+/// Filters mixing 443 with other kinds are split so the cap does not apply to the other kinds.
+/// Returns the rewritten subscription, None if every filter already complies.
+fn cap_keypackage_filters(subscription: &Subscription, max_per_query: u32) -> Option<Subscription> {
+    let max = max_per_query.min(2) as u64;
+    let keypackage_kind = kinds::number(KEYPACKAGE_KIND);
+    let mut changed = false;
+    let mut filters = Vec::with_capacity(subscription.filters.len());
+    for filter in &subscription.filters {
+        if !filter.kinds.contains(&keypackage_kind) {
+            filters.push(filter.clone());
+            continue;
+        }
+        let mut keypackages = filter.clone();
+        if filter.kinds.len() > 1 {
+            let mut rest = filter.clone();
+            rest.kinds = SortList::from(
+                filter
+                    .kinds
+                    .iter()
+                    .copied()
+                    .filter(|&k| k != keypackage_kind)
+                    .collect::<Vec<_>>(),
+            );
+            filters.push(rest);
+            keypackages.kinds = SortList::from(vec![keypackage_kind]);
+            changed = true;
+        }
+        if keypackages.limit.map_or(true, |limit| limit > max) {
+            keypackages.limit = Some(max);
+            changed = true;
+        }
+        filters.push(keypackages);
+    }
+    changed.then(|| Subscription {
+        id: subscription.id.clone(),
+        filters,
+    })
+}
+
 fn build_synthetic_keypackage_event(
     event_id: &str,
     owner_pubkey: &str,
@@ -92,7 +176,9 @@ fn build_synthetic_keypackage_event(
             serde_json::json!([]),
         ),
         KeyPackageOutputEncoding::Base64 => (
-            crate::mls_gateway::keypackage_encoding::base64_from_firestore_content(firestore_content)?,
+            crate::mls_gateway::keypackage_encoding::base64_from_firestore_content(
+                firestore_content,
+            )?,
             serde_json::json!([["encoding", "base64"]]),
         ),
     };
@@ -107,12 +193,13 @@ fn build_synthetic_keypackage_event(
         "sig": "00000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000"
     });
 
-    serde_json::from_value::<Event>(event_json)
-        .map_err(|e| anyhow::anyhow!("Failed to construct synthetic keypackage event {event_id}: {e}"))
+    serde_json::from_value::<Event>(event_json).map_err(|e| {
+        anyhow::anyhow!("Failed to construct synthetic keypackage event {event_id}: {e}")
+    })
 }
 
 /// Storage backend type configuration
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
 #[serde(rename_all = "lowercase")]
 pub enum StorageType {
     Firestore,
@@ -144,14 +231,51 @@ pub struct MlsGatewayConfig {
     pub enable_api: bool,
     /// API endpoint prefix
     pub api_prefix: String,
+    /// Bearer token for the admin API under `{api_prefix}/admin` (or MLS_ADMIN_TOKEN env); admin API is disabled when unset
+    pub admin_token: Option<String>,
     /// Enable message archival for offline delivery
     pub enable_message_archive: bool,
     /// Message archive TTL in days
     pub message_archive_ttl_days: u32,
+    /// Kinds archived for offline delivery; kinds other than group messages (445) and
+    /// giftwraps (1059) are direct messages like Noise DMs (446)
+    pub archived_kinds: Vec<u16>,
+    /// Maximum archived Noise DMs (446) per recipient, oldest evicted first (0 disables)
+    pub noise_dm_archive_max_per_recipient: u32,
+    /// Maximum archived Noise DM (446) content bytes per recipient, oldest evicted first (0 disables)
+    pub noise_dm_archive_max_bytes_per_recipient: u64,
     /// System/relay pubkey (deprecated - was used for kind 447 requests)
     pub system_pubkey: Option<String>,
     /// Admin pubkeys allowed to send roster/policy events (kind 450)
     pub admin_pubkeys: Vec<String>,
+    /// Hex pubkeys allowed to call the NIP-86 management API, which is disabled when empty
+    pub management_pubkeys: Vec<String>,
+    /// Deliver giftwraps (1059) and direct messages, live and in REQ results, only to their authenticated recipients
+    pub recipient_only_delivery: bool,
+    /// Broadcast group messages (445) and roster snapshots of groups with a roster only to authenticated members
+    pub roster_gated_delivery: bool,
+    /// Handling of roster/policy sequence gaps and forks: `reject` at ingress or `flag` and accept
+    pub roster_sequence_policy: roster_sequence::SequencePolicy,
+    /// Minimum role (`owner` or `admin`) per roster operation, overriding the built-in matrix
+    pub roster_operation_roles: std::collections::HashMap<String, roster_auth::RosterRole>,
+    /// Per-group overrides of `roster_operation_roles` (group_id -> operation -> role)
+    pub roster_group_operation_roles: std::collections::HashMap<
+        String,
+        std::collections::HashMap<String, roster_auth::RosterRole>,
+    >,
+    /// Admin approvals required per roster operation before it is applied (operation -> count)
+    pub roster_quorum: std::collections::HashMap<String, u32>,
+    /// Per-group overrides of `roster_quorum` (group_id -> operation -> count)
+    pub roster_group_quorum:
+        std::collections::HashMap<String, std::collections::HashMap<String, u32>>,
+    /// Seconds a roster operation waits for its quorum before it expires
+    pub roster_quorum_ttl_secs: u64,
+    /// Publish a relay-signed roster snapshot (kind 30450) per group after roster/policy events, needs the service key
+    pub publish_roster_snapshots: bool,
+    /// Mirror groups as relay-signed NIP-29 metadata (39000) and admins (39001) events, needs the service key
+    pub nip29_groups: bool,
+    /// Also publish NIP-29 member lists (39002), making rosters public
+    pub nip29_members: bool,
     /// TTL for KeyPackage requests (deprecated - kind 447 no longer supported)
     pub keypackage_request_ttl: u64,
     /// TTL for roster/policy events in days (default: indefinite/365 days)
@@ -160,11 +284,20 @@ pub struct MlsGatewayConfig {
     /// Enable in-process MLS decrypt/dispatch for service actions
     pub enable_in_process_decrypt: bool,
     /// Select the active handler for service actions: "in-process" or "external"
+    /// (external endpoint settings are read by the NIP-SERVICE extension)
     pub preferred_service_handler: String,
     /// Optional policy hint: if true, skip attempt when registry does not mark service-enabled
     pub gating_use_registry_hint: bool,
     /// MLS service-member user identifier used for membership checks
     pub mls_service_user_id: Option<String>,
+    /// Per-group service-member identities (group_id -> user id), overriding `mls_service_user_id`
+    pub mls_service_user_ids: std::collections::HashMap<String, String>,
+    /// Directory of the persistent (SQLCipher) service-member state, e.g. a GCS Fuse mount
+    pub mls_service_storage_path: Option<String>,
+    /// Secret Manager version holding the SQLCipher key (or MLS_SERVICE_SQLCIPHER_KEY env)
+    pub mls_service_sqlcipher_secret: Option<String>,
+    /// KMS key unwrapping the secret when it stores a KMS ciphertext
+    pub mls_service_sqlcipher_kms_key: Option<String>,
 
     /// Backfill Firestore archived events into LMDB on startup
     pub backfill_on_startup: bool,
@@ -172,10 +305,101 @@ pub struct MlsGatewayConfig {
     pub backfill_kinds: Vec<u32>,
     /// Upper bound on total events to backfill
     pub backfill_max_events: u32,
+    /// Interval in seconds for incremental backfill after startup (0 disables)
+    pub backfill_interval_secs: u64,
+    /// Forward giftwraps (1059) to the recipient's KeyPackage relays (kind 10051)
+    pub forward_giftwraps: bool,
+    /// Maximum giftwraps forwarded per recipient per minute
+    pub giftwrap_forward_max_per_minute: u32,
+    /// Maximum giftwraps accepted per recipient per day (0 disables)
+    pub giftwrap_daily_quota: u32,
+    /// Oldest accepted giftwrap created_at in seconds before now, NIP-59 backdates up to 2 days (0 disables)
+    pub giftwrap_max_backdate_secs: u64,
+    /// Newest accepted giftwrap created_at in seconds after now (0 disables)
+    pub giftwrap_max_future_secs: u64,
+    /// Minimum giftwrap content length in bytes, the smallest NIP-44 v2 payload is 132
+    pub giftwrap_min_content_bytes: usize,
+    /// Maximum giftwrap content length in bytes (0 disables)
+    pub giftwrap_max_content_bytes: usize,
+    /// Reject giftwraps signed by long-term identities instead of ephemeral keys
+    pub giftwrap_reject_known_identities: bool,
+    /// Public urls of this relay, skipped when forwarding
+    pub relay_urls: Vec<String>,
+    /// Send FCM/APNs pushes for 1059/445 events to recipients without an authenticated session
+    pub push_enabled: bool,
+    /// Firebase project for FCM, defaults to `project_id`
+    pub push_project_id: Option<String>,
+    /// APNs auth key (.p8 PEM) for `apns` tokens, usually a `secret://` reference
+    pub push_apns_key: Option<String>,
+    /// Key id of `push_apns_key`
+    pub push_apns_key_id: Option<String>,
+    /// Apple developer team id issuing the APNs provider token
+    pub push_apns_team_id: Option<String>,
+    /// Bundle id of the app, the `apns-topic`
+    pub push_apns_topic: Option<String>,
+    /// Send APNs pushes to the sandbox (development builds)
+    pub push_apns_sandbox: bool,
+    /// Upper bound of recipients notified per event
+    pub push_max_recipients: usize,
+    /// Interval in seconds for LMDB -> archive reconciliation (0 disables)
+    pub reconcile_interval_secs: u64,
+    /// How far back in seconds reconciliation scans LMDB
+    pub reconcile_lookback_secs: u64,
+    /// Persist the last event delivered to subscriptions of authenticated sessions and replay
+    /// archived events they missed when they subscribe again with the same id
+    pub resume_subscriptions: bool,
+    /// Days a subscription cursor is kept after its last update
+    pub resume_cursor_ttl_days: u32,
+    /// Upper bound of archived events replayed per subscription
+    pub resume_max_events: u32,
+    /// Bucket archived events are exported to, `gs://bucket[/prefix]` or `s3://bucket[/prefix]` (unset disables)
+    pub export_bucket: Option<String>,
+    /// Object name of a kind and day below the prefix, with `{kind}`, `{yyyy}`, `{mm}` and `{dd}`
+    pub export_layout: String,
+    /// Export days about to expire from Firestore (`expiring`) or every finished day (`all`)
+    pub export_mode: export::ExportMode,
+    /// Kinds exported
+    pub export_kinds: Vec<u32>,
+    /// Interval in seconds between exports
+    pub export_interval_secs: u64,
+    /// Days exported objects are kept in the bucket (0 keeps them)
+    pub export_retention_days: u32,
+    /// Region of an `s3://` export bucket, credentials are read from AWS_ACCESS_KEY_ID/AWS_SECRET_ACCESS_KEY
+    pub export_s3_region: String,
+    /// Endpoint of an S3-compatible store, defaults to AWS
+    pub export_s3_endpoint: Option<String>,
+    /// Count messages, senders and epochs per group on every group message (445)
+    pub group_stats_enabled: bool,
+    /// Interval in seconds between group activity gauge updates (0 disables)
+    pub group_stats_interval_secs: u64,
+    /// Busiest groups published in `mls_gateway_group_messages` (0 disables)
+    pub group_stats_top: usize,
+    /// Days without messages after which a group counts as abandoned
+    pub group_abandoned_days: u32,
     /// Maximum number of keypackages per user
     pub max_keypackages_per_user: Option<u32>,
     /// Maximum keypackages to return per author per query (default: 1, max: 2)
     pub max_keypackages_per_query: u32,
+    /// NOTICE owners whose unexpired keypackage count drops below this (0 disables)
+    pub low_keypackage_threshold: u32,
+    /// Seconds after an owner's second KeyPackage arrives before their last resort KeyPackage is deleted
+    pub last_resort_deletion_delay_secs: u64,
+    /// KeyPackages an owner must hold for the last resort KeyPackage to be deleted (at least 2)
+    pub last_resort_min_keypackages: u32,
+    /// Ciphersuites accepted on KeyPackages (`0x0001` or decimal), empty accepts any
+    pub allowed_ciphersuites: Vec<String>,
+    /// `mls_protocol_version` values accepted on KeyPackages, empty accepts any
+    pub accepted_protocol_versions: Vec<String>,
+    /// Tag validation per kind as `kind_<number> = "strict" | "lenient" | "off"`, default lenient
+    pub validation_mode: std::collections::HashMap<String, validation::ValidationMode>,
+    /// NIP-44 v2 shape check of encrypted kind (446, 1059) content: `strict`, `lenient` or `off` (default)
+    pub nip44_validation: validation::ValidationMode,
+    /// Valid KeyPackages kept published for the service member (0 disables)
+    pub service_keypackage_count: u32,
+    /// Interval in seconds between service member KeyPackage checks
+    pub service_keypackage_interval_secs: u64,
+    /// Kind number of each MLS role (`[extensions.mls_gateway.kinds]`), NIP-EE numbers by default
+    pub kinds: kinds::KindMap,
 }
 
 impl Default for MlsGatewayConfig {
@@ -188,23 +412,160 @@ impl Default for MlsGatewayConfig {
             welcome_ttl: 259200,    // 3 days
             enable_api: false,
             api_prefix: "/api/v1".to_string(),
+            admin_token: None,
             enable_message_archive: true,
             message_archive_ttl_days: 30,
+            archived_kinds: vec![MLS_GROUP_MESSAGE_KIND, NOISE_DM_KIND, GIFTWRAP_KIND],
+            noise_dm_archive_max_per_recipient: 1000,
+            noise_dm_archive_max_bytes_per_recipient: 16 * 1024 * 1024,
             system_pubkey: None,
             admin_pubkeys: Vec::new(),
+            management_pubkeys: Vec::new(),
+            recipient_only_delivery: false,
+            roster_gated_delivery: false,
+            roster_sequence_policy: Default::default(),
+            roster_operation_roles: std::collections::HashMap::new(),
+            roster_group_operation_roles: std::collections::HashMap::new(),
+            roster_quorum: std::collections::HashMap::new(),
+            roster_group_quorum: std::collections::HashMap::new(),
+            roster_quorum_ttl_secs: 604800, // 7 days
+            publish_roster_snapshots: true,
+            nip29_groups: false,
+            nip29_members: false,
             keypackage_request_ttl: 604800, // 7 days
             roster_policy_ttl_days: 365,    // 1 year
             enable_in_process_decrypt: true,
             preferred_service_handler: "in-process".to_string(),
             gating_use_registry_hint: false,
             mls_service_user_id: None,
+            mls_service_user_ids: Default::default(),
+            mls_service_storage_path: None,
+            mls_service_sqlcipher_secret: None,
+            mls_service_sqlcipher_kms_key: None,
             backfill_on_startup: true,
-            backfill_kinds: vec![445, 1059, 446],
+            backfill_kinds: vec![
+                MLS_GROUP_MESSAGE_KIND as u32,
+                GIFTWRAP_KIND as u32,
+                NOISE_DM_KIND as u32,
+            ],
             backfill_max_events: 50000,
+            backfill_interval_secs: 300,
+            forward_giftwraps: false,
+            giftwrap_forward_max_per_minute: 10,
+            giftwrap_daily_quota: 500,
+            giftwrap_max_backdate_secs: 259200, // 3 days
+            giftwrap_max_future_secs: 900,
+            giftwrap_min_content_bytes: 132,
+            giftwrap_max_content_bytes: 131072,
+            giftwrap_reject_known_identities: false,
+            relay_urls: Vec::new(),
+            push_enabled: false,
+            push_project_id: None,
+            push_apns_key: None,
+            push_apns_key_id: None,
+            push_apns_team_id: None,
+            push_apns_topic: None,
+            push_apns_sandbox: false,
+            push_max_recipients: 100,
+            reconcile_interval_secs: 600,
+            reconcile_lookback_secs: 3600,
+            resume_subscriptions: false,
+            resume_cursor_ttl_days: 7,
+            resume_max_events: 500,
+            export_bucket: None,
+            export_layout: "kind={kind}/{yyyy}/{mm}/{dd}.jsonl.gz".to_string(),
+            export_mode: export::ExportMode::Expiring,
+            export_kinds: vec![
+                MLS_GROUP_MESSAGE_KIND as u32,
+                GIFTWRAP_KIND as u32,
+                NOISE_DM_KIND as u32,
+            ],
+            export_interval_secs: 3600,
+            export_retention_days: 0,
+            export_s3_region: "us-east-1".to_string(),
+            export_s3_endpoint: None,
+            group_stats_enabled: true,
+            group_stats_interval_secs: 300,
+            group_stats_top: 10,
+            group_abandoned_days: 30,
             max_keypackages_per_user: Some(15),
             max_keypackages_per_query: 1,
+            low_keypackage_threshold: 2,
+            last_resort_deletion_delay_secs: 600, // 10 minutes
+            last_resort_min_keypackages: 3,
+            // RFC 9420 registered ciphersuites
+            allowed_ciphersuites: (1..=7).map(|cs| format!("0x{:04x}", cs)).collect(),
+            accepted_protocol_versions: vec!["1.0".to_string()],
+            validation_mode: Default::default(),
+            nip44_validation: validation::ValidationMode::Off,
+            service_keypackage_count: 5,
+            service_keypackage_interval_secs: 300,
+            kinds: Default::default(),
+        }
+    }
+}
+
+impl MlsGatewayConfig {
+    /// Reset out of range values to their defaults, returns the fields that were reset
+    pub fn validate(&mut self) -> Vec<&'static str> {
+        let defaults = Self::default();
+        let mut reset = Vec::new();
+        if self.last_resort_deletion_delay_secs == 0 {
+            self.last_resort_deletion_delay_secs = defaults.last_resort_deletion_delay_secs;
+            reset.push("last_resort_deletion_delay_secs");
+        }
+        // Deleting the last resort KeyPackage must leave the owner at least one
+        if self.last_resort_min_keypackages < 2 {
+            self.last_resort_min_keypackages = defaults.last_resort_min_keypackages;
+            reset.push("last_resort_min_keypackages");
+        }
+        // Each role needs its own kind number
+        if self.kinds.has_duplicates() {
+            self.kinds = defaults.kinds.clone();
+            reset.push("kinds");
+        }
+        // Kind lists left at their defaults follow the configured numbers
+        if self.archived_kinds == defaults.archived_kinds {
+            self.archived_kinds = self
+                .archived_kinds
+                .iter()
+                .map(|k| self.kinds.number(*k))
+                .collect();
+        }
+        let number = |k: &u32| self.kinds.number(*k as u16) as u32;
+        if self.backfill_kinds == defaults.backfill_kinds {
+            self.backfill_kinds = defaults.backfill_kinds.iter().map(number).collect();
+        }
+        if self.export_kinds == defaults.export_kinds {
+            self.export_kinds = defaults.export_kinds.iter().map(number).collect();
+        }
+        reset
+    }
+
+    /// Whether a kind is a direct message: Noise DMs (446) and archived kinds other than 445 and 1059
+    pub fn is_direct_kind(&self, kind: u16) -> bool {
+        match kinds::canonical(kind) {
+            NOISE_DM_KIND => true,
+            MLS_GROUP_MESSAGE_KIND | GIFTWRAP_KIND => false,
+            _ => self.archived_kinds.contains(&kind),
         }
     }
+
+    /// Tag validation mode of a kind
+    pub fn validation_mode(&self, kind: u16) -> validation::ValidationMode {
+        self.validation_mode
+            .get(&format!("kind_{}", kind))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Service-member identity used in a group
+    pub fn service_user_id(&self, group_id: &str) -> Option<&str> {
+        self.mls_service_user_ids
+            .get(group_id)
+            .or(self.mls_service_user_id.as_ref())
+            .map(|s| s.as_str())
+    }
 }
 
 /// Storage trait for MLS Gateway
@@ -226,10 +587,10 @@ pub trait MlsStorage: Send + Sync {
     async fn is_admin(&self, group_id: &str, pubkey: &str) -> anyhow::Result<bool>;
     async fn add_admins(&self, group_id: &str, admins: &[String]) -> anyhow::Result<()>;
     async fn remove_admins(&self, group_id: &str, admins: &[String]) -> anyhow::Result<()>;
-    
+
     /// Get the last roster/policy sequence number for a group
     async fn get_last_roster_sequence(&self, group_id: &str) -> anyhow::Result<Option<u64>>;
-    
+
     /// Store a roster/policy event with sequence validation
     async fn store_roster_policy(
         &self,
@@ -242,7 +603,11 @@ pub trait MlsStorage: Send + Sync {
     ) -> anyhow::Result<()>;
 
     /// KeyPackage Relays List per owner (kind 10051)
-    async fn upsert_keypackage_relays(&self, owner_pubkey: &str, relays: &[String]) -> anyhow::Result<()>;
+    async fn upsert_keypackage_relays(
+        &self,
+        owner_pubkey: &str,
+        relays: &[String],
+    ) -> anyhow::Result<()>;
     async fn get_keypackage_relays(&self, owner_pubkey: &str) -> anyhow::Result<Vec<String>>;
 
     /// KeyPackage lifecycle management (kind 443)
@@ -258,8 +623,9 @@ pub trait MlsStorage: Send + Sync {
         created_at: i64,
         expires_at: i64,
     ) -> anyhow::Result<()>;
-    
-    /// Query keypackages with filters
+
+    /// Query keypackages with filters, `since` is an inclusive lower bound of
+    /// created_at and a page holds at most 1000 rows
     async fn query_keypackages(
         &self,
         authors: Option<&[String]>,
@@ -267,56 +633,384 @@ pub trait MlsStorage: Send + Sync {
         limit: Option<u32>,
         order_by: Option<&str>,
     ) -> anyhow::Result<Vec<(String, String, String, i64)>>; // (event_id, owner_pubkey, content, created_at)
-    
+
     /// Delete a consumed keypackage (unless it's a last resort keypackage)
     async fn delete_consumed_keypackage(&self, event_id: &str) -> anyhow::Result<bool>; // returns true if deleted
-    
+
     /// Count keypackages per user
     async fn count_user_keypackages(&self, owner_pubkey: &str) -> anyhow::Result<u32>;
-    
+
+    /// Unexpired keypackage of an owner with the given content hash
+    async fn find_keypackage_by_hash(
+        &self,
+        owner_pubkey: &str,
+        content_hash: &str,
+    ) -> anyhow::Result<Option<String>>;
+
+    /// KeyPackage query rate limit window of a requester/author pair
+    async fn get_keypackage_request_rate_limit(
+        &self,
+        requester_pubkey: &str,
+        recipient_pubkey: &str,
+    ) -> anyhow::Result<Option<firestore::KeyPackageRequestRateLimit>>;
+    async fn put_keypackage_request_rate_limit(
+        &self,
+        limit: &firestore::KeyPackageRequestRateLimit,
+    ) -> anyhow::Result<()>;
+
     /// Clean up expired keypackages and enforce per-user limits
     async fn cleanup_expired_keypackages(&self, max_per_user: u32) -> anyhow::Result<u32>;
 
     // New methods for pending deletion management
-    
+
     /// Create a pending deletion record for last resort keypackage
-    async fn create_pending_deletion(&self, pending: &firestore::PendingDeletion) -> anyhow::Result<()>;
-    
+    async fn create_pending_deletion(
+        &self,
+        pending: &firestore::PendingDeletion,
+    ) -> anyhow::Result<()>;
+
     /// Get pending deletion for a user
-    async fn get_pending_deletion(&self, user_pubkey: &str) -> anyhow::Result<Option<firestore::PendingDeletion>>;
-    
+    async fn get_pending_deletion(
+        &self,
+        user_pubkey: &str,
+    ) -> anyhow::Result<Option<firestore::PendingDeletion>>;
+
     /// Update pending deletion (add new keypackages to the list)
-    async fn update_pending_deletion(&self, pending: &firestore::PendingDeletion) -> anyhow::Result<()>;
-    
+    async fn update_pending_deletion(
+        &self,
+        pending: &firestore::PendingDeletion,
+    ) -> anyhow::Result<()>;
+
     /// Delete pending deletion record
     async fn delete_pending_deletion(&self, user_pubkey: &str) -> anyhow::Result<()>;
-    
+
     /// Delete keypackage by ID (bypassing last-one check)
     async fn delete_keypackage_by_id(&self, event_id: &str) -> anyhow::Result<()>;
-    
+
     /// Check if a keypackage exists
     async fn keypackage_exists(&self, event_id: &str) -> anyhow::Result<bool>;
-    
+
+    /// Get the owner pubkey of a stored keypackage
+    async fn get_keypackage_owner(&self, event_id: &str) -> anyhow::Result<Option<String>>;
+
     /// Get all pending deletions that should be processed
-    async fn get_expired_pending_deletions(&self) -> anyhow::Result<Vec<firestore::PendingDeletion>>;
+    async fn get_expired_pending_deletions(
+        &self,
+    ) -> anyhow::Result<Vec<firestore::PendingDeletion>>;
+
+    /// Push notification tokens per pubkey
+    async fn upsert_push_token(
+        &self,
+        pubkey: &str,
+        token: &str,
+        platform: &str,
+    ) -> anyhow::Result<()>;
+    async fn get_push_tokens(&self, pubkey: &str) -> anyhow::Result<Vec<firestore::PushToken>>;
+
+    /// Remove a push token, limited to tokens of `pubkey` when given
+    async fn delete_push_token(&self, pubkey: Option<&str>, token: &str) -> anyhow::Result<bool>;
+
+    // Group administration (used by the `rnostr group` command)
+
+    /// Fetch the full group record, including admins
+    async fn get_group(&self, group_id: &str) -> anyhow::Result<Option<firestore::GroupInfo>>;
+
+    /// List all roster/policy records for a group ordered by sequence ascending
+    async fn list_roster_history(
+        &self,
+        group_id: &str,
+    ) -> anyhow::Result<Vec<firestore::RosterPolicyDocument>>;
+
+    /// Overwrite the owner of an existing group
+    async fn set_group_owner(&self, group_id: &str, owner_pubkey: &str) -> anyhow::Result<()>;
+
+    /// Delete a group record and its roster/policy history, returns the number of roster records removed
+    async fn delete_group(&self, group_id: &str) -> anyhow::Result<u32>;
+
+    // Welcome mailbox
+    /// Record a giftwrap (1059) delivered to a recipient
+    async fn store_mailbox_welcome(&self, entry: &firestore::MailboxWelcome) -> anyhow::Result<()>;
+
+    /// Ack giftwraps of a recipient, returns the entries newly acked
+    async fn ack_mailbox_welcomes(
+        &self,
+        recipient: &str,
+        event_ids: &[String],
+    ) -> anyhow::Result<Vec<firestore::MailboxWelcome>>;
+
+    /// Ids and expires_at of acked or expired giftwraps with expires_at after `since`
+    async fn list_welcome_tombstones(
+        &self,
+        since: chrono::DateTime<chrono::Utc>,
+    ) -> anyhow::Result<Vec<(String, i64)>>;
+
+    /// Remove mailbox entries with expires_at up to `before`, returns the number removed
+    async fn cleanup_expired_welcomes(
+        &self,
+        before: chrono::DateTime<chrono::Utc>,
+    ) -> anyhow::Result<u32>;
+
+    // NIP-SERVICE idempotency
+    /// Claim an action_id until `expires_at`, false while an unexpired claim exists
+    async fn claim_service_action(
+        &self,
+        action_id: &str,
+        client_id: &str,
+        expires_at: chrono::DateTime<chrono::Utc>,
+    ) -> anyhow::Result<bool>;
+
+    /// Remove action claims with expires_at up to `before`, returns the number removed
+    async fn cleanup_expired_service_actions(
+        &self,
+        before: chrono::DateTime<chrono::Utc>,
+    ) -> anyhow::Result<u32>;
+
+    // Moderation
+    /// All allow/deny rules
+    async fn list_moderation_rules(&self) -> anyhow::Result<Vec<firestore::ModerationRule>>;
+
+    /// Add or replace a rule
+    async fn put_moderation_rule(&self, rule: &firestore::ModerationRule) -> anyhow::Result<()>;
+
+    /// Remove a rule, returns false if it did not exist
+    async fn delete_moderation_rule(
+        &self,
+        list: &str,
+        target: &str,
+        value: &str,
+    ) -> anyhow::Result<bool>;
+
+    /// Group activity analytics
+    /// Count a group message (445) of `sender`, returns the updated counters
+    async fn record_group_activity(
+        &self,
+        group_id: &str,
+        sender: &str,
+        epoch: Option<i64>,
+    ) -> anyhow::Result<firestore::GroupStats>;
+
+    async fn get_group_stats(
+        &self,
+        group_id: &str,
+    ) -> anyhow::Result<Option<firestore::GroupStats>>;
+
+    /// Counters of all groups
+    async fn list_group_stats(&self) -> anyhow::Result<Vec<firestore::GroupStats>>;
+
+    // Roster sequence anomalies
+    /// Stored roster/policy record of a group at `sequence`
+    async fn get_roster_policy(
+        &self,
+        group_id: &str,
+        sequence: u64,
+    ) -> anyhow::Result<Option<firestore::RosterPolicyDocument>>;
+
+    async fn store_roster_anomaly(&self, anomaly: &firestore::RosterAnomaly) -> anyhow::Result<()>;
+
+    /// Anomalies of a group, or of all groups, oldest first
+    async fn list_roster_anomalies(
+        &self,
+        group_id: Option<&str>,
+    ) -> anyhow::Result<Vec<firestore::RosterAnomaly>>;
+
+    /// Store a roster/policy record and apply it to the group registry in one
+    /// transaction, failing if the sequence is already taken
+    async fn apply_roster_operation(&self, op: &firestore::RosterOperation) -> anyhow::Result<()>;
+
+    // Roster quorum
+    /// Insert a pending roster operation keyed by its event id, an existing one is an error
+    async fn create_pending_roster_op(&self, op: &firestore::PendingRosterOp)
+        -> anyhow::Result<()>;
+
+    /// Count an approval of a pending roster operation in one transaction, `None` if it does not exist
+    async fn approve_pending_roster_op(
+        &self,
+        event_id: &str,
+        approver: &str,
+        now: i64,
+    ) -> anyhow::Result<Option<(roster_quorum::Approval, firestore::PendingRosterOp)>>;
+
+    /// Close an approved roster operation as executed or superseded
+    async fn set_pending_roster_op_status(
+        &self,
+        event_id: &str,
+        status: &str,
+    ) -> anyhow::Result<()>;
+
+    async fn get_pending_roster_op(
+        &self,
+        event_id: &str,
+    ) -> anyhow::Result<Option<firestore::PendingRosterOp>>;
+
+    /// Pending roster operations of a group, or of all groups, oldest first
+    async fn list_pending_roster_ops(
+        &self,
+        group_id: Option<&str>,
+    ) -> anyhow::Result<Vec<firestore::PendingRosterOp>>;
+
+    // Device attestation
+    async fn get_attestation(
+        &self,
+        pubkey: &str,
+    ) -> anyhow::Result<Option<firestore::AttestationRecord>>;
+
+    /// Add or replace the attestation of a pubkey
+    async fn put_attestation(&self, record: &firestore::AttestationRecord) -> anyhow::Result<()>;
+
+    // Subscription resume
+    async fn get_subscription_cursor(
+        &self,
+        pubkey: &str,
+        subscription_id: &str,
+    ) -> anyhow::Result<Option<firestore::SubscriptionCursor>>;
+
+    /// Add or replace subscription cursors
+    async fn put_subscription_cursors(
+        &self,
+        cursors: &[firestore::SubscriptionCursor],
+    ) -> anyhow::Result<()>;
 }
 
-/// MLS Gateway Extension
+/// Storage implementation behind a [`StorageBackend`]
 #[derive(Debug, Clone)]
-pub enum StorageBackend {
+pub enum Backend {
     #[cfg(feature = "mls_gateway_sql")]
     Sql(Arc<storage::SqlStorage>),
     #[cfg(feature = "mls_gateway_firestore")]
     Firestore(Arc<firestore::FirestoreStorage>),
 }
 
+impl Backend {
+    /// Calls and handles holding this backend besides the caller
+    fn in_flight(&self) -> usize {
+        match self {
+            #[cfg(feature = "mls_gateway_sql")]
+            Backend::Sql(storage) => Arc::strong_count(storage) - 1,
+            #[cfg(feature = "mls_gateway_firestore")]
+            Backend::Firestore(storage) => Arc::strong_count(storage) - 1,
+        }
+    }
+
+    /// Label of this backend in metrics
+    fn name(&self) -> &'static str {
+        match self {
+            #[cfg(feature = "mls_gateway_sql")]
+            Backend::Sql(_) => "sql",
+            #[cfg(feature = "mls_gateway_firestore")]
+            Backend::Firestore(_) => "firestore",
+        }
+    }
+}
+
+/// Records the duration of a storage operation in `mls_gateway_db_operation_duration` when dropped
+pub(crate) struct DbTimer {
+    operation: &'static str,
+    backend: &'static str,
+    start: std::time::Instant,
+}
+
+impl DbTimer {
+    pub(crate) fn new(operation: &'static str, backend: &'static str) -> Self {
+        Self {
+            operation,
+            backend,
+            start: std::time::Instant::now(),
+        }
+    }
+}
+
+impl Drop for DbTimer {
+    fn drop(&mut self) {
+        histogram!(
+            "mls_gateway_db_operation_duration",
+            "operation" => self.operation,
+            "backend" => self.backend
+        )
+        .record(self.start.elapsed());
+    }
+}
+
+/// Time calls in flight get to finish on a replaced storage backend
+const DRAIN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// Shared handle to the MLS storage, every clone sees a backend swapped on setting reload
+#[derive(Debug, Clone)]
+pub struct StorageBackend(Arc<parking_lot::RwLock<Backend>>);
+
 impl StorageBackend {
+    pub fn new(backend: Backend) -> Self {
+        Self(Arc::new(parking_lot::RwLock::new(backend)))
+    }
+
+    /// Current backend, calls in flight keep the backend they started with
+    pub fn current(&self) -> Backend {
+        self.0.read().clone()
+    }
+
+    /// Label of the current backend, `sql` or `firestore`
+    pub fn backend_name(&self) -> &'static str {
+        self.0.read().name()
+    }
+
+    /// Current backend and a timer for an operation on it
+    fn timed(&self, operation: &'static str) -> (Backend, DbTimer) {
+        let backend = self.current();
+        let timer = DbTimer::new(operation, backend.name());
+        (backend, timer)
+    }
+
+    /// Swap the backend of every clone of this handle, returns the previous backend
+    fn replace(&self, other: &StorageBackend) -> Backend {
+        std::mem::replace(&mut *self.0.write(), other.current())
+    }
+
+    /// Connect to changed storage settings and swap the backend, the previous one is
+    /// released once the calls in flight on it finish or after [`DRAIN_TIMEOUT`]
+    async fn reconnect(&self, config: MlsGatewayConfig) {
+        info!(
+            "MLS Gateway storage settings changed, connecting to {:?}",
+            config.storage_backend
+        );
+        let next = match MlsGateway::connect(&config).await {
+            Ok(next) => next,
+            Err(e) => {
+                error!(
+                    "Failed to switch MLS Gateway storage, keeping the current backend: {}",
+                    e
+                );
+                counter!("mls_gateway_storage_reloads", "result" => "error").increment(1);
+                return;
+            }
+        };
+        let previous = self.replace(&next);
+        counter!("mls_gateway_storage_reloads", "result" => "ok").increment(1);
+
+        // Caches loaded from the previous backend
+        if let Err(e) = moderation::reload(self).await {
+            warn!("Failed to reload moderation rules: {}", e);
+        }
+        if let Err(e) = mailbox::load_delivered(self).await {
+            warn!("Failed to reload acked welcomes: {}", e);
+        }
+
+        let deadline = std::time::Instant::now() + DRAIN_TIMEOUT;
+        while previous.in_flight() > 0 && std::time::Instant::now() < deadline {
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+        info!(
+            "Switched MLS Gateway storage to {:?}, previous backend released with {} calls in flight",
+            config.storage_backend,
+            previous.in_flight()
+        );
+    }
+
     async fn migrate(&self) -> anyhow::Result<()> {
-        match self {
+        let (backend, _timer) = self.timed("migrate");
+        match backend {
             #[cfg(feature = "mls_gateway_sql")]
-            StorageBackend::Sql(storage) => storage.migrate().await,
+            Backend::Sql(storage) => storage.migrate().await,
             #[cfg(feature = "mls_gateway_firestore")]
-            StorageBackend::Firestore(storage) => storage.migrate().await,
+            Backend::Firestore(storage) => storage.migrate().await,
         }
     }
 
@@ -327,116 +1021,98 @@ impl StorageBackend {
         creator_pubkey: &str,
         epoch: u64,
     ) -> anyhow::Result<()> {
-        match self {
+        let (backend, _timer) = self.timed("upsert_group");
+        match backend {
             #[cfg(feature = "mls_gateway_sql")]
-            StorageBackend::Sql(storage) => storage.upsert_group(group_id, display_name, creator_pubkey, Some(epoch as i64)).await,
+            Backend::Sql(storage) => {
+                storage
+                    .upsert_group(group_id, display_name, creator_pubkey, Some(epoch as i64))
+                    .await
+            }
             #[cfg(feature = "mls_gateway_firestore")]
-            StorageBackend::Firestore(storage) => storage.upsert_group(group_id, display_name, creator_pubkey, epoch as i64).await,
+            Backend::Firestore(storage) => {
+                storage
+                    .upsert_group(group_id, display_name, creator_pubkey, epoch as i64)
+                    .await
+            }
         }
     }
 
     async fn health_check(&self) -> anyhow::Result<()> {
-        match self {
+        let (backend, _timer) = self.timed("health_check");
+        match backend {
             #[cfg(feature = "mls_gateway_sql")]
-            StorageBackend::Sql(storage) => storage.health_check().await,
+            Backend::Sql(storage) => storage.health_check().await,
             #[cfg(feature = "mls_gateway_firestore")]
-            StorageBackend::Firestore(storage) => storage.health_check().await,
+            Backend::Firestore(storage) => storage.health_check().await,
         }
     }
 
     /// Group-level metadata and authorization helpers
     async fn group_exists(&self, group_id: &str) -> anyhow::Result<bool> {
-        match self {
+        let (backend, _timer) = self.timed("group_exists");
+        match backend {
             #[cfg(feature = "mls_gateway_sql")]
-            StorageBackend::Sql(storage) => storage.group_exists(group_id).await,
+            Backend::Sql(storage) => storage.group_exists(group_id).await,
             #[cfg(feature = "mls_gateway_firestore")]
-            StorageBackend::Firestore(storage) => storage.group_exists(group_id).await,
+            Backend::Firestore(storage) => storage.group_exists(group_id).await,
         }
     }
 
     async fn is_owner(&self, group_id: &str, pubkey: &str) -> anyhow::Result<bool> {
-        match self {
+        let (backend, _timer) = self.timed("is_owner");
+        match backend {
             #[cfg(feature = "mls_gateway_sql")]
-            StorageBackend::Sql(storage) => storage.is_owner(group_id, pubkey).await,
+            Backend::Sql(storage) => storage.is_owner(group_id, pubkey).await,
             #[cfg(feature = "mls_gateway_firestore")]
-            StorageBackend::Firestore(storage) => storage.is_owner(group_id, pubkey).await,
+            Backend::Firestore(storage) => storage.is_owner(group_id, pubkey).await,
         }
     }
 
     async fn is_admin(&self, group_id: &str, pubkey: &str) -> anyhow::Result<bool> {
-        match self {
-            #[cfg(feature = "mls_gateway_sql")]
-            StorageBackend::Sql(storage) => storage.is_admin(group_id, pubkey).await,
-            #[cfg(feature = "mls_gateway_firestore")]
-            StorageBackend::Firestore(storage) => storage.is_admin(group_id, pubkey).await,
-        }
-    }
-
-    async fn add_admins(&self, group_id: &str, admins: &[String]) -> anyhow::Result<()> {
-        match self {
-            #[cfg(feature = "mls_gateway_sql")]
-            StorageBackend::Sql(storage) => storage.add_admins(group_id, admins).await,
-            #[cfg(feature = "mls_gateway_firestore")]
-            StorageBackend::Firestore(storage) => storage.add_admins(group_id, admins).await,
-        }
-    }
-
-    async fn remove_admins(&self, group_id: &str, admins: &[String]) -> anyhow::Result<()> {
-        match self {
+        let (backend, _timer) = self.timed("is_admin");
+        match backend {
             #[cfg(feature = "mls_gateway_sql")]
-            StorageBackend::Sql(storage) => storage.remove_admins(group_id, admins).await,
+            Backend::Sql(storage) => storage.is_admin(group_id, pubkey).await,
             #[cfg(feature = "mls_gateway_firestore")]
-            StorageBackend::Firestore(storage) => storage.remove_admins(group_id, admins).await,
+            Backend::Firestore(storage) => storage.is_admin(group_id, pubkey).await,
         }
     }
 
     /// Get the last roster/policy sequence number for a group
     async fn get_last_roster_sequence(&self, group_id: &str) -> anyhow::Result<Option<u64>> {
-        match self {
+        let (backend, _timer) = self.timed("get_last_roster_sequence");
+        match backend {
             #[cfg(feature = "mls_gateway_sql")]
-            StorageBackend::Sql(storage) => storage.get_last_roster_sequence(group_id).await,
+            Backend::Sql(storage) => storage.get_last_roster_sequence(group_id).await,
             #[cfg(feature = "mls_gateway_firestore")]
-            StorageBackend::Firestore(storage) => storage.get_last_roster_sequence(group_id).await,
+            Backend::Firestore(storage) => storage.get_last_roster_sequence(group_id).await,
         }
     }
 
-    /// Store a roster/policy event with sequence validation
-    async fn store_roster_policy(
+    async fn upsert_keypackage_relays(
         &self,
-        group_id: &str,
-        sequence: u64,
-        operation: &str,
-        member_pubkeys: &[String],
-        admin_pubkey: &str,
-        created_at: i64,
+        owner_pubkey: &str,
+        relays: &[String],
     ) -> anyhow::Result<()> {
-        match self {
+        let (backend, _timer) = self.timed("upsert_keypackage_relays");
+        match backend {
             #[cfg(feature = "mls_gateway_sql")]
-            StorageBackend::Sql(storage) => {
-                storage.store_roster_policy(group_id, sequence, operation, member_pubkeys, admin_pubkey, created_at).await
-            }
+            Backend::Sql(storage) => storage.upsert_keypackage_relays(owner_pubkey, relays).await,
             #[cfg(feature = "mls_gateway_firestore")]
-            StorageBackend::Firestore(storage) => {
-                storage.store_roster_policy(group_id, sequence, operation, member_pubkeys, admin_pubkey, created_at).await
+            Backend::Firestore(storage) => {
+                storage.upsert_keypackage_relays(owner_pubkey, relays).await
             }
         }
     }
 
-    async fn upsert_keypackage_relays(&self, owner_pubkey: &str, relays: &[String]) -> anyhow::Result<()> {
-        match self {
-            #[cfg(feature = "mls_gateway_sql")]
-            StorageBackend::Sql(storage) => storage.upsert_keypackage_relays(owner_pubkey, relays).await,
-            #[cfg(feature = "mls_gateway_firestore")]
-            StorageBackend::Firestore(storage) => storage.upsert_keypackage_relays(owner_pubkey, relays).await,
-        }
-    }
-
     async fn get_keypackage_relays(&self, owner_pubkey: &str) -> anyhow::Result<Vec<String>> {
-        match self {
+        let (backend, _timer) = self.timed("get_keypackage_relays");
+        match backend {
             #[cfg(feature = "mls_gateway_sql")]
-            StorageBackend::Sql(storage) => storage.get_keypackage_relays(owner_pubkey).await,
+            Backend::Sql(storage) => storage.get_keypackage_relays(owner_pubkey).await,
             #[cfg(feature = "mls_gateway_firestore")]
-            StorageBackend::Firestore(storage) => storage.get_keypackage_relays(owner_pubkey).await,
+            Backend::Firestore(storage) => storage.get_keypackage_relays(owner_pubkey).await,
         }
     }
 
@@ -452,15 +1128,40 @@ impl StorageBackend {
         created_at: i64,
         expires_at: i64,
     ) -> anyhow::Result<()> {
-        match self {
+        let (backend, _timer) = self.timed("store_keypackage");
+        match backend {
             #[cfg(feature = "mls_gateway_sql")]
-            StorageBackend::Sql(storage) => storage.store_keypackage(
-                event_id, owner_pubkey, content, ciphersuite, extensions, relays, has_last_resort, created_at, expires_at
-            ).await,
+            Backend::Sql(storage) => {
+                storage
+                    .store_keypackage(
+                        event_id,
+                        owner_pubkey,
+                        content,
+                        ciphersuite,
+                        extensions,
+                        relays,
+                        has_last_resort,
+                        created_at,
+                        expires_at,
+                    )
+                    .await
+            }
             #[cfg(feature = "mls_gateway_firestore")]
-            StorageBackend::Firestore(storage) => storage.store_keypackage(
-                event_id, owner_pubkey, content, ciphersuite, extensions, relays, has_last_resort, created_at, expires_at
-            ).await,
+            Backend::Firestore(storage) => {
+                storage
+                    .store_keypackage(
+                        event_id,
+                        owner_pubkey,
+                        content,
+                        ciphersuite,
+                        extensions,
+                        relays,
+                        has_last_resort,
+                        created_at,
+                        expires_at,
+                    )
+                    .await
+            }
         }
     }
 
@@ -471,112 +1172,850 @@ impl StorageBackend {
         limit: Option<u32>,
         order_by: Option<&str>,
     ) -> anyhow::Result<Vec<(String, String, String, i64)>> {
-        match self {
+        let (backend, _timer) = self.timed("query_keypackages");
+        match backend {
             #[cfg(feature = "mls_gateway_sql")]
-            StorageBackend::Sql(storage) => storage.query_keypackages(authors, since, limit, order_by).await,
+            Backend::Sql(storage) => {
+                storage
+                    .query_keypackages(authors, since, limit, order_by)
+                    .await
+            }
             #[cfg(feature = "mls_gateway_firestore")]
-            StorageBackend::Firestore(storage) => storage.query_keypackages(authors, since, limit, order_by).await,
+            Backend::Firestore(storage) => {
+                storage
+                    .query_keypackages(authors, since, limit, order_by)
+                    .await
+            }
         }
     }
 
     async fn delete_consumed_keypackage(&self, event_id: &str) -> anyhow::Result<bool> {
-        match self {
+        let (backend, _timer) = self.timed("delete_consumed_keypackage");
+        match backend {
             #[cfg(feature = "mls_gateway_sql")]
-            StorageBackend::Sql(storage) => storage.delete_consumed_keypackage(event_id).await,
+            Backend::Sql(storage) => storage.delete_consumed_keypackage(event_id).await,
             #[cfg(feature = "mls_gateway_firestore")]
-            StorageBackend::Firestore(storage) => storage.delete_consumed_keypackage(event_id).await,
+            Backend::Firestore(storage) => storage.delete_consumed_keypackage(event_id).await,
         }
     }
 
     async fn count_user_keypackages(&self, owner_pubkey: &str) -> anyhow::Result<u32> {
-        match self {
+        let (backend, _timer) = self.timed("count_user_keypackages");
+        match backend {
             #[cfg(feature = "mls_gateway_sql")]
-            StorageBackend::Sql(storage) => storage.count_user_keypackages(owner_pubkey).await,
+            Backend::Sql(storage) => storage.count_user_keypackages(owner_pubkey).await,
             #[cfg(feature = "mls_gateway_firestore")]
-            StorageBackend::Firestore(storage) => storage.count_user_keypackages(owner_pubkey).await,
+            Backend::Firestore(storage) => storage.count_user_keypackages(owner_pubkey).await,
         }
     }
 
-    async fn cleanup_expired_keypackages(&self, max_per_user: u32) -> anyhow::Result<u32> {
-        match self {
+    async fn find_keypackage_by_hash(
+        &self,
+        owner_pubkey: &str,
+        content_hash: &str,
+    ) -> anyhow::Result<Option<String>> {
+        let (backend, _timer) = self.timed("find_keypackage_by_hash");
+        match backend {
             #[cfg(feature = "mls_gateway_sql")]
-            StorageBackend::Sql(storage) => storage.cleanup_expired_keypackages(max_per_user).await,
+            // SQL keeps no content index, duplicates are only caught by the in-memory check at ingress
+            Backend::Sql(_storage) => Ok(None),
             #[cfg(feature = "mls_gateway_firestore")]
-            StorageBackend::Firestore(storage) => storage.cleanup_expired_keypackages(max_per_user).await,
+            Backend::Firestore(storage) => {
+                storage
+                    .find_keypackage_by_hash(owner_pubkey, content_hash)
+                    .await
+            }
         }
     }
 
-    // New methods for pending deletion management
-    
-    async fn create_pending_deletion(&self, pending: &firestore::PendingDeletion) -> anyhow::Result<()> {
-        match self {
+    async fn get_keypackage_request_rate_limit(
+        &self,
+        requester_pubkey: &str,
+        recipient_pubkey: &str,
+    ) -> anyhow::Result<Option<firestore::KeyPackageRequestRateLimit>> {
+        let (backend, _timer) = self.timed("get_keypackage_request_rate_limit");
+        match backend {
             #[cfg(feature = "mls_gateway_sql")]
-            StorageBackend::Sql(_storage) => Err(anyhow::anyhow!("Pending deletion not implemented for SQL backend")),
+            Backend::Sql(storage) => {
+                storage
+                    .get_keypackage_request_rate_limit(requester_pubkey, recipient_pubkey)
+                    .await
+            }
             #[cfg(feature = "mls_gateway_firestore")]
-            StorageBackend::Firestore(storage) => storage.create_pending_deletion(pending).await,
+            Backend::Firestore(storage) => {
+                storage
+                    .get_keypackage_request_rate_limit(requester_pubkey, recipient_pubkey)
+                    .await
+            }
         }
     }
-    
-    async fn get_pending_deletion(&self, user_pubkey: &str) -> anyhow::Result<Option<firestore::PendingDeletion>> {
-        match self {
+
+    async fn put_keypackage_request_rate_limit(
+        &self,
+        limit: &firestore::KeyPackageRequestRateLimit,
+    ) -> anyhow::Result<()> {
+        let (backend, _timer) = self.timed("put_keypackage_request_rate_limit");
+        match backend {
             #[cfg(feature = "mls_gateway_sql")]
-            StorageBackend::Sql(_storage) => Ok(None),
+            Backend::Sql(storage) => storage.put_keypackage_request_rate_limit(limit).await,
             #[cfg(feature = "mls_gateway_firestore")]
-            StorageBackend::Firestore(storage) => storage.get_pending_deletion(user_pubkey).await,
+            Backend::Firestore(storage) => storage.put_keypackage_request_rate_limit(limit).await,
         }
     }
-    
-    async fn update_pending_deletion(&self, pending: &firestore::PendingDeletion) -> anyhow::Result<()> {
-        match self {
+
+    async fn cleanup_expired_keypackages(&self, max_per_user: u32) -> anyhow::Result<u32> {
+        let (backend, _timer) = self.timed("cleanup_expired_keypackages");
+        match backend {
             #[cfg(feature = "mls_gateway_sql")]
-            StorageBackend::Sql(_storage) => Err(anyhow::anyhow!("Pending deletion not implemented for SQL backend")),
+            Backend::Sql(storage) => storage.cleanup_expired_keypackages(max_per_user).await,
             #[cfg(feature = "mls_gateway_firestore")]
-            StorageBackend::Firestore(storage) => storage.update_pending_deletion(pending).await,
+            Backend::Firestore(storage) => storage.cleanup_expired_keypackages(max_per_user).await,
         }
     }
-    
-    async fn delete_pending_deletion(&self, user_pubkey: &str) -> anyhow::Result<()> {
-        match self {
+
+    // New methods for pending deletion management
+
+    async fn create_pending_deletion(
+        &self,
+        pending: &firestore::PendingDeletion,
+    ) -> anyhow::Result<()> {
+        let (backend, _timer) = self.timed("create_pending_deletion");
+        match backend {
             #[cfg(feature = "mls_gateway_sql")]
-            StorageBackend::Sql(_storage) => Ok(()),
+            Backend::Sql(_storage) => Err(anyhow::anyhow!(
+                "Pending deletion not implemented for SQL backend"
+            )),
             #[cfg(feature = "mls_gateway_firestore")]
-            StorageBackend::Firestore(storage) => storage.delete_pending_deletion(user_pubkey).await,
+            Backend::Firestore(storage) => storage.create_pending_deletion(pending).await,
         }
     }
-    
-    async fn delete_keypackage_by_id(&self, event_id: &str) -> anyhow::Result<()> {
-        match self {
+
+    async fn get_pending_deletion(
+        &self,
+        user_pubkey: &str,
+    ) -> anyhow::Result<Option<firestore::PendingDeletion>> {
+        let (backend, _timer) = self.timed("get_pending_deletion");
+        match backend {
+            #[cfg(feature = "mls_gateway_sql")]
+            Backend::Sql(_storage) => Ok(None),
+            #[cfg(feature = "mls_gateway_firestore")]
+            Backend::Firestore(storage) => storage.get_pending_deletion(user_pubkey).await,
+        }
+    }
+
+    async fn update_pending_deletion(
+        &self,
+        pending: &firestore::PendingDeletion,
+    ) -> anyhow::Result<()> {
+        let (backend, _timer) = self.timed("update_pending_deletion");
+        match backend {
+            #[cfg(feature = "mls_gateway_sql")]
+            Backend::Sql(_storage) => Err(anyhow::anyhow!(
+                "Pending deletion not implemented for SQL backend"
+            )),
+            #[cfg(feature = "mls_gateway_firestore")]
+            Backend::Firestore(storage) => storage.update_pending_deletion(pending).await,
+        }
+    }
+
+    async fn delete_pending_deletion(&self, user_pubkey: &str) -> anyhow::Result<()> {
+        let (backend, _timer) = self.timed("delete_pending_deletion");
+        match backend {
+            #[cfg(feature = "mls_gateway_sql")]
+            Backend::Sql(_storage) => Ok(()),
+            #[cfg(feature = "mls_gateway_firestore")]
+            Backend::Firestore(storage) => storage.delete_pending_deletion(user_pubkey).await,
+        }
+    }
+
+    async fn store_mailbox_welcome(&self, entry: &firestore::MailboxWelcome) -> anyhow::Result<()> {
+        let (backend, _timer) = self.timed("store_mailbox_welcome");
+        match backend {
+            #[cfg(feature = "mls_gateway_sql")]
+            Backend::Sql(storage) => storage.store_mailbox_welcome(entry).await,
+            #[cfg(feature = "mls_gateway_firestore")]
+            Backend::Firestore(storage) => storage.store_mailbox_welcome(entry).await,
+        }
+    }
+
+    async fn ack_mailbox_welcomes(
+        &self,
+        recipient: &str,
+        event_ids: &[String],
+    ) -> anyhow::Result<Vec<firestore::MailboxWelcome>> {
+        let (backend, _timer) = self.timed("ack_mailbox_welcomes");
+        match backend {
+            #[cfg(feature = "mls_gateway_sql")]
+            Backend::Sql(storage) => storage.ack_mailbox_welcomes(recipient, event_ids).await,
+            #[cfg(feature = "mls_gateway_firestore")]
+            Backend::Firestore(storage) => storage.ack_mailbox_welcomes(recipient, event_ids).await,
+        }
+    }
+
+    async fn list_welcome_tombstones(
+        &self,
+        since: chrono::DateTime<chrono::Utc>,
+    ) -> anyhow::Result<Vec<(String, i64)>> {
+        let (backend, _timer) = self.timed("list_welcome_tombstones");
+        match backend {
+            #[cfg(feature = "mls_gateway_sql")]
+            Backend::Sql(storage) => storage.list_welcome_tombstones(since).await,
+            #[cfg(feature = "mls_gateway_firestore")]
+            Backend::Firestore(storage) => storage.list_welcome_tombstones(since).await,
+        }
+    }
+
+    async fn cleanup_expired_welcomes(
+        &self,
+        before: chrono::DateTime<chrono::Utc>,
+    ) -> anyhow::Result<u32> {
+        let (backend, _timer) = self.timed("cleanup_expired_welcomes");
+        match backend {
+            #[cfg(feature = "mls_gateway_sql")]
+            Backend::Sql(storage) => storage.cleanup_expired_welcomes(before).await,
+            #[cfg(feature = "mls_gateway_firestore")]
+            Backend::Firestore(storage) => storage.cleanup_expired_welcomes(before).await,
+        }
+    }
+
+    async fn claim_service_action(
+        &self,
+        action_id: &str,
+        client_id: &str,
+        expires_at: chrono::DateTime<chrono::Utc>,
+    ) -> anyhow::Result<bool> {
+        let (backend, _timer) = self.timed("claim_service_action");
+        match backend {
+            #[cfg(feature = "mls_gateway_sql")]
+            Backend::Sql(storage) => {
+                storage
+                    .claim_service_action(action_id, client_id, expires_at)
+                    .await
+            }
+            #[cfg(feature = "mls_gateway_firestore")]
+            Backend::Firestore(storage) => {
+                storage
+                    .claim_service_action(action_id, client_id, expires_at)
+                    .await
+            }
+        }
+    }
+
+    async fn cleanup_expired_service_actions(
+        &self,
+        before: chrono::DateTime<chrono::Utc>,
+    ) -> anyhow::Result<u32> {
+        let (backend, _timer) = self.timed("cleanup_expired_service_actions");
+        match backend {
+            #[cfg(feature = "mls_gateway_sql")]
+            Backend::Sql(storage) => storage.cleanup_expired_service_actions(before).await,
+            #[cfg(feature = "mls_gateway_firestore")]
+            Backend::Firestore(storage) => storage.cleanup_expired_service_actions(before).await,
+        }
+    }
+
+    async fn list_moderation_rules(&self) -> anyhow::Result<Vec<firestore::ModerationRule>> {
+        let (backend, _timer) = self.timed("list_moderation_rules");
+        match backend {
+            #[cfg(feature = "mls_gateway_sql")]
+            Backend::Sql(storage) => storage.list_moderation_rules().await,
+            #[cfg(feature = "mls_gateway_firestore")]
+            Backend::Firestore(storage) => storage.list_moderation_rules().await,
+        }
+    }
+
+    async fn put_moderation_rule(&self, rule: &firestore::ModerationRule) -> anyhow::Result<()> {
+        let (backend, _timer) = self.timed("put_moderation_rule");
+        match backend {
+            #[cfg(feature = "mls_gateway_sql")]
+            Backend::Sql(storage) => storage.put_moderation_rule(rule).await,
+            #[cfg(feature = "mls_gateway_firestore")]
+            Backend::Firestore(storage) => storage.put_moderation_rule(rule).await,
+        }
+    }
+
+    async fn delete_moderation_rule(
+        &self,
+        list: &str,
+        target: &str,
+        value: &str,
+    ) -> anyhow::Result<bool> {
+        let (backend, _timer) = self.timed("delete_moderation_rule");
+        match backend {
+            #[cfg(feature = "mls_gateway_sql")]
+            Backend::Sql(storage) => storage.delete_moderation_rule(list, target, value).await,
+            #[cfg(feature = "mls_gateway_firestore")]
+            Backend::Firestore(storage) => {
+                storage.delete_moderation_rule(list, target, value).await
+            }
+        }
+    }
+
+    async fn record_group_activity(
+        &self,
+        group_id: &str,
+        sender: &str,
+        epoch: Option<i64>,
+    ) -> anyhow::Result<firestore::GroupStats> {
+        let (backend, _timer) = self.timed("record_group_activity");
+        match backend {
+            #[cfg(feature = "mls_gateway_sql")]
+            Backend::Sql(storage) => storage.record_group_activity(group_id, sender, epoch).await,
+            #[cfg(feature = "mls_gateway_firestore")]
+            Backend::Firestore(storage) => {
+                storage.record_group_activity(group_id, sender, epoch).await
+            }
+        }
+    }
+
+    async fn get_group_stats(
+        &self,
+        group_id: &str,
+    ) -> anyhow::Result<Option<firestore::GroupStats>> {
+        let (backend, _timer) = self.timed("get_group_stats");
+        match backend {
+            #[cfg(feature = "mls_gateway_sql")]
+            Backend::Sql(storage) => storage.get_group_stats(group_id).await,
+            #[cfg(feature = "mls_gateway_firestore")]
+            Backend::Firestore(storage) => storage.get_group_stats(group_id).await,
+        }
+    }
+
+    async fn list_group_stats(&self) -> anyhow::Result<Vec<firestore::GroupStats>> {
+        let (backend, _timer) = self.timed("list_group_stats");
+        match backend {
+            #[cfg(feature = "mls_gateway_sql")]
+            Backend::Sql(storage) => storage.list_group_stats().await,
+            #[cfg(feature = "mls_gateway_firestore")]
+            Backend::Firestore(storage) => storage.list_group_stats().await,
+        }
+    }
+
+    async fn get_roster_policy(
+        &self,
+        group_id: &str,
+        sequence: u64,
+    ) -> anyhow::Result<Option<firestore::RosterPolicyDocument>> {
+        let (backend, _timer) = self.timed("get_roster_policy");
+        match backend {
             #[cfg(feature = "mls_gateway_sql")]
-            StorageBackend::Sql(_storage) => Err(anyhow::anyhow!("Direct deletion not implemented for SQL backend")),
+            Backend::Sql(storage) => storage.get_roster_policy(group_id, sequence).await,
             #[cfg(feature = "mls_gateway_firestore")]
-            StorageBackend::Firestore(storage) => storage.delete_keypackage_by_id(event_id).await,
+            Backend::Firestore(storage) => storage.get_roster_policy(group_id, sequence).await,
         }
     }
-    
+
+    async fn store_roster_anomaly(&self, anomaly: &firestore::RosterAnomaly) -> anyhow::Result<()> {
+        let (backend, _timer) = self.timed("store_roster_anomaly");
+        match backend {
+            #[cfg(feature = "mls_gateway_sql")]
+            Backend::Sql(storage) => storage.store_roster_anomaly(anomaly).await,
+            #[cfg(feature = "mls_gateway_firestore")]
+            Backend::Firestore(storage) => storage.store_roster_anomaly(anomaly).await,
+        }
+    }
+
+    async fn list_roster_anomalies(
+        &self,
+        group_id: Option<&str>,
+    ) -> anyhow::Result<Vec<firestore::RosterAnomaly>> {
+        let (backend, _timer) = self.timed("list_roster_anomalies");
+        match backend {
+            #[cfg(feature = "mls_gateway_sql")]
+            Backend::Sql(storage) => storage.list_roster_anomalies(group_id).await,
+            #[cfg(feature = "mls_gateway_firestore")]
+            Backend::Firestore(storage) => storage.list_roster_anomalies(group_id).await,
+        }
+    }
+
+    async fn apply_roster_operation(&self, op: &firestore::RosterOperation) -> anyhow::Result<()> {
+        let (backend, _timer) = self.timed("apply_roster_operation");
+        match backend {
+            #[cfg(feature = "mls_gateway_sql")]
+            Backend::Sql(storage) => storage.apply_roster_operation(op).await,
+            #[cfg(feature = "mls_gateway_firestore")]
+            Backend::Firestore(storage) => storage.apply_roster_operation(op).await,
+        }
+    }
+
+    async fn create_pending_roster_op(
+        &self,
+        op: &firestore::PendingRosterOp,
+    ) -> anyhow::Result<()> {
+        let (backend, _timer) = self.timed("create_pending_roster_op");
+        match backend {
+            #[cfg(feature = "mls_gateway_sql")]
+            Backend::Sql(storage) => storage.create_pending_roster_op(op).await,
+            #[cfg(feature = "mls_gateway_firestore")]
+            Backend::Firestore(storage) => storage.create_pending_roster_op(op).await,
+        }
+    }
+
+    async fn approve_pending_roster_op(
+        &self,
+        event_id: &str,
+        approver: &str,
+        now: i64,
+    ) -> anyhow::Result<Option<(roster_quorum::Approval, firestore::PendingRosterOp)>> {
+        let (backend, _timer) = self.timed("approve_pending_roster_op");
+        match backend {
+            #[cfg(feature = "mls_gateway_sql")]
+            Backend::Sql(storage) => {
+                storage
+                    .approve_pending_roster_op(event_id, approver, now)
+                    .await
+            }
+            #[cfg(feature = "mls_gateway_firestore")]
+            Backend::Firestore(storage) => {
+                storage
+                    .approve_pending_roster_op(event_id, approver, now)
+                    .await
+            }
+        }
+    }
+
+    async fn set_pending_roster_op_status(
+        &self,
+        event_id: &str,
+        status: &str,
+    ) -> anyhow::Result<()> {
+        let (backend, _timer) = self.timed("set_pending_roster_op_status");
+        match backend {
+            #[cfg(feature = "mls_gateway_sql")]
+            Backend::Sql(storage) => storage.set_pending_roster_op_status(event_id, status).await,
+            #[cfg(feature = "mls_gateway_firestore")]
+            Backend::Firestore(storage) => {
+                storage.set_pending_roster_op_status(event_id, status).await
+            }
+        }
+    }
+
+    async fn get_pending_roster_op(
+        &self,
+        event_id: &str,
+    ) -> anyhow::Result<Option<firestore::PendingRosterOp>> {
+        let (backend, _timer) = self.timed("get_pending_roster_op");
+        match backend {
+            #[cfg(feature = "mls_gateway_sql")]
+            Backend::Sql(storage) => storage.get_pending_roster_op(event_id).await,
+            #[cfg(feature = "mls_gateway_firestore")]
+            Backend::Firestore(storage) => storage.get_pending_roster_op(event_id).await,
+        }
+    }
+
+    async fn list_pending_roster_ops(
+        &self,
+        group_id: Option<&str>,
+    ) -> anyhow::Result<Vec<firestore::PendingRosterOp>> {
+        let (backend, _timer) = self.timed("list_pending_roster_ops");
+        match backend {
+            #[cfg(feature = "mls_gateway_sql")]
+            Backend::Sql(storage) => storage.list_pending_roster_ops(group_id).await,
+            #[cfg(feature = "mls_gateway_firestore")]
+            Backend::Firestore(storage) => storage.list_pending_roster_ops(group_id).await,
+        }
+    }
+
+    async fn set_service_member(&self, group_id: &str, inviter_pubkey: &str) -> anyhow::Result<()> {
+        let (backend, _timer) = self.timed("set_service_member");
+        match backend {
+            #[cfg(feature = "mls_gateway_sql")]
+            Backend::Sql(storage) => storage.set_service_member(group_id, inviter_pubkey).await,
+            #[cfg(feature = "mls_gateway_firestore")]
+            Backend::Firestore(storage) => {
+                storage.set_service_member(group_id, inviter_pubkey).await
+            }
+        }
+    }
+
+    async fn delete_keypackage_by_id(&self, event_id: &str) -> anyhow::Result<()> {
+        let (backend, _timer) = self.timed("delete_keypackage_by_id");
+        match backend {
+            #[cfg(feature = "mls_gateway_sql")]
+            Backend::Sql(_storage) => Err(anyhow::anyhow!(
+                "Direct deletion not implemented for SQL backend"
+            )),
+            #[cfg(feature = "mls_gateway_firestore")]
+            Backend::Firestore(storage) => storage.delete_keypackage_by_id(event_id).await,
+        }
+    }
+
     async fn keypackage_exists(&self, event_id: &str) -> anyhow::Result<bool> {
-        match self {
+        let (backend, _timer) = self.timed("keypackage_exists");
+        match backend {
             #[cfg(feature = "mls_gateway_sql")]
-            StorageBackend::Sql(_storage) => Ok(false),
+            Backend::Sql(_storage) => Ok(false),
             #[cfg(feature = "mls_gateway_firestore")]
-            StorageBackend::Firestore(storage) => storage.keypackage_exists(event_id).await,
+            Backend::Firestore(storage) => storage.keypackage_exists(event_id).await,
         }
     }
-    
-    async fn get_expired_pending_deletions(&self) -> anyhow::Result<Vec<firestore::PendingDeletion>> {
-        match self {
+
+    async fn get_keypackage_owner(&self, event_id: &str) -> anyhow::Result<Option<String>> {
+        let (backend, _timer) = self.timed("get_keypackage_owner");
+        match backend {
+            #[cfg(feature = "mls_gateway_sql")]
+            Backend::Sql(storage) => storage.get_keypackage_owner(event_id).await,
+            #[cfg(feature = "mls_gateway_firestore")]
+            Backend::Firestore(storage) => storage.get_keypackage_owner(event_id).await,
+        }
+    }
+
+    async fn get_expired_pending_deletions(
+        &self,
+    ) -> anyhow::Result<Vec<firestore::PendingDeletion>> {
+        let (backend, _timer) = self.timed("get_expired_pending_deletions");
+        match backend {
+            #[cfg(feature = "mls_gateway_sql")]
+            Backend::Sql(_storage) => Ok(Vec::new()),
+            #[cfg(feature = "mls_gateway_firestore")]
+            Backend::Firestore(storage) => storage.get_expired_pending_deletions().await,
+        }
+    }
+
+    async fn get_group(&self, group_id: &str) -> anyhow::Result<Option<firestore::GroupInfo>> {
+        let (backend, _timer) = self.timed("get_group");
+        match backend {
+            #[cfg(feature = "mls_gateway_sql")]
+            Backend::Sql(storage) => storage.get_group(group_id).await,
+            #[cfg(feature = "mls_gateway_firestore")]
+            Backend::Firestore(storage) => MlsStorage::get_group(storage.as_ref(), group_id).await,
+        }
+    }
+
+    async fn list_roster_history(
+        &self,
+        group_id: &str,
+    ) -> anyhow::Result<Vec<firestore::RosterPolicyDocument>> {
+        let (backend, _timer) = self.timed("list_roster_history");
+        match backend {
+            #[cfg(feature = "mls_gateway_sql")]
+            Backend::Sql(storage) => storage.list_roster_history(group_id).await,
+            #[cfg(feature = "mls_gateway_firestore")]
+            Backend::Firestore(storage) => {
+                MlsStorage::list_roster_history(storage.as_ref(), group_id).await
+            }
+        }
+    }
+
+    /// Everything stored for a pubkey, for a data export
+    async fn user_data(&self, pubkey: &str) -> anyhow::Result<firestore::UserData> {
+        let (backend, _timer) = self.timed("user_data");
+        match backend {
+            #[cfg(feature = "mls_gateway_sql")]
+            Backend::Sql(_storage) => Err(anyhow::anyhow!(
+                "User data export not implemented for SQL backend"
+            )),
+            #[cfg(feature = "mls_gateway_firestore")]
+            Backend::Firestore(storage) => storage.user_data(pubkey).await,
+        }
+    }
+
+    /// Erase what is stored for a pubkey
+    async fn erase_user(&self, pubkey: &str) -> anyhow::Result<firestore::UserErasure> {
+        let (backend, _timer) = self.timed("erase_user");
+        match backend {
+            #[cfg(feature = "mls_gateway_sql")]
+            Backend::Sql(_storage) => Err(anyhow::anyhow!(
+                "User erasure not implemented for SQL backend"
+            )),
+            #[cfg(feature = "mls_gateway_firestore")]
+            Backend::Firestore(storage) => storage.erase_user(pubkey).await,
+        }
+    }
+
+    async fn upsert_push_token(
+        &self,
+        pubkey: &str,
+        token: &str,
+        platform: &str,
+    ) -> anyhow::Result<()> {
+        let (backend, _timer) = self.timed("upsert_push_token");
+        match backend {
+            #[cfg(feature = "mls_gateway_sql")]
+            Backend::Sql(storage) => storage.upsert_push_token(pubkey, token, platform).await,
+            #[cfg(feature = "mls_gateway_firestore")]
+            Backend::Firestore(storage) => storage.upsert_push_token(pubkey, token, platform).await,
+        }
+    }
+
+    async fn get_push_tokens(&self, pubkey: &str) -> anyhow::Result<Vec<firestore::PushToken>> {
+        let (backend, _timer) = self.timed("get_push_tokens");
+        match backend {
+            #[cfg(feature = "mls_gateway_sql")]
+            Backend::Sql(storage) => storage.get_push_tokens(pubkey).await,
+            #[cfg(feature = "mls_gateway_firestore")]
+            Backend::Firestore(storage) => storage.get_push_tokens(pubkey).await,
+        }
+    }
+
+    async fn delete_push_token(&self, pubkey: Option<&str>, token: &str) -> anyhow::Result<bool> {
+        let (backend, _timer) = self.timed("delete_push_token");
+        match backend {
+            #[cfg(feature = "mls_gateway_sql")]
+            Backend::Sql(storage) => storage.delete_push_token(pubkey, token).await,
+            #[cfg(feature = "mls_gateway_firestore")]
+            Backend::Firestore(storage) => storage.delete_push_token(pubkey, token).await,
+        }
+    }
+
+    async fn get_subscription_cursor(
+        &self,
+        pubkey: &str,
+        subscription_id: &str,
+    ) -> anyhow::Result<Option<firestore::SubscriptionCursor>> {
+        let (backend, _timer) = self.timed("get_subscription_cursor");
+        match backend {
+            #[cfg(feature = "mls_gateway_sql")]
+            Backend::Sql(storage) => {
+                storage
+                    .get_subscription_cursor(pubkey, subscription_id)
+                    .await
+            }
+            #[cfg(feature = "mls_gateway_firestore")]
+            Backend::Firestore(storage) => {
+                storage
+                    .get_subscription_cursor(pubkey, subscription_id)
+                    .await
+            }
+        }
+    }
+
+    async fn put_subscription_cursors(
+        &self,
+        cursors: &[firestore::SubscriptionCursor],
+    ) -> anyhow::Result<()> {
+        let (backend, _timer) = self.timed("put_subscription_cursors");
+        match backend {
+            #[cfg(feature = "mls_gateway_sql")]
+            Backend::Sql(storage) => storage.put_subscription_cursors(cursors).await,
+            #[cfg(feature = "mls_gateway_firestore")]
+            Backend::Firestore(storage) => storage.put_subscription_cursors(cursors).await,
+        }
+    }
+}
+
+/// Attested pubkeys of the attestation gate
+#[cfg(feature = "attestation")]
+#[async_trait::async_trait]
+impl crate::attestation::AttestationStore for StorageBackend {
+    async fn get_attestation(
+        &self,
+        pubkey: &str,
+    ) -> anyhow::Result<Option<firestore::AttestationRecord>> {
+        let (backend, _timer) = self.timed("get_attestation");
+        match backend {
             #[cfg(feature = "mls_gateway_sql")]
-            StorageBackend::Sql(_storage) => Ok(Vec::new()),
+            Backend::Sql(storage) => storage.get_attestation(pubkey).await,
             #[cfg(feature = "mls_gateway_firestore")]
-            StorageBackend::Firestore(storage) => storage.get_expired_pending_deletions().await,
+            Backend::Firestore(storage) => storage.get_attestation(pubkey).await,
+        }
+    }
+
+    async fn put_attestation(&self, record: &firestore::AttestationRecord) -> anyhow::Result<()> {
+        let (backend, _timer) = self.timed("put_attestation");
+        match backend {
+            #[cfg(feature = "mls_gateway_sql")]
+            Backend::Sql(storage) => storage.put_attestation(record).await,
+            #[cfg(feature = "mls_gateway_firestore")]
+            Backend::Firestore(storage) => storage.put_attestation(record).await,
+        }
+    }
+}
+
+/// Group admins authorize NIP-SERVICE requests
+#[cfg(feature = "nip_service")]
+#[async_trait::async_trait]
+impl crate::nip_service::authz::AdminAuthorizer for StorageBackend {
+    async fn is_admin(&self, group_id: &str, pubkey: &str) -> anyhow::Result<bool> {
+        StorageBackend::is_admin(self, group_id, pubkey).await
+    }
+}
+
+/// Processed NIP-SERVICE action_ids are claimed in storage
+#[cfg(feature = "nip_service")]
+#[async_trait::async_trait]
+impl crate::nip_service::dispatcher::ActionLedger for StorageBackend {
+    async fn claim_action(
+        &self,
+        action_id: &str,
+        client_id: &str,
+        expires_at: i64,
+    ) -> anyhow::Result<bool> {
+        let expires_at = chrono::DateTime::from_timestamp(expires_at, 0)
+            .ok_or_else(|| anyhow::anyhow!("invalid claim expiry {}", expires_at))?;
+        self.claim_service_action(action_id, client_id, expires_at)
+            .await
+    }
+}
+
+/// Check that `pubkey` may apply a roster/policy operation to a group
+async fn authorize_roster_policy(
+    store: &StorageBackend,
+    config: &MlsGatewayConfig,
+    group_id: &str,
+    operation: &str,
+    pubkey: &str,
+) -> Result<(), &'static str> {
+    let group_exists = store.group_exists(group_id).await.unwrap_or(false);
+    if !group_exists {
+        // Only allow bootstrap to create a new group; creator becomes owner and initial admin
+        if operation != "bootstrap" {
+            warn!(
+                "Rejecting non-bootstrap roster event for unknown group {}",
+                group_id
+            );
+            return Err("group does not exist, bootstrap required");
+        }
+    } else {
+        let is_owner = store.is_owner(group_id, pubkey).await.unwrap_or(false);
+        let required = roster_auth::required_role(
+            operation,
+            group_id,
+            &config.roster_operation_roles,
+            &config.roster_group_operation_roles,
+        );
+        match required {
+            _ if is_owner => {}
+            roster_auth::RosterRole::Owner => {
+                warn!(
+                    "Roster operation {} in group {} by non-owner {}",
+                    operation, group_id, pubkey
+                );
+                return Err("only the group owner may perform this roster operation");
+            }
+            roster_auth::RosterRole::Admin => {
+                if !store.is_admin(group_id, pubkey).await.unwrap_or(false) {
+                    warn!(
+                        "Unauthorized roster/policy event for group {} from {}",
+                        group_id, pubkey
+                    );
+                    return Err("only the group owner or admins may change the roster");
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Replay and quota rejection of a giftwrap
+fn giftwrap_rejection(event: &Event, config: &MlsGatewayConfig) -> Option<OutgoingMessage> {
+    match giftwrap_guard::check(event, config) {
+        giftwrap_guard::Verdict::Accept => None,
+        giftwrap_guard::Verdict::Duplicate => {
+            counter!("mls_gateway_giftwraps_rejected", "reason" => "duplicate").increment(1);
+            Some(OutgoingMessage::rejected(
+                &event.id_str(),
+                Prefix::Duplicate,
+                "giftwrap already delivered to recipient",
+            ))
+        }
+        giftwrap_guard::Verdict::QuotaExceeded => {
+            counter!("mls_gateway_giftwraps_rejected", "reason" => "quota").increment(1);
+            Some(OutgoingMessage::rejected(
+                &event.id_str(),
+                Prefix::RateLimited,
+                "daily giftwrap quota reached for recipient",
+            ))
+        }
+    }
+}
+
+/// Archive a direct message and keep each recipient within its archive caps
+async fn archive_direct_message(
+    archive: &MessageArchive,
+    config: &MlsGatewayConfig,
+    event: &Event,
+) {
+    let kind = event.kind();
+    match archive
+        .archive_event(event, Some(config.message_archive_ttl_days))
+        .await
+    {
+        Ok(true) => {
+            for recipient in event.tags().iter().filter(|t| t.len() >= 2 && t[0] == "p") {
+                match archive
+                    .enforce_recipient_cap(
+                        kind as u32,
+                        &recipient[1],
+                        event.content().len() as u64,
+                        config.noise_dm_archive_max_per_recipient,
+                        config.noise_dm_archive_max_bytes_per_recipient,
+                    )
+                    .await
+                {
+                    Ok(evicted) if kinds::canonical(kind) == NOISE_DM_KIND => {
+                        counter!("mls_gateway_446_archive_evicted").increment(evicted)
+                    }
+                    Ok(evicted) => {
+                        counter!("mls_gateway_dm_archive_evicted", "kind" => kind.to_string())
+                            .increment(evicted)
+                    }
+                    Err(e) => warn!(
+                        "Failed to enforce direct message archive cap for {}: {}",
+                        recipient[1], e
+                    ),
+                }
+            }
+        }
+        Ok(false) => {}
+        Err(e) => warn!(
+            "Failed to archive direct message ({}) for offline delivery: {}",
+            kind, e
+        ),
+    }
+}
+
+/// Handlers of stored events still running, awaited on shutdown
+static IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+
+/// Spawn the processing of a stored event, panics are logged and counted instead of ending the task silently
+fn spawn_handler<F>(kind: &'static str, handler: F)
+where
+    F: std::future::Future<Output = ()> + Send + 'static,
+{
+    IN_FLIGHT.fetch_add(1, Ordering::SeqCst);
+    let start = std::time::Instant::now();
+    let task = tokio::spawn(handler);
+    tokio::spawn(async move {
+        let res = task.await;
+        histogram!("mls_gateway_handler_duration", "kind" => kind).record(start.elapsed());
+        if let Err(e) = res {
+            if e.is_panic() {
+                error!("MLS handler for kind {} panicked: {}", kind, e);
+                counter!("mls_gateway_handler_panics", "kind" => kind).increment(1);
+            }
+        }
+        IN_FLIGHT.fetch_sub(1, Ordering::SeqCst);
+    });
+}
+
+/// Wait for the spawned handlers, they archive messages and upsert groups
+async fn drain_handlers() {
+    let mut interval = tokio::time::interval(std::time::Duration::from_millis(50));
+    // the first tick is immediate
+    interval.tick().await;
+    loop {
+        // gives the server time to hand over the events of the last write
+        interval.tick().await;
+        let pending = IN_FLIGHT.load(Ordering::SeqCst);
+        if pending == 0 {
+            info!("MLS handlers drained");
+            return;
         }
+        info!("Waiting for {} MLS handlers", pending);
     }
 }
 
+/// Session state: pubkey the session was last marked online with
+struct OnlineAs(String);
+
 pub struct MlsGateway {
     config: MlsGatewayConfig,
     store: Option<StorageBackend>,
     message_archive: Option<MessageArchive>,
     initialized: bool,
+    /// Runtime the store was created on, storage switches on reload run there
+    runtime: Option<tokio::runtime::Handle>,
 }
 
 impl MlsGateway {
@@ -587,6 +2026,7 @@ impl MlsGateway {
             store: None,
             message_archive: None,
             initialized: false,
+            runtime: None,
         }
     }
 
@@ -596,88 +2036,247 @@ impl MlsGateway {
             return Ok(());
         }
 
-        info!("Initializing MLS Gateway Extension with {:?} backend", self.config.storage_backend);
-        
+        info!(
+            "Initializing MLS Gateway Extension with {:?} backend",
+            self.config.storage_backend
+        );
+
         // Initialize the delivery store
         init_delivery_store();
-        
+
         // Initialize metrics
-        describe_counter!("mls_gateway_events_processed", "Number of MLS events processed by kind");
-        describe_counter!("mls_gateway_groups_updated", "Number of group registry updates");
-        describe_counter!("mls_gateway_keypackages_stored", "Number of key packages stored");
-        describe_counter!("mls_gateway_keypackages_consumed", "Number of key packages consumed by requests");
-        describe_counter!("mls_gateway_keypackages_expired_cleanup", "Number of expired key packages cleaned up");
-        describe_counter!("mls_gateway_keypackages_pruned_for_limit", "Number of keypackages pruned to enforce per-user limit");
-        describe_counter!("mls_gateway_welcomes_stored", "Number of welcome messages stored");
-        describe_counter!("mls_gateway_giftwarps_processed", "Number of giftwrap envelopes processed");
-        describe_counter!("mls_gateway_membership_updates", "Number of membership updates from giftwarps");
+        describe_counter!(
+            "mls_gateway_events_processed",
+            "Number of MLS events processed by kind"
+        );
+        describe_counter!(
+            "mls_gateway_groups_updated",
+            "Number of group registry updates"
+        );
+        describe_counter!(
+            "mls_gateway_keypackages_stored",
+            "Number of key packages stored"
+        );
+        describe_counter!(
+            "mls_gateway_keypackages_stored_by_ciphersuite",
+            "Number of key packages stored by ciphersuite"
+        );
+        describe_counter!(
+            "mls_gateway_validation_issues",
+            "Number of tag validation issues by kind and issue"
+        );
+        describe_counter!(
+            "mls_gateway_management_requests",
+            "Number of NIP-86 management requests by method and result"
+        );
+        describe_counter!(
+            "mls_gateway_moderation_rejected",
+            "Number of events rejected by moderation allow/deny lists by reason"
+        );
+        describe_counter!(
+            "mls_gateway_validation_rejected",
+            "Number of events rejected by strict validation_mode by kind"
+        );
+        describe_counter!(
+            "mls_gateway_low_keypackage_notices",
+            "Number of NOTICEs sent to owners whose keypackage count dropped below the threshold"
+        );
+        describe_counter!(
+            "mls_gateway_443_duplicate_content",
+            "Number of KeyPackages not stored because the owner already has the same bundle"
+        );
+        describe_counter!(
+            "mls_gateway_443_rejected",
+            "Number of KeyPackage events rejected at ingress by reason"
+        );
+        describe_counter!(
+            "mls_gateway_keypackages_consumed",
+            "Number of key packages consumed by requests"
+        );
+        describe_counter!(
+            "mls_gateway_keypackages_consumed_by_notice",
+            "Number of kind 449 keypackage consumed notices by result"
+        );
+        describe_counter!(
+            "mls_gateway_keypackages_expired_cleanup",
+            "Number of expired key packages cleaned up"
+        );
+        describe_counter!(
+            "mls_gateway_keypackages_pruned_for_limit",
+            "Number of keypackages pruned to enforce per-user limit"
+        );
+        describe_counter!(
+            "mls_gateway_welcomes_stored",
+            "Number of welcome messages stored"
+        );
+        describe_counter!(
+            "mls_gateway_giftwarps_processed",
+            "Number of giftwrap envelopes processed"
+        );
+        describe_counter!(
+            "mls_gateway_membership_updates",
+            "Number of membership updates from giftwarps"
+        );
         // Validation/hygiene counters
-        describe_counter!("mls_gateway_443_missing_tag", "Count of KeyPackage events missing required tags");
-        describe_counter!("mls_gateway_443_invalid_tag", "Count of KeyPackage events with invalid tag values");
-        describe_counter!("mls_gateway_443_content_invalid", "Count of KeyPackage events with invalid content (decode failure)");
-        describe_counter!("mls_gateway_443_ingest", "Count of inbound KeyPackage (443) ingests by declared encoding");
-        describe_counter!("mls_gateway_445_unexpected_tag", "Count of unexpected outer tags observed on kind 445 events");
-        describe_counter!("mls_gateway_top_level_444_dropped", "Number of top-level 444 events dropped (should be wrapped in 1059)");
-        describe_counter!("mls_gateway_10051_processed", "Number of KeyPackage Relays List (10051) events processed");
-        describe_histogram!("mls_gateway_db_operation_duration", "Duration of database operations");
+        describe_counter!(
+            "mls_gateway_443_missing_tag",
+            "Count of KeyPackage events missing required tags"
+        );
+        describe_counter!(
+            "mls_gateway_443_invalid_tag",
+            "Count of KeyPackage events with invalid tag values"
+        );
+        describe_counter!(
+            "mls_gateway_443_content_invalid",
+            "Count of KeyPackage events with invalid content (decode failure)"
+        );
+        describe_counter!(
+            "mls_gateway_443_ingest",
+            "Count of inbound KeyPackage (443) ingests by declared encoding"
+        );
+        describe_counter!(
+            "mls_gateway_445_unexpected_tag",
+            "Count of unexpected outer tags observed on kind 445 events"
+        );
+        describe_counter!(
+            "mls_gateway_top_level_444_dropped",
+            "Number of top-level 444 events dropped (should be wrapped in 1059)"
+        );
+        describe_counter!(
+            "mls_gateway_10051_processed",
+            "Number of KeyPackage Relays List (10051) events processed"
+        );
+        describe_counter!("mls_gateway_backfill_events", "Number of archived events handled by backfill by result (inserted/duplicate/invalid/ignored)");
+        describe_counter!(
+            "mls_gateway_giftwrap_forwards",
+            "Number of giftwrap forwards to recipient 10051 relays by result"
+        );
+        describe_counter!(
+            "mls_gateway_push_sent",
+            "Number of push notifications sent to offline recipients by result"
+        );
+        describe_counter!(
+            "mls_gateway_deletions",
+            "Number of keypackages and archived events removed by NIP-09 deletions"
+        );
+        describe_counter!(
+            "mls_gateway_deletion_failures",
+            "Number of NIP-09 deletion targets that failed to be removed"
+        );
+        describe_counter!(
+            "mls_gateway_service_keypackages_published",
+            "Number of KeyPackages generated and published for the service member"
+        );
+        describe_counter!(
+            "mls_gateway_service_member_welcomes",
+            "Number of giftwrapped Welcomes processed by the service member by result"
+        );
+        describe_counter!("mls_gateway_dm_archive_evicted", "Number of archived direct messages of other archived kinds evicted to keep recipients within their archive caps");
+        describe_counter!("mls_gateway_446_archive_evicted", "Number of archived Noise DMs (446) evicted to keep recipients within their archive caps");
+        describe_counter!(
+            "mls_gateway_giftwraps_rejected",
+            "Number of giftwraps rejected as replays or over the per-recipient daily quota"
+        );
+        describe_counter!(
+            "mls_gateway_giftwrap_structure_rejected",
+            "Number of giftwraps rejected by outer structure checks by check"
+        );
+        describe_counter!(
+            "mls_gateway_welcomes_acked",
+            "Number of giftwraps acked through the welcome mailbox"
+        );
+        describe_counter!("mls_gateway_welcomes_expired", "Number of welcome mailbox entries removed after welcome_ttl and the NIP-59 backdating window");
+        describe_counter!(
+            "mls_gateway_archive_gaps_repaired",
+            "Number of LMDB events missing from the archive re-archived by reconciliation"
+        );
+        describe_counter!(
+            "mls_gateway_roster_policy_rejected",
+            "Number of roster/policy events (450) rejected as unauthorized before storage"
+        );
+        describe_counter!(
+            "mls_gateway_storage_reloads",
+            "Number of storage backend switches on setting reload by result"
+        );
+        describe_counter!(
+            "mls_gateway_handler_panics",
+            "Number of stored event handlers that panicked, by kind"
+        );
+        describe_counter!("mls_gateway_query_results_withheld", "Number of giftwraps and Noise DMs left out of REQ results for sessions that are not their recipients");
+        describe_counter!("mls_gateway_broadcast_withheld", "Number of live events withheld from subscribers that are not their recipients or group members");
+        describe_counter!("mls_gateway_roster_quorum", "Number of quorum roster operations and approvals by result (proposed/recorded/duplicate/approved/executed/superseded/expired/closed)");
+        describe_counter!(
+            "mls_gateway_roster_anomalies",
+            "Number of roster/policy sequence gaps and forks by kind and action (rejected/flagged)"
+        );
+        describe_counter!(
+            "mls_gateway_nip29_published",
+            "Number of relay-signed NIP-29 group events (39000-39002) published by result"
+        );
+        describe_counter!(
+            "mls_gateway_roster_snapshots_published",
+            "Number of relay-signed roster snapshots (30450) published by result"
+        );
+        describe_counter!(
+            "mls_gateway_roster_cache_miss",
+            "Number of group messages whose roster was not cached for delivery gating"
+        );
+        describe_counter!(
+            "mls_gateway_resume_replayed",
+            "Number of archived events replayed to resumed subscriptions"
+        );
+        describe_counter!(
+            "mls_gateway_resume_cursors_flushed",
+            "Number of subscription cursors written to storage"
+        );
+        describe_counter!(
+            "mls_gateway_group_messages_counted",
+            "Number of group messages (445) counted in group activity stats by result"
+        );
+        describe_counter!(
+            "mls_gateway_group_stats_conflicts",
+            "Number of group activity stats updates retried after a concurrent update"
+        );
+        describe_gauge!(
+            "mls_gateway_groups_by_activity",
+            "Number of groups with messages within the window (hour/day/week) or abandoned"
+        );
+        describe_gauge!(
+            "mls_gateway_group_messages",
+            "Message count of the busiest groups by group_id"
+        );
+        describe_histogram!(
+            "mls_gateway_db_operation_duration",
+            "Duration of database operations by operation and backend"
+        );
+        describe_histogram!(
+            "mls_gateway_handler_duration",
+            "Duration of MLS event handlers by kind"
+        );
 
         // Initialize storage backend
-        let store = match self.config.storage_backend {
-            #[cfg(feature = "mls_gateway_firestore")]
-            StorageType::Firestore => {
-                // Determine project_id from config or environment
-                let project_id = if let Some(pid) = self.config.project_id.clone() {
-                    pid
-                } else if let Ok(pid) = std::env::var("MLS_FIRESTORE_PROJECT_ID") {
-                    pid
-                } else if let Ok(pid) = std::env::var("GOOGLE_CLOUD_PROJECT") {
-                    pid
-                } else if let Ok(pid) = std::env::var("GCP_PROJECT") {
-                    pid
-                } else {
-                    return Err(anyhow::anyhow!(
-                        "project_id required for Firestore backend (set extensions.mls_gateway.project_id or MLS_FIRESTORE_PROJECT_ID/GOOGLE_CLOUD_PROJECT/GCP_PROJECT env)"
-                    ));
-                };
-                let firestore_store = firestore::FirestoreStorage::new(&project_id).await?;
-                firestore_store.migrate().await?;
-                StorageBackend::Firestore(Arc::new(firestore_store))
-            },
-            #[cfg(feature = "mls_gateway_sql")]
-            StorageType::CloudSql => {
-                let pool = match &self.config.database_url {
-                    Some(url) => {
-                        info!("Connecting to SQL database at {}", url);
-                        sqlx::postgres::PgPoolOptions::new()
-                            .max_connections(10)
-                            .connect(url)
-                            .await
-                            .map_err(|e| anyhow::anyhow!("Failed to connect to database: {}", e))?
-                    }
-                    None => return Err(anyhow::anyhow!("SQL URL not configured")),
-                };
-                
-                let storage = storage::SqlStorage::new(pool).await?;
-                StorageBackend::Sql(Arc::new(storage))
-            }
-        };
+        let store = Self::connect(&self.config).await?;
 
         // Initialize message archive if enabled
         let message_archive = if self.config.enable_message_archive {
             match &self.config.storage_backend {
                 #[cfg(feature = "mls_gateway_firestore")]
-                StorageType::Firestore => {
-                    match MessageArchive::new().await {
-                        Ok(archive) => {
-                            info!("Message archival enabled with {} day TTL", self.config.message_archive_ttl_days);
-                            Some(archive)
-                        }
-                        Err(e) => {
-                            warn!("Failed to initialize message archive: {}. Archival disabled.", e);
-                            None
-                        }
+                StorageType::Firestore => match MessageArchive::new().await {
+                    Ok(archive) => {
+                        info!(
+                            "Message archival enabled with {} day TTL",
+                            self.config.message_archive_ttl_days
+                        );
+                        Some(archive)
                     }
-                }
+                    Err(e) => {
+                        warn!(
+                            "Failed to initialize message archive: {}. Archival disabled.",
+                            e
+                        );
+                        None
+                    }
+                },
                 #[cfg(feature = "mls_gateway_sql")]
                 StorageType::CloudSql => {
                     info!("Message archival not yet supported for SQL backend; disabling");
@@ -688,237 +2287,319 @@ impl MlsGateway {
             info!("Message archival disabled in configuration");
             None
         };
-        
+
         self.store = Some(store.clone());
         self.message_archive = message_archive;
+        self.runtime = tokio::runtime::Handle::try_current().ok();
+        // Service-requests are executed only for group admins per the roster
+        #[cfg(feature = "nip_service")]
+        crate::nip_service::authz::set_authorizer(Arc::new(store.clone()));
+        // Replayed action_ids are recognized across instances and restarts
+        #[cfg(feature = "nip_service")]
+        crate::nip_service::dispatcher::set_ledger(Arc::new(store.clone()));
+        // Attested pubkeys persist across sessions and instances
+        #[cfg(feature = "attestation")]
+        crate::attestation::set_store(Arc::new(store.clone()));
+        // Restore the service member before any 445 is decrypted
+        #[cfg(feature = "nip_service_mls")]
+        if let Err(e) = service_member::initialize_from_config(&self.config).await {
+            warn!("{}", e);
+        }
+        #[cfg(feature = "nip_service_mls")]
+        service_member::spawn_keypackage_replenisher(self.config.clone(), store.clone());
+        match mailbox::load_delivered(&store).await {
+            Ok(count) => info!("Loaded {} acked welcome tombstones", count),
+            Err(e) => warn!("Failed to load acked welcomes: {}", e),
+        }
+        mailbox::spawn_maintenance(store.clone());
+        if self.config.group_stats_enabled {
+            group_stats::spawn_metrics(store.clone(), self.config.clone());
+        }
+        keypackage_consumer::init_rate_limiter(store.clone());
+        match moderation::reload(&store).await {
+            Ok(count) => info!("Loaded {} moderation rules", count),
+            Err(e) => warn!("Failed to load moderation rules: {}", e),
+        }
+        moderation::spawn_refresh(store.clone());
+        if self.config.roster_gated_delivery {
+            delivery::spawn_refresh(store.clone());
+        }
+        if self.config.resume_subscriptions {
+            resume::spawn_flush(store.clone(), self.resume_ttl());
+        }
         self.initialized = true;
-        
+
         // Spawn background task for periodic keypackage cleanup
         let cleanup_store = store;
         let max_keypackages_per_user = self.config.max_keypackages_per_user.unwrap_or(15);
+        let low_keypackage_threshold = self.config.low_keypackage_threshold;
         tokio::spawn(async move {
             let mut interval = tokio::time::interval(std::time::Duration::from_secs(3600)); // Run every hour
             loop {
                 interval.tick().await;
-                match cleanup_store.cleanup_expired_keypackages(max_keypackages_per_user).await {
+                match cleanup_store
+                    .cleanup_expired_keypackages(max_keypackages_per_user)
+                    .await
+                {
                     Ok(count) => {
                         if count > 0 {
                             info!("Cleaned up {} expired keypackages", count);
-                            counter!("mls_gateway_keypackages_expired_cleanup").increment(count as u64);
+                            counter!("mls_gateway_keypackages_expired_cleanup")
+                                .increment(count as u64);
                         }
                     }
                     Err(e) => {
                         error!("Error cleaning up expired keypackages: {}", e);
                     }
                 }
+                low_keypackages::check_online(&cleanup_store, low_keypackage_threshold).await;
+                match cleanup_store
+                    .cleanup_expired_service_actions(chrono::Utc::now())
+                    .await
+                {
+                    Ok(count) if count > 0 => {
+                        info!("Removed {} expired service action claims", count)
+                    }
+                    Ok(_) => {}
+                    Err(e) => warn!("Error cleaning up service action claims: {}", e),
+                }
             }
         });
-        
+
         info!("MLS Gateway Extension initialized successfully");
         Ok(())
     }
 
-    /// Get the store reference
-    fn store(&self) -> anyhow::Result<&StorageBackend> {
-        self.store.as_ref().ok_or_else(|| anyhow::anyhow!("MLS Gateway not initialized"))
+    /// Connect to the configured storage and run its health check, for the relay preflight
+    pub async fn preflight(config: &MlsGatewayConfig) -> anyhow::Result<String> {
+        let store = Self::connect(config).await?;
+        store.health_check().await?;
+        Ok(format!("{:?} storage reachable", config.storage_backend))
     }
 
-    /// Handle KeyPackage (kind 443)
-    async fn handle_keypackage(&self, event: &Event) -> anyhow::Result<()> {
-        let store = self.store()?;
-        
-        // Extract owner from p tag (should match pubkey for security)
-        let owner_tag = event.tags().iter()
-            .find(|tag| tag.len() >= 2 && tag[0] == "p")
-            .map(|tag| tag[1].clone());
-            
-        let event_pubkey = hex::encode(event.pubkey());
-        
-        // Verify owner matches event pubkey (security requirement)
-        if let Some(owner) = &owner_tag {
-            if owner != &event_pubkey {
-                warn!("KeyPackage owner tag {} doesn't match event pubkey {}", owner, event_pubkey);
-                return Err(anyhow::anyhow!("KeyPackage owner verification failed"));
-            }
-        }
-        
-        // Extract expiry from exp tag
-        let expiry = event.tags().iter()
-            .find(|tag| tag.len() >= 2 && tag[0] == "exp")
-            .and_then(|tag| tag[1].parse::<i64>().ok());
-            
-        // Check if expired
-        if let Some(exp_timestamp) = expiry {
-            let now = chrono::Utc::now().timestamp();
-            if exp_timestamp <= now {
-                warn!("Rejecting expired KeyPackage from {}", event_pubkey);
-                return Err(anyhow::anyhow!("KeyPackage has expired"));
+    /// Connect to the configured storage backend and run its migrations, for the relay and the admin commands
+    pub async fn connect(config: &MlsGatewayConfig) -> anyhow::Result<StorageBackend> {
+        let store = match config.storage_backend {
+            #[cfg(feature = "mls_gateway_firestore")]
+            StorageType::Firestore => {
+                // Determine project_id from config or environment
+                let project_id = if let Some(pid) = config.project_id.clone() {
+                    pid
+                } else if let Ok(pid) = std::env::var("MLS_FIRESTORE_PROJECT_ID") {
+                    pid
+                } else if let Ok(pid) = std::env::var("GOOGLE_CLOUD_PROJECT") {
+                    pid
+                } else if let Ok(pid) = std::env::var("GCP_PROJECT") {
+                    pid
+                } else {
+                    return Err(anyhow::anyhow!(
+                        "project_id required for Firestore backend (set extensions.mls_gateway.project_id or MLS_FIRESTORE_PROJECT_ID/GOOGLE_CLOUD_PROJECT/GCP_PROJECT env)"
+                    ));
+                };
+                let firestore_store = firestore::FirestoreStorage::new(&project_id).await?;
+                firestore_store.migrate().await?;
+                StorageBackend::new(Backend::Firestore(Arc::new(firestore_store)))
             }
-        }
+            #[cfg(feature = "mls_gateway_sql")]
+            StorageType::CloudSql => {
+                let pool = match &config.database_url {
+                    Some(url) => {
+                        info!("Connecting to SQL database at {}", url);
+                        sqlx::postgres::PgPoolOptions::new()
+                            .max_connections(10)
+                            .connect(url)
+                            .await
+                            .map_err(|e| anyhow::anyhow!("Failed to connect to database: {}", e))?
+                    }
+                    None => return Err(anyhow::anyhow!("SQL URL not configured")),
+                };
+
+                let storage = storage::SqlStorage::new(pool).await?;
+                StorageBackend::new(Backend::Sql(Arc::new(storage)))
+            }
+        };
+        Ok(store)
+    }
+
+    /// Drop KeyPackages beyond the per author limit, the signed events are served unchanged
+    fn limit_keypackages(
+        events: Vec<Event>,
+        limited: &std::collections::HashSet<[u8; 32]>,
+    ) -> Vec<Event> {
+        events
+            .into_iter()
+            .filter(|event| {
+                kinds::canonical(event.kind()) != KEYPACKAGE_KIND || limited.contains(event.id())
+            })
+            .collect()
+    }
+
+    /// How long subscription cursors are kept
+    fn resume_ttl(&self) -> chrono::Duration {
+        chrono::Duration::days(self.config.resume_cursor_ttl_days as i64)
+    }
 
-        // NIP-EE required tags (soft validation)
-        let mls_version = event.tags().iter()
-            .find(|tag| tag.len() >= 2 && tag[0] == "mls_protocol_version")
-            .map(|tag| tag[1].clone());
-        match mls_version.as_deref() {
-            Some("1.0") => {}
-            Some(other) => {
-                warn!("KeyPackage mls_protocol_version invalid: {}", other);
-                counter!("mls_gateway_443_invalid_tag").increment(1);
-            }
-            None => {
-                warn!("KeyPackage missing required tag: mls_protocol_version");
-                counter!("mls_gateway_443_missing_tag").increment(1);
-            }
+    /// Write subscription cursors on the runtime the store was created on
+    fn flush_cursors(&self, cursors: Vec<firestore::SubscriptionCursor>) {
+        if cursors.is_empty() {
+            return;
         }
-
-        let ciphersuite = event.tags().iter()
-            .find(|tag| tag.len() >= 2 && tag[0] == "ciphersuite")
-            .map(|tag| tag[1].clone());
-        if ciphersuite.is_none() {
-            warn!("KeyPackage missing required tag: ciphersuite");
-            counter!("mls_gateway_443_missing_tag").increment(1);
+        if let (Some(store), Some(runtime)) = (self.store.clone(), &self.runtime) {
+            runtime.spawn(async move { resume::flush(&store, cursors).await });
         }
+    }
 
-        let extensions = event.tags().iter()
-            .find(|tag| tag.len() >= 2 && tag[0] == "extensions")
-            .map(|tag| tag[1..].to_vec());
-        if extensions.is_none() {
-            warn!("KeyPackage missing required tag: extensions");
-            counter!("mls_gateway_443_missing_tag").increment(1);
+    /// Read the archived events a REQ of an authenticated session missed since its stored
+    /// cursor without blocking the session, the REQ goes on once they are kept, see `resume`
+    fn replay_pending(
+        &self,
+        session: &Session,
+        msg: nostr_relay::message::ClientMessage,
+    ) -> ExtensionMessageResult {
+        let nostr_relay::message::IncomingMessage::Req(subscription) = &msg.msg else {
+            return ExtensionMessageResult::Continue(msg);
+        };
+        let (true, Some(pubkey)) = (
+            self.config.resume_subscriptions,
+            session.auth_pubkey().cloned(),
+        ) else {
+            return ExtensionMessageResult::Continue(msg);
+        };
+        let (Some(store), Some(archive), Some(runtime)) = (
+            self.store.clone(),
+            self.message_archive.clone(),
+            self.runtime.clone(),
+        ) else {
+            return ExtensionMessageResult::Continue(msg);
+        };
+        if queries_keypackages(subscription) || !resume::replayable(subscription) {
+            return ExtensionMessageResult::Continue(msg);
         }
 
-        // Note: We no longer check for "last_resort" extension as we use
-        // the "last remaining keypackage" approach instead
-        let has_last_resort = false; // Keep parameter for backward compatibility
+        let session_id = session.id();
+        let sub = subscription.clone();
+        let max_events = self.config.resume_max_events;
+        ExtensionMessageResult::pending(async move {
+            // the storage clients belong to the runtime they were created on
+            let replayed = runtime
+                .spawn({
+                    let sub = sub.clone();
+                    let pubkey = pubkey.clone();
+                    async move { resume::replay(&store, &archive, &pubkey, &sub, max_events).await }
+                })
+                .await;
+            let replayed = match replayed {
+                Ok(Ok(Some((cursor, events)))) => (Some(cursor), events),
+                Ok(Ok(None)) => (None, Vec::new()),
+                Ok(Err(e)) => {
+                    warn!(
+                        "Failed to replay subscription {} of {}: {}",
+                        sub.id, pubkey, e
+                    );
+                    (None, Vec::new())
+                }
+                Err(e) => {
+                    error!("Replay of subscription {} failed: {}", sub.id, e);
+                    (None, Vec::new())
+                }
+            };
+            resume::stash(session_id, &sub.id, replayed);
+            ExtensionMessageResult::Continue(msg)
+        })
+    }
 
-        // Relays: accept either a single ["relays", ..many..] tag or multiple ["relay", url] tags
-        let relays_vec = event.tags().iter()
-            .find(|tag| !tag.is_empty() && tag[0] == "relays")
-            .map(|tag| tag[1..].to_vec());
-        let relay_tags: Vec<String> = event.tags().iter()
-            .filter(|tag| tag.len() >= 2 && tag[0] == "relay")
-            .map(|tag| tag[1].clone())
-            .collect();
-        let all_relays = if let Some(rv) = relays_vec {
-            rv
-        } else {
-            relay_tags
+    /// Track a subscription of an authenticated session and add the archived events
+    /// `replay_pending` read for it
+    fn resume(&self, session: &SessionInfo, subscription: &Subscription) -> ExtensionReqResult {
+        let replayed = resume::take_replayed(session.id, &subscription.id);
+        let (true, Some(pubkey)) = (
+            self.config.resume_subscriptions,
+            session.auth_pubkey.clone(),
+        ) else {
+            return ExtensionReqResult::Continue;
         };
-        
-        if all_relays.is_empty() {
-            warn!("KeyPackage missing relays list (tag 'relays' or repeated 'relay')");
-            counter!("mls_gateway_443_missing_tag").increment(1);
+        if !resume::replayable(subscription) {
+            return ExtensionReqResult::Continue;
         }
 
-        // Content: accept hex by default (no encoding tag), accept base64 when encoding=base64.
-        // Always store canonical standard base64 in Firestore.
-        let content = event.content().trim();
-        let (declared_encoding, content_b64) = match crate::mls_gateway::keypackage_encoding::canonical_base64_from_event(event.tags(), content) {
-            Ok(v) => v,
-            Err(e) => {
-                warn!("KeyPackage content decode failed (declared encoding): {}", e);
-                counter!("mls_gateway_443_content_invalid").increment(1);
-                return Err(anyhow::anyhow!("Invalid keypackage content"));
-            }
-        };
-        counter!("mls_gateway_443_ingest", "encoding" => declared_encoding.as_str().to_string()).increment(1);
-
-        // Get current count for last resort detection (no limit enforcement)
-        let current_count = store.count_user_keypackages(&event_pubkey).await?;
-        
-        // Check if this is a last resort scenario (user had exactly 1 keypackage before this upload)
-        let should_start_timer = current_count == 1;
-        let oldest_keypackage_id = if should_start_timer {
-            // Get the existing keypackage ID (the one that will become "last resort")
-            let existing = store.query_keypackages(
-                Some(&[event_pubkey.clone()]),
-                None,
-                Some(1),
-                Some("created_at_asc") // Get the oldest one
-            ).await?;
-            existing.first().map(|(id, _, _, _)| id.clone())
-        } else {
-            None
-        };
+        let (cursor, events) = replayed.unwrap_or_default();
+        let events = self.deliverable(&pubkey, events);
+        resume::track(session.id, &pubkey, subscription, cursor.as_ref());
+        resume::delivered(session.id, &subscription.id, &events);
 
-        // Calculate expiry if not provided
-        let expires_at = expiry.unwrap_or_else(|| {
-            chrono::Utc::now().timestamp() + self.config.keypackage_ttl as i64
-        });
+        if events.is_empty() {
+            return ExtensionReqResult::Continue;
+        }
+        counter!("mls_gateway_resume_replayed").increment(events.len() as u64);
+        ExtensionReqResult::AddEvents(events)
+    }
 
-        // Store the keypackage
-        store.store_keypackage(
-            &event.id_str(),
-            &event_pubkey,
-            &content_b64,
-            &ciphersuite.unwrap_or_default(),
-            &extensions.unwrap_or_default(),
-            &all_relays,
-            has_last_resort,
-            event.created_at() as i64,
-            expires_at,
-        ).await?;
-        
-        info!("Stored KeyPackage {} from owner: {} (last_resort: {})", event.id_str(), event_pubkey, has_last_resort);
-        
-        // Handle last resort transition
-        if should_start_timer && oldest_keypackage_id.is_some() {
-            let store_clone = store.clone();
-            let event_pubkey_clone = event_pubkey.clone();
-            let new_keypackage_id = event.id_str();
-            let oldest_id = oldest_keypackage_id.unwrap();
-            
-            tokio::spawn(async move {
-                if let Err(e) = handle_last_resort_transition(
-                    store_clone,
-                    event_pubkey_clone,
-                    oldest_id,
-                    new_keypackage_id
-                ).await {
-                    error!("Failed to handle last resort transition: {}", e);
+    /// Replayed events a pubkey may receive, the checks of stored results and broadcasts
+    fn deliverable(&self, pubkey: &String, mut events: Vec<Event>) -> Vec<Event> {
+        events = mailbox::filter_events(events, self.config.welcome_ttl, nostr_relay::db::now());
+        if self.config.recipient_only_delivery {
+            events.retain(|e| {
+                delivery::visible(e, Some(pubkey), self.config.is_direct_kind(e.kind()))
+            });
+        }
+        if self.config.roster_gated_delivery {
+            events.retain(|e| match kinds::canonical(e.kind()) {
+                MLS_GROUP_MESSAGE_KIND | roster_snapshot::ROSTER_SNAPSHOT_KIND => {
+                    delivery::group_id(e)
+                        .and_then(|group_id| delivery::roster_allows(group_id, Some(pubkey)))
+                        .unwrap_or(true)
                 }
+                _ => true,
             });
         }
-        
-        counter!("mls_gateway_keypackages_stored").increment(1);
-        counter!("mls_gateway_events_processed", "kind" => "443").increment(1);
-        Ok(())
+        events
+    }
+
+    /// Get the store reference
+    fn store(&self) -> anyhow::Result<&StorageBackend> {
+        self.store
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("MLS Gateway not initialized"))
     }
 
     /// Handle Giftwrap (kind 1059) containing Welcome message
     async fn handle_giftwrap(&self, event: &Event) -> anyhow::Result<()> {
         let _store = self.store()?;
-        
+
         // Extract recipient and group ID from tags
-        let recipient = event.tags().iter()
+        let recipient = event
+            .tags()
+            .iter()
             .find(|tag| tag.len() >= 2 && tag[0] == "p")
             .map(|tag| tag[1].clone());
-            
-        let group_id = event.tags().iter()
+
+        let group_id = event
+            .tags()
+            .iter()
             .find(|tag| tag.len() >= 2 && tag[0] == "h")
             .map(|tag| tag[1].clone());
-            
+
         if let Some(recipient) = recipient {
             // Process giftwrap for recipient; group_id is optional per NIP-59/NIP-EE
-            info!("Processing Giftwrap for recipient={}, group_hint={:?}", recipient, group_id);
+            info!(
+                "Processing Giftwrap for recipient={}, group_hint={:?}",
+                recipient, group_id
+            );
             // Membership update is best-effort; in practice handled by clients post-decrypt
             counter!("mls_gateway_membership_updates").increment(1);
             if let Some(ref gid) = group_id {
                 info!("Giftwrap hints group {} for {}", gid, recipient);
             }
-            
+
             // NOTE: Welcome messages inside giftwraps contain an 'e' tag referencing the consumed keypackage,
             // but since giftwraps are end-to-end encrypted, the relay cannot decrypt them to track consumption.
-            // Keypackage consumption tracking would require either:
-            // 1. Clients explicitly notifying the relay when a keypackage is consumed
-            // 2. The relay having access to decrypt Welcome messages (breaks E2EE)
-            // For now, we rely on TTL-based expiry and client cooperation.
+            // Senders report consumption with a kind 449 notice instead (see handle_keypackage_consumed);
+            // keypackages nobody reports are left to TTL-based expiry.
         } else {
             // NIP-59 requires a 'p' tag for recipient routing; warn if missing
             warn!("Giftwrap missing required p (recipient) tag");
         }
-        
+
         counter!("mls_gateway_giftwarps_processed").increment(1);
         counter!("mls_gateway_events_processed", "kind" => "1059").increment(1);
         Ok(())
@@ -927,25 +2608,31 @@ impl MlsGateway {
     /// Handle MLS group message (kind 445)
     async fn handle_mls_group_message(&self, event: &Event) -> anyhow::Result<()> {
         let store = self.store()?;
-        
+
         // Extract group ID and epoch from tags
-        let group_id = event.tags().iter()
+        let group_id = event
+            .tags()
+            .iter()
             .find(|tag| tag.len() >= 2 && tag[0] == "h")
             .map(|tag| tag[1].clone());
-            
-        let epoch = event.tags().iter()
+
+        let epoch = event
+            .tags()
+            .iter()
             .find(|tag| tag.len() >= 2 && tag[0] == "k")
             .and_then(|tag| tag[1].parse::<i64>().ok());
 
         if let Some(group_id) = group_id {
             // Update group registry
-            store.upsert_group(
-                &group_id,
-                None, // display_name from content if needed
-                &hex::encode(event.pubkey()),
-                epoch.unwrap_or(0) as u64,
-            ).await?;
-            
+            store
+                .upsert_group(
+                    &group_id,
+                    None, // display_name from content if needed
+                    &hex::encode(event.pubkey()),
+                    epoch.unwrap_or(0) as u64,
+                )
+                .await?;
+
             counter!("mls_gateway_groups_updated").increment(1);
             info!("Updated group registry for group: {}", group_id);
         }
@@ -954,11 +2641,12 @@ impl MlsGateway {
         Ok(())
     }
 
-
     /// Archive event for offline delivery if enabled
     async fn maybe_archive_event(&self, event: &Event) -> anyhow::Result<()> {
         if let Some(ref archive) = self.message_archive {
-            archive.archive_event(event, Some(self.config.message_archive_ttl_days)).await?;
+            archive
+                .archive_event(event, Some(self.config.message_archive_ttl_days))
+                .await?;
         }
         Ok(())
     }
@@ -967,14 +2655,16 @@ impl MlsGateway {
     async fn handle_noise_dm(&self, event: &Event) -> anyhow::Result<()> {
         // For Noise DMs, we primarily just route them
         // The content remains opaque as per spec
-        
+
         // Log recipient for observability (non-PII)
-        let recipient_count = event.tags().iter()
+        let recipient_count = event
+            .tags()
+            .iter()
             .filter(|tag| tag.len() >= 2 && tag[0] == "p")
             .count();
-            
+
         info!("Processing Noise DM with {} recipients", recipient_count);
-        
+
         counter!("mls_gateway_events_processed", "kind" => "446").increment(1);
         Ok(())
     }
@@ -985,7 +2675,9 @@ impl MlsGateway {
         let owner_pubkey = hex::encode(event.pubkey());
 
         // Collect relay URLs from tags
-        let relays: Vec<String> = event.tags().iter()
+        let relays: Vec<String> = event
+            .tags()
+            .iter()
             .filter(|tag| tag.len() >= 2 && tag[0] == "relay")
             .map(|tag| tag[1].clone())
             .collect();
@@ -1000,165 +2692,752 @@ impl MlsGateway {
         dedup.sort();
         dedup.dedup();
 
-        store.upsert_keypackage_relays(&owner_pubkey, &dedup).await?;
+        store
+            .upsert_keypackage_relays(&owner_pubkey, &dedup)
+            .await?;
         counter!("mls_gateway_10051_processed").increment(1);
         counter!("mls_gateway_events_processed", "kind" => "10051").increment(1);
         Ok(())
     }
 
+    /// Handle NIP-09 deletion (kind 5) of keypackages and archived events.
+    /// Only documents authored by the deletion's pubkey are removed.
+    async fn handle_deletion(&self, event: &Event) -> anyhow::Result<()> {
+        let author = hex::encode(event.pubkey());
+        let ids: Vec<&String> = event
+            .tags()
+            .iter()
+            .filter(|tag| tag.len() >= 2 && tag[0] == "e")
+            .map(|tag| &tag[1])
+            .collect();
+        // Optional `k` tags narrow the kinds to look up
+        let kinds: Vec<u16> = event
+            .tags()
+            .iter()
+            .filter(|tag| tag.len() >= 2 && tag[0] == "k")
+            .filter_map(|tag| tag[1].parse().ok())
+            .collect();
+        let targets = |kind: u16| kinds.is_empty() || kinds.contains(&kind);
+
+        // One failed id does not stop the deletion of the others
+        let mut failed = 0;
+        for id in &ids {
+            if targets(kinds::number(KEYPACKAGE_KIND)) {
+                if let Err(e) = self.delete_owned_keypackage(id, &author).await {
+                    warn!(
+                        "Failed to delete keypackage {} on request of {}: {}",
+                        id, author, e
+                    );
+                    failed += 1;
+                }
+            }
+
+            if let Some(archive) = self.message_archive.as_ref() {
+                for &kind in &self.config.archived_kinds {
+                    if !targets(kind) {
+                        continue;
+                    }
+                    match archive.delete_event(kind as u32, id, &author).await {
+                        Ok(true) => {
+                            info!(
+                                "Deleted archived event {}-{} on request of its author",
+                                kind, id
+                            );
+                            counter!("mls_gateway_deletions", "kind" => kind.to_string())
+                                .increment(1);
+                        }
+                        Ok(false) => {}
+                        Err(e) => {
+                            warn!(
+                                "Failed to delete archived event {}-{} on request of {}: {}",
+                                kind, id, author, e
+                            );
+                            failed += 1;
+                        }
+                    }
+                }
+            }
+        }
+        if failed > 0 {
+            counter!("mls_gateway_deletion_failures").increment(failed as u64);
+            return Err(anyhow::anyhow!(
+                "{} deletions of {} failed",
+                failed,
+                event.id_str()
+            ));
+        }
+        Ok(())
+    }
+
+    /// Delete a keypackage when the deletion request comes from its owner
+    async fn delete_owned_keypackage(&self, id: &str, author: &str) -> anyhow::Result<()> {
+        let Some(store) = self.store.as_ref() else {
+            return Ok(());
+        };
+        match store.get_keypackage_owner(id).await? {
+            Some(owner) if owner == author => {
+                store.delete_keypackage_by_id(id).await?;
+                if let Some(delivery) = keypackage_delivery::get_delivery_store() {
+                    delivery.remove_keypackage(id).await;
+                }
+                if let Some(pending) = store.get_pending_deletion(author).await? {
+                    if pending.old_keypackage_id == id {
+                        store.delete_pending_deletion(author).await?;
+                    }
+                }
+                info!("Deleted keypackage {} on request of its owner", id);
+                counter!("mls_gateway_deletions", "kind" => "443").increment(1);
+            }
+            Some(owner) => {
+                warn!(
+                    "Ignoring deletion of keypackage {} owned by {} from {}",
+                    id, owner, author
+                );
+            }
+            None => {}
+        }
+        Ok(())
+    }
+
+    /// Handle KeyPackage consumed notice (kind 449)
+    ///
+    /// Signed by the client that used the referenced 443 in a Welcome. The
+    /// optional `p` tag must name the KeyPackage owner; the owner's last
+    /// KeyPackage is kept as last resort like on query consumption.
+    async fn handle_keypackage_consumed(&self, event: &Event) -> anyhow::Result<()> {
+        let store = self.store()?;
+        let (id, claimed_owner) = keypackage_consumer::consumed_reference(event).map_err(|e| {
+            anyhow::anyhow!(
+                "invalid keypackage consumed notice {}: {}",
+                event.id_str(),
+                e
+            )
+        })?;
+
+        let result = match store.get_keypackage_owner(&id).await? {
+            None => "unknown",
+            Some(owner) if claimed_owner.as_ref().is_some_and(|p| p != &owner) => {
+                warn!(
+                    "Consumed notice {} names {} but keypackage {} is owned by {}",
+                    event.id_str(),
+                    claimed_owner.unwrap_or_default(),
+                    id,
+                    owner
+                );
+                "owner_mismatch"
+            }
+            Some(owner) => {
+                let consumed =
+                    keypackage_consumer::consume_keypackage(store, &id, &owner, "").await?;
+                if consumed {
+                    if let Some(delivery) = keypackage_delivery::get_delivery_store() {
+                        delivery.remove_keypackage(&id).await;
+                    }
+                    info!(
+                        "KeyPackage {} of {} consumed per notice from {}",
+                        id,
+                        owner,
+                        event.pubkey_str()
+                    );
+                    if let Err(e) =
+                        low_keypackages::check(store, &owner, self.config.low_keypackage_threshold)
+                            .await
+                    {
+                        warn!("Low KeyPackage check failed for {}: {}", owner, e);
+                    }
+                    "consumed"
+                } else {
+                    "last_resort"
+                }
+            }
+        };
+        counter!("mls_gateway_keypackages_consumed_by_notice", "result" => result).increment(1);
+        counter!("mls_gateway_events_processed", "kind" => "449").increment(1);
+        Ok(())
+    }
+
     /// Handle Roster/Policy event (kind 450)
     async fn handle_roster_policy(&self, event: &Event) -> anyhow::Result<()> {
         let store = self.store()?;
         let event_pubkey = hex::encode(event.pubkey());
 
         // Extract required tags
-        let group_id = event.tags().iter()
+        let group_id = event
+            .tags()
+            .iter()
             .find(|tag| tag.len() >= 2 && tag[0] == "h")
             .map(|tag| tag[1].clone())
             .ok_or_else(|| anyhow::anyhow!("Missing group_id (h tag)"))?;
 
         // Determine operation up front (used for auth on non-existent groups)
-        let operation = event.tags().iter()
+        let operation = event
+            .tags()
+            .iter()
             .find(|tag| tag.len() >= 2 && tag[0] == "op")
             .map(|tag| tag[1].clone())
             .ok_or_else(|| anyhow::anyhow!("Missing operation (op tag)"))?;
 
         // Authorization based on per-group ownership/admins
-        let group_exists = store.group_exists(&group_id).await.unwrap_or(false);
-        if !group_exists {
-            // Only allow bootstrap to create a new group; creator becomes owner and initial admin
-            if operation.as_str() != "bootstrap" {
-                warn!("Rejecting non-bootstrap roster event for unknown group {}", group_id);
-                return Err(anyhow::anyhow!("Group does not exist; bootstrap required"));
-            }
-        } else {
-            let is_owner = store.is_owner(&group_id, &event_pubkey).await.unwrap_or(false);
-            let is_admin = store.is_admin(&group_id, &event_pubkey).await.unwrap_or(false);
-            if !(is_owner || is_admin) {
-                warn!("Unauthorized roster/policy event for group {} from {}", group_id, event_pubkey);
-                return Err(anyhow::anyhow!("Unauthorized roster/policy event"));
-            }
+        authorize_roster_policy(store, &self.config, &group_id, &operation, &event_pubkey)
+            .await
+            .map_err(|reason| anyhow::anyhow!(reason))?;
+
+        if operation == roster_quorum::APPROVE_OP {
+            return self
+                .handle_roster_approval(event, &group_id, &event_pubkey)
+                .await;
+        }
+
+        // The proposal event of an approved operation is published after it was applied
+        let event_id = event.id_str();
+        if store
+            .get_pending_roster_op(&event_id)
+            .await?
+            .is_some_and(|op| op.status == "executed")
+        {
+            return Ok(());
         }
 
-        let sequence = event.tags().iter()
+        let sequence = event
+            .tags()
+            .iter()
             .find(|tag| tag.len() >= 2 && tag[0] == "seq")
             .and_then(|tag| tag[1].parse::<u64>().ok())
             .ok_or_else(|| anyhow::anyhow!("Missing or invalid sequence (seq tag)"))?;
 
-
         // Validate operation type
         match operation.as_str() {
-            "add" | "remove" | "promote" | "demote" | "bootstrap" | "replace" => {},
+            "add" | "remove" | "promote" | "demote" | "bootstrap" | "replace" | "transfer"
+            | "delete" => {}
             _ => return Err(anyhow::anyhow!("Invalid operation: {}", operation)),
         }
 
         // Extract member pubkeys
-        let member_pubkeys: Vec<String> = event.tags().iter()
+        let member_pubkeys: Vec<String> = event
+            .tags()
+            .iter()
             .filter(|tag| tag.len() >= 2 && tag[0] == "p")
             .map(|tag| tag[1].clone())
             .collect();
 
-        if member_pubkeys.is_empty() && operation != "bootstrap" {
+        if operation == "transfer" && member_pubkeys.len() != 1 {
+            return Err(anyhow::anyhow!(
+                "Transfer needs exactly one new owner (p tag)"
+            ));
+        }
+        if member_pubkeys.is_empty() && operation != "bootstrap" && operation != "delete" {
             warn!("Roster/policy event has no member pubkeys");
         }
 
-        // Check sequence number for idempotency
-        if let Ok(last_seq) = store.get_last_roster_sequence(&group_id).await {
-            if let Some(last_sequence) = last_seq {
-                if sequence <= last_sequence {
-                    warn!("Ignoring roster/policy event with stale sequence: {} <= {}", sequence, last_sequence);
-                    return Err(anyhow::anyhow!("Stale sequence number"));
+        // Sequence must follow the last stored one, gaps and forks are audited
+        let proposal = roster_sequence::Proposal {
+            group_id: &group_id,
+            sequence,
+            operation: &operation,
+            member_pubkeys: &member_pubkeys,
+            admin_pubkey: &event_pubkey,
+            event_id: &event_id,
+        };
+        let verdict = roster_sequence::check(store, &proposal).await?;
+        match &verdict {
+            roster_sequence::Verdict::Next => {}
+            roster_sequence::Verdict::Replay => {
+                info!(
+                    "Ignoring replayed roster/policy event: group={}, seq={}",
+                    group_id, sequence
+                );
+                return Ok(());
+            }
+            roster_sequence::Verdict::Stale { last } => {
+                warn!(
+                    "Ignoring roster/policy event with stale sequence: {} <= {}",
+                    sequence, last
+                );
+                return Err(anyhow::anyhow!("Stale sequence number"));
+            }
+            roster_sequence::Verdict::Gap { last } => {
+                // Gaps that got past ingress (no storage there) still follow the policy
+                if self.config.roster_sequence_policy == roster_sequence::SequencePolicy::Reject {
+                    roster_sequence::record(store, &proposal, &verdict, "rejected").await;
+                    return Err(anyhow::anyhow!("Sequence gap: expected {}", last + 1));
+                }
+                roster_sequence::record(store, &proposal, &verdict, "flagged").await;
+            }
+            roster_sequence::Verdict::Fork { .. } => {
+                let action = match self.config.roster_sequence_policy {
+                    roster_sequence::SequencePolicy::Reject => "rejected",
+                    roster_sequence::SequencePolicy::Flag => "flagged",
+                };
+                roster_sequence::record(store, &proposal, &verdict, action).await;
+                return Err(anyhow::anyhow!(
+                    "Sequence {} already used by a different roster change",
+                    sequence
+                ));
+            }
+        }
+
+        let role_admin = event
+            .tags()
+            .iter()
+            .find(|tag| tag.len() >= 2 && tag[0] == "role")
+            .map(|tag| tag[1].to_lowercase())
+            .map(|s| s == "admin")
+            .unwrap_or(false);
+        let change = firestore::RosterOperation {
+            group_id,
+            sequence,
+            operation,
+            member_pubkeys,
+            admin_pubkey: event_pubkey,
+            role_admin,
+            created_at: event.created_at() as i64,
+        };
+
+        let required = roster_quorum::required_approvals(
+            &change.operation,
+            &change.group_id,
+            &self.config.roster_quorum,
+            &self.config.roster_group_quorum,
+        );
+        // Quorum operations are withheld at ingress, this only sees events that bypassed it
+        if roster_quorum::needs_quorum(&change.operation, required) {
+            let pending = roster_quorum::propose(store, &self.config, event, required)
+                .await
+                .map_err(|reason| anyhow::anyhow!(reason))?;
+            counter!("mls_gateway_roster_quorum", "result" => "proposed").increment(1);
+            info!(
+                "Roster operation {} in group {} waits for {} approvals: {}",
+                pending.operation, pending.group_id, required, pending.event_id
+            );
+            counter!("mls_gateway_events_processed", "kind" => "450").increment(1);
+            return Ok(());
+        }
+
+        self.apply_roster_change(store, &change).await?;
+        counter!("mls_gateway_events_processed", "kind" => "450").increment(1);
+        Ok(())
+    }
+
+    /// Co-sign a pending roster operation, applying it once its quorum is reached
+    async fn handle_roster_approval(
+        &self,
+        event: &Event,
+        group_id: &str,
+        approver: &str,
+    ) -> anyhow::Result<()> {
+        let store = self.store()?;
+        let target = event
+            .tags()
+            .iter()
+            .find(|tag| tag.len() >= 2 && tag[0] == "e")
+            .map(|tag| tag[1].clone())
+            .ok_or_else(|| anyhow::anyhow!("Missing pending operation (e tag)"))?;
+        // Counted in one storage transaction, only one of concurrent approvals closes the quorum
+        let (approval, pending) = store
+            .approve_pending_roster_op(&target, approver, chrono::Utc::now().timestamp())
+            .await?
+            .filter(|(_, op)| op.group_id == group_id)
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "No pending roster operation {} in group {}",
+                    target,
+                    group_id
+                )
+            })?;
+
+        counter!("mls_gateway_roster_quorum", "result" => approval.as_str()).increment(1);
+        match approval {
+            roster_quorum::Approval::Duplicate | roster_quorum::Approval::Closed => {
+                info!(
+                    "Ignoring approval of roster operation {} by {} ({})",
+                    target,
+                    approver,
+                    approval.as_str()
+                );
+                return Ok(());
+            }
+            roster_quorum::Approval::Recorded => {
+                info!(
+                    "Roster operation {} approved by {} ({}/{})",
+                    target,
+                    approver,
+                    pending.approvals.len(),
+                    pending.required
+                );
+            }
+            roster_quorum::Approval::Expired => {
+                warn!(
+                    "Roster operation {} expired before reaching its quorum",
+                    target
+                );
+            }
+            roster_quorum::Approval::Approved => {
+                // The sequence may have been taken while the operation was pending
+                let proposal = roster_sequence::Proposal {
+                    group_id,
+                    sequence: pending.sequence,
+                    operation: &pending.operation,
+                    member_pubkeys: &pending.member_pubkeys,
+                    admin_pubkey: &pending.proposer,
+                    event_id: &pending.event_id,
+                };
+                let apply = match roster_sequence::check(store, &proposal).await? {
+                    roster_sequence::Verdict::Next => true,
+                    roster_sequence::Verdict::Gap { .. } => {
+                        self.config.roster_sequence_policy == roster_sequence::SequencePolicy::Flag
+                    }
+                    _ => false,
+                };
+                if apply {
+                    let change = firestore::RosterOperation {
+                        group_id: pending.group_id.clone(),
+                        sequence: pending.sequence,
+                        operation: pending.operation.clone(),
+                        member_pubkeys: pending.member_pubkeys.clone(),
+                        admin_pubkey: pending.proposer.clone(),
+                        role_admin: pending.role_admin,
+                        created_at: pending.created_at,
+                    };
+                    self.apply_roster_change(store, &change).await?;
+                    // executed before publishing, so the stored proposal is not applied twice
+                    store
+                        .set_pending_roster_op_status(&target, "executed")
+                        .await?;
+                    if let Err(e) = roster_quorum::publish(&pending) {
+                        warn!(
+                            "Failed to publish approved roster operation {}: {}",
+                            target, e
+                        );
+                    }
+                    counter!("mls_gateway_roster_quorum", "result" => "executed").increment(1);
+                    info!(
+                        "Roster operation {} reached its quorum and was applied",
+                        target
+                    );
+                } else {
+                    store
+                        .set_pending_roster_op_status(&target, "superseded")
+                        .await?;
+                    counter!("mls_gateway_roster_quorum", "result" => "superseded").increment(1);
+                    warn!(
+                        "Roster operation {} reached its quorum but sequence {} was taken",
+                        target, pending.sequence
+                    );
+                }
+            }
+        }
+        counter!("mls_gateway_events_processed", "kind" => "450").increment(1);
+        Ok(())
+    }
+
+    /// Store a roster change with the group registry, then refresh the delivery roster and snapshot
+    async fn apply_roster_change(
+        &self,
+        store: &StorageBackend,
+        change: &firestore::RosterOperation,
+    ) -> anyhow::Result<()> {
+        info!(
+            "Processing roster/policy event: group={}, seq={}, op={}, members={:?}",
+            change.group_id, change.sequence, change.operation, change.member_pubkeys
+        );
+        store.apply_roster_operation(change).await?;
+        let group_id = &change.group_id;
+
+        // delete removes the group with its roster history and archived messages
+        if change.operation == "delete" {
+            let removed = store.delete_group(group_id).await?;
+            if let Some(archive) = &self.message_archive {
+                if let Err(e) = archive.delete_group_events(group_id).await {
+                    warn!(
+                        "Failed to delete archived messages of group {}: {}",
+                        group_id, e
+                    );
+                }
+            }
+            if self.config.roster_gated_delivery {
+                if let Err(e) = delivery::load(store, group_id).await {
+                    warn!("Failed to reload roster of group {}: {}", group_id, e);
+                }
+            }
+            info!("Deleted group {} and {} roster records", group_id, removed);
+            counter!("mls_gateway_roster_policy_updates").increment(1);
+            return Ok(());
+        }
+
+        if self.config.roster_gated_delivery {
+            if let Err(e) = delivery::load(store, group_id).await {
+                warn!("Failed to reload roster of group {}: {}", group_id, e);
+            }
+        }
+
+        if self.config.publish_roster_snapshots {
+            match roster_snapshot::publish(store, group_id).await {
+                Ok(Some(id)) => {
+                    counter!("mls_gateway_roster_snapshots_published", "result" => "ok")
+                        .increment(1);
+                    info!("Published roster snapshot {} of group {}", id, group_id);
                 }
+                Ok(None) => {}
+                Err(e) => {
+                    counter!("mls_gateway_roster_snapshots_published", "result" => "error")
+                        .increment(1);
+                    warn!(
+                        "Failed to publish roster snapshot of group {}: {}",
+                        group_id, e
+                    );
+                }
+            }
+        }
+
+        if self.config.nip29_groups {
+            match nip29::publish(store, group_id, self.config.nip29_members).await {
+                Ok(ids) if !ids.is_empty() => {
+                    counter!("mls_gateway_nip29_published", "result" => "ok")
+                        .increment(ids.len() as u64);
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    counter!("mls_gateway_nip29_published", "result" => "error").increment(1);
+                    warn!(
+                        "Failed to publish NIP-29 events of group {}: {}",
+                        group_id, e
+                    );
+                }
+            }
+        }
+
+        counter!("mls_gateway_roster_policy_updates").increment(1);
+        Ok(())
+    }
+}
+
+/// Handle KeyPackage (kind 443), for client events and the service member's own
+pub(crate) async fn handle_keypackage(
+    config: &MlsGatewayConfig,
+    store: &StorageBackend,
+    event: &Event,
+) -> anyhow::Result<()> {
+    // Extract owner from p tag (should match pubkey for security)
+    let owner_tag = event
+        .tags()
+        .iter()
+        .find(|tag| tag.len() >= 2 && tag[0] == "p")
+        .map(|tag| tag[1].clone());
+
+    let event_pubkey = hex::encode(event.pubkey());
+
+    // Verify owner matches event pubkey (security requirement)
+    if let Some(owner) = &owner_tag {
+        if owner != &event_pubkey {
+            warn!(
+                "KeyPackage owner tag {} doesn't match event pubkey {}",
+                owner, event_pubkey
+            );
+            return Err(anyhow::anyhow!("KeyPackage owner verification failed"));
+        }
+    }
+
+    // Extract expiry from exp tag
+    let expiry = event
+        .tags()
+        .iter()
+        .find(|tag| tag.len() >= 2 && tag[0] == "exp")
+        .and_then(|tag| tag[1].parse::<i64>().ok());
+
+    // Check if expired
+    if let Some(exp_timestamp) = expiry {
+        let now = chrono::Utc::now().timestamp();
+        if exp_timestamp <= now {
+            warn!("Rejecting expired KeyPackage from {}", event_pubkey);
+            return Err(anyhow::anyhow!("KeyPackage has expired"));
+        }
+    }
+
+    // Unsupported ciphersuites and protocol versions are rejected hard
+    if let Err(reason) = keypackage_policy::check(event.tags(), &config) {
+        warn!(
+            "Rejecting KeyPackage {} from {}: {}",
+            event.id_str(),
+            event_pubkey,
+            reason
+        );
+        counter!("mls_gateway_443_invalid_tag").increment(1);
+        return Err(anyhow::anyhow!("KeyPackage {}", reason));
+    }
+
+    // Missing NIP-EE tags are reported at ingress per validation_mode
+    let ciphersuite = event
+        .tags()
+        .iter()
+        .find(|tag| tag.len() >= 2 && tag[0] == "ciphersuite")
+        .map(|tag| tag[1].clone());
+
+    let extensions = event
+        .tags()
+        .iter()
+        .find(|tag| tag.len() >= 2 && tag[0] == "extensions")
+        .map(|tag| tag[1..].to_vec());
+
+    // Note: We no longer check for "last_resort" extension as we use
+    // the "last remaining keypackage" approach instead
+    let has_last_resort = false; // Keep parameter for backward compatibility
+
+    // Relays: accept either a single ["relays", ..many..] tag or multiple ["relay", url] tags
+    let relays_vec = event
+        .tags()
+        .iter()
+        .find(|tag| !tag.is_empty() && tag[0] == "relays")
+        .map(|tag| tag[1..].to_vec());
+    let relay_tags: Vec<String> = event
+        .tags()
+        .iter()
+        .filter(|tag| tag.len() >= 2 && tag[0] == "relay")
+        .map(|tag| tag[1].clone())
+        .collect();
+    let all_relays = if let Some(rv) = relays_vec {
+        rv
+    } else {
+        relay_tags
+    };
+
+    // Content: accept hex by default (no encoding tag), accept base64 when encoding=base64.
+    // Always store canonical standard base64 in Firestore.
+    let content = event.content().trim();
+    let (declared_encoding, content_b64) =
+        match crate::mls_gateway::keypackage_encoding::canonical_base64_from_event(
+            event.tags(),
+            content,
+        ) {
+            Ok(v) => v,
+            Err(e) => {
+                warn!(
+                    "KeyPackage content decode failed (declared encoding): {}",
+                    e
+                );
+                counter!("mls_gateway_443_content_invalid").increment(1);
+                return Err(anyhow::anyhow!("Invalid keypackage content"));
             }
+        };
+    counter!("mls_gateway_443_ingest", "encoding" => declared_encoding.as_str().to_string())
+        .increment(1);
+
+    // The same KeyPackageBundle republished under a new event id is not stored again
+    let content_hash = crate::mls_gateway::keypackage_encoding::content_hash(&content_b64)?;
+    if let Some(existing) = store
+        .find_keypackage_by_hash(&event_pubkey, &content_hash)
+        .await?
+    {
+        if existing != event.id_str() {
+            warn!(
+                "Rejecting KeyPackage {} from {}: duplicate of {}",
+                event.id_str(),
+                event_pubkey,
+                existing
+            );
+            counter!("mls_gateway_443_duplicate_content").increment(1);
+            return Err(anyhow::anyhow!("KeyPackage duplicates {}", existing));
         }
-        
-        info!("Processing roster/policy event: group={}, seq={}, op={}, members={:?}",
-              group_id, sequence, operation, member_pubkeys);
+    }
+
+    // Get current count for last resort detection (no limit enforcement)
+    let current_count = store.count_user_keypackages(&event_pubkey).await?;
+
+    // Check if this is a last resort scenario (user had exactly 1 keypackage before this upload)
+    let should_start_timer = current_count == 1;
+    let oldest_keypackage_id = if should_start_timer {
+        // Get the existing keypackage ID (the one that will become "last resort")
+        let existing = store
+            .query_keypackages(
+                Some(&[event_pubkey.clone()]),
+                None,
+                Some(1),
+                Some("created_at_asc"), // Get the oldest one
+            )
+            .await?;
+        existing.first().map(|(id, _, _, _)| id.clone())
+    } else {
+        None
+    };
 
-        // Store the roster/policy event for audit trail and idempotency
-        store.store_roster_policy(
-            &group_id,
-            sequence,
-            &operation,
-            &member_pubkeys,
+    // Calculate expiry if not provided
+    let expires_at =
+        expiry.unwrap_or_else(|| chrono::Utc::now().timestamp() + config.keypackage_ttl as i64);
+
+    let ciphersuite = ciphersuite.unwrap_or_default();
+    let ciphersuite_label = keypackage_policy::canonical_ciphersuite(&ciphersuite)
+        .unwrap_or_else(|| "unknown".to_string());
+
+    // Store the keypackage
+    store
+        .store_keypackage(
+            &event.id_str(),
             &event_pubkey,
+            &content_b64,
+            &ciphersuite,
+            &extensions.unwrap_or_default(),
+            &all_relays,
+            has_last_resort,
             event.created_at() as i64,
-        ).await?;
+            expires_at,
+        )
+        .await?;
 
-        // Update group registry based on operation
-        let role_admin = event.tags().iter()
-            .find(|tag| tag.len() >= 2 && tag[0] == "role")
-            .map(|tag| tag[1].to_lowercase())
-            .map(|s| s == "admin")
-            .unwrap_or(false);
+    keypackage_policy::remember(&event_pubkey, &content_hash, expires_at);
+    info!(
+        "Stored KeyPackage {} from owner: {} (last_resort: {})",
+        event.id_str(),
+        event_pubkey,
+        has_last_resort
+    );
 
-        match operation.as_str() {
-            "bootstrap" => {
-                // Create group with sender as owner and initial admin
-                store.upsert_group(
-                    &group_id,
-                    None,
-                    &event_pubkey,
-                    0,
-                ).await?;
-                // Ensure creator is an admin
-                store.add_admins(&group_id, &vec![event_pubkey.clone()]).await?;
-                info!("Initialized group {} by owner {}", group_id, event_pubkey);
-            }
-            "add" | "replace" => {
-                // Ensure group record exists and bump updated_at
-                store.upsert_group(
-                    &group_id,
-                    None,
-                    &event_pubkey,
-                    0,
-                ).await?;
-                info!("Roster operation {} applied to group {}", operation, group_id);
-            }
-            "promote" => {
-                // If role=admin, add listed pubkeys as admins
-                if role_admin && !member_pubkeys.is_empty() {
-                    store.add_admins(&group_id, &member_pubkeys).await?;
-                    info!("Promoted admins in group {}: {:?}", group_id, member_pubkeys);
-                } else {
-                    info!("Roster operation promote applied to group {}", group_id);
-                }
-            }
-            "demote" => {
-                // If role=admin, remove listed pubkeys from admins
-                if role_admin && !member_pubkeys.is_empty() {
-                    store.remove_admins(&group_id, &member_pubkeys).await?;
-                    info!("Demoted admins in group {}: {:?}", group_id, member_pubkeys);
-                } else {
-                    info!("Roster operation demote applied to group {}", group_id);
-                }
-            }
-            "remove" => {
-                info!("Roster operation remove applied to group {}", group_id);
-            }
-            _ => unreachable!(), // Already validated above
-        }
+    // Handle last resort transition
+    if should_start_timer && oldest_keypackage_id.is_some() {
+        let store_clone = store.clone();
+        let event_pubkey_clone = event_pubkey.clone();
+        let new_keypackage_id = event.id_str();
+        let oldest_id = oldest_keypackage_id.unwrap();
+        let delay_secs = config.last_resort_deletion_delay_secs;
+        let min_keypackages = config.last_resort_min_keypackages;
 
-        counter!("mls_gateway_roster_policy_updates").increment(1);
-        counter!("mls_gateway_events_processed", "kind" => "450").increment(1);
-        Ok(())
+        tokio::spawn(async move {
+            if let Err(e) = handle_last_resort_transition(
+                store_clone,
+                event_pubkey_clone,
+                oldest_id,
+                new_keypackage_id,
+                delay_secs,
+                min_keypackages,
+            )
+            .await
+            {
+                error!("Failed to handle last resort transition: {}", e);
+            }
+        });
     }
+
+    counter!("mls_gateway_keypackages_stored").increment(1);
+    counter!("mls_gateway_keypackages_stored_by_ciphersuite", "ciphersuite" => ciphersuite_label)
+        .increment(1);
+    counter!("mls_gateway_events_processed", "kind" => "443").increment(1);
+    Ok(())
 }
 
 /// Handle the transition when a user goes from 1 to 2+ keypackages
-/// Starts a timer to delete the old keypackage after 10 minutes
+/// Starts a timer to delete the old keypackage after `delay_secs`
 async fn handle_last_resort_transition(
     store: StorageBackend,
     user_pubkey: String,
     old_keypackage_id: String,
     new_keypackage_id: String,
+    delay_secs: u64,
+    min_keypackages: u32,
 ) -> anyhow::Result<()> {
     use crate::mls_gateway::firestore::PendingDeletion;
     use chrono::{Duration, Utc};
-    
+
     let now = Utc::now();
-    let deletion_time = now + Duration::minutes(10);
-    
+    let deletion_time = now + Duration::seconds(delay_secs as i64);
+
     // Create pending deletion record
     let pending = PendingDeletion {
         user_pubkey: user_pubkey.clone(),
@@ -1167,26 +3446,25 @@ async fn handle_last_resort_transition(
         timer_started_at: now,
         deletion_scheduled_at: deletion_time,
     };
-    
+
     store.create_pending_deletion(&pending).await?;
-    
+
     info!(
         "Started last resort keypackage deletion timer for user {} - will delete {} at {:?}",
         user_pubkey, old_keypackage_id, deletion_time
     );
     counter!("mls_gateway_last_resort_timers_started").increment(1);
-    
+
     // Spawn timer task
     tokio::spawn(async move {
-        // Wait for 10 minutes
-        tokio::time::sleep(tokio::time::Duration::from_secs(600)).await;
-        
+        tokio::time::sleep(tokio::time::Duration::from_secs(delay_secs)).await;
+
         // Process the deletion
-        if let Err(e) = process_pending_deletion(store, user_pubkey).await {
+        if let Err(e) = process_pending_deletion(store, user_pubkey, min_keypackages).await {
             error!("Failed to process pending deletion: {}", e);
         }
     });
-    
+
     Ok(())
 }
 
@@ -1194,6 +3472,7 @@ async fn handle_last_resort_transition(
 async fn process_pending_deletion(
     store: StorageBackend,
     user_pubkey: String,
+    min_keypackages: u32,
 ) -> anyhow::Result<()> {
     // Get the pending deletion record
     let pending = match store.get_pending_deletion(&user_pubkey).await? {
@@ -1203,29 +3482,29 @@ async fn process_pending_deletion(
             return Ok(());
         }
     };
-    
+
     // Check if it's time to delete
     if pending.deletion_scheduled_at > chrono::Utc::now() {
         info!("Deletion not yet due for user {}", user_pubkey);
         return Ok(());
     }
-    
+
     // Count current valid keypackages
     let keypackage_count = store.count_user_keypackages(&user_pubkey).await?;
-    
-    if keypackage_count < 3 {
+
+    if keypackage_count < min_keypackages {
         // Not enough keypackages - cancel deletion
         warn!(
-            "Cancelling deletion for user {} - only {} keypackages (need 3+)",
-            user_pubkey, keypackage_count
+            "Cancelling deletion for user {} - only {} keypackages (need {}+)",
+            user_pubkey, keypackage_count, min_keypackages
         );
         counter!("mls_gateway_last_resort_deletions_cancelled").increment(1);
-        
+
         // Clean up the pending deletion record
         store.delete_pending_deletion(&user_pubkey).await?;
         return Ok(());
     }
-    
+
     // Check if the old keypackage still exists
     if !store.keypackage_exists(&pending.old_keypackage_id).await? {
         info!(
@@ -1235,19 +3514,56 @@ async fn process_pending_deletion(
         store.delete_pending_deletion(&user_pubkey).await?;
         return Ok(());
     }
-    
+
     // All conditions met - delete the old keypackage
-    store.delete_keypackage_by_id(&pending.old_keypackage_id).await?;
-    
+    store
+        .delete_keypackage_by_id(&pending.old_keypackage_id)
+        .await?;
+
     info!(
         "Successfully deleted old keypackage {} for user {} (now has {} keypackages)",
-        pending.old_keypackage_id, user_pubkey, keypackage_count - 1
+        pending.old_keypackage_id,
+        user_pubkey,
+        keypackage_count - 1
     );
     counter!("mls_gateway_last_resort_deletions_completed").increment(1);
-    
+
     // Clean up the pending deletion record
     store.delete_pending_deletion(&user_pubkey).await?;
-    
+
+    Ok(())
+}
+
+/// Open a giftwrap addressed to the relay service pubkey, process the Welcome
+/// with the service member and flag the group in the registry.
+#[cfg(feature = "nip_service_mls")]
+async fn onboard_service_member(
+    store: &StorageBackend,
+    config: &MlsGatewayConfig,
+    event: &Event,
+) -> anyhow::Result<()> {
+    let user_id = config
+        .mls_service_user_id
+        .as_deref()
+        .ok_or_else(|| anyhow::anyhow!("mls_service_user_id not configured"))?;
+    let secret = crate::nip_service::emit::service_secret()
+        .ok_or_else(|| anyhow::anyhow!("service secret key not configured"))?;
+    let rumor = crate::nip59::unwrap(event, kinds::number(GIFTWRAP_KIND), &secret)?;
+    if rumor.kind != kinds::number(WELCOME_KIND) {
+        return Err(anyhow::anyhow!(
+            "giftwrap carries kind {}, not a Welcome",
+            rumor.kind
+        ));
+    }
+    let encoding = keypackage_encoding::declared_encoding_from_tags(&rumor.tags)?;
+    let welcome = keypackage_encoding::decode_keypackage_content(&rumor.content, encoding)?;
+    let group_id =
+        service_member::join_from_welcome(user_id, &welcome).map_err(anyhow::Error::msg)?;
+    store.set_service_member(&group_id, &rumor.pubkey).await?;
+    info!(
+        "Service member joined group {} (invited by {})",
+        group_id, rumor.pubkey
+    );
     Ok(())
 }
 
@@ -1262,23 +3578,144 @@ impl Extension for MlsGateway {
         let mut cfg: MlsGatewayConfig = r.parse_extension("mls_gateway");
         drop(r);
 
+        for field in cfg.validate() {
+            warn!("Invalid mls_gateway.{}, using the default", field);
+        }
+        kinds::configure(&cfg.kinds);
+
         // Safety: do not expose REST API unless explicitly allowed
         if cfg.enable_api && std::env::var("MLS_API_UNSAFE_ALLOW").unwrap_or_default() != "true" {
             info!("Disabling MLS Gateway REST API until proper authentication is in place");
             cfg.enable_api = false;
         }
 
+        if !cfg.management_pubkeys.is_empty() {
+            setting.write().add_nip(86);
+        }
+
+        // Switch the storage backend in place, the message archive is kept until restart
+        if let (Some(store), Some(runtime)) = (&self.store, &self.runtime) {
+            if (&cfg.storage_backend, &cfg.project_id, &cfg.database_url)
+                != (
+                    &self.config.storage_backend,
+                    &self.config.project_id,
+                    &self.config.database_url,
+                )
+            {
+                let store = store.clone();
+                let next = cfg.clone();
+                runtime.spawn(async move { store.reconnect(next).await });
+            }
+        }
+
         self.config = cfg;
         info!("MLS Gateway settings updated");
     }
 
     fn config_web(&mut self, cfg: &mut ServiceConfig) {
+        // Admin routes are authenticated and mounted independently of the unsafe mailbox API
+        if let Some(token) = admin::admin_token(&self.config) {
+            info!("Configuring MLS Gateway admin API endpoints");
+            admin::configure_admin_routes(
+                cfg,
+                &self.config.api_prefix,
+                admin::AdminState {
+                    token,
+                    config: self.config.clone(),
+                    store: self.store.clone(),
+                    archive: self.message_archive.clone(),
+                },
+            );
+        }
+
+        // Push token registration is authenticated with NIP-98
+        if self.config.push_enabled {
+            match &self.store {
+                Some(store) => {
+                    info!("Configuring MLS Gateway push token endpoints");
+                    push::configure_push_routes(
+                        cfg,
+                        &self.config.api_prefix,
+                        push::PushState {
+                            store: store.clone(),
+                        },
+                    );
+                }
+                None => warn!("Push enabled but MLS Gateway storage is not initialized"),
+            }
+        }
+
+        // NIP-86 management is authenticated with NIP-98
+        if !self.config.management_pubkeys.is_empty() {
+            match &self.store {
+                Some(store) => {
+                    info!("Configuring NIP-86 management API");
+                    management::configure_management_routes(
+                        cfg,
+                        management::ManagementState {
+                            store: store.clone(),
+                            pubkeys: self.config.management_pubkeys.clone(),
+                        },
+                    );
+                }
+                None => {
+                    warn!("NIP-86 management enabled but MLS Gateway storage is not initialized")
+                }
+            }
+        }
+
+        // Welcome mailbox acks are authenticated with NIP-98
+        if let Some(store) = &self.store {
+            mailbox::configure_mailbox_routes(
+                cfg,
+                &self.config.api_prefix,
+                mailbox::MailboxState {
+                    store: store.clone(),
+                    archive: self.message_archive.clone(),
+                },
+            );
+            let admin_state = admin::admin_token(&self.config).map(|token| admin::AdminState {
+                token,
+                config: self.config.clone(),
+                store: self.store.clone(),
+                archive: self.message_archive.clone(),
+            });
+            if self.config.group_stats_enabled {
+                group_stats::configure_stats_routes(
+                    cfg,
+                    &self.config.api_prefix,
+                    group_stats::StatsState {
+                        store: store.clone(),
+                        admin: admin_state.clone(),
+                    },
+                );
+            }
+            // Members, admins and the operator check roster membership
+            membership::configure_membership_routes(
+                cfg,
+                &self.config.api_prefix,
+                membership::MembershipState {
+                    store: store.clone(),
+                    admin: admin_state,
+                },
+            );
+            // Users export and erase their own data with NIP-98
+            privacy::configure_privacy_routes(
+                cfg,
+                &self.config.api_prefix,
+                privacy::PrivacyState {
+                    store: store.clone(),
+                    archive: self.message_archive.clone(),
+                },
+            );
+        }
+
         if !self.config.enable_api {
             return;
         }
 
         info!("Configuring MLS Gateway REST API endpoints");
-        
+
         // Configure HTTP routes for mailbox services
         endpoints::configure_routes(cfg, &self.config.api_prefix);
     }
@@ -1289,192 +3726,792 @@ impl Extension for MlsGateway {
 
     fn disconnected(&self, session: &mut Session, _ctx: &mut <Session as actix::Actor>::Context) {
         info!("Client disconnected from MLS Gateway: {}", session.id());
+        push::mark_offline(session.id());
+        if self.config.resume_subscriptions {
+            self.flush_cursors(resume::disconnect(session.id(), self.resume_ttl()));
+        }
     }
 
     fn message(
         &self,
         msg: nostr_relay::message::ClientMessage,
-        _session: &mut Session,
+        session: &mut Session,
         _ctx: &mut <Session as actix::Actor>::Context,
     ) -> ExtensionMessageResult {
-        // Handle MLS events asynchronously
+        // Authenticated sessions suppress pushes to their pubkey
+        let newly_authenticated = session
+            .auth_pubkey()
+            .filter(|pubkey| session.get::<OnlineAs>().map(|o| &o.0) != Some(*pubkey))
+            .cloned();
+        if let Some(pubkey) = newly_authenticated {
+            session.set(OnlineAs(pubkey.clone()));
+            if push::mark_online(session.id(), &pubkey) && self.config.low_keypackage_threshold > 0
+            {
+                // Tell a newly authenticated owner if their keypackages ran low while away
+                if let Some(store) = self.store.clone() {
+                    let owner = pubkey;
+                    let threshold = self.config.low_keypackage_threshold;
+                    tokio::spawn(async move {
+                        if let Err(e) = low_keypackages::check(&store, &owner, threshold).await {
+                            warn!("Low KeyPackage check failed for {}: {}", owner, e);
+                        }
+                    });
+                }
+            }
+        }
+
+        // A closed subscription keeps its cursor for the next REQ with its id
+        if let (true, nostr_relay::message::IncomingMessage::Close(sub_id)) =
+            (self.config.resume_subscriptions, &msg.msg)
+        {
+            self.flush_cursors(
+                resume::close(session.id(), sub_id, self.resume_ttl())
+                    .into_iter()
+                    .collect(),
+            );
+        }
+
+        // A resumed subscription waits for its missed archived events
+        if matches!(msg.msg, nostr_relay::message::IncomingMessage::Req(_)) {
+            return self.replay_pending(session, msg);
+        }
+
+        // Reject MLS events synchronously, accepted events are processed in event_stored
         if let nostr_relay::message::IncomingMessage::Event(event) = &msg.msg {
-            match event.kind() {
+            // Runtime allow/deny lists apply before any MLS handling
+            if let Err(reason) = moderation::check(event) {
+                counter!("mls_gateway_moderation_rejected", "reason" => reason).increment(1);
+                return OutgoingMessage::rejected(&event.id_str(), Prefix::Blocked, reason).into();
+            }
+            if matches!(
+                kinds::canonical(event.kind()),
+                KEYPACKAGE_KIND | MLS_GROUP_MESSAGE_KIND | NOISE_DM_KIND | GIFTWRAP_KIND
+            ) {
+                if let Err(reason) =
+                    validation::check(event, self.config.validation_mode(event.kind())).and_then(
+                        |_| validation::check_payload(event, self.config.nip44_validation),
+                    )
+                {
+                    counter!("mls_gateway_validation_rejected", "kind" => kinds::canonical(event.kind()).to_string()).increment(1);
+                    return OutgoingMessage::rejected(&event.id_str(), Prefix::Invalid, &reason)
+                        .into();
+                }
+            }
+            match kinds::canonical(event.kind()) {
                 KEYPACKAGE_KIND => {
                     // KeyPackage (443) - validate and process using gateway handler
-                    let config = self.config.clone();
-                    let store = match self.store() {
-                        Ok(store) => store.clone(),
-                        Err(e) => {
-                            error!("MLS Gateway not initialized: {}", e);
-                            return ExtensionMessageResult::Continue(msg);
-                        }
-                    };
-                    let event_clone = event.clone();
-                    tokio::spawn(async move {
-                        let mut gateway = MlsGateway::new(config);
-                        gateway.store = Some(store);
-                        gateway.initialized = true;
-                        if let Err(e) = gateway.handle_keypackage(&event_clone).await {
-                            error!("Error handling KeyPackage (443): {}", e);
+                    if let Err(reason) = keypackage_policy::check(event.tags(), &self.config) {
+                        counter!("mls_gateway_443_rejected", "reason" => "policy").increment(1);
+                        return OutgoingMessage::rejected(
+                            &event.id_str(),
+                            Prefix::Invalid,
+                            &reason,
+                        )
+                        .into();
+                    }
+                    let content_hash =
+                        crate::mls_gateway::keypackage_encoding::canonical_base64_from_event(
+                            event.tags(),
+                            event.content().trim(),
+                        )
+                        .and_then(|(_, b64)| {
+                            crate::mls_gateway::keypackage_encoding::content_hash(&b64)
+                        });
+                    if let Ok(hash) = content_hash {
+                        if keypackage_policy::is_duplicate(
+                            &event.pubkey_str(),
+                            &hash,
+                            chrono::Utc::now().timestamp(),
+                        ) {
+                            counter!("mls_gateway_443_rejected", "reason" => "duplicate")
+                                .increment(1);
+                            return OutgoingMessage::rejected(
+                                &event.id_str(),
+                                Prefix::Duplicate,
+                                "keypackage content already published",
+                            )
+                            .into();
                         }
-                    });
+                    }
                 }
                 WELCOME_KIND => {
                     // Top-level Welcome events should never appear; they must be inside 1059 giftwrap.
-                    warn!("Dropping top-level 444 Welcome event; must be carried inside giftwrap (1059)");
+                    warn!("Rejecting top-level 444 Welcome event; must be carried inside giftwrap (1059)");
                     counter!("mls_gateway_top_level_444_dropped").increment(1);
+                    return OutgoingMessage::rejected(
+                        &event.id_str(),
+                        Prefix::Invalid,
+                        "welcome must be carried inside a giftwrap (1059)",
+                    )
+                    .into();
                 }
                 GIFTWRAP_KIND => {
                     // Giftwrap (1059) containing Welcome (444)
-                    let event_clone = event.clone();
-                    let archive = self.message_archive.clone();
-                    let config = self.config.clone();
-                    let ttl_days = config.message_archive_ttl_days;
-                    tokio::spawn(async move {
-                        // Attempt to archive giftwrap for offline delivery (requires p tag for recipient)
-                        if let Some(archive) = archive {
-                            if let Err(e) = archive.archive_event(&event_clone, Some(ttl_days)).await {
-                                warn!("Failed to archive Giftwrap (1059) for offline delivery: {}", e);
+                    if let Err(issue) =
+                        giftwrap_guard::structure(event, &self.config, nostr_relay::db::now())
+                    {
+                        counter!("mls_gateway_giftwrap_structure_rejected", "check" => issue.as_str()).increment(1);
+                        return OutgoingMessage::rejected(
+                            &event.id_str(),
+                            Prefix::Invalid,
+                            issue.reason(),
+                        )
+                        .into();
+                    }
+                    if let (true, Some(store)) = (
+                        self.config.giftwrap_reject_known_identities,
+                        self.store.clone(),
+                    ) {
+                        let event = event.clone();
+                        let config = self.config.clone();
+                        return ExtensionMessageResult::pending(async move {
+                            let pubkey = event.pubkey_str();
+                            match giftwrap_guard::is_known_identity(&store, &pubkey).await {
+                                Ok(true) => {
+                                    let issue = giftwrap_guard::StructureIssue::Identity;
+                                    counter!("mls_gateway_giftwrap_structure_rejected", "check" => issue.as_str()).increment(1);
+                                    return OutgoingMessage::rejected(
+                                        &event.id_str(),
+                                        Prefix::Invalid,
+                                        issue.reason(),
+                                    )
+                                    .into();
+                                }
+                                Ok(false) => {}
+                                // The lookup is a heuristic, storage errors do not block delivery
+                                Err(e) => {
+                                    warn!("Failed to look up giftwrap pubkey {}: {}", pubkey, e)
+                                }
                             }
-                        }
-
-                        // Extract recipient and optional group hint from tags
-                        let recipient = event_clone.tags().iter()
-                            .find(|tag| tag.len() >= 2 && tag[0] == "p")
-                            .map(|tag| tag[1].clone());
-                            
-                        let group_id = event_clone.tags().iter()
-                            .find(|tag| tag.len() >= 2 && tag[0] == "h")
-                            .map(|tag| tag[1].clone());
-                            
-                        if let Some(recipient) = recipient {
-                            // Best-effort membership/accounting; clients handle formal join post-decrypt
-                            info!("Processing Giftwrap for recipient={}, group_hint={:?}", recipient, group_id);
-                            counter!("mls_gateway_membership_updates").increment(1);
-                            if let Some(ref gid) = group_id {
-                                info!("Giftwrap hints group {} for {}", gid, recipient);
+                            match giftwrap_rejection(&event, &config) {
+                                Some(rejected) => rejected.into(),
+                                None => ExtensionMessageResult::Continue(msg),
                             }
-                        } else {
-                            // NIP-59 requires 'p'; if absent, we still archived earlier but warn here
-                            warn!("Giftwrap missing required p (recipient) tag");
-                        }
-                        
-                        counter!("mls_gateway_giftwarps_processed").increment(1);
-                        counter!("mls_gateway_events_processed", "kind" => "1059").increment(1);
-                    });
+                        });
+                    }
+                    if let Some(rejected) = giftwrap_rejection(event, &self.config) {
+                        return rejected.into();
+                    }
                 }
                 MLS_GROUP_MESSAGE_KIND => {
-                    // MLS group message (445)
-                    let store = match self.store() {
-                        Ok(store) => store.clone(),
-                        Err(e) => {
-                            error!("MLS Gateway not initialized: {}", e);
-                            return ExtensionMessageResult::Continue(msg);
-                        }
+                    // Load the group roster before the message is broadcast
+                    if let (true, Some(store), Some(group_id)) = (
+                        self.config.roster_gated_delivery,
+                        self.store.as_ref(),
+                        delivery::group_id(event),
+                    ) {
+                        delivery::ensure_loaded(store, group_id);
+                    }
+                }
+                KEYPACKAGE_CONSUMED_KIND => {
+                    // KeyPackage consumed notice (449) - the relay cannot see Welcomes inside giftwraps
+                    if let Err(reason) = keypackage_consumer::consumed_reference(event) {
+                        return OutgoingMessage::rejected(&event.id_str(), Prefix::Invalid, reason)
+                            .into();
+                    }
+                }
+                ROSTER_POLICY_KIND => {
+                    // Await the authorization so unauthorized roster changes are rejected, not dropped
+                    let tag = |name: &str| {
+                        event
+                            .tags()
+                            .iter()
+                            .find(|tag| tag.len() >= 2 && tag[0] == name)
+                            .map(|tag| tag[1].clone())
                     };
-                    
-                    // Check if we have message archive
-                    let archive = self.message_archive.clone();
-                    let config = self.config.clone();
-                    
-                    let event_clone = event.clone();
-                    tokio::spawn(async move {
-                        // Archive message for offline delivery if enabled
-                        if let Some(ref archive) = archive {
-                            if let Err(e) = archive.archive_event(&event_clone, Some(config.message_archive_ttl_days)).await {
-                                warn!("Failed to archive event for offline delivery: {}", e);
+                    if let (Some(store), Some(group_id), Some(operation)) =
+                        (self.store.clone(), tag("h"), tag("op"))
+                    {
+                        let event_id = event.id_str();
+                        let pubkey = event.pubkey_str();
+                        let seq = tag("seq").and_then(|seq| seq.parse::<u64>().ok());
+                        let members: Vec<String> = event
+                            .tags()
+                            .iter()
+                            .filter(|tag| tag.len() >= 2 && tag[0] == "p")
+                            .map(|tag| tag[1].clone())
+                            .collect();
+                        let required = roster_quorum::required_approvals(
+                            &operation,
+                            &group_id,
+                            &self.config.roster_quorum,
+                            &self.config.roster_group_quorum,
+                        );
+                        let config = self.config.clone();
+                        let event = event.clone();
+                        return ExtensionMessageResult::pending(async move {
+                            if let Err(reason) = authorize_roster_policy(
+                                &store, &config, &group_id, &operation, &pubkey,
+                            )
+                            .await
+                            {
+                                counter!("mls_gateway_roster_policy_rejected").increment(1);
+                                return OutgoingMessage::rejected(
+                                    &event_id,
+                                    Prefix::Restricted,
+                                    reason,
+                                )
+                                .into();
+                            }
+                            // Quorum operations are withheld from storage and delivery until approved
+                            if roster_quorum::needs_quorum(&operation, required) {
+                                return match roster_quorum::propose(
+                                    &store, &config, &event, required,
+                                )
+                                .await
+                                {
+                                    Ok(pending) => {
+                                        counter!("mls_gateway_roster_quorum", "result" => "proposed").increment(1);
+                                        info!("Roster operation {} in group {} waits for {} approvals: {}",
+                                              pending.operation, pending.group_id, required, pending.event_id);
+                                        OutgoingMessage::ok(
+                                            &event_id,
+                                            true,
+                                            &format!(
+                                                "pending: waiting for {} admin approvals",
+                                                required
+                                            ),
+                                        )
+                                        .into()
+                                    }
+                                    Err(reason) => OutgoingMessage::rejected(
+                                        &event_id,
+                                        Prefix::Invalid,
+                                        &reason,
+                                    )
+                                    .into(),
+                                };
+                            }
+                            // The sequence of a pending operation is reserved for it
+                            if let (true, Some(sequence)) = (
+                                roster_quorum::configured(&config)
+                                    && operation != roster_quorum::APPROVE_OP,
+                                seq,
+                            ) {
+                                match roster_quorum::reserved_by(
+                                    &store,
+                                    &group_id,
+                                    sequence,
+                                    &event_id,
+                                    chrono::Utc::now().timestamp(),
+                                )
+                                .await
+                                {
+                                    Ok(None) => {}
+                                    Ok(Some(pending)) => {
+                                        let reason = format!("sequence {} is reserved by pending roster operation {}", sequence, pending);
+                                        return OutgoingMessage::rejected(
+                                            &event_id,
+                                            Prefix::Invalid,
+                                            &reason,
+                                        )
+                                        .into();
+                                    }
+                                    Err(e) => {
+                                        warn!("Failed to check pending roster operations of group {}: {}", group_id, e);
+                                        return OutgoingMessage::rejected(
+                                            &event_id,
+                                            Prefix::Error,
+                                            "failed to check roster sequence",
+                                        )
+                                        .into();
+                                    }
+                                }
+                            }
+                            // With the reject policy gaps and forks are refused before storage
+                            let Some(sequence) = seq.filter(|_| {
+                                config.roster_sequence_policy
+                                    == roster_sequence::SequencePolicy::Reject
+                            }) else {
+                                return ExtensionMessageResult::Continue(msg);
+                            };
+                            let proposal = roster_sequence::Proposal {
+                                group_id: &group_id,
+                                sequence,
+                                operation: &operation,
+                                member_pubkeys: &members,
+                                admin_pubkey: &pubkey,
+                                event_id: &event_id,
+                            };
+                            match roster_sequence::check(&store, &proposal).await {
+                                Ok(verdict) if verdict.anomaly().is_some() => {
+                                    roster_sequence::record(
+                                        &store, &proposal, &verdict, "rejected",
+                                    )
+                                    .await;
+                                    let reason = match verdict {
+                                        roster_sequence::Verdict::Gap { last } => {
+                                            format!("sequence gap: expected {}", last + 1)
+                                        }
+                                        _ => format!("sequence fork: {} already used", sequence),
+                                    };
+                                    OutgoingMessage::rejected(&event_id, Prefix::Invalid, &reason)
+                                        .into()
+                                }
+                                Ok(_) => ExtensionMessageResult::Continue(msg),
+                                Err(e) => {
+                                    warn!(
+                                        "Failed to check roster sequence of group {}: {}",
+                                        group_id, e
+                                    );
+                                    OutgoingMessage::rejected(
+                                        &event_id,
+                                        Prefix::Error,
+                                        "failed to check roster sequence",
+                                    )
+                                    .into()
+                                }
+                            }
+                        });
+                    }
+                }
+                roster_snapshot::ROSTER_SNAPSHOT_KIND => {
+                    // Only the relay speaks for the roster
+                    if !roster_snapshot::from_relay(event) {
+                        return OutgoingMessage::rejected(
+                            &event.id_str(),
+                            Prefix::Restricted,
+                            "roster snapshots are published by the relay",
+                        )
+                        .into();
+                    }
+                }
+                kind if self.config.nip29_groups && nip29::is_group_kind(kind) => {
+                    // NIP-29 group events mirror the registry, like roster snapshots
+                    if !roster_snapshot::from_relay(event) {
+                        return OutgoingMessage::rejected(
+                            &event.id_str(),
+                            Prefix::Restricted,
+                            "group metadata is published by the relay",
+                        )
+                        .into();
+                    }
+                }
+                _ => {
+                    // Not an MLS event, continue processing
+                }
+            }
+        }
+
+        ExtensionMessageResult::Continue(msg)
+    }
+
+    fn event_stored(&self, event: &Event) {
+        // MLS processing runs only for events that were accepted and stored
+        match kinds::canonical(event.kind()) {
+            // the service member stores its KeyPackages before publishing them
+            KEYPACKAGE_KIND if roster_snapshot::from_relay(event) => {}
+            KEYPACKAGE_KIND => {
+                let config = self.config.clone();
+                let store = match self.store() {
+                    Ok(store) => store.clone(),
+                    Err(e) => {
+                        error!("MLS Gateway not initialized: {}", e);
+                        return;
+                    }
+                };
+                let event_clone = event.clone();
+                spawn_handler("443", async move {
+                    if let Err(e) = handle_keypackage(&config, &store, &event_clone).await {
+                        error!("Error handling KeyPackage (443): {}", e);
+                    }
+                });
+            }
+            GIFTWRAP_KIND => {
+                // Replays are rejected at ingress once stored
+                giftwrap_guard::remember(event);
+                let event_clone = event.clone();
+                let archive = self.message_archive.clone();
+                let config = self.config.clone();
+                let ttl_days = config.message_archive_ttl_days;
+                let store = self.store.clone();
+                spawn_handler("1059", async move {
+                    // Replays that reached another instance are neither archived nor tracked
+                    if let (Some(archive), Some(recipient)) =
+                        (archive.as_ref(), giftwrap_guard::recipient(&event_clone))
+                    {
+                        let digest = hex::encode(giftwrap_guard::digest(&event_clone));
+                        match archive
+                            .claim_giftwrap(&recipient, &digest, config.welcome_ttl)
+                            .await
+                        {
+                            Ok(false) => {
+                                counter!("mls_gateway_giftwraps_rejected", "reason" => "archived_duplicate").increment(1);
+                                return;
                             }
+                            Ok(true) => {}
+                            Err(e) => warn!("Failed to claim Giftwrap (1059) digest: {}", e),
                         }
+                    }
 
-                        if let Err(e) = Self::handle_mls_group_message_static(store, config.clone(), &event_clone).await {
-                            error!("Error handling MLS group message: {}", e);
+                    // Stored giftwraps are charged to the recipient quota, the ones that raced past it are not delivered further
+                    if let Some(recipient) = giftwrap_guard::recipient(&event_clone) {
+                        let quota = config.giftwrap_daily_quota;
+                        let mut charged = giftwrap_guard::charge(&recipient, quota);
+                        if let (true, true, Some(archive)) = (charged, quota > 0, archive.as_ref())
+                        {
+                            match archive.charge_giftwrap_quota(&recipient, quota).await {
+                                Ok(ok) => charged = ok,
+                                Err(e) => warn!(
+                                    "Failed to charge Giftwrap (1059) quota of {}: {}",
+                                    recipient, e
+                                ),
+                            }
                         }
-                    });
-                }
-                NOISE_DM_KIND => {
-                    // Noise DM (446) - archive if enabled
-                    if let Some(ref archive) = self.message_archive {
-                        let event_clone = event.clone();
-                        let config = self.config.clone();
-                        let archive_clone = archive.clone();
-                        let event_clone_2 = event_clone.clone();
-                        let ttl_days = config.message_archive_ttl_days;
-                        tokio::spawn(async move {
-                            if let Err(e) = archive_clone.archive_event(&event_clone_2, Some(ttl_days)).await {
-                                warn!("Failed to archive Noise DM for offline delivery: {}", e);
+                        if !charged {
+                            counter!("mls_gateway_giftwraps_rejected", "reason" => "stored_quota")
+                                .increment(1);
+                            return;
+                        }
+                    }
+
+                    // Republish to the recipient's 10051 relays if enabled
+                    if let (true, Some(store)) = (config.forward_giftwraps, store.as_ref()) {
+                        if let Err(e) =
+                            forward::forward_giftwrap(store, &config, &event_clone).await
+                        {
+                            warn!("Failed to forward Giftwrap (1059): {}", e);
+                        }
+                    }
+
+                    // Track the giftwrap in the recipient's mailbox until acked or welcome_ttl
+                    if let Some(store) = store.as_ref() {
+                        if let Err(e) =
+                            mailbox::record(store, &event_clone, config.welcome_ttl).await
+                        {
+                            warn!("Failed to record Giftwrap (1059) in welcome mailbox: {}", e);
+                        }
+                    }
+
+                    // Attempt to archive giftwrap for offline delivery (requires p tag for recipient)
+                    if let Some(archive) =
+                        archive.filter(|_| config.archived_kinds.contains(&event_clone.kind()))
+                    {
+                        // Archived giftwraps do not outlive the welcome mailbox
+                        let welcome_days = config.welcome_ttl.div_ceil(86400).max(1) as u32;
+                        if let Err(e) = archive
+                            .archive_event(&event_clone, Some(ttl_days.min(welcome_days)))
+                            .await
+                        {
+                            warn!(
+                                "Failed to archive Giftwrap (1059) for offline delivery: {}",
+                                e
+                            );
+                        }
+                    }
+
+                    // Extract recipient and optional group hint from tags
+                    let recipient = event_clone
+                        .tags()
+                        .iter()
+                        .find(|tag| tag.len() >= 2 && tag[0] == "p")
+                        .map(|tag| tag[1].clone());
+
+                    let group_id = event_clone
+                        .tags()
+                        .iter()
+                        .find(|tag| tag.len() >= 2 && tag[0] == "h")
+                        .map(|tag| tag[1].clone());
+
+                    if let Some(recipient) = recipient {
+                        // Best-effort membership/accounting; clients handle formal join post-decrypt
+                        info!(
+                            "Processing Giftwrap for recipient={}, group_hint={:?}",
+                            recipient, group_id
+                        );
+                        counter!("mls_gateway_membership_updates").increment(1);
+                        if let Some(ref gid) = group_id {
+                            info!("Giftwrap hints group {} for {}", gid, recipient);
+                        }
+
+                        // Welcomes addressed to the relay onboard its service member
+                        #[cfg(feature = "nip_service_mls")]
+                        if crate::nip_service::emit::service_pubkey().as_deref()
+                            == Some(recipient.as_str())
+                        {
+                            if let Some(store) = store.as_ref() {
+                                let result =
+                                    onboard_service_member(store, &config, &event_clone).await;
+                                let label = if result.is_ok() { "joined" } else { "error" };
+                                counter!("mls_gateway_service_member_welcomes", "result" => label)
+                                    .increment(1);
+                                if let Err(e) = result {
+                                    warn!(
+                                        "Service member onboarding failed for giftwrap {}: {}",
+                                        event_clone.id_str(),
+                                        e
+                                    );
+                                }
                             }
-                        });
+                        }
+                    } else {
+                        // NIP-59 requires 'p'; if absent, we still archived earlier but warn here
+                        warn!("Giftwrap missing required p (recipient) tag");
+                    }
+
+                    if let (true, Some(store)) = (config.push_enabled, store.as_ref()) {
+                        if let Err(e) = push::notify_offline(store, &config, &event_clone).await {
+                            warn!("Failed to push Giftwrap (1059) notification: {}", e);
+                        }
+                    }
+
+                    counter!("mls_gateway_giftwarps_processed").increment(1);
+                    counter!("mls_gateway_events_processed", "kind" => "1059").increment(1);
+                });
+            }
+            MLS_GROUP_MESSAGE_KIND => {
+                // MLS group message (445)
+                let store = match self.store() {
+                    Ok(store) => store.clone(),
+                    Err(e) => {
+                        error!("MLS Gateway not initialized: {}", e);
+                        return;
+                    }
+                };
+
+                // Check if we have message archive
+                let archive = self.message_archive.clone();
+                let config = self.config.clone();
+
+                let event_clone = event.clone();
+                spawn_handler("445", async move {
+                    // Archive message for offline delivery if enabled
+                    if let Some(ref archive) =
+                        archive.filter(|_| config.archived_kinds.contains(&event_clone.kind()))
+                    {
+                        if let Err(e) = archive
+                            .archive_event(&event_clone, Some(config.message_archive_ttl_days))
+                            .await
+                        {
+                            warn!("Failed to archive event for offline delivery: {}", e);
+                        }
+                    }
+
+                    if config.push_enabled {
+                        if let Err(e) = push::notify_offline(&store, &config, &event_clone).await {
+                            warn!("Failed to push group message (445) notification: {}", e);
+                        }
+                    }
+
+                    // service-notify sent by the relay is delivered, not a request to it
+                    if roster_snapshot::from_relay(&event_clone) {
+                        return;
+                    }
+                    if let Err(e) =
+                        Self::handle_mls_group_message_static(store, config.clone(), &event_clone)
+                            .await
+                    {
+                        error!("Error handling MLS group message: {}", e);
+                    }
+                });
+            }
+            KEYPACKAGE_RELAYS_LIST_KIND => {
+                // KeyPackage Relays List (10051)
+                let config = self.config.clone();
+                let store = match self.store() {
+                    Ok(store) => store.clone(),
+                    Err(e) => {
+                        error!("MLS Gateway not initialized: {}", e);
+                        return;
+                    }
+                };
+                let event_clone = event.clone();
+                spawn_handler("10051", async move {
+                    let mut gateway = MlsGateway::new(config);
+                    gateway.store = Some(store);
+                    gateway.initialized = true;
+                    if let Err(e) = gateway.handle_keypackage_relays_list(&event_clone).await {
+                        error!("Error handling KeyPackage Relays List (10051): {}", e);
+                    }
+                });
+            }
+            // Kind 447 (KeyPackage Request) is deprecated - use REQ queries for kind 443 instead
+            ROSTER_POLICY_KIND => {
+                // Roster/Policy (450)
+                let config = self.config.clone();
+                let store = match self.store() {
+                    Ok(store) => store.clone(),
+                    Err(e) => {
+                        error!("MLS Gateway not initialized: {}", e);
+                        return;
+                    }
+                };
+                let archive = self.message_archive.clone();
+                let event_clone = event.clone();
+                spawn_handler("450", async move {
+                    let mut gateway = MlsGateway::new(config);
+                    // Set the store manually since we're in a spawned task
+                    gateway.store = Some(store);
+                    gateway.message_archive = archive;
+                    gateway.initialized = true;
+                    if let Err(e) = gateway.handle_roster_policy(&event_clone).await {
+                        error!("Error handling roster/policy event: {}", e);
+                    }
+                });
+            }
+            KEYPACKAGE_CONSUMED_KIND => {
+                let config = self.config.clone();
+                let store = self.store.clone();
+                let event_clone = event.clone();
+                spawn_handler("449", async move {
+                    let mut gateway = MlsGateway::new(config);
+                    gateway.store = store;
+                    gateway.initialized = true;
+                    if let Err(e) = gateway.handle_keypackage_consumed(&event_clone).await {
+                        error!("Error handling keypackage consumed notice (449): {}", e);
+                    }
+                });
+            }
+            DELETION_KIND => {
+                // NIP-09 deletion, propagate to MLS storage and archive
+                let config = self.config.clone();
+                let store = self.store.clone();
+                let archive = self.message_archive.clone();
+                let event_clone = event.clone();
+                spawn_handler("5", async move {
+                    let mut gateway = MlsGateway::new(config);
+                    gateway.store = store;
+                    gateway.message_archive = archive;
+                    gateway.initialized = true;
+                    if let Err(e) = gateway.handle_deletion(&event_clone).await {
+                        error!("Error handling deletion (5): {}", e);
+                    }
+                });
+            }
+            _ if self.config.is_direct_kind(event.kind()) => {
+                // Noise DMs (446) and other direct messages: archive and push to offline recipients
+                let kind = event.kind();
+                let archive = self
+                    .message_archive
+                    .clone()
+                    .filter(|_| self.config.archived_kinds.contains(&kind));
+                let store = self.store.clone();
+                let config = self.config.clone();
+                let event_clone = event.clone();
+                let label = if kinds::canonical(kind) == NOISE_DM_KIND {
+                    "446"
+                } else {
+                    "dm"
+                };
+                spawn_handler(label, async move {
+                    if let Some(archive) = archive {
+                        archive_direct_message(&archive, &config, &event_clone).await;
                     }
-                    
-                    counter!("mls_gateway_events_processed", "kind" => "446").increment(1);
-                    info!("Processing Noise DM from {}", hex::encode(event.pubkey()));
-                }
-                KEYPACKAGE_RELAYS_LIST_KIND => {
-                    // KeyPackage Relays List (10051)
-                    let config = self.config.clone();
-                    let store = match self.store() {
-                        Ok(store) => store.clone(),
-                        Err(e) => {
-                            error!("MLS Gateway not initialized: {}", e);
-                            return ExtensionMessageResult::Continue(msg);
-                        }
-                    };
-                    let event_clone = event.clone();
-                    tokio::spawn(async move {
-                        let mut gateway = MlsGateway::new(config);
-                        gateway.store = Some(store);
-                        gateway.initialized = true;
-                        if let Err(e) = gateway.handle_keypackage_relays_list(&event_clone).await {
-                            error!("Error handling KeyPackage Relays List (10051): {}", e);
-                        }
-                    });
-                }
-                // Kind 447 (KeyPackage Request) is deprecated - use REQ queries for kind 443 instead
-                ROSTER_POLICY_KIND => {
-                    // Roster/Policy (450)
-                    let config = self.config.clone();
-                    let store = match self.store() {
-                        Ok(store) => store.clone(),
-                        Err(e) => {
-                            error!("MLS Gateway not initialized: {}", e);
-                            return ExtensionMessageResult::Continue(msg);
-                        }
-                    };
-                    let event_clone = event.clone();
-                    tokio::spawn(async move {
-                        let mut gateway = MlsGateway::new(config);
-                        // Set the store manually since we're in a spawned task
-                        gateway.store = Some(store);
-                        gateway.initialized = true;
-                        if let Err(e) = gateway.handle_roster_policy(&event_clone).await {
-                            error!("Error handling roster/policy event: {}", e);
+                    if let (true, Some(store)) = (config.push_enabled, store.as_ref()) {
+                        if let Err(e) = push::notify_offline(store, &config, &event_clone).await {
+                            warn!(
+                                "Failed to push direct message ({}) notification: {}",
+                                kind, e
+                            );
                         }
-                    });
-                }
-                _ => {
-                    // Not an MLS event, continue processing
-                }
+                    }
+                });
+
+                counter!("mls_gateway_events_processed", "kind" => kind.to_string()).increment(1);
+                info!(
+                    "Processing direct message ({}) from {}",
+                    kind,
+                    hex::encode(event.pubkey())
+                );
             }
+            _ => {}
         }
+    }
 
-        ExtensionMessageResult::Continue(msg)
+    fn broadcast(&self, session_id: usize, auth_pubkey: Option<&String>, event: &Event) -> bool {
+        let direct = self.config.is_direct_kind(event.kind());
+        let allowed = match kinds::canonical(event.kind()) {
+            kind if self.config.recipient_only_delivery && (kind == GIFTWRAP_KIND || direct) => {
+                delivery::visible(event, auth_pubkey, direct)
+            }
+            MLS_GROUP_MESSAGE_KIND | roster_snapshot::ROSTER_SNAPSHOT_KIND
+                if self.config.roster_gated_delivery =>
+            {
+                delivery::group_id(event)
+                    .and_then(|group_id| delivery::roster_allows(group_id, auth_pubkey))
+                    .unwrap_or(true)
+            }
+            _ => true,
+        };
+        if !allowed {
+            counter!("mls_gateway_broadcast_withheld", "kind" => event.kind().to_string())
+                .increment(1);
+            return false;
+        }
+        if kinds::canonical(event.kind()) == KEYPACKAGE_KIND {
+            debug!(
+                "Sending KeyPackage {} from {} to session {}",
+                event.id_str(),
+                event.pubkey_str(),
+                session_id
+            );
+        }
+        if self.config.resume_subscriptions {
+            resume::broadcast(session_id, event);
+        }
+        true
+    }
+
+    fn shutdown(&self) -> Option<ShutdownFuture> {
+        // Cursors advanced since the last flush are written after the handlers drained
+        let flush = match (
+            self.config.resume_subscriptions,
+            self.store.clone(),
+            self.runtime.clone(),
+        ) {
+            (true, Some(store), Some(runtime)) => Some((store, runtime, self.resume_ttl())),
+            _ => None,
+        };
+        Some(Box::pin(async move {
+            drain_handlers().await;
+            if let Some((store, runtime, ttl)) = flush {
+                let _ = runtime
+                    .spawn(async move { resume::flush(&store, resume::take_dirty(ttl)).await })
+                    .await;
+            }
+        }))
+    }
+
+    fn ready(&self) -> Option<ReadyFuture> {
+        let (Some(store), Some(runtime)) = (self.store.clone(), self.runtime.clone()) else {
+            return Some(Box::pin(async { Err("not initialized".to_owned()) }));
+        };
+        let archive = self.message_archive.clone();
+        // the storage clients belong to the runtime they were created on
+        let check = runtime.spawn(async move {
+            store
+                .health_check()
+                .await
+                .map_err(|e| format!("storage: {}", e))?;
+            if let Some(archive) = archive {
+                archive
+                    .health_check()
+                    .await
+                    .map_err(|e| format!("archive: {}", e))?;
+            }
+            Ok(())
+        });
+        Some(Box::pin(
+            async move { check.await.map_err(|e| e.to_string())? },
+        ))
     }
 
     fn process_req(
         &self,
-        session_id: usize,
+        session: &SessionInfo,
         subscription: &Subscription,
     ) -> ExtensionReqResult {
-        // Check if this is a query for KeyPackages (kind 443)
-        let is_keypackage_query = subscription.filters.iter().any(|filter| {
-            filter.kinds.iter().any(|&k| k == 443)
-        });
-
-        if !is_keypackage_query {
-            return ExtensionReqResult::Continue;
+        if !queries_keypackages(subscription) {
+            return self.resume(session, subscription);
         }
 
+        // Queries served from LMDB get the same per query cap as the Firestore path
+        let lmdb_query =
+            || match cap_keypackage_filters(subscription, self.config.max_keypackages_per_query) {
+                Some(rewritten) => ExtensionReqResult::Rewrite(rewritten),
+                None => ExtensionReqResult::Continue,
+            };
+
         // Extract authors from filters to determine which KeyPackages to query
         let mut authors: Vec<String> = Vec::new();
         for filter in &subscription.filters {
@@ -1485,62 +4522,101 @@ impl Extension for MlsGateway {
 
         if authors.is_empty() {
             // No specific authors requested, let the database handle it
-            return ExtensionReqResult::Continue;
+            return lmdb_query();
         }
 
-        info!("KeyPackage REQ intercepted for session {} with authors: {:?}", session_id, authors);
+        info!(
+            "KeyPackage REQ intercepted for session {} ({:?}) with authors: {:?}",
+            session.id, session.auth_pubkey, authors
+        );
 
         // Clone necessary data for async operation
         let store = match self.store() {
             Ok(store) => store.clone(),
             Err(e) => {
                 error!("MLS Gateway not initialized: {}", e);
-                return ExtensionReqResult::Continue;
+                return lmdb_query();
             }
         };
 
         // Get since timestamp from filters
-        let since = subscription.filters.iter()
+        let since = subscription
+            .filters
+            .iter()
             .filter_map(|f| f.since)
             .max()
             .unwrap_or(0);
 
         // Get limit from filters
-        let limit = subscription.filters.iter()
+        let limit = subscription
+            .filters
+            .iter()
             .filter_map(|f| f.limit)
             .min()
             .map(|l| l as usize)
-            .unwrap_or(1);  // Default to 1 when no limit specified per NIP-EE-RELAY
+            .unwrap_or(1); // Default to 1 when no limit specified per NIP-EE-RELAY
 
         // Enforce max_keypackages_per_query config (default: 1, max: 2)
         let max_keypackages_per_query = self.config.max_keypackages_per_query;
         let query_limit = (limit as u32).min(max_keypackages_per_query).min(2);
 
         let output = keypackage_output_encoding(subscription);
+        // Unauthenticated requesters are limited per IP
+        let requester = session
+            .auth_pubkey
+            .clone()
+            .unwrap_or_else(|| session.ip.clone());
 
         // Create a new single-threaded runtime for the blocking operation
-        let firestore_events = match std::thread::spawn(move || {
+        let (firestore_events, rate_limited, all_limited) = match std::thread::spawn(move || {
             // Create a new runtime in this thread
             let runtime = tokio::runtime::Builder::new_current_thread()
                 .enable_all()
                 .build()
                 .expect("Failed to create runtime");
-            
+
             runtime.block_on(async move {
-                info!("Querying Firestore for KeyPackages with authors: {:?}, limit: {}", authors, query_limit);
-                match store.query_keypackages(
-                    Some(&authors),
-                    Some(since as i64),
-                    Some(query_limit),
-                    Some("created_at_asc"),
-                ).await {
+                let requested = authors.len();
+                let mut authors = authors;
+                if let Some(limiter) = keypackage_consumer::rate_limiter() {
+                    let mut allowed = Vec::with_capacity(requested);
+                    for author in authors {
+                        match limiter.check_rate_limit(&requester, &author).await {
+                            Ok(_) => allowed.push(author),
+                            Err(e) => info!(
+                                "KeyPackage query by {} for {} rate limited: {}",
+                                requester, author, e
+                            ),
+                        }
+                    }
+                    authors = allowed;
+                }
+                let rate_limited = authors.len() < requested;
+                if authors.is_empty() {
+                    return (Vec::new(), rate_limited, true);
+                }
+
+                info!(
+                    "Querying Firestore for KeyPackages with authors: {:?}, limit: {}",
+                    authors, query_limit
+                );
+                let events = match store
+                    .query_keypackages(
+                        Some(&authors),
+                        Some(since as i64),
+                        Some(query_limit),
+                        Some("created_at_asc"),
+                    )
+                    .await
+                {
                     Ok(keypackages) => {
                         info!("Found {} KeyPackages in Firestore", keypackages.len());
-                        
+
                         // Convert Firestore keypackages to Events
                         // We need to reconstruct the full event from Firestore data
                         let mut events = Vec::new();
-                        for (event_id, owner_pubkey, keypackage_content, created_at) in keypackages {
+                        for (event_id, owner_pubkey, keypackage_content, created_at) in keypackages
+                        {
                             match build_synthetic_keypackage_event(
                                 &event_id,
                                 &owner_pubkey,
@@ -1549,7 +4625,10 @@ impl Extension for MlsGateway {
                                 output,
                             ) {
                                 Ok(event) => {
-                                    info!("Successfully reconstructed KeyPackage event {}", event_id);
+                                    info!(
+                                        "Successfully reconstructed KeyPackage event {}",
+                                        event_id
+                                    );
                                     events.push(event);
                                 }
                                 Err(e) => {
@@ -1564,129 +4643,188 @@ impl Extension for MlsGateway {
                         error!("Failed to query Firestore for KeyPackages: {}", e);
                         Vec::new()
                     }
-                }
+                };
+                (events, rate_limited, false)
             })
-        }).join() {
-            Ok(events) => events,
+        })
+        .join()
+        {
+            Ok(result) => result,
             Err(e) => {
                 error!("Thread panic while querying Firestore: {:?}", e);
-                Vec::new()
+                (Vec::new(), false, false)
             }
         };
 
-        if firestore_events.is_empty() {
+        if all_limited {
+            info!(
+                "KeyPackage query by session {} refused, every author rate limited",
+                session.id
+            );
+            ExtensionReqResult::Close(
+                Prefix::RateLimited.with("too many keypackage queries for these authors"),
+            )
+        } else if rate_limited {
+            // Rate limited authors must not be served from LMDB either
+            info!(
+                "Returning {} KeyPackages from Firestore after rate limiting",
+                firestore_events.len()
+            );
+            ExtensionReqResult::Handle(firestore_events)
+        } else if firestore_events.is_empty() {
             info!("No KeyPackages found in Firestore, continuing with LMDB query");
-            ExtensionReqResult::Continue
+            lmdb_query()
         } else {
-            info!("Returning {} KeyPackages from Firestore", firestore_events.len());
+            info!(
+                "Returning {} KeyPackages from Firestore",
+                firestore_events.len()
+            );
             ExtensionReqResult::Handle(firestore_events)
         }
     }
 
+    fn process_count(&self, session: &SessionInfo, subscription: &Subscription) -> Option<u64> {
+        // Only answer COUNTs that ask exclusively for keypackages of specific authors
+        let keypackage_only = !subscription.filters.is_empty()
+            && subscription.filters.iter().all(|filter| {
+                !filter.authors.is_empty()
+                    && !filter.kinds.is_empty()
+                    && filter
+                        .kinds
+                        .iter()
+                        .all(|&k| k == kinds::number(KEYPACKAGE_KIND))
+            });
+        if !keypackage_only {
+            return None;
+        }
+
+        let mut authors: Vec<String> = subscription
+            .filters
+            .iter()
+            .flat_map(|filter| filter.authors.iter().map(hex::encode))
+            .collect();
+        authors.sort();
+        authors.dedup();
+
+        let store = match self.store() {
+            Ok(store) => store.clone(),
+            Err(e) => {
+                error!("MLS Gateway not initialized: {}", e);
+                return None;
+            }
+        };
+
+        info!(
+            "KeyPackage COUNT intercepted for session {} with authors: {:?}",
+            session.id, authors
+        );
+
+        // Same blocking pattern as process_req, the hook is synchronous
+        let result = std::thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .expect("Failed to create runtime");
+
+            runtime.block_on(async move {
+                let mut total = 0u64;
+                for author in &authors {
+                    total += store.count_user_keypackages(author).await? as u64;
+                }
+                Ok::<_, anyhow::Error>(total)
+            })
+        })
+        .join();
+
+        match result {
+            Ok(Ok(count)) => Some(count),
+            Ok(Err(e)) => {
+                error!("Failed to count KeyPackages in Firestore: {}", e);
+                None
+            }
+            Err(e) => {
+                error!("Thread panic while counting KeyPackages: {:?}", e);
+                None
+            }
+        }
+    }
+
+    fn post_processes(&self, session: &SessionInfo, subscription: &Subscription) -> bool {
+        if self.config.resume_subscriptions && session.auth_pubkey.is_some() {
+            return true;
+        }
+        // only results that may hold the kinds filtered below are held back
+        let post_processed = |kind: u16| {
+            matches!(kinds::canonical(kind), KEYPACKAGE_KIND | GIFTWRAP_KIND)
+                || (self.config.recipient_only_delivery && self.config.is_direct_kind(kind))
+                || (self.config.roster_gated_delivery
+                    && kind == roster_snapshot::ROSTER_SNAPSHOT_KIND)
+        };
+        subscription.filters.iter().any(|filter| {
+            filter.kinds.is_empty() || filter.kinds.iter().any(|kind| post_processed(*kind))
+        })
+    }
+
     fn post_process_query_results(
         &self,
-        session_id: usize,
+        session: &SessionInfo,
         subscription: &Subscription,
         mut events: Vec<Event>,
     ) -> PostProcessResult {
-        // Check if this is a keypackage query
-        let is_keypackage_query = subscription.filters.iter().any(|filter| {
-            filter.kinds.iter().any(|&k| k == 443)
-        });
+        // Stored results advance the cursor, withheld ones would be withheld on replay too
+        if self.config.resume_subscriptions && session.auth_pubkey.is_some() {
+            resume::delivered(session.id, &subscription.id, &events);
+        }
 
-        // If it's a keypackage query and we got no results, query Firestore
-        if is_keypackage_query && events.iter().filter(|e| e.kind() == 443).count() == 0 {
-            // Extract authors from filters
-            let mut authors: Vec<String> = Vec::new();
-            for filter in &subscription.filters {
-                for author in filter.authors.iter() {
-                    authors.push(hex::encode(author));
-                }
+        // Acked or expired giftwraps are never redelivered
+        events = mailbox::filter_events(events, self.config.welcome_ttl, nostr_relay::db::now());
+        // KeyPackages past their exp tag wait in LMDB for the next sweep
+        events = keypackage_expiry::filter_events(events, nostr_relay::db::now());
+
+        // Giftwraps and direct messages are only returned to their recipients
+        if self.config.recipient_only_delivery {
+            let before = events.len();
+            events.retain(|e| {
+                delivery::visible(
+                    e,
+                    session.auth_pubkey.as_ref(),
+                    self.config.is_direct_kind(e.kind()),
+                )
+            });
+            let withheld = before - events.len();
+            if withheld > 0 {
+                counter!("mls_gateway_query_results_withheld").increment(withheld as u64);
             }
+        }
 
-            if !authors.is_empty() {
-                let output = keypackage_output_encoding(subscription);
-                info!(
-                    "No KeyPackages found in LMDB for session {}, querying Firestore for authors: {:?}",
-                    session_id, authors
-                );
-
-                // Clone necessary data for async operation
-                let store = match self.store() {
-                    Ok(store) => store.clone(),
-                    Err(e) => {
-                        error!("MLS Gateway not initialized: {}", e);
-                        return PostProcessResult {
-                            events,
-                            consumed_events: vec![],
-                        };
-                    }
+        // Roster snapshots reveal membership, only members read them
+        if self.config.roster_gated_delivery {
+            let before = events.len();
+            events.retain(|e| {
+                let Some(group_id) = delivery::group_id(e)
+                    .filter(|_| e.kind() == roster_snapshot::ROSTER_SNAPSHOT_KIND)
+                else {
+                    return true;
                 };
-
-                // Get since timestamp from filters
-                let since = subscription.filters.iter()
-                    .filter_map(|f| f.since)
-                    .max()
-                    .unwrap_or(0);
-
-                // Get limit from filters
-                let limit = subscription.filters.iter()
-                    .filter_map(|f| f.limit)
-                    .min()
-                    .map(|l| l as usize)
-                    .unwrap_or(100);
-
-                // Query Firestore synchronously using blocking
-                let firestore_events = tokio::task::block_in_place(move || {
-                    let runtime = tokio::runtime::Handle::current();
-                    runtime.block_on(async move {
-                        match store.query_keypackages(
-                            Some(&authors),
-                            Some(since as i64),
-                            Some(limit.min(u32::MAX as usize) as u32),
-                            Some("created_at_asc"),
-                        ).await {
-                            Ok(keypackages) => {
-                                info!("Found {} KeyPackages in Firestore", keypackages.len());
-                                
-                                // Convert Firestore keypackages to Events
-                                let mut firestore_events = Vec::new();
-                                for (event_id, owner_pubkey, keypackage_content, created_at) in keypackages {
-                                    match build_synthetic_keypackage_event(
-                                        &event_id,
-                                        &owner_pubkey,
-                                        created_at,
-                                        &keypackage_content,
-                                        output,
-                                    ) {
-                                        Ok(event) => {
-                                            info!("Successfully reconstructed KeyPackage event {}", event_id);
-                                            firestore_events.push(event);
-                                        }
-                                        Err(e) => {
-                                            error!("{}", e);
-                                            counter!("mls_gateway_443_content_invalid").increment(1);
-                                        }
-                                    }
-                                }
-                                firestore_events
-                            }
-                            Err(e) => {
-                                error!("Failed to query Firestore for KeyPackages: {}", e);
-                                Vec::new()
-                            }
+                delivery::roster_allows(group_id, session.auth_pubkey.as_ref()).unwrap_or_else(
+                    || {
+                        if let Some(store) = self.store.as_ref() {
+                            delivery::ensure_loaded(store, group_id);
                         }
-                    })
-                });
-
-                // Add Firestore events to the results
-                events.extend(firestore_events);
+                        true
+                    },
+                )
+            });
+            let withheld = before - events.len();
+            if withheld > 0 {
+                counter!("mls_gateway_query_results_withheld").increment(withheld as u64);
             }
         }
 
         // Check if any of the events are KeyPackages (kind 443)
-        let keypackage_events: Vec<&Event> = events.iter()
-            .filter(|event| event.kind() == 443)
+        let keypackage_events: Vec<&Event> = events
+            .iter()
+            .filter(|event| kinds::canonical(event.kind()) == KEYPACKAGE_KIND)
             .collect();
 
         if keypackage_events.is_empty() {
@@ -1698,20 +4836,23 @@ impl Extension for MlsGateway {
 
         // Limit KeyPackages per author based on config and REQ filter
         // Check if any filter specifies a limit
-        let requested_limit = subscription.filters.iter()
+        let requested_limit = subscription
+            .filters
+            .iter()
             .filter_map(|f| f.limit)
             .min()
             .map(|l| l as usize);
-        
+
         // Use config default (1) or requested limit, capped at 2
         let max_allowed = std::cmp::min(self.config.max_keypackages_per_query as usize, 2);
         let max_keypackages_per_author = match requested_limit {
             Some(limit) => std::cmp::min(limit, max_allowed),
             None => self.config.max_keypackages_per_query as usize,
         };
-        
-        let mut keypackages_by_author: std::collections::HashMap<Vec<u8>, Vec<&Event>> = std::collections::HashMap::new();
-        
+
+        let mut keypackages_by_author: std::collections::HashMap<Vec<u8>, Vec<&Event>> =
+            std::collections::HashMap::new();
+
         // Group KeyPackages by author
         for event in &keypackage_events {
             keypackages_by_author
@@ -1719,7 +4860,7 @@ impl Extension for MlsGateway {
                 .or_insert_with(Vec::new)
                 .push(event);
         }
-        
+
         // Sort KeyPackages by created_at (oldest first) and take only the limit
         let limited_keypackage_events: Vec<&Event> = keypackages_by_author
             .into_iter()
@@ -1734,11 +4875,37 @@ impl Extension for MlsGateway {
         info!(
             "Post-processing {} KeyPackage(s) for session {} subscription {} (limited from {})",
             limited_keypackage_events.len(),
-            session_id,
+            session.id,
             subscription.id,
             keypackage_events.len()
         );
 
+        // Build set of limited KeyPackage event IDs for filtering
+        let limited_keypackage_ids: std::collections::HashSet<[u8; 32]> =
+            limited_keypackage_events.iter().map(|e| *e.id()).collect();
+
+        // Only NIP-42 authenticated requesters consume KeyPackages and never their own,
+        // anonymous reads and owners listing their bundle leave them in place
+        let requester = session.auth_pubkey.clone();
+        let events_to_consume: Vec<(String, String, String)> = limited_keypackage_events
+            .iter()
+            .map(|event| {
+                let event_id = hex::encode(event.id());
+                let owner_pubkey = hex::encode(event.pubkey());
+                let content = event.content().to_string();
+                (event_id, owner_pubkey, content)
+            })
+            .filter(|(_, owner_pubkey, _)| requester.as_ref().is_some_and(|r| r != owner_pubkey))
+            .collect();
+
+        let events = Self::limit_keypackages(events, &limited_keypackage_ids);
+        if events_to_consume.is_empty() {
+            return PostProcessResult {
+                events,
+                consumed_events: vec![],
+            };
+        }
+
         // Clone necessary data for async processing
         let store = match self.store() {
             Ok(store) => store.clone(),
@@ -1751,79 +4918,52 @@ impl Extension for MlsGateway {
             }
         };
 
-        // Build set of limited KeyPackage event IDs for filtering
-        let limited_keypackage_ids: std::collections::HashSet<[u8; 32]> = limited_keypackage_events
-            .iter()
-            .map(|e| *e.id())
-            .collect();
-
-        let events_to_consume: Vec<(String, String, String)> = limited_keypackage_events.iter()
-            .map(|event| {
-                let event_id = hex::encode(event.id());
-                let owner_pubkey = hex::encode(event.pubkey());
-                let content = event.content().to_string();
-                (event_id, owner_pubkey, content)
-            })
-            .collect();
-        
         let sub_id = subscription.id.clone();
+        let low_threshold = self.config.low_keypackage_threshold;
 
         // Spawn async task to handle consumption
         tokio::spawn(async move {
             use crate::mls_gateway::keypackage_consumer;
-            
+
+            let mut owners = std::collections::BTreeSet::new();
             for (event_id, owner_pubkey, content) in events_to_consume {
-                // Note: We can't get the requester pubkey from session_id alone
-                // For now, we'll consume any KeyPackage that's queried
-                // In production, you might want to track session->pubkey mapping
+                owners.insert(owner_pubkey.clone());
                 match keypackage_consumer::consume_keypackage(
                     &store,
                     &event_id,
                     &owner_pubkey,
                     &content,
-                ).await {
+                )
+                .await
+                {
                     Ok(true) => {
                         info!(
-                            "KeyPackage {} consumed for subscription {}",
-                            event_id, sub_id
+                            "KeyPackage {} consumed for subscription {} by {:?}",
+                            event_id, sub_id, requester
                         );
                         counter!("mls_gateway_keypackages_consumed").increment(1);
                     }
                     Ok(false) => {
                         // KeyPackage was last resort, not consumed
-                        info!(
-                            "KeyPackage {} is last resort, not consumed",
-                            event_id
-                        );
+                        info!("KeyPackage {} is last resort, not consumed", event_id);
                     }
                     Err(e) => {
-                        error!(
-                            "Failed to consume KeyPackage {}: {}",
-                            event_id, e
-                        );
+                        error!("Failed to consume KeyPackage {}: {}", event_id, e);
                     }
                 }
             }
-        });
 
-        // Filter events to return only limited KeyPackages and non-KeyPackage events
-        let filtered_events: Vec<Event> = events
-            .into_iter()
-            .filter(|event| {
-                if event.kind() == 443 {
-                    // Only include KeyPackages that are in our limited set
-                    limited_keypackage_ids.contains(event.id())
-                } else {
-                    // Include all non-KeyPackage events
-                    true
+            for owner in owners {
+                if let Err(e) = low_keypackages::check(&store, &owner, low_threshold).await {
+                    warn!("Low KeyPackage check failed for {}: {}", owner, e);
                 }
-            })
-            .collect();
+            }
+        });
 
         // Return filtered events to the client
         // The actual consumption happens asynchronously
         PostProcessResult {
-            events: filtered_events,
+            events,
             consumed_events: vec![],
         }
     }
@@ -1831,48 +4971,64 @@ impl Extension for MlsGateway {
 
 impl MlsGateway {
     /// Static version of handle_mls_group_message for use in async context
-    async fn handle_mls_group_message_static(store: StorageBackend, config: MlsGatewayConfig, event: &Event) -> anyhow::Result<()> {
+    async fn handle_mls_group_message_static(
+        store: StorageBackend,
+        config: MlsGatewayConfig,
+        event: &Event,
+    ) -> anyhow::Result<()> {
         // Extract group ID and epoch from tags
-
-        // Outer tag hygiene (non-sensitive): warn on unexpected tags per NIP-EE (allow only "h" and optional "k")
-        let unexpected_tag_count = event.tags().iter()
-            .filter(|tag| !tag.is_empty())
-            .filter(|tag| {
-                let key = &tag[0];
-                !(key == "h" || key == "k" || key == "mls_ver")
-            })
-            .count();
-        if unexpected_tag_count > 0 {
-            warn!("kind 445 contains non-standard outer tags: count={}", unexpected_tag_count);
-            counter!("mls_gateway_445_unexpected_tag").increment(unexpected_tag_count as u64);
-        }
-
-        let group_id_opt = event.tags().iter()
+        // (outer tag hygiene per NIP-EE is checked at ingress per validation_mode)
+        let group_id_opt = event
+            .tags()
+            .iter()
             .find(|tag| tag.len() >= 2 && tag[0] == "h")
             .map(|tag| tag[1].clone());
-            
-        let epoch = event.tags().iter()
+
+        let epoch = event
+            .tags()
+            .iter()
             .find(|tag| tag.len() >= 2 && tag[0] == "k")
             .and_then(|tag| tag[1].parse::<i64>().ok());
 
         if let Some(ref group_id) = group_id_opt {
             // Update group registry
-            store.upsert_group(
-                group_id,
-                None, // display_name from content if needed
-                &hex::encode(event.pubkey()),
-                epoch.unwrap_or(0) as u64,
-            ).await?;
-            
+            store
+                .upsert_group(
+                    group_id,
+                    None, // display_name from content if needed
+                    &hex::encode(event.pubkey()),
+                    epoch.unwrap_or(0) as u64,
+                )
+                .await?;
+
             counter!("mls_gateway_groups_updated").increment(1);
             info!("Updated group registry for group: {}", group_id);
+
+            if config.group_stats_enabled {
+                match store
+                    .record_group_activity(group_id, &hex::encode(event.pubkey()), epoch)
+                    .await
+                {
+                    Ok(_) => counter!("mls_gateway_group_messages_counted", "result" => "ok")
+                        .increment(1),
+                    Err(e) => {
+                        counter!("mls_gateway_group_messages_counted", "result" => "error")
+                            .increment(1);
+                        warn!("Failed to record activity of group {}: {}", group_id, e);
+                    }
+                }
+            }
         }
 
         // Membership-first gating for MLS-first decrypt/dispatch
         #[cfg(feature = "nip_service_mls")]
         if let Some(ref group_id) = group_id_opt {
             // 1) Handler selection and global enable
-            if !config.enable_in_process_decrypt || config.preferred_service_handler.to_lowercase() != "in-process" {
+            // decrypt stays in-process, "external" only changes who handles the decrypted request
+            let handler = config.preferred_service_handler.to_lowercase();
+            if !config.enable_in_process_decrypt
+                || (handler != "in-process" && handler != "external")
+            {
                 counter!("mls_gateway_events_processed", "kind" => "445_nip_service_handler_disabled").increment(1);
             } else {
                 // 2) Optional registry hint prefilter (policy/ops only)
@@ -1880,10 +5036,14 @@ impl MlsGateway {
                 if config.gating_use_registry_hint {
                     #[cfg(feature = "mls_gateway_firestore")]
                     {
-                        let is_service_enabled = match &store {
-                            StorageBackend::Firestore(storage) => storage.has_service_member(group_id).await.unwrap_or(false),
+                        let is_service_enabled = match store.current() {
+                            Backend::Firestore(storage) => {
+                                storage.has_service_member(group_id).await.unwrap_or(false)
+                            }
                             #[cfg(feature = "mls_gateway_sql")]
-                            StorageBackend::Sql(_storage) => false,
+                            Backend::Sql(storage) => {
+                                storage.has_service_member(group_id).await.unwrap_or(false)
+                            }
                         };
                         if !is_service_enabled {
                             counter!("mls_gateway_events_processed", "kind" => "445_nip_service_policy_hint_skip").increment(1);
@@ -1898,16 +5058,52 @@ impl MlsGateway {
 
                 // 3) Membership-first gating (fast in-memory)
                 if allowed {
-                    if let Some(user_id) = config.mls_service_user_id.as_deref() {
+                    if let Some(user_id) = config.service_user_id(group_id) {
                         if crate::mls_gateway::service_member::has_group(user_id, group_id) {
-                            // Try to decrypt via service member (dev stub for now)
-                            if let Some(json) = crate::mls_gateway::service_member::try_decrypt_service_request(event).await {
-                                // Dispatch decrypted NIP-SERVICE payload without exposing plaintext outside this scope
-                                crate::nip_service::dispatcher::handle_service_request_payload(&json, Some(group_id.as_str()));
-                                counter!("mls_gateway_events_processed", "kind" => "445_nip_service_decrypted").increment(1);
-                            } else {
-                                // Not a NIP-SERVICE payload or decrypt failed; content remains opaque
-                                counter!("mls_gateway_events_processed", "kind" => "445_nip_service_decrypt_skip").increment(1);
+                            use crate::mls_gateway::service_member::GroupMessage;
+                            match crate::mls_gateway::service_member::process_group_message(
+                                event, user_id,
+                            )
+                            .await
+                            {
+                                GroupMessage::ServiceRequest(json) => {
+                                    // Dispatch decrypted NIP-SERVICE payload without exposing plaintext outside this scope
+                                    // The 445 author must hold an admin role in the group roster (checked before execution)
+                                    let sender = hex::encode(event.pubkey());
+                                    crate::nip_service::dispatcher::handle_service_request_payload(
+                                        &json,
+                                        Some(group_id.as_str()),
+                                        Some(&sender),
+                                    );
+                                    counter!("mls_gateway_events_processed", "kind" => "445_nip_service_decrypted").increment(1);
+                                }
+                                GroupMessage::Handshake { epoch } => {
+                                    // Record the epoch the service member advanced to, a commit never registers a group
+                                    if store.group_exists(group_id).await? {
+                                        store
+                                            .upsert_group(
+                                                group_id,
+                                                None,
+                                                &hex::encode(event.pubkey()),
+                                                epoch,
+                                            )
+                                            .await?;
+                                        counter!("mls_gateway_events_processed", "kind" => "445_nip_service_commit").increment(1);
+                                    } else {
+                                        warn!(
+                                            "Service member commit for unregistered group {}",
+                                            group_id
+                                        );
+                                        counter!("mls_gateway_events_processed", "kind" => "445_nip_service_commit_unknown_group").increment(1);
+                                    }
+                                }
+                                GroupMessage::Skipped => {
+                                    // Not a NIP-SERVICE payload; content remains opaque
+                                    counter!("mls_gateway_events_processed", "kind" => "445_nip_service_decrypt_skip").increment(1);
+                                }
+                                GroupMessage::Rejected => {
+                                    counter!("mls_gateway_events_processed", "kind" => "445_nip_service_rejected").increment(1);
+                                }
                             }
                         } else {
                             counter!("mls_gateway_events_processed", "kind" => "445_nip_service_not_member").increment(1);
@@ -1928,13 +5124,103 @@ impl MlsGateway {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use nostr_relay::db::Event;
     use chrono::Utc;
+    use nostr_relay::db::Event;
+
+    #[test]
+    fn test_config_validate_resets_last_resort_thresholds() {
+        let mut config = MlsGatewayConfig {
+            last_resort_deletion_delay_secs: 0,
+            last_resort_min_keypackages: 1,
+            ..Default::default()
+        };
+        assert_eq!(
+            config.validate(),
+            vec![
+                "last_resort_deletion_delay_secs",
+                "last_resort_min_keypackages"
+            ]
+        );
+        assert_eq!(config.last_resort_deletion_delay_secs, 600);
+        assert_eq!(config.last_resort_min_keypackages, 3);
+
+        config.last_resort_deletion_delay_secs = 60;
+        config.last_resort_min_keypackages = 2;
+        assert!(config.validate().is_empty());
+    }
+
+    #[test]
+    fn test_config_validate_default_kind_lists_follow_kind_map() {
+        let mut config = MlsGatewayConfig::default();
+        config.kinds.giftwrap = 21059;
+        config.export_kinds = vec![445];
+        assert!(config.validate().is_empty());
+        assert_eq!(config.archived_kinds, vec![445, 446, 21059]);
+        assert_eq!(config.backfill_kinds, vec![445, 21059, 446]);
+        // lists set in the config are kept as written
+        assert_eq!(config.export_kinds, vec![445]);
+    }
+
+    #[test]
+    fn test_roster_operation_apply() {
+        let op = |operation: &str, members: &[&str], role_admin: bool| firestore::RosterOperation {
+            group_id: "g".to_owned(),
+            sequence: 1,
+            operation: operation.to_owned(),
+            member_pubkeys: members.iter().map(|m| m.to_string()).collect(),
+            admin_pubkey: "owner".to_owned(),
+            role_admin,
+            created_at: 0,
+        };
+        let now = Utc::now();
+        let group = op("bootstrap", &["alice"], false).apply(None, now).unwrap();
+        assert_eq!(
+            (group.owner_pubkey.as_str(), group.admin_pubkeys.clone()),
+            ("owner", vec!["owner".to_owned()])
+        );
+
+        let promoted = op("promote", &["alice", "owner"], true)
+            .apply(Some(group.clone()), now)
+            .unwrap();
+        assert_eq!(promoted.admin_pubkeys, vec!["owner", "alice"]);
+        let demoted = op("demote", &["alice"], true)
+            .apply(Some(promoted), now)
+            .unwrap();
+        assert_eq!(demoted.admin_pubkeys, vec!["owner"]);
+
+        let transferred = op("transfer", &["alice"], false)
+            .apply(Some(group.clone()), now)
+            .unwrap();
+        assert_eq!(
+            (transferred.owner_pubkey.as_str(), transferred.admin_pubkeys),
+            ("alice", vec!["owner".to_owned(), "alice".to_owned()])
+        );
+        assert!(op("transfer", &["alice", "bob"], false)
+            .apply(Some(group.clone()), now)
+            .is_none());
+        assert!(op("delete", &[], false)
+            .apply(Some(group.clone()), now)
+            .is_none());
+
+        assert!(op("promote", &["alice"], false)
+            .apply(Some(group.clone()), now)
+            .is_none());
+        assert!(op("promote", &["alice"], true).apply(None, now).is_none());
+        assert!(op("remove", &["alice"], false)
+            .apply(Some(group), now)
+            .is_none());
+    }
 
     #[test]
     fn test_keypackage_output_encoding_default_hex() {
-        let subscription = Subscription { id: "s".into(), filters: vec![] };
-        assert_eq!(keypackage_output_encoding(&subscription), KeyPackageOutputEncoding::Hex);
+        let subscription = Subscription {
+            id: "s".into(),
+            filters: vec![],
+        };
+        assert_eq!(
+            keypackage_output_encoding(&subscription),
+            KeyPackageOutputEncoding::Hex
+        );
     }
 
     #[test]
@@ -1944,8 +5230,14 @@ mod tests {
             "f".to_string(),
             vec!["base64".to_string()],
         )]));
-        let subscription = Subscription { id: "s".into(), filters: vec![filter] };
-        assert_eq!(keypackage_output_encoding(&subscription), KeyPackageOutputEncoding::Base64);
+        let subscription = Subscription {
+            id: "s".into(),
+            filters: vec![filter],
+        };
+        assert_eq!(
+            keypackage_output_encoding(&subscription),
+            KeyPackageOutputEncoding::Base64
+        );
     }
 
     #[test]
@@ -1995,13 +5287,14 @@ mod tests {
             .iter()
             .any(|t| t.len() >= 2 && t[0] == "encoding"));
     }
-    
+
     // Mock storage backend for testing
     struct MockStorage {
         keypackages: std::sync::Arc<std::sync::Mutex<Vec<(String, String, i64)>>>, // (id, owner, created_at)
-        pending_deletions: std::sync::Arc<std::sync::Mutex<Vec<crate::mls_gateway::firestore::PendingDeletion>>>,
+        pending_deletions:
+            std::sync::Arc<std::sync::Mutex<Vec<crate::mls_gateway::firestore::PendingDeletion>>>,
     }
-    
+
     impl MockStorage {
         fn new() -> Self {
             Self {
@@ -2009,17 +5302,17 @@ mod tests {
                 pending_deletions: std::sync::Arc::new(std::sync::Mutex::new(Vec::new())),
             }
         }
-        
+
         async fn add_keypackage(&self, id: &str, owner: &str) {
             let mut kps = self.keypackages.lock().unwrap();
             kps.push((id.to_string(), owner.to_string(), Utc::now().timestamp()));
         }
-        
+
         async fn count_keypackages(&self, owner: &str) -> usize {
             let kps = self.keypackages.lock().unwrap();
             kps.iter().filter(|(_, o, _)| o == owner).count()
         }
-        
+
         async fn get_oldest_keypackage(&self, owner: &str) -> Option<String> {
             let kps = self.keypackages.lock().unwrap();
             kps.iter()
@@ -2027,45 +5320,48 @@ mod tests {
                 .min_by_key(|(_, _, created)| *created)
                 .map(|(id, _, _)| id.clone())
         }
-        
+
         async fn has_pending_deletion(&self, owner: &str) -> bool {
             let pds = self.pending_deletions.lock().unwrap();
             pds.iter().any(|pd| pd.user_pubkey == owner)
         }
-        
-        async fn get_pending_deletion(&self, owner: &str) -> Option<crate::mls_gateway::firestore::PendingDeletion> {
+
+        async fn get_pending_deletion(
+            &self,
+            owner: &str,
+        ) -> Option<crate::mls_gateway::firestore::PendingDeletion> {
             let pds = self.pending_deletions.lock().unwrap();
             pds.iter().find(|pd| pd.user_pubkey == owner).cloned()
         }
     }
-    
+
     #[tokio::test]
     async fn test_last_resort_timer_not_started_with_zero_keypackages() {
         let storage = MockStorage::new();
         let owner = "test_user";
-        
+
         // User has 0 keypackages initially
         assert_eq!(storage.count_keypackages(owner).await, 0);
-        
+
         // Upload first keypackage
         storage.add_keypackage("kp1", owner).await;
-        
+
         // No timer should be started (user went from 0 to 1 keypackage)
         assert!(!storage.has_pending_deletion(owner).await);
     }
-    
+
     #[tokio::test]
     async fn test_last_resort_timer_started_with_one_keypackage() {
         let storage = MockStorage::new();
         let owner = "test_user";
-        
+
         // User has 1 keypackage initially
         storage.add_keypackage("kp1", owner).await;
         assert_eq!(storage.count_keypackages(owner).await, 1);
-        
+
         // Upload second keypackage - this should trigger timer
         storage.add_keypackage("kp2", owner).await;
-        
+
         // Simulate timer creation
         let pending = crate::mls_gateway::firestore::PendingDeletion {
             user_pubkey: owner.to_string(),
@@ -2075,40 +5371,40 @@ mod tests {
             deletion_scheduled_at: Utc::now() + chrono::Duration::minutes(10),
         };
         storage.pending_deletions.lock().unwrap().push(pending);
-        
+
         // Timer should be started
         assert!(storage.has_pending_deletion(owner).await);
         let pd = storage.get_pending_deletion(owner).await.unwrap();
         assert_eq!(pd.old_keypackage_id, "kp1");
         assert_eq!(pd.new_keypackages_collected.len(), 1);
     }
-    
+
     #[tokio::test]
     async fn test_last_resort_timer_not_started_with_multiple_keypackages() {
         let storage = MockStorage::new();
         let owner = "test_user";
-        
+
         // User has 2 keypackages initially
         storage.add_keypackage("kp1", owner).await;
         storage.add_keypackage("kp2", owner).await;
         assert_eq!(storage.count_keypackages(owner).await, 2);
-        
+
         // Upload third keypackage
         storage.add_keypackage("kp3", owner).await;
-        
+
         // No timer should be started (user already had 2+ keypackages)
         assert!(!storage.has_pending_deletion(owner).await);
     }
-    
+
     #[tokio::test]
     async fn test_deletion_cancelled_if_not_enough_keypackages() {
         let storage = MockStorage::new();
         let owner = "test_user";
-        
+
         // Set up scenario: user had 1 kp, uploaded 1 more
         storage.add_keypackage("kp1", owner).await;
         storage.add_keypackage("kp2", owner).await;
-        
+
         // Create pending deletion that's already expired
         let pending = crate::mls_gateway::firestore::PendingDeletion {
             user_pubkey: owner.to_string(),
@@ -2118,54 +5414,58 @@ mod tests {
             deletion_scheduled_at: Utc::now() - chrono::Duration::minutes(5),
         };
         storage.pending_deletions.lock().unwrap().push(pending);
-        
+
         // With only 2 keypackages, deletion should be cancelled
         assert_eq!(storage.count_keypackages(owner).await, 2);
-        
+
         // In real implementation, process_pending_deletion would:
         // 1. Check keypackage count (2 < 3)
         // 2. Cancel the deletion
         // 3. Remove pending deletion record
     }
-    
+
     #[tokio::test]
     async fn test_deletion_proceeds_with_enough_keypackages() {
         let storage = MockStorage::new();
         let owner = "test_user";
-        
+
         // Set up scenario: user had 1 kp, uploaded 3 more
         storage.add_keypackage("kp1", owner).await;
         storage.add_keypackage("kp2", owner).await;
         storage.add_keypackage("kp3", owner).await;
         storage.add_keypackage("kp4", owner).await;
-        
+
         // Create pending deletion that's already expired
         let pending = crate::mls_gateway::firestore::PendingDeletion {
             user_pubkey: owner.to_string(),
             old_keypackage_id: "kp1".to_string(),
-            new_keypackages_collected: vec!["kp2".to_string(), "kp3".to_string(), "kp4".to_string()],
+            new_keypackages_collected: vec![
+                "kp2".to_string(),
+                "kp3".to_string(),
+                "kp4".to_string(),
+            ],
             timer_started_at: Utc::now() - chrono::Duration::minutes(15),
             deletion_scheduled_at: Utc::now() - chrono::Duration::minutes(5),
         };
         storage.pending_deletions.lock().unwrap().push(pending);
-        
+
         // With 4 keypackages (>= 3), deletion should proceed
         assert_eq!(storage.count_keypackages(owner).await, 4);
-        
+
         // In real implementation, process_pending_deletion would:
         // 1. Check keypackage count (4 >= 3)
         // 2. Delete old keypackage (kp1)
         // 3. Remove pending deletion record
     }
-    
+
     #[tokio::test]
     async fn test_concurrent_uploads_during_timer() {
         let storage = MockStorage::new();
         let owner = "test_user";
-        
+
         // User starts with 1 keypackage
         storage.add_keypackage("kp1", owner).await;
-        
+
         // Upload triggers timer
         storage.add_keypackage("kp2", owner).await;
         let pending = crate::mls_gateway::firestore::PendingDeletion {
@@ -2176,11 +5476,11 @@ mod tests {
             deletion_scheduled_at: Utc::now() + chrono::Duration::minutes(10),
         };
         storage.pending_deletions.lock().unwrap().push(pending);
-        
+
         // More uploads during timer period
         storage.add_keypackage("kp3", owner).await;
         storage.add_keypackage("kp4", owner).await;
-        
+
         // Update pending deletion with new keypackages
         let mut pds = storage.pending_deletions.lock().unwrap();
         if let Some(pd) = pds.iter_mut().find(|pd| pd.user_pubkey == owner) {
@@ -2188,7 +5488,7 @@ mod tests {
             pd.new_keypackages_collected.push("kp4".to_string());
         }
         drop(pds);
-        
+
         // Verify state
         assert_eq!(storage.count_keypackages(owner).await, 4);
         let pd = storage.get_pending_deletion(owner).await.unwrap();
@@ -0,0 +1,236 @@
+//! Runtime pubkey and kind allow/deny lists
+//!
+//! Rules live in storage (`moderation_rules`) and are managed through the admin
+//! API under `{api_prefix}/admin/moderation`, so abusive keys can be blocked
+//! without a config rollout. Each instance caches the rules, applies its own
+//! admin changes immediately and reloads the rest every [`REFRESH_INTERVAL`].
+//!
+//! Events are checked before any MLS handler:
+//! - a denied pubkey or kind is rejected with OK false `blocked:`
+//! - once any pubkey (or kind) is allowlisted, other pubkeys (or kinds) are rejected
+//! - deny rules take precedence over allow rules
+
+use super::firestore::ModerationRule;
+use super::StorageBackend;
+use anyhow::Result;
+use nostr_relay::db::Event;
+use once_cell::sync::Lazy;
+use parking_lot::RwLock;
+use std::{collections::HashSet, time::Duration};
+use tracing::{info, warn};
+
+/// Interval between rule reloads from storage
+const REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+pub const ALLOW: &str = "allow";
+pub const DENY: &str = "deny";
+pub const PUBKEY: &str = "pubkey";
+pub const KIND: &str = "kind";
+
+static RULES: Lazy<RwLock<Rules>> = Lazy::new(|| RwLock::new(Rules::default()));
+
+/// Cached rule sets
+#[derive(Debug, Default)]
+pub struct Rules {
+    allow_pubkeys: HashSet<String>,
+    deny_pubkeys: HashSet<String>,
+    allow_kinds: HashSet<u16>,
+    deny_kinds: HashSet<u16>,
+}
+
+impl Rules {
+    fn from_rules(rules: &[ModerationRule]) -> Self {
+        let mut sets = Self::default();
+        for rule in rules {
+            sets.insert(rule);
+        }
+        sets
+    }
+
+    fn insert(&mut self, rule: &ModerationRule) {
+        match (rule.list.as_str(), rule.target.as_str()) {
+            (ALLOW, PUBKEY) => {
+                self.allow_pubkeys.insert(rule.value.clone());
+            }
+            (DENY, PUBKEY) => {
+                self.deny_pubkeys.insert(rule.value.clone());
+            }
+            (ALLOW, KIND) => {
+                self.allow_kinds.extend(rule.value.parse::<u16>());
+            }
+            (DENY, KIND) => {
+                self.deny_kinds.extend(rule.value.parse::<u16>());
+            }
+            _ => warn!("Ignoring moderation rule {}:{}", rule.list, rule.target),
+        }
+    }
+
+    fn remove(&mut self, list: &str, target: &str, value: &str) {
+        match (list, target) {
+            (ALLOW, PUBKEY) => {
+                self.allow_pubkeys.remove(value);
+            }
+            (DENY, PUBKEY) => {
+                self.deny_pubkeys.remove(value);
+            }
+            (ALLOW, KIND) => {
+                if let Ok(kind) = value.parse() {
+                    self.allow_kinds.remove(&kind);
+                }
+            }
+            (DENY, KIND) => {
+                if let Ok(kind) = value.parse() {
+                    self.deny_kinds.remove(&kind);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Rejection reason of an event
+    pub fn check(&self, pubkey: &str, kind: u16) -> Result<(), &'static str> {
+        if self.deny_pubkeys.contains(pubkey) {
+            return Err("pubkey is denied");
+        }
+        if self.deny_kinds.contains(&kind) {
+            return Err("kind is denied");
+        }
+        if !self.allow_pubkeys.is_empty() && !self.allow_pubkeys.contains(pubkey) {
+            return Err("pubkey is not allowed");
+        }
+        if !self.allow_kinds.is_empty() && !self.allow_kinds.contains(&kind) {
+            return Err("kind is not allowed");
+        }
+        Ok(())
+    }
+}
+
+/// Check an event against the cached rules
+pub fn check(event: &Event) -> Result<(), &'static str> {
+    RULES.read().check(&event.pubkey_str(), event.kind())
+}
+
+/// Normalize and validate the list, target and value of a rule
+pub fn normalize(
+    list: &str,
+    target: &str,
+    value: &str,
+) -> Result<(String, String, String), String> {
+    let list = list.to_lowercase();
+    let target = target.to_lowercase();
+    if list != ALLOW && list != DENY {
+        return Err(format!("unknown list {}", list));
+    }
+    let value = match target.as_str() {
+        PUBKEY => {
+            let value = value.to_lowercase();
+            if value.len() != 64 || hex::decode(&value).is_err() {
+                return Err("pubkey must be 64 hex characters".to_string());
+            }
+            value
+        }
+        KIND => value
+            .parse::<u16>()
+            .map_err(|_| format!("invalid kind {}", value))?
+            .to_string(),
+        _ => return Err(format!("unknown target {}", target)),
+    };
+    Ok((list, target, value))
+}
+
+/// Store a rule and apply it on this instance
+pub async fn add(store: &StorageBackend, rule: &ModerationRule) -> Result<()> {
+    store.put_moderation_rule(rule).await?;
+    RULES.write().insert(rule);
+    info!(
+        "Added moderation rule {}:{}:{}",
+        rule.list, rule.target, rule.value
+    );
+    Ok(())
+}
+
+/// Remove a rule and apply the removal on this instance
+pub async fn remove(store: &StorageBackend, list: &str, target: &str, value: &str) -> Result<bool> {
+    let removed = store.delete_moderation_rule(list, target, value).await?;
+    RULES.write().remove(list, target, value);
+    if removed {
+        info!("Removed moderation rule {}:{}:{}", list, target, value);
+    }
+    Ok(removed)
+}
+
+/// Replace the cached rules with the stored ones
+pub async fn reload(store: &StorageBackend) -> Result<usize> {
+    let rules = store.list_moderation_rules().await?;
+    *RULES.write() = Rules::from_rules(&rules);
+    Ok(rules.len())
+}
+
+/// Load the rules and keep reloading them
+pub fn spawn_refresh(store: StorageBackend) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(REFRESH_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = reload(&store).await {
+                warn!("Failed to reload moderation rules: {}", e);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    fn rule(list: &str, target: &str, value: &str) -> ModerationRule {
+        ModerationRule {
+            list: list.to_string(),
+            target: target.to_string(),
+            value: value.to_string(),
+            reason: None,
+            created_at: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn allow_and_deny() {
+        let alice = "aa".repeat(32);
+        let bob = "bb".repeat(32);
+
+        let mut rules = Rules::from_rules(&[rule(DENY, PUBKEY, &bob), rule(DENY, KIND, "4")]);
+        assert!(rules.check(&alice, 1).is_ok());
+        assert_eq!(rules.check(&bob, 1), Err("pubkey is denied"));
+        assert_eq!(rules.check(&alice, 4), Err("kind is denied"));
+
+        // an allowlist excludes everyone else, deny still wins
+        rules.insert(&rule(ALLOW, PUBKEY, &alice));
+        rules.insert(&rule(ALLOW, PUBKEY, &bob));
+        assert_eq!(
+            rules.check(&"cc".repeat(32), 1),
+            Err("pubkey is not allowed")
+        );
+        assert_eq!(rules.check(&bob, 1), Err("pubkey is denied"));
+
+        rules.insert(&rule(ALLOW, KIND, "443"));
+        assert!(rules.check(&alice, 443).is_ok());
+        assert_eq!(rules.check(&alice, 1), Err("kind is not allowed"));
+        rules.remove(ALLOW, KIND, "443");
+        assert!(rules.check(&alice, 1).is_ok());
+    }
+
+    #[test]
+    fn normalize_rules() {
+        let pubkey = "AB".repeat(32);
+        assert_eq!(
+            normalize("Deny", "pubkey", &pubkey),
+            Ok((DENY.to_string(), PUBKEY.to_string(), "ab".repeat(32)))
+        );
+        assert!(normalize("deny", "pubkey", "abc").is_err());
+        assert_eq!(normalize("allow", "kind", "445").unwrap().2, "445");
+        assert!(normalize("allow", "kind", "70000").is_err());
+        assert!(normalize("block", "kind", "1").is_err());
+        assert!(normalize("deny", "ip", "1").is_err());
+    }
+}
@@ -0,0 +1,139 @@
+//! NIP-29 view of MLS groups
+//!
+//! With `nip29_groups` the relay mirrors its group registry into NIP-29
+//! relay-generated group events, signed with the service key and addressed by
+//! group id (`d` tag), so generic NIP-29 clients can discover and display
+//! groups managed by the gateway:
+//! - 39000 metadata: name (display name or group id), `private` and `closed`
+//!   since messages are MLS encrypted and joining takes a Welcome
+//! - 39001 admins: owner and admins as `p` tags with their role
+//! - 39002 members: roster members, only with `nip29_members` as it makes
+//!   the roster public
+//!
+//! Events are republished after every applied roster/policy event (450).
+//! Clients cannot publish these kinds while the mirror is enabled.
+
+use super::firestore::GroupInfo;
+use super::roster_snapshot::RosterSnapshot;
+use super::StorageBackend;
+use anyhow::Result;
+
+pub const GROUP_METADATA_KIND: u16 = 39000;
+pub const GROUP_ADMINS_KIND: u16 = 39001;
+pub const GROUP_MEMBERS_KIND: u16 = 39002;
+
+/// Whether a kind is one of the mirrored NIP-29 group kinds
+pub fn is_group_kind(kind: u16) -> bool {
+    matches!(
+        kind,
+        GROUP_METADATA_KIND | GROUP_ADMINS_KIND | GROUP_MEMBERS_KIND
+    )
+}
+
+fn d_tag(group_id: &str) -> Vec<String> {
+    vec!["d".to_string(), group_id.to_string()]
+}
+
+/// Kinds and tags of the NIP-29 events of a group
+pub fn events(
+    roster: &RosterSnapshot,
+    group: Option<&GroupInfo>,
+    members: bool,
+) -> Vec<(u16, Vec<Vec<String>>)> {
+    let name = group
+        .and_then(|g| g.display_name.clone())
+        .unwrap_or_else(|| roster.group_id.clone());
+    let metadata = vec![
+        d_tag(&roster.group_id),
+        vec!["name".to_string(), name],
+        vec!["private".to_string()],
+        vec!["closed".to_string()],
+    ];
+
+    let mut admins = vec![d_tag(&roster.group_id)];
+    if let Some(owner) = &roster.owner {
+        admins.push(vec!["p".to_string(), owner.clone(), "owner".to_string()]);
+    }
+    admins.extend(
+        roster
+            .admins
+            .iter()
+            .filter(|p| roster.owner.as_ref() != Some(*p))
+            .map(|p| vec!["p".to_string(), p.clone(), "admin".to_string()]),
+    );
+
+    let mut events = vec![(GROUP_METADATA_KIND, metadata), (GROUP_ADMINS_KIND, admins)];
+    if members {
+        let mut tags = vec![d_tag(&roster.group_id)];
+        tags.extend(
+            roster
+                .members
+                .iter()
+                .map(|p| vec!["p".to_string(), p.clone()]),
+        );
+        events.push((GROUP_MEMBERS_KIND, tags));
+    }
+    events
+}
+
+/// Sign and publish the NIP-29 events of a group, returns their ids (none without a service key)
+#[cfg(feature = "nip_service")]
+pub async fn publish(store: &StorageBackend, group_id: &str, members: bool) -> Result<Vec<String>> {
+    use crate::nip_service::emit;
+
+    if emit::service_pubkey().is_none() {
+        return Ok(Vec::new());
+    }
+    let group = store.get_group(group_id).await?;
+    let history = store.list_roster_history(group_id).await?;
+    let roster = RosterSnapshot::build(group_id, group.as_ref(), &history);
+    events(&roster, group.as_ref(), members)
+        .into_iter()
+        .map(|(kind, tags)| emit::emit(kind, tags, String::new()))
+        .collect()
+}
+
+#[cfg(not(feature = "nip_service"))]
+pub async fn publish(
+    _store: &StorageBackend,
+    _group_id: &str,
+    _members: bool,
+) -> Result<Vec<String>> {
+    Ok(Vec::new())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn group_events() {
+        let roster = RosterSnapshot {
+            group_id: "g".to_owned(),
+            sequence: 2,
+            owner: Some("owner".to_owned()),
+            members: vec!["alice".to_owned(), "owner".to_owned()],
+            admins: vec!["alice".to_owned(), "owner".to_owned()],
+        };
+        let published = events(&roster, None, false);
+        assert_eq!(published.len(), 2);
+        assert_eq!(published[0].0, GROUP_METADATA_KIND);
+        assert_eq!(published[0].1[1], vec!["name", "g"]);
+        assert_eq!(
+            published[1].1,
+            vec![
+                vec!["d", "g"],
+                vec!["p", "owner", "owner"],
+                vec!["p", "alice", "admin"]
+            ]
+        );
+
+        let with_members = events(&roster, None, true);
+        assert_eq!(with_members[2].0, GROUP_MEMBERS_KIND);
+        assert_eq!(
+            with_members[2].1[1..],
+            [vec!["p", "alice"], vec!["p", "owner"]]
+        );
+        assert!(is_group_kind(39002) && !is_group_kind(39003));
+    }
+}
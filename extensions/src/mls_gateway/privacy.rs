@@ -0,0 +1,286 @@
+//! Per-user data export and erasure (GDPR)
+//!
+//! A user exports or erases the data held for their own pubkey through
+//! `GET {api_prefix}/privacy/export` and `DELETE {api_prefix}/privacy/data`,
+//! authenticated with NIP-98. Operators do the same for any pubkey through the
+//! admin API (`{api_prefix}/admin/users/{pubkey}`), which `rnostr privacy` calls.
+//!
+//! The bundle holds the MLS storage documents (keypackages, relay lists, push
+//! tokens, welcomes, group roles and roster records), the archived messages
+//! authored by or addressed to the pubkey and the matching LMDB events.
+//! Erasure removes the storage documents, the events authored by the pubkey and
+//! the giftwraps addressed to it. Events of other authors that only mention the
+//! pubkey (notes, contact lists, reports, Noise DMs) are kept. LMDB is only
+//! erased on the instance serving the request, other instances keep their
+//! copies until they are rebuilt from the archive. Both operations need the
+//! Firestore backend and are refused with the SQL backend.
+
+use super::firestore::UserErasure;
+use super::message_archive::MessageArchive;
+use super::{http_auth, kinds, StorageBackend, GIFTWRAP_KIND};
+use actix_web::{web, HttpRequest, HttpResponse, Result as ActixResult};
+use anyhow::Result;
+use metrics::counter;
+use nostr_relay::{
+    db::{Db, Event, Filter},
+    App,
+};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::{collections::HashSet, str::FromStr};
+use tracing::{info, warn};
+
+/// Result of an erasure
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ErasureReport {
+    pub pubkey: String,
+    pub lmdb_events: usize,
+    pub archived_events: u64,
+    pub storage: UserErasure,
+}
+
+pub struct PrivacyState {
+    pub store: StorageBackend,
+    pub archive: Option<MessageArchive>,
+}
+
+/// Whether `pubkey` is a lowercase hex pubkey
+pub fn valid_pubkey(pubkey: &str) -> bool {
+    pubkey.len() == 64
+        && pubkey
+            .bytes()
+            .all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// LMDB events matching any of the filters, without duplicates
+fn lmdb_events(db: &Db, filters: &[Value]) -> Result<Vec<Event>> {
+    let reader = db.reader()?;
+    let mut seen = HashSet::new();
+    let mut events = Vec::new();
+    for filter in filters {
+        let filter = Filter::from_str(&filter.to_string())?;
+        for event in db.iter::<Event, _>(&reader, &filter)? {
+            let event = event?;
+            if seen.insert(event.id().to_vec()) {
+                events.push(event);
+            }
+        }
+    }
+    Ok(events)
+}
+
+/// LMDB events authored by or addressed (`p` tag) to a pubkey
+fn exported_events(db: &Db, pubkey: &str) -> Result<Vec<Event>> {
+    lmdb_events(
+        db,
+        &[json!({ "authors": [pubkey] }), json!({ "#p": [pubkey] })],
+    )
+}
+
+/// LMDB events authored by a pubkey and the giftwraps addressed to it
+fn erased_events(db: &Db, pubkey: &str) -> Result<Vec<Event>> {
+    let giftwrap = kinds::number(GIFTWRAP_KIND);
+    lmdb_events(
+        db,
+        &[
+            json!({ "authors": [pubkey] }),
+            json!({ "kinds": [giftwrap], "#p": [pubkey] }),
+        ],
+    )
+}
+
+/// Refusal of a request the storage backend cannot serve
+fn unsupported(store: &StorageBackend) -> Option<HttpResponse> {
+    (store.backend_name() == "sql").then(|| {
+        HttpResponse::NotImplemented().json(json!({
+            "ok": false,
+            "error": "user data export and erasure need the firestore storage backend"
+        }))
+    })
+}
+
+/// Everything held for a pubkey as a JSON bundle
+pub async fn export(
+    store: &StorageBackend,
+    archive: Option<&MessageArchive>,
+    db: &Db,
+    pubkey: &str,
+) -> Result<Value> {
+    let storage = store.user_data(pubkey).await?;
+    let archived = match archive {
+        Some(archive) => archive.list_for_pubkey(pubkey).await?,
+        None => Vec::new(),
+    };
+    let events = exported_events(db, pubkey)?;
+    Ok(json!({
+        "pubkey": pubkey,
+        "exported_at": chrono::Utc::now().timestamp(),
+        "storage": storage,
+        "archived_events": archived,
+        "events": events,
+    }))
+}
+
+/// Erase everything held for a pubkey, the archive and MLS storage first so a
+/// failure leaves LMDB to be erased by a retry
+pub async fn erase(
+    store: &StorageBackend,
+    archive: Option<&MessageArchive>,
+    db: &Db,
+    pubkey: &str,
+) -> Result<ErasureReport> {
+    let archived_events = match archive {
+        Some(archive) => archive.erase_pubkey(pubkey).await?,
+        None => 0,
+    };
+    let storage = store.erase_user(pubkey).await?;
+    let ids: Vec<Vec<u8>> = erased_events(db, pubkey)?
+        .iter()
+        .map(|e| e.id().to_vec())
+        .collect();
+    db.batch_del(&ids)?;
+    info!(
+        "Erased {}: {} LMDB events, {} archived events",
+        pubkey,
+        ids.len(),
+        archived_events
+    );
+    Ok(ErasureReport {
+        pubkey: pubkey.to_owned(),
+        lmdb_events: ids.len(),
+        archived_events,
+        storage,
+    })
+}
+
+/// Respond with an export bundle, shared with the admin API
+pub async fn export_response(state: &PrivacyState, app: &App, pubkey: &str) -> HttpResponse {
+    if let Some(resp) = unsupported(&state.store) {
+        return resp;
+    }
+    match export(&state.store, state.archive.as_ref(), &app.db, pubkey).await {
+        Ok(bundle) => {
+            counter!("mls_gateway_privacy_requests", "action" => "export", "result" => "ok")
+                .increment(1);
+            HttpResponse::Ok().json(json!({ "ok": true, "data": bundle }))
+        }
+        Err(e) => {
+            counter!("mls_gateway_privacy_requests", "action" => "export", "result" => "error")
+                .increment(1);
+            warn!("Data export of {} failed: {}", pubkey, e);
+            HttpResponse::InternalServerError().json(json!({ "ok": false, "error": e.to_string() }))
+        }
+    }
+}
+
+/// Respond with an erasure report, shared with the admin API
+pub async fn erase_response(state: &PrivacyState, app: &App, pubkey: &str) -> HttpResponse {
+    if let Some(resp) = unsupported(&state.store) {
+        return resp;
+    }
+    match erase(&state.store, state.archive.as_ref(), &app.db, pubkey).await {
+        Ok(report) => {
+            counter!("mls_gateway_privacy_requests", "action" => "erase", "result" => "ok")
+                .increment(1);
+            HttpResponse::Ok().json(json!({ "ok": true, "erased": report }))
+        }
+        Err(e) => {
+            counter!("mls_gateway_privacy_requests", "action" => "erase", "result" => "error")
+                .increment(1);
+            warn!("Data erasure of {} failed: {}", pubkey, e);
+            HttpResponse::InternalServerError().json(json!({ "ok": false, "error": e.to_string() }))
+        }
+    }
+}
+
+pub fn configure_privacy_routes(cfg: &mut web::ServiceConfig, prefix: &str, state: PrivacyState) {
+    cfg.service(
+        web::scope(&format!("{}/privacy", prefix))
+            .app_data(web::Data::new(state))
+            .route("/export", web::get().to(export_own))
+            .route("/data", web::delete().to(erase_own)),
+    );
+}
+
+fn unauthorized(error: String) -> HttpResponse {
+    HttpResponse::Unauthorized().json(json!({ "ok": false, "error": error }))
+}
+
+/// Export the data of the NIP-98 authenticated pubkey
+async fn export_own(
+    req: HttpRequest,
+    state: web::Data<PrivacyState>,
+    app: web::Data<App>,
+) -> ActixResult<HttpResponse> {
+    match http_auth::verify(&req) {
+        Ok(pubkey) => Ok(export_response(&state, &app, &pubkey).await),
+        Err(e) => Ok(unauthorized(e)),
+    }
+}
+
+/// Erase the data of the NIP-98 authenticated pubkey
+async fn erase_own(
+    req: HttpRequest,
+    state: web::Data<PrivacyState>,
+    app: web::Data<App>,
+) -> ActixResult<HttpResponse> {
+    match http_auth::verify(&req) {
+        Ok(pubkey) => Ok(erase_response(&state, &app, &pubkey).await),
+        Err(e) => Ok(unauthorized(e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nostr_relay::db::secp256k1::{Keypair, SECP256K1};
+
+    #[test]
+    fn lmdb_events_of_pubkey() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let db = Db::open(dir.path())?;
+        let alice = Keypair::from_seckey_str(SECP256K1, &"07".repeat(32))?;
+        let bob = Keypair::from_seckey_str(SECP256K1, &"08".repeat(32))?;
+        let authored = Event::create(&alice, 10, 1, vec![], "a".to_owned())?;
+        let alice_hex = authored.pubkey_str();
+        assert!(valid_pubkey(&alice_hex));
+        assert!(!valid_pubkey(&alice_hex.to_uppercase()));
+
+        let addressed = Event::create(
+            &bob,
+            11,
+            1059,
+            vec![vec!["p".to_owned(), alice_hex.clone()]],
+            "b".to_owned(),
+        )?;
+        let mention = Event::create(
+            &bob,
+            12,
+            3,
+            vec![vec!["p".to_owned(), alice_hex.clone()]],
+            "".to_owned(),
+        )?;
+        let other = Event::create(&bob, 13, 1, vec![], "c".to_owned())?;
+        db.batch_put(vec![
+            authored.clone(),
+            addressed.clone(),
+            mention.clone(),
+            other,
+        ])?;
+
+        let ids = |events: Vec<Event>| {
+            let mut ids: Vec<String> = events.iter().map(|e| e.id_str()).collect();
+            ids.sort();
+            ids
+        };
+        let mut expected = vec![authored.id_str(), addressed.id_str(), mention.id_str()];
+        expected.sort();
+        assert_eq!(ids(exported_events(&db, &alice_hex)?), expected);
+
+        // contact lists of other authors naming alice are not alice's to erase
+        let mut expected = vec![authored.id_str(), addressed.id_str()];
+        expected.sort();
+        assert_eq!(ids(erased_events(&db, &alice_hex)?), expected);
+        Ok(())
+    }
+}
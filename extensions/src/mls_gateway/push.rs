@@ -0,0 +1,456 @@
+//! Push notifications for offline MLS recipients
+//!
+//! Mobile clients register FCM/APNs tokens under their pubkey through
+//! `{api_prefix}/push/tokens`, authenticated with NIP-98. When a giftwrap (1059),
+//! group message (445) or direct message (Noise DM (446) or another direct
+//! `archived_kinds` kind) arrives for a recipient without an authenticated
+//! (NIP-42) session on this instance, a data-only push is sent to each of their
+//! tokens so the client wakes up and fetches from the archive: `fcm` tokens
+//! through FCM HTTP v1, `apns` tokens as background pushes straight to APNs with
+//! a provider token signed by `push_apns_key`. Pushes never carry event content.
+//! A failed token or recipient is logged and the others are still notified.
+
+use super::firestore::PushToken;
+use super::groups::GroupRegistry;
+use super::{
+    http_auth, kinds, MlsGatewayConfig, StorageBackend, GIFTWRAP_KIND, MLS_GROUP_MESSAGE_KIND,
+};
+use actix_web::{web, HttpRequest, HttpResponse, Result as ActixResult};
+use anyhow::Result;
+use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
+use metrics::counter;
+use nostr_relay::db::{now, Event};
+use once_cell::sync::Lazy;
+use parking_lot::{Mutex, RwLock};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    time::{Duration, Instant},
+};
+use tracing::{debug, info, warn};
+
+const FCM_URL: &str = "https://fcm.googleapis.com/v1/projects";
+const APNS_URL: &str = "https://api.push.apple.com/3/device";
+const APNS_SANDBOX_URL: &str = "https://api.sandbox.push.apple.com/3/device";
+/// APNs provider tokens are valid for an hour and must not be renewed more than every 20 minutes
+const APNS_TOKEN_TTL: Duration = Duration::from_secs(45 * 60);
+
+/// Authenticated sessions per pubkey on this instance
+#[derive(Default)]
+struct Online {
+    pubkeys: HashMap<String, HashSet<usize>>,
+    sessions: HashMap<usize, String>,
+}
+
+static ONLINE: Lazy<RwLock<Online>> = Lazy::new(|| RwLock::new(Online::default()));
+static APNS_TOKEN: Lazy<Mutex<Option<(String, Instant)>>> = Lazy::new(|| Mutex::new(None));
+static HTTP: Lazy<reqwest::Client> = Lazy::new(reqwest::Client::new);
+
+/// Record the authenticated pubkey of a session, returns true when newly recorded
+pub fn mark_online(session_id: usize, pubkey: &str) -> bool {
+    if ONLINE.read().sessions.get(&session_id).map(String::as_str) == Some(pubkey) {
+        return false;
+    }
+    let mut online = ONLINE.write();
+    if let Some(old) = online.sessions.insert(session_id, pubkey.to_string()) {
+        if let Some(ids) = online.pubkeys.get_mut(&old) {
+            ids.remove(&session_id);
+        }
+    }
+    online
+        .pubkeys
+        .entry(pubkey.to_string())
+        .or_default()
+        .insert(session_id);
+    true
+}
+
+/// Forget a closed session
+pub fn mark_offline(session_id: usize) {
+    let mut online = ONLINE.write();
+    if let Some(pubkey) = online.sessions.remove(&session_id) {
+        if let Some(ids) = online.pubkeys.get_mut(&pubkey) {
+            ids.remove(&session_id);
+            if ids.is_empty() {
+                online.pubkeys.remove(&pubkey);
+            }
+        }
+    }
+}
+
+/// Authenticated sessions of a pubkey on this instance
+pub fn sessions(pubkey: &str) -> Vec<usize> {
+    ONLINE
+        .read()
+        .pubkeys
+        .get(pubkey)
+        .map(|ids| ids.iter().copied().collect())
+        .unwrap_or_default()
+}
+
+/// Pubkeys with an authenticated session on this instance
+pub fn online_pubkeys() -> Vec<String> {
+    ONLINE.read().pubkeys.keys().cloned().collect()
+}
+
+/// Whether a pubkey has an authenticated session on this instance
+pub fn is_online(pubkey: &str) -> bool {
+    ONLINE.read().pubkeys.contains_key(pubkey)
+}
+
+/// FCM v1 message waking the client in the background
+fn fcm_message(token: &str, event: &Event, group_id: Option<&str>) -> Value {
+    json!({
+        "message": {
+            "token": token,
+            "data": {
+                "kind": event.kind().to_string(),
+                "event_id": event.id_str(),
+                "group_id": group_id.unwrap_or_default(),
+            },
+            "android": { "priority": "high" },
+            "apns": {
+                "headers": { "apns-push-type": "background", "apns-priority": "5" },
+                "payload": { "aps": { "content-available": 1 } },
+            },
+        }
+    })
+}
+
+/// APNs provider token signed with the configured auth key, cached for [`APNS_TOKEN_TTL`]
+fn apns_token(config: &MlsGatewayConfig) -> Result<String> {
+    if let Some((token, expires)) = APNS_TOKEN.lock().clone() {
+        if expires > Instant::now() {
+            return Ok(token);
+        }
+    }
+    let missing = |name: &str| anyhow::anyhow!("apns token without {}", name);
+    let key = config
+        .push_apns_key
+        .as_deref()
+        .ok_or_else(|| missing("push_apns_key"))?;
+    let key_id = config
+        .push_apns_key_id
+        .as_deref()
+        .ok_or_else(|| missing("push_apns_key_id"))?;
+    let team_id = config
+        .push_apns_team_id
+        .as_deref()
+        .ok_or_else(|| missing("push_apns_team_id"))?;
+    let mut header = Header::new(Algorithm::ES256);
+    header.kid = Some(key_id.to_string());
+    let token = encode(
+        &header,
+        &json!({ "iss": team_id, "iat": now() }),
+        &EncodingKey::from_ec_pem(key.as_bytes())?,
+    )?;
+    *APNS_TOKEN.lock() = Some((token.clone(), Instant::now() + APNS_TOKEN_TTL));
+    Ok(token)
+}
+
+/// APNs background push, the data keys match the FCM message
+fn apns_payload(event: &Event, group_id: Option<&str>) -> Value {
+    json!({
+        "aps": { "content-available": 1 },
+        "kind": event.kind().to_string(),
+        "event_id": event.id_str(),
+        "group_id": group_id.unwrap_or_default(),
+    })
+}
+
+/// Outcome of a push to one token
+enum Sent {
+    Delivered,
+    /// the provider no longer knows the token, it is removed
+    Unregistered,
+    Failed(reqwest::StatusCode),
+}
+
+/// Push to one token through the provider of its platform
+async fn send(
+    config: &MlsGatewayConfig,
+    token: &PushToken,
+    event: &Event,
+    group_id: Option<&str>,
+) -> Result<Sent> {
+    if token.platform == "apns" {
+        let topic = config
+            .push_apns_topic
+            .as_deref()
+            .ok_or_else(|| anyhow::anyhow!("apns token without push_apns_topic"))?;
+        let url = if config.push_apns_sandbox {
+            APNS_SANDBOX_URL
+        } else {
+            APNS_URL
+        };
+        let res = HTTP
+            .post(format!("{}/{}", url, token.token))
+            .header("authorization", format!("bearer {}", apns_token(config)?))
+            .header("apns-topic", topic)
+            .header("apns-push-type", "background")
+            .header("apns-priority", "5")
+            .json(&apns_payload(event, group_id))
+            .send()
+            .await?;
+        let status = res.status();
+        if status.is_success() {
+            return Ok(Sent::Delivered);
+        }
+        // 410 Unregistered, 400 BadDeviceToken
+        let reason = res.json::<Value>().await.ok();
+        let reason = reason
+            .as_ref()
+            .and_then(|r| r.get("reason"))
+            .and_then(Value::as_str);
+        return Ok(match (status, reason) {
+            (reqwest::StatusCode::GONE, _) | (_, Some("BadDeviceToken")) => Sent::Unregistered,
+            _ => Sent::Failed(status),
+        });
+    }
+    let project_id = config
+        .push_project_id
+        .as_ref()
+        .or(config.project_id.as_ref())
+        .ok_or_else(|| anyhow::anyhow!("fcm token without a project id"))?;
+    let res = HTTP
+        .post(format!("{}/{}/messages:send", FCM_URL, project_id))
+        .bearer_auth(crate::gcp::access_token().await?)
+        .json(&fcm_message(&token.token, event, group_id))
+        .send()
+        .await?;
+    Ok(match res.status() {
+        status if status.is_success() => Sent::Delivered,
+        // UNREGISTERED: the app was uninstalled or the token rotated
+        reqwest::StatusCode::NOT_FOUND => Sent::Unregistered,
+        status => Sent::Failed(status),
+    })
+}
+
+/// Recipients of an event, group members are used when a 445 has no p tags
+async fn recipients(
+    store: &StorageBackend,
+    event: &Event,
+    group_id: Option<&str>,
+    max: usize,
+) -> Result<BTreeSet<String>> {
+    let mut recipients: BTreeSet<String> = event
+        .tags()
+        .iter()
+        .filter(|tag| tag.len() >= 2 && tag[0] == "p")
+        .map(|tag| tag[1].clone())
+        .collect();
+    if recipients.is_empty() && kinds::canonical(event.kind()) == MLS_GROUP_MESSAGE_KIND {
+        if let Some(group_id) = group_id {
+            recipients = GroupRegistry::members(&store.list_roster_history(group_id).await?);
+        }
+    }
+    let sender = hex::encode(event.pubkey());
+    recipients.remove(&sender);
+    if recipients.len() > max {
+        warn!(
+            "Push for {} limited to {} of {} recipients",
+            event.id_str(),
+            max,
+            recipients.len()
+        );
+        recipients = recipients.into_iter().take(max).collect();
+    }
+    Ok(recipients)
+}
+
+/// Send data-only pushes to the offline recipients of a giftwrap, group message or direct message
+pub async fn notify_offline(
+    store: &StorageBackend,
+    config: &MlsGatewayConfig,
+    event: &Event,
+) -> Result<usize> {
+    let kind = kinds::canonical(event.kind());
+    if kind != GIFTWRAP_KIND
+        && kind != MLS_GROUP_MESSAGE_KIND
+        && !config.is_direct_kind(event.kind())
+    {
+        return Ok(0);
+    }
+    let group_id = event
+        .tags()
+        .iter()
+        .find(|tag| tag.len() >= 2 && tag[0] == "h")
+        .map(|tag| tag[1].as_str());
+
+    let mut sent = 0;
+    for pubkey in recipients(store, event, group_id, config.push_max_recipients).await? {
+        if is_online(&pubkey) {
+            continue;
+        }
+        let tokens = match store.get_push_tokens(&pubkey).await {
+            Ok(tokens) => tokens,
+            Err(e) => {
+                warn!("Failed to load push tokens of {}: {}", pubkey, e);
+                counter!("mls_gateway_push_sent", "result" => "error").increment(1);
+                continue;
+            }
+        };
+        for token in tokens {
+            match send(config, &token, event, group_id).await {
+                Ok(Sent::Delivered) => {
+                    sent += 1;
+                    counter!("mls_gateway_push_sent", "result" => "ok").increment(1);
+                }
+                Ok(Sent::Unregistered) => {
+                    debug!(
+                        "Removing unregistered {} push token of {}",
+                        token.platform, pubkey
+                    );
+                    if let Err(e) = store.delete_push_token(None, &token.token).await {
+                        warn!("Failed to remove push token of {}: {}", pubkey, e);
+                    }
+                    counter!("mls_gateway_push_sent", "result" => "unregistered").increment(1);
+                }
+                Ok(Sent::Failed(status)) => {
+                    warn!("{} push to {} failed: {}", token.platform, pubkey, status);
+                    counter!("mls_gateway_push_sent", "result" => "error").increment(1);
+                }
+                Err(e) => {
+                    warn!("{} push to {} failed: {}", token.platform, pubkey, e);
+                    counter!("mls_gateway_push_sent", "result" => "error").increment(1);
+                }
+            }
+        }
+    }
+    Ok(sent)
+}
+
+/// Shared state of the push token routes
+#[derive(Clone)]
+pub struct PushState {
+    pub store: StorageBackend,
+}
+
+/// Configure push token routes, must be registered before the general API scope
+pub fn configure_push_routes(cfg: &mut web::ServiceConfig, prefix: &str, state: PushState) {
+    cfg.service(
+        web::scope(&format!("{}/push", prefix))
+            .app_data(web::Data::new(state))
+            .route("/tokens", web::post().to(register_token))
+            .route("/tokens", web::delete().to(unregister_token)),
+    );
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TokenRequest {
+    pub token: String,
+    /// "fcm" or "apns", defaults to "fcm"
+    #[serde(default)]
+    pub platform: Option<String>,
+}
+
+fn unauthorized(error: String) -> HttpResponse {
+    HttpResponse::Unauthorized().json(json!({ "ok": false, "error": error }))
+}
+
+/// NIP-98 pubkey of a request whose auth event commits to the body, and the parsed body
+fn authorized(req: &HttpRequest, body: &[u8]) -> Result<(String, TokenRequest), HttpResponse> {
+    let pubkey = http_auth::verify_with_payload(req, body).map_err(unauthorized)?;
+    let request = serde_json::from_slice(body).map_err(|_| {
+        HttpResponse::BadRequest().json(json!({ "ok": false, "error": "invalid body" }))
+    })?;
+    Ok((pubkey, request))
+}
+
+/// Register a push token for the NIP-98 authenticated pubkey
+async fn register_token(
+    req: HttpRequest,
+    state: web::Data<PushState>,
+    body: web::Bytes,
+) -> ActixResult<HttpResponse> {
+    let (pubkey, body) = match authorized(&req, &body) {
+        Ok(authorized) => authorized,
+        Err(res) => return Ok(res),
+    };
+    let platform = body.platform.clone().unwrap_or_else(|| "fcm".to_string());
+    if body.token.is_empty()
+        || body.token.len() > 4096
+        || !matches!(platform.as_str(), "fcm" | "apns")
+    {
+        return Ok(
+            HttpResponse::BadRequest().json(json!({ "ok": false, "error": "invalid token" }))
+        );
+    }
+    match state
+        .store
+        .upsert_push_token(&pubkey, &body.token, &platform)
+        .await
+    {
+        Ok(()) => {
+            info!("Registered {} push token for {}", platform, pubkey);
+            Ok(HttpResponse::Ok().json(json!({ "ok": true })))
+        }
+        Err(e) => {
+            warn!("Failed to register push token: {}", e);
+            Ok(HttpResponse::InternalServerError()
+                .json(json!({ "ok": false, "error": "storage error" })))
+        }
+    }
+}
+
+/// Remove a push token of the NIP-98 authenticated pubkey
+async fn unregister_token(
+    req: HttpRequest,
+    state: web::Data<PushState>,
+    body: web::Bytes,
+) -> ActixResult<HttpResponse> {
+    let (pubkey, body) = match authorized(&req, &body) {
+        Ok(authorized) => authorized,
+        Err(res) => return Ok(res),
+    };
+    match state
+        .store
+        .delete_push_token(Some(&pubkey), &body.token)
+        .await
+    {
+        Ok(removed) => Ok(HttpResponse::Ok().json(json!({ "ok": true, "removed": removed }))),
+        Err(e) => {
+            warn!("Failed to remove push token: {}", e);
+            Ok(HttpResponse::InternalServerError()
+                .json(json!({ "ok": false, "error": "storage error" })))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn online_sessions() {
+        let pubkey = "push-test-pubkey";
+        assert!(!is_online(pubkey));
+        mark_online(9001, pubkey);
+        mark_online(9002, pubkey);
+        mark_offline(9001);
+        assert!(is_online(pubkey));
+        mark_offline(9002);
+        assert!(!is_online(pubkey));
+    }
+
+    #[test]
+    fn payloads_carry_no_content() -> anyhow::Result<()> {
+        use nostr_relay::db::secp256k1::{rand::thread_rng, Keypair};
+        let key_pair = Keypair::new_global(&mut thread_rng());
+        let tags = vec![vec!["h".to_owned(), "g".to_owned()]];
+        let event = Event::create(&key_pair, now(), 445, tags, "ciphertext".to_owned())?;
+        let apns = apns_payload(&event, Some("g"));
+        assert_eq!(apns["aps"]["content-available"], 1);
+        assert_eq!(apns["group_id"], "g");
+        let fcm = fcm_message("token", &event, Some("g"));
+        assert_eq!(
+            fcm["message"]["data"],
+            json!({ "kind": "445", "event_id": event.id_str(), "group_id": "g" })
+        );
+        assert!(
+            !apns.to_string().contains("ciphertext") && !fcm.to_string().contains("ciphertext")
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,123 @@
+//! LMDB -> Firestore archival reconciliation
+//!
+//! Archive writes happen in the background after an event is accepted, so a
+//! Firestore outage leaves events in LMDB that never reach the archive. This job
+//! periodically scans recent LMDB events of archive-eligible kinds and
+//! re-archives any that are missing.
+
+use super::message_archive::MessageArchive;
+use anyhow::Result;
+use metrics::counter;
+use nostr_relay::db::{Db, Event, Filter, SortList};
+use std::{sync::Arc, time::Duration};
+use tracing::{debug, info, warn};
+
+/// Upper bound of events scanned per run
+const MAX_SCAN: u64 = 10_000;
+
+/// Result of a reconciliation run
+#[derive(Debug, Clone, Default)]
+pub struct ReconcileReport {
+    pub scanned: usize,
+    pub missing: usize,
+    pub repaired: usize,
+    pub failed: usize,
+}
+
+/// Reconciliation job
+pub struct Reconciler {
+    archive: MessageArchive,
+    db: Arc<Db>,
+    kinds: Vec<u32>,
+    ttl_days: u32,
+    lookback: Duration,
+}
+
+impl Reconciler {
+    pub fn new(
+        archive: MessageArchive,
+        db: Arc<Db>,
+        kinds: Vec<u32>,
+        ttl_days: u32,
+        lookback: Duration,
+    ) -> Self {
+        Self {
+            archive,
+            db,
+            kinds,
+            ttl_days,
+            lookback,
+        }
+    }
+
+    fn recent_events(&self) -> Result<Vec<Event>> {
+        let filter = Filter {
+            kinds: SortList::from(self.kinds.iter().map(|k| *k as u16).collect::<Vec<_>>()),
+            since: Some(nostr_relay::db::now().saturating_sub(self.lookback.as_secs())),
+            limit: Some(MAX_SCAN),
+            ..Default::default()
+        };
+        let reader = self.db.reader()?;
+        let events = self
+            .db
+            .iter::<Event, _>(&reader, &filter)?
+            .collect::<Result<Vec<Event>, _>>()?;
+        Ok(events)
+    }
+
+    /// Scan once and re-archive missing events
+    pub async fn run_once(&self) -> Result<ReconcileReport> {
+        let mut report = ReconcileReport::default();
+        for event in self.recent_events()? {
+            if !MessageArchive::is_archivable(&event) {
+                continue;
+            }
+            report.scanned += 1;
+            match self.archive.is_archived(&event).await {
+                Ok(true) => continue,
+                Ok(false) => report.missing += 1,
+                Err(e) => {
+                    debug!("Archive lookup failed for {}: {}", event.id_str(), e);
+                    report.failed += 1;
+                    continue;
+                }
+            }
+            match self
+                .archive
+                .archive_event(&event, Some(self.ttl_days))
+                .await
+            {
+                Ok(_) => {
+                    report.repaired += 1;
+                    counter!("mls_gateway_archive_gaps_repaired", "kind" => event.kind().to_string()).increment(1);
+                }
+                Err(e) => {
+                    warn!("Failed to re-archive event {}: {}", event.id_str(), e);
+                    report.failed += 1;
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    /// Run reconciliation on an interval in the background
+    pub fn spawn(self, interval: Duration) {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                match self.run_once().await {
+                    Ok(report) if report.missing > 0 || report.failed > 0 => info!(
+                        "Archive reconciliation: scanned {}, missing {}, repaired {}, failed {}",
+                        report.scanned, report.missing, report.repaired, report.failed
+                    ),
+                    Ok(report) => debug!(
+                        "Archive reconciliation: scanned {}, no gaps",
+                        report.scanned
+                    ),
+                    Err(e) => warn!("Archive reconciliation failed: {}", e),
+                }
+            }
+        });
+    }
+}
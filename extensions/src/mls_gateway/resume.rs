@@ -0,0 +1,361 @@
+//! Subscription resume across reconnects and instance restarts
+//!
+//! With `resume_subscriptions`, the gateway remembers the last event delivered
+//! to each subscription of an authenticated (NIP-42) session, keyed by pubkey
+//! and subscription id. Stored results and live events advance the cursor,
+//! cursors are written to storage every [`FLUSH_INTERVAL`], when the
+//! subscription is closed or the session disconnects, and at shutdown.
+//!
+//! When the pubkey subscribes again with the same id, e.g. after reconnecting
+//! to an instance whose LMDB does not have the events yet, archived events
+//! after the cursor matching the filters are replayed ahead of the stored
+//! results. Only filters with `#h` (group messages) or `#p` (recipients) are
+//! tracked, the archive is indexed by those. Events of the cursor's second may
+//! be delivered again, clients dedupe by id.
+//!
+//! The archive is read while the REQ is pending in the message chain, the
+//! result is kept here until `process_req` adds it to the subscription.
+
+use super::firestore::SubscriptionCursor;
+use super::message_archive::MessageArchive;
+use super::StorageBackend;
+use anyhow::Result;
+use chrono::Utc;
+use metrics::counter;
+use nostr_relay::db::{Event, Filter};
+use nostr_relay::message::Subscription;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use std::{collections::HashMap, time::Duration};
+use tracing::{info, warn};
+
+/// Interval between writes of advanced cursors
+pub const FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Subscription of an authenticated session
+struct Tracked {
+    pubkey: String,
+    filters: Vec<Filter>,
+    /// created_at and id of the last delivered event
+    last: Option<(u64, String)>,
+    /// advanced since the last write
+    dirty: bool,
+}
+
+impl Tracked {
+    fn advance(&mut self, event: &Event) {
+        if self
+            .last
+            .as_ref()
+            .map_or(true, |(created_at, _)| event.created_at() >= *created_at)
+        {
+            self.last = Some((event.created_at(), event.id_str()));
+            self.dirty = true;
+        }
+    }
+
+    fn cursor(&self, subscription_id: &str, ttl: chrono::Duration) -> Option<SubscriptionCursor> {
+        let (created_at, event_id) = self.last.clone()?;
+        let now = Utc::now();
+        Some(SubscriptionCursor {
+            pubkey: self.pubkey.clone(),
+            subscription_id: subscription_id.to_string(),
+            created_at,
+            event_id,
+            updated_at: now,
+            expires_at: now + ttl,
+        })
+    }
+}
+
+/// session id -> subscription id -> tracked subscription
+static SUBSCRIPTIONS: Lazy<Mutex<HashMap<usize, HashMap<String, Tracked>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Replayed cursor and events per session id and subscription id, taken by `process_req`
+type Replayed = (Option<SubscriptionCursor>, Vec<Event>);
+static REPLAYED: Lazy<Mutex<HashMap<(usize, String), Replayed>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Group ids (`#h`) and recipients (`#p`) of a filter the archive is queried by
+fn archive_keys(filter: &Filter) -> (Vec<String>, Vec<String>) {
+    let values = |name: &[u8]| {
+        filter
+            .tags
+            .get(name)
+            .map(|values| {
+                values
+                    .iter()
+                    .map(|v| String::from_utf8_lossy(v).into_owned())
+                    .collect()
+            })
+            .unwrap_or_default()
+    };
+    (values(b"h"), values(b"p"))
+}
+
+/// Whether missed events of a subscription can be replayed from the archive
+pub fn replayable(subscription: &Subscription) -> bool {
+    subscription.filters.iter().any(|filter| {
+        let (groups, recipients) = archive_keys(filter);
+        !groups.is_empty() || !recipients.is_empty()
+    })
+}
+
+/// Start tracking a subscription, replacing one with the same id
+pub fn track(
+    session_id: usize,
+    pubkey: &str,
+    subscription: &Subscription,
+    cursor: Option<&SubscriptionCursor>,
+) {
+    SUBSCRIPTIONS.lock().entry(session_id).or_default().insert(
+        subscription.id.clone(),
+        Tracked {
+            pubkey: pubkey.to_string(),
+            filters: subscription.filters.clone(),
+            last: cursor.map(|c| (c.created_at, c.event_id.clone())),
+            dirty: false,
+        },
+    );
+}
+
+/// Keep the replay of a pending REQ until its `process_req`
+pub fn stash(session_id: usize, subscription_id: &str, replayed: Replayed) {
+    REPLAYED
+        .lock()
+        .insert((session_id, subscription_id.to_string()), replayed);
+}
+
+/// Replay kept for a REQ, `None` when it was not read
+pub fn take_replayed(session_id: usize, subscription_id: &str) -> Option<Replayed> {
+    REPLAYED
+        .lock()
+        .remove(&(session_id, subscription_id.to_string()))
+}
+
+/// Events delivered to a subscription, replayed or stored results
+pub fn delivered(session_id: usize, subscription_id: &str, events: &[Event]) {
+    let mut sessions = SUBSCRIPTIONS.lock();
+    let Some(tracked) = sessions
+        .get_mut(&session_id)
+        .and_then(|subs| subs.get_mut(subscription_id))
+    else {
+        return;
+    };
+    for event in events {
+        tracked.advance(event);
+    }
+}
+
+/// Live event delivered to a session, advances the subscriptions it matches
+pub fn broadcast(session_id: usize, event: &Event) {
+    let mut sessions = SUBSCRIPTIONS.lock();
+    let Some(subs) = sessions.get_mut(&session_id) else {
+        return;
+    };
+    for tracked in subs.values_mut() {
+        if tracked
+            .filters
+            .iter()
+            .any(|filter| filter.r#match(event.index()))
+        {
+            tracked.advance(event);
+        }
+    }
+}
+
+/// Stop tracking a closed subscription, its cursor if it advanced
+pub fn close(
+    session_id: usize,
+    subscription_id: &str,
+    ttl: chrono::Duration,
+) -> Option<SubscriptionCursor> {
+    let mut sessions = SUBSCRIPTIONS.lock();
+    let subs = sessions.get_mut(&session_id)?;
+    let tracked = subs.remove(subscription_id)?;
+    if subs.is_empty() {
+        sessions.remove(&session_id);
+    }
+    tracked
+        .cursor(subscription_id, ttl)
+        .filter(|_| tracked.dirty)
+}
+
+/// Stop tracking the subscriptions of a session, cursors that advanced
+pub fn disconnect(session_id: usize, ttl: chrono::Duration) -> Vec<SubscriptionCursor> {
+    REPLAYED.lock().retain(|(id, _), _| *id != session_id);
+    SUBSCRIPTIONS
+        .lock()
+        .remove(&session_id)
+        .unwrap_or_default()
+        .iter()
+        .filter(|(_, tracked)| tracked.dirty)
+        .filter_map(|(subscription_id, tracked)| tracked.cursor(subscription_id, ttl))
+        .collect()
+}
+
+/// Cursors that advanced since they were last taken
+pub fn take_dirty(ttl: chrono::Duration) -> Vec<SubscriptionCursor> {
+    let mut sessions = SUBSCRIPTIONS.lock();
+    let mut cursors = Vec::new();
+    for (subscription_id, tracked) in sessions.values_mut().flat_map(|subs| subs.iter_mut()) {
+        if tracked.dirty {
+            tracked.dirty = false;
+            cursors.extend(tracked.cursor(subscription_id, ttl));
+        }
+    }
+    cursors
+}
+
+/// Write cursors, failures are logged
+pub async fn flush(store: &StorageBackend, cursors: Vec<SubscriptionCursor>) {
+    if cursors.is_empty() {
+        return;
+    }
+    match store.put_subscription_cursors(&cursors).await {
+        Ok(()) => counter!("mls_gateway_resume_cursors_flushed").increment(cursors.len() as u64),
+        Err(e) => warn!(
+            "Failed to write {} subscription cursors: {}",
+            cursors.len(),
+            e
+        ),
+    }
+}
+
+/// Write advanced cursors every [`FLUSH_INTERVAL`]
+pub fn spawn_flush(store: StorageBackend, ttl: chrono::Duration) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+        loop {
+            interval.tick().await;
+            flush(&store, take_dirty(ttl)).await;
+        }
+    });
+}
+
+/// Stored cursor of a subscription and the archived events after it matching its filters,
+/// oldest first and at most `max_events`
+pub async fn replay(
+    store: &StorageBackend,
+    archive: &MessageArchive,
+    pubkey: &str,
+    subscription: &Subscription,
+    max_events: u32,
+) -> Result<Option<(SubscriptionCursor, Vec<Event>)>> {
+    let Some(cursor) = store
+        .get_subscription_cursor(pubkey, &subscription.id)
+        .await?
+    else {
+        return Ok(None);
+    };
+    if cursor.expires_at <= Utc::now() {
+        return Ok(None);
+    }
+    // the archive queries are exclusive, events of the cursor's second count
+    let since = cursor.created_at.saturating_sub(1) as i64;
+    let mut found: HashMap<[u8; 32], Event> = HashMap::new();
+    for filter in &subscription.filters {
+        let (groups, recipients) = archive_keys(filter);
+        let mut events = Vec::new();
+        for group_id in &groups {
+            events.extend(
+                archive
+                    .get_group_messages(group_id, since, max_events)
+                    .await?,
+            );
+        }
+        for recipient in &recipients {
+            events.extend(
+                archive
+                    .get_missed_messages(recipient, since, max_events)
+                    .await?,
+            );
+        }
+        for event in events {
+            if event.id_str() != cursor.event_id && filter.r#match(event.index()) {
+                found.insert(*event.id(), event);
+            }
+        }
+    }
+    let mut events: Vec<Event> = found.into_values().collect();
+    events.sort_by_key(|e| (e.created_at(), *e.id()));
+    events.truncate(max_events as usize);
+    if !events.is_empty() {
+        info!(
+            "Replaying {} archived events to {} subscription {}",
+            events.len(),
+            pubkey,
+            subscription.id
+        );
+    }
+    Ok(Some((cursor, events)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nostr_relay::db::secp256k1::{Keypair, SECP256K1};
+
+    fn event(keypair: &Keypair, created_at: u64, tags: Vec<Vec<String>>) -> Event {
+        Event::create(keypair, created_at, 445, tags, "".to_owned()).unwrap()
+    }
+
+    #[test]
+    fn cursors() -> anyhow::Result<()> {
+        let keypair = Keypair::from_seckey_str(SECP256K1, &"07".repeat(32))?;
+        let subscription = Subscription {
+            id: "group".to_owned(),
+            filters: vec![r#"{"kinds":[445],"#h":["g1"]}"#.parse()?],
+        };
+        assert!(replayable(&subscription));
+        assert!(!replayable(&Subscription {
+            id: "feed".to_owned(),
+            filters: vec![r#"{"kinds":[1]}"#.parse()?],
+        }));
+
+        let ttl = chrono::Duration::hours(1);
+        let session_id = usize::MAX - 1;
+        track(session_id, "alice", &subscription, None);
+        assert!(close(session_id, "group", ttl).is_none());
+
+        track(session_id, "alice", &subscription, None);
+        let first = event(&keypair, 100, vec![vec!["h".to_owned(), "g1".to_owned()]]);
+        let older = event(&keypair, 90, vec![vec!["h".to_owned(), "g1".to_owned()]]);
+        delivered(session_id, "group", &[first.clone(), older]);
+        let cursors = take_dirty(ttl);
+        let cursor = cursors.iter().find(|c| c.pubkey == "alice").unwrap();
+        assert_eq!(
+            (cursor.created_at, &cursor.event_id),
+            (100, &first.id_str())
+        );
+        assert!(!take_dirty(ttl).iter().any(|c| c.pubkey == "alice"));
+
+        // live events of other groups do not advance the cursor
+        broadcast(
+            session_id,
+            &event(&keypair, 120, vec![vec!["h".to_owned(), "g2".to_owned()]]),
+        );
+        let live = event(&keypair, 110, vec![vec!["h".to_owned(), "g1".to_owned()]]);
+        broadcast(session_id, &live);
+        let cursors = disconnect(session_id, ttl);
+        assert_eq!(cursors.len(), 1);
+        assert_eq!(
+            (cursors[0].created_at, &cursors[0].event_id),
+            (110, &live.id_str())
+        );
+        assert!(disconnect(session_id, ttl).is_empty());
+
+        // a kept replay is taken once and dropped with the session
+        stash(session_id, "group", (None, vec![live.clone()]));
+        assert_eq!(
+            take_replayed(session_id, "group").map(|(_, events)| events.len()),
+            Some(1)
+        );
+        assert!(take_replayed(session_id, "group").is_none());
+        stash(session_id, "group", (None, vec![live]));
+        disconnect(session_id, ttl);
+        assert!(take_replayed(session_id, "group").is_none());
+        Ok(())
+    }
+}
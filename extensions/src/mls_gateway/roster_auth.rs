@@ -0,0 +1,95 @@
+//! Per-operation authorization of roster/policy events (450)
+//!
+//! Each roster operation requires a minimum role in its group. By default
+//! admins may `add`, `remove` and `replace` members while only the owner may
+//! `promote` and `demote` admins, `transfer` ownership to a single `p` and
+//! `delete` the group. `roster_operation_roles` overrides the
+//! defaults for all groups and `roster_group_operation_roles` per group:
+//!
+//! ```toml
+//! [extensions.mls_gateway.roster_operation_roles]
+//! replace = "owner"
+//!
+//! [extensions.mls_gateway.roster_group_operation_roles.grp_abc123]
+//! promote = "admin"
+//! ```
+//!
+//! `bootstrap` creates the group and is not part of the matrix.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Minimum group role required for a roster operation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RosterRole {
+    /// the group owner only
+    Owner,
+    /// the owner or any group admin
+    Admin,
+}
+
+/// Built-in role of an operation
+pub fn default_role(operation: &str) -> RosterRole {
+    match operation {
+        "promote" | "demote" | "transfer" | "delete" => RosterRole::Owner,
+        _ => RosterRole::Admin,
+    }
+}
+
+/// Role required for `operation` in `group_id`: group override, then configured, then built-in
+pub fn required_role(
+    operation: &str,
+    group_id: &str,
+    roles: &HashMap<String, RosterRole>,
+    group_roles: &HashMap<String, HashMap<String, RosterRole>>,
+) -> RosterRole {
+    group_roles
+        .get(group_id)
+        .and_then(|roles| roles.get(operation))
+        .or_else(|| roles.get(operation))
+        .copied()
+        .unwrap_or_else(|| default_role(operation))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolves_roles() {
+        let roles = HashMap::from([("replace".to_owned(), RosterRole::Owner)]);
+        let group_roles = HashMap::from([(
+            "g".to_owned(),
+            HashMap::from([("promote".to_owned(), RosterRole::Admin)]),
+        )]);
+        assert_eq!(
+            required_role("add", "g", &roles, &group_roles),
+            RosterRole::Admin
+        );
+        assert_eq!(
+            required_role("replace", "g", &roles, &group_roles),
+            RosterRole::Owner
+        );
+        assert_eq!(
+            required_role("promote", "g", &roles, &group_roles),
+            RosterRole::Admin
+        );
+        assert_eq!(
+            required_role("promote", "other", &roles, &group_roles),
+            RosterRole::Owner
+        );
+        assert_eq!(
+            required_role("demote", "other", &HashMap::new(), &HashMap::new()),
+            RosterRole::Owner
+        );
+        assert_eq!(
+            required_role("transfer", "g", &roles, &group_roles),
+            RosterRole::Owner
+        );
+        assert_eq!(
+            required_role("delete", "g", &roles, &group_roles),
+            RosterRole::Owner
+        );
+    }
+}
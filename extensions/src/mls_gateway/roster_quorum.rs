@@ -0,0 +1,295 @@
+//! Multi-admin quorum for destructive roster operations
+//!
+//! `roster_quorum` maps roster operations to the number of admins (the owner
+//! counts as one) that must sign off before the operation is applied, e.g.
+//! `{ replace = 2, transfer = 2, delete = 3 }`; `roster_group_quorum`
+//! overrides it per group. A roster/policy event (450) of such an operation
+//! passes the usual authorization and sequence checks at ingress and is
+//! answered `OK true` without being stored or delivered: it is kept as a
+//! `PendingRosterOp` holding the signed event and the proposer's approval.
+//! Its sequence is reserved, other roster events at that sequence are
+//! rejected while it is pending. Other admins co-sign by publishing a 450
+//! referencing it:
+//!
+//! ```json
+//! {"kind": 450, "tags": [["h", "<group_id>"], ["op", "approve"], ["e", "<proposal event id>"]]}
+//! ```
+//!
+//! Approvals are counted in a storage transaction, so concurrent co-signers
+//! on any instance are all counted and exactly one of them closes the quorum.
+//! The operation is then applied with its original sequence and the proposal
+//! event is published. If another roster change took the sequence in the
+//! meantime the pending operation is `superseded`; after
+//! `roster_quorum_ttl_secs` it is `expired`. Pending operations are listed
+//! through the admin API and `rnostr group pending`.
+
+use super::firestore::PendingRosterOp;
+use super::{roster_sequence, MlsGatewayConfig, StorageBackend};
+use nostr_relay::db::Event;
+use std::collections::HashMap;
+
+/// Operation of co-signing events
+pub const APPROVE_OP: &str = "approve";
+
+/// Approvals required for `operation` in `group_id`, at most 1 means no quorum
+pub fn required_approvals(
+    operation: &str,
+    group_id: &str,
+    quorum: &HashMap<String, u32>,
+    group_quorum: &HashMap<String, HashMap<String, u32>>,
+) -> u32 {
+    group_quorum
+        .get(group_id)
+        .and_then(|quorum| quorum.get(operation))
+        .or_else(|| quorum.get(operation))
+        .copied()
+        .unwrap_or(0)
+}
+
+/// Whether `operation` waits for `required` approvals, bootstrap creates the
+/// group and has no admins yet to approve it
+pub fn needs_quorum(operation: &str, required: u32) -> bool {
+    required > 1 && operation != "bootstrap" && operation != APPROVE_OP
+}
+
+/// Whether any quorum is configured, roster sequences are only reserved then
+pub fn configured(config: &MlsGatewayConfig) -> bool {
+    !config.roster_quorum.is_empty() || !config.roster_group_quorum.is_empty()
+}
+
+/// Outcome of an approval
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Approval {
+    /// counted, quorum not reached yet
+    Recorded,
+    /// the pubkey already approved
+    Duplicate,
+    /// quorum reached, the operation can be applied
+    Approved,
+    /// past `expires_at`, the operation is marked expired
+    Expired,
+    /// the operation is no longer pending
+    Closed,
+}
+
+impl Approval {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Approval::Recorded => "recorded",
+            Approval::Duplicate => "duplicate",
+            Approval::Approved => "approved",
+            Approval::Expired => "expired",
+            Approval::Closed => "closed",
+        }
+    }
+}
+
+/// Count the approval of `pubkey` at `now`. Reaching the quorum moves the
+/// operation to "approved", later approvals find it closed.
+pub fn approve(op: &mut PendingRosterOp, pubkey: &str, now: i64) -> Approval {
+    if op.status != "pending" {
+        return Approval::Closed;
+    }
+    if now > op.expires_at {
+        op.status = "expired".to_string();
+        return Approval::Expired;
+    }
+    if !op.approve(pubkey) {
+        return Approval::Duplicate;
+    }
+    if op.approved() {
+        op.status = "approved".to_string();
+        Approval::Approved
+    } else {
+        Approval::Recorded
+    }
+}
+
+/// Id of the pending operation holding `sequence` of a group, other than `event_id`
+pub async fn reserved_by(
+    store: &StorageBackend,
+    group_id: &str,
+    sequence: u64,
+    event_id: &str,
+    now: i64,
+) -> anyhow::Result<Option<String>> {
+    Ok(store
+        .list_pending_roster_ops(Some(group_id))
+        .await?
+        .into_iter()
+        .find(|op| {
+            op.sequence == sequence
+                && op.event_id != event_id
+                && op.status == "pending"
+                && now <= op.expires_at
+        })
+        .map(|op| op.event_id))
+}
+
+/// Keep a checked roster/policy event as a pending operation instead of applying it,
+/// the error is the reason to reject the event with
+pub async fn propose(
+    store: &StorageBackend,
+    config: &MlsGatewayConfig,
+    event: &Event,
+    required: u32,
+) -> Result<PendingRosterOp, String> {
+    let tag = |name: &str| {
+        event
+            .tags()
+            .iter()
+            .find(|tag| tag.len() >= 2 && tag[0] == name)
+            .map(|tag| tag[1].clone())
+    };
+    let group_id = tag("h").ok_or("missing group_id (h tag)")?;
+    let operation = tag("op").ok_or("missing operation (op tag)")?;
+    let sequence = tag("seq")
+        .and_then(|seq| seq.parse::<u64>().ok())
+        .ok_or("missing or invalid sequence (seq tag)")?;
+    let member_pubkeys: Vec<String> = event
+        .tags()
+        .iter()
+        .filter(|tag| tag.len() >= 2 && tag[0] == "p")
+        .map(|tag| tag[1].clone())
+        .collect();
+    let proposer = event.pubkey_str();
+    let event_id = event.id_str();
+
+    let proposal = roster_sequence::Proposal {
+        group_id: &group_id,
+        sequence,
+        operation: &operation,
+        member_pubkeys: &member_pubkeys,
+        admin_pubkey: &proposer,
+        event_id: &event_id,
+    };
+    let verdict = roster_sequence::check(store, &proposal)
+        .await
+        .map_err(|e| format!("failed to check roster sequence: {}", e))?;
+    match verdict {
+        roster_sequence::Verdict::Next => {}
+        roster_sequence::Verdict::Gap { .. }
+            if config.roster_sequence_policy == roster_sequence::SequencePolicy::Flag => {}
+        roster_sequence::Verdict::Gap { last } => {
+            return Err(format!("sequence gap: expected {}", last + 1))
+        }
+        roster_sequence::Verdict::Stale { last } => {
+            return Err(format!("stale sequence: {} <= {}", sequence, last))
+        }
+        roster_sequence::Verdict::Replay | roster_sequence::Verdict::Fork { .. } => {
+            return Err(format!("sequence {} already used", sequence))
+        }
+    }
+    let now = chrono::Utc::now().timestamp();
+    if let Some(pending) = reserved_by(store, &group_id, sequence, &event_id, now)
+        .await
+        .map_err(|e| format!("failed to check pending roster operations: {}", e))?
+    {
+        return Err(format!(
+            "sequence {} is reserved by pending roster operation {}",
+            sequence, pending
+        ));
+    }
+
+    let role_admin = tag("role").is_some_and(|role| role.eq_ignore_ascii_case("admin"));
+    let pending = PendingRosterOp {
+        event_id,
+        group_id,
+        sequence,
+        operation,
+        member_pubkeys,
+        role_admin,
+        proposer: proposer.clone(),
+        approvals: vec![proposer],
+        required,
+        status: "pending".to_string(),
+        created_at: event.created_at() as i64,
+        expires_at: now + config.roster_quorum_ttl_secs as i64,
+        event: Some(event.to_string()),
+    };
+    store
+        .create_pending_roster_op(&pending)
+        .await
+        .map_err(|e| format!("failed to store pending roster operation: {}", e))?;
+    Ok(pending)
+}
+
+/// Store and deliver the proposal event of an approved operation through the relay pipeline
+#[cfg(feature = "nip_service")]
+pub fn publish(op: &PendingRosterOp) -> anyhow::Result<()> {
+    let event: Event = op
+        .event
+        .as_deref()
+        .ok_or_else(|| anyhow::anyhow!("pending roster operation {} has no event", op.event_id))?
+        .parse()?;
+    crate::nip_service::emit::publish(event)?;
+    Ok(())
+}
+
+#[cfg(not(feature = "nip_service"))]
+pub fn publish(op: &PendingRosterOp) -> anyhow::Result<()> {
+    anyhow::bail!(
+        "roster operation {} is applied but not published without nip_service",
+        op.event_id
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pending(required: u32) -> PendingRosterOp {
+        PendingRosterOp {
+            event_id: "e".to_owned(),
+            group_id: "g".to_owned(),
+            sequence: 4,
+            operation: "replace".to_owned(),
+            member_pubkeys: vec!["alice".to_owned()],
+            role_admin: false,
+            proposer: "owner".to_owned(),
+            approvals: vec!["owner".to_owned()],
+            required,
+            status: "pending".to_owned(),
+            created_at: 100,
+            expires_at: 200,
+            event: None,
+        }
+    }
+
+    #[test]
+    fn resolves_quorum() {
+        let quorum = HashMap::from([("replace".to_owned(), 2)]);
+        let group_quorum =
+            HashMap::from([("g".to_owned(), HashMap::from([("replace".to_owned(), 3)]))]);
+        assert_eq!(
+            required_approvals("replace", "g", &quorum, &group_quorum),
+            3
+        );
+        assert_eq!(
+            required_approvals("replace", "other", &quorum, &group_quorum),
+            2
+        );
+        assert_eq!(required_approvals("add", "g", &quorum, &group_quorum), 0);
+        assert!(needs_quorum("replace", 2));
+        assert!(!needs_quorum("replace", 1));
+        assert!(!needs_quorum("bootstrap", 2));
+        assert!(!needs_quorum(APPROVE_OP, 2));
+    }
+
+    #[test]
+    fn counts_approvals() {
+        let mut op = pending(3);
+        assert_eq!(approve(&mut op, "owner", 150), Approval::Duplicate);
+        assert_eq!(approve(&mut op, "bob", 150), Approval::Recorded);
+        assert_eq!(approve(&mut op, "carol", 150), Approval::Approved);
+        assert_eq!(op.approvals, vec!["owner", "bob", "carol"]);
+        // the quorum is closed once, a concurrent approval finds it approved
+        assert_eq!(op.status, "approved");
+        assert_eq!(approve(&mut op, "dave", 150), Approval::Closed);
+
+        let mut late = pending(2);
+        assert_eq!(approve(&mut late, "bob", 201), Approval::Expired);
+        assert_eq!(late.status, "expired");
+        assert_eq!(approve(&mut late, "carol", 150), Approval::Closed);
+    }
+}
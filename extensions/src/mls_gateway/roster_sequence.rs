@@ -0,0 +1,225 @@
+//! Sequence gap and fork detection of roster/policy events (450)
+//!
+//! A roster event must carry the next sequence of its group. A sequence that
+//! skips ahead is a gap (lost events or an admin publishing out of band), a
+//! sequence already stored with a different operation, author or member list is
+//! a fork (two admins, or a compromised key, writing the same slot). Replays of
+//! a stored record are ignored and older free sequences are stale, as before.
+//!
+//! `roster_sequence_policy` decides what happens to gaps: `reject` refuses the
+//! event at ingress, `flag` applies it. Forks are never applied since the slot
+//! is taken; `reject` refuses them at ingress, `flag` accepts the event without
+//! applying it. Every gap and fork is stored as a `RosterAnomaly`, counted in
+//! `mls_gateway_roster_anomalies{kind,action}` and listed through the admin API
+//! and `rnostr group anomalies`.
+
+use super::firestore::{RosterAnomaly, RosterPolicyDocument};
+use super::StorageBackend;
+use metrics::counter;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use tracing::warn;
+
+/// Handling of sequence gaps and forks
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SequencePolicy {
+    /// refuse the event
+    Reject,
+    /// accept and record the anomaly
+    #[default]
+    Flag,
+}
+
+/// Roster/policy event as proposed by its author
+#[derive(Debug, Clone)]
+pub struct Proposal<'a> {
+    pub group_id: &'a str,
+    pub sequence: u64,
+    pub operation: &'a str,
+    pub member_pubkeys: &'a [String],
+    pub admin_pubkey: &'a str,
+    pub event_id: &'a str,
+}
+
+impl Proposal<'_> {
+    /// Whether a stored record holds the same change
+    fn same_as(&self, stored: &RosterPolicyDocument) -> bool {
+        let members = |m: &[String]| m.iter().cloned().collect::<BTreeSet<_>>();
+        stored.operation == self.operation
+            && stored.admin_pubkey == self.admin_pubkey
+            && members(&stored.member_pubkeys) == members(self.member_pubkeys)
+    }
+}
+
+/// Classification of a proposal against the stored roster
+#[derive(Debug, Clone)]
+pub enum Verdict {
+    /// the next sequence, or the first of a group
+    Next,
+    /// identical to the stored record at its sequence
+    Replay,
+    /// below the last sequence at a free slot
+    Stale { last: u64 },
+    /// skips sequences after `last`
+    Gap { last: u64 },
+    /// different content at a stored sequence
+    Fork {
+        last: u64,
+        stored: RosterPolicyDocument,
+    },
+}
+
+impl Verdict {
+    /// Anomaly kind of the verdict
+    pub fn anomaly(&self) -> Option<&'static str> {
+        match self {
+            Verdict::Gap { .. } => Some("gap"),
+            Verdict::Fork { .. } => Some("fork"),
+            _ => None,
+        }
+    }
+}
+
+/// Classify a proposal from the last stored sequence and the record stored at its sequence
+pub fn classify(
+    proposal: &Proposal,
+    last: Option<u64>,
+    stored: Option<RosterPolicyDocument>,
+) -> Verdict {
+    if let Some(stored) = stored {
+        return if proposal.same_as(&stored) {
+            Verdict::Replay
+        } else {
+            Verdict::Fork {
+                last: last.unwrap_or(stored.sequence),
+                stored,
+            }
+        };
+    }
+    match last {
+        None => Verdict::Next,
+        Some(last) if proposal.sequence == last + 1 => Verdict::Next,
+        Some(last) if proposal.sequence > last => Verdict::Gap { last },
+        Some(last) => Verdict::Stale { last },
+    }
+}
+
+/// Classify a proposal against storage
+pub async fn check(store: &StorageBackend, proposal: &Proposal<'_>) -> anyhow::Result<Verdict> {
+    let last = store.get_last_roster_sequence(proposal.group_id).await?;
+    let stored = match last {
+        Some(last) if proposal.sequence <= last => {
+            store
+                .get_roster_policy(proposal.group_id, proposal.sequence)
+                .await?
+        }
+        _ => None,
+    };
+    Ok(classify(proposal, last, stored))
+}
+
+/// Store and count the anomaly of a verdict, `action` is "rejected" or "flagged"
+pub async fn record(
+    store: &StorageBackend,
+    proposal: &Proposal<'_>,
+    verdict: &Verdict,
+    action: &'static str,
+) {
+    let Some(kind) = verdict.anomaly() else {
+        return;
+    };
+    let (last_sequence, stored) = match verdict {
+        Verdict::Gap { last } => (Some(*last), None),
+        Verdict::Fork { last, stored } => (Some(*last), Some(stored.clone())),
+        _ => (None, None),
+    };
+    counter!("mls_gateway_roster_anomalies", "kind" => kind, "action" => action).increment(1);
+    warn!(
+        "Roster {} in group {}: seq={} last={:?} by {} ({})",
+        kind, proposal.group_id, proposal.sequence, last_sequence, proposal.admin_pubkey, action
+    );
+    let anomaly = RosterAnomaly {
+        group_id: proposal.group_id.to_string(),
+        sequence: proposal.sequence,
+        last_sequence,
+        kind: kind.to_string(),
+        action: action.to_string(),
+        event_id: proposal.event_id.to_string(),
+        admin_pubkey: proposal.admin_pubkey.to_string(),
+        operation: proposal.operation.to_string(),
+        member_pubkeys: proposal.member_pubkeys.to_vec(),
+        stored,
+        created_at: chrono::Utc::now().timestamp(),
+    };
+    if let Err(e) = store.store_roster_anomaly(&anomaly).await {
+        warn!(
+            "Failed to store roster anomaly of group {}: {}",
+            proposal.group_id, e
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stored(
+        sequence: u64,
+        operation: &str,
+        members: &[&str],
+        admin: &str,
+    ) -> RosterPolicyDocument {
+        RosterPolicyDocument {
+            group_id: "g".to_owned(),
+            sequence,
+            operation: operation.to_owned(),
+            member_pubkeys: members.iter().map(|m| m.to_string()).collect(),
+            admin_pubkey: admin.to_owned(),
+            created_at: 0,
+            updated_at: 0,
+        }
+    }
+
+    #[test]
+    fn classifies_sequences() {
+        let members = vec!["bob".to_owned(), "alice".to_owned()];
+        let proposal = |sequence| Proposal {
+            group_id: "g",
+            sequence,
+            operation: "add",
+            member_pubkeys: &members,
+            admin_pubkey: "admin",
+            event_id: "e",
+        };
+        assert!(matches!(classify(&proposal(1), None, None), Verdict::Next));
+        assert!(matches!(
+            classify(&proposal(4), Some(3), None),
+            Verdict::Next
+        ));
+        assert!(matches!(
+            classify(&proposal(6), Some(3), None),
+            Verdict::Gap { last: 3 }
+        ));
+        assert!(matches!(
+            classify(&proposal(2), Some(3), None),
+            Verdict::Stale { last: 3 }
+        ));
+
+        let same = stored(3, "add", &["alice", "bob"], "admin");
+        assert!(matches!(
+            classify(&proposal(3), Some(3), Some(same)),
+            Verdict::Replay
+        ));
+        for other in [
+            stored(3, "remove", &["alice", "bob"], "admin"),
+            stored(3, "add", &["alice"], "admin"),
+            stored(3, "add", &["alice", "bob"], "mallory"),
+        ] {
+            let verdict = classify(&proposal(3), Some(5), Some(other));
+            assert_eq!(verdict.anomaly(), Some("fork"));
+        }
+        assert_eq!(classify(&proposal(6), Some(3), None).anomaly(), Some("gap"));
+        assert_eq!(classify(&proposal(4), Some(3), None).anomaly(), None);
+    }
+}
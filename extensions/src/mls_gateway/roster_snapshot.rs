@@ -0,0 +1,147 @@
+//! Relay-published roster snapshots
+//!
+//! After a roster/policy event (450) is applied the relay signs, with its
+//! service key, an addressable event of kind [`ROSTER_SNAPSHOT_KIND`] per group
+//! (`d` and `h` tags set to the group id) holding the current owner, members,
+//! admins and roster sequence. Clients read authoritative membership with one
+//! REQ (`{"kinds":[30450],"#d":[group_id]}`) instead of replaying the 450
+//! history. Snapshots from other pubkeys are rejected, and with
+//! `roster_gated_delivery` they are only delivered to roster members like
+//! group messages.
+
+use super::firestore::{GroupInfo, RosterPolicyDocument};
+use super::groups::GroupRegistry;
+use super::StorageBackend;
+use anyhow::Result;
+use nostr_relay::db::Event;
+use serde::{Deserialize, Serialize};
+
+/// Roster snapshot, addressable by group id
+pub const ROSTER_SNAPSHOT_KIND: u16 = 30450;
+
+/// Content of a roster snapshot event
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RosterSnapshot {
+    pub group_id: String,
+    /// Sequence of the last applied roster/policy event
+    pub sequence: u64,
+    pub owner: Option<String>,
+    pub members: Vec<String>,
+    pub admins: Vec<String>,
+}
+
+impl RosterSnapshot {
+    /// Current roster of a group from its registry entry and roster history
+    pub fn build(
+        group_id: &str,
+        group: Option<&GroupInfo>,
+        history: &[RosterPolicyDocument],
+    ) -> Self {
+        let mut admins = group.map(|g| g.admin_pubkeys.clone()).unwrap_or_default();
+        admins.sort();
+        admins.dedup();
+        Self {
+            group_id: group_id.to_string(),
+            sequence: history.iter().map(|r| r.sequence).max().unwrap_or(0),
+            owner: group.map(|g| g.owner_pubkey.clone()),
+            members: GroupRegistry::members(history).into_iter().collect(),
+            admins,
+        }
+    }
+
+    pub fn tags(&self) -> Vec<Vec<String>> {
+        vec![
+            vec!["d".to_string(), self.group_id.clone()],
+            vec!["h".to_string(), self.group_id.clone()],
+            vec!["seq".to_string(), self.sequence.to_string()],
+        ]
+    }
+}
+
+/// Load the roster of a group from storage
+pub async fn load(store: &StorageBackend, group_id: &str) -> Result<RosterSnapshot> {
+    let group = store.get_group(group_id).await?;
+    let history = store.list_roster_history(group_id).await?;
+    Ok(RosterSnapshot::build(group_id, group.as_ref(), &history))
+}
+
+/// Sign and publish the current roster snapshot of a group, `None` without a service key
+#[cfg(feature = "nip_service")]
+pub async fn publish(store: &StorageBackend, group_id: &str) -> Result<Option<String>> {
+    use crate::nip_service::emit;
+
+    if emit::service_pubkey().is_none() {
+        return Ok(None);
+    }
+    let snapshot = load(store, group_id).await?;
+    let content = serde_json::to_string(&snapshot)?;
+    emit::emit(ROSTER_SNAPSHOT_KIND, snapshot.tags(), content).map(Some)
+}
+
+#[cfg(not(feature = "nip_service"))]
+pub async fn publish(_store: &StorageBackend, _group_id: &str) -> Result<Option<String>> {
+    Ok(None)
+}
+
+/// Whether a snapshot event was signed by this relay's service key
+pub fn from_relay(event: &Event) -> bool {
+    #[cfg(feature = "nip_service")]
+    {
+        crate::nip_service::emit::service_pubkey().as_deref() == Some(event.pubkey_str().as_str())
+    }
+    #[cfg(not(feature = "nip_service"))]
+    {
+        let _ = event;
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    fn record(sequence: u64, operation: &str, members: &[&str]) -> RosterPolicyDocument {
+        RosterPolicyDocument {
+            group_id: "g".to_owned(),
+            sequence,
+            operation: operation.to_owned(),
+            member_pubkeys: members.iter().map(|m| m.to_string()).collect(),
+            admin_pubkey: "owner".to_owned(),
+            created_at: 0,
+            updated_at: 0,
+        }
+    }
+
+    #[test]
+    fn snapshot_of_history() {
+        let history = vec![
+            record(1, "bootstrap", &["alice", "owner"]),
+            record(2, "add", &["carol", "bob"]),
+            record(4, "remove", &["alice"]),
+            record(5, "promote", &["bob"]),
+        ];
+        let group = GroupInfo {
+            group_id: "g".to_owned(),
+            display_name: None,
+            owner_pubkey: "owner".to_owned(),
+            last_epoch: None,
+            admin_pubkeys: vec!["owner".to_owned(), "bob".to_owned(), "owner".to_owned()],
+            service_member: false,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        };
+        let snapshot = RosterSnapshot::build("g", Some(&group), &history);
+        assert_eq!(snapshot.sequence, 5);
+        assert_eq!(snapshot.owner.as_deref(), Some("owner"));
+        assert_eq!(snapshot.members, vec!["bob", "carol", "owner"]);
+        assert_eq!(snapshot.admins, vec!["bob", "owner"]);
+        assert_eq!(snapshot.tags()[0], vec!["d", "g"]);
+
+        let empty = RosterSnapshot::build("g", None, &[]);
+        assert_eq!(
+            (empty.sequence, empty.owner, empty.members.len()),
+            (0, None, 0)
+        );
+    }
+}
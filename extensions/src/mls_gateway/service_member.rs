@@ -9,16 +9,36 @@
 //! - Decrypting MLS application messages (kind 445) that contain NIP-SERVICE payloads
 //! - Validating membership in MLS groups
 //! - Processing decrypted NIP-SERVICE service-request JSON payloads
+//! - Persisting service-member state: the client stores its SQLCipher database
+//!   under `mls_service_storage_path` (see docs/architecture/mls-service-member-storage.md)
+//!   and the groups it joined are indexed next to it, so both survive restarts
 
+use loxation_mls_rust::api::{KeyPackage, MlsClient, Result as MLSResult};
+use metrics::counter;
 use nostr_relay::db::Event;
+use parking_lot::RwLock;
 use serde_json::Value as JsonValue;
-use tracing::{info, warn, error};
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
 use std::sync::OnceLock;
-use loxation_mls_rust::api::{MlsClient, Result as MLSResult, KeyPackage};
+use tracing::{error, info, warn};
+
+use super::MlsGatewayConfig;
 
 /// Global MLS client instance
 static MLS_CLIENT: OnceLock<MlsClient> = OnceLock::new();
 
+/// Groups joined by the service member, persisted when a storage path is configured
+static JOINED_GROUPS: RwLock<BTreeSet<String>> = RwLock::new(BTreeSet::new());
+static JOIN_INDEX_PATH: OnceLock<PathBuf> = OnceLock::new();
+
+/// File listing joined groups, inside the storage path
+const JOIN_INDEX_FILE: &str = "service_groups.json";
+
+/// Ciphersuite and extensions advertised by service member KeyPackages
+const SERVICE_CIPHERSUITE: &str = "0x0001";
+const SERVICE_EXTENSIONS: &str = "0x000a";
+
 /// Get or initialize the global MLS client
 pub fn get_mls_client() -> &'static MlsClient {
     MLS_CLIENT.get_or_init(|| {
@@ -28,7 +48,11 @@ pub fn get_mls_client() -> &'static MlsClient {
 }
 
 /// Initialize MLS client with storage configuration
-pub fn initialize_mls_client(storage_path: Option<&str>, user_id: &str, encryption_key: &str) -> MLSResult<()> {
+pub fn initialize_mls_client(
+    storage_path: Option<&str>,
+    user_id: &str,
+    encryption_key: &str,
+) -> MLSResult<()> {
     let client = get_mls_client();
 
     // Set storage path if provided
@@ -39,10 +63,126 @@ pub fn initialize_mls_client(storage_path: Option<&str>, user_id: &str, encrypti
     // Set storage encryption key for the service user
     client.set_storage_key(user_id, encryption_key)?;
 
-    info!("MLS client initialized for service member with user_id: {}", user_id);
+    if let Some(path) = storage_path {
+        let index = Path::new(path).join(JOIN_INDEX_FILE);
+        let groups = load_join_index(&index);
+        for group_id in &groups {
+            // State of a group lost from the database cannot be used, it must be rejoined
+            if let Err(e) = client.get_current_epoch(group_id, user_id) {
+                warn!("MLS service member lost state of group {}: {}", group_id, e);
+            }
+        }
+        info!(
+            "MLS service member resumed {} group(s) from {}",
+            groups.len(),
+            path
+        );
+        *JOINED_GROUPS.write() = groups;
+        let _ = JOIN_INDEX_PATH.set(index);
+    }
+
+    info!(
+        "MLS client initialized for service member with user_id: {}",
+        user_id
+    );
     Ok(())
 }
 
+/// Initialize the service member from the gateway config.
+///
+/// Requires `mls_service_user_id`. Without a storage key the client keeps its
+/// default (non persistent) storage and group state is lost on restart.
+pub async fn initialize_from_config(config: &MlsGatewayConfig) -> anyhow::Result<()> {
+    let Some(user_id) = config.mls_service_user_id.as_deref() else {
+        info!("MLS service member disabled: mls_service_user_id not set");
+        return Ok(());
+    };
+    let Some(key) = load_storage_key(config).await? else {
+        warn!("MLS service member has no storage key; group state will not survive restarts");
+        return Ok(());
+    };
+    initialize_mls_client(config.mls_service_storage_path.as_deref(), user_id, &key)
+        .map_err(|e| anyhow::anyhow!("MLS service member init failed: {}", e))
+}
+
+/// Resolve the SQLCipher key: `MLS_SERVICE_SQLCIPHER_KEY`, or the Secret Manager
+/// secret `mls_service_sqlcipher_secret`, unwrapped with `mls_service_sqlcipher_kms_key` when set
+async fn load_storage_key(config: &MlsGatewayConfig) -> anyhow::Result<Option<String>> {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+
+    if let Some(key) = nostr_relay::secret::env("MLS_SERVICE_SQLCIPHER_KEY") {
+        return Ok(Some(key));
+    }
+    let Some(secret) = config.mls_service_sqlcipher_secret.as_deref() else {
+        return Ok(None);
+    };
+    let mut key = crate::gcp::access_secret(secret)
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("secret {} not found", secret))?
+        .data;
+
+    // The secret holds the base64 KMS ciphertext of the key
+    if let Some(kms_key) = config.mls_service_sqlcipher_kms_key.as_deref() {
+        let ciphertext = String::from_utf8(key)?;
+        let res: JsonValue = crate::gcp::http()
+            .post(format!(
+                "https://cloudkms.googleapis.com/v1/{}:decrypt",
+                kms_key
+            ))
+            .bearer_auth(crate::gcp::access_token().await?)
+            .json(&serde_json::json!({ "ciphertext": ciphertext.trim() }))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let plaintext = res["plaintext"]
+            .as_str()
+            .ok_or_else(|| anyhow::anyhow!("KMS decrypt returned no plaintext"))?;
+        key = STANDARD.decode(plaintext)?;
+    }
+    Ok(Some(String::from_utf8(key)?.trim().to_string()))
+}
+
+fn load_join_index(path: &Path) -> BTreeSet<String> {
+    match std::fs::read(path) {
+        Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+            warn!(
+                "Ignoring corrupt MLS service group index {}: {}",
+                path.display(),
+                e
+            );
+            BTreeSet::new()
+        }),
+        Err(_) => BTreeSet::new(),
+    }
+}
+
+fn save_join_index(path: &Path, groups: &BTreeSet<String>) -> std::io::Result<()> {
+    // write then rename so a crash never leaves a truncated index
+    let tmp = path.with_extension("json.tmp");
+    std::fs::write(&tmp, serde_json::to_vec(groups)?)?;
+    std::fs::rename(tmp, path)
+}
+
+/// Record a group joined by the service member
+pub fn record_group_join(group_id: &str) {
+    let mut groups = JOINED_GROUPS.write();
+    if !groups.insert(group_id.to_string()) {
+        return;
+    }
+    if let Some(path) = JOIN_INDEX_PATH.get() {
+        if let Err(e) = save_join_index(path, &groups) {
+            warn!("Failed to persist MLS service group index: {}", e);
+        }
+    }
+}
+
+/// Groups joined by the service member
+pub fn joined_groups() -> Vec<String> {
+    JOINED_GROUPS.read().iter().cloned().collect()
+}
+
 /// Check if a user is a member of an MLS group
 ///
 /// # Arguments
@@ -60,7 +200,10 @@ pub fn has_group(user_id: &str, group_id: &str) -> bool {
             members.contains(&user_id.to_string())
         }
         Err(e) => {
-            warn!("Failed to check group membership for user {} in group {}: {}", user_id, group_id, e);
+            warn!(
+                "Failed to check group membership for user {} in group {}: {}",
+                user_id, group_id, e
+            );
             false
         }
     }
@@ -80,15 +223,124 @@ pub fn create_client_group(group_id: &str, creator_id: &str) -> Result<usize, St
     match client.create_group(group_id, creator_id) {
         Ok(handle_id) => {
             info!("Created MLS group {} for client {}", group_id, creator_id);
+            record_group_join(group_id);
             Ok(handle_id)
         }
         Err(e) => {
-            error!("Failed to create MLS group {} for client {}: {}", group_id, creator_id, e);
+            error!(
+                "Failed to create MLS group {} for client {}: {}",
+                group_id, creator_id, e
+            );
             Err(format!("Failed to create group: {}", e))
         }
     }
 }
 
+/// Generate a fresh KeyPackage for the service member, returns its serialized bytes
+pub fn create_key_package(user_id: &str) -> Result<Vec<u8>, String> {
+    let client = get_mls_client();
+
+    match client.create_key_package(user_id) {
+        Ok(key_package) => Ok(key_package.to_bytes()),
+        Err(e) => {
+            error!(
+                "Failed to create KeyPackage for service member {}: {}",
+                user_id, e
+            );
+            Err(format!("Failed to create key package: {}", e))
+        }
+    }
+}
+
+/// Keep `service_keypackage_count` valid KeyPackages (443) of the service pubkey
+/// published: missing ones are generated, signed with the service key, stored
+/// through the normal 443 handler and published through the relay.
+pub fn spawn_keypackage_replenisher(config: MlsGatewayConfig, store: super::StorageBackend) {
+    let interval = config.service_keypackage_interval_secs;
+    if config.service_keypackage_count == 0 || interval == 0 || config.mls_service_user_id.is_none()
+    {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(std::time::Duration::from_secs(interval));
+        loop {
+            ticker.tick().await;
+            match replenish_keypackages(&config, &store).await {
+                Ok(0) => {}
+                Ok(n) => info!("Published {} service member KeyPackage(s)", n),
+                Err(e) => warn!("Service member KeyPackage replenishment failed: {}", e),
+            }
+        }
+    });
+}
+
+async fn replenish_keypackages(
+    config: &MlsGatewayConfig,
+    store: &super::StorageBackend,
+) -> anyhow::Result<u32> {
+    use crate::nip_service::emit;
+
+    let (Some(user_id), Some(pubkey)) = (
+        config.mls_service_user_id.as_deref(),
+        emit::service_pubkey(),
+    ) else {
+        return Ok(0);
+    };
+    let valid = store.count_user_keypackages(&pubkey).await?;
+    let missing = config.service_keypackage_count.saturating_sub(valid);
+    for _ in 0..missing {
+        let key_package = create_key_package(user_id).map_err(anyhow::Error::msg)?;
+        let expires_at = chrono::Utc::now().timestamp() + config.keypackage_ttl as i64;
+        let event = emit::sign(
+            super::kinds::number(super::KEYPACKAGE_KIND),
+            keypackage_tags(config, &pubkey, expires_at),
+            super::keypackage_encoding::encode_canonical_base64(&key_package),
+        )?;
+        super::handle_keypackage(config, store, &event).await?;
+        emit::publish(event)?;
+        counter!("mls_gateway_service_keypackages_published").increment(1);
+    }
+    Ok(missing)
+}
+
+/// NIP-EE tags of a service member KeyPackage
+fn keypackage_tags(config: &MlsGatewayConfig, pubkey: &str, expires_at: i64) -> Vec<Vec<String>> {
+    let mut relays = vec!["relays".to_string()];
+    relays.extend(config.relay_urls.iter().cloned());
+    vec![
+        vec!["p".to_string(), pubkey.to_string()],
+        vec!["mls_protocol_version".to_string(), "1.0".to_string()],
+        vec!["ciphersuite".to_string(), SERVICE_CIPHERSUITE.to_string()],
+        vec!["extensions".to_string(), SERVICE_EXTENSIONS.to_string()],
+        vec!["encoding".to_string(), "base64".to_string()],
+        vec!["exp".to_string(), expires_at.to_string()],
+        relays,
+    ]
+}
+
+/// Join a group from a Welcome addressed to the service member, returns the group id
+pub fn join_from_welcome(user_id: &str, welcome: &[u8]) -> Result<String, String> {
+    let client = get_mls_client();
+
+    match client.process_welcome(user_id, welcome) {
+        Ok(group_id) => {
+            info!(
+                "Service member {} joined MLS group {} from Welcome",
+                user_id, group_id
+            );
+            record_group_join(&group_id);
+            Ok(group_id)
+        }
+        Err(e) => {
+            error!(
+                "Failed to process Welcome for service member {}: {}",
+                user_id, e
+            );
+            Err(format!("Failed to process welcome: {}", e))
+        }
+    }
+}
+
 /// Add a member to an MLS group
 ///
 /// # Arguments
@@ -103,7 +355,7 @@ pub fn add_group_member(
     group_id: &str,
     creator_id: &str,
     new_member_id: &str,
-    key_package_data: &[u8]
+    key_package_data: &[u8],
 ) -> Result<(), String> {
     let client = get_mls_client();
 
@@ -112,11 +364,17 @@ pub fn add_group_member(
 
     match client.add_member(group_id, creator_id, new_member_id, &key_package) {
         Ok(_result) => {
-            info!("Added member {} to group {} by {}", new_member_id, group_id, creator_id);
+            info!(
+                "Added member {} to group {} by {}",
+                new_member_id, group_id, creator_id
+            );
             Ok(())
         }
         Err(e) => {
-            error!("Failed to add member {} to group {}: {}", new_member_id, group_id, e);
+            error!(
+                "Failed to add member {} to group {}: {}",
+                new_member_id, group_id, e
+            );
             Err(format!("Failed to add member: {}", e))
         }
     }
@@ -134,7 +392,7 @@ pub fn add_group_member(
 pub fn encrypt_service_payload(
     group_id: &str,
     sender_id: &str,
-    payload: JsonValue
+    payload: JsonValue,
 ) -> Result<Vec<u8>, String> {
     let client = get_mls_client();
 
@@ -150,11 +408,17 @@ pub fn encrypt_service_payload(
     // Use the MLS client to encrypt the message
     match client.encrypt_message(group_id, sender_id, &payload_str) {
         Ok(encrypted_bytes) => {
-            info!("Encrypted service payload for group {} by {}", group_id, sender_id);
+            info!(
+                "Encrypted service payload for group {} by {}",
+                group_id, sender_id
+            );
             Ok(encrypted_bytes)
         }
         Err(e) => {
-            error!("Failed to encrypt service payload for group {}: {}", group_id, e);
+            error!(
+                "Failed to encrypt service payload for group {}: {}",
+                group_id, e
+            );
             Err(format!("Encryption failed: {}", e))
         }
     }
@@ -180,31 +444,125 @@ pub fn get_group_epoch(group_id: &str, user_id: &str) -> Result<u64, String> {
     }
 }
 
+/// Outcome of a kind 445 processed by the service member
+#[derive(Debug)]
+pub enum GroupMessage {
+    /// Decrypted NIP-SERVICE service-request
+    ServiceRequest(JsonValue),
+    /// Commit or proposal applied to the group, with the epoch afterwards
+    Handshake { epoch: u64 },
+    /// Application message that is not a service-request
+    Skipped,
+    /// Undecryptable application message or handshake the group refused
+    Rejected,
+}
+
+/// MLS content type of a group message (RFC 9420 §6)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ContentType {
+    Application,
+    Proposal,
+    Commit,
+}
+
+/// Content type read from the framing of a base64 `MLSMessage`, in the clear
+/// for both public and private messages. `None` when it cannot be read.
+fn content_type(content: &str) -> Option<ContentType> {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+
+    // QUIC style variable-length integer of vector lengths (RFC 9420 §2.1.2)
+    fn varint(data: &[u8]) -> Option<(usize, &[u8])> {
+        let len = 1usize << (data.first()? >> 6);
+        if len > 4 || data.len() < len {
+            return None;
+        }
+        let mut value = (data[0] & 0x3f) as usize;
+        for b in &data[1..len] {
+            value = (value << 8) | *b as usize;
+        }
+        Some((value, &data[len..]))
+    }
+    fn vector(data: &[u8]) -> Option<&[u8]> {
+        let (len, rest) = varint(data)?;
+        rest.get(len..)
+    }
+
+    let data = STANDARD.decode(content.trim()).ok()?;
+    // version mls10, then wire_format
+    if data.get(..2)? != [0, 1] {
+        return None;
+    }
+    let wire_format = u16::from_be_bytes([*data.get(2)?, *data.get(3)?]);
+    // group_id and epoch open both framings
+    let rest = vector(&data[4..])?.get(8..)?;
+    let content_type = match wire_format {
+        // private_message: content_type follows the epoch
+        2 => *rest.first()?,
+        // public_message: sender, authenticated_data, then content_type
+        1 => {
+            let rest = match *rest.first()? {
+                1 | 2 => rest.get(5..)?,
+                3 | 4 => rest.get(1..)?,
+                _ => return None,
+            };
+            *vector(rest)?.first()?
+        }
+        _ => return None,
+    };
+    match content_type {
+        1 => Some(ContentType::Application),
+        2 => Some(ContentType::Proposal),
+        3 => Some(ContentType::Commit),
+        _ => None,
+    }
+}
+
 /// Attempt to decrypt an incoming MLS group message (kind 445) into a NIP-SERVICE JSON payload.
 ///
+/// Returns Some(JsonValue) if the payload is a valid NIP-SERVICE service-request; otherwise None.
+/// Handshake messages are applied as a side effect, see [`process_group_message`].
+pub async fn try_decrypt_service_request(
+    event: &Event,
+    service_user_id: &str,
+) -> Option<JsonValue> {
+    match process_group_message(event, service_user_id).await {
+        GroupMessage::ServiceRequest(json) => Some(json),
+        _ => None,
+    }
+}
+
+/// Process an incoming MLS group message (kind 445) as the service member.
+///
 /// This function:
 /// 1. Extracts group_id from event tags (["h", group_id])
-/// 2. Uses the MLS client to decrypt the message content
-/// 3. Validates that the decrypted content is a NIP-SERVICE service-request
-/// 4. Returns the parsed JSON payload if valid
+/// 2. Applies commits and proposals, told apart by their MLS framing, so the
+///    service member follows epoch changes instead of desyncing, and reports
+///    the new epoch
+/// 3. Decrypts application messages and validates NIP-SERVICE service-requests
 ///
-/// Returns Some(JsonValue) if the payload is a valid NIP-SERVICE service-request; otherwise None.
+/// A message that fails to decrypt or to apply is rejected, it is never retried
+/// as the other content type.
+///
+/// `service_user_id` is the service-member identity of the group, see
+/// [`MlsGatewayConfig::service_user_id`].
 ///
 /// NEVER logs plaintext payloads; only logs non-sensitive summary for observability.
-pub async fn try_decrypt_service_request(event: &Event) -> Option<JsonValue> {
-    if event.kind() != 445 {
-        return None;
+pub async fn process_group_message(event: &Event, service_user_id: &str) -> GroupMessage {
+    if super::kinds::canonical(event.kind()) != super::MLS_GROUP_MESSAGE_KIND {
+        return GroupMessage::Skipped;
     }
 
     // Extract group_id from event tags
-    let group_id = match event.tags()
+    let group_id = match event
+        .tags()
         .iter()
         .find(|tag| tag.len() >= 2 && tag[0] == "h")
-        .map(|tag| tag[1].as_str()) {
+        .map(|tag| tag[1].as_str())
+    {
         Some(id) => id,
         None => {
             warn!("MLS message (kind 445) missing group_id tag");
-            return None;
+            return GroupMessage::Skipped;
         }
     };
 
@@ -213,71 +571,161 @@ pub async fn try_decrypt_service_request(event: &Event) -> Option<JsonValue> {
 
     if encrypted_content.is_empty() {
         warn!("Empty content in MLS message");
-        return None;
+        return GroupMessage::Skipped;
     }
 
     let client = get_mls_client();
 
-    // Try to decrypt the message using MLS
-    // Note: We need to determine the user_id for decryption. For service operations,
-    // this could be a service account ID. For now, we'll use a default service user.
-    let service_user_id = "nip_service";
-
-    match client.decrypt_message(group_id, service_user_id, encrypted_content) {
-        Ok(decrypted_json) => {
-            // Parse the decrypted content as JSON
-            match serde_json::from_str::<JsonValue>(&decrypted_json) {
-                Ok(json_payload) => {
-                    // Validate that this looks like a NIP-SERVICE service-request
-                    let action_type = json_payload.get("action_type").and_then(|x| x.as_str());
-                    let action_id = json_payload.get("action_id").and_then(|x| x.as_str());
-                    let client_id = json_payload.get("client_id").and_then(|x| x.as_str());
-                    let profile = json_payload.get("profile").and_then(|x| x.as_str());
-
-                    // Check for required NIP-SERVICE fields
-                    let is_valid_service_request =
-                        action_type.is_some() && action_id.is_some() &&
-                        client_id.is_some() && profile.is_some();
-
-                    if is_valid_service_request {
-                        info!(
-                            target: "nip_service",
-                            "try_decrypt_service_request: successfully decrypted MLS message (profile={:?}, action_type={:?}, group_id={})",
-                            profile, action_type, group_id
-                        );
-                        Some(json_payload)
-                    } else {
-                        warn!(
-                            target: "nip_service",
-                            "try_decrypt_service_request: decrypted content missing required NIP-SERVICE fields (action_type={:?}, action_id={:?}, client_id={:?}, profile={:?})",
-                            action_type, action_id, client_id, profile
-                        );
-                        None
-                    }
-                }
-                Err(e) => {
-                    warn!(
-                        target: "nip_service",
-                        "try_decrypt_service_request: decrypted content is not valid JSON: {}",
-                        e
-                    );
-                    None
-                }
-            }
+    if matches!(
+        content_type(event.content()),
+        Some(ContentType::Proposal | ContentType::Commit)
+    ) {
+        if let Err(e) = client.process_commit(group_id, service_user_id, encrypted_content) {
+            // Not a member anymore, or a commit of an epoch the service member missed
+            warn!(target: "nip_service", "service member rejected handshake message for group {}: {}", group_id, e);
+            return GroupMessage::Rejected;
         }
+        return match client.get_current_epoch(group_id, service_user_id) {
+            Ok(epoch) => {
+                info!(
+                    target: "nip_service",
+                    "service member applied handshake message: group_id={} epoch={}",
+                    group_id, epoch
+                );
+                GroupMessage::Handshake { epoch }
+            }
+            Err(e) => {
+                warn!(target: "nip_service", "epoch lookup after commit failed for group {}: {}", group_id, e);
+                GroupMessage::Skipped
+            }
+        };
+    }
+
+    let decrypted_json = match client.decrypt_message(group_id, service_user_id, encrypted_content)
+    {
+        Ok(decrypted_json) => decrypted_json,
         Err(e) => {
-            // This could be:
-            // - Not an MLS message (normal Nostr content)
-            // - Not a member of the group
-            // - Corrupted ciphertext
-            // - Other decryption failures
-            // We log at debug level since this is expected for non-MLS messages
+            // Corrupted ciphertext, or a message of an epoch the service member is not in
             warn!(
                 target: "nip_service",
-                "try_decrypt_service_request: failed to decrypt MLS message for group {}: {}",
+                "process_group_message: failed to decrypt MLS message for group {}: {}",
                 group_id, e
             );
-            None
+            return GroupMessage::Rejected;
         }
+    };
+
+    // Parse the decrypted content as JSON
+    let json_payload = match serde_json::from_str::<JsonValue>(&decrypted_json) {
+        Ok(json_payload) => json_payload,
+        Err(e) => {
+            warn!(
+                target: "nip_service",
+                "try_decrypt_service_request: decrypted content is not valid JSON: {}",
+                e
+            );
+            return GroupMessage::Skipped;
+        }
+    };
+
+    // Validate that this looks like a NIP-SERVICE service-request
+    let action_type = json_payload.get("action_type").and_then(|x| x.as_str());
+    let action_id = json_payload.get("action_id").and_then(|x| x.as_str());
+    let client_id = json_payload.get("client_id").and_then(|x| x.as_str());
+    let profile = json_payload.get("profile").and_then(|x| x.as_str());
+
+    // Check for required NIP-SERVICE fields
+    let is_valid_service_request =
+        action_type.is_some() && action_id.is_some() && client_id.is_some() && profile.is_some();
+
+    if is_valid_service_request {
+        info!(
+            target: "nip_service",
+            "try_decrypt_service_request: successfully decrypted MLS message (profile={:?}, action_type={:?}, group_id={})",
+            profile, action_type, group_id
+        );
+        GroupMessage::ServiceRequest(json_payload)
+    } else {
+        warn!(
+            target: "nip_service",
+            "try_decrypt_service_request: decrypted content missing required NIP-SERVICE fields (action_type={:?}, action_id={:?}, client_id={:?}, profile={:?})",
+            action_type, action_id, client_id, profile
+        );
+        GroupMessage::Skipped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::{engine::general_purpose::STANDARD, Engine};
+
+    #[test]
+    fn content_type_from_framing() {
+        // mls10, private_message, group_id "g", epoch 7, commit
+        let private = [&[0, 1, 0, 2, 1, b'g'][..], &7u64.to_be_bytes(), &[3, 0]].concat();
+        assert_eq!(
+            content_type(&STANDARD.encode(&private)),
+            Some(ContentType::Commit)
+        );
+        // mls10, public_message, group_id "g", epoch 7, member sender 0, no authenticated_data, proposal
+        let public = [
+            &[0, 1, 0, 1, 1, b'g'][..],
+            &7u64.to_be_bytes(),
+            &[1, 0, 0, 0, 0, 0, 2],
+        ]
+        .concat();
+        assert_eq!(
+            content_type(&STANDARD.encode(&public)),
+            Some(ContentType::Proposal)
+        );
+        let application = [&[0, 1, 0, 2, 1, b'g'][..], &7u64.to_be_bytes(), &[1]].concat();
+        assert_eq!(
+            content_type(&STANDARD.encode(&application)),
+            Some(ContentType::Application)
+        );
+        // welcome framing, truncated data and non-base64 content are unknown
+        assert_eq!(content_type(&STANDARD.encode([0, 1, 0, 3])), None);
+        assert_eq!(content_type(&STANDARD.encode(&private[..8])), None);
+        assert_eq!(content_type("not base64!"), None);
+    }
+
+    #[test]
+    fn per_group_service_user_id() {
+        let mut config = MlsGatewayConfig::default();
+        assert_eq!(config.service_user_id("g1"), None);
+        config.mls_service_user_id = Some("relay".to_string());
+        config
+            .mls_service_user_ids
+            .insert("g2".to_string(), "relay-ops".to_string());
+        assert_eq!(config.service_user_id("g1"), Some("relay"));
+        assert_eq!(config.service_user_id("g2"), Some("relay-ops"));
+    }
+
+    #[test]
+    fn service_keypackage_tags() {
+        let config = MlsGatewayConfig {
+            relay_urls: vec!["wss://relay.example.com".to_string()],
+            ..Default::default()
+        };
+        let tags = keypackage_tags(&config, "abcd", 100);
+        assert!(tags.contains(&vec!["p".to_string(), "abcd".to_string()]));
+        assert!(tags.contains(&vec!["encoding".to_string(), "base64".to_string()]));
+        assert!(tags.contains(&vec![
+            "relays".to_string(),
+            "wss://relay.example.com".to_string()
+        ]));
+    }
+
+    #[test]
+    fn join_index_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(JOIN_INDEX_FILE);
+        assert!(load_join_index(&path).is_empty());
+        let groups = BTreeSet::from(["g1".to_string(), "g2".to_string()]);
+        save_join_index(&path, &groups).unwrap();
+        assert_eq!(load_join_index(&path), groups);
+        std::fs::write(&path, b"not json").unwrap();
+        assert!(load_join_index(&path).is_empty());
     }
 }
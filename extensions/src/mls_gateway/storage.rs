@@ -6,13 +6,17 @@
 
 #[cfg(feature = "mls_gateway_sql")]
 mod sql_storage {
-    use sqlx::PgPool;
+    use crate::mls_gateway::firestore::{
+        AttestationRecord, GroupStats, PendingRosterOp, RosterAnomaly, RosterOperation,
+        RosterPolicyDocument, SubscriptionCursor,
+    };
+    use crate::mls_gateway::{roster_quorum, MlsStorage};
+    use anyhow::Result;
+    use async_trait::async_trait;
     use chrono::{DateTime, Utc};
     use serde::{Deserialize, Serialize};
+    use sqlx::PgPool;
     use tracing::{info, warn};
-    use anyhow::Result;
-    use async_trait::async_trait;
-    use crate::mls_gateway::MlsStorage;
 
     /// Group metadata stored in the registry
     #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -66,22 +70,33 @@ mod sql_storage {
         /// Run database migrations
         async fn run_migrations(&self) -> Result<()> {
             info!("Running SQL database migrations...");
-            
+
             // Create groups table
-            sqlx::query(r#"
+            sqlx::query(
+                r#"
                 CREATE TABLE IF NOT EXISTS mls_groups (
                     group_id TEXT PRIMARY KEY,
                     display_name TEXT,
                     owner_pubkey TEXT NOT NULL,
                     last_epoch BIGINT,
                     admin_pubkeys TEXT[] NOT NULL DEFAULT ARRAY[]::TEXT[],
+                    service_member BOOLEAN NOT NULL DEFAULT FALSE,
                     created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
                     updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
                 )
-            "#).execute(&self.pool).await?;
+            "#,
+            )
+            .execute(&self.pool)
+            .await?;
+
+            // tables created before the service member flag
+            sqlx::query("ALTER TABLE mls_groups ADD COLUMN IF NOT EXISTS service_member BOOLEAN NOT NULL DEFAULT FALSE")
+                .execute(&self.pool)
+                .await?;
 
             // Create key packages table
-            sqlx::query(r#"
+            sqlx::query(
+                r#"
                 CREATE TABLE IF NOT EXISTS mls_keypackages (
                     id TEXT PRIMARY KEY,
                     recipient_pubkey TEXT NOT NULL,
@@ -91,10 +106,14 @@ mod sql_storage {
                     expires_at TIMESTAMPTZ NOT NULL,
                     picked_up_at TIMESTAMPTZ
                 )
-            "#).execute(&self.pool).await?;
+            "#,
+            )
+            .execute(&self.pool)
+            .await?;
 
             // Create welcome messages table
-            sqlx::query(r#"
+            sqlx::query(
+                r#"
                 CREATE TABLE IF NOT EXISTS mls_welcomes (
                     id TEXT PRIMARY KEY,
                     recipient_pubkey TEXT NOT NULL,
@@ -105,10 +124,14 @@ mod sql_storage {
                     expires_at TIMESTAMPTZ NOT NULL,
                     picked_up_at TIMESTAMPTZ
                 )
-            "#).execute(&self.pool).await?;
+            "#,
+            )
+            .execute(&self.pool)
+            .await?;
 
             // Create roster/policy events table
-            sqlx::query(r#"
+            sqlx::query(
+                r#"
                 CREATE TABLE IF NOT EXISTS mls_roster_policy (
                     id TEXT PRIMARY KEY,
                     group_id TEXT NOT NULL,
@@ -120,7 +143,174 @@ mod sql_storage {
                     updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
                     UNIQUE(group_id, sequence)
                 )
-            "#).execute(&self.pool).await?;
+            "#,
+            )
+            .execute(&self.pool)
+            .await?;
+
+            // Create push tokens table
+            sqlx::query(
+                r#"
+                CREATE TABLE IF NOT EXISTS mls_push_tokens (
+                    token TEXT PRIMARY KEY,
+                    pubkey TEXT NOT NULL,
+                    platform TEXT NOT NULL,
+                    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+                )
+            "#,
+            )
+            .execute(&self.pool)
+            .await?;
+
+            sqlx::query(
+                r#"
+                CREATE TABLE IF NOT EXISTS mls_welcome_mailbox (
+                    event_id TEXT PRIMARY KEY,
+                    recipient_pubkey TEXT NOT NULL,
+                    author_pubkey TEXT NOT NULL,
+                    created_at TIMESTAMPTZ NOT NULL,
+                    expires_at TIMESTAMPTZ NOT NULL,
+                    acked_at TIMESTAMPTZ
+                )
+            "#,
+            )
+            .execute(&self.pool)
+            .await?;
+
+            sqlx::query(
+                r#"
+                CREATE TABLE IF NOT EXISTS mls_service_actions (
+                    action_id TEXT PRIMARY KEY,
+                    client_id TEXT NOT NULL,
+                    expires_at TIMESTAMPTZ NOT NULL
+                )
+            "#,
+            )
+            .execute(&self.pool)
+            .await?;
+
+            sqlx::query(
+                r#"
+                CREATE TABLE IF NOT EXISTS mls_keypackage_request_rate_limits (
+                    requester_pubkey TEXT NOT NULL,
+                    recipient_pubkey TEXT NOT NULL,
+                    request_times BIGINT[] NOT NULL DEFAULT '{}',
+                    PRIMARY KEY (requester_pubkey, recipient_pubkey)
+                )
+            "#,
+            )
+            .execute(&self.pool)
+            .await?;
+
+            sqlx::query(
+                r#"
+                CREATE TABLE IF NOT EXISTS mls_moderation_rules (
+                    list TEXT NOT NULL,
+                    target TEXT NOT NULL,
+                    value TEXT NOT NULL,
+                    reason TEXT,
+                    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+                    PRIMARY KEY (list, target, value)
+                )
+            "#,
+            )
+            .execute(&self.pool)
+            .await?;
+
+            sqlx::query(
+                r#"
+                CREATE TABLE IF NOT EXISTS mls_group_stats (
+                    group_id TEXT PRIMARY KEY,
+                    message_count BIGINT NOT NULL DEFAULT 0,
+                    senders TEXT[] NOT NULL DEFAULT ARRAY[]::TEXT[],
+                    first_epoch BIGINT,
+                    last_epoch BIGINT,
+                    epoch_changes BIGINT NOT NULL DEFAULT 0,
+                    first_activity BIGINT NOT NULL,
+                    last_activity BIGINT NOT NULL
+                )
+            "#,
+            )
+            .execute(&self.pool)
+            .await?;
+
+            // Anomalies keep the stored record of a fork as JSON
+            sqlx::query(
+                r#"
+                CREATE TABLE IF NOT EXISTS mls_roster_anomalies (
+                    event_id TEXT PRIMARY KEY,
+                    group_id TEXT NOT NULL,
+                    sequence BIGINT NOT NULL,
+                    last_sequence BIGINT,
+                    kind TEXT NOT NULL,
+                    action TEXT NOT NULL,
+                    admin_pubkey TEXT NOT NULL,
+                    operation TEXT NOT NULL,
+                    member_pubkeys TEXT[] NOT NULL,
+                    stored TEXT,
+                    created_at BIGINT NOT NULL
+                )
+            "#,
+            )
+            .execute(&self.pool)
+            .await?;
+
+            sqlx::query(
+                r#"
+                CREATE TABLE IF NOT EXISTS mls_roster_pending_ops (
+                    event_id TEXT PRIMARY KEY,
+                    group_id TEXT NOT NULL,
+                    sequence BIGINT NOT NULL,
+                    operation TEXT NOT NULL,
+                    member_pubkeys TEXT[] NOT NULL,
+                    role_admin BOOLEAN NOT NULL DEFAULT FALSE,
+                    proposer TEXT NOT NULL,
+                    approvals TEXT[] NOT NULL,
+                    required INTEGER NOT NULL,
+                    status TEXT NOT NULL,
+                    created_at BIGINT NOT NULL,
+                    expires_at BIGINT NOT NULL,
+                    event TEXT
+                )
+            "#,
+            )
+            .execute(&self.pool)
+            .await?;
+
+            // tables created before withheld events were kept
+            sqlx::query("ALTER TABLE mls_roster_pending_ops ADD COLUMN IF NOT EXISTS event TEXT")
+                .execute(&self.pool)
+                .await?;
+
+            sqlx::query(
+                r#"
+                CREATE TABLE IF NOT EXISTS mls_attested_pubkeys (
+                    pubkey TEXT PRIMARY KEY,
+                    issuer TEXT NOT NULL,
+                    subject TEXT NOT NULL,
+                    attested_at TIMESTAMPTZ NOT NULL,
+                    expires_at TIMESTAMPTZ NOT NULL
+                )
+            "#,
+            )
+            .execute(&self.pool)
+            .await?;
+
+            sqlx::query(
+                r#"
+                CREATE TABLE IF NOT EXISTS mls_subscription_cursors (
+                    pubkey TEXT NOT NULL,
+                    subscription_id TEXT NOT NULL,
+                    created_at BIGINT NOT NULL,
+                    event_id TEXT NOT NULL,
+                    updated_at TIMESTAMPTZ NOT NULL,
+                    expires_at TIMESTAMPTZ NOT NULL,
+                    PRIMARY KEY (pubkey, subscription_id)
+                )
+            "#,
+            )
+            .execute(&self.pool)
+            .await?;
 
             // Create indexes for performance
             let indexes = [
@@ -131,6 +321,10 @@ mod sql_storage {
                 "CREATE INDEX IF NOT EXISTS idx_mls_groups_owner ON mls_groups(owner_pubkey)",
                 "CREATE INDEX IF NOT EXISTS idx_mls_roster_policy_group ON mls_roster_policy(group_id)",
                 "CREATE INDEX IF NOT EXISTS idx_mls_roster_policy_sequence ON mls_roster_policy(group_id, sequence)",
+                "CREATE INDEX IF NOT EXISTS idx_mls_push_tokens_pubkey ON mls_push_tokens(pubkey)",
+                "CREATE INDEX IF NOT EXISTS idx_mls_welcome_mailbox_expires ON mls_welcome_mailbox(expires_at)",
+                "CREATE INDEX IF NOT EXISTS idx_mls_roster_anomalies_group ON mls_roster_anomalies(group_id)",
+                "CREATE INDEX IF NOT EXISTS idx_mls_roster_pending_ops_group ON mls_roster_pending_ops(group_id)",
             ];
 
             for index_sql in indexes.iter() {
@@ -140,6 +334,32 @@ mod sql_storage {
             info!("SQL database migrations completed successfully");
             Ok(())
         }
+
+        /// Flag the group as containing the relay service member, creating the registry entry if needed
+        pub async fn set_service_member(&self, group_id: &str, inviter_pubkey: &str) -> Result<()> {
+            sqlx::query(
+                "INSERT INTO mls_groups (group_id, owner_pubkey, service_member, created_at, updated_at)
+                 VALUES ($1, $2, TRUE, NOW(), NOW())
+                 ON CONFLICT (group_id) DO UPDATE SET service_member = TRUE, updated_at = NOW()"
+            )
+            .bind(group_id)
+            .bind(inviter_pubkey)
+            .execute(&self.pool)
+            .await?;
+
+            info!("Flagged service member in group registry: {}", group_id);
+            Ok(())
+        }
+
+        /// Returns true if the group is flagged to contain a service member
+        pub async fn has_service_member(&self, group_id: &str) -> Result<bool> {
+            let flag: Option<bool> =
+                sqlx::query_scalar("SELECT service_member FROM mls_groups WHERE group_id = $1")
+                    .bind(group_id)
+                    .fetch_optional(&self.pool)
+                    .await?;
+            Ok(flag.unwrap_or(false))
+        }
     }
 
     #[async_trait]
@@ -157,14 +377,16 @@ mod sql_storage {
         ) -> anyhow::Result<()> {
             // Preserve existing owner_pubkey, created_at, and admin_pubkeys on update.
             // Only update display_name/last_epoch when provided (COALESCE to retain existing when NULL).
-            let result = sqlx::query(r#"
+            let result = sqlx::query(
+                r#"
                 INSERT INTO mls_groups (group_id, display_name, owner_pubkey, last_epoch)
                 VALUES ($1, $2, $3, $4)
                 ON CONFLICT (group_id) DO UPDATE SET
                     display_name = COALESCE(EXCLUDED.display_name, mls_groups.display_name),
                     last_epoch = COALESCE(EXCLUDED.last_epoch, mls_groups.last_epoch),
                     updated_at = NOW()
-            "#)
+            "#,
+            )
             .bind(group_id)
             .bind(display_name)
             .bind(creator_pubkey)
@@ -172,7 +394,11 @@ mod sql_storage {
             .execute(&self.pool)
             .await?;
 
-            info!("Upserted group {} (rows affected: {})", group_id, result.rows_affected());
+            info!(
+                "Upserted group {} (rows affected: {})",
+                group_id,
+                result.rows_affected()
+            );
             Ok(())
         }
 
@@ -183,7 +409,7 @@ mod sql_storage {
 
         async fn group_exists(&self, group_id: &str) -> anyhow::Result<bool> {
             let exists = sqlx::query_scalar::<_, i64>(
-                "SELECT 1 FROM mls_groups WHERE group_id = $1 LIMIT 1"
+                "SELECT 1 FROM mls_groups WHERE group_id = $1 LIMIT 1",
             )
             .bind(group_id)
             .fetch_optional(&self.pool)
@@ -193,18 +419,17 @@ mod sql_storage {
         }
 
         async fn is_owner(&self, group_id: &str, pubkey: &str) -> anyhow::Result<bool> {
-            let owner: Option<String> = sqlx::query_scalar(
-                "SELECT owner_pubkey FROM mls_groups WHERE group_id = $1"
-            )
-            .bind(group_id)
-            .fetch_optional(&self.pool)
-            .await?;
+            let owner: Option<String> =
+                sqlx::query_scalar("SELECT owner_pubkey FROM mls_groups WHERE group_id = $1")
+                    .bind(group_id)
+                    .fetch_optional(&self.pool)
+                    .await?;
             Ok(owner.map_or(false, |o| o == pubkey))
         }
 
         async fn is_admin(&self, group_id: &str, pubkey: &str) -> anyhow::Result<bool> {
             let is_admin: Option<bool> = sqlx::query_scalar(
-                "SELECT $2 = ANY(admin_pubkeys) FROM mls_groups WHERE group_id = $1"
+                "SELECT $2 = ANY(admin_pubkeys) FROM mls_groups WHERE group_id = $1",
             )
             .bind(group_id)
             .bind(pubkey)
@@ -216,7 +441,7 @@ mod sql_storage {
         async fn add_admins(&self, group_id: &str, admins: &[String]) -> anyhow::Result<()> {
             let mut tx = self.pool.begin().await?;
             let current: Option<Vec<String>> = sqlx::query_scalar(
-                "SELECT admin_pubkeys FROM mls_groups WHERE group_id = $1 FOR UPDATE"
+                "SELECT admin_pubkeys FROM mls_groups WHERE group_id = $1 FOR UPDATE",
             )
             .bind(group_id)
             .fetch_optional(&mut *tx)
@@ -230,7 +455,7 @@ mod sql_storage {
             }
 
             sqlx::query(
-                "UPDATE mls_groups SET admin_pubkeys = $2, updated_at = NOW() WHERE group_id = $1"
+                "UPDATE mls_groups SET admin_pubkeys = $2, updated_at = NOW() WHERE group_id = $1",
             )
             .bind(group_id)
             .bind(&new_list)
@@ -244,7 +469,7 @@ mod sql_storage {
         async fn remove_admins(&self, group_id: &str, admins: &[String]) -> anyhow::Result<()> {
             let mut tx = self.pool.begin().await?;
             let current: Option<Vec<String>> = sqlx::query_scalar(
-                "SELECT admin_pubkeys FROM mls_groups WHERE group_id = $1 FOR UPDATE"
+                "SELECT admin_pubkeys FROM mls_groups WHERE group_id = $1 FOR UPDATE",
             )
             .bind(group_id)
             .fetch_optional(&mut *tx)
@@ -254,7 +479,7 @@ mod sql_storage {
             new_list.retain(|p| !admins.iter().any(|a| a == p));
 
             sqlx::query(
-                "UPDATE mls_groups SET admin_pubkeys = $2, updated_at = NOW() WHERE group_id = $1"
+                "UPDATE mls_groups SET admin_pubkeys = $2, updated_at = NOW() WHERE group_id = $1",
             )
             .bind(group_id)
             .bind(&new_list)
@@ -264,7 +489,7 @@ mod sql_storage {
             tx.commit().await?;
             Ok(())
         }
-        
+
         async fn get_last_roster_sequence(&self, group_id: &str) -> anyhow::Result<Option<u64>> {
             let seq_opt: Option<i64> = sqlx::query_scalar(
                 "SELECT sequence FROM mls_roster_policy WHERE group_id = $1 ORDER BY sequence DESC LIMIT 1"
@@ -275,7 +500,7 @@ mod sql_storage {
 
             Ok(seq_opt.map(|s| s as u64))
         }
-        
+
         async fn store_roster_policy(
             &self,
             group_id: &str,
@@ -288,7 +513,7 @@ mod sql_storage {
             let id = format!("{}_{}", group_id, sequence);
             let created_at_ts = chrono::DateTime::from_timestamp(created_at, 0)
                 .ok_or_else(|| anyhow::anyhow!("Invalid timestamp"))?;
-            
+
             let result = sqlx::query(
                 r#"
                 INSERT INTO mls_roster_policy (id, group_id, sequence, operation, member_pubkeys, admin_pubkey, created_at, updated_at)
@@ -304,11 +529,903 @@ mod sql_storage {
             .bind(created_at_ts)
             .execute(&self.pool)
             .await?;
-            
-            info!("Stored roster/policy event: group={}, seq={}, op={} (rows affected: {})",
-                  group_id, sequence, operation, result.rows_affected());
+
+            info!(
+                "Stored roster/policy event: group={}, seq={}, op={} (rows affected: {})",
+                group_id,
+                sequence,
+                operation,
+                result.rows_affected()
+            );
+            Ok(())
+        }
+
+        async fn get_group(
+            &self,
+            group_id: &str,
+        ) -> anyhow::Result<Option<crate::mls_gateway::firestore::GroupInfo>> {
+            let row: Option<(String, Option<String>, String, Option<i64>, Vec<String>, bool, DateTime<Utc>, DateTime<Utc>)> = sqlx::query_as(
+                "SELECT group_id, display_name, owner_pubkey, last_epoch, admin_pubkeys, service_member, created_at, updated_at FROM mls_groups WHERE group_id = $1"
+            )
+            .bind(group_id)
+            .fetch_optional(&self.pool)
+            .await?;
+
+            Ok(row.map(
+                |(
+                    group_id,
+                    display_name,
+                    owner_pubkey,
+                    last_epoch,
+                    admin_pubkeys,
+                    service_member,
+                    created_at,
+                    updated_at,
+                )| {
+                    crate::mls_gateway::firestore::GroupInfo {
+                        group_id,
+                        display_name,
+                        owner_pubkey,
+                        last_epoch,
+                        admin_pubkeys,
+                        service_member,
+                        created_at,
+                        updated_at,
+                    }
+                },
+            ))
+        }
+
+        async fn list_roster_history(
+            &self,
+            group_id: &str,
+        ) -> anyhow::Result<Vec<crate::mls_gateway::firestore::RosterPolicyDocument>> {
+            let rows: Vec<(i64, String, Vec<String>, String, DateTime<Utc>, DateTime<Utc>)> = sqlx::query_as(
+                "SELECT sequence, operation, member_pubkeys, admin_pubkey, created_at, updated_at FROM mls_roster_policy WHERE group_id = $1 ORDER BY sequence ASC"
+            )
+            .bind(group_id)
+            .fetch_all(&self.pool)
+            .await?;
+
+            Ok(rows
+                .into_iter()
+                .map(
+                    |(
+                        sequence,
+                        operation,
+                        member_pubkeys,
+                        admin_pubkey,
+                        created_at,
+                        updated_at,
+                    )| {
+                        crate::mls_gateway::firestore::RosterPolicyDocument {
+                            group_id: group_id.to_string(),
+                            sequence: sequence as u64,
+                            operation,
+                            member_pubkeys,
+                            admin_pubkey,
+                            created_at: created_at.timestamp(),
+                            updated_at: updated_at.timestamp(),
+                        }
+                    },
+                )
+                .collect())
+        }
+
+        async fn set_group_owner(&self, group_id: &str, owner_pubkey: &str) -> anyhow::Result<()> {
+            let result = sqlx::query(
+                "UPDATE mls_groups SET owner_pubkey = $2, updated_at = NOW() WHERE group_id = $1",
+            )
+            .bind(group_id)
+            .bind(owner_pubkey)
+            .execute(&self.pool)
+            .await?;
+
+            if result.rows_affected() == 0 {
+                return Err(anyhow::anyhow!("Group {} not found", group_id));
+            }
+            info!("Set owner of group {} to {}", group_id, owner_pubkey);
             Ok(())
         }
+
+        async fn get_keypackage_owner(&self, event_id: &str) -> anyhow::Result<Option<String>> {
+            let owner: Option<String> =
+                sqlx::query_scalar("SELECT sender_pubkey FROM mls_keypackages WHERE id = $1")
+                    .bind(event_id)
+                    .fetch_optional(&self.pool)
+                    .await?;
+            Ok(owner)
+        }
+
+        async fn upsert_push_token(
+            &self,
+            pubkey: &str,
+            token: &str,
+            platform: &str,
+        ) -> anyhow::Result<()> {
+            sqlx::query(
+                "INSERT INTO mls_push_tokens (token, pubkey, platform, updated_at) VALUES ($1, $2, $3, NOW())
+                 ON CONFLICT (token) DO UPDATE SET pubkey = $2, platform = $3, updated_at = NOW()"
+            )
+            .bind(token)
+            .bind(pubkey)
+            .bind(platform)
+            .execute(&self.pool)
+            .await?;
+            Ok(())
+        }
+
+        async fn get_push_tokens(
+            &self,
+            pubkey: &str,
+        ) -> anyhow::Result<Vec<crate::mls_gateway::firestore::PushToken>> {
+            let rows: Vec<(String, String, DateTime<Utc>)> = sqlx::query_as(
+                "SELECT token, platform, updated_at FROM mls_push_tokens WHERE pubkey = $1",
+            )
+            .bind(pubkey)
+            .fetch_all(&self.pool)
+            .await?;
+            Ok(rows
+                .into_iter()
+                .map(
+                    |(token, platform, updated_at)| crate::mls_gateway::firestore::PushToken {
+                        pubkey: pubkey.to_string(),
+                        token,
+                        platform,
+                        updated_at,
+                    },
+                )
+                .collect())
+        }
+
+        async fn delete_push_token(
+            &self,
+            pubkey: Option<&str>,
+            token: &str,
+        ) -> anyhow::Result<bool> {
+            let result = sqlx::query(
+                "DELETE FROM mls_push_tokens WHERE token = $1 AND ($2::TEXT IS NULL OR pubkey = $2)"
+            )
+            .bind(token)
+            .bind(pubkey)
+            .execute(&self.pool)
+            .await?;
+            Ok(result.rows_affected() > 0)
+        }
+
+        async fn delete_group(&self, group_id: &str) -> anyhow::Result<u32> {
+            let mut tx = self.pool.begin().await?;
+            let roster = sqlx::query("DELETE FROM mls_roster_policy WHERE group_id = $1")
+                .bind(group_id)
+                .execute(&mut *tx)
+                .await?;
+            sqlx::query("DELETE FROM mls_groups WHERE group_id = $1")
+                .bind(group_id)
+                .execute(&mut *tx)
+                .await?;
+            tx.commit().await?;
+
+            info!(
+                "Deleted group {} and {} roster/policy records",
+                group_id,
+                roster.rows_affected()
+            );
+            Ok(roster.rows_affected() as u32)
+        }
+
+        async fn store_mailbox_welcome(
+            &self,
+            entry: &crate::mls_gateway::firestore::MailboxWelcome,
+        ) -> anyhow::Result<()> {
+            sqlx::query(
+                "INSERT INTO mls_welcome_mailbox (event_id, recipient_pubkey, author_pubkey, created_at, expires_at)
+                 VALUES ($1, $2, $3, $4, $5) ON CONFLICT (event_id) DO NOTHING"
+            )
+            .bind(&entry.event_id)
+            .bind(&entry.recipient)
+            .bind(&entry.author)
+            .bind(entry.created_at)
+            .bind(entry.expires_at)
+            .execute(&self.pool)
+            .await?;
+            Ok(())
+        }
+
+        async fn ack_mailbox_welcomes(
+            &self,
+            recipient: &str,
+            event_ids: &[String],
+        ) -> anyhow::Result<Vec<crate::mls_gateway::firestore::MailboxWelcome>> {
+            let rows: Vec<(String, String, String, DateTime<Utc>, DateTime<Utc>, Option<DateTime<Utc>>)> = sqlx::query_as(
+                "UPDATE mls_welcome_mailbox SET acked_at = NOW()
+                 WHERE event_id = ANY($1) AND recipient_pubkey = $2 AND acked_at IS NULL
+                 RETURNING event_id, recipient_pubkey, author_pubkey, created_at, expires_at, acked_at"
+            )
+            .bind(event_ids)
+            .bind(recipient)
+            .fetch_all(&self.pool)
+            .await?;
+            Ok(rows
+                .into_iter()
+                .map(
+                    |(event_id, recipient, author, created_at, expires_at, acked_at)| {
+                        crate::mls_gateway::firestore::MailboxWelcome {
+                            event_id,
+                            recipient,
+                            author,
+                            created_at,
+                            expires_at,
+                            acked_at,
+                        }
+                    },
+                )
+                .collect())
+        }
+
+        async fn list_welcome_tombstones(
+            &self,
+            since: DateTime<Utc>,
+        ) -> anyhow::Result<Vec<(String, i64)>> {
+            let rows: Vec<(String, DateTime<Utc>)> = sqlx::query_as(
+                "SELECT event_id, expires_at FROM mls_welcome_mailbox
+                 WHERE expires_at > $1 AND (acked_at IS NOT NULL OR expires_at <= NOW())",
+            )
+            .bind(since)
+            .fetch_all(&self.pool)
+            .await?;
+            Ok(rows
+                .into_iter()
+                .map(|(id, expires_at)| (id, expires_at.timestamp()))
+                .collect())
+        }
+
+        async fn cleanup_expired_welcomes(&self, before: DateTime<Utc>) -> anyhow::Result<u32> {
+            let result = sqlx::query("DELETE FROM mls_welcome_mailbox WHERE expires_at <= $1")
+                .bind(before)
+                .execute(&self.pool)
+                .await?;
+            Ok(result.rows_affected() as u32)
+        }
+
+        async fn claim_service_action(
+            &self,
+            action_id: &str,
+            client_id: &str,
+            expires_at: DateTime<Utc>,
+        ) -> anyhow::Result<bool> {
+            // an expired claim is taken over, a live one leaves the row untouched
+            let result = sqlx::query(
+                "INSERT INTO mls_service_actions (action_id, client_id, expires_at) VALUES ($1, $2, $3)
+                 ON CONFLICT (action_id) DO UPDATE SET client_id = EXCLUDED.client_id, expires_at = EXCLUDED.expires_at
+                 WHERE mls_service_actions.expires_at <= NOW()"
+            )
+            .bind(action_id)
+            .bind(client_id)
+            .bind(expires_at)
+            .execute(&self.pool)
+            .await?;
+            Ok(result.rows_affected() == 1)
+        }
+
+        async fn cleanup_expired_service_actions(
+            &self,
+            before: DateTime<Utc>,
+        ) -> anyhow::Result<u32> {
+            let result = sqlx::query("DELETE FROM mls_service_actions WHERE expires_at <= $1")
+                .bind(before)
+                .execute(&self.pool)
+                .await?;
+            Ok(result.rows_affected() as u32)
+        }
+
+        async fn get_keypackage_request_rate_limit(
+            &self,
+            requester_pubkey: &str,
+            recipient_pubkey: &str,
+        ) -> anyhow::Result<Option<crate::mls_gateway::firestore::KeyPackageRequestRateLimit>>
+        {
+            let row: Option<(Vec<i64>,)> = sqlx::query_as(
+                "SELECT request_times FROM mls_keypackage_request_rate_limits WHERE requester_pubkey = $1 AND recipient_pubkey = $2"
+            )
+            .bind(requester_pubkey)
+            .bind(recipient_pubkey)
+            .fetch_optional(&self.pool)
+            .await?;
+            Ok(row.map(|(request_times,)| {
+                crate::mls_gateway::firestore::KeyPackageRequestRateLimit {
+                    requester_pubkey: requester_pubkey.to_string(),
+                    recipient_pubkey: recipient_pubkey.to_string(),
+                    request_count: request_times.len() as u32,
+                    window_start: request_times
+                        .first()
+                        .and_then(|t| DateTime::from_timestamp(*t, 0))
+                        .unwrap_or_else(Utc::now),
+                    request_times,
+                }
+            }))
+        }
+
+        async fn put_keypackage_request_rate_limit(
+            &self,
+            limit: &crate::mls_gateway::firestore::KeyPackageRequestRateLimit,
+        ) -> anyhow::Result<()> {
+            sqlx::query(
+                "INSERT INTO mls_keypackage_request_rate_limits (requester_pubkey, recipient_pubkey, request_times)
+                 VALUES ($1, $2, $3)
+                 ON CONFLICT (requester_pubkey, recipient_pubkey) DO UPDATE SET request_times = EXCLUDED.request_times"
+            )
+            .bind(&limit.requester_pubkey)
+            .bind(&limit.recipient_pubkey)
+            .bind(&limit.request_times)
+            .execute(&self.pool)
+            .await?;
+            Ok(())
+        }
+
+        async fn list_moderation_rules(
+            &self,
+        ) -> anyhow::Result<Vec<crate::mls_gateway::firestore::ModerationRule>> {
+            let rows: Vec<(String, String, String, Option<String>, DateTime<Utc>)> =
+                sqlx::query_as(
+                    "SELECT list, target, value, reason, created_at FROM mls_moderation_rules",
+                )
+                .fetch_all(&self.pool)
+                .await?;
+            Ok(rows
+                .into_iter()
+                .map(|(list, target, value, reason, created_at)| {
+                    crate::mls_gateway::firestore::ModerationRule {
+                        list,
+                        target,
+                        value,
+                        reason,
+                        created_at,
+                    }
+                })
+                .collect())
+        }
+
+        async fn put_moderation_rule(
+            &self,
+            rule: &crate::mls_gateway::firestore::ModerationRule,
+        ) -> anyhow::Result<()> {
+            sqlx::query(
+                "INSERT INTO mls_moderation_rules (list, target, value, reason, created_at)
+                 VALUES ($1, $2, $3, $4, $5)
+                 ON CONFLICT (list, target, value) DO UPDATE SET reason = EXCLUDED.reason, created_at = EXCLUDED.created_at"
+            )
+            .bind(&rule.list)
+            .bind(&rule.target)
+            .bind(&rule.value)
+            .bind(&rule.reason)
+            .bind(rule.created_at)
+            .execute(&self.pool)
+            .await?;
+            Ok(())
+        }
+
+        async fn delete_moderation_rule(
+            &self,
+            list: &str,
+            target: &str,
+            value: &str,
+        ) -> anyhow::Result<bool> {
+            let result = sqlx::query(
+                "DELETE FROM mls_moderation_rules WHERE list = $1 AND target = $2 AND value = $3",
+            )
+            .bind(list)
+            .bind(target)
+            .bind(value)
+            .execute(&self.pool)
+            .await?;
+            Ok(result.rows_affected() > 0)
+        }
+
+        async fn record_group_activity(
+            &self,
+            group_id: &str,
+            sender: &str,
+            epoch: Option<i64>,
+        ) -> anyhow::Result<GroupStats> {
+            let now = Utc::now().timestamp();
+            // one statement, concurrent messages of a group each count
+            let row: GroupStatsRow = sqlx::query_as(
+                "INSERT INTO mls_group_stats (group_id, message_count, senders, first_epoch, last_epoch, epoch_changes, first_activity, last_activity)
+                 VALUES ($1, 1, ARRAY[$2]::TEXT[], $3, $3, 0, $4, $4)
+                 ON CONFLICT (group_id) DO UPDATE SET
+                    message_count = mls_group_stats.message_count + 1,
+                    senders = CASE WHEN $2 = ANY(mls_group_stats.senders) OR cardinality(mls_group_stats.senders) >= $5
+                        THEN mls_group_stats.senders ELSE array_append(mls_group_stats.senders, $2) END,
+                    first_epoch = COALESCE(mls_group_stats.first_epoch, $3),
+                    last_epoch = GREATEST(mls_group_stats.last_epoch, $3),
+                    epoch_changes = mls_group_stats.epoch_changes + CASE WHEN $3 > mls_group_stats.last_epoch THEN 1 ELSE 0 END,
+                    last_activity = GREATEST(mls_group_stats.last_activity, $4)
+                 RETURNING group_id, message_count, senders, first_epoch, last_epoch, epoch_changes, first_activity, last_activity"
+            )
+            .bind(group_id)
+            .bind(sender)
+            .bind(epoch)
+            .bind(now)
+            .bind(crate::mls_gateway::firestore::MAX_TRACKED_SENDERS as i32)
+            .fetch_one(&self.pool)
+            .await?;
+            Ok(group_stats(row))
+        }
+
+        async fn get_group_stats(&self, group_id: &str) -> anyhow::Result<Option<GroupStats>> {
+            let row: Option<GroupStatsRow> = sqlx::query_as(
+                "SELECT group_id, message_count, senders, first_epoch, last_epoch, epoch_changes, first_activity, last_activity
+                 FROM mls_group_stats WHERE group_id = $1"
+            )
+            .bind(group_id)
+            .fetch_optional(&self.pool)
+            .await?;
+            Ok(row.map(group_stats))
+        }
+
+        async fn list_group_stats(&self) -> anyhow::Result<Vec<GroupStats>> {
+            let rows: Vec<GroupStatsRow> = sqlx::query_as(
+                "SELECT group_id, message_count, senders, first_epoch, last_epoch, epoch_changes, first_activity, last_activity
+                 FROM mls_group_stats"
+            )
+            .fetch_all(&self.pool)
+            .await?;
+            Ok(rows.into_iter().map(group_stats).collect())
+        }
+
+        async fn get_roster_policy(
+            &self,
+            group_id: &str,
+            sequence: u64,
+        ) -> anyhow::Result<Option<RosterPolicyDocument>> {
+            let row: Option<(String, Vec<String>, String, DateTime<Utc>, DateTime<Utc>)> = sqlx::query_as(
+                "SELECT operation, member_pubkeys, admin_pubkey, created_at, updated_at FROM mls_roster_policy WHERE group_id = $1 AND sequence = $2"
+            )
+            .bind(group_id)
+            .bind(sequence as i64)
+            .fetch_optional(&self.pool)
+            .await?;
+            Ok(row.map(
+                |(operation, member_pubkeys, admin_pubkey, created_at, updated_at)| {
+                    RosterPolicyDocument {
+                        group_id: group_id.to_string(),
+                        sequence,
+                        operation,
+                        member_pubkeys,
+                        admin_pubkey,
+                        created_at: created_at.timestamp(),
+                        updated_at: updated_at.timestamp(),
+                    }
+                },
+            ))
+        }
+
+        async fn store_roster_anomaly(&self, anomaly: &RosterAnomaly) -> anyhow::Result<()> {
+            let stored = anomaly
+                .stored
+                .as_ref()
+                .map(serde_json::to_string)
+                .transpose()?;
+            sqlx::query(
+                "INSERT INTO mls_roster_anomalies (event_id, group_id, sequence, last_sequence, kind, action, admin_pubkey, operation, member_pubkeys, stored, created_at)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
+                 ON CONFLICT (event_id) DO NOTHING"
+            )
+            .bind(&anomaly.event_id)
+            .bind(&anomaly.group_id)
+            .bind(anomaly.sequence as i64)
+            .bind(anomaly.last_sequence.map(|s| s as i64))
+            .bind(&anomaly.kind)
+            .bind(&anomaly.action)
+            .bind(&anomaly.admin_pubkey)
+            .bind(&anomaly.operation)
+            .bind(&anomaly.member_pubkeys)
+            .bind(stored)
+            .bind(anomaly.created_at)
+            .execute(&self.pool)
+            .await?;
+            Ok(())
+        }
+
+        async fn list_roster_anomalies(
+            &self,
+            group_id: Option<&str>,
+        ) -> anyhow::Result<Vec<RosterAnomaly>> {
+            let rows: Vec<RosterAnomalyRow> = sqlx::query_as(
+                "SELECT event_id, group_id, sequence, last_sequence, kind, action, admin_pubkey, operation, member_pubkeys, stored, created_at
+                 FROM mls_roster_anomalies WHERE $1::TEXT IS NULL OR group_id = $1 ORDER BY created_at, sequence"
+            )
+            .bind(group_id)
+            .fetch_all(&self.pool)
+            .await?;
+            Ok(rows.into_iter().map(roster_anomaly).collect())
+        }
+
+        async fn apply_roster_operation(&self, op: &RosterOperation) -> anyhow::Result<()> {
+            let mut tx = self.pool.begin().await?;
+            let created_at = chrono::DateTime::from_timestamp(op.created_at, 0)
+                .ok_or_else(|| anyhow::anyhow!("Invalid timestamp"))?;
+            // the primary key rejects a second record at the same sequence
+            sqlx::query(
+                "INSERT INTO mls_roster_policy (id, group_id, sequence, operation, member_pubkeys, admin_pubkey, created_at, updated_at)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7, NOW())"
+            )
+            .bind(format!("{}_{}", op.group_id, op.sequence))
+            .bind(&op.group_id)
+            .bind(op.sequence as i64)
+            .bind(&op.operation)
+            .bind(&op.member_pubkeys)
+            .bind(&op.admin_pubkey)
+            .bind(created_at)
+            .execute(&mut *tx)
+            .await?;
+
+            let row: Option<(Option<String>, String, Option<i64>, Vec<String>, bool, DateTime<Utc>, DateTime<Utc>)> = sqlx::query_as(
+                "SELECT display_name, owner_pubkey, last_epoch, admin_pubkeys, service_member, created_at, updated_at FROM mls_groups WHERE group_id = $1 FOR UPDATE"
+            )
+            .bind(&op.group_id)
+            .fetch_optional(&mut *tx)
+            .await?;
+            let group = row.map(
+                |(
+                    display_name,
+                    owner_pubkey,
+                    last_epoch,
+                    admin_pubkeys,
+                    service_member,
+                    created_at,
+                    updated_at,
+                )| {
+                    crate::mls_gateway::firestore::GroupInfo {
+                        group_id: op.group_id.clone(),
+                        display_name,
+                        owner_pubkey,
+                        last_epoch,
+                        admin_pubkeys,
+                        service_member,
+                        created_at,
+                        updated_at,
+                    }
+                },
+            );
+            if let Some(group) = op.apply(group, Utc::now()) {
+                sqlx::query(
+                    "INSERT INTO mls_groups (group_id, display_name, owner_pubkey, last_epoch, admin_pubkeys, created_at, updated_at)
+                     VALUES ($1, $2, $3, $4, $5, $6, $7)
+                     ON CONFLICT (group_id) DO UPDATE SET owner_pubkey = EXCLUDED.owner_pubkey, admin_pubkeys = EXCLUDED.admin_pubkeys,
+                     updated_at = EXCLUDED.updated_at"
+                )
+                .bind(&group.group_id)
+                .bind(&group.display_name)
+                .bind(&group.owner_pubkey)
+                .bind(group.last_epoch)
+                .bind(&group.admin_pubkeys)
+                .bind(group.created_at)
+                .bind(group.updated_at)
+                .execute(&mut *tx)
+                .await?;
+            }
+            tx.commit().await?;
+            info!(
+                "Applied roster operation {} to group {} at seq {}",
+                op.operation, op.group_id, op.sequence
+            );
+            Ok(())
+        }
+
+        async fn create_pending_roster_op(&self, op: &PendingRosterOp) -> anyhow::Result<()> {
+            let result = sqlx::query(
+                "INSERT INTO mls_roster_pending_ops (event_id, group_id, sequence, operation, member_pubkeys, role_admin, proposer, approvals, required, status, created_at, expires_at, event)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13)
+                 ON CONFLICT (event_id) DO NOTHING"
+            )
+            .bind(&op.event_id)
+            .bind(&op.group_id)
+            .bind(op.sequence as i64)
+            .bind(&op.operation)
+            .bind(&op.member_pubkeys)
+            .bind(op.role_admin)
+            .bind(&op.proposer)
+            .bind(&op.approvals)
+            .bind(op.required as i32)
+            .bind(&op.status)
+            .bind(op.created_at)
+            .bind(op.expires_at)
+            .bind(&op.event)
+            .execute(&self.pool)
+            .await?;
+            if result.rows_affected() == 0 {
+                anyhow::bail!("roster operation {} is already pending", op.event_id);
+            }
+            Ok(())
+        }
+
+        async fn approve_pending_roster_op(
+            &self,
+            event_id: &str,
+            approver: &str,
+            now: i64,
+        ) -> anyhow::Result<Option<(roster_quorum::Approval, PendingRosterOp)>> {
+            // the row lock serializes concurrent approvals of the same operation
+            let mut tx = self.pool.begin().await?;
+            let row: Option<PendingRosterOpRow> = sqlx::query_as(
+                "SELECT event_id, group_id, sequence, operation, member_pubkeys, role_admin, proposer, approvals, required, status, created_at, expires_at, event
+                 FROM mls_roster_pending_ops WHERE event_id = $1 FOR UPDATE"
+            )
+            .bind(event_id)
+            .fetch_optional(&mut *tx)
+            .await?;
+            let Some(mut op) = row.map(pending_roster_op) else {
+                return Ok(None);
+            };
+            let approval = roster_quorum::approve(&mut op, approver, now);
+            if !matches!(
+                approval,
+                roster_quorum::Approval::Duplicate | roster_quorum::Approval::Closed
+            ) {
+                sqlx::query("UPDATE mls_roster_pending_ops SET approvals = $2, status = $3 WHERE event_id = $1")
+                    .bind(event_id)
+                    .bind(&op.approvals)
+                    .bind(&op.status)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            tx.commit().await?;
+            Ok(Some((approval, op)))
+        }
+
+        async fn set_pending_roster_op_status(
+            &self,
+            event_id: &str,
+            status: &str,
+        ) -> anyhow::Result<()> {
+            sqlx::query("UPDATE mls_roster_pending_ops SET status = $2 WHERE event_id = $1")
+                .bind(event_id)
+                .bind(status)
+                .execute(&self.pool)
+                .await?;
+            Ok(())
+        }
+
+        async fn get_pending_roster_op(
+            &self,
+            event_id: &str,
+        ) -> anyhow::Result<Option<PendingRosterOp>> {
+            let row: Option<PendingRosterOpRow> = sqlx::query_as(
+                "SELECT event_id, group_id, sequence, operation, member_pubkeys, role_admin, proposer, approvals, required, status, created_at, expires_at, event
+                 FROM mls_roster_pending_ops WHERE event_id = $1"
+            )
+            .bind(event_id)
+            .fetch_optional(&self.pool)
+            .await?;
+            Ok(row.map(pending_roster_op))
+        }
+
+        async fn list_pending_roster_ops(
+            &self,
+            group_id: Option<&str>,
+        ) -> anyhow::Result<Vec<PendingRosterOp>> {
+            let rows: Vec<PendingRosterOpRow> = sqlx::query_as(
+                "SELECT event_id, group_id, sequence, operation, member_pubkeys, role_admin, proposer, approvals, required, status, created_at, expires_at, event
+                 FROM mls_roster_pending_ops WHERE $1::TEXT IS NULL OR group_id = $1 ORDER BY created_at, sequence"
+            )
+            .bind(group_id)
+            .fetch_all(&self.pool)
+            .await?;
+            Ok(rows.into_iter().map(pending_roster_op).collect())
+        }
+
+        async fn get_attestation(&self, pubkey: &str) -> anyhow::Result<Option<AttestationRecord>> {
+            let row: Option<(String, String, String, DateTime<Utc>, DateTime<Utc>)> = sqlx::query_as(
+                "SELECT pubkey, issuer, subject, attested_at, expires_at FROM mls_attested_pubkeys WHERE pubkey = $1"
+            )
+            .bind(pubkey)
+            .fetch_optional(&self.pool)
+            .await?;
+            Ok(row.map(
+                |(pubkey, issuer, subject, attested_at, expires_at)| AttestationRecord {
+                    pubkey,
+                    issuer,
+                    subject,
+                    attested_at,
+                    expires_at,
+                },
+            ))
+        }
+
+        async fn put_attestation(&self, record: &AttestationRecord) -> anyhow::Result<()> {
+            sqlx::query(
+                "INSERT INTO mls_attested_pubkeys (pubkey, issuer, subject, attested_at, expires_at)
+                 VALUES ($1, $2, $3, $4, $5)
+                 ON CONFLICT (pubkey) DO UPDATE SET issuer = EXCLUDED.issuer, subject = EXCLUDED.subject,
+                     attested_at = EXCLUDED.attested_at, expires_at = EXCLUDED.expires_at"
+            )
+            .bind(&record.pubkey)
+            .bind(&record.issuer)
+            .bind(&record.subject)
+            .bind(record.attested_at)
+            .bind(record.expires_at)
+            .execute(&self.pool)
+            .await?;
+            Ok(())
+        }
+
+        async fn get_subscription_cursor(
+            &self,
+            pubkey: &str,
+            subscription_id: &str,
+        ) -> anyhow::Result<Option<SubscriptionCursor>> {
+            let row: Option<(String, String, i64, String, DateTime<Utc>, DateTime<Utc>)> =
+                sqlx::query_as(
+                    "SELECT pubkey, subscription_id, created_at, event_id, updated_at, expires_at
+                 FROM mls_subscription_cursors WHERE pubkey = $1 AND subscription_id = $2",
+                )
+                .bind(pubkey)
+                .bind(subscription_id)
+                .fetch_optional(&self.pool)
+                .await?;
+            Ok(row.map(
+                |(pubkey, subscription_id, created_at, event_id, updated_at, expires_at)| {
+                    SubscriptionCursor {
+                        pubkey,
+                        subscription_id,
+                        created_at: created_at as u64,
+                        event_id,
+                        updated_at,
+                        expires_at,
+                    }
+                },
+            ))
+        }
+
+        async fn put_subscription_cursors(
+            &self,
+            cursors: &[SubscriptionCursor],
+        ) -> anyhow::Result<()> {
+            for cursor in cursors {
+                sqlx::query(
+                    "INSERT INTO mls_subscription_cursors (pubkey, subscription_id, created_at, event_id, updated_at, expires_at)
+                     VALUES ($1, $2, $3, $4, $5, $6)
+                     ON CONFLICT (pubkey, subscription_id) DO UPDATE SET created_at = EXCLUDED.created_at,
+                         event_id = EXCLUDED.event_id, updated_at = EXCLUDED.updated_at, expires_at = EXCLUDED.expires_at"
+                )
+                .bind(&cursor.pubkey)
+                .bind(&cursor.subscription_id)
+                .bind(cursor.created_at as i64)
+                .bind(&cursor.event_id)
+                .bind(cursor.updated_at)
+                .bind(cursor.expires_at)
+                .execute(&self.pool)
+                .await?;
+            }
+            Ok(())
+        }
+    }
+
+    type PendingRosterOpRow = (
+        String,
+        String,
+        i64,
+        String,
+        Vec<String>,
+        bool,
+        String,
+        Vec<String>,
+        i32,
+        String,
+        i64,
+        i64,
+        Option<String>,
+    );
+
+    fn pending_roster_op(row: PendingRosterOpRow) -> PendingRosterOp {
+        let (
+            event_id,
+            group_id,
+            sequence,
+            operation,
+            member_pubkeys,
+            role_admin,
+            proposer,
+            approvals,
+            required,
+            status,
+            created_at,
+            expires_at,
+            event,
+        ) = row;
+        PendingRosterOp {
+            event_id,
+            group_id,
+            sequence: sequence as u64,
+            operation,
+            member_pubkeys,
+            role_admin,
+            proposer,
+            approvals,
+            required: required as u32,
+            status,
+            created_at,
+            expires_at,
+            event,
+        }
+    }
+
+    type RosterAnomalyRow = (
+        String,
+        String,
+        i64,
+        Option<i64>,
+        String,
+        String,
+        String,
+        String,
+        Vec<String>,
+        Option<String>,
+        i64,
+    );
+
+    fn roster_anomaly(row: RosterAnomalyRow) -> RosterAnomaly {
+        let (
+            event_id,
+            group_id,
+            sequence,
+            last_sequence,
+            kind,
+            action,
+            admin_pubkey,
+            operation,
+            member_pubkeys,
+            stored,
+            created_at,
+        ) = row;
+        RosterAnomaly {
+            group_id,
+            sequence: sequence as u64,
+            last_sequence: last_sequence.map(|s| s as u64),
+            kind,
+            action,
+            event_id,
+            admin_pubkey,
+            operation,
+            member_pubkeys,
+            stored: stored.and_then(|s| serde_json::from_str(&s).ok()),
+            created_at,
+        }
+    }
+
+    type GroupStatsRow = (
+        String,
+        i64,
+        Vec<String>,
+        Option<i64>,
+        Option<i64>,
+        i64,
+        i64,
+        i64,
+    );
+
+    fn group_stats(row: GroupStatsRow) -> GroupStats {
+        let (
+            group_id,
+            message_count,
+            senders,
+            first_epoch,
+            last_epoch,
+            epoch_changes,
+            first_activity,
+            last_activity,
+        ) = row;
+        GroupStats {
+            group_id,
+            message_count: message_count as u64,
+            senders,
+            first_epoch,
+            last_epoch,
+            epoch_changes: epoch_changes as u64,
+            first_activity,
+            last_activity,
+        }
     }
 }
 
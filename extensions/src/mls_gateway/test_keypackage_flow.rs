@@ -15,25 +15,25 @@ mod tests {
     #[tokio::test]
     async fn test_keypackage_request_flow() {
         // This test demonstrates what SHOULD happen:
-        
+
         // 1. Bob publishes KeyPackages (443)
         let bob_kp1 = create_test_keypackage("bob_pubkey", "kp_content_1");
         let bob_kp2 = create_test_keypackage("bob_pubkey", "kp_content_2");
         let bob_kp3 = create_test_keypackage("bob_pubkey", "kp_content_3");
-        
+
         // 2. Alice sends a KeyPackage request (447) for Bob
         let alice_request = create_keypackage_request("alice_pubkey", "bob_pubkey");
-        
+
         // 3. MLS Gateway processes the request:
         //    - Queries Bob's KeyPackages
         //    - Marks some as consumed (not the last one)
         //    - Stores them for delivery to Alice
-        
+
         // 4. Alice should receive Bob's KeyPackages through her subscription
-        
+
         // The problem: Step 4 doesn't work because we can't push events
         // to Alice's subscription from the extension
-        
+
         println!("Test demonstrates the intended flow");
         println!("Current issue: Cannot deliver KeyPackages to requester");
         println!("Solution options:");
@@ -41,7 +41,7 @@ mod tests {
         println!("2. Use a different delivery mechanism (e.g., REST API)");
         println!("3. Store KeyPackages and let clients query them separately");
     }
-    
+
     fn create_test_keypackage(author: &str, content: &str) -> Event {
         let fake_sig = hex::encode([0u8; 64]); // 64 bytes = 128 hex chars
         let author_pubkey = if author.len() == 64 {
@@ -49,8 +49,9 @@ mod tests {
         } else {
             hex::encode([0u8; 32]) // Use zero pubkey if not provided
         };
-        
-        Event::from_str(&format!(r#"{{
+
+        Event::from_str(&format!(
+            r#"{{
             "id": "{}",
             "pubkey": "{}",
             "created_at": {},
@@ -69,9 +70,10 @@ mod tests {
             chrono::Utc::now().timestamp(),
             content,
             fake_sig
-        )).unwrap()
+        ))
+        .unwrap()
     }
-    
+
     fn create_keypackage_request(requester: &str, target: &str) -> Event {
         let fake_sig = hex::encode([0u8; 64]); // 64 bytes = 128 hex chars
         let requester_pubkey = if requester.len() == 64 {
@@ -84,8 +86,9 @@ mod tests {
         } else {
             hex::encode([2u8; 32]) // Use different pubkey for target
         };
-        
-        Event::from_str(&format!(r#"{{
+
+        Event::from_str(&format!(
+            r#"{{
             "id": "{}",
             "pubkey": "{}",
             "created_at": {},
@@ -102,7 +105,8 @@ mod tests {
             chrono::Utc::now().timestamp(),
             target_pubkey,
             fake_sig
-        )).unwrap()
+        ))
+        .unwrap()
     }
 }
 
@@ -122,19 +126,19 @@ impl QueryBasedConsumption {
     pub fn should_consume(filter: &Filter) -> bool {
         filter.kinds.iter().any(|&k| k == 443)
     }
-    
+
     /// Process query results and mark KeyPackages as consumed
-    pub async fn process_query_results(
-        events: &[Event],
-        requester: &str,
-    ) -> anyhow::Result<()> {
+    pub async fn process_query_results(events: &[Event], requester: &str) -> anyhow::Result<()> {
         for event in events {
             if event.kind() == 443 {
-                println!("Would mark KeyPackage {} as consumed for requester {}",
-                         event.id_str(), requester);
+                println!(
+                    "Would mark KeyPackage {} as consumed for requester {}",
+                    event.id_str(),
+                    requester
+                );
                 // TODO: Actually mark as consumed in storage
             }
         }
         Ok(())
     }
-}
\ No newline at end of file
+}
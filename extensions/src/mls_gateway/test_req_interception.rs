@@ -3,8 +3,11 @@
 #[cfg(test)]
 mod tests {
     use crate::mls_gateway::{MlsGateway, MlsGatewayConfig};
-    use nostr_relay::{Extension, db::{Event, SortList}, ExtensionReqResult, PostProcessResult};
     use nostr_relay::message::Subscription;
+    use nostr_relay::{
+        db::{Event, SortList},
+        Extension, ExtensionReqResult, PostProcessResult, SessionInfo,
+    };
 
     #[test]
     fn test_process_req_keypackage_query() {
@@ -16,29 +19,91 @@ mod tests {
             id: "test_sub_1".to_string(),
             filters: vec![],
         };
-        
+
         // Add filter for kind 443
         let mut filter = nostr_relay::db::Filter::default();
         filter.kinds = SortList::from(vec![443]);
-        let author_bytes: [u8; 32] = hex::decode("1234567890abcdef1234567890abcdef1234567890abcdef1234567890abcdef")
-            .unwrap()
-            .try_into()
-            .unwrap();
+        let author_bytes: [u8; 32] =
+            hex::decode("1234567890abcdef1234567890abcdef1234567890abcdef1234567890abcdef")
+                .unwrap()
+                .try_into()
+                .unwrap();
         filter.authors = SortList::from(vec![author_bytes]);
         subscription.filters.push(filter);
 
         // Test process_req
-        let result = gateway.process_req(1, &subscription);
-        
+        let result = gateway.process_req(
+            &SessionInfo {
+                id: 1,
+                ..Default::default()
+            },
+            &subscription,
+        );
+
         match result {
-            ExtensionReqResult::Continue => {
-                // Expected: we let the database query proceed
-                println!("✓ process_req returned Continue for KeyPackage query");
+            ExtensionReqResult::Rewrite(rewritten) => {
+                // Expected: the database query proceeds with the per query cap
+                assert_eq!(rewritten.filters.len(), 1);
+                assert_eq!(rewritten.filters[0].limit, Some(1));
+                println!("✓ process_req capped the KeyPackage query");
             }
-            _ => panic!("Expected Continue result for KeyPackage query"),
+            _ => panic!("Expected Rewrite result for KeyPackage query"),
         }
     }
 
+    #[test]
+    fn test_process_req_keypackage_limit_rewrite() {
+        let mut config = MlsGatewayConfig::default();
+        config.max_keypackages_per_query = 5;
+        let gateway = MlsGateway::new(config);
+
+        // A filter mixing kinds is split, only the 443 part is capped at 2
+        let mut filter = nostr_relay::db::Filter::default();
+        filter.kinds = SortList::from(vec![1, 443]);
+        filter.limit = Some(100);
+        let subscription = Subscription {
+            id: "test_sub_mixed".to_string(),
+            filters: vec![filter],
+        };
+
+        match gateway.process_req(
+            &SessionInfo {
+                id: 1,
+                ..Default::default()
+            },
+            &subscription,
+        ) {
+            ExtensionReqResult::Rewrite(rewritten) => {
+                assert_eq!(rewritten.id, "test_sub_mixed");
+                assert_eq!(rewritten.filters.len(), 2);
+                assert_eq!(rewritten.filters[0].kinds, SortList::from(vec![1]));
+                assert_eq!(rewritten.filters[0].limit, Some(100));
+                assert_eq!(rewritten.filters[1].kinds, SortList::from(vec![443]));
+                assert_eq!(rewritten.filters[1].limit, Some(2));
+            }
+            _ => panic!("Expected Rewrite result for mixed kind query"),
+        }
+
+        // Compliant queries are left alone
+        let mut filter = nostr_relay::db::Filter::default();
+        filter.kinds = SortList::from(vec![443]);
+        filter.limit = Some(2);
+        let subscription = Subscription {
+            id: "test_sub_capped".to_string(),
+            filters: vec![filter],
+        };
+        assert!(matches!(
+            gateway.process_req(
+                &SessionInfo {
+                    id: 1,
+                    ..Default::default()
+                },
+                &subscription
+            ),
+            ExtensionReqResult::Continue
+        ));
+    }
+
     #[test]
     fn test_process_req_non_keypackage_query() {
         let config = MlsGatewayConfig::default();
@@ -49,15 +114,21 @@ mod tests {
             id: "test_sub_2".to_string(),
             filters: vec![],
         };
-        
+
         // Add filter for kind 1
         let mut filter = nostr_relay::db::Filter::default();
         filter.kinds = SortList::from(vec![1]);
         subscription.filters.push(filter);
 
         // Test process_req
-        let result = gateway.process_req(1, &subscription);
-        
+        let result = gateway.process_req(
+            &SessionInfo {
+                id: 1,
+                ..Default::default()
+            },
+            &subscription,
+        );
+
         match result {
             ExtensionReqResult::Continue => {
                 // Expected: non-KeyPackage queries should continue normally
@@ -80,7 +151,7 @@ mod tests {
 
         // Create mock KeyPackage events
         let mut events = Vec::new();
-        
+
         // Create a KeyPackage event (kind 443)
         let keypackage_json = r#"{
             "id": "1234567890abcdef1234567890abcdef1234567890abcdef1234567890abcdef",
@@ -96,7 +167,7 @@ mod tests {
             "content": "0123456789abcdef",
             "sig": "fedcba0987654321fedcba0987654321fedcba0987654321fedcba0987654321fedcba0987654321fedcba0987654321fedcba0987654321fedcba0987654321"
         }"#;
-        
+
         if let Ok(event) = serde_json::from_str::<Event>(keypackage_json) {
             events.push(event);
         }
@@ -111,19 +182,33 @@ mod tests {
             "content": "Hello world",
             "sig": "fedcba0987654321fedcba0987654321fedcba0987654321fedcba0987654321fedcba0987654321fedcba0987654321fedcba0987654321fedcba0987654321"
         }"#;
-        
+
         if let Ok(event) = serde_json::from_str::<Event>(regular_event_json) {
             events.push(event);
         }
 
         // Test post_process_query_results
-        let result = gateway.post_process_query_results(1, &subscription, events.clone());
-        
+        let result = gateway.post_process_query_results(
+            &SessionInfo {
+                id: 1,
+                ..Default::default()
+            },
+            &subscription,
+            events.clone(),
+        );
+
         // Verify results
         assert_eq!(result.events.len(), 2, "Should return all events");
-        assert_eq!(result.consumed_events.len(), 0, "Should not mark any as consumed synchronously");
-        
-        println!("✓ post_process_query_results handled {} events correctly", result.events.len());
+        assert_eq!(
+            result.consumed_events.len(),
+            0,
+            "Should not mark any as consumed synchronously"
+        );
+
+        println!(
+            "✓ post_process_query_results handled {} events correctly",
+            result.events.len()
+        );
     }
 
     #[test]
@@ -139,7 +224,7 @@ mod tests {
 
         // Create only non-KeyPackage events
         let mut events = Vec::new();
-        
+
         let regular_event_json = r#"{
             "id": "3234567890abcdef1234567890abcdef1234567890abcdef1234567890abcdef",
             "pubkey": "cbcdef1234567890abcdef1234567890abcdef1234567890abcdef1234567890",
@@ -149,18 +234,106 @@ mod tests {
             "content": "Just a regular event",
             "sig": "fedcba0987654321fedcba0987654321fedcba0987654321fedcba0987654321fedcba0987654321fedcba0987654321fedcba0987654321fedcba0987654321"
         }"#;
-        
+
         if let Ok(event) = serde_json::from_str::<Event>(regular_event_json) {
             events.push(event);
         }
 
         // Test post_process_query_results
-        let result = gateway.post_process_query_results(1, &subscription, events.clone());
-        
+        let result = gateway.post_process_query_results(
+            &SessionInfo {
+                id: 1,
+                ..Default::default()
+            },
+            &subscription,
+            events.clone(),
+        );
+
         // Verify results
         assert_eq!(result.events.len(), 1, "Should return all events");
-        assert_eq!(result.consumed_events.len(), 0, "Should not consume any events");
-        
+        assert_eq!(
+            result.consumed_events.len(),
+            0,
+            "Should not consume any events"
+        );
+
         println!("✓ post_process_query_results correctly ignored non-KeyPackage events");
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_post_process_query_results_recipient_only() {
+        use nostr_relay::db::secp256k1::{Keypair, SECP256K1};
+
+        let mut config = MlsGatewayConfig::default();
+        config.recipient_only_delivery = true;
+        let gateway = MlsGateway::new(config);
+
+        let subscription = Subscription {
+            id: "test_sub_5".to_string(),
+            filters: vec![],
+        };
+        let key = Keypair::from_seckey_str(SECP256K1, &"05".repeat(32)).unwrap();
+        let recipient = "aa".repeat(32);
+        let p_tag = vec![vec!["p".to_string(), recipient.clone()]];
+        let events = vec![
+            Event::create(
+                &key,
+                nostr_relay::db::now(),
+                1059,
+                p_tag.clone(),
+                "wrap".to_string(),
+            )
+            .unwrap(),
+            Event::create(&key, nostr_relay::db::now(), 446, p_tag, "dm".to_string()).unwrap(),
+            Event::create(&key, nostr_relay::db::now(), 1, vec![], "note".to_string()).unwrap(),
+        ];
+
+        let anonymous = gateway.post_process_query_results(
+            &SessionInfo {
+                id: 1,
+                ..Default::default()
+            },
+            &subscription,
+            events.clone(),
+        );
+        assert_eq!(
+            anonymous
+                .events
+                .iter()
+                .map(|e| e.kind())
+                .collect::<Vec<_>>(),
+            vec![1]
+        );
+
+        let session = SessionInfo {
+            id: 2,
+            auth_pubkey: Some(recipient),
+            ..Default::default()
+        };
+        let result = gateway.post_process_query_results(&session, &subscription, events);
+        assert_eq!(result.events.len(), 3);
+    }
+
+    #[test]
+    fn test_post_processes_only_affected_kinds() {
+        let gateway = MlsGateway::new(MlsGatewayConfig::default());
+        let session = SessionInfo {
+            id: 1,
+            ..Default::default()
+        };
+        let subscription = |kinds: Vec<u16>| {
+            let mut filter = nostr_relay::db::Filter::default();
+            filter.kinds = SortList::from(kinds);
+            Subscription {
+                id: "test_sub_6".to_string(),
+                filters: vec![filter],
+            }
+        };
+
+        // notes are sent as they are read, filters without kinds may match keypackages
+        assert!(!gateway.post_processes(&session, &subscription(vec![1, 7])));
+        assert!(gateway.post_processes(&session, &subscription(vec![1, 443])));
+        assert!(gateway.post_processes(&session, &subscription(vec![1059])));
+        assert!(gateway.post_processes(&session, &subscription(vec![])));
+    }
+}
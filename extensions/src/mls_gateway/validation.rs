@@ -0,0 +1,209 @@
+//! Tag validation policy per MLS kind.
+//!
+//! `validation_mode` selects, per kind, what happens when required tags are
+//! missing or unexpected outer tags are present:
+//! - `strict`: the event is rejected with OK false `invalid:`
+//! - `lenient`: the event is accepted, the issue is logged and counted
+//! - `off`: the event is accepted, the issue is only counted
+//!
+//! ```toml
+//! [extensions.mls_gateway.validation_mode]
+//! kind_443 = "strict"
+//! kind_445 = "lenient"
+//! ```
+//!
+//! `nip44_validation` applies the same modes to the content of encrypted kinds
+//! (446, 1059), which must be shaped like NIP-44 v2 ciphertext: base64 within
+//! the size bounds, version byte 2 and a padded ciphertext length. Its default
+//! `off` skips the check; `strict` keeps garbage out of archives and clients.
+
+use metrics::counter;
+use nostr_relay::db::Event;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ValidationMode {
+    Strict,
+    #[default]
+    Lenient,
+    Off,
+}
+
+/// Outer tags allowed on group messages (445) per NIP-EE
+const GROUP_MESSAGE_TAGS: [&str; 3] = ["h", "k", "mls_ver"];
+
+fn has_tag(event: &Event, name: &str) -> bool {
+    event
+        .tags()
+        .iter()
+        .any(|tag| tag.len() >= 2 && tag[0] == name)
+}
+
+/// Tag issues of an event as (issue, description)
+pub fn tag_issues(event: &Event) -> Vec<(&'static str, String)> {
+    let mut issues = Vec::new();
+    match super::kinds::canonical(event.kind()) {
+        443 => {
+            for tag in ["mls_protocol_version", "ciphersuite", "extensions"] {
+                if !has_tag(event, tag) {
+                    issues.push(("missing_tag", format!("missing required tag {}", tag)));
+                }
+            }
+            let has_relays = event
+                .tags()
+                .iter()
+                .any(|tag| tag.len() >= 2 && (tag[0] == "relays" || tag[0] == "relay"));
+            if !has_relays {
+                issues.push(("missing_tag", "missing required tag relays".to_owned()));
+            }
+        }
+        445 => {
+            if !has_tag(event, "h") {
+                issues.push(("missing_tag", "missing required tag h".to_owned()));
+            }
+            let unexpected = event
+                .tags()
+                .iter()
+                .filter(|tag| !tag.is_empty() && !GROUP_MESSAGE_TAGS.contains(&tag[0].as_str()))
+                .count();
+            if unexpected > 0 {
+                issues.push((
+                    "unexpected_tag",
+                    format!("{} non-standard outer tags", unexpected),
+                ));
+            }
+        }
+        446 | 1059 => {
+            if !has_tag(event, "p") {
+                issues.push(("missing_tag", "missing required tag p".to_owned()));
+            }
+        }
+        _ => {}
+    }
+    issues
+}
+
+/// Apply the validation mode of the event kind, returns the rejection reason in strict mode
+pub fn check(event: &Event, mode: ValidationMode) -> Result<(), String> {
+    let issues = tag_issues(event);
+    // counters are labelled by role, the same under any kind numbering
+    let canonical = super::kinds::canonical(event.kind());
+    let label = canonical.to_string();
+    let kind = event.kind();
+    for (issue, description) in &issues {
+        counter!("mls_gateway_validation_issues", "kind" => label.clone(), "issue" => *issue)
+            .increment(1);
+        // per-kind counters predating validation_mode
+        match (canonical, *issue) {
+            (443, "missing_tag") => counter!("mls_gateway_443_missing_tag").increment(1),
+            (445, "unexpected_tag") => counter!("mls_gateway_445_unexpected_tag").increment(1),
+            _ => {}
+        }
+        if mode == ValidationMode::Lenient {
+            warn!("kind {} event {}: {}", kind, event.id_str(), description);
+        }
+    }
+    match (mode, issues.into_iter().next()) {
+        (ValidationMode::Strict, Some((_, description))) => Err(description),
+        _ => Ok(()),
+    }
+}
+
+/// Apply `mode` to the NIP-44 shape of an encrypted kind's content, returns the rejection reason in strict mode
+pub fn check_payload(event: &Event, mode: ValidationMode) -> Result<(), String> {
+    if mode == ValidationMode::Off {
+        return Ok(());
+    }
+    if !matches!(super::kinds::canonical(event.kind()), 446 | 1059) {
+        return Ok(());
+    }
+    let Err(e) = crate::nip44::check_payload(event.content()) else {
+        return Ok(());
+    };
+    let kind = event.kind();
+    counter!("mls_gateway_validation_issues", "kind" => super::kinds::canonical(kind).to_string(), "issue" => "invalid_payload").increment(1);
+    match mode {
+        ValidationMode::Strict => Err(format!("content is not a NIP-44 v2 payload: {}", e)),
+        _ => {
+            warn!(
+                "kind {} event {}: content is not a NIP-44 v2 payload: {}",
+                kind,
+                event.id_str(),
+                e
+            );
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use nostr_relay::db::secp256k1::{Keypair, SECP256K1};
+
+    fn event(kind: u16, tags: &[&[&str]]) -> Result<Event> {
+        let key = Keypair::from_seckey_str(SECP256K1, &"08".repeat(32))?;
+        let tags = tags
+            .iter()
+            .map(|t| t.iter().map(|s| s.to_string()).collect())
+            .collect();
+        Ok(Event::create(
+            &key,
+            nostr_relay::db::now(),
+            kind,
+            tags,
+            "x".to_owned(),
+        )?)
+    }
+
+    #[test]
+    fn modes() -> Result<()> {
+        let complete = event(
+            443,
+            &[
+                &["mls_protocol_version", "1.0"],
+                &["ciphersuite", "0x0001"],
+                &["extensions", "0x000a"],
+                &["relays", "wss://r"],
+            ],
+        )?;
+        assert!(tag_issues(&complete).is_empty());
+        assert!(check(&complete, ValidationMode::Strict).is_ok());
+
+        let missing = event(443, &[&["ciphersuite", "0x0001"]])?;
+        assert_eq!(tag_issues(&missing).len(), 3);
+        assert!(check(&missing, ValidationMode::Strict).is_err());
+        assert!(check(&missing, ValidationMode::Lenient).is_ok());
+        assert!(check(&missing, ValidationMode::Off).is_ok());
+
+        let group = event(445, &[&["h", "g"], &["p", "x"]])?;
+        assert_eq!(tag_issues(&group)[0].0, "unexpected_tag");
+        assert!(tag_issues(&event(1059, &[&["p", "x"]])?).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn payload_modes() -> Result<()> {
+        let garbage = event(1059, &[&["p", "x"]])?;
+        assert!(check_payload(&garbage, ValidationMode::Strict).is_err());
+        assert!(check_payload(&garbage, ValidationMode::Lenient).is_ok());
+        assert!(check_payload(&garbage, ValidationMode::Off).is_ok());
+        // only encrypted kinds carry NIP-44 payloads
+        assert!(check_payload(&event(445, &[&["h", "g"]])?, ValidationMode::Strict).is_ok());
+
+        let key = Keypair::from_seckey_str(SECP256K1, &"08".repeat(32))?;
+        let payload = crate::nip44::encrypt(&[1u8; 32], "sealed", &[2u8; 32])?;
+        let wrapped = Event::create(
+            &key,
+            nostr_relay::db::now(),
+            446,
+            vec![vec!["p".to_owned(), "x".to_owned()]],
+            payload,
+        )?;
+        assert!(check_payload(&wrapped, ValidationMode::Strict).is_ok());
+        Ok(())
+    }
+}
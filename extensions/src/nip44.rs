@@ -0,0 +1,191 @@
+//! NIP-44 v2 payload encryption.
+//!
+//! Used to open NIP-59 giftwraps addressed to the relay (service member
+//! onboarding). Only the primitives needed by the relay are implemented:
+//! conversation key, encrypt with a caller supplied nonce, decrypt, and a
+//! shape check of payloads the relay cannot decrypt.
+
+use anyhow::{anyhow, bail, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chacha20::{
+    cipher::{KeyIvInit, StreamCipher},
+    ChaCha20,
+};
+use hmac::{Hmac, Mac};
+use nostr_relay::db::secp256k1::{ecdh, Parity, SecretKey, XOnlyPublicKey};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+const VERSION: u8 = 2;
+const MIN_PLAINTEXT: usize = 1;
+const MAX_PLAINTEXT: usize = 65535;
+/// Base64 payload size bounds
+const MIN_PAYLOAD: usize = 132;
+const MAX_PAYLOAD: usize = 87472;
+
+fn hmac(key: &[u8], parts: &[&[u8]]) -> [u8; 32] {
+    let mut mac = HmacSha256::new_from_slice(key).expect("hmac accepts any key length");
+    for part in parts {
+        mac.update(part);
+    }
+    mac.finalize().into_bytes().into()
+}
+
+/// Conversation key shared by `secret` and the x-only `pubkey`
+pub fn conversation_key(secret: &SecretKey, pubkey: &XOnlyPublicKey) -> [u8; 32] {
+    let point = ecdh::shared_secret_point(&pubkey.public_key(Parity::Even), secret);
+    // HKDF-extract with salt "nip44-v2" over the shared x coordinate
+    hmac(b"nip44-v2", &[&point[..32]])
+}
+
+/// chacha key, chacha nonce and hmac key derived for a message nonce (HKDF-expand, 76 bytes)
+fn message_keys(conversation_key: &[u8; 32], nonce: &[u8; 32]) -> ([u8; 32], [u8; 12], [u8; 32]) {
+    let t1 = hmac(conversation_key, &[nonce, &[1]]);
+    let t2 = hmac(conversation_key, &[&t1, nonce, &[2]]);
+    let t3 = hmac(conversation_key, &[&t2, nonce, &[3]]);
+    let okm = [t1, t2, t3].concat();
+    let mut chacha_key = [0u8; 32];
+    let mut chacha_nonce = [0u8; 12];
+    let mut hmac_key = [0u8; 32];
+    chacha_key.copy_from_slice(&okm[0..32]);
+    chacha_nonce.copy_from_slice(&okm[32..44]);
+    hmac_key.copy_from_slice(&okm[44..76]);
+    (chacha_key, chacha_nonce, hmac_key)
+}
+
+/// Padded length of a plaintext
+pub fn padded_len(len: usize) -> usize {
+    if len <= 32 {
+        return 32;
+    }
+    let next_power = 1usize << (usize::BITS - (len - 1).leading_zeros());
+    let chunk = if next_power <= 256 {
+        32
+    } else {
+        next_power / 8
+    };
+    chunk * ((len - 1) / chunk + 1)
+}
+
+/// Encrypt with an explicit 32 byte nonce, returns the base64 payload
+pub fn encrypt(conversation_key: &[u8; 32], plaintext: &str, nonce: &[u8; 32]) -> Result<String> {
+    let len = plaintext.len();
+    if !(MIN_PLAINTEXT..=MAX_PLAINTEXT).contains(&len) {
+        bail!("invalid plaintext length {}", len);
+    }
+    let (chacha_key, chacha_nonce, hmac_key) = message_keys(conversation_key, nonce);
+    let mut buf = vec![0u8; 2 + padded_len(len)];
+    buf[0..2].copy_from_slice(&(len as u16).to_be_bytes());
+    buf[2..2 + len].copy_from_slice(plaintext.as_bytes());
+    ChaCha20::new(&chacha_key.into(), &chacha_nonce.into()).apply_keystream(&mut buf);
+    let mac = hmac(&hmac_key, &[nonce, &buf]);
+    Ok(STANDARD.encode([&[VERSION][..], nonce, &buf, &mac].concat()))
+}
+
+/// Base64 decode a payload and check its version and length bounds
+fn decode(payload: &str) -> Result<Vec<u8>> {
+    if payload.starts_with('#') {
+        bail!("unsupported encryption version");
+    }
+    if !(MIN_PAYLOAD..=MAX_PAYLOAD).contains(&payload.len()) {
+        bail!("invalid payload size {}", payload.len());
+    }
+    let data = STANDARD.decode(payload)?;
+    // version + nonce + min padded ciphertext + mac
+    if data.len() < 1 + 32 + 2 + 32 + 32 || data.len() > 1 + 32 + 2 + MAX_PLAINTEXT + 1 + 32 {
+        bail!("invalid payload length {}", data.len());
+    }
+    if data[0] != VERSION {
+        bail!("unsupported encryption version {}", data[0]);
+    }
+    Ok(data)
+}
+
+/// Check that a base64 payload is shaped like NIP-44 v2 ciphertext, without decrypting it
+pub fn check_payload(payload: &str) -> Result<()> {
+    let data = decode(payload)?;
+    // the length prefix and padding are encrypted, only the padded size is visible
+    let padded = data.len() - 1 - 32 - 32 - 2;
+    if padded_len(padded) != padded {
+        bail!("invalid padded length {}", padded);
+    }
+    Ok(())
+}
+
+/// Decrypt a base64 payload
+pub fn decrypt(conversation_key: &[u8; 32], payload: &str) -> Result<String> {
+    let data = decode(payload)?;
+    let mut nonce = [0u8; 32];
+    nonce.copy_from_slice(&data[1..33]);
+    let (ciphertext, mac) = data[33..].split_at(data.len() - 33 - 32);
+    let (chacha_key, chacha_nonce, hmac_key) = message_keys(conversation_key, &nonce);
+
+    let mut check = HmacSha256::new_from_slice(&hmac_key).expect("hmac accepts any key length");
+    check.update(&nonce);
+    check.update(ciphertext);
+    check
+        .verify_slice(mac)
+        .map_err(|_| anyhow!("invalid MAC"))?;
+
+    let mut padded = ciphertext.to_vec();
+    ChaCha20::new(&chacha_key.into(), &chacha_nonce.into()).apply_keystream(&mut padded);
+    let len = u16::from_be_bytes([padded[0], padded[1]]) as usize;
+    if len < MIN_PLAINTEXT || padded.len() != 2 + padded_len(len) {
+        bail!("invalid padding");
+    }
+    Ok(String::from_utf8(padded[2..2 + len].to_vec())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nostr_relay::db::secp256k1::{Keypair, SECP256K1};
+
+    #[test]
+    fn padding() {
+        assert_eq!(padded_len(1), 32);
+        assert_eq!(padded_len(32), 32);
+        assert_eq!(padded_len(33), 64);
+        assert_eq!(padded_len(257), 320);
+        assert_eq!(padded_len(1000), 1024);
+        assert_eq!(padded_len(65535), 65536);
+    }
+
+    #[test]
+    fn roundtrip() -> Result<()> {
+        let alice = Keypair::from_seckey_str(SECP256K1, &"01".repeat(32))?;
+        let bob = Keypair::from_seckey_str(SECP256K1, &"02".repeat(32))?;
+        let key_ab = conversation_key(&alice.secret_key(), &bob.x_only_public_key().0);
+        let key_ba = conversation_key(&bob.secret_key(), &alice.x_only_public_key().0);
+        assert_eq!(key_ab, key_ba);
+
+        let payload = encrypt(&key_ab, "hello welcome", &[7u8; 32])?;
+        assert_eq!(decrypt(&key_ba, &payload)?, "hello welcome");
+
+        // tampered ciphertext fails the MAC check
+        let mut data = STANDARD.decode(&payload)?;
+        data[40] ^= 1;
+        assert!(decrypt(&key_ba, &STANDARD.encode(data)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn payload_shape() -> Result<()> {
+        let key = [3u8; 32];
+        assert!(check_payload(&encrypt(&key, "x", &[7u8; 32])?).is_ok());
+        assert!(check_payload(&encrypt(&key, &"x".repeat(300), &[7u8; 32])?).is_ok());
+
+        assert!(check_payload("not base64 at all").is_err());
+        assert!(check_payload(&"!".repeat(200)).is_err());
+        // wrong version byte
+        let mut data = STANDARD.decode(encrypt(&key, "x", &[7u8; 32])?)?;
+        data[0] = 1;
+        assert!(check_payload(&STANDARD.encode(&data)).is_err());
+        // ciphertext not a padded size
+        data[0] = VERSION;
+        data.insert(40, 0);
+        assert!(check_payload(&STANDARD.encode(&data)).is_err());
+        Ok(())
+    }
+}
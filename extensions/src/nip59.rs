@@ -0,0 +1,105 @@
+//! NIP-59 giftwrap unwrapping.
+//!
+//! A giftwrap (1059) encrypts a signed seal (13) to the recipient with an
+//! ephemeral key; the seal encrypts the unsigned rumor with the sender key.
+
+use crate::nip44;
+use anyhow::{bail, Result};
+use nostr_relay::db::{
+    secp256k1::{SecretKey, XOnlyPublicKey},
+    Event,
+};
+use serde::Deserialize;
+use std::str::FromStr;
+
+pub const SEAL_KIND: u16 = 13;
+pub const GIFTWRAP_KIND: u16 = 1059;
+
+/// Unsigned inner event
+#[derive(Debug, Clone, Deserialize)]
+pub struct Rumor {
+    pub pubkey: String,
+    pub created_at: u64,
+    pub kind: u16,
+    #[serde(default)]
+    pub tags: Vec<Vec<String>>,
+    pub content: String,
+}
+
+fn open(secret: &SecretKey, author: &[u8; 32], payload: &str) -> Result<String> {
+    let author = XOnlyPublicKey::from_slice(author)?;
+    nip44::decrypt(&nip44::conversation_key(secret, &author), payload)
+}
+
+/// Decrypt a giftwrap of `kind` addressed to `secret`, returns the rumor authored by the seal signer.
+/// `kind` is [`GIFTWRAP_KIND`] unless the relay maps giftwraps to another kind.
+pub fn unwrap(giftwrap: &Event, kind: u16, secret: &SecretKey) -> Result<Rumor> {
+    if giftwrap.kind() != kind {
+        bail!("not a giftwrap: kind {}", giftwrap.kind());
+    }
+    let seal = Event::from_str(&open(secret, giftwrap.pubkey(), giftwrap.content())?)?;
+    if seal.kind() != SEAL_KIND {
+        bail!("invalid seal kind {}", seal.kind());
+    }
+    seal.verify_id()?;
+    seal.verify_sign()?;
+    let rumor: Rumor = serde_json::from_str(&open(secret, seal.pubkey(), seal.content())?)?;
+    // the seal signer is the only authenticated author of the rumor
+    if rumor.pubkey != seal.pubkey_str() {
+        bail!("rumor pubkey does not match seal");
+    }
+    Ok(rumor)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nostr_relay::db::{
+        now,
+        secp256k1::{Keypair, SECP256K1},
+    };
+    use serde_json::json;
+
+    fn seal_to(sender: &Keypair, recipient: &Keypair, rumor: &str, nonce: u8) -> Result<String> {
+        let key = nip44::conversation_key(&sender.secret_key(), &recipient.x_only_public_key().0);
+        nip44::encrypt(&key, rumor, &[nonce; 32])
+    }
+
+    #[test]
+    fn unwrap_welcome() -> Result<()> {
+        let sender = Keypair::from_seckey_str(SECP256K1, &"01".repeat(32))?;
+        let recipient = Keypair::from_seckey_str(SECP256K1, &"02".repeat(32))?;
+        let ephemeral = Keypair::from_seckey_str(SECP256K1, &"03".repeat(32))?;
+        let rumor = json!({
+            "pubkey": hex::encode(sender.x_only_public_key().0.serialize()),
+            "created_at": now(),
+            "kind": 444,
+            "tags": [["e", "kp"]],
+            "content": "d2VsY29tZQ==",
+        });
+        let seal = Event::create(
+            &sender,
+            now(),
+            SEAL_KIND,
+            vec![],
+            seal_to(&sender, &recipient, &rumor.to_string(), 1)?,
+        )?;
+        let wrap = Event::create(
+            &ephemeral,
+            now(),
+            GIFTWRAP_KIND,
+            vec![vec![
+                "p".to_owned(),
+                hex::encode(recipient.x_only_public_key().0.serialize()),
+            ]],
+            seal_to(&ephemeral, &recipient, &seal.to_string(), 2)?,
+        )?;
+
+        let opened = unwrap(&wrap, GIFTWRAP_KIND, &recipient.secret_key())?;
+        assert_eq!(opened.kind, 444);
+        assert_eq!(opened.content, "d2VsY29tZQ==");
+        assert!(unwrap(&wrap, GIFTWRAP_KIND, &sender.secret_key()).is_err());
+        assert!(unwrap(&wrap, 21059, &recipient.secret_key()).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,152 @@
+//! Relay-signed service-ack (40911) responses.
+//!
+//! After a service-request is processed the relay answers the requesting group
+//! and client with a 40911 signed by the service key. The ack is non-sensitive:
+//! action identifiers, a status and an optional error code.
+
+use metrics::counter;
+use serde_json::{json, Value as JsonValue};
+use tracing::{debug, warn};
+
+use crate::nip_service::{emit, SERVICE_ACK_KIND};
+
+/// Request prepared or forwarded, completion follows later
+pub const STATUS_ACCEPTED: &str = "accepted";
+/// Action executed
+pub const STATUS_COMPLETED: &str = "completed";
+/// Request refused, see the error code
+pub const STATUS_REJECTED: &str = "rejected";
+/// Request valid but execution failed, see the error code
+pub const STATUS_FAILED: &str = "failed";
+/// action_id already processed within the replay window, not executed again
+pub const STATUS_DUPLICATE: &str = "duplicate";
+
+/// Missing required tags or fields
+pub const ERR_INVALID_REQUEST: &str = "invalid_request";
+/// No handler for the service/profile pair
+pub const ERR_UNSUPPORTED_PROFILE: &str = "unsupported_profile";
+/// Profile handler could not prepare the action
+pub const ERR_PREPARE_FAILED: &str = "prepare_failed";
+/// External handler unreachable or returned an invalid response
+pub const ERR_EXTERNAL_UNAVAILABLE: &str = "external_unavailable";
+/// External handler rejected the request
+pub const ERR_EXTERNAL_REJECTED: &str = "external_rejected";
+/// Requester is not an admin of the request group
+pub const ERR_UNAUTHORIZED: &str = "unauthorized";
+/// Replayed action_id
+pub const ERR_DUPLICATE_ACTION: &str = "duplicate_action";
+/// Idempotency store unavailable
+pub const ERR_STORE_UNAVAILABLE: &str = "store_unavailable";
+
+/// Where and for what an ack is sent
+#[derive(Debug, Clone, Default)]
+pub struct AckTarget {
+    pub service: Option<String>,
+    pub profile: Option<String>,
+    pub client_id: Option<String>,
+    pub action_id: Option<String>,
+    /// Admin MLS group of the request
+    pub group: Option<String>,
+    /// Hex pubkey of the 40910 author, absent for MLS-first requests
+    pub requester: Option<String>,
+}
+
+impl AckTarget {
+    /// Target of a service-request JSON payload
+    pub fn from_payload(json: &JsonValue, group: Option<&str>, requester: Option<&str>) -> Self {
+        let field = |k: &str| json.get(k).and_then(|v| v.as_str()).map(|s| s.to_owned());
+        Self {
+            service: field("action_type"),
+            profile: field("profile"),
+            client_id: field("client_id"),
+            action_id: field("action_id"),
+            group: group.map(|s| s.to_owned()),
+            requester: requester.map(|s| s.to_owned()),
+        }
+    }
+}
+
+/// Tags and content of an ack
+pub fn ack_parts(
+    target: &AckTarget,
+    status: &str,
+    error: Option<(&str, &str)>,
+    ack_by: &str,
+    ack_at_ms: i64,
+) -> (Vec<Vec<String>>, String) {
+    let mut tags = vec![];
+    let mut tag = |name: &str, value: &Option<String>| {
+        if let Some(value) = value {
+            tags.push(vec![name.to_owned(), value.clone()]);
+        }
+    };
+    tag("service", &target.service);
+    tag("action", &target.action_id);
+    tag("client", &target.client_id);
+    tag("profile", &target.profile);
+    tag("mls", &target.group);
+    tag("p", &target.requester);
+    tags.push(vec!["nip-service".to_owned(), "0.1.0".to_owned()]);
+
+    let content = json!({
+        "action_type": target.service,
+        "action_id": target.action_id,
+        "client_id": target.client_id,
+        "profile": target.profile,
+        "ack_by": ack_by,
+        "ack_at": ack_at_ms,
+        "status": status,
+        "error": error.map(|(code, message)| json!({ "code": code, "message": message })),
+    });
+    (tags, content.to_string())
+}
+
+/// Publish a relay-signed ack, skipped when no service key is configured
+pub fn send(target: &AckTarget, status: &str, error: Option<(&str, &str)>) {
+    let Some(ack_by) = emit::service_pubkey() else {
+        debug!(target: "nip_service", "service-ack skipped: no service key");
+        return;
+    };
+    let ack_at_ms = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as i64;
+    let (tags, content) = ack_parts(target, status, error, &ack_by, ack_at_ms);
+    match emit::emit(SERVICE_ACK_KIND, tags, content) {
+        Ok(id) => {
+            counter!("nip_service_acks_sent", "status" => status.to_owned()).increment(1);
+            debug!(target: "nip_service", "service-ack {} {} for action_id={:?}", id, status, target.action_id);
+        }
+        Err(e) => {
+            warn!(target: "nip_service", "service-ack for action_id={:?} failed: {}", target.action_id, e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ack_tags_and_content() {
+        let target = AckTarget::from_payload(
+            &json!({ "action_type": "rotation", "action_id": "a1", "client_id": "c1", "profile": "nip-kr/0.1.0" }),
+            Some("g1"),
+            Some("abcd"),
+        );
+        let (tags, content) = ack_parts(
+            &target,
+            STATUS_REJECTED,
+            Some((ERR_INVALID_REQUEST, "missing")),
+            "relay",
+            1,
+        );
+        assert!(tags.contains(&vec!["action".to_owned(), "a1".to_owned()]));
+        assert!(tags.contains(&vec!["mls".to_owned(), "g1".to_owned()]));
+        assert!(tags.contains(&vec!["p".to_owned(), "abcd".to_owned()]));
+        let content: JsonValue = serde_json::from_str(&content).unwrap();
+        assert_eq!(content["status"], "rejected");
+        assert_eq!(content["error"]["code"], "invalid_request");
+        assert_eq!(content["ack_by"], "relay");
+    }
+}
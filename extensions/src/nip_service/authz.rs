@@ -0,0 +1,132 @@
+//! Authorization of service-requests.
+//!
+//! Before a request is executed its sender must hold an admin role in the
+//! roster of the request's admin MLS group. For MLS-first requests the caller
+//! has already checked that the service member belongs to the group before
+//! decrypting. The roster lookup is provided by the MLS gateway storage; without
+//! it every request is rejected. Acks counted towards a quorum come from admins
+//! of the same group.
+
+use anyhow::Result;
+use async_trait::async_trait;
+use metrics::counter;
+use once_cell::sync::Lazy;
+use parking_lot::RwLock;
+use std::sync::Arc;
+use tracing::warn;
+
+use crate::nip_service::ack::{self, AckTarget};
+
+/// Group roster lookup
+#[async_trait]
+pub trait AdminAuthorizer: Send + Sync {
+    /// Whether pubkey holds an admin role in the group roster
+    async fn is_admin(&self, group_id: &str, pubkey: &str) -> Result<bool>;
+}
+
+static AUTHORIZER: Lazy<RwLock<Option<Arc<dyn AdminAuthorizer>>>> = Lazy::new(|| RwLock::new(None));
+
+/// Register the roster lookup
+pub fn set_authorizer(authorizer: Arc<dyn AdminAuthorizer>) {
+    *AUTHORIZER.write() = Some(authorizer);
+}
+
+/// Check that the requester is an admin of the request group, rejecting otherwise
+pub async fn authorize(target: &AckTarget) -> bool {
+    let result = check(target).await;
+    let label = match &result {
+        Ok(()) => "ok",
+        Err((code, _)) => *code,
+    };
+    counter!("nip_service_authz", "result" => label).increment(1);
+    match result {
+        Ok(()) => true,
+        Err((code, message)) => {
+            warn!(
+                target: "nip_service",
+                "service-request action_id={:?} unauthorized ({}): group={:?} requester={:?}",
+                target.action_id, code, target.group, target.requester
+            );
+            ack::send(
+                target,
+                ack::STATUS_REJECTED,
+                Some((ack::ERR_UNAUTHORIZED, message)),
+            );
+            false
+        }
+    }
+}
+
+/// Whether pubkey holds an admin role in the group roster, false without a roster lookup
+pub async fn is_group_admin(group: &str, pubkey: &str) -> bool {
+    let Some(authorizer) = AUTHORIZER.read().clone() else {
+        return false;
+    };
+    match authorizer.is_admin(group, pubkey).await {
+        Ok(admin) => admin,
+        Err(e) => {
+            warn!(target: "nip_service", "roster lookup for group {} failed: {}", group, e);
+            false
+        }
+    }
+}
+
+async fn check(target: &AckTarget) -> Result<(), (&'static str, &'static str)> {
+    let (Some(group), Some(requester)) = (&target.group, &target.requester) else {
+        return Err(("missing_scope", "request has no admin group or requester"));
+    };
+    let authorizer = AUTHORIZER
+        .read()
+        .clone()
+        .ok_or(("no_roster", "group roster unavailable"))?;
+    match authorizer.is_admin(group, requester).await {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(("not_admin", "requester is not a group admin")),
+        Err(e) => {
+            warn!(target: "nip_service", "roster lookup for group {} failed: {}", group, e);
+            Err(("error", "group roster unavailable"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Roster;
+
+    #[async_trait]
+    impl AdminAuthorizer for Roster {
+        async fn is_admin(&self, group_id: &str, pubkey: &str) -> Result<bool> {
+            Ok(group_id == "g1" && pubkey == "admin")
+        }
+    }
+
+    fn target(group: Option<&str>, requester: &str) -> AckTarget {
+        AckTarget {
+            group: group.map(|s| s.to_owned()),
+            requester: Some(requester.to_owned()),
+            ..Default::default()
+        }
+    }
+
+    #[actix_rt::test]
+    async fn admin_only() {
+        set_authorizer(Arc::new(Roster));
+        assert!(check(&target(Some("g1"), "admin")).await.is_ok());
+        assert_eq!(
+            check(&target(Some("g1"), "member")).await.unwrap_err().0,
+            "not_admin"
+        );
+        assert_eq!(
+            check(&target(Some("g2"), "admin")).await.unwrap_err().0,
+            "not_admin"
+        );
+        assert_eq!(
+            check(&target(None, "admin")).await.unwrap_err().0,
+            "missing_scope"
+        );
+        assert!(is_group_admin("g1", "admin").await);
+        assert!(!is_group_admin("g1", "member").await);
+    }
+}
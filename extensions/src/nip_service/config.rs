@@ -4,6 +4,9 @@
 //! It is intentionally minimal and uses defaults; parsing from the relay Setting
 //! can be added when wiring real KMS/Firestore/MLS notifier implementations.
 
+use serde::Deserialize;
+use std::path::PathBuf;
+
 #[derive(Debug, Clone)]
 pub struct NipServiceConfig {
     // JWKS endpoint for jwt_proof verification (loxation-server)
@@ -54,8 +57,83 @@ impl Default for NipServiceConfig {
             dev_local_hmac: std::env::var("NIP_SERVICE_DEV_LOCAL_HMAC")
                 .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
                 .unwrap_or(true),
-            dev_test_hmac_key_base64url: std::env::var("NIP_KR_TEST_HMAC_KEY_BASE64URL").ok(),
+            dev_test_hmac_key_base64url: nostr_relay::secret::env("NIP_KR_TEST_HMAC_KEY_BASE64URL"),
             mls_service_storage_path: std::env::var("NIP_SERVICE_MLS_STORAGE_PATH").ok(),
         }
     }
 }
+
+impl NipServiceConfig {
+    /// Read the NIP-KR MAC key from Cloud KMS with the instance service account, for the relay preflight.
+    /// `None` when no KMS key is configured.
+    pub async fn check_kms(&self) -> anyhow::Result<Option<String>> {
+        let Some(key) = self.kms_mac_key.as_deref() else {
+            return Ok(None);
+        };
+        let res = crate::gcp::http()
+            .get(format!("https://cloudkms.googleapis.com/v1/{}", key))
+            .bearer_auth(crate::gcp::access_token().await?)
+            .send()
+            .await?;
+        if !res.status().is_success() {
+            anyhow::bail!("{}: {}", key, res.status());
+        }
+        Ok(Some(key.to_string()))
+    }
+}
+
+/// Service action handler selection, read from `[extensions.mls_gateway]`
+/// next to the in-process decrypt settings.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ServiceHandlerSetting {
+    /// "in-process" or "external"
+    pub preferred_service_handler: String,
+    /// HTTPS endpoint receiving service-request metadata in external mode
+    pub external_service_url: Option<String>,
+    /// HMAC-SHA256 key signing external requests
+    pub external_service_hmac_secret: Option<String>,
+    /// PEM client certificate and PKCS#8 key for mTLS
+    pub external_service_client_cert: Option<PathBuf>,
+    pub external_service_client_key: Option<PathBuf>,
+    /// Additional PEM root certificate for the external endpoint
+    pub external_service_ca_cert: Option<PathBuf>,
+    pub external_service_timeout_secs: u64,
+    /// MLS identity of the relay service member
+    pub mls_service_user_id: Option<String>,
+    /// Per-group MLS identities (group_id -> user id) overriding `mls_service_user_id`
+    pub mls_service_user_ids: std::collections::HashMap<String, String>,
+    /// Seconds a processed action_id is remembered, replays within it get a duplicate ack
+    pub action_replay_window_secs: u64,
+    /// Seconds between checks retiring Grace versions past not_after (0 disables)
+    pub secret_retire_interval_secs: u64,
+    /// Distinct admin acks required before a NIP-KR rotation is promoted
+    pub ack_quorum_default: u32,
+    /// Minutes to reach the ack quorum, then the rotation is canceled or expired
+    pub ack_deadline_minutes: u32,
+    /// Hex secret key signing events emitted by the relay (service-notify, service-ack)
+    pub service_secret_key: Option<String>,
+}
+
+impl Default for ServiceHandlerSetting {
+    fn default() -> Self {
+        Self {
+            preferred_service_handler: "in-process".to_string(),
+            external_service_url: None,
+            external_service_hmac_secret: nostr_relay::secret::env(
+                "NIP_SERVICE_EXTERNAL_HMAC_SECRET",
+            ),
+            external_service_client_cert: None,
+            external_service_client_key: None,
+            external_service_ca_cert: None,
+            external_service_timeout_secs: 10,
+            mls_service_user_id: None,
+            mls_service_user_ids: Default::default(),
+            action_replay_window_secs: 86_400,
+            secret_retire_interval_secs: 60,
+            ack_quorum_default: 1,
+            ack_deadline_minutes: 30,
+            service_secret_key: nostr_relay::secret::env("NIP_SERVICE_SECRET_KEY"),
+        }
+    }
+}
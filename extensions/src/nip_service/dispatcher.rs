@@ -1,9 +1,48 @@
+use crate::nip_service::ack::{self, AckTarget};
+use crate::nip_service::config::ServiceHandlerSetting;
+use crate::nip_service::profiles::{self, ServiceRequest};
+use crate::nip_service::store::NipKrStore;
+use async_trait::async_trait;
+use metrics::counter;
+use once_cell::sync::Lazy;
+use parking_lot::RwLock;
 use serde_json::Value as JsonValue;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
 use tracing::{info, warn};
-use crate::nip_service::store::NipKrStore;
+
+/// Seconds a claimed action_id is remembered
+static REPLAY_WINDOW_SECS: AtomicU64 = AtomicU64::new(86_400);
+
+/// Apply the action_id replay window
+pub fn configure(setting: &ServiceHandlerSetting) {
+    REPLAY_WINDOW_SECS.store(setting.action_replay_window_secs, Ordering::Relaxed);
+}
+
+/// Persistent action_id claims shared by instances and restarts
+#[async_trait]
+pub trait ActionLedger: Send + Sync {
+    /// Claim an action_id until `expires_at` (unix seconds), false while an unexpired claim exists
+    async fn claim_action(
+        &self,
+        action_id: &str,
+        client_id: &str,
+        expires_at: i64,
+    ) -> anyhow::Result<bool>;
+}
+
+static LEDGER: Lazy<RwLock<Option<Arc<dyn ActionLedger>>>> = Lazy::new(|| RwLock::new(None));
+
+/// Register the persistent claims, the in-memory store is used without them
+pub fn set_ledger(ledger: Arc<dyn ActionLedger>) {
+    *LEDGER.write() = Some(ledger);
+}
 
 /// Handle a decrypted MLS-first NIP-SERVICE service-request payload (JSON).
-/// This path avoids any dependency on Nostr events/tags and takes an optional group hint.
+/// This path avoids any dependency on Nostr events/tags and takes an optional group hint
+/// and the hex pubkey of the 445 sender, which must be a group admin.
 ///
 /// Expected JSON shape (nip-service.md):
 /// {
@@ -14,11 +53,28 @@ use crate::nip_service::store::NipKrStore;
 ///   "params": { ... },
 ///   "jwt_proof": "compact JWS"
 /// }
-pub fn handle_service_request_payload(json: &JsonValue, group_hint: Option<&str>) {
-    let action_type = json.get("action_type").and_then(|v| v.as_str()).map(|s| s.to_string());
-    let action_id = json.get("action_id").and_then(|v| v.as_str()).map(|s| s.to_string());
-    let client_id = json.get("client_id").and_then(|v| v.as_str()).map(|s| s.to_string());
-    let profile = json.get("profile").and_then(|v| v.as_str()).map(|s| s.to_string());
+pub fn handle_service_request_payload(
+    json: &JsonValue,
+    group_hint: Option<&str>,
+    requester: Option<&str>,
+) {
+    let action_type = json
+        .get("action_type")
+        .and_then(|v| v.as_str())
+        .map(|s| s.to_string());
+    let action_id = json
+        .get("action_id")
+        .and_then(|v| v.as_str())
+        .map(|s| s.to_string());
+    let client_id = json
+        .get("client_id")
+        .and_then(|v| v.as_str())
+        .map(|s| s.to_string());
+    let profile = json
+        .get("profile")
+        .and_then(|v| v.as_str())
+        .map(|s| s.to_string());
+    let ack_target = ack::AckTarget::from_payload(json, group_hint, requester);
 
     // Basic shape validation (non-sensitive fields only)
     if action_type.is_none() || action_id.is_none() || client_id.is_none() || profile.is_none() {
@@ -27,98 +83,152 @@ pub fn handle_service_request_payload(json: &JsonValue, group_hint: Option<&str>
             "MLS-first service-request missing required fields: action_type={:?} action_id={:?} client_id={:?} profile={:?}",
             action_type, action_id, client_id, profile
         );
+        ack::send(
+            &ack_target,
+            ack::STATUS_REJECTED,
+            Some((ack::ERR_INVALID_REQUEST, "missing required fields")),
+        );
         return;
     }
 
-    // Route profiles. First supported: rotation (NIP-KR 0.1.0)
-    if action_type.as_deref() == Some("rotation") && profile.as_deref() == Some("nip-kr/0.1.0") {
-        // Extract rotation-specific fields using existing helper.
-        let (rotation_reason, not_before_ms, grace_duration_ms, jwt_present, params_keys) =
-            crate::nip_service::profiles::kr::extract_rotation_params(json);
-
-        let ctx = crate::nip_service::profiles::kr::RotationRequestContext {
-            client_id: client_id.clone(),
-            rotation_id: action_id.clone(),
-            mls_group: group_hint.map(|s| s.to_owned()),
-            rotation_reason: rotation_reason.clone(),
-            not_before_ms,
-            grace_duration_ms,
-            jwt_proof_present: jwt_present,
-            params_keys,
-        };
-
-        // Log a redacted summary (no plaintext).
-        info!(
-            target: "nip_service",
-            "MLS-first service-request mapped: profile=nip-kr/0.1.0 client_id={:?} action_id={:?} group_hint={:?} jwt_proof_present={} params={:?}",
-            client_id, action_id, group_hint, jwt_present, ctx.params_keys
-        );
+    dispatch(json.clone(), group_hint.map(|s| s.to_owned()), ack_target);
+}
 
-        // Stub handler (authorization, KMS, Firestore to be wired later)
-        crate::nip_service::profiles::kr::handle_rotation_request(ctx.clone());
+/// Execute an authorized service-request once per action_id, through the
+/// external handler when enabled or the registered profile otherwise.
+pub fn dispatch(payload: JsonValue, group: Option<String>, ack_target: AckTarget) {
+    tokio::spawn(async move {
+        if !crate::nip_service::authz::authorize(&ack_target).await || !claim(&ack_target).await {
+            return;
+        }
+        // External mode: forward metadata to the operator service instead of in-process handlers
+        if crate::nip_service::external::is_enabled() {
+            crate::nip_service::external::handle_service_request_payload(
+                payload, group, ack_target,
+            )
+            .await;
+        } else {
+            route(payload, group.as_deref(), ack_target);
+        }
+    });
+}
 
-        // DEV/local: demonstrate prepare (no KMS/DB/MLS), using env NIP_KR_TEST_HMAC_KEY_BASE64URL
-        if let Some(prep) = crate::nip_service::profiles::kr::prepare_rotation_local(&ctx) {
-            info!(
-                target: "nip_service",
-                "NIP-KR local prepare (MLS-first): version_id={} mac_key_ref={} secret_hash_len={}",
-                prep.version_id, prep.mac_key_ref, prep.secret_hash.len()
+/// Claim the action_id, replays get a duplicate ack instead of re-executing
+async fn claim(target: &AckTarget) -> bool {
+    let (Some(action_id), Some(client_id)) = (&target.action_id, &target.client_id) else {
+        return false;
+    };
+    let now_ms = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as i64;
+    let window_ms = REPLAY_WINDOW_SECS.load(Ordering::Relaxed) as i64 * 1000;
+    let ledger = LEDGER.read().clone();
+    let claimed = match ledger {
+        Some(ledger) => {
+            ledger
+                .claim_action(action_id, client_id, (now_ms + window_ms) / 1000)
+                .await
+        }
+        None => {
+            crate::nip_service::store::get_global_store()
+                .claim_action(action_id, client_id, now_ms, window_ms)
+                .await
+        }
+    };
+    match claimed {
+        Ok(true) => true,
+        Ok(false) => {
+            counter!("nip_service_duplicate_actions").increment(1);
+            warn!(target: "nip_service", "replayed service-request action_id={} client_id={}", action_id, client_id);
+            ack::send(
+                target,
+                ack::STATUS_DUPLICATE,
+                Some((ack::ERR_DUPLICATE_ACTION, "action_id already processed")),
             );
+            false
+        }
+        Err(e) => {
+            counter!("nip_service_errors_total").increment(1);
+            warn!(target: "nip_service", "action_id claim failed for {}: {}", action_id, e);
+            ack::send(
+                target,
+                ack::STATUS_FAILED,
+                Some((ack::ERR_STORE_UNAVAILABLE, "idempotency store unavailable")),
+            );
+            false
+        }
+    }
+}
 
-            // Persist a dev record in the in-memory store to exercise the flow.
-            let cid = client_id.clone();
-            let rid = action_id.clone();
-            let ver = prep.version_id.clone();
-            let hash = prep.secret_hash.clone();
-            let mkr = prep.mac_key_ref.clone();
-            let reason = rotation_reason.clone();
-            // not_before default: now + 10 minutes if not provided
-            let now_ms = std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap_or_default()
-                .as_millis() as i64;
-            let effective_not_before = not_before_ms.unwrap_or(now_ms + 10 * 60 * 1000);
-            let grace_ms = grace_duration_ms;
+/// Route a validated service-request to its registered profile.
+///
+/// The payload must carry action_type, action_id, client_id and profile.
+pub fn route(payload: JsonValue, group: Option<&str>, ack_target: AckTarget) {
+    let field = |k: &str| {
+        payload
+            .get(k)
+            .and_then(|v| v.as_str())
+            .unwrap_or_default()
+            .to_owned()
+    };
+    let (action_type, profile, client_id, action_id) = (
+        field("action_type"),
+        field("profile"),
+        field("client_id"),
+        field("action_id"),
+    );
 
-            tokio::spawn(async move {
-                if let (Some(cid), Some(rid)) = (cid, rid) {
-                    let store = crate::nip_service::store::get_global_store();
-                    if let Err(e) = store
-                        .prepare_rotation(
-                            &cid,
-                            &ver,
-                            &hash,
-                            &mkr,
-                            effective_not_before,
-                            grace_ms,
-                            &rid,
-                            reason.as_deref(),
-                            1, // quorum_required (dev default)
-                        )
-                        .await
-                    {
-                        warn!("NIP-KR dev store prepare (MLS-first) failed: {}", e);
-                    } else {
-                        info!(
-                            target: "nip_service",
-                            "NIP-KR dev store prepared (MLS-first): client_id={} version_id={} rotation_id={}",
-                            cid, ver, rid
-                        );
-                    }
-                } else {
-                    warn!("NIP-KR dev store prepare (MLS-first) skipped: missing client_id/action_id");
-                }
-            });
-        } else {
-            warn!("NIP-KR local prepare (MLS-first) skipped (missing/invalid NIP_KR_TEST_HMAC_KEY_BASE64URL)");
-        }
+    let Some(handler) = profiles::get(&action_type, &profile) else {
+        warn!(
+            target: "nip_service",
+            "service-request unsupported: action_type={} profile={} (ignored)",
+            action_type, profile
+        );
+        ack::send(
+            &ack_target,
+            ack::STATUS_REJECTED,
+            Some((ack::ERR_UNSUPPORTED_PROFILE, "unsupported service profile")),
+        );
         return;
-    }
+    };
 
-    // Unknown or unsupported profile
-    warn!(
+    // Log a redacted summary (no plaintext).
+    info!(
         target: "nip_service",
-        "MLS-first service-request unsupported: action_type={:?} profile={:?} (ignored)",
-        action_type, profile
+        "service-request routed: profile={} client_id={} action_id={} group={:?}",
+        profile, client_id, action_id, group
     );
+    handler.handle_request(ServiceRequest {
+        payload,
+        client_id,
+        action_id,
+        group: group.map(|s| s.to_owned()),
+        ack_target,
+    });
+}
+
+/// Handle a service-ack for an action, from a 40911 event or an external handler response.
+/// `ack_by` identifies the acker (hex pubkey, or "external"), each counts once towards a quorum.
+pub fn handle_service_ack_payload(
+    service: Option<String>,
+    profile: Option<String>,
+    client_id: Option<String>,
+    action_id: Option<String>,
+    ack_by: String,
+) {
+    let (Some(service), Some(profile)) = (service, profile) else {
+        return;
+    };
+    let Some(handler) = profiles::get(&service, &profile) else {
+        return;
+    };
+    if let (Some(client_id), Some(action_id)) = (client_id, action_id) {
+        handler.handle_ack(client_id, action_id, ack_by);
+    } else {
+        warn!(
+            "service-ack for {} skipped: missing client_id/action_id",
+            profile
+        );
+    }
 }
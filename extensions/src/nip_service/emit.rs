@@ -0,0 +1,100 @@
+//! Events emitted by the relay itself.
+//!
+//! Service-notify and service-ack events are signed with the relay service key
+//! (`service_secret_key` or `NIP_SERVICE_SECRET_KEY`) and injected into the
+//! relay's own pipeline, so they are stored, dispatched to subscribers and
+//! fanned out like client events.
+
+use actix::Addr;
+use anyhow::Result;
+use nostr_relay::db::{
+    now,
+    secp256k1::{Keypair, SECP256K1},
+    Event,
+};
+use nostr_relay::{message::RelayEvent, Server};
+use once_cell::sync::{Lazy, OnceCell};
+use parking_lot::RwLock;
+use tracing::{info, warn};
+
+static SERVER: OnceCell<Addr<Server>> = OnceCell::new();
+static KEY: Lazy<RwLock<Option<Keypair>>> = Lazy::new(|| RwLock::new(None));
+
+/// Register the relay server receiving emitted events
+pub fn set_server(server: Addr<Server>) {
+    let _ = SERVER.set(server);
+}
+
+/// Load the service key, emission is disabled without one
+pub fn configure_key(secret: Option<&str>) {
+    let key = secret.and_then(|s| match Keypair::from_seckey_str(SECP256K1, s.trim()) {
+        Ok(key) => Some(key),
+        Err(e) => {
+            warn!(target: "nip_service", "invalid service secret key: {}", e);
+            None
+        }
+    });
+    if let Some(key) = &key {
+        info!(target: "nip_service", "service pubkey: {}", hex::encode(key.x_only_public_key().0.serialize()));
+    }
+    *KEY.write() = key;
+}
+
+/// Hex pubkey of the service key
+pub fn service_pubkey() -> Option<String> {
+    KEY.read()
+        .as_ref()
+        .map(|key| hex::encode(key.x_only_public_key().0.serialize()))
+}
+
+/// Secret of the service key, used to open giftwraps addressed to the relay
+pub fn service_secret() -> Option<nostr_relay::db::secp256k1::SecretKey> {
+    KEY.read().as_ref().map(|key| key.secret_key())
+}
+
+/// Sign an event with the service key
+pub fn sign(kind: u16, tags: Vec<Vec<String>>, content: String) -> Result<Event> {
+    let key = (*KEY.read()).ok_or_else(|| anyhow::anyhow!("service secret key not configured"))?;
+    Ok(Event::create(&key, now(), kind, tags, content)?)
+}
+
+/// Sign an event and publish it through the relay pipeline, returns the event id
+pub fn emit(kind: u16, tags: Vec<Vec<String>>, content: String) -> Result<String> {
+    publish(sign(kind, tags, content)?)
+}
+
+/// Publish a signed event through the relay pipeline, returns the event id
+pub fn publish(event: Event) -> Result<String> {
+    let server = SERVER
+        .get()
+        .ok_or_else(|| anyhow::anyhow!("relay server not registered"))?;
+    let id = event.id_str();
+    server.do_send(RelayEvent { event });
+    Ok(id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signed_by_service_key() -> Result<()> {
+        configure_key(None);
+        assert!(sign(40911, vec![], "{}".to_owned()).is_err());
+        configure_key(Some("not a key"));
+        assert!(service_pubkey().is_none());
+
+        configure_key(Some(
+            "0000000000000000000000000000000000000000000000000000000000000001",
+        ));
+        let event = sign(
+            40911,
+            vec![vec!["action".to_owned(), "a1".to_owned()]],
+            "{}".to_owned(),
+        )?;
+        assert_eq!(Some(hex::encode(event.pubkey())), service_pubkey());
+        event.validate(now(), 0, 0)?;
+        configure_key(None);
+        Ok(())
+    }
+}
@@ -0,0 +1,267 @@
+//! NIP-SERVICE "external" handler mode.
+//!
+//! With `preferred_service_handler = "external"`, validated service-request
+//! metadata is POSTed to an operator HTTPS endpoint instead of the in-process
+//! profile handlers. Requests are signed with HMAC-SHA256 over
+//! `{timestamp}.{body}` and/or sent over mTLS. The response status is mapped
+//! back into service-ack handling:
+//!
+//! - `completed`: the action is acknowledged as if a 40911 arrived
+//! - `accepted`: the service acks later by publishing 40911 itself
+//! - `rejected`: logged and counted
+//!
+//! Only metadata leaves the relay: params whose name suggests a secret are dropped
+//! and the bearer `jwt_proof` is replaced by its SHA-256, which cannot be replayed.
+
+use anyhow::Result;
+use hmac::{Hmac, Mac};
+use metrics::counter;
+use once_cell::sync::Lazy;
+use parking_lot::RwLock;
+use serde::Deserialize;
+use serde_json::{json, Map, Value as JsonValue};
+use sha2::{Digest, Sha256};
+use std::time::Duration;
+use tracing::{info, warn};
+
+use crate::nip_service::ack::{self, AckTarget};
+use crate::nip_service::config::ServiceHandlerSetting;
+
+/// Param names never forwarded
+const SECRET_MARKERS: [&str; 4] = ["secret", "password", "private", "key_material"];
+
+struct ExternalHandler {
+    url: String,
+    hmac_secret: Option<String>,
+    client: reqwest::Client,
+}
+
+static HANDLER: Lazy<RwLock<Option<ExternalHandler>>> = Lazy::new(|| RwLock::new(None));
+
+/// Response of the external service
+#[derive(Debug, Deserialize)]
+pub struct ExternalResponse {
+    pub status: String,
+    #[serde(default)]
+    pub reason: Option<String>,
+}
+
+fn build_client(setting: &ServiceHandlerSetting) -> Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder()
+        .timeout(Duration::from_secs(
+            setting.external_service_timeout_secs.max(1),
+        ))
+        .https_only(true);
+    if let (Some(cert), Some(key)) = (
+        &setting.external_service_client_cert,
+        &setting.external_service_client_key,
+    ) {
+        let identity =
+            reqwest::Identity::from_pkcs8_pem(&std::fs::read(cert)?, &std::fs::read(key)?)?;
+        builder = builder.identity(identity);
+    }
+    if let Some(ca) = &setting.external_service_ca_cert {
+        builder =
+            builder.add_root_certificate(reqwest::Certificate::from_pem(&std::fs::read(ca)?)?);
+    }
+    Ok(builder.build()?)
+}
+
+/// Apply handler settings, the external handler is active only in "external" mode with a url
+pub fn configure(setting: &ServiceHandlerSetting) {
+    let mut handler = HANDLER.write();
+    *handler = None;
+    if !setting
+        .preferred_service_handler
+        .eq_ignore_ascii_case("external")
+    {
+        return;
+    }
+    let Some(url) = setting.external_service_url.clone() else {
+        warn!(target: "nip_service", "preferred_service_handler=external without external_service_url; service requests are not handled");
+        return;
+    };
+    if setting.external_service_hmac_secret.is_none()
+        && setting.external_service_client_cert.is_none()
+    {
+        warn!(target: "nip_service", "external service handler has neither HMAC secret nor client certificate");
+    }
+    match build_client(setting) {
+        Ok(client) => {
+            info!(target: "nip_service", "external service handler: {}", url);
+            *handler = Some(ExternalHandler {
+                url,
+                hmac_secret: setting.external_service_hmac_secret.clone(),
+                client,
+            });
+        }
+        Err(e) => warn!(target: "nip_service", "external service handler client: {}", e),
+    }
+}
+
+/// Whether service requests go to the external handler
+pub fn is_enabled() -> bool {
+    HANDLER.read().is_some()
+}
+
+/// Metadata forwarded for a service-request payload
+pub fn request_metadata(json: &JsonValue, group_hint: Option<&str>) -> JsonValue {
+    let params: Map<String, JsonValue> = json
+        .get("params")
+        .and_then(|p| p.as_object())
+        .map(|p| {
+            p.iter()
+                .filter(|(k, _)| {
+                    let k = k.to_lowercase();
+                    !SECRET_MARKERS.iter().any(|m| k.contains(m))
+                })
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect()
+        })
+        .unwrap_or_default();
+    let jwt_proof = json.get("jwt_proof").and_then(|v| v.as_str());
+    json!({
+        "action_type": json.get("action_type"),
+        "action_id": json.get("action_id"),
+        "client_id": json.get("client_id"),
+        "profile": json.get("profile"),
+        "group": group_hint,
+        "params": params,
+        "jwt_proof_present": jwt_proof.is_some(),
+        "jwt_proof_sha256": jwt_proof.map(|jwt| hex::encode(Sha256::digest(jwt.as_bytes()))),
+    })
+}
+
+fn sign(secret: &str, timestamp: i64, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(format!("{}.", timestamp).as_bytes());
+    mac.update(body.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+async fn post(metadata: &JsonValue) -> Result<ExternalResponse> {
+    let (url, secret, client) = {
+        let handler = HANDLER.read();
+        let handler = handler
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("external handler not configured"))?;
+        (
+            handler.url.clone(),
+            handler.hmac_secret.clone(),
+            handler.client.clone(),
+        )
+    };
+    let body = metadata.to_string();
+    let timestamp = chrono::Utc::now().timestamp();
+    let mut req = client
+        .post(url)
+        .header("Content-Type", "application/json")
+        .header("X-Nostr-Timestamp", timestamp.to_string());
+    if let Some(secret) = secret {
+        req = req.header(
+            "X-Nostr-Signature",
+            format!("sha256={}", sign(&secret, timestamp, &body)),
+        );
+    }
+    let res = req.body(body).send().await?.error_for_status()?;
+    Ok(res.json().await?)
+}
+
+/// Forward a service-request payload and map the response into ack handling
+pub async fn handle_service_request_payload(
+    json: JsonValue,
+    group_hint: Option<String>,
+    ack_target: AckTarget,
+) {
+    let metadata = request_metadata(&json, group_hint.as_deref());
+    let str_field = |k: &str| json.get(k).and_then(|v| v.as_str()).map(|s| s.to_string());
+    let (action_type, profile, client_id, action_id) = (
+        str_field("action_type"),
+        str_field("profile"),
+        str_field("client_id"),
+        str_field("action_id"),
+    );
+
+    match post(&metadata).await {
+        Ok(res) => {
+            counter!("nip_service_external_requests", "status" => res.status.clone()).increment(1);
+            match res.status.as_str() {
+                "completed" => {
+                    ack::send(&ack_target, ack::STATUS_COMPLETED, None);
+                    crate::nip_service::dispatcher::handle_service_ack_payload(
+                        action_type,
+                        profile,
+                        client_id,
+                        action_id,
+                        "external".to_owned(),
+                    )
+                }
+                "accepted" => {
+                    info!(
+                        target: "nip_service",
+                        "external handler accepted action_id={:?}, awaiting 40911", action_id
+                    );
+                    ack::send(&ack_target, ack::STATUS_ACCEPTED, None);
+                }
+                _ => {
+                    warn!(
+                        target: "nip_service",
+                        "external handler {} action_id={:?}: {:?}", res.status, action_id, res.reason
+                    );
+                    let reason = res
+                        .reason
+                        .as_deref()
+                        .unwrap_or("rejected by external handler");
+                    ack::send(
+                        &ack_target,
+                        ack::STATUS_REJECTED,
+                        Some((ack::ERR_EXTERNAL_REJECTED, reason)),
+                    );
+                }
+            }
+        }
+        Err(e) => {
+            counter!("nip_service_external_requests", "status" => "error").increment(1);
+            counter!("nip_service_errors_total").increment(1);
+            warn!(target: "nip_service", "external handler request failed for action_id={:?}: {}", action_id, e);
+            ack::send(
+                &ack_target,
+                ack::STATUS_FAILED,
+                Some((
+                    ack::ERR_EXTERNAL_UNAVAILABLE,
+                    "external handler unavailable",
+                )),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metadata_drops_secrets() {
+        let json = json!({
+            "action_type": "rotation",
+            "action_id": "a1",
+            "client_id": "c1",
+            "profile": "nip-kr/0.1.0",
+            "params": { "rotation_reason": "scheduled", "new_secret": "s3cr3t", "api_password": "x" },
+            "jwt_proof": "jws"
+        });
+        let metadata = request_metadata(&json, Some("g1"));
+        assert_eq!(metadata["group"], "g1");
+        assert_eq!(metadata["params"]["rotation_reason"], "scheduled");
+        assert!(metadata["params"].get("new_secret").is_none());
+        assert!(!metadata.to_string().contains("s3cr3t"));
+        assert!(!metadata.to_string().contains("api_password"));
+        assert!(metadata.get("jwt_proof").is_none());
+        assert_eq!(metadata["jwt_proof_present"], true);
+        assert_eq!(
+            metadata["jwt_proof_sha256"],
+            hex::encode(Sha256::digest(b"jws"))
+        );
+    }
+}
@@ -0,0 +1,156 @@
+//! Secret version lifecycle for NIP-KR.
+//!
+//! A promoted rotation leaves the replaced version in Grace until `not_after_ms`.
+//! A background task retires expired Grace versions and announces each with a
+//! non-sensitive service-notify (40912) signed by the relay service key.
+//! The same task closes rotations that missed their ack deadline: Canceled
+//! without acks, Expired below quorum, each announced the same way.
+
+use metrics::counter;
+use serde_json::json;
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+use tracing::{info, warn};
+
+use crate::nip_service::store::{NipKrStore, RotationOutcome, RotationRecord, SecretVersionRecord};
+use crate::nip_service::{emit, SERVICE_NOTIFY_KIND};
+
+static STARTED: AtomicBool = AtomicBool::new(false);
+
+fn now_ms() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as i64
+}
+
+/// Start the retirement and ack deadline task once, interval 0 disables it
+pub fn spawn_retirement(interval: Duration) {
+    if interval.is_zero() || tokio::runtime::Handle::try_current().is_err() {
+        return;
+    }
+    if STARTED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            let now = now_ms();
+            expire_overdue(now).await;
+            retire_expired(now).await;
+        }
+    });
+}
+
+/// Retire expired Grace versions, returns how many were retired
+pub async fn retire_expired(now_ms: i64) -> usize {
+    let store = crate::nip_service::store::get_global_store();
+    let retired = match store.retire_expired(now_ms).await {
+        Ok(retired) => retired,
+        Err(e) => {
+            warn!(target: "nip_service", "secret retirement failed: {}", e);
+            return 0;
+        }
+    };
+    for version in &retired {
+        counter!("nip_service_versions_retired").increment(1);
+        info!(
+            target: "nip_service",
+            "NIP-KR version retired: client_id={} version_id={}",
+            version.client_id, version.version_id
+        );
+        if emit::service_pubkey().is_some() {
+            let (tags, content) = retire_notify_parts(version, now_ms);
+            if let Err(e) = emit::emit(SERVICE_NOTIFY_KIND, tags, content) {
+                warn!(target: "nip_service", "retire notify for {} failed: {}", version.version_id, e);
+            }
+        }
+    }
+    retired.len()
+}
+
+/// Close rotations past their ack deadline, returns how many were closed
+pub async fn expire_overdue(now_ms: i64) -> usize {
+    let store = crate::nip_service::store::get_global_store();
+    let closed = match store.expire_overdue(now_ms).await {
+        Ok(closed) => closed,
+        Err(e) => {
+            warn!(target: "nip_service", "rotation deadline check failed: {}", e);
+            return 0;
+        }
+    };
+    for rotation in &closed {
+        let outcome = outcome_name(rotation.outcome);
+        counter!("nip_service_rotations_closed", "outcome" => outcome).increment(1);
+        warn!(
+            target: "nip_service",
+            "NIP-KR rotation {}: client_id={} rotation_id={} acks={}/{}",
+            outcome, rotation.client_id, rotation.action_id, rotation.quorum_acks, rotation.quorum_required
+        );
+        if emit::service_pubkey().is_some() {
+            let (tags, content) = cancel_notify_parts(rotation, now_ms);
+            if let Err(e) = emit::emit(SERVICE_NOTIFY_KIND, tags, content) {
+                warn!(target: "nip_service", "{} notify for {} failed: {}", outcome, rotation.action_id, e);
+            }
+        }
+    }
+    closed.len()
+}
+
+fn outcome_name(outcome: RotationOutcome) -> &'static str {
+    match outcome {
+        RotationOutcome::None => "pending",
+        RotationOutcome::Promoted => "promoted",
+        RotationOutcome::Canceled => "canceled",
+        RotationOutcome::Expired => "expired",
+        RotationOutcome::RolledBack => "rolled_back",
+    }
+}
+
+/// Tags and content of a cancellation/expiry notification, identifiers only
+fn cancel_notify_parts(rotation: &RotationRecord, closed_at_ms: i64) -> (Vec<Vec<String>>, String) {
+    let tags = vec![
+        vec!["service".to_owned(), "rotation".to_owned()],
+        vec!["profile".to_owned(), "nip-kr/0.1.0".to_owned()],
+        vec!["client".to_owned(), rotation.client_id.clone()],
+        vec!["action".to_owned(), rotation.action_id.clone()],
+        vec!["version".to_owned(), rotation.new_version.clone()],
+        vec!["nip-service".to_owned(), "0.1.0".to_owned()],
+    ];
+    let content = json!({
+        "type": outcome_name(rotation.outcome),
+        "client_id": rotation.client_id,
+        "rotation_id": rotation.action_id,
+        "version_id": rotation.new_version,
+        "quorum_required": rotation.quorum_required,
+        "quorum_acks": rotation.quorum_acks,
+        "ack_deadline": rotation.ack_deadline_ms,
+        "closed_at": closed_at_ms,
+    });
+    (tags, content.to_string())
+}
+
+/// Tags and content of a retire notification, identifiers only
+fn retire_notify_parts(
+    version: &SecretVersionRecord,
+    retired_at_ms: i64,
+) -> (Vec<Vec<String>>, String) {
+    let tags = vec![
+        vec!["service".to_owned(), "rotation".to_owned()],
+        vec!["profile".to_owned(), "nip-kr/0.1.0".to_owned()],
+        vec!["client".to_owned(), version.client_id.clone()],
+        vec!["version".to_owned(), version.version_id.clone()],
+        vec!["nip-service".to_owned(), "0.1.0".to_owned()],
+    ];
+    let content = json!({
+        "type": "retired",
+        "client_id": version.client_id,
+        "version_id": version.version_id,
+        "not_after": version.not_after_ms,
+        "retired_at": retired_at_ms,
+    });
+    (tags, content.to_string())
+}
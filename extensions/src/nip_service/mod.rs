@@ -2,26 +2,31 @@
 //! NIP-SERVICE Extension (generic service account action plumbing)
 //!
 //! Handles control-plane events for service-request (40910) and service-ack (40911).
-//! Validates basic tags/shape and logs/metrics for routing to profile-specific handlers (e.g., NIP-KR).
-//!
-//! This is an initial scaffold. Profile execution (e.g., rotation) will be wired in a follow-up.
+//! Validates basic tags/shape and logs/metrics, then routes requests to the
+//! profile registered for the service/profile pair (see [`profiles`], e.g. NIP-KR).
 
+use crate::nip_service::config::ServiceHandlerSetting;
 use actix_web::web::ServiceConfig;
 use metrics::{counter, describe_counter};
-use nostr_relay::{Extension, ExtensionMessageResult, Session};
 use nostr_relay::db::Event;
+use nostr_relay::{Extension, ExtensionMessageResult, Session};
 use serde_json::Value as JsonValue;
 use tracing::{info, warn};
-use crate::nip_service::store::NipKrStore;
 
-pub mod profiles;
+pub mod ack;
+pub mod authz;
 pub mod config;
-pub mod store;
 pub mod dispatcher;
+pub mod emit;
+pub mod external;
+pub mod lifecycle;
+pub mod notify;
+pub mod profiles;
+pub mod store;
 
 const SERVICE_REQUEST_KIND: u16 = 40910; // NIP-SERVICE: service-request
-const SERVICE_ACK_KIND: u16 = 40911;     // NIP-SERVICE: service-ack
-const SERVICE_NOTIFY_KIND: u16 = 40912;  // Optional: service-notify (non-sensitive via Nostr; MLS preferred)
+const SERVICE_ACK_KIND: u16 = 40911; // NIP-SERVICE: service-ack
+const SERVICE_NOTIFY_KIND: u16 = 40912; // Optional: service-notify (non-sensitive via Nostr; MLS preferred)
 
 #[derive(Debug, Clone, Default)]
 pub struct NipService;
@@ -29,10 +34,51 @@ pub struct NipService;
 impl NipService {
     pub fn new() -> Self {
         // Metrics descriptors (idempotent)
-        describe_counter!("nip_service_events_processed", "Number of NIP-SERVICE events processed by kind");
-        describe_counter!("nip_service_requests_total", "Count of service-request (40910) processed");
-        describe_counter!("nip_service_acks_total", "Count of service-ack (40911) processed");
-        describe_counter!("nip_service_errors_total", "Count of errors while processing NIP-SERVICE events");
+        describe_counter!(
+            "nip_service_events_processed",
+            "Number of NIP-SERVICE events processed by kind"
+        );
+        describe_counter!(
+            "nip_service_requests_total",
+            "Count of service-request (40910) processed"
+        );
+        describe_counter!(
+            "nip_service_acks_total",
+            "Count of service-ack (40911) processed"
+        );
+        describe_counter!("nip_service_acks_unauthorized", "Count of service-acks (40911) ignored as their author is not an admin of the rotation group");
+        describe_counter!(
+            "nip_service_errors_total",
+            "Count of errors while processing NIP-SERVICE events"
+        );
+        describe_counter!(
+            "nip_service_external_requests",
+            "Count of service-requests sent to the external handler by response status"
+        );
+        describe_counter!(
+            "nip_service_notify_total",
+            "Count of MLS service-notify emissions by result"
+        );
+        describe_counter!(
+            "nip_service_acks_sent",
+            "Count of relay-signed service-acks (40911) by status"
+        );
+        describe_counter!(
+            "nip_service_versions_retired",
+            "Count of NIP-KR secret versions moved from Grace to Retired"
+        );
+        describe_counter!(
+            "nip_service_authz",
+            "Count of service-request authorization checks by result"
+        );
+        describe_counter!(
+            "nip_service_duplicate_actions",
+            "Count of replayed service-requests answered with a duplicate ack"
+        );
+        describe_counter!(
+            "nip_service_rotations_closed",
+            "Count of NIP-KR rotations closed without promotion by outcome"
+        );
         Self
     }
 
@@ -48,10 +94,25 @@ impl NipService {
         let action_id = get_tag(event, "action");
         let nip_service = get_tag(event, "nip-service");
 
+        let ack_target = ack::AckTarget {
+            service: service.clone(),
+            profile: profile.clone(),
+            client_id: client_id.clone(),
+            action_id: action_id.clone(),
+            group: mls_group.clone(),
+            requester: Some(hex::encode(event.pubkey())),
+        };
+
         // Basic shape validation/logging (full auth: jwt_proof + MLS membership handled downstream)
         if service.is_none() || profile.is_none() || client_id.is_none() || action_id.is_none() {
             warn!("NIP-SERVICE 40910 missing required tags. service={:?}, profile={:?}, client={:?}, action={:?}",
                 service, profile, client_id, action_id);
+            ack::send(
+                &ack_target,
+                ack::STATUS_REJECTED,
+                Some((ack::ERR_INVALID_REQUEST, "missing required tags")),
+            );
+            return;
         }
 
         // Parse content JSON for params and jwt_proof (non-sensitive)
@@ -62,7 +123,10 @@ impl NipService {
         let ct = event.content();
         match serde_json::from_str::<JsonValue>(ct.as_str()) {
             Ok(v) => {
-                action_type = v.get("action_type").and_then(|x| x.as_str()).map(|s| s.to_owned());
+                action_type = v
+                    .get("action_type")
+                    .and_then(|x| x.as_str())
+                    .map(|s| s.to_owned());
                 jwt_present = v.get("jwt_proof").and_then(|x| x.as_str()).is_some();
                 if let Some(p) = v.get("params").and_then(|x| x.as_object()) {
                     params_keys = p.keys().cloned().collect();
@@ -79,87 +143,30 @@ impl NipService {
             service, profile, action_id, client_id, mls_group, nip_service, action_type, jwt_present, params_keys
         );
 
-        // Route to NIP-KR (Rotation) profile stub if applicable
-        if service.as_deref() == Some("rotation") && profile.as_deref() == Some("nip-kr/0.1.0") {
-            let ct2 = event.content();
-            if let Ok(json) = serde_json::from_str::<JsonValue>(ct2.as_str()) {
-                let (rotation_reason, not_before_ms, grace_duration_ms, jwt_present2, params_keys2) =
-                    crate::nip_service::profiles::kr::extract_rotation_params(&json);
-
-                let ctx = crate::nip_service::profiles::kr::RotationRequestContext {
-                    client_id: client_id.clone(),
-                    rotation_id: action_id.clone(),
-                    mls_group: mls_group.clone(),
-                    rotation_reason: rotation_reason.clone(),
-                    not_before_ms,
-                    grace_duration_ms,
-                    jwt_proof_present: jwt_present2,
-                    params_keys: params_keys2,
-                };
-                crate::nip_service::profiles::kr::handle_rotation_request(ctx.clone());
-                // DEV/local: demonstrate prepare (no KMS/DB/MLS), using env NIP_KR_TEST_HMAC_KEY_BASE64URL
-                if let Some(prep) = crate::nip_service::profiles::kr::prepare_rotation_local(&ctx) {
-                    info!(
-                        target: "nip_service",
-                        "NIP-KR local prepare: version_id={} mac_key_ref={} secret_hash_len={}",
-                        prep.version_id, prep.mac_key_ref, prep.secret_hash.len()
-                    );
+        // Tags are authoritative for the identifiers used in routing
+        let Ok(mut json) = serde_json::from_str::<JsonValue>(ct.as_str()) else {
+            ack::send(
+                &ack_target,
+                ack::STATUS_REJECTED,
+                Some((ack::ERR_INVALID_REQUEST, "content is not valid JSON")),
+            );
+            return;
+        };
+        let Some(obj) = json.as_object_mut() else {
+            ack::send(
+                &ack_target,
+                ack::STATUS_REJECTED,
+                Some((ack::ERR_INVALID_REQUEST, "content is not a JSON object")),
+            );
+            return;
+        };
+        obj.insert("action_id".to_owned(), action_id.clone().into());
+        obj.insert("client_id".to_owned(), client_id.clone().into());
+        obj.insert("profile".to_owned(), profile.clone().into());
+        obj.insert("action_type".to_owned(), service.clone().into());
 
-                    // Also persist a dev record in the in-memory store to exercise the flow.
-                    let cid = client_id.clone();
-                    let rid = action_id.clone();
-                    let ver = prep.version_id.clone();
-                    let hash = prep.secret_hash.clone();
-                    let mkr = prep.mac_key_ref.clone();
-                    let reason = rotation_reason.clone();
-                    // not_before default: now + 10 minutes if not provided
-                    let now_ms = std::time::SystemTime::now()
-                        .duration_since(std::time::UNIX_EPOCH)
-                        .unwrap_or_default()
-                        .as_millis() as i64;
-                    let effective_not_before = not_before_ms.unwrap_or(now_ms + 10 * 60 * 1000);
-                    let grace_ms = grace_duration_ms;
-
-                    tokio::spawn(async move {
-                        if let (Some(cid), Some(rid)) = (cid, rid) {
-                            let store = crate::nip_service::store::get_global_store();
-                            if let Err(e) = store
-                                .prepare_rotation(
-                                    &cid,
-                                    &ver,
-                                    &hash,
-                                    &mkr,
-                                    effective_not_before,
-                                    grace_ms,
-                                    &rid,
-                                    reason.as_deref(),
-                                    1, // quorum_required (dev default)
-                                )
-                                .await
-                            {
-                                warn!("NIP-KR dev store prepare failed: {}", e);
-                            } else {
-                                info!(
-                                    target: "nip_service",
-                                    "NIP-KR dev store prepared: client_id={} version_id={} rotation_id={}",
-                                    cid, ver, rid
-                                );
-                            }
-                        } else {
-                            warn!("NIP-KR dev store prepare skipped: missing client_id/action_id");
-                        }
-                    });
-                } else {
-                    warn!("NIP-KR local prepare skipped (missing/invalid NIP_KR_TEST_HMAC_KEY_BASE64URL)");
-                }
-            } else {
-                warn!("NIP-KR route: content JSON parse failed");
-            }
-        }
-
-        // TODO: Dispatch to profile router when available.
-        // For example, if service == Some(\"rotation\") && profile == Some(\"nip-kr/0.1.0\"):
-        // map params to NIP-KR rotate-request semantics and forward to KR handler.
+        // Admins only, then external handler or registered profile, once per action_id
+        dispatcher::dispatch(json, mls_group.clone(), ack_target);
     }
 
     fn handle_service_ack(&self, event: &Event) {
@@ -171,36 +178,24 @@ impl NipService {
         let client_id = get_tag(event, "client");
         let action_id = get_tag(event, "action");
 
+        // Acks signed by the relay report request status, they are not admin acks
+        if emit::service_pubkey().as_deref() == Some(hex::encode(event.pubkey()).as_str()) {
+            return;
+        }
+
         info!(
             target: "nip_service",
             "service-ack 40911 received: service={:?} profile={:?} action_id={:?} client_id={:?}",
             service, profile, action_id, client_id
         );
 
-        // DEV/local: For rotation profile, record ack and promote immediately (quorum=1 default).
-        if service.as_deref() == Some("rotation") && profile.as_deref() == Some("nip-kr/0.1.0") {
-            let rid = action_id.clone();
-            let cid = client_id.clone();
-            tokio::spawn(async move {
-                if let (Some(rid), Some(cid)) = (rid, cid) {
-                    let store = crate::nip_service::store::get_global_store();
-                    if let Err(e) = store.record_ack(&rid).await {
-                        warn!("NIP-KR dev store ack failed: {}", e);
-                    }
-                    if let Err(e) = store.promote_rotation(&cid, &rid).await {
-                        warn!("NIP-KR dev store promote failed: {}", e);
-                    } else {
-                        info!(
-                            target: "nip_service",
-                            "NIP-KR dev store promoted: client_id={} rotation_id={}",
-                            cid, rid
-                        );
-                    }
-                } else {
-                    warn!("NIP-KR dev store ack/promote skipped: missing client_id/action_id");
-                }
-            });
-        }
+        crate::nip_service::dispatcher::handle_service_ack_payload(
+            service,
+            profile,
+            client_id,
+            action_id,
+            hex::encode(event.pubkey()),
+        );
     }
 }
 
@@ -217,9 +212,24 @@ impl Extension for NipService {
         "nip-service"
     }
 
-    fn setting(&mut self, _setting: &nostr_relay::setting::SettingWrapper) {
-        // No settings yet; keep for parity with other extensions
-        info!("NIP-SERVICE settings applied");
+    fn setting(&mut self, setting: &nostr_relay::setting::SettingWrapper) {
+        // Handler selection lives next to the MLS decrypt settings
+        let handler: ServiceHandlerSetting = setting.read().parse_extension("mls_gateway");
+        external::configure(&handler);
+        notify::configure(&handler);
+        dispatcher::configure(&handler);
+        profiles::register(std::sync::Arc::new(profiles::kr::KrProfile::new(
+            handler.ack_quorum_default,
+            std::time::Duration::from_secs(handler.ack_deadline_minutes as u64 * 60),
+        )));
+        lifecycle::spawn_retirement(std::time::Duration::from_secs(
+            handler.secret_retire_interval_secs,
+        ));
+        emit::configure_key(handler.service_secret_key.as_deref());
+        info!(
+            "NIP-SERVICE settings applied: handler={}",
+            handler.preferred_service_handler
+        );
     }
 
     fn config_web(&mut self, _cfg: &mut ServiceConfig) {
@@ -0,0 +1,94 @@
+//! MLS service-notify emission.
+//!
+//! Sensitive results, such as the NIP-KR rotate-notify carrying the plaintext
+//! secret, are encrypted by the relay's MLS service member for the admin group
+//! and published as a group message (445, or the kind it is mapped to by the
+//! mls_gateway kind map) that only carries the `h` tag.
+//! The payload is never logged.
+
+use anyhow::Result;
+use metrics::counter;
+use once_cell::sync::Lazy;
+use parking_lot::RwLock;
+use serde_json::Value as JsonValue;
+use std::collections::HashMap;
+use tracing::{info, warn};
+
+use crate::nip_service::config::ServiceHandlerSetting;
+
+/// MLS group message kind carrying the encrypted notify
+pub const MLS_GROUP_MESSAGE_KIND: u16 = 445;
+
+/// Configured number of the group message kind
+#[cfg(feature = "mls_gateway")]
+fn group_message_kind() -> u16 {
+    crate::mls_gateway::kinds::number(MLS_GROUP_MESSAGE_KIND)
+}
+
+#[cfg(not(feature = "mls_gateway"))]
+fn group_message_kind() -> u16 {
+    MLS_GROUP_MESSAGE_KIND
+}
+
+/// MLS identity of the service member, matches the decrypt path default
+const DEFAULT_SERVICE_USER_ID: &str = "nip_service";
+
+static SENDER: Lazy<RwLock<String>> =
+    Lazy::new(|| RwLock::new(DEFAULT_SERVICE_USER_ID.to_string()));
+static GROUP_SENDERS: Lazy<RwLock<HashMap<String, String>>> = Lazy::new(Default::default);
+
+/// Apply the service member identities used as MLS sender
+pub fn configure(setting: &ServiceHandlerSetting) {
+    *SENDER.write() = setting
+        .mls_service_user_id
+        .clone()
+        .unwrap_or_else(|| DEFAULT_SERVICE_USER_ID.to_string());
+    *GROUP_SENDERS.write() = setting.mls_service_user_ids.clone();
+}
+
+/// MLS sender identity in a group
+fn sender(group_id: &str) -> String {
+    GROUP_SENDERS
+        .read()
+        .get(group_id)
+        .cloned()
+        .unwrap_or_else(|| SENDER.read().clone())
+}
+
+#[cfg(feature = "nip_service_mls")]
+fn encrypt(group_id: &str, payload: JsonValue) -> Result<Vec<u8>> {
+    let sender = sender(group_id);
+    crate::mls_gateway::service_member::encrypt_service_payload(group_id, &sender, payload)
+        .map_err(anyhow::Error::msg)
+}
+
+#[cfg(not(feature = "nip_service_mls"))]
+fn encrypt(_group_id: &str, _payload: JsonValue) -> Result<Vec<u8>> {
+    Err(anyhow::anyhow!(
+        "MLS service member requires the nip_service_mls feature"
+    ))
+}
+
+/// Encrypt a service-notify payload for an admin group and publish it, returns the event id
+pub fn send_service_notify(group_id: &str, payload: JsonValue) -> Result<String> {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+
+    let result = encrypt(group_id, payload).and_then(|ciphertext| {
+        crate::nip_service::emit::emit(
+            group_message_kind(),
+            vec![vec!["h".to_owned(), group_id.to_owned()]],
+            STANDARD.encode(ciphertext),
+        )
+    });
+    match &result {
+        Ok(id) => {
+            counter!("nip_service_notify_total", "result" => "ok").increment(1);
+            info!(target: "nip_service", "service-notify {} published to group {}", id, group_id);
+        }
+        Err(e) => {
+            counter!("nip_service_notify_total", "result" => "error").increment(1);
+            warn!(target: "nip_service", "service-notify to group {} failed: {}", group_id, e);
+        }
+    }
+    result
+}
@@ -7,7 +7,8 @@
 //!
 //! NOTE: This stub avoids logging plaintext secrets. It only logs non-sensitive fields.
 
-use serde_json::Value as JsonValue;
+use metrics::counter;
+use serde_json::{json, Value as JsonValue};
 use tracing::{info, warn};
 
 use hmac::{Hmac, Mac};
@@ -19,6 +20,10 @@ use uuid::Uuid;
 use base64::engine::general_purpose::URL_SAFE_NO_PAD;
 use base64::Engine;
 
+use crate::nip_service::ack;
+use crate::nip_service::profiles::{ServiceProfile, ServiceRequest};
+use crate::nip_service::store::NipKrStore;
+
 /// Structured context extracted from tags and content.
 #[derive(Debug, Clone)]
 pub struct RotationRequestContext {
@@ -32,12 +37,25 @@ pub struct RotationRequestContext {
     pub params_keys: Vec<String>,
 }
 
-/// Result of local/dev prepare flow (non-sensitive subset).
-#[derive(Debug, Clone)]
+/// Result of local/dev prepare flow.
+#[derive(Clone)]
 pub struct PreparedRotation {
     pub version_id: String,
     pub secret_hash: String,
     pub mac_key_ref: String,
+    /// Plaintext secret, only ever sent in the MLS rotate-notify
+    pub secret: String,
+}
+
+impl std::fmt::Debug for PreparedRotation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PreparedRotation")
+            .field("version_id", &self.version_id)
+            .field("secret_hash", &self.secret_hash)
+            .field("mac_key_ref", &self.mac_key_ref)
+            .field("secret", &"<redacted>")
+            .finish()
+    }
 }
 
 /// Extract rotation-specific fields from a service-request JSON content.
@@ -79,9 +97,9 @@ pub fn extract_rotation_params(
 
 /// Handle a rotation service-request (stub).
 ///
-/// This currently logs a structured summary. Next step: hand off to the KR flow:
+/// This currently logs a structured summary. Group admin authorization has
+/// already happened in the dispatcher. Next step: hand off to the KR flow:
 /// - Validate jwt_proof (JWKS)
-/// - AuthZ MLS membership
 /// - KMS MACSign (compute secret_hash)
 /// - Firestore prepare/promote transactions
 /// - MLS rotate-notify to admin group(s)
@@ -131,9 +149,9 @@ pub fn prepare_rotation_local(ctx: &RotationRequestContext) -> Option<PreparedRo
     let canonical = canonical_input(client_id, &version_id, &secret_b64);
 
     // Load dev HMAC key from env
-    let dev_key_b64 = match std::env::var("NIP_KR_TEST_HMAC_KEY_BASE64URL") {
-        Ok(v) => v,
-        Err(_) => {
+    let dev_key_b64 = match nostr_relay::secret::env("NIP_KR_TEST_HMAC_KEY_BASE64URL") {
+        Some(v) => v,
+        None => {
             warn!("prepare_rotation_local: env NIP_KR_TEST_HMAC_KEY_BASE64URL not set; skip local MACSign");
             return None;
         }
@@ -142,7 +160,10 @@ pub fn prepare_rotation_local(ctx: &RotationRequestContext) -> Option<PreparedRo
     let dev_key = match URL_SAFE_NO_PAD.decode(dev_key_b64.as_bytes()) {
         Ok(v) => v,
         Err(e) => {
-            warn!("prepare_rotation_local: base64url decode dev key failed: {}", e);
+            warn!(
+                "prepare_rotation_local: base64url decode dev key failed: {}",
+                e
+            );
             return None;
         }
     };
@@ -156,9 +177,59 @@ pub fn prepare_rotation_local(ctx: &RotationRequestContext) -> Option<PreparedRo
         version_id,
         secret_hash,
         mac_key_ref,
+        secret: secret_b64,
     })
 }
 
+/// rotate-notify body (nip-kr.md) with the NIP-SERVICE action identifiers.
+pub fn rotate_notify_payload(
+    ctx: &RotationRequestContext,
+    prep: &PreparedRotation,
+    not_before_ms: i64,
+    issued_at_ms: i64,
+    relay_msg_id: &str,
+) -> JsonValue {
+    json!({
+        "action_type": "rotation",
+        "action_id": ctx.rotation_id,
+        "profile": "nip-kr/0.1.0",
+        "client_id": ctx.client_id,
+        "version_id": prep.version_id,
+        "secret": prep.secret,
+        "secret_hash": prep.secret_hash,
+        "mac_key_ref": prep.mac_key_ref,
+        "not_before": not_before_ms,
+        "grace_until": ctx.grace_duration_ms.map(|grace| not_before_ms + grace),
+        "rotation_id": ctx.rotation_id,
+        "issued_at": issued_at_ms,
+        "relay_msg_id": relay_msg_id,
+    })
+}
+
+/// Distribute a prepared rotation to the admin MLS group of the request.
+///
+/// Returns the id of the published 445 event.
+pub fn notify_rotation(
+    ctx: &RotationRequestContext,
+    prep: &PreparedRotation,
+    not_before_ms: i64,
+) -> Option<String> {
+    let Some(group_id) = ctx.mls_group.as_deref() else {
+        warn!(
+            "NIP-KR rotate-notify skipped: no admin MLS group for rotation_id={:?}",
+            ctx.rotation_id
+        );
+        return None;
+    };
+    let issued_at_ms = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as i64;
+    let relay_msg_id = Uuid::new_v4().to_string();
+    let payload = rotate_notify_payload(ctx, prep, not_before_ms, issued_at_ms, &relay_msg_id);
+    crate::nip_service::notify::send_service_notify(group_id, payload).ok()
+}
+
 /// Helper: generate a random secret and return base64url (no padding).
 fn generate_secret_base64url(len: usize) -> String {
     let mut buf = vec![0u8; len];
@@ -192,3 +263,221 @@ fn hmac_sign_base64url(key: &[u8], data: &[u8]) -> String {
     let tag = mac.finalize().into_bytes();
     URL_SAFE_NO_PAD.encode(tag)
 }
+
+/// NIP-KR 0.1.0 rotation profile.
+///
+/// DEV/local flow: prepare with the dev HMAC key, record the rotation in the
+/// in-memory store, distribute the rotate-notify and promote once enough
+/// distinct admins of the request's group acked it.
+pub struct KrProfile {
+    /// Distinct acks required before promotion
+    quorum: u32,
+    /// Time allowed to reach the quorum after prepare
+    ack_deadline: std::time::Duration,
+}
+
+impl KrProfile {
+    pub fn new(quorum: u32, ack_deadline: std::time::Duration) -> Self {
+        Self {
+            quorum: quorum.max(1),
+            ack_deadline,
+        }
+    }
+}
+
+impl Default for KrProfile {
+    fn default() -> Self {
+        Self::new(1, std::time::Duration::from_secs(30 * 60))
+    }
+}
+
+impl ServiceProfile for KrProfile {
+    fn service(&self) -> &'static str {
+        "rotation"
+    }
+
+    fn profile(&self) -> &'static str {
+        "nip-kr/0.1.0"
+    }
+
+    fn handle_request(&self, request: ServiceRequest) {
+        let (rotation_reason, not_before_ms, grace_duration_ms, jwt_proof_present, params_keys) =
+            extract_rotation_params(&request.payload);
+        let ctx = RotationRequestContext {
+            client_id: Some(request.client_id.clone()),
+            rotation_id: Some(request.action_id.clone()),
+            mls_group: request.group.clone(),
+            rotation_reason,
+            not_before_ms,
+            grace_duration_ms,
+            jwt_proof_present,
+            params_keys,
+        };
+        handle_rotation_request(ctx.clone());
+        let ack_target = request.ack_target;
+
+        // DEV/local: prepare without KMS, using env NIP_KR_TEST_HMAC_KEY_BASE64URL
+        let Some(prep) = prepare_rotation_local(&ctx) else {
+            warn!("NIP-KR local prepare skipped (missing/invalid NIP_KR_TEST_HMAC_KEY_BASE64URL)");
+            ack::send(
+                &ack_target,
+                ack::STATUS_FAILED,
+                Some((ack::ERR_PREPARE_FAILED, "rotation prepare unavailable")),
+            );
+            return;
+        };
+        info!(
+            target: "nip_service",
+            "NIP-KR local prepare: version_id={} mac_key_ref={} secret_hash_len={}",
+            prep.version_id, prep.mac_key_ref, prep.secret_hash.len()
+        );
+
+        // not_before default: now + 10 minutes if not provided
+        let now_ms = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as i64;
+        let effective_not_before = not_before_ms.unwrap_or(now_ms + 10 * 60 * 1000);
+        let ack_deadline_ms = now_ms + self.ack_deadline.as_millis() as i64;
+        let quorum = self.quorum;
+
+        tokio::spawn(async move {
+            let store = crate::nip_service::store::get_global_store();
+            if let Err(e) = store
+                .prepare_rotation(
+                    &request.client_id,
+                    &prep.version_id,
+                    &prep.secret_hash,
+                    &prep.mac_key_ref,
+                    effective_not_before,
+                    ctx.grace_duration_ms,
+                    &request.action_id,
+                    ctx.rotation_reason.as_deref(),
+                    quorum,
+                    ack_deadline_ms,
+                    ctx.mls_group.as_deref(),
+                )
+                .await
+            {
+                warn!("NIP-KR dev store prepare failed: {}", e);
+                ack::send(
+                    &ack_target,
+                    ack::STATUS_FAILED,
+                    Some((ack::ERR_PREPARE_FAILED, "rotation prepare failed")),
+                );
+            } else {
+                info!(
+                    target: "nip_service",
+                    "NIP-KR dev store prepared: client_id={} version_id={} rotation_id={}",
+                    request.client_id, prep.version_id, request.action_id
+                );
+                // Distribute the plaintext to the admin group via the MLS service member
+                notify_rotation(&ctx, &prep, effective_not_before);
+                // Prepared, promotion waits for admin acks
+                ack::send(&ack_target, ack::STATUS_ACCEPTED, None);
+            }
+        });
+    }
+
+    fn handle_ack(&self, client_id: String, action_id: String, ack_by: String) {
+        // Promote once distinct admin acks reach the quorum before the deadline
+        tokio::spawn(async move {
+            let store = crate::nip_service::store::get_global_store();
+            // Only admins of the rotation's admin group count, anyone can publish a 40911
+            let rotation = match store.get_rotation(&action_id).await {
+                Ok(rotation) => rotation,
+                Err(e) => {
+                    warn!("NIP-KR dev store lookup failed: {}", e);
+                    return;
+                }
+            };
+            // an ack naming another client is not counted
+            if let Some(rotation) = rotation.as_ref().filter(|r| r.client_id != client_id) {
+                warn!(
+                    "NIP-KR ack for rotation_id={} names client_id={}, expected {}",
+                    action_id, client_id, rotation.client_id
+                );
+                return;
+            }
+            let Some(admin_group) = rotation.and_then(|r| r.admin_group) else {
+                info!(target: "nip_service", "NIP-KR ack ignored (unknown rotation or no admin group): rotation_id={}", action_id);
+                return;
+            };
+            if !crate::nip_service::authz::is_group_admin(&admin_group, &ack_by).await {
+                counter!("nip_service_acks_unauthorized").increment(1);
+                warn!(
+                    target: "nip_service",
+                    "NIP-KR ack ignored, {} is not an admin of group {}: rotation_id={}",
+                    ack_by, admin_group, action_id
+                );
+                return;
+            }
+            let now_ms = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as i64;
+            let rotation = match store.record_ack(&action_id, &ack_by, now_ms).await {
+                Ok(Some(rotation)) => rotation,
+                Ok(None) => {
+                    info!(
+                        target: "nip_service",
+                        "NIP-KR ack ignored (unknown, closed, late or repeated): rotation_id={} ack_by={}",
+                        action_id, ack_by
+                    );
+                    return;
+                }
+                Err(e) => {
+                    warn!("NIP-KR dev store ack failed: {}", e);
+                    return;
+                }
+            };
+            if rotation.quorum_acks < rotation.quorum_required {
+                info!(
+                    target: "nip_service",
+                    "NIP-KR ack recorded: rotation_id={} acks={}/{}",
+                    action_id, rotation.quorum_acks, rotation.quorum_required
+                );
+                return;
+            }
+            if let Err(e) = store.promote_rotation(&client_id, &action_id).await {
+                warn!("NIP-KR dev store promote failed: {}", e);
+            } else {
+                info!(
+                    target: "nip_service",
+                    "NIP-KR dev store promoted: client_id={} rotation_id={}",
+                    client_id, action_id
+                );
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotate_notify_body() {
+        let ctx = RotationRequestContext {
+            client_id: Some("ext-totp-svc".to_string()),
+            rotation_id: Some("r1".to_string()),
+            mls_group: Some("admin".to_string()),
+            rotation_reason: None,
+            not_before_ms: None,
+            grace_duration_ms: Some(1000),
+            jwt_proof_present: true,
+            params_keys: vec![],
+        };
+        let prep = PreparedRotation {
+            version_id: "v1".to_string(),
+            secret_hash: "hash".to_string(),
+            mac_key_ref: "local-test-key-v1".to_string(),
+            secret: "s3cr3t".to_string(),
+        };
+        let payload = rotate_notify_payload(&ctx, &prep, 5000, 4000, "m1");
+        assert_eq!(payload["secret"], "s3cr3t");
+        assert_eq!(payload["grace_until"], 6000);
+        assert_eq!(payload["rotation_id"], "r1");
+        assert!(!format!("{:?}", prep).contains("s3cr3t"));
+    }
+}
@@ -1,2 +1,98 @@
-// Profiles router modules for NIP-SERVICE
+//! NIP-SERVICE profiles.
+//!
+//! A profile handles the service-requests and service-acks of one
+//! `(action_type, profile)` pair, e.g. `("rotation", "nip-kr/0.1.0")`.
+//! Profiles live in a registry so new ones (key revocation, device
+//! deprovisioning, group migration) are added with [`register`] instead of
+//! editing the dispatcher.
+
 pub mod kr;
+
+use once_cell::sync::Lazy;
+use parking_lot::RwLock;
+use serde_json::Value as JsonValue;
+use std::{collections::HashMap, sync::Arc};
+
+use crate::nip_service::ack::AckTarget;
+
+/// Validated service-request routed to a profile
+#[derive(Debug, Clone)]
+pub struct ServiceRequest {
+    /// Request JSON: action_type, action_id, client_id, profile, params, jwt_proof
+    pub payload: JsonValue,
+    pub client_id: String,
+    pub action_id: String,
+    /// Admin MLS group of the request
+    pub group: Option<String>,
+    /// Destination of the relay-signed 40911
+    pub ack_target: AckTarget,
+}
+
+/// Handler of a service profile
+pub trait ServiceProfile: Send + Sync {
+    /// action_type handled, e.g. "rotation"
+    fn service(&self) -> &'static str;
+
+    /// profile id, e.g. "nip-kr/0.1.0"
+    fn profile(&self) -> &'static str;
+
+    /// Execute a validated request, the profile sends its own acks
+    fn handle_request(&self, request: ServiceRequest);
+
+    /// Handle an admin ack of an action, `ack_by` identifies the acker
+    fn handle_ack(&self, _client_id: String, _action_id: String, _ack_by: String) {}
+}
+
+type Registry = HashMap<(String, String), Arc<dyn ServiceProfile>>;
+
+static REGISTRY: Lazy<RwLock<Registry>> = Lazy::new(|| {
+    let mut registry = Registry::new();
+    let kr: Arc<dyn ServiceProfile> = Arc::new(kr::KrProfile::default());
+    registry.insert((kr.service().to_owned(), kr.profile().to_owned()), kr);
+    RwLock::new(registry)
+});
+
+/// Register a profile, replacing one with the same service and profile id
+pub fn register(profile: Arc<dyn ServiceProfile>) {
+    REGISTRY.write().insert(
+        (profile.service().to_owned(), profile.profile().to_owned()),
+        profile,
+    );
+}
+
+/// Profile handling a service and profile id
+pub fn get(service: &str, profile: &str) -> Option<Arc<dyn ServiceProfile>> {
+    REGISTRY
+        .read()
+        .get(&(service.to_owned(), profile.to_owned()))
+        .cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Revocation;
+
+    impl ServiceProfile for Revocation {
+        fn service(&self) -> &'static str {
+            "revocation"
+        }
+
+        fn profile(&self) -> &'static str {
+            "test-revocation/0.1.0"
+        }
+
+        fn handle_request(&self, _request: ServiceRequest) {}
+    }
+
+    #[test]
+    fn registry() {
+        assert!(get("rotation", "nip-kr/0.1.0").is_some());
+        assert!(get("revocation", "test-revocation/0.1.0").is_none());
+        register(Arc::new(Revocation));
+        let profile = get("revocation", "test-revocation/0.1.0").unwrap();
+        assert_eq!(profile.service(), "revocation");
+        assert!(get("rotation", "test-revocation/0.1.0").is_none());
+    }
+}
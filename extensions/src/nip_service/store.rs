@@ -8,10 +8,12 @@
 
 use anyhow::Result;
 use async_trait::async_trait;
+use serde::Serialize;
 use std::collections::HashMap;
 use std::sync::{Mutex, OnceLock};
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum SecretState {
     Pending,
     Current,
@@ -19,7 +21,7 @@ pub enum SecretState {
     Retired,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct SecretVersionRecord {
     pub client_id: String,
     pub version_id: String,
@@ -32,7 +34,8 @@ pub struct SecretVersionRecord {
     pub rotation_reason: Option<String>,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum RotationOutcome {
     None,
     Promoted,
@@ -41,7 +44,7 @@ pub enum RotationOutcome {
     RolledBack,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct RotationRecord {
     pub action_id: String, // rotation_id
     pub client_id: String,
@@ -51,7 +54,16 @@ pub struct RotationRecord {
     pub grace_until_ms: Option<i64>,
     pub quorum_required: u32,
     pub quorum_acks: u32,
+    /// Distinct ackers counted towards the quorum
+    pub acked_by: Vec<String>,
+    /// Acks after this are ignored, pending rotations are canceled or expired
+    pub ack_deadline_ms: i64,
+    /// Admin MLS group of the request, only its admins ack the rotation
+    pub admin_group: Option<String>,
     pub outcome: RotationOutcome,
+    pub prepared_at_ms: i64,
+    /// When the outcome was decided (promoted, canceled, expired)
+    pub closed_at_ms: Option<i64>,
 }
 
 #[async_trait]
@@ -68,17 +80,47 @@ pub trait NipKrStore: Send + Sync + 'static {
         rotation_id: &str,
         rotation_reason: Option<&str>,
         quorum_required: u32,
+        ack_deadline_ms: i64,
+        admin_group: Option<&str>,
     ) -> Result<()>;
 
     /// Promote rotation: atomically set current_version=new and old to grace (skeleton).
-    async fn promote_rotation(
+    async fn promote_rotation(&self, client_id: &str, rotation_id: &str) -> Result<()>;
+
+    /// Rotation audit entry by rotation_id.
+    async fn get_rotation(&self, rotation_id: &str) -> Result<Option<RotationRecord>>;
+
+    /// Record an ack of a pending rotation before its deadline, once per acker.
+    /// Returns the updated rotation, None when the ack was not counted.
+    async fn record_ack(
         &self,
-        client_id: &str,
         rotation_id: &str,
-    ) -> Result<()>;
+        ack_by: &str,
+        now_ms: i64,
+    ) -> Result<Option<RotationRecord>>;
+
+    /// Close pending rotations past their ack deadline: Canceled without acks,
+    /// Expired with acks below quorum. Their pending versions are retired.
+    async fn expire_overdue(&self, now_ms: i64) -> Result<Vec<RotationRecord>>;
+
+    /// Version history of a client, oldest first.
+    async fn list_versions(&self, client_id: &str) -> Result<Vec<SecretVersionRecord>>;
+
+    /// Rotation audit entries of a client, oldest first.
+    async fn list_rotations(&self, client_id: &str) -> Result<Vec<RotationRecord>>;
+
+    /// Transition Grace versions whose not_after has passed to Retired, returns them.
+    async fn retire_expired(&self, now_ms: i64) -> Result<Vec<SecretVersionRecord>>;
 
-    /// Record an ack (increments quorum_acks).
-    async fn record_ack(&self, rotation_id: &str) -> Result<()>;
+    /// Claim an action_id for execution (idempotency key).
+    /// Returns false when the action was already claimed within the replay window.
+    async fn claim_action(
+        &self,
+        action_id: &str,
+        client_id: &str,
+        now_ms: i64,
+        replay_window_ms: i64,
+    ) -> Result<bool>;
 }
 
 // ---------------- In-memory store (dev only) ----------------
@@ -93,6 +135,8 @@ struct InMemoryInner {
     current_version: HashMap<String, String>,
     // Previous pointer per client
     previous_version: HashMap<String, String>,
+    // Claimed action_ids: (client_id, claimed_at_ms)
+    actions: HashMap<String, (String, i64)>,
 }
 
 pub struct InMemoryStore {
@@ -107,6 +151,13 @@ impl InMemoryStore {
     }
 }
 
+fn now_ms() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as i64
+}
+
 static GLOBAL_STORE: OnceLock<InMemoryStore> = OnceLock::new();
 
 /// Get a global in-memory store (dev-only; replace with Firestore in prod).
@@ -127,6 +178,8 @@ impl NipKrStore for InMemoryStore {
         rotation_id: &str,
         rotation_reason: Option<&str>,
         quorum_required: u32,
+        ack_deadline_ms: i64,
+        admin_group: Option<&str>,
     ) -> Result<()> {
         let mut g = self.inner.lock().unwrap();
 
@@ -137,7 +190,8 @@ impl NipKrStore for InMemoryStore {
             secret_hash: secret_hash.to_string(),
             mac_key_ref: mac_key_ref.to_string(),
             not_before_ms,
-            not_after_ms: grace_duration_ms.map(|gms| not_before_ms + gms),
+            // not_after is set on the replaced version when this one is promoted
+            not_after_ms: None,
             state: SecretState::Pending,
             rotated_by: None,
             rotation_reason: rotation_reason.map(|s| s.to_string()),
@@ -155,7 +209,12 @@ impl NipKrStore for InMemoryStore {
             grace_until_ms: grace_duration_ms.map(|gms| not_before_ms + gms),
             quorum_required,
             quorum_acks: 0,
+            acked_by: vec![],
+            ack_deadline_ms,
+            admin_group: admin_group.map(|s| s.to_string()),
             outcome: RotationOutcome::None,
+            prepared_at_ms: now_ms(),
+            closed_at_ms: None,
         };
         g.rotations.insert(rotation_id.to_string(), rot);
 
@@ -166,19 +225,22 @@ impl NipKrStore for InMemoryStore {
         let mut g = self.inner.lock().unwrap();
 
         // First, read the new_version without holding a mutable borrow across further ops
-        let new_version = match g.rotations.get(rotation_id) {
-            Some(r) => r.new_version.clone(),
-            None => return Ok(()), // no-op
+        let (new_version, grace_until_ms) = match g.rotations.get(rotation_id) {
+            // without a grace period the old version is retired at not_before
+            Some(r) if r.outcome == RotationOutcome::None => (
+                r.new_version.clone(),
+                r.grace_until_ms.unwrap_or(r.not_before_ms),
+            ),
+            _ => return Ok(()), // no-op: unknown or already closed
         };
 
-        // Move current -> previous, and set previous state to Grace
+        // Move current -> previous, and set previous state to Grace until grace_until
         if let Some(cur) = g.current_version.get(client_id).cloned() {
-            g.previous_version.insert(client_id.to_string(), cur.clone());
-            if let Some(prev_rec) = g
-                .versions
-                .get_mut(&(client_id.to_string(), cur.clone()))
-            {
+            g.previous_version
+                .insert(client_id.to_string(), cur.clone());
+            if let Some(prev_rec) = g.versions.get_mut(&(client_id.to_string(), cur.clone())) {
                 prev_rec.state = SecretState::Grace;
+                prev_rec.not_after_ms = Some(grace_until_ms);
             }
         }
 
@@ -195,16 +257,238 @@ impl NipKrStore for InMemoryStore {
         // Finally, update the rotation outcome in a separate mutable borrow
         if let Some(rot) = g.rotations.get_mut(rotation_id) {
             rot.outcome = RotationOutcome::Promoted;
+            rot.closed_at_ms = Some(now_ms());
         }
 
         Ok(())
     }
 
-    async fn record_ack(&self, rotation_id: &str) -> Result<()> {
+    async fn get_rotation(&self, rotation_id: &str) -> Result<Option<RotationRecord>> {
+        Ok(self
+            .inner
+            .lock()
+            .unwrap()
+            .rotations
+            .get(rotation_id)
+            .cloned())
+    }
+
+    async fn record_ack(
+        &self,
+        rotation_id: &str,
+        ack_by: &str,
+        now_ms: i64,
+    ) -> Result<Option<RotationRecord>> {
         let mut g = self.inner.lock().unwrap();
-        if let Some(rot) = g.rotations.get_mut(rotation_id) {
-            rot.quorum_acks = rot.quorum_acks.saturating_add(1);
+        let Some(rot) = g.rotations.get_mut(rotation_id) else {
+            return Ok(None);
+        };
+        if rot.outcome != RotationOutcome::None
+            || now_ms > rot.ack_deadline_ms
+            || rot.acked_by.iter().any(|a| a == ack_by)
+        {
+            return Ok(None);
+        }
+        rot.acked_by.push(ack_by.to_string());
+        rot.quorum_acks = rot.quorum_acks.saturating_add(1);
+        Ok(Some(rot.clone()))
+    }
+
+    async fn expire_overdue(&self, now_ms: i64) -> Result<Vec<RotationRecord>> {
+        let mut g = self.inner.lock().unwrap();
+        let mut closed = vec![];
+        for rot in g.rotations.values_mut() {
+            if rot.outcome == RotationOutcome::None && now_ms > rot.ack_deadline_ms {
+                rot.outcome = if rot.quorum_acks == 0 {
+                    RotationOutcome::Canceled
+                } else {
+                    RotationOutcome::Expired
+                };
+                rot.closed_at_ms = Some(now_ms);
+                closed.push(rot.clone());
+            }
+        }
+        for rot in &closed {
+            if let Some(v) = g
+                .versions
+                .get_mut(&(rot.client_id.clone(), rot.new_version.clone()))
+            {
+                if v.state == SecretState::Pending {
+                    v.state = SecretState::Retired;
+                }
+            }
+        }
+        Ok(closed)
+    }
+
+    async fn list_versions(&self, client_id: &str) -> Result<Vec<SecretVersionRecord>> {
+        let g = self.inner.lock().unwrap();
+        let mut versions = g
+            .versions
+            .values()
+            .filter(|v| v.client_id == client_id)
+            .cloned()
+            .collect::<Vec<_>>();
+        versions.sort_by_key(|v| v.not_before_ms);
+        Ok(versions)
+    }
+
+    async fn list_rotations(&self, client_id: &str) -> Result<Vec<RotationRecord>> {
+        let g = self.inner.lock().unwrap();
+        let mut rotations = g
+            .rotations
+            .values()
+            .filter(|r| r.client_id == client_id)
+            .cloned()
+            .collect::<Vec<_>>();
+        rotations.sort_by(|a, b| {
+            (a.prepared_at_ms, &a.action_id).cmp(&(b.prepared_at_ms, &b.action_id))
+        });
+        Ok(rotations)
+    }
+
+    async fn retire_expired(&self, now_ms: i64) -> Result<Vec<SecretVersionRecord>> {
+        let mut g = self.inner.lock().unwrap();
+        let mut retired = vec![];
+        for v in g.versions.values_mut() {
+            if v.state == SecretState::Grace && v.not_after_ms.map_or(false, |t| t <= now_ms) {
+                v.state = SecretState::Retired;
+                retired.push(v.clone());
+            }
+        }
+        for v in &retired {
+            if g.previous_version.get(&v.client_id) == Some(&v.version_id) {
+                g.previous_version.remove(&v.client_id);
+            }
         }
+        Ok(retired)
+    }
+
+    async fn claim_action(
+        &self,
+        action_id: &str,
+        client_id: &str,
+        now_ms: i64,
+        replay_window_ms: i64,
+    ) -> Result<bool> {
+        let mut g = self.inner.lock().unwrap();
+        g.actions
+            .retain(|_, (_, claimed_at)| now_ms - *claimed_at < replay_window_ms);
+        if g.actions.contains_key(action_id) {
+            return Ok(false);
+        }
+        g.actions
+            .insert(action_id.to_string(), (client_id.to_string(), now_ms));
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_rt::test]
+    async fn grace_to_retired() -> Result<()> {
+        let store = InMemoryStore::new();
+        store
+            .prepare_rotation(
+                "c1", "v1", "h1", "k", 1_000, None, "r1", None, 1, 10_000, None,
+            )
+            .await?;
+        store.promote_rotation("c1", "r1").await?;
+        store
+            .prepare_rotation(
+                "c1",
+                "v2",
+                "h2",
+                "k",
+                2_000,
+                Some(500),
+                "r2",
+                None,
+                1,
+                10_000,
+                None,
+            )
+            .await?;
+        store.promote_rotation("c1", "r2").await?;
+
+        let versions = store.list_versions("c1").await?;
+        assert_eq!(versions[0].state, SecretState::Grace);
+        assert_eq!(versions[0].not_after_ms, Some(2_500));
+        assert_eq!(versions[1].state, SecretState::Current);
+
+        assert!(store.retire_expired(2_499).await?.is_empty());
+        let retired = store.retire_expired(2_500).await?;
+        assert_eq!(retired.len(), 1);
+        assert_eq!(retired[0].version_id, "v1");
+        assert_eq!(
+            store.list_versions("c1").await?[0].state,
+            SecretState::Retired
+        );
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn quorum_and_deadline() -> Result<()> {
+        let store = InMemoryStore::new();
+        store
+            .prepare_rotation(
+                "c1", "v1", "h1", "k", 1_000, None, "r1", None, 2, 5_000, None,
+            )
+            .await?;
+        store
+            .prepare_rotation(
+                "c1", "v2", "h2", "k", 1_000, None, "r2", None, 2, 5_000, None,
+            )
+            .await?;
+        store
+            .prepare_rotation(
+                "c1", "v3", "h3", "k", 1_000, None, "r3", None, 2, 5_000, None,
+            )
+            .await?;
+
+        // distinct ackers only, none after the deadline
+        assert_eq!(
+            store.record_ack("r1", "a", 100).await?.unwrap().quorum_acks,
+            1
+        );
+        assert!(store.record_ack("r1", "a", 200).await?.is_none());
+        assert_eq!(
+            store.record_ack("r1", "b", 300).await?.unwrap().quorum_acks,
+            2
+        );
+        assert!(store.record_ack("r2", "a", 100).await?.is_some());
+        assert!(store.record_ack("r3", "a", 5_001).await?.is_none());
+
+        let closed = store.expire_overdue(5_001).await?;
+        let outcome = |id: &str| closed.iter().find(|r| r.action_id == id).map(|r| r.outcome);
+        assert_eq!(outcome("r2"), Some(RotationOutcome::Expired));
+        assert_eq!(outcome("r3"), Some(RotationOutcome::Canceled));
+        // r1 reached quorum but was not promoted in time either
+        assert_eq!(outcome("r1"), Some(RotationOutcome::Expired));
+        // closed rotations are never promoted
+        store.promote_rotation("c1", "r1").await?;
+        let history = store.list_rotations("c1").await?;
+        assert_eq!(history.len(), 3);
+        assert!(history.iter().all(|r| r.closed_at_ms == Some(5_001)));
+        assert_eq!(serde_json::to_value(&history[0])?["outcome"], "expired");
+        assert!(store
+            .list_versions("c1")
+            .await?
+            .iter()
+            .all(|v| v.state == SecretState::Retired));
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn claim_action_once() -> Result<()> {
+        let store = InMemoryStore::new();
+        assert!(store.claim_action("a1", "c1", 1_000, 60_000).await?);
+        assert!(!store.claim_action("a1", "c1", 2_000, 60_000).await?);
+        assert!(store.claim_action("a2", "c1", 2_000, 60_000).await?);
+        // forgotten after the replay window
+        assert!(store.claim_action("a1", "c1", 61_000, 60_000).await?);
         Ok(())
     }
 }
@@ -5,7 +5,7 @@ use metrics::{counter, describe_counter};
 use nostr_relay::db::Event;
 use nostr_relay::{
     duration::NonZeroDuration,
-    message::{ClientMessage, IncomingMessage, OutgoingMessage},
+    message::{ClientMessage, IncomingMessage, OutgoingMessage, Prefix},
     setting::SettingWrapper,
     Extension, ExtensionMessageResult, Session,
 };
@@ -39,6 +39,32 @@ pub struct EventQuota {
     /// mixed: [1, 2, [30000, 40000]]
     pub kinds: Option<Vec<Range>>,
     pub ip_whitelist: Option<Vec<String>>,
+    /// bucket key: "ip" (default), "pubkey" or "group"
+    #[serde(default)]
+    pub key: QuotaKey,
+}
+
+/// What an event quota is counted against
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum QuotaKey {
+    /// client ip
+    #[default]
+    Ip,
+    /// NIP-42 authenticated pubkey, unauthenticated sessions are not counted
+    Pubkey,
+    /// group id of the `h` tag (MLS 445), events without one are not counted
+    Group,
+}
+
+impl QuotaKey {
+    fn as_str(&self) -> &'static str {
+        match self {
+            QuotaKey::Ip => "ip",
+            QuotaKey::Pubkey => "pubkey",
+            QuotaKey::Group => "group",
+        }
+    }
 }
 
 /// a simple range included(start)..excluded(end)
@@ -108,6 +134,33 @@ impl EventQuota {
         // has not condition
         true
     }
+
+    /// bucket of the event, none if the quota does not apply
+    pub fn key(&self, event: &Event, ip: &str, pubkey: Option<&String>) -> Option<String> {
+        match self.key {
+            QuotaKey::Ip => Some(ip.to_owned()),
+            QuotaKey::Pubkey => pubkey.cloned(),
+            QuotaKey::Group => event
+                .tags()
+                .iter()
+                .find(|tag| tag.len() >= 2 && tag[0] == "h")
+                .map(|tag| tag[1].clone()),
+        }
+    }
+
+    /// reason sent in the OK message
+    pub fn reason(&self) -> String {
+        if self.description.is_empty() {
+            format!(
+                "{} events per {}s per {}",
+                self.limit,
+                self.period.as_secs(),
+                self.key.as_str()
+            )
+        } else {
+            self.description.clone()
+        }
+    }
 }
 
 pub trait Quotable {
@@ -214,20 +267,26 @@ impl Extension for Ratelimiter {
         session: &mut Session,
         _ctx: &mut <Session as actix::Actor>::Context,
     ) -> ExtensionMessageResult {
-        // enabled
-        if self.setting.enabled {
+        // enabled, remote events were charged on the instance that accepted them
+        if self.setting.enabled && !session.is_remote() {
             self.clear();
             let ip = session.ip();
             if let IncomingMessage::Event(event) = &msg.msg {
                 // check event limiter
                 for (index, limiter) in self.event_limiters.iter().enumerate() {
                     let q = &self.setting.event[index];
-                    if q.hit(event, ip) && limiter.check_key(ip).is_err() {
-                        counter!("nostr_relay_rate_limiter_exceeded", "command" => "EVENT", "name" => q.name.clone()).increment(1);
-                        return OutgoingMessage::ok(
+                    if !q.hit(event, ip) {
+                        continue;
+                    }
+                    let Some(key) = q.key(event, ip, msg.auth_pubkey.as_ref()) else {
+                        continue;
+                    };
+                    if limiter.check_key(&key).is_err() {
+                        counter!("nostr_relay_rate_limiter_exceeded", "command" => "EVENT", "name" => q.name.clone(), "key" => q.key.as_str()).increment(1);
+                        return OutgoingMessage::rejected(
                             &event.id_str(),
-                            false,
-                            &format!("rate-limited: {}", q.description),
+                            Prefix::RateLimited,
+                            &q.reason(),
                         )
                         .into();
                     }
@@ -311,6 +370,7 @@ mod tests {
             limit: NonZeroU32::new(1).unwrap(),
             kinds: None,
             ip_whitelist: None,
+            key: QuotaKey::Ip,
         };
         assert!(q.hit(&event, &ip));
 
@@ -321,6 +381,7 @@ mod tests {
             limit: NonZeroU32::new(1).unwrap(),
             kinds: Some(vec![Range(1, 100), Range(200, 300)]),
             ip_whitelist: Some(vec![ip.clone()]),
+            key: QuotaKey::Ip,
         };
         // ip whitelist
         assert!(!q.hit(&event, &ip));
@@ -333,6 +394,34 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn key() -> Result<()> {
+        let key_pair =
+            Keypair::from_seckey_str(nostr_relay::db::secp256k1::SECP256K1, &"01".repeat(32))?;
+        let tags = vec![vec!["h".to_owned(), "group".to_owned()]];
+        let group_message = Event::create(&key_pair, now(), 445, tags, "x".to_owned())?;
+        let note = Event::create(&key_pair, now(), 1, vec![], "x".to_owned())?;
+        let pubkey = "ab".repeat(32);
+        let ip = "127.0.0.1";
+
+        let mut q: EventQuota = serde_json::from_str(r#"{"period": 1, "limit": 2}"#)?;
+        assert_eq!(q.key, QuotaKey::Ip);
+        assert_eq!(q.key(&note, ip, None), Some(ip.to_owned()));
+        assert_eq!(q.reason(), "2 events per 1s per ip");
+
+        q.key = QuotaKey::Pubkey;
+        assert_eq!(q.key(&note, ip, Some(&pubkey)), Some(pubkey.clone()));
+        assert_eq!(q.key(&note, ip, None), None);
+
+        q.key = QuotaKey::Group;
+        assert_eq!(
+            q.key(&group_message, ip, Some(&pubkey)),
+            Some("group".to_owned())
+        );
+        assert_eq!(q.key(&note, ip, Some(&pubkey)), None);
+        Ok(())
+    }
+
     #[actix_rt::test]
     async fn check() -> Result<()> {
         let setting: SettingWrapper = Setting::default().into();
@@ -0,0 +1,224 @@
+//! Secrets providers for `secret://` config references
+//!
+//! Before the relay reads its config, [`Secrets::load`] collects the
+//! `secret://<name>` references of the extension settings and of the env vars,
+//! and loads them into [`nostr_relay::secret`]. Google Secret Manager names are
+//! `projects/<project>/secrets/<secret>[/versions/<version>]`, or
+//! `<secret>[/versions/<version>]` in `[secrets] project_id`.
+//!
+//! Unpinned references follow the `latest` version: every `refresh_secs` the
+//! provider is asked for it again, and a new version replaces the cached value
+//! and reloads the setting so extensions pick it up (e.g. the storage backend
+//! reconnects with a rotated `database_url`). A failed refresh keeps the cached
+//! value. References added to the config later need a restart.
+
+use async_trait::async_trait;
+use metrics::counter;
+use nostr_relay::{secret, setting::Secrets as SecretsSetting, Setting};
+use std::{collections::HashMap, path::Path, sync::Arc, time::Duration};
+use tracing::{info, warn};
+
+/// Value of a secret version
+#[derive(Debug, Clone)]
+pub struct SecretVersion {
+    pub value: String,
+    /// provider version id, a change means the secret rotated
+    pub version: String,
+}
+
+/// Source of `secret://` values
+#[async_trait]
+pub trait SecretProvider: Send + Sync {
+    /// Current value of a reference name (without `secret://`)
+    async fn access(&self, name: &str) -> anyhow::Result<SecretVersion>;
+
+    /// Whether the name fixes a version, pinned references are not refreshed
+    fn is_pinned(&self, name: &str) -> bool;
+}
+
+/// Google Secret Manager, see [`crate::gcp`]
+pub struct GcpSecretManager {
+    project_id: Option<String>,
+}
+
+impl GcpSecretManager {
+    pub fn new(project_id: Option<String>) -> Self {
+        Self {
+            project_id: project_id
+                .or_else(|| std::env::var("GOOGLE_CLOUD_PROJECT").ok())
+                .or_else(|| std::env::var("GCP_PROJECT").ok()),
+        }
+    }
+
+    /// Full version resource name of a reference name
+    fn version_name(&self, name: &str) -> anyhow::Result<String> {
+        let name = name.trim_matches('/');
+        let name = if name.starts_with("projects/") {
+            name.to_owned()
+        } else {
+            let project = self.project_id.as_deref().ok_or_else(|| {
+                anyhow::anyhow!("no project for secret {}, set [secrets] project_id", name)
+            })?;
+            format!("projects/{}/secrets/{}", project, name)
+        };
+        Ok(if name.contains("/versions/") {
+            name
+        } else {
+            format!("{}/versions/latest", name)
+        })
+    }
+}
+
+#[async_trait]
+impl SecretProvider for GcpSecretManager {
+    async fn access(&self, name: &str) -> anyhow::Result<SecretVersion> {
+        let version = self.version_name(name)?;
+        let payload = crate::gcp::access_secret(&version)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("secret {} not found", name))?;
+        Ok(SecretVersion {
+            // values written with `echo` end with a newline
+            value: String::from_utf8(payload.data)?
+                .trim_end_matches(['\r', '\n'])
+                .to_owned(),
+            version: payload.version,
+        })
+    }
+
+    fn is_pinned(&self, name: &str) -> bool {
+        name.contains("/versions/") && !name.trim_end_matches('/').ends_with("/versions/latest")
+    }
+}
+
+/// Provider of the `[secrets]` setting
+pub fn provider(setting: &SecretsSetting) -> anyhow::Result<Arc<dyn SecretProvider>> {
+    match setting.provider.as_str() {
+        "gcp" => Ok(Arc::new(GcpSecretManager::new(setting.project_id.clone()))),
+        other => Err(anyhow::anyhow!("unknown secrets provider '{}'", other)),
+    }
+}
+
+/// `secret://` references loaded for the relay, with the version of each
+pub struct Secrets {
+    provider: Arc<dyn SecretProvider>,
+    versions: HashMap<String, String>,
+    refresh: Duration,
+}
+
+impl Secrets {
+    /// Load the references of the config file and of the env vars, None without references.
+    /// Fails if one of them cannot be loaded.
+    pub async fn load(config: &Path, env_prefix: Option<String>) -> anyhow::Result<Option<Self>> {
+        let setting = Setting::read_unresolved(config, env_prefix)?;
+        let mut references = setting.secret_references();
+        for (_, value) in std::env::vars() {
+            if secret::is_reference(&value) && !references.contains(&value) {
+                references.push(value);
+            }
+        }
+        if references.is_empty() {
+            return Ok(None);
+        }
+
+        let mut secrets = Self {
+            provider: provider(&setting.secrets)?,
+            versions: HashMap::new(),
+            refresh: Duration::from_secs(setting.secrets.refresh_secs),
+        };
+        for reference in references {
+            let loaded = secrets
+                .provider
+                .access(&reference[secret::SCHEME.len()..])
+                .await?;
+            secret::set(&reference, loaded.value);
+            secrets.versions.insert(reference, loaded.version);
+        }
+        info!("Loaded {} config secrets", secrets.versions.len());
+        Ok(Some(secrets))
+    }
+
+    /// Number of loaded references
+    pub fn len(&self) -> usize {
+        self.versions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.versions.is_empty()
+    }
+
+    /// Check the unpinned references for new versions, returns the rotated references
+    pub async fn refresh(&mut self) -> Vec<String> {
+        let mut rotated = vec![];
+        for (reference, version) in self.versions.iter_mut() {
+            let name = &reference[secret::SCHEME.len()..];
+            if self.provider.is_pinned(name) {
+                continue;
+            }
+            match self.provider.access(name).await {
+                Ok(loaded) if loaded.version != *version => {
+                    info!("Config secret {} rotated to {}", reference, loaded.version);
+                    counter!("nostr_relay_secret_rotations").increment(1);
+                    secret::set(reference, loaded.value);
+                    *version = loaded.version;
+                    rotated.push(reference.clone());
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    counter!("nostr_relay_secret_refresh_failed").increment(1);
+                    warn!(
+                        "Refreshing config secret {} failed, keeping the cached value: {}",
+                        reference, e
+                    );
+                }
+            }
+        }
+        rotated
+    }
+
+    /// Refresh every `refresh_secs`, `on_rotate` runs after secrets rotated
+    pub fn spawn_refresh<F: Fn() + Send + 'static>(mut self, on_rotate: F) {
+        if self.refresh.is_zero()
+            || self
+                .versions
+                .keys()
+                .all(|r| self.provider.is_pinned(&r[secret::SCHEME.len()..]))
+        {
+            return;
+        }
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(self.refresh);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                if !self.refresh().await.is_empty() {
+                    on_rotate();
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gcp_names() {
+        let gcp = GcpSecretManager::new(Some("relay".to_owned()));
+        assert_eq!(
+            gcp.version_name("db-url").unwrap(),
+            "projects/relay/secrets/db-url/versions/latest"
+        );
+        assert_eq!(
+            gcp.version_name("db-url/versions/3").unwrap(),
+            "projects/relay/secrets/db-url/versions/3"
+        );
+        assert_eq!(
+            gcp.version_name("projects/other/secrets/kr-hmac").unwrap(),
+            "projects/other/secrets/kr-hmac/versions/latest"
+        );
+        assert!(gcp.is_pinned("db-url/versions/3"));
+        assert!(!gcp.is_pinned("db-url/versions/latest"));
+        assert!(!gcp.is_pinned("projects/other/secrets/kr-hmac"));
+    }
+}
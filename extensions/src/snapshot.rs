@@ -0,0 +1,528 @@
+//! Scheduled LMDB snapshots to object storage
+//!
+//! Every `interval` the events database is copied with LMDB's consistent
+//! compacting copy (writers keep going), gzipped and uploaded to a GCS
+//! (`gs://bucket`) or S3 (`s3://bucket`) bucket. The Firestore archive only
+//! holds MLS kinds for `message_archive_ttl_days`, snapshots keep the whole
+//! database for point-in-time recovery with `rnostr snapshot restore`.
+//!
+//! Each snapshot is recorded in a `snapshots.json` manifest next to the
+//! objects with its size, sha256 and event count. With `keep` the oldest
+//! snapshots beyond that count are deleted. The manifest is written only if
+//! it did not change since it was read, instances sharing a bucket retry on
+//! a conflict instead of dropping each other's entries.
+//!
+//! The copy and its compressed file are written to `work_dir`
+//! (`$data.path/snapshot` by default), which needs room for both; the upload
+//! and a restore stream from and to disk.
+
+use crate::object_store::ObjectStore;
+use anyhow::{anyhow, bail, Result};
+use chrono::{DateTime, Utc};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use metrics::{counter, describe_counter, describe_gauge, describe_histogram, gauge, histogram};
+use nostr_relay::{db::Db, setting::CARGO_PKG_VERSION, App};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tracing::{info, warn};
+
+/// Manifest object name, relative to the prefix
+const MANIFEST_OBJECT: &str = "snapshots.json";
+
+/// Attempts to update the manifest when other instances write it concurrently
+const MANIFEST_ATTEMPTS: usize = 5;
+
+/// Compressed copy in the work dir
+const COMPRESSED_FILE: &str = "events.mdb.gz";
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct SnapshotSetting {
+    pub enabled: bool,
+    /// `gs://bucket[/prefix]` or `s3://bucket[/prefix]`
+    pub bucket: Option<String>,
+    #[serde(deserialize_with = "nostr_relay::duration::deserialize")]
+    pub interval: Duration,
+    /// snapshots kept in the bucket, 0 keeps all
+    pub keep: usize,
+    /// gzip level, 0-9
+    pub compression_level: u32,
+    /// S3 region and endpoint, the endpoint defaults to AWS
+    pub s3_region: String,
+    pub s3_endpoint: Option<String>,
+    /// directory of the copy and the compressed file, `$data.path/snapshot` by default
+    pub work_dir: Option<PathBuf>,
+}
+
+impl Default for SnapshotSetting {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            bucket: None,
+            interval: Duration::from_secs(6 * 3600),
+            keep: 14,
+            compression_level: 6,
+            s3_region: "us-east-1".to_owned(),
+            s3_endpoint: None,
+            work_dir: None,
+        }
+    }
+}
+
+impl SnapshotSetting {
+    /// Client of the configured bucket
+    fn store(&self) -> Result<ObjectStore> {
+        let bucket = self
+            .bucket
+            .as_deref()
+            .ok_or_else(|| anyhow!("snapshot.bucket is not set"))?;
+        ObjectStore::new(bucket, &self.s3_region, self.s3_endpoint.as_deref())
+    }
+
+    /// Work dir of the copy, `snapshot` below the data path by default
+    pub fn work_dir(&self, data_path: &Path) -> PathBuf {
+        self.work_dir
+            .clone()
+            .unwrap_or_else(|| data_path.join("snapshot"))
+    }
+}
+
+/// Uploaded snapshot
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotMeta {
+    /// object name below the prefix
+    pub name: String,
+    pub created_at: DateTime<Utc>,
+    /// bytes of the LMDB data file
+    pub size: u64,
+    /// bytes of the gzipped object
+    pub compressed_size: u64,
+    /// sha256 of the gzipped object, hex
+    pub sha256: String,
+    /// events in the database
+    pub events: u64,
+    /// relay version that took the snapshot
+    pub version: String,
+    /// Cloud Run revision or host name
+    pub instance: Option<String>,
+}
+
+/// Snapshots in the bucket, oldest first
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct SnapshotManifest {
+    pub snapshots: Vec<SnapshotMeta>,
+}
+
+impl SnapshotManifest {
+    /// Snapshot by name, the latest without one
+    pub fn find(&self, name: Option<&str>) -> Option<&SnapshotMeta> {
+        match name {
+            Some(name) => self.snapshots.iter().find(|s| s.name == name),
+            None => self.snapshots.last(),
+        }
+    }
+
+    /// Add a snapshot, returns the ones beyond `keep` to delete
+    fn push(&mut self, meta: SnapshotMeta, keep: usize) -> Vec<SnapshotMeta> {
+        self.snapshots.push(meta);
+        if keep == 0 || self.snapshots.len() <= keep {
+            return vec![];
+        }
+        let expired = self.snapshots.len() - keep;
+        self.snapshots.drain(..expired).collect()
+    }
+}
+
+/// Object name of a snapshot taken at `time`
+fn object_name(time: DateTime<Utc>) -> String {
+    format!("events-{}.mdb.gz", time.format("%Y%m%dT%H%M%SZ"))
+}
+
+/// Writer passing bytes through while hashing and counting them
+struct HashWriter<W> {
+    inner: W,
+    hasher: Sha256,
+    written: u64,
+}
+
+impl<W: Write> Write for HashWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Compressed copy of the database
+struct Taken {
+    /// bytes of the data file
+    size: u64,
+    events: u64,
+    compressed_size: u64,
+    /// sha256 of the compressed file, hex
+    sha256: String,
+    /// compressed file in the work dir
+    file: PathBuf,
+}
+
+/// Compacting copy of the database into `dir`, gzipped into [`COMPRESSED_FILE`] next to it
+fn take(db: &Db, dir: &Path, level: u32) -> Result<Taken> {
+    if dir.exists() {
+        fs::remove_dir_all(dir)?;
+    }
+    let events = db
+        .storage_stats()?
+        .trees
+        .iter()
+        .find(|(name, _)| *name == "data")
+        .map_or(0, |(_, stat)| stat.entries as u64);
+    let copy = dir.join("copy");
+    db.copy_to(&copy, true)?;
+    let data = copy.join("data.mdb");
+    let size = fs::metadata(&data)?.len();
+
+    let file = dir.join(COMPRESSED_FILE);
+    let out = HashWriter {
+        inner: io::BufWriter::new(File::create(&file)?),
+        hasher: Sha256::new(),
+        written: 0,
+    };
+    let mut encoder = GzEncoder::new(out, Compression::new(level.min(9)));
+    io::copy(&mut File::open(&data)?, &mut encoder)?;
+    let mut out = encoder.finish()?;
+    out.flush()?;
+    out.inner
+        .into_inner()
+        .map_err(|e| e.into_error())?
+        .sync_all()?;
+    fs::remove_dir_all(&copy)?;
+    Ok(Taken {
+        size,
+        events,
+        compressed_size: out.written,
+        sha256: hex::encode(out.hasher.finalize()),
+        file,
+    })
+}
+
+/// sha256 of a file, hex
+fn file_sha256(path: &Path) -> Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Snapshot job
+pub struct Snapshotter {
+    db: Arc<Db>,
+    store: ObjectStore,
+    setting: SnapshotSetting,
+    /// directory the copy is written to before compressing
+    work_dir: PathBuf,
+}
+
+impl Snapshotter {
+    pub fn new(db: Arc<Db>, work_dir: PathBuf, setting: SnapshotSetting) -> Result<Self> {
+        Ok(Self {
+            db,
+            store: setting.store()?,
+            setting,
+            work_dir,
+        })
+    }
+
+    /// Start the job when enabled in the relay setting
+    pub fn start_with(app: &App) {
+        let r = app.setting.read();
+        let setting: SnapshotSetting = r.parse_extension("snapshot");
+        let work_dir = setting.work_dir(&r.data.path);
+        drop(r);
+        if !setting.enabled {
+            return;
+        }
+        describe_counter!(
+            "nostr_relay_snapshot_total",
+            "The total count of database snapshots by result"
+        );
+        describe_counter!(
+            "nostr_relay_snapshot_manifest_conflict",
+            "The total count of manifest writes retried after another instance wrote it"
+        );
+        describe_gauge!(
+            "nostr_relay_snapshot_bytes",
+            "The compressed size of the last database snapshot"
+        );
+        describe_gauge!(
+            "nostr_relay_snapshot_last_success",
+            "The unix time of the last uploaded database snapshot"
+        );
+        describe_histogram!(
+            "nostr_relay_snapshot_duration",
+            "The time to copy, compress and upload a database snapshot"
+        );
+        match Self::new(app.db.clone(), work_dir, setting) {
+            Ok(snapshotter) => snapshotter.spawn(),
+            Err(e) => warn!("Database snapshots disabled: {}", e),
+        }
+    }
+
+    /// Take a snapshot, upload it and apply the retention
+    pub async fn run_once(&self) -> Result<SnapshotMeta> {
+        let start = Instant::now();
+        let created_at = Utc::now();
+        let db = self.db.clone();
+        let work_dir = self.work_dir.clone();
+        let level = self.setting.compression_level;
+        let taken = tokio::task::spawn_blocking(move || take(&db, &work_dir, level)).await??;
+
+        let meta = SnapshotMeta {
+            name: object_name(created_at),
+            created_at,
+            size: taken.size,
+            compressed_size: taken.compressed_size,
+            sha256: taken.sha256,
+            events: taken.events,
+            version: CARGO_PKG_VERSION.unwrap_or_default().to_owned(),
+            instance: std::env::var("K_REVISION")
+                .or_else(|_| std::env::var("HOSTNAME"))
+                .ok(),
+        };
+        let uploaded = self.store.put_file(&meta.name, &taken.file).await;
+        if let Err(e) = fs::remove_dir_all(&self.work_dir) {
+            warn!(
+                "Removing the snapshot work dir {} failed: {}",
+                self.work_dir.display(),
+                e
+            );
+        }
+        uploaded?;
+
+        let expired = self.record(&meta).await?;
+        for old in expired {
+            if let Err(e) = self.store.delete(&old.name).await {
+                warn!("Deleting expired snapshot {} failed: {}", old.name, e);
+            }
+        }
+
+        histogram!("nostr_relay_snapshot_duration").record(start.elapsed());
+        gauge!("nostr_relay_snapshot_bytes").set(meta.compressed_size as f64);
+        gauge!("nostr_relay_snapshot_last_success").set(created_at.timestamp() as f64);
+        Ok(meta)
+    }
+
+    /// Add a snapshot to the manifest unless another instance wrote it since it was read,
+    /// then read it again. Returns the snapshots beyond `keep`.
+    async fn record(&self, meta: &SnapshotMeta) -> Result<Vec<SnapshotMeta>> {
+        for _ in 0..MANIFEST_ATTEMPTS {
+            let (mut manifest, version) = match self.store.get_versioned(MANIFEST_OBJECT).await? {
+                Some((body, version)) => (
+                    serde_json::from_slice::<SnapshotManifest>(&body)?,
+                    Some(version),
+                ),
+                None => (SnapshotManifest::default(), None),
+            };
+            let expired = manifest.push(meta.clone(), self.setting.keep);
+            let body = serde_json::to_vec_pretty(&manifest)?;
+            if self
+                .store
+                .put_if(MANIFEST_OBJECT, body, version.as_deref())
+                .await?
+            {
+                return Ok(expired);
+            }
+            counter!("nostr_relay_snapshot_manifest_conflict").increment(1);
+        }
+        bail!(
+            "{} changed concurrently {} times, {} is not recorded",
+            MANIFEST_OBJECT,
+            MANIFEST_ATTEMPTS,
+            meta.name
+        )
+    }
+
+    /// Run the snapshots on the interval in the background, the first after one interval
+    pub fn spawn(self) {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(self.setting.interval);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                match self.run_once().await {
+                    Ok(meta) => {
+                        counter!("nostr_relay_snapshot_total", "result" => "ok").increment(1);
+                        info!(
+                            "Database snapshot {}: {} events, {} bytes, {} compressed",
+                            meta.name, meta.events, meta.size, meta.compressed_size
+                        );
+                    }
+                    Err(e) => {
+                        counter!("nostr_relay_snapshot_total", "result" => "failed").increment(1);
+                        warn!("Database snapshot failed: {}", e);
+                    }
+                }
+            }
+        });
+    }
+}
+
+/// Load the manifest, a missing object starts empty
+async fn load_manifest(store: &ObjectStore) -> Result<SnapshotManifest> {
+    match store.get(MANIFEST_OBJECT).await? {
+        Some(body) => Ok(serde_json::from_slice(&body)?),
+        None => Ok(SnapshotManifest::default()),
+    }
+}
+
+/// Snapshots in the configured bucket
+pub async fn list(setting: &SnapshotSetting) -> Result<SnapshotManifest> {
+    load_manifest(&setting.store()?).await
+}
+
+/// Download a snapshot, the latest without a name, verify it and write its data file
+/// into the directory `path`. A directory already holding a database is refused.
+pub async fn restore(
+    setting: &SnapshotSetting,
+    name: Option<&str>,
+    path: &Path,
+) -> Result<SnapshotMeta> {
+    let file = path.join("data.mdb");
+    if file.exists() {
+        bail!(
+            "{} already exists, restore into an empty directory",
+            file.display()
+        );
+    }
+    let store = setting.store()?;
+    let manifest = load_manifest(&store).await?;
+    let meta = manifest
+        .find(name)
+        .ok_or_else(|| anyhow!("snapshot {} not found", name.unwrap_or("latest")))?
+        .clone();
+    fs::create_dir_all(path)?;
+    let download = path.join(format!("{}.part", COMPRESSED_FILE));
+    let restored = restore_file(&store, &meta, &download, &file).await;
+    if download.exists() {
+        fs::remove_file(&download)?;
+    }
+    restored?;
+    Ok(meta)
+}
+
+/// Download the compressed snapshot to `download`, verify it and decompress it into `file`
+async fn restore_file(
+    store: &ObjectStore,
+    meta: &SnapshotMeta,
+    download: &Path,
+    file: &Path,
+) -> Result<()> {
+    if !store.download(&meta.name, download).await? {
+        bail!("snapshot object {} is missing", meta.name);
+    }
+    let download = download.to_owned();
+    let file = file.to_owned();
+    let expected = meta.sha256.clone();
+    let name = meta.name.clone();
+    tokio::task::spawn_blocking(move || {
+        let sha256 = file_sha256(&download)?;
+        if sha256 != expected {
+            bail!(
+                "snapshot {} checksum mismatch: {} != {}",
+                name,
+                sha256,
+                expected
+            );
+        }
+        // written aside first, a failed restore leaves no data file behind
+        let partial = file.with_extension("mdb.part");
+        let mut out = File::create(&partial)?;
+        io::copy(
+            &mut GzDecoder::new(io::BufReader::new(File::open(&download)?)),
+            &mut out,
+        )?;
+        out.sync_all()?;
+        fs::rename(&partial, &file)?;
+        Ok(())
+    })
+    .await?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::temp_data_path;
+    use nostr_relay::db::Event;
+    use std::str::FromStr;
+
+    fn meta(name: &str) -> SnapshotMeta {
+        SnapshotMeta {
+            name: name.to_owned(),
+            created_at: Utc::now(),
+            size: 0,
+            compressed_size: 0,
+            sha256: String::new(),
+            events: 0,
+            version: String::new(),
+            instance: None,
+        }
+    }
+
+    #[test]
+    fn retention() {
+        let mut manifest = SnapshotManifest::default();
+        assert!(manifest.push(meta("a"), 2).is_empty());
+        assert!(manifest.push(meta("b"), 2).is_empty());
+        let expired = manifest.push(meta("c"), 2);
+        assert_eq!(
+            expired.iter().map(|m| m.name.as_str()).collect::<Vec<_>>(),
+            vec!["a"]
+        );
+        assert_eq!(manifest.find(None).unwrap().name, "c");
+        assert_eq!(manifest.find(Some("b")).unwrap().name, "b");
+        assert!(manifest.find(Some("a")).is_none());
+        assert!(manifest.push(meta("d"), 0).is_empty());
+
+        let time = DateTime::<Utc>::from_timestamp(1_737_244_800, 0).unwrap();
+        assert_eq!(object_name(time), "events-20250119T000000Z.mdb.gz");
+    }
+
+    #[test]
+    fn snapshot() -> Result<()> {
+        let dir = temp_data_path("snapshot")?;
+        let db = Db::open(dir.path().join("events"))?;
+        let event = Event::from_str(
+            r#"{"content":"Good morning everyone 😃","created_at":1680690006,"id":"332747c0fab8a1a92def4b0937e177be6df4382ce6dd7724f86dc4710b7d4d7d","kind":1,"pubkey":"7abf57d516b1ff7308ca3bd5650ea6a4674d469c7c5057b1d005fb13d218bfef","sig":"ef4ff4f69ac387239eb1401fb07d7a44a5d5d57127e0dc3466a0403cf7d5486b668608ebfcbe9ff1f8d3b5d710545999fe08ee767284ec0b474e4cf92537678f","tags":[["t","nostr"]]}"#,
+        )?;
+        db.batch_put(vec![event])?;
+
+        let work_dir = dir.path().join("snapshot");
+        let taken = take(&db, &work_dir, 6)?;
+        assert_eq!(taken.events, 1);
+        assert!(!work_dir.join("copy").exists());
+        assert_eq!(fs::metadata(&taken.file)?.len(), taken.compressed_size);
+        assert_eq!(file_sha256(&taken.file)?, taken.sha256);
+
+        // the restored file opens as the same database
+        let restored = dir.path().join("restored");
+        fs::create_dir_all(&restored)?;
+        io::copy(
+            &mut GzDecoder::new(File::open(&taken.file)?),
+            &mut File::create(restored.join("data.mdb"))?,
+        )?;
+        assert_eq!(fs::metadata(restored.join("data.mdb"))?.len(), taken.size);
+        let copy = Db::open(&restored)?;
+        assert_eq!(copy.storage_stats()?.trees[0].1.entries, 1);
+        Ok(())
+    }
+}
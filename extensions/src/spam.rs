@@ -0,0 +1,583 @@
+//! Spam heuristics
+//!
+//! Events of scored kinds go through a pipeline of scorers whose scores add up;
+//! an event reaching the threshold of its kind is logged (`shadow`) or rejected
+//! with `blocked:` (`reject`). The built-in scorers are:
+//! - `rate`: events of the pubkey within `rate.window` beyond `rate.limit`
+//! - `burst`: the same over a short `burst.window`
+//! - `duplicate`: copies of the same content (case and whitespace folded) within
+//!   `duplicate.window`, from any pubkey
+//! - `new_pubkey`: a flat penalty while the pubkey was first seen less than
+//!   `new_pubkey.age` ago
+//!
+//! Embedders add their own with [`Spam::with_scorer`]. State is per instance
+//! and in memory; MLS kinds are skipped by default, their senders are ephemeral
+//! or bound by the MLS gateway checks. The default `skip_kinds` follow the
+//! `[extensions.mls_gateway.kinds]` map.
+
+use metrics::{counter, describe_counter};
+use nostr_relay::db::Event;
+use nostr_relay::{
+    message::{ClientMessage, IncomingMessage, OutgoingMessage, Prefix},
+    setting::SettingWrapper,
+    Extension, ExtensionMessageResult, Session,
+};
+use parking_lot::Mutex;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tracing::info;
+
+/// Interval between prunes of the scorer state
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Score contribution of one heuristic
+pub trait Scorer: Send + Sync {
+    /// label in logs and metrics
+    fn name(&self) -> &'static str;
+
+    /// Score an event, recording it in the scorer state
+    fn score(&self, event: &Event, now: Instant) -> f64;
+
+    /// Drop state older than the scorer needs
+    #[allow(unused_variables)]
+    fn prune(&self, now: Instant) {}
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    /// log and count, accept the event
+    #[default]
+    Shadow,
+    Reject,
+}
+
+impl Mode {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Mode::Shadow => "shadow",
+            Mode::Reject => "reject",
+        }
+    }
+}
+
+/// Threshold and mode of some kinds
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct KindPolicy {
+    pub kinds: Vec<u16>,
+    pub threshold: Option<f64>,
+    pub mode: Option<Mode>,
+}
+
+/// Events within a window beyond a limit, `weight` per extra event
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct WindowSetting {
+    #[serde(deserialize_with = "nostr_relay::duration::deserialize")]
+    pub window: Duration,
+    pub limit: usize,
+    pub weight: f64,
+}
+
+impl Default for WindowSetting {
+    fn default() -> Self {
+        Self {
+            window: Duration::from_secs(60),
+            limit: 30,
+            weight: 1.0,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct DuplicateSetting {
+    #[serde(deserialize_with = "nostr_relay::duration::deserialize")]
+    pub window: Duration,
+    /// shorter content (e.g. reactions) is not compared
+    pub min_length: usize,
+    /// score per earlier copy
+    pub weight: f64,
+}
+
+impl Default for DuplicateSetting {
+    fn default() -> Self {
+        Self {
+            window: Duration::from_secs(600),
+            min_length: 20,
+            weight: 4.0,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct NewPubkeySetting {
+    #[serde(deserialize_with = "nostr_relay::duration::deserialize")]
+    pub age: Duration,
+    /// pubkeys idle this long are forgotten and new again
+    #[serde(deserialize_with = "nostr_relay::duration::deserialize")]
+    pub remember: Duration,
+    pub weight: f64,
+}
+
+impl Default for NewPubkeySetting {
+    fn default() -> Self {
+        Self {
+            age: Duration::from_secs(600),
+            remember: Duration::from_secs(86400),
+            weight: 3.0,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct SpamSetting {
+    pub enabled: bool,
+    pub mode: Mode,
+    pub threshold: f64,
+    /// overrides of threshold and mode by kind
+    pub kinds: Vec<KindPolicy>,
+    /// kinds never scored
+    pub skip_kinds: Vec<u16>,
+    pub rate: WindowSetting,
+    pub burst: WindowSetting,
+    pub duplicate: DuplicateSetting,
+    pub new_pubkey: NewPubkeySetting,
+}
+
+/// Kind map of the MLS gateway section
+#[cfg(feature = "mls_gateway")]
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct GatewayKinds {
+    kinds: crate::mls_gateway::kinds::KindMap,
+}
+
+impl Default for SpamSetting {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            mode: Mode::Shadow,
+            threshold: 10.0,
+            kinds: vec![],
+            // MLS kinds and NIP-42 auth
+            skip_kinds: vec![443, 444, 445, 446, 447, 448, 449, 450, 1059, 10051, 22242],
+            rate: WindowSetting::default(),
+            burst: WindowSetting {
+                window: Duration::from_secs(2),
+                limit: 5,
+                weight: 3.0,
+            },
+            duplicate: DuplicateSetting::default(),
+            new_pubkey: NewPubkeySetting::default(),
+        }
+    }
+}
+
+impl SpamSetting {
+    /// Threshold and mode of a kind, `None` if it is not scored
+    fn policy(&self, kind: u16) -> Option<(f64, Mode)> {
+        if self.skip_kinds.contains(&kind) {
+            return None;
+        }
+        let policy = self.kinds.iter().find(|p| p.kinds.contains(&kind));
+        Some((
+            policy.and_then(|p| p.threshold).unwrap_or(self.threshold),
+            policy.and_then(|p| p.mode).unwrap_or(self.mode),
+        ))
+    }
+}
+
+/// Events per pubkey within a window
+pub struct WindowScorer {
+    name: &'static str,
+    setting: WindowSetting,
+    seen: Mutex<HashMap<String, VecDeque<Instant>>>,
+}
+
+impl WindowScorer {
+    pub fn new(name: &'static str, setting: WindowSetting) -> Self {
+        Self {
+            name,
+            setting,
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl Scorer for WindowScorer {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn score(&self, event: &Event, now: Instant) -> f64 {
+        let mut seen = self.seen.lock();
+        let times = seen.entry(event.pubkey_str()).or_default();
+        while times
+            .front()
+            .map_or(false, |t| now.duration_since(*t) >= self.setting.window)
+        {
+            times.pop_front();
+        }
+        times.push_back(now);
+        times.len().saturating_sub(self.setting.limit) as f64 * self.setting.weight
+    }
+
+    fn prune(&self, now: Instant) {
+        self.seen.lock().retain(|_, times| {
+            times
+                .back()
+                .map_or(false, |t| now.duration_since(*t) < self.setting.window)
+        });
+    }
+}
+
+/// Copies of the same content
+pub struct DuplicateScorer {
+    setting: DuplicateSetting,
+    seen: Mutex<HashMap<[u8; 32], (Instant, usize)>>,
+}
+
+impl DuplicateScorer {
+    pub fn new(setting: DuplicateSetting) -> Self {
+        Self {
+            setting,
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Hash of the content with case and whitespace folded
+    fn digest(content: &str) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        for word in content.split_whitespace() {
+            hasher.update(word.to_lowercase().as_bytes());
+            hasher.update(b" ");
+        }
+        hasher.finalize().into()
+    }
+}
+
+impl Scorer for DuplicateScorer {
+    fn name(&self) -> &'static str {
+        "duplicate"
+    }
+
+    fn score(&self, event: &Event, now: Instant) -> f64 {
+        if event.content().trim().chars().count() < self.setting.min_length {
+            return 0.0;
+        }
+        let mut seen = self.seen.lock();
+        let entry = seen
+            .entry(Self::digest(event.content()))
+            .or_insert((now, 0));
+        if now.duration_since(entry.0) >= self.setting.window {
+            *entry = (now, 0);
+        }
+        entry.1 += 1;
+        (entry.1 - 1) as f64 * self.setting.weight
+    }
+
+    fn prune(&self, now: Instant) {
+        self.seen
+            .lock()
+            .retain(|_, (first, _)| now.duration_since(*first) < self.setting.window);
+    }
+}
+
+/// Pubkeys first seen recently
+pub struct NewPubkeyScorer {
+    setting: NewPubkeySetting,
+    /// first and last seen
+    seen: Mutex<HashMap<String, (Instant, Instant)>>,
+}
+
+impl NewPubkeyScorer {
+    pub fn new(setting: NewPubkeySetting) -> Self {
+        Self {
+            setting,
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl Scorer for NewPubkeyScorer {
+    fn name(&self) -> &'static str {
+        "new_pubkey"
+    }
+
+    fn score(&self, event: &Event, now: Instant) -> f64 {
+        let mut seen = self.seen.lock();
+        let entry = seen.entry(event.pubkey_str()).or_insert((now, now));
+        entry.1 = now;
+        if now.duration_since(entry.0) < self.setting.age {
+            self.setting.weight
+        } else {
+            0.0
+        }
+    }
+
+    fn prune(&self, now: Instant) {
+        self.seen
+            .lock()
+            .retain(|_, (_, last)| now.duration_since(*last) < self.setting.remember);
+    }
+}
+
+pub struct Spam {
+    setting: SpamSetting,
+    scorers: Vec<Arc<dyn Scorer>>,
+    /// added by the embedder, kept across reloads
+    custom: Vec<Arc<dyn Scorer>>,
+    pruned: Mutex<Instant>,
+}
+
+impl Default for Spam {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Spam {
+    pub fn new() -> Self {
+        describe_counter!(
+            "nostr_relay_spam_flagged",
+            "The total count of events reaching the spam threshold by kind and mode"
+        );
+        describe_counter!(
+            "nostr_relay_spam_scores",
+            "The total count of non-zero spam scores by scorer"
+        );
+        Self {
+            setting: SpamSetting::default(),
+            scorers: vec![],
+            custom: vec![],
+            pruned: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Add a scorer after the built-in ones
+    pub fn with_scorer<S: Scorer + 'static>(mut self, scorer: S) -> Self {
+        let scorer: Arc<dyn Scorer> = Arc::new(scorer);
+        self.scorers.push(scorer.clone());
+        self.custom.push(scorer);
+        self
+    }
+
+    /// Total score of an event and the scores of the scorers that contributed
+    fn score(&self, event: &Event, now: Instant) -> (f64, Vec<(&'static str, f64)>) {
+        let parts = self
+            .scorers
+            .iter()
+            .map(|s| (s.name(), s.score(event, now)))
+            .filter(|(_, score)| *score > 0.0)
+            .collect::<Vec<_>>();
+        (parts.iter().map(|(_, score)| score).sum(), parts)
+    }
+
+    fn prune(&self, now: Instant) {
+        let mut pruned = self.pruned.lock();
+        if now.duration_since(*pruned) < PRUNE_INTERVAL {
+            return;
+        }
+        *pruned = now;
+        drop(pruned);
+        for scorer in &self.scorers {
+            scorer.prune(now);
+        }
+    }
+}
+
+impl Extension for Spam {
+    fn name(&self) -> &'static str {
+        "spam"
+    }
+
+    fn setting(&mut self, setting: &SettingWrapper) {
+        self.setting = setting.read().parse_extension(self.name());
+        // MLS kinds moved by the gateway kind map are skipped under their numbers
+        #[cfg(feature = "mls_gateway")]
+        if self.setting.skip_kinds == SpamSetting::default().skip_kinds {
+            let gateway: GatewayKinds = setting.read().parse_extension("mls_gateway");
+            for kind in self.setting.skip_kinds.iter_mut() {
+                *kind = gateway.kinds.number(*kind);
+            }
+        }
+        let s = &self.setting;
+        self.scorers = vec![
+            Arc::new(WindowScorer::new("rate", s.rate.clone())),
+            Arc::new(WindowScorer::new("burst", s.burst.clone())),
+            Arc::new(DuplicateScorer::new(s.duplicate.clone())),
+            Arc::new(NewPubkeyScorer::new(s.new_pubkey.clone())),
+        ];
+        self.scorers.extend(self.custom.iter().cloned());
+        if s.enabled {
+            info!(
+                "spam scoring in {} mode, threshold {}",
+                s.mode.as_str(),
+                s.threshold
+            );
+        }
+    }
+
+    fn message(
+        &self,
+        msg: ClientMessage,
+        _session: &mut Session,
+        _ctx: &mut <Session as actix::Actor>::Context,
+    ) -> ExtensionMessageResult {
+        if !self.setting.enabled {
+            return ExtensionMessageResult::Continue(msg);
+        }
+        let IncomingMessage::Event(event) = &msg.msg else {
+            return ExtensionMessageResult::Continue(msg);
+        };
+        let Some((threshold, mode)) = self.setting.policy(event.kind()) else {
+            return ExtensionMessageResult::Continue(msg);
+        };
+        let now = Instant::now();
+        self.prune(now);
+        let (score, parts) = self.score(event, now);
+        for (scorer, _) in &parts {
+            counter!("nostr_relay_spam_scores", "scorer" => *scorer).increment(1);
+        }
+        if score < threshold {
+            return ExtensionMessageResult::Continue(msg);
+        }
+        counter!("nostr_relay_spam_flagged", "kind" => event.kind().to_string(), "mode" => mode.as_str())
+            .increment(1);
+        info!(
+            "spam score {:.1} of event {} kind {} from {} ({}): {:?}",
+            score,
+            event.id_str(),
+            event.kind(),
+            event.pubkey_str(),
+            mode.as_str(),
+            parts
+        );
+        match mode {
+            Mode::Shadow => ExtensionMessageResult::Continue(msg),
+            Mode::Reject => {
+                OutgoingMessage::rejected(&event.id_str(), Prefix::Blocked, "event looks like spam")
+                    .into()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nostr_relay::db::secp256k1::{rand::thread_rng, Keypair, SECP256K1};
+
+    fn event(key: &Keypair, kind: u16, content: &str) -> Event {
+        Event::create(key, 0, kind, vec![], content.to_owned()).unwrap()
+    }
+
+    #[test]
+    fn scorers() {
+        let key = Keypair::new(SECP256K1, &mut thread_rng());
+        let other = Keypair::new(SECP256K1, &mut thread_rng());
+        let start = Instant::now();
+        let later = |secs| start + Duration::from_secs(secs);
+
+        let burst = WindowScorer::new(
+            "burst",
+            WindowSetting {
+                window: Duration::from_secs(2),
+                limit: 2,
+                weight: 3.0,
+            },
+        );
+        let note = event(&key, 1, "hi");
+        assert_eq!(burst.score(&note, start), 0.0);
+        assert_eq!(burst.score(&note, start), 0.0);
+        assert_eq!(burst.score(&note, later(1)), 3.0);
+        assert_eq!(burst.score(&note, later(3)), 0.0);
+        burst.prune(later(10));
+        assert!(burst.seen.lock().is_empty());
+
+        let duplicate = DuplicateScorer::new(DuplicateSetting::default());
+        let text = "Buy cheap followers at example.com now";
+        assert_eq!(duplicate.score(&event(&key, 1, text), start), 0.0);
+        assert_eq!(
+            duplicate.score(&event(&other, 1, &text.to_uppercase()), start),
+            4.0
+        );
+        assert_eq!(
+            duplicate.score(
+                &event(&other, 1, " buy  cheap followers at example.com now"),
+                start
+            ),
+            8.0
+        );
+        assert_eq!(duplicate.score(&event(&key, 1, text), later(600)), 0.0);
+        assert_eq!(duplicate.score(&event(&key, 1, "short"), start), 0.0);
+
+        let new_pubkey = NewPubkeyScorer::new(NewPubkeySetting::default());
+        assert_eq!(new_pubkey.score(&note, start), 3.0);
+        assert_eq!(new_pubkey.score(&note, later(600)), 0.0);
+        new_pubkey.prune(later(600 + 86400));
+        assert_eq!(new_pubkey.score(&note, later(600 + 86400)), 3.0);
+    }
+
+    #[test]
+    fn policy() {
+        let setting = SpamSetting {
+            kinds: vec![KindPolicy {
+                kinds: vec![1],
+                threshold: Some(5.0),
+                mode: Some(Mode::Reject),
+            }],
+            ..Default::default()
+        };
+        assert_eq!(setting.policy(1), Some((5.0, Mode::Reject)));
+        assert_eq!(setting.policy(7), Some((10.0, Mode::Shadow)));
+        assert_eq!(setting.policy(445), None);
+    }
+
+    struct Fixed;
+    impl Scorer for Fixed {
+        fn name(&self) -> &'static str {
+            "fixed"
+        }
+        fn score(&self, _event: &Event, _now: Instant) -> f64 {
+            20.0
+        }
+    }
+
+    #[test]
+    fn custom_scorer() {
+        let mut spam = Spam::new().with_scorer(Fixed);
+        let setting: SettingWrapper = nostr_relay::Setting::default().into();
+        spam.setting(&setting);
+        assert_eq!(spam.scorers.len(), 5);
+
+        let key = Keypair::new(SECP256K1, &mut thread_rng());
+        let (score, parts) = spam.score(&event(&key, 1, "hello"), Instant::now());
+        assert_eq!(score, 23.0);
+        assert_eq!(parts, vec![("new_pubkey", 3.0), ("fixed", 20.0)]);
+    }
+
+    #[cfg(feature = "mls_gateway")]
+    #[test]
+    fn skip_mapped_kinds() {
+        let mut spam = Spam::new();
+        let mut setting = nostr_relay::Setting::default();
+        setting.extra.insert(
+            "mls_gateway".to_owned(),
+            serde_json::json!({ "kinds": { "group_message": 1445 } }),
+        );
+        spam.setting(&setting.into());
+        assert_eq!(spam.setting.policy(1445), None);
+        assert_eq!(spam.setting.policy(443), None);
+        assert!(spam.setting.policy(445).is_some());
+    }
+}
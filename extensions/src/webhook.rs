@@ -0,0 +1,285 @@
+//! Webhook notifications
+//!
+//! POST a JSON notification for stored events of selected kinds to
+//! operator-defined urls, rejected and duplicate events are not notified.
+//! Notifications carry metadata only (event id, kind, group id and recipient
+//! pubkeys), never content. Each body is signed with HMAC-SHA256 over
+//! `{timestamp}.{body}` and failed deliveries are retried with exponential
+//! backoff. At most `queue_size` deliveries are in flight and as many wait in
+//! the queue, further notifications are dropped.
+
+use hmac::{Hmac, Mac};
+use metrics::{counter, describe_counter};
+use nostr_relay::db::{now, Event};
+use nostr_relay::{setting::SettingWrapper, Extension};
+use parking_lot::Mutex;
+use serde::Deserialize;
+use serde_json::json;
+use sha2::Sha256;
+use std::{sync::Arc, time::Duration};
+use tokio::sync::{mpsc, Semaphore};
+use tracing::{debug, info, warn};
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct WebhookEndpoint {
+    pub url: String,
+    /// HMAC-SHA256 key, the `X-Nostr-Signature` header is omitted when unset
+    pub secret: Option<String>,
+    /// kinds notified to this endpoint, empty notifies all
+    pub kinds: Vec<u16>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct WebhookSetting {
+    pub enabled: bool,
+    pub endpoints: Vec<WebhookEndpoint>,
+    /// notifications in flight, and as many buffered, while endpoints are slow
+    pub queue_size: usize,
+    /// delivery attempts after the first failure
+    pub max_retries: u32,
+    #[serde(deserialize_with = "nostr_relay::duration::deserialize")]
+    pub timeout: Duration,
+}
+
+impl Default for WebhookSetting {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            endpoints: vec![],
+            queue_size: 1000,
+            max_retries: 5,
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+struct Delivery {
+    endpoint: Arc<WebhookEndpoint>,
+    body: String,
+}
+
+#[derive(Default)]
+pub struct Webhook {
+    setting: WebhookSetting,
+    endpoints: Vec<Arc<WebhookEndpoint>>,
+    /// started lazily on the first notification, inside the runtime
+    tx: Mutex<Option<mpsc::Sender<Delivery>>>,
+}
+
+impl Webhook {
+    pub fn new() -> Self {
+        describe_counter!(
+            "nostr_relay_webhook_deliveries",
+            "The total count of webhook deliveries by result"
+        );
+        Self::default()
+    }
+
+    fn notify(&self, event: &Event) {
+        let endpoints = self
+            .endpoints
+            .iter()
+            .filter(|e| e.kinds.is_empty() || e.kinds.contains(&event.kind()))
+            .collect::<Vec<_>>();
+        if endpoints.is_empty() {
+            return;
+        }
+        let body = payload(event);
+        let mut tx = self.tx.lock();
+        let tx = tx.get_or_insert_with(|| {
+            let (tx, rx) = mpsc::channel(self.setting.queue_size.max(1));
+            tokio::spawn(run(
+                rx,
+                self.setting.queue_size.max(1),
+                self.setting.max_retries,
+                self.setting.timeout,
+            ));
+            tx
+        });
+        for endpoint in endpoints {
+            let delivery = Delivery {
+                endpoint: endpoint.clone(),
+                body: body.clone(),
+            };
+            if tx.try_send(delivery).is_err() {
+                counter!("nostr_relay_webhook_deliveries", "result" => "dropped").increment(1);
+            }
+        }
+    }
+}
+
+impl Extension for Webhook {
+    fn name(&self) -> &'static str {
+        "webhook"
+    }
+
+    fn setting(&mut self, setting: &SettingWrapper) {
+        let r = setting.read();
+        self.setting = r.parse_extension(self.name());
+        self.endpoints = self
+            .setting
+            .endpoints
+            .iter()
+            .cloned()
+            .map(Arc::new)
+            .collect();
+        // the worker exits once its channel is dropped
+        self.tx.lock().take();
+        if self.setting.enabled {
+            info!("webhook endpoints: {}", self.endpoints.len());
+        }
+    }
+
+    fn event_stored(&self, event: &Event) {
+        if self.setting.enabled {
+            self.notify(event);
+        }
+    }
+}
+
+/// Notification body, metadata only
+fn payload(event: &Event) -> String {
+    let tag = |name: &str| {
+        event
+            .tags()
+            .iter()
+            .filter(|t| t.len() >= 2 && t[0] == name)
+            .map(|t| t[1].clone())
+            .collect::<Vec<_>>()
+    };
+    json!({
+        "id": event.id_str(),
+        "kind": event.kind(),
+        "created_at": event.created_at(),
+        "group_id": tag("h").into_iter().next(),
+        "recipients": tag("p"),
+    })
+    .to_string()
+}
+
+/// Hex HMAC-SHA256 of `{timestamp}.{body}`
+fn sign(secret: &str, timestamp: u64, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+async fn deliver(client: &reqwest::Client, delivery: &Delivery) -> Result<(), String> {
+    let timestamp = now();
+    let mut req = client
+        .post(&delivery.endpoint.url)
+        .header("Content-Type", "application/json")
+        .header("X-Nostr-Timestamp", timestamp.to_string())
+        .body(delivery.body.clone());
+    if let Some(secret) = &delivery.endpoint.secret {
+        req = req.header(
+            "X-Nostr-Signature",
+            format!("sha256={}", sign(secret, timestamp, &delivery.body)),
+        );
+    }
+    match req.send().await {
+        Ok(res) if res.status().is_success() => Ok(()),
+        Ok(res) => Err(res.status().to_string()),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+/// Deliver notifications, retrying each in its own task so a failing endpoint does not block others.
+/// Once `in_flight` deliveries are pending the queue is no longer drained.
+async fn run(
+    mut rx: mpsc::Receiver<Delivery>,
+    in_flight: usize,
+    max_retries: u32,
+    timeout: Duration,
+) {
+    let client = match reqwest::Client::builder().timeout(timeout).build() {
+        Ok(client) => client,
+        Err(err) => {
+            warn!("webhook client: {}", err);
+            return;
+        }
+    };
+    let permits = Arc::new(Semaphore::new(in_flight));
+    loop {
+        let Ok(permit) = permits.clone().acquire_owned().await else {
+            return;
+        };
+        let Some(delivery) = rx.recv().await else {
+            return;
+        };
+        let client = client.clone();
+        tokio::spawn(async move {
+            // released once delivered or given up
+            let _permit = permit;
+            let mut backoff = Duration::from_secs(1);
+            for attempt in 0..=max_retries {
+                match deliver(&client, &delivery).await {
+                    Ok(()) => {
+                        counter!("nostr_relay_webhook_deliveries", "result" => "ok").increment(1);
+                        return;
+                    }
+                    Err(err) => {
+                        debug!(
+                            "webhook {} attempt {} failed: {}",
+                            delivery.endpoint.url,
+                            attempt + 1,
+                            err
+                        );
+                    }
+                }
+                if attempt < max_retries {
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(Duration::from_secs(300));
+                }
+            }
+            warn!(
+                "webhook {} gave up after {} attempts",
+                delivery.endpoint.url,
+                max_retries + 1
+            );
+            counter!("nostr_relay_webhook_deliveries", "result" => "failed").increment(1);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    #[test]
+    fn setting() -> Result<()> {
+        let setting: WebhookSetting = serde_json::from_str(
+            r#"{"enabled": true, "endpoints": [{"url": "https://example.com/hook", "secret": "s", "kinds": [445]}], "timeout": "5s"}"#,
+        )?;
+        assert_eq!(setting.endpoints[0].kinds, vec![445]);
+        assert_eq!(setting.timeout, Duration::from_secs(5));
+        assert_eq!(setting.max_retries, 5);
+        Ok(())
+    }
+
+    #[test]
+    fn metadata_only() -> Result<()> {
+        let event: Event = serde_json::from_str(
+            r#"{"content":"Good morning everyone 😃","created_at":1680690006,"id":"332747c0fab8a1a92def4b0937e177be6df4382ce6dd7724f86dc4710b7d4d7d","kind":1,"pubkey":"7abf57d516b1ff7308ca3bd5650ea6a4674d469c7c5057b1d005fb13d218bfef","sig":"ef4ff4f69ac387239eb1401fb07d7a44a5d5d57127e0dc3466a0403cf7d5486b668608ebfcbe9ff1f8d3b5d710545999fe08ee767284ec0b474e4cf92537678f","tags":[["t","nostr"]]}"#,
+        )?;
+        let body = payload(&event);
+        assert!(!body.contains("Good morning"));
+        assert!(body.contains(&event.id_str()));
+        Ok(())
+    }
+
+    #[test]
+    fn signature() {
+        let a = sign("secret", 1, "{}");
+        assert_eq!(a.len(), 64);
+        assert_eq!(a, sign("secret", 1, "{}"));
+        assert_ne!(a, sign("secret", 2, "{}"));
+        assert_ne!(a, sign("other", 1, "{}"));
+    }
+}
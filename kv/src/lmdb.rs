@@ -109,6 +109,54 @@ pub trait Transaction: Sized {
     fn iter(&self, tree: &Tree) -> Iter {
         self.iter_from(tree, Bound::Unbounded::<Vec<u8>>, false)
     }
+
+    /// Entries and pages of a tree
+    fn stat(&self, tree: &Tree) -> Result<TreeStat> {
+        let mut stat = MaybeUninit::uninit();
+        unsafe {
+            lmdb_result(ffi::mdb_stat(self.txn(), tree.inner, stat.as_mut_ptr()))?;
+            let stat: ffi::MDB_stat = stat.assume_init();
+            Ok(TreeStat {
+                entries: stat.ms_entries,
+                depth: stat.ms_depth,
+                branch_pages: stat.ms_branch_pages,
+                leaf_pages: stat.ms_leaf_pages,
+                overflow_pages: stat.ms_overflow_pages,
+            })
+        }
+    }
+
+    /// Pages freed by earlier transactions, reused before the map grows
+    fn free_pages(&self) -> Result<usize> {
+        // the freelist is dbi 0, each value is a page list led by its length
+        let mut inner = IterInner::new(self, 0)?;
+        let mut pages = 0;
+        let mut op = ffi::MDB_FIRST;
+        while let Some((_, data)) = inner.get(op)? {
+            if let Some(len) = data.get(..mem::size_of::<usize>()) {
+                pages += usize::from_ne_bytes(len.try_into().unwrap_or_default());
+            }
+            op = ffi::MDB_NEXT;
+        }
+        Ok(pages)
+    }
+}
+
+/// Entries and pages of a tree
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TreeStat {
+    pub entries: usize,
+    pub depth: u32,
+    pub branch_pages: usize,
+    pub leaf_pages: usize,
+    pub overflow_pages: usize,
+}
+
+impl TreeStat {
+    /// All pages of the tree
+    pub fn pages(&self) -> usize {
+        self.branch_pages + self.leaf_pages + self.overflow_pages
+    }
 }
 
 pub struct Reader<'env> {
@@ -355,6 +403,19 @@ pub struct Db {
     inner: Arc<DbInner>,
 }
 
+/// Map and reader usage of an environment
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EnvInfo {
+    /// Size of the memory map, the environment cannot grow beyond it
+    pub map_size: usize,
+    /// Bytes of the map up to the last used page
+    pub used_size: usize,
+    pub page_size: u32,
+    pub max_readers: u32,
+    /// Reader slots in use
+    pub num_readers: u32,
+}
+
 unsafe impl Send for DbInner {}
 unsafe impl Sync for DbInner {}
 
@@ -391,12 +452,47 @@ impl Db {
         Reader::new(&self.inner)
     }
 
+    pub fn info(&self) -> Result<EnvInfo> {
+        let mut info = MaybeUninit::uninit();
+        let mut stat = MaybeUninit::uninit();
+        unsafe {
+            lmdb_result(ffi::mdb_env_info(self.inner.inner, info.as_mut_ptr()))?;
+            lmdb_result(ffi::mdb_env_stat(self.inner.inner, stat.as_mut_ptr()))?;
+            let info: ffi::MDB_envinfo = info.assume_init();
+            let stat: ffi::MDB_stat = stat.assume_init();
+            Ok(EnvInfo {
+                map_size: info.me_mapsize,
+                used_size: (info.me_last_pgno + 1) * stat.ms_psize as usize,
+                page_size: stat.ms_psize,
+                max_readers: info.me_maxreaders,
+                num_readers: info.me_numreaders,
+            })
+        }
+    }
+
     pub fn flush(&self) -> Result<()> {
         unsafe {
             lmdb_result(ffi::mdb_env_sync(self.inner.inner, 1))?;
         }
         Ok(())
     }
+
+    /// Consistent copy of the environment into the directory `path`, writers are not blocked.
+    /// `compact` leaves out free pages and renumbers the rest.
+    pub fn copy_to<P: AsRef<Path>>(&self, path: P, compact: bool) -> Result<()> {
+        let path = path.as_ref();
+        if let Err(e) = fs::create_dir_all(path) {
+            return Err(Error::Message(format!(
+                "Failed to create LMDB copy directory: `{e:?}`."
+            )));
+        }
+        let c_path = to_cpath(path)?;
+        let flags = if compact { ffi::MDB_CP_COMPACT } else { 0 };
+        unsafe {
+            lmdb_result(ffi::mdb_env_copy2(self.inner.inner, c_path.as_ptr(), flags))?;
+        }
+        Ok(())
+    }
 }
 
 pub struct Iter<'txn> {
@@ -376,3 +376,108 @@ pub fn test_dup() -> Result<()> {
     }
     Ok(())
 }
+
+#[test]
+pub fn test_info() -> Result<()> {
+    let dir = tempfile::Builder::new()
+        .prefix("nokv-test-lmdb-info")
+        .tempdir()
+        .unwrap();
+    let db = Db::open_with(
+        dir.path(),
+        Some(4),
+        Some(16),
+        Some(10 * 1024 * 1024),
+        ffi::MDB_NOSYNC,
+    )?;
+    let t1 = db.open_tree(Some("t1"), 0)?;
+    let before = db.info()?;
+    assert_eq!(before.map_size, 10 * 1024 * 1024);
+    assert_eq!(before.max_readers, 16);
+
+    let mut writer = db.writer()?;
+    for i in 0u32..1000 {
+        writer.put(&t1, i.to_be_bytes(), [0u8; 100])?;
+    }
+    writer.commit()?;
+    let after = db.info()?;
+    assert!(after.used_size > before.used_size);
+    assert_eq!(after.used_size % after.page_size as usize, 0);
+    {
+        let _reader = db.reader()?;
+        assert!(db.info()?.num_readers >= 1);
+    }
+    Ok(())
+}
+
+#[test]
+pub fn test_stat() -> Result<()> {
+    let dir = tempfile::Builder::new()
+        .prefix("nokv-test-lmdb-stat")
+        .tempdir()
+        .unwrap();
+    let db = Db::open_with(
+        dir.path(),
+        Some(4),
+        Some(16),
+        Some(10 * 1024 * 1024),
+        ffi::MDB_NOSYNC,
+    )?;
+    let t1 = db.open_tree(Some("t1"), 0)?;
+    for round in 0..3u32 {
+        let mut writer = db.writer()?;
+        for i in 0u32..1000 {
+            writer.put(&t1, i.to_be_bytes(), round.to_be_bytes().repeat(25))?;
+        }
+        writer.commit()?;
+    }
+    let reader = db.reader()?;
+    let stat = reader.stat(&t1)?;
+    assert_eq!(stat.entries, 1000);
+    assert!(stat.leaf_pages > 0);
+    assert_eq!(
+        stat.pages(),
+        stat.branch_pages + stat.leaf_pages + stat.overflow_pages
+    );
+    // rewritten pages of earlier rounds
+    assert!(reader.free_pages()? > 0);
+    Ok(())
+}
+
+#[test]
+pub fn test_copy() -> Result<()> {
+    let dir = tempfile::Builder::new()
+        .prefix("nokv-test-lmdb-copy")
+        .tempdir()
+        .unwrap();
+    let db = Db::open_with(
+        dir.path().join("db"),
+        Some(4),
+        Some(16),
+        Some(10 * 1024 * 1024),
+        0,
+    )?;
+    let t1 = db.open_tree(Some("t1"), 0)?;
+    let mut writer = db.writer()?;
+    for i in 0u32..100 {
+        writer.put(&t1, i.to_be_bytes(), i.to_be_bytes())?;
+    }
+    writer.commit()?;
+    db.copy_to(dir.path().join("copy"), true)?;
+
+    let copy = Db::open_with(
+        dir.path().join("copy"),
+        Some(4),
+        Some(16),
+        Some(10 * 1024 * 1024),
+        0,
+    )?;
+    let t1 = copy.open_tree(Some("t1"), 0)?;
+    let reader = copy.reader()?;
+    assert_eq!(reader.iter(&t1).count(), 100);
+    assert_eq!(
+        reader.get(&t1, 7u32.to_be_bytes())?,
+        Some(&7u32.to_be_bytes()[..])
+    );
+    Ok(())
+}
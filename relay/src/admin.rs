@@ -0,0 +1,100 @@
+//! Admin commands over the websocket
+//!
+//! Sessions authenticated with NIP-42 as one of the `[admin] pubkeys` can
+//! inspect and manage the running instance without a redeploy:
+//! - `["ADMIN", "stats"]`: connections, sessions, authenticated sessions and subscriptions
+//! - `["ADMIN", "sessions"]`: id, NIP-42 pubkey and subscriptions of every session
+//! - `["ADMIN", "kick", <session id>, <reason>?]`: close a session with a NOTICE
+//! - `["ADMIN", "broadcast-notice", <message>]`: NOTICE every session
+//! - `["ADMIN", "reload-config"]`: reload the config file and the extension settings
+//!
+//! The relay answers `["ADMIN", <verb>, true, <result>]`, or
+//! `["ADMIN", <verb>, false, <reason>]` with a NIP-01 prefix.
+
+use actix::Message;
+use serde_json::Value;
+
+/// Admin command, the server runs all but `ReloadConfig`
+#[derive(Message, Clone, Debug, PartialEq, Eq)]
+#[rtype(result = "Result<Value, String>")]
+pub enum AdminCommand {
+    Stats,
+    Sessions,
+    Kick { id: usize, reason: String },
+    BroadcastNotice(String),
+    ReloadConfig,
+}
+
+impl AdminCommand {
+    /// Parse the arguments of an `ADMIN` message
+    pub fn parse(args: &[Value]) -> Result<Self, String> {
+        let verb = args.first().and_then(Value::as_str).unwrap_or_default();
+        match verb {
+            "stats" => Ok(Self::Stats),
+            "sessions" => Ok(Self::Sessions),
+            "kick" => {
+                let id = args
+                    .get(1)
+                    .and_then(|v| v.as_u64().or_else(|| v.as_str()?.parse().ok()))
+                    .ok_or("kick needs a session id")?;
+                let reason = args
+                    .get(2)
+                    .and_then(Value::as_str)
+                    .unwrap_or("disconnected by the relay operator");
+                Ok(Self::Kick {
+                    id: id as usize,
+                    reason: reason.to_owned(),
+                })
+            }
+            "broadcast-notice" => match args.get(1).and_then(Value::as_str) {
+                Some(message) if !message.is_empty() => {
+                    Ok(Self::BroadcastNotice(message.to_owned()))
+                }
+                _ => Err("broadcast-notice needs a message".to_owned()),
+            },
+            "reload-config" => Ok(Self::ReloadConfig),
+            _ => Err(format!("unknown admin command '{}'", verb)),
+        }
+    }
+
+    pub fn verb(&self) -> &'static str {
+        match self {
+            Self::Stats => "stats",
+            Self::Sessions => "sessions",
+            Self::Kick { .. } => "kick",
+            Self::BroadcastNotice(_) => "broadcast-notice",
+            Self::ReloadConfig => "reload-config",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn parse() {
+        let parse = |v: Value| AdminCommand::parse(v.as_array().unwrap());
+        assert_eq!(parse(json!(["stats"])), Ok(AdminCommand::Stats));
+        assert_eq!(
+            parse(json!(["kick", "12", "spam"])),
+            Ok(AdminCommand::Kick {
+                id: 12,
+                reason: "spam".to_owned()
+            })
+        );
+        assert!(matches!(
+            parse(json!(["kick", 3])),
+            Ok(AdminCommand::Kick { id: 3, .. })
+        ));
+        assert!(parse(json!(["kick"])).is_err());
+        assert!(parse(json!(["broadcast-notice", ""])).is_err());
+        assert_eq!(
+            parse(json!(["reload-config"])).unwrap().verb(),
+            "reload-config"
+        );
+        assert!(parse(json!(["shutdown"])).is_err());
+        assert!(parse(json!([])).is_err());
+    }
+}
@@ -1,43 +1,59 @@
-use crate::{setting::SettingWrapper, Extension, Extensions, Result, Server, Setting};
-use actix::Addr;
+use crate::{
+    message::Shutdown, setting::SettingWrapper, Connections, Error, Extension, Extensions, Result,
+    Server, Setting, Verifier,
+};
+use actix::{Addr, SyncArbiter};
 use actix_cors::Cors;
 use actix_web::{
     body::MessageBody,
-    dev::{ServiceFactory, ServiceRequest},
+    dev::{ServerHandle, ServiceFactory, ServiceRequest},
     web, App as WebApp, HttpServer,
 };
 use nostr_db::Db;
 use parking_lot::RwLock;
-use std::{path::Path, sync::Arc};
-use tracing::info;
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tracing::{info, warn};
 
 pub mod route {
-    use crate::{App, Session};
+    use crate::{deflate::Deflate, App, ConnectionResult, Session};
     use actix_web::http::header::{ACCEPT, LOCATION, UPGRADE};
     use actix_web::{web, Error, HttpRequest, HttpResponse};
     use actix_web_actors::ws;
+    use metrics::counter;
 
-    fn get_ip(req: &HttpRequest, header: Option<&String>) -> Option<String> {
+    /// Client ip of the request. Behind proxies `header` is read, each of the
+    /// `trusted_hops` proxies appends the address it saw to a list header, so
+    /// the client is that many entries from the right. Entries further left
+    /// are written by the client and are not trusted. Without a usable header
+    /// the peer address is the client.
+    pub(crate) fn get_ip(
+        req: &HttpRequest,
+        header: Option<&String>,
+        trusted_hops: usize,
+    ) -> Option<String> {
         if let Some(header) = header {
-            // find from header list
-            // header.iter().find_map(|s| {
-            //     let hdr = req.headers().get(s)?.to_str().ok()?;
-            //     let val = hdr.split(',').next()?.trim();
-            //     Some(val.to_string())
-            // })
-            Some(
-                req.headers()
-                    .get(header)?
-                    .to_str()
-                    .ok()?
-                    .split(',')
-                    .next()?
-                    .trim()
-                    .to_string(),
-            )
-        } else {
-            Some(req.peer_addr()?.ip().to_string())
+            let hops = req
+                .headers()
+                .get(header)
+                .and_then(|value| value.to_str().ok())
+                .map(|value| {
+                    value
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|s| !s.is_empty())
+                        .collect::<Vec<_>>()
+                })
+                .unwrap_or_default();
+            let index = hops.len().saturating_sub(trusted_hops.max(1));
+            if let Some(ip) = hops.get(index) {
+                return Some(ip.to_string());
+            }
         }
+        Some(req.peer_addr()?.ip().to_string())
     }
 
     pub async fn websocket(
@@ -46,17 +62,73 @@ pub mod route {
         data: web::Data<App>,
     ) -> Result<HttpResponse, Error> {
         let r = data.setting.read();
-        let ip = get_ip(&req, r.network.real_ip_header.as_ref());
+        let Some(ip) = get_ip(
+            &req,
+            r.network.real_ip_header.as_ref(),
+            r.network.real_ip_trusted_hops,
+        ) else {
+            // connection limits are per ip, clients without one would share a slot
+            return Ok(HttpResponse::BadRequest().body("unknown client address"));
+        };
         let max_size = r.limitation.max_message_length;
+        let max_connections = r.network.max_connections;
+        let max_connections_per_ip = r.network.max_connections_per_ip;
+        let compression = r
+            .network
+            .compression
+            .then(|| Deflate::negotiate(&req, r.network.compression_context_takeover))
+            .flatten();
+        let compression_threshold = r.network.compression_threshold;
         drop(r);
 
-        let session = Session::new(ip.unwrap_or_default(), data);
+        // take the slot first, a tarpitted request holds it while it waits so
+        // the connection limits also bound the number of held requests
+        let connection =
+            match data
+                .connections
+                .acquire(&ip, max_connections, max_connections_per_ip)
+            {
+                Ok(connection) => connection,
+                Err(limit) => {
+                    counter!("nostr_relay_connection_rejected", "reason" => limit).increment(1);
+                    return Ok(if limit == "ip" {
+                        HttpResponse::TooManyRequests().body("too many connections from this ip")
+                    } else {
+                        HttpResponse::ServiceUnavailable().body("too many connections")
+                    });
+                }
+            };
+
+        let refused = data.extensions.read().call_connection(&ip, &req);
+        match refused {
+            ConnectionResult::Accept => {}
+            ConnectionResult::Reject => {
+                return Ok(HttpResponse::Forbidden().body("connection refused"));
+            }
+            ConnectionResult::Tarpit(delay) => {
+                actix_rt::time::sleep(delay).await;
+                drop(connection);
+                return Ok(HttpResponse::Forbidden().body("connection refused"));
+            }
+        }
+
+        let mut session = Session::new(ip, data);
+        session.connection = Some(connection);
 
         // ws::start(session, &req, stream)
         // The default max frame size is 60k, change from setting.
-        ws::WsResponseBuilder::new(session, &req, stream)
-            .frame_size(max_size)
-            .start()
+        match compression {
+            Some(deflate) => {
+                let stream = crate::deflate::Inflate::new(stream, max_size);
+                let res = ws::WsResponseBuilder::new(session, &req, stream)
+                    .frame_size(max_size)
+                    .start()?;
+                Ok(deflate.wrap(res, compression_threshold))
+            }
+            None => ws::WsResponseBuilder::new(session, &req, stream)
+                .frame_size(max_size)
+                .start(),
+        }
     }
 
     pub async fn information(
@@ -70,6 +142,56 @@ pub mod route {
             .body(r.render_information()?))
     }
 
+    /// Liveness, the process serves http
+    pub async fn healthz() -> HttpResponse {
+        HttpResponse::Ok().body("ok")
+    }
+
+    /// Readiness of the database and the extension dependencies
+    pub async fn readyz(data: web::Data<App>) -> HttpResponse {
+        let r = data.setting.read();
+        let strict = r.health.strict;
+        let timeout = *r.health.timeout;
+        drop(r);
+
+        let mut checks = serde_json::Map::new();
+        let mut ready = match data.db.reader() {
+            Ok(_) => {
+                checks.insert("lmdb".to_owned(), "ok".into());
+                true
+            }
+            Err(e) => {
+                checks.insert("lmdb".to_owned(), e.to_string().into());
+                false
+            }
+        };
+        let futures = data.extensions.read().call_ready();
+        for (name, fut) in futures {
+            let res = match actix_rt::time::timeout(timeout, fut).await {
+                Ok(res) => res,
+                Err(_) => Err(format!("timed out after {:?}", timeout)),
+            };
+            match res {
+                Ok(()) => {
+                    checks.insert(name.to_owned(), "ok".into());
+                }
+                Err(e) => {
+                    counter!("nostr_relay_ready_failed", "check" => name).increment(1);
+                    checks.insert(name.to_owned(), e.into());
+                    if strict {
+                        ready = false;
+                    }
+                }
+            }
+        }
+        let body = serde_json::json!({ "ready": ready, "checks": checks });
+        if ready {
+            HttpResponse::Ok().json(body)
+        } else {
+            HttpResponse::ServiceUnavailable().json(body)
+        }
+    }
+
     pub async fn index(
         req: HttpRequest,
         stream: web::Payload,
@@ -98,11 +220,17 @@ pub mod route {
 }
 
 /// App with data
+#[derive(Clone)]
 pub struct App {
     pub server: Addr<Server>,
     pub db: Arc<Db>,
     pub setting: SettingWrapper,
     pub extensions: Arc<RwLock<Extensions>>,
+    pub connections: Arc<Connections>,
+    /// signature verification workers, None verifies in the session
+    pub verifier: Option<Addr<Verifier>>,
+    /// config file and ENV prefix the setting was read from, for reloads
+    setting_source: Option<(PathBuf, Option<String>)>,
 }
 
 impl App {
@@ -114,6 +242,9 @@ impl App {
         data_path: Option<P>,
     ) -> Result<Self> {
         let extensions = Arc::new(RwLock::new(Extensions::default()));
+        let setting_source = setting_path
+            .as_ref()
+            .map(|p| (p.as_ref().to_path_buf(), setting_env_prefix.clone()));
         let c_extensions = Arc::clone(&extensions);
         let env_notice = setting_env_prefix
             .as_ref()
@@ -144,7 +275,10 @@ impl App {
         };
 
         {
-            info!("{:?}", setting.read());
+            info!(
+                "{}",
+                crate::secret::redact(&format!("{:?}", setting.read()))
+            );
         }
 
         let r = setting.read();
@@ -152,26 +286,50 @@ impl App {
             .map(|p| p.as_ref().to_path_buf())
             .unwrap_or_else(|| r.data.path.clone())
             .join("events");
+        let options = r.data.db_options();
         drop(r);
-        let db = Arc::new(Db::open(path)?);
+        let db = Arc::new(Db::open_with(path, &options)?);
         db.check_schema()?;
 
         let server = Server::create_with(db.clone(), setting.clone());
+        server.do_send(crate::message::SetExtensions(Arc::clone(&extensions)));
+
+        let verifiers = setting.read().thread.verifier;
+        let verifier = (verifiers > 0).then(|| {
+            info!("starting {} signature verification workers", verifiers);
+            SyncArbiter::start(verifiers, || Verifier)
+        });
 
         Ok(Self {
             server,
             setting,
             db,
             extensions,
+            connections: Default::default(),
+            verifier,
+            setting_source,
         })
     }
 
+    /// Reload the config file and apply it to the extensions, like a watched config change
+    pub fn reload_setting(&self) -> Result<()> {
+        let (path, env_prefix) = self
+            .setting_source
+            .as_ref()
+            .ok_or(Error::Str("relay was started without a config file"))?;
+        self.setting.reload(path, env_prefix.clone())?;
+        info!("Reload config success {:?}", path);
+        self.extensions.write().call_setting(&self.setting);
+        Ok(())
+    }
+
     pub fn add_extension<E: Extension + 'static>(self, mut ext: E) -> Self {
         info!("Add extension {}", ext.name());
         ext.setting(&self.setting);
         {
             let mut w = self.extensions.write();
             w.add(ext);
+            w.arrange(&self.setting);
         }
         self
     }
@@ -199,16 +357,91 @@ impl App {
         };
         let host = r.network.host.clone();
         let port = r.network.port;
+        let timeout = *r.network.shutdown_timeout;
+        let handshake_timeout = *r.network.handshake_timeout;
         drop(r);
         info!("Start http server {}:{}", host, port);
+        let server = self.server.clone();
+        let extensions = Arc::clone(&self.extensions);
         let data = web::Data::new(self);
-        Ok(HttpServer::new(move || create_web_app(data.clone()))
+        let http = HttpServer::new(move || create_web_app(data.clone()))
             .workers(num)
+            .client_request_timeout(handshake_timeout)
+            .disable_signals()
             .bind((host, port))?
-            .run())
+            .run();
+        let handle = http.handle();
+        actix_rt::spawn(async move {
+            shutdown_signal().await;
+            shutdown(handle, server, extensions, timeout).await;
+        });
+        Ok(http)
     }
 }
 
+/// SIGTERM or SIGINT
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use actix_rt::signal::unix::{signal, SignalKind};
+        use std::task::Poll;
+        match (
+            signal(SignalKind::terminate()),
+            signal(SignalKind::interrupt()),
+        ) {
+            (Ok(mut term), Ok(mut int)) => {
+                std::future::poll_fn(|cx| {
+                    if term.poll_recv(cx).is_ready() || int.poll_recv(cx).is_ready() {
+                        Poll::Ready(())
+                    } else {
+                        Poll::Pending
+                    }
+                })
+                .await;
+                return;
+            }
+            (Err(err), _) | (_, Err(err)) => {
+                warn!(
+                    "Failed to listen for SIGTERM, only ctrl-c shuts down gracefully: {}",
+                    err
+                )
+            }
+        }
+    }
+    if let Err(err) = actix_rt::signal::ctrl_c().await {
+        warn!("Failed to listen for ctrl-c: {}", err);
+        std::future::pending::<()>().await;
+    }
+}
+
+/// Stop accepting connections, drain the sessions and extensions, then stop the http server
+async fn shutdown(
+    handle: ServerHandle,
+    server: Addr<Server>,
+    extensions: Arc<RwLock<Extensions>>,
+    timeout: Duration,
+) {
+    info!("Shutting down, draining for up to {:?}", timeout);
+    handle.pause().await;
+    let drain = async {
+        let msg = Shutdown {
+            message: "relay is shutting down".to_owned(),
+        };
+        if let Err(err) = server.send(msg).await {
+            warn!("Failed to shut down sessions: {}", err);
+        }
+        let futures = extensions.read().call_shutdown();
+        for fut in futures {
+            fut.await;
+        }
+    };
+    if actix_rt::time::timeout(timeout, drain).await.is_err() {
+        warn!("Shutdown timed out after {:?}", timeout);
+    }
+    // sessions were told and extensions drained, remaining connections are dropped
+    handle.stop(false).await;
+}
+
 pub fn create_web_app(
     data: web::Data<App>,
 ) -> WebApp<
@@ -227,6 +460,8 @@ pub fn create_web_app(
             extensions.write().call_config_web(cfg);
         })
         .service(web::resource("/").route(web::get().to(route::index)))
+        .service(web::resource("/healthz").route(web::get().to(route::healthz)))
+        .service(web::resource("/readyz").route(web::get().to(route::readyz)))
         .wrap(
             Cors::default()
                 .send_wildcard()
@@ -276,6 +511,79 @@ pub mod tests {
         Ok(())
     }
 
+    /// Fails readiness with a message
+    struct Down;
+    impl crate::Extension for Down {
+        fn name(&self) -> &'static str {
+            "down"
+        }
+
+        fn ready(&self) -> Option<crate::ReadyFuture> {
+            Some(Box::pin(async { Err("storage unreachable".to_owned()) }))
+        }
+    }
+
+    #[actix_rt::test]
+    async fn probes() -> Result<()> {
+        let data = create_test_app("probes")?;
+        let setting = data.setting.clone();
+        let data = data.add_extension(Down);
+        let app = init_service(data.web_app()).await;
+
+        let res = app
+            .call(TestRequest::with_uri("/healthz").to_request())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 200);
+
+        let res = app
+            .call(TestRequest::with_uri("/readyz").to_request())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 503);
+        let body: serde_json::Value = serde_json::from_slice(&read_body(res).await)?;
+        assert_eq!(body["checks"]["lmdb"], "ok");
+        assert_eq!(body["checks"]["down"], "storage unreachable");
+
+        setting.write().health.strict = false;
+        let res = app
+            .call(TestRequest::with_uri("/readyz").to_request())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 200);
+        Ok(())
+    }
+
+    #[test]
+    fn real_ip() {
+        let header = Some("x-forwarded-for".to_owned());
+        let req = TestRequest::default()
+            .insert_header(("x-forwarded-for", "1.1.1.1, 2.2.2.2, 3.3.3.3"))
+            .to_http_request();
+        let ip = |hops| super::route::get_ip(&req, header.as_ref(), hops);
+        assert_eq!(ip(1).as_deref(), Some("3.3.3.3"));
+        assert_eq!(ip(2).as_deref(), Some("2.2.2.2"));
+        assert_eq!(ip(5).as_deref(), Some("1.1.1.1"));
+
+        let req = TestRequest::default().to_http_request();
+        assert_eq!(super::route::get_ip(&req, header.as_ref(), 1), None);
+        // a missing or unreadable header falls back to the peer address
+        let peer = "9.9.9.9:4000".parse().unwrap();
+        let req = TestRequest::default().peer_addr(peer).to_http_request();
+        assert_eq!(
+            super::route::get_ip(&req, header.as_ref(), 1).as_deref(),
+            Some("9.9.9.9")
+        );
+        let req = TestRequest::default()
+            .insert_header(("x-forwarded-for", " , "))
+            .peer_addr(peer)
+            .to_http_request();
+        assert_eq!(
+            super::route::get_ip(&req, header.as_ref(), 1).as_deref(),
+            Some("9.9.9.9")
+        );
+    }
+
     #[actix_rt::test]
     async fn connect_ws() -> Result<()> {
         let mut srv = actix_test::start(|| {
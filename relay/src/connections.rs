@@ -0,0 +1,86 @@
+use parking_lot::Mutex;
+use std::{collections::HashMap, sync::Arc};
+
+/// Open websocket connections, in total and per ip
+#[derive(Debug, Default)]
+pub struct Connections {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    total: usize,
+    ips: HashMap<String, usize>,
+}
+
+/// Slot of an open connection, released when dropped
+#[derive(Debug)]
+pub struct Connection {
+    connections: Arc<Connections>,
+    ip: String,
+}
+
+impl Connections {
+    /// Take a slot for a connection from `ip`, a limit of 0 is unlimited.
+    /// Returns the exceeded limit, `global` or `ip`.
+    pub fn acquire(
+        self: &Arc<Self>,
+        ip: &str,
+        max: usize,
+        max_per_ip: usize,
+    ) -> Result<Connection, &'static str> {
+        let mut inner = self.inner.lock();
+        if max > 0 && inner.total >= max {
+            return Err("global");
+        }
+        let count = inner.ips.entry(ip.to_owned()).or_default();
+        if max_per_ip > 0 && *count >= max_per_ip {
+            return Err("ip");
+        }
+        *count += 1;
+        inner.total += 1;
+        Ok(Connection {
+            connections: Arc::clone(self),
+            ip: ip.to_owned(),
+        })
+    }
+
+    /// Number of open connections
+    pub fn total(&self) -> usize {
+        self.inner.lock().total
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let mut inner = self.connections.inner.lock();
+        inner.total -= 1;
+        if let Some(count) = inner.ips.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                inner.ips.remove(&self.ip);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits() {
+        let connections = Arc::new(Connections::default());
+        let a1 = connections.acquire("a", 3, 2).unwrap();
+        let _a2 = connections.acquire("a", 3, 2).unwrap();
+        assert_eq!(connections.acquire("a", 3, 2).unwrap_err(), "ip");
+        let _b1 = connections.acquire("b", 3, 2).unwrap();
+        assert_eq!(connections.acquire("c", 3, 2).unwrap_err(), "global");
+        assert_eq!(connections.total(), 3);
+
+        drop(a1);
+        assert_eq!(connections.total(), 2);
+        assert!(connections.acquire("a", 3, 2).is_ok());
+        assert!(connections.acquire("d", 0, 0).is_ok());
+    }
+}
@@ -0,0 +1,547 @@
+//! permessage-deflate (RFC 7692) for websocket sessions
+//!
+//! The actix websocket codec knows no extensions, so compression is applied to
+//! the raw frames around it: compressed client messages (RSV1) are inflated
+//! into plain frames before the codec parses them, and outgoing text and binary
+//! messages of at least `compression_threshold` bytes are deflated on their way
+//! out. Clients may keep their compression context across messages, so the
+//! inflater lives as long as the session; the server compressor only does with
+//! `compression_context_takeover`, otherwise it exists while a message is
+//! compressed. Inflated messages are bounded by `max_message_length`.
+
+use actix_http::ws::ProtocolError;
+use actix_web::{
+    body::{BodySize, BoxBody, MessageBody},
+    error::PayloadError,
+    http::header::{HeaderValue, SEC_WEBSOCKET_EXTENSIONS},
+    HttpRequest, HttpResponse,
+};
+use bytes::{Buf, Bytes, BytesMut};
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress};
+use futures_core::Stream;
+use std::{
+    error::Error as StdError,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+/// Tail of a sync flush, stripped by the sender and appended by the receiver
+const SYNC_TAIL: [u8; 4] = [0, 0, 0xff, 0xff];
+
+const OP_CONTINUATION: u8 = 0;
+const OP_CLOSE: u8 = 8;
+
+/// Parameters agreed with a client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Deflate {
+    /// keep the server compressor between messages
+    pub context_takeover: bool,
+}
+
+impl Deflate {
+    /// Accept the first permessage-deflate offer of the request that can be served
+    pub fn negotiate(req: &HttpRequest, context_takeover: bool) -> Option<Self> {
+        req.headers()
+            .get_all(SEC_WEBSOCKET_EXTENSIONS)
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .find_map(|offer| accept(offer, context_takeover))
+    }
+
+    /// `Sec-WebSocket-Extensions` of the handshake response
+    pub fn response_header(&self) -> &'static str {
+        if self.context_takeover {
+            "permessage-deflate"
+        } else {
+            "permessage-deflate; server_no_context_takeover"
+        }
+    }
+
+    /// Compress the outgoing frames of a websocket handshake response
+    pub fn wrap(self, res: HttpResponse, threshold: usize) -> HttpResponse {
+        res.map_body(|head, body| {
+            head.headers_mut().insert(
+                SEC_WEBSOCKET_EXTENSIONS,
+                HeaderValue::from_static(self.response_header()),
+            );
+            BoxBody::new(DeflateBody {
+                inner: body,
+                buf: BytesMut::new(),
+                compressor: None,
+                context_takeover: self.context_takeover,
+                threshold,
+                fragmented: false,
+            })
+        })
+    }
+}
+
+/// Parameters of one offer, `None` if it is not permessage-deflate or asks for what is not supported
+fn accept(offer: &str, context_takeover: bool) -> Option<Deflate> {
+    let mut params = offer.split(';').map(str::trim);
+    if params.next()? != "permessage-deflate" {
+        return None;
+    }
+    let mut deflate = Deflate { context_takeover };
+    for param in params {
+        let (name, value) = param
+            .split_once('=')
+            .map_or((param, None), |(name, value)| {
+                (name.trim(), Some(value.trim().trim_matches('"')))
+            });
+        match name {
+            "server_no_context_takeover" => deflate.context_takeover = false,
+            // the client context is the client's business, the inflater keeps a full window
+            "client_no_context_takeover" | "client_max_window_bits" => {}
+            // the compressor always uses a 32K window
+            "server_max_window_bits" if value == Some("15") => {}
+            _ => return None,
+        }
+    }
+    Some(deflate)
+}
+
+/// A parsed frame, the payload is unmasked
+struct Frame {
+    fin: bool,
+    rsv1: bool,
+    opcode: u8,
+    payload: Bytes,
+}
+
+/// Take the next complete frame of `buf`, frames longer than `max_size` are an error
+fn parse(buf: &mut BytesMut, max_size: usize) -> Result<Option<Frame>, ProtocolError> {
+    if buf.len() < 2 {
+        return Ok(None);
+    }
+    let (first, second) = (buf[0], buf[1]);
+    let masked = second & 0x80 != 0;
+    let (len, mut offset) = match second & 0x7f {
+        126 if buf.len() >= 4 => (u16::from_be_bytes([buf[2], buf[3]]) as usize, 4),
+        127 if buf.len() >= 10 => {
+            let len = u64::from_be_bytes(<[u8; 8]>::try_from(&buf[2..10]).unwrap());
+            (usize::try_from(len).unwrap_or(usize::MAX), 10)
+        }
+        126 | 127 => return Ok(None),
+        len => (len as usize, 2),
+    };
+    if len > max_size {
+        return Err(ProtocolError::Overflow);
+    }
+    let mask = if masked {
+        if buf.len() < offset + 4 {
+            return Ok(None);
+        }
+        offset += 4;
+        Some([
+            buf[offset - 4],
+            buf[offset - 3],
+            buf[offset - 2],
+            buf[offset - 1],
+        ])
+    } else {
+        None
+    };
+    if buf.len() < offset + len {
+        return Ok(None);
+    }
+    buf.advance(offset);
+    let mut payload = buf.split_to(len);
+    if let Some(mask) = mask {
+        payload
+            .iter_mut()
+            .enumerate()
+            .for_each(|(i, b)| *b ^= mask[i % 4]);
+    }
+    Ok(Some(Frame {
+        fin: first & 0x80 != 0,
+        rsv1: first & 0x40 != 0,
+        opcode: first & 0x0f,
+        payload: payload.freeze(),
+    }))
+}
+
+/// Append a frame, client frames are masked with a zero key which leaves the payload as is
+fn encode(dst: &mut BytesMut, fin: bool, rsv1: bool, opcode: u8, payload: &[u8], masked: bool) {
+    dst.extend_from_slice(&[(fin as u8) << 7 | (rsv1 as u8) << 6 | opcode]);
+    let mask_bit = (masked as u8) << 7;
+    match payload.len() {
+        len if len < 126 => dst.extend_from_slice(&[mask_bit | len as u8]),
+        len if len <= u16::MAX as usize => {
+            dst.extend_from_slice(&[mask_bit | 126]);
+            dst.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            dst.extend_from_slice(&[mask_bit | 127]);
+            dst.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    if masked {
+        dst.extend_from_slice(&[0; 4]);
+    }
+    dst.extend_from_slice(payload);
+}
+
+fn invalid(e: ProtocolError) -> PayloadError {
+    PayloadError::Io(std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+/// Client frames with compressed messages inflated into plain frames
+pub struct Inflate<S> {
+    inner: S,
+    buf: BytesMut,
+    out: BytesMut,
+    /// opcode and compressed payload of the message being received
+    message: Option<(u8, BytesMut)>,
+    inflater: Decompress,
+    max_size: usize,
+}
+
+impl<S> Inflate<S> {
+    pub fn new(inner: S, max_size: usize) -> Self {
+        Self {
+            inner,
+            buf: BytesMut::new(),
+            out: BytesMut::new(),
+            message: None,
+            inflater: Decompress::new(false),
+            max_size,
+        }
+    }
+
+    /// Inflate a message with the session context, at most `max_size` bytes
+    fn inflate(&mut self, mut input: BytesMut) -> Result<Vec<u8>, ProtocolError> {
+        input.extend_from_slice(&SYNC_TAIL);
+        let mut out = Vec::with_capacity((input.len() * 4).min(self.max_size + 1));
+        let start = self.inflater.total_in();
+        loop {
+            if out.len() > self.max_size {
+                return Err(ProtocolError::Overflow);
+            }
+            if out.len() == out.capacity() {
+                out.reserve(input.len().max(1024));
+            }
+            let before = (self.inflater.total_in(), out.len());
+            let consumed = (before.0 - start) as usize;
+            self.inflater
+                .decompress_vec(&input[consumed..], &mut out, FlushDecompress::Sync)
+                .map_err(|e| {
+                    ProtocolError::Io(std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+                })?;
+            let consumed = (self.inflater.total_in() - start) as usize;
+            if consumed == input.len() && out.len() < out.capacity() {
+                break;
+            }
+            if (self.inflater.total_in(), out.len()) == before {
+                return Err(ProtocolError::Io(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "truncated compressed message",
+                )));
+            }
+        }
+        if out.len() > self.max_size {
+            return Err(ProtocolError::Overflow);
+        }
+        Ok(out)
+    }
+
+    /// Rewrite the complete frames of `buf` into `out`
+    fn process(&mut self) -> Result<(), ProtocolError> {
+        while let Some(frame) = parse(&mut self.buf, self.max_size)? {
+            let compressed = if frame.opcode >= OP_CLOSE {
+                false
+            } else if let Some((_, payload)) = self
+                .message
+                .as_mut()
+                .filter(|_| frame.opcode == OP_CONTINUATION)
+            {
+                if payload.len() + frame.payload.len() > self.max_size {
+                    return Err(ProtocolError::Overflow);
+                }
+                payload.extend_from_slice(&frame.payload);
+                true
+            } else if self.message.is_none() && frame.opcode != OP_CONTINUATION && frame.rsv1 {
+                self.message = Some((frame.opcode, BytesMut::from(&frame.payload[..])));
+                true
+            } else {
+                false
+            };
+            if !compressed {
+                encode(
+                    &mut self.out,
+                    frame.fin,
+                    false,
+                    frame.opcode,
+                    &frame.payload,
+                    true,
+                );
+            } else if frame.fin {
+                let (opcode, payload) = self.message.take().unwrap();
+                let message = self.inflate(payload)?;
+                encode(&mut self.out, true, false, opcode, &message, true);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<S> Stream for Inflate<S>
+where
+    S: Stream<Item = Result<Bytes, PayloadError>> + Unpin,
+{
+    type Item = Result<Bytes, PayloadError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            this.process().map_err(invalid)?;
+            if !this.out.is_empty() {
+                return Poll::Ready(Some(Ok(this.out.split().freeze())));
+            }
+            match ready!(Pin::new(&mut this.inner).poll_next(cx)) {
+                Some(Ok(bytes)) => this.buf.extend_from_slice(&bytes),
+                other => return Poll::Ready(other),
+            }
+        }
+    }
+}
+
+/// Server frames with large text and binary messages deflated
+struct DeflateBody {
+    inner: BoxBody,
+    buf: BytesMut,
+    compressor: Option<Compress>,
+    context_takeover: bool,
+    threshold: usize,
+    /// inside a message sent in several frames, which is left uncompressed
+    fragmented: bool,
+}
+
+impl DeflateBody {
+    fn deflate(&mut self, data: &[u8]) -> Result<Vec<u8>, flate2::CompressError> {
+        let compressor = self
+            .compressor
+            .get_or_insert_with(|| Compress::new(Compression::default(), false));
+        let mut out = Vec::with_capacity(data.len() / 2 + 64);
+        let start = compressor.total_in();
+        loop {
+            if out.len() == out.capacity() {
+                out.reserve((data.len() / 4).max(1024));
+            }
+            let consumed = (compressor.total_in() - start) as usize;
+            compressor.compress_vec(&data[consumed..], &mut out, FlushCompress::Sync)?;
+            if (compressor.total_in() - start) as usize == data.len() && out.len() < out.capacity()
+            {
+                break;
+            }
+        }
+        if out.ends_with(&SYNC_TAIL) {
+            out.truncate(out.len() - SYNC_TAIL.len());
+        }
+        if !self.context_takeover {
+            self.compressor = None;
+        }
+        Ok(out)
+    }
+
+    fn process(&mut self) -> Result<BytesMut, Box<dyn StdError>> {
+        let mut out = BytesMut::new();
+        while let Some(frame) = parse(&mut self.buf, usize::MAX)? {
+            let data = frame.opcode != OP_CONTINUATION && frame.opcode < OP_CLOSE;
+            if data && frame.fin && !self.fragmented && frame.payload.len() >= self.threshold {
+                let compressed = self.deflate(&frame.payload)?;
+                encode(&mut out, true, true, frame.opcode, &compressed, false);
+                continue;
+            }
+            if data || frame.opcode == OP_CONTINUATION {
+                self.fragmented = !frame.fin;
+            }
+            encode(
+                &mut out,
+                frame.fin,
+                frame.rsv1,
+                frame.opcode,
+                &frame.payload,
+                false,
+            );
+        }
+        Ok(out)
+    }
+}
+
+impl MessageBody for DeflateBody {
+    type Error = Box<dyn StdError>;
+
+    fn size(&self) -> BodySize {
+        BodySize::Stream
+    }
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        let this = self.get_mut();
+        loop {
+            let out = this.process()?;
+            if !out.is_empty() {
+                return Poll::Ready(Some(Ok(out.freeze())));
+            }
+            match ready!(Pin::new(&mut this.inner).poll_next(cx)) {
+                Some(Ok(bytes)) => this.buf.extend_from_slice(&bytes),
+                other => return Poll::Ready(other),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn client_deflate(compressor: &mut Compress, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(data.len() + 64);
+        compressor
+            .compress_vec(data, &mut out, FlushCompress::Sync)
+            .unwrap();
+        out.truncate(out.len() - SYNC_TAIL.len());
+        out
+    }
+
+    #[test]
+    fn negotiate() {
+        let offer = |value: &str| {
+            let req = TestRequest::default()
+                .insert_header((SEC_WEBSOCKET_EXTENSIONS, value))
+                .to_http_request();
+            Deflate::negotiate(&req, true)
+        };
+        assert_eq!(
+            offer("permessage-deflate; client_max_window_bits"),
+            Some(Deflate {
+                context_takeover: true
+            })
+        );
+        assert_eq!(
+            offer("permessage-deflate; server_no_context_takeover"),
+            Some(Deflate {
+                context_takeover: false
+            })
+        );
+        // a smaller server window cannot be served, the next offer is taken
+        assert_eq!(
+            offer("permessage-deflate; server_max_window_bits=10, permessage-deflate"),
+            Some(Deflate {
+                context_takeover: true
+            })
+        );
+        assert_eq!(offer("x-webkit-deflate-frame"), None);
+        assert_eq!(
+            Deflate::negotiate(&TestRequest::default().to_http_request(), true),
+            None
+        );
+    }
+
+    #[actix_rt::test]
+    async fn inflate_client_messages() {
+        let message = br#"["REQ","sub",{"kinds":[443],"authors":["aaaa"]}]"#.repeat(20);
+        let mut compressor = Compress::new(Compression::default(), false);
+        let mut frames = BytesMut::new();
+        // two messages sharing the compression context, the first one fragmented
+        let first = client_deflate(&mut compressor, &message);
+        let (head, tail) = first.split_at(first.len() / 2);
+        encode(&mut frames, false, true, 1, head, true);
+        encode(&mut frames, true, false, 9, b"ping", true);
+        encode(&mut frames, true, false, OP_CONTINUATION, tail, true);
+        encode(
+            &mut frames,
+            true,
+            true,
+            1,
+            &client_deflate(&mut compressor, &message),
+            true,
+        );
+        encode(&mut frames, true, false, 1, b"plain", true);
+
+        let stream = futures_util::stream::iter(
+            frames
+                .chunks(7)
+                .map(|c| Ok::<_, PayloadError>(Bytes::copy_from_slice(c)))
+                .collect::<Vec<_>>(),
+        );
+        let mut inflate = Inflate::new(stream, 4096);
+        let mut plain = BytesMut::new();
+        while let Some(bytes) = futures_util::StreamExt::next(&mut inflate).await {
+            plain.extend_from_slice(&bytes.unwrap());
+        }
+        let mut frames = vec![];
+        while let Some(frame) = parse(&mut plain, usize::MAX).unwrap() {
+            assert!(frame.fin && !frame.rsv1);
+            frames.push((frame.opcode, frame.payload));
+        }
+        assert_eq!(frames.len(), 4);
+        assert_eq!(frames[0], (9, Bytes::from_static(b"ping")));
+        assert_eq!(frames[1], (1, Bytes::from(message.clone())));
+        assert_eq!(frames[2], (1, Bytes::from(message.clone())));
+        assert_eq!(frames[3], (1, Bytes::from_static(b"plain")));
+
+        // inflated messages are bounded
+        let mut frames = BytesMut::new();
+        let mut compressor = Compress::new(Compression::default(), false);
+        encode(
+            &mut frames,
+            true,
+            true,
+            1,
+            &client_deflate(&mut compressor, &[b'a'; 8192]),
+            true,
+        );
+        let stream = futures_util::stream::iter(vec![Ok::<_, PayloadError>(frames.freeze())]);
+        let mut inflate = Inflate::new(stream, 4096);
+        assert!(futures_util::StreamExt::next(&mut inflate)
+            .await
+            .unwrap()
+            .is_err());
+    }
+
+    #[test]
+    fn deflate_server_messages() {
+        let message = br#"["EVENT","sub",{"kind":443,"content":"00112233"}]"#.repeat(20);
+        let mut frames = BytesMut::new();
+        encode(&mut frames, true, false, 1, &message, false);
+        encode(&mut frames, true, false, 1, b"small", false);
+        encode(&mut frames, true, false, 1, &message, false);
+
+        for context_takeover in [true, false] {
+            let mut body = DeflateBody {
+                inner: BoxBody::new(()),
+                buf: frames.clone(),
+                compressor: None,
+                context_takeover,
+                threshold: 64,
+                fragmented: false,
+            };
+            let mut out = body.process().unwrap();
+            let mut inflater = Decompress::new(false);
+            let mut messages = vec![];
+            while let Some(frame) = parse(&mut out, usize::MAX).unwrap() {
+                if !frame.rsv1 {
+                    messages.push(frame.payload.to_vec());
+                    continue;
+                }
+                let mut input = frame.payload.to_vec();
+                input.extend_from_slice(&SYNC_TAIL);
+                let mut plain = Vec::with_capacity(message.len() * 2);
+                inflater
+                    .decompress_vec(&input, &mut plain, FlushDecompress::Sync)
+                    .unwrap();
+                assert!(frame.payload.len() < message.len());
+                messages.push(plain);
+            }
+            assert_eq!(
+                messages,
+                vec![message.clone(), b"small".to_vec(), message.clone()]
+            );
+        }
+    }
+}
@@ -1,10 +1,31 @@
 use crate::{
-    message::{ClientMessage, OutgoingMessage, ReadEvent, Subscription},
+    message::{ClientMessage, IncomingMessage, OutgoingMessage, Prefix, ReadEvent, Subscription},
     setting::SettingWrapper,
-    Session,
+    Session, SessionInfo,
 };
-use actix_web::web::ServiceConfig;
+use actix_web::{web::ServiceConfig, HttpRequest};
+use metrics::counter;
 use nostr_db::Event;
+use std::{
+    any::Any,
+    cmp::Reverse,
+    collections::HashSet,
+    future::Future,
+    panic::{self, AssertUnwindSafe},
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicU32, Ordering},
+    time::Duration,
+};
+use tracing::error;
+
+/// Future of an async message handler, polled on the session's thread
+pub type MessageFuture = Pin<Box<dyn Future<Output = ExtensionMessageResult>>>;
+
+/// Future of a shutdown hook, awaited until the shutdown timeout
+pub type ShutdownFuture = Pin<Box<dyn Future<Output = ()>>>;
+
+/// Future of a readiness check, the error tells what is unavailable
+pub type ReadyFuture = Pin<Box<dyn Future<Output = Result<(), String>>>>;
 
 pub enum ExtensionMessageResult {
     /// Continue run the next extension message method, the server takes over finally.
@@ -13,6 +34,16 @@ pub enum ExtensionMessageResult {
     Stop(OutgoingMessage),
     /// Stop run the next, does not send any messages to the client.
     Ignore,
+    /// Wait for the future without blocking the session, then go on with its result.
+    /// The next extensions run after it resolves to `Continue`.
+    Pending(MessageFuture),
+}
+
+impl ExtensionMessageResult {
+    /// Answer the message asynchronously, e.g. after awaiting a storage check
+    pub fn pending<F: Future<Output = ExtensionMessageResult> + 'static>(fut: F) -> Self {
+        Self::Pending(Box::pin(fut))
+    }
 }
 
 impl From<OutgoingMessage> for ExtensionMessageResult {
@@ -21,7 +52,20 @@ impl From<OutgoingMessage> for ExtensionMessageResult {
     }
 }
 
+/// Decision on a new connection, taken before the websocket handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionResult {
+    Accept,
+    /// Refuse with http 403
+    Reject,
+    /// Hold the request for a time, then refuse it with http 403
+    Tarpit(Duration),
+}
+
 /// Result of processing a REQ message
+///
+/// Events of `AddEvents` and `Handle` are sent before EOSE, merged with the
+/// database results by [`merge_req_events`].
 pub enum ExtensionReqResult {
     /// Continue with normal database query
     Continue,
@@ -29,6 +73,61 @@ pub enum ExtensionReqResult {
     AddEvents(Vec<Event>),
     /// Completely handle the request (skip database query)
     Handle(Vec<Event>),
+    /// Refuse the request with CLOSED, the reason carries a NIP-01 prefix
+    Close(String),
+    /// Replace the filters of the request (e.g. cap limits, strip kinds), the subscription id is kept
+    Rewrite(Subscription),
+}
+
+/// Merge events supplied by extensions into the stored results of a REQ
+///
+/// Duplicates of stored events (or of other added events) are dropped, the
+/// stored copy wins as it passed `post_process_query_results`. Each filter
+/// with a limit keeps its newest matching events up to the limit, events
+/// matching no filter (e.g. ones an extension built for the request) are
+/// kept. The merged events are ordered like the database returns them: newest
+/// first when every filter has a limit, oldest first otherwise, ties by id.
+/// Without added events the stored results are kept as they are.
+pub fn merge_req_events(
+    subscription: &Subscription,
+    stored: Vec<Event>,
+    added: Vec<Event>,
+) -> Vec<Event> {
+    if added.is_empty() {
+        return stored;
+    }
+    let mut seen = HashSet::new();
+    let mut events: Vec<Event> = stored
+        .into_iter()
+        .chain(added)
+        .filter(|event| seen.insert(*event.id()))
+        .collect();
+    events.sort_by_key(|event| (Reverse(event.created_at()), *event.id()));
+
+    let mut kept = HashSet::new();
+    let mut matched = HashSet::new();
+    for filter in &subscription.filters {
+        let matching = events
+            .iter()
+            .filter(|event| filter.r#match(event.index()))
+            .map(|event| *event.id());
+        matched.extend(matching.clone());
+        match filter.limit {
+            Some(limit) => kept.extend(matching.take(limit as usize)),
+            None => kept.extend(matching),
+        }
+    }
+    events.retain(|event| kept.contains(event.id()) || !matched.contains(event.id()));
+
+    let newest_first = !subscription.filters.is_empty()
+        && subscription
+            .filters
+            .iter()
+            .all(|filter| filter.limit.is_some());
+    if !newest_first {
+        events.reverse();
+    }
+    events
 }
 
 /// Result of post-processing query results
@@ -51,6 +150,12 @@ pub trait Extension: Send + Sync {
     #[allow(unused_variables)]
     fn config_web(&mut self, cfg: &mut ServiceConfig) {}
 
+    /// Decide on a connection from `ip` before the websocket handshake, no session exists yet
+    #[allow(unused_variables)]
+    fn connection(&self, ip: &str, req: &HttpRequest) -> ConnectionResult {
+        ConnectionResult::Accept
+    }
+
     /// Execute after a user connect
     #[allow(unused_variables)]
     fn connected(&self, session: &mut Session, ctx: &mut <Session as actix::Actor>::Context) {}
@@ -59,7 +164,9 @@ pub trait Extension: Send + Sync {
     #[allow(unused_variables)]
     fn disconnected(&self, session: &mut Session, ctx: &mut <Session as actix::Actor>::Context) {}
 
-    /// Execute when message incoming
+    /// Execute when message incoming, return `ExtensionMessageResult::pending` to answer asynchronously
+    /// Events of other instances arrive on a remote session, see `Session::is_remote`,
+    /// `msg.auth_pubkey` is the NIP-42 pubkey the message was sent under
     #[allow(unused_variables)]
     fn message(
         &self,
@@ -72,15 +179,45 @@ pub trait Extension: Send + Sync {
 
     /// Intercept REQ messages before database query
     #[allow(unused_variables)]
-    fn process_req(&self, session_id: usize, subscription: &Subscription) -> ExtensionReqResult {
+    fn process_req(
+        &self,
+        session: &SessionInfo,
+        subscription: &Subscription,
+    ) -> ExtensionReqResult {
         ExtensionReqResult::Continue
     }
 
-    /// Post-process query results before sending to client
+    /// Answer a COUNT from the extension's own storage, `None` leaves it to the next extension.
+    /// Runs like `process_req` after every extension accepted the message.
+    #[allow(unused_variables)]
+    fn process_count(&self, session: &SessionInfo, subscription: &Subscription) -> Option<u64> {
+        None
+    }
+
+    /// Execute after an event published by a client or emitted by the relay was stored, not for duplicates or rejected events.
+    /// Runs on the server actor, long work should be spawned.
+    #[allow(unused_variables)]
+    fn event_stored(&self, event: &Event) {}
+
+    /// Decide whether a stored event is sent to a live subscription, false skips the session.
+    /// `auth_pubkey` is the NIP-42 pubkey of the subscribed session.
+    #[allow(unused_variables)]
+    fn broadcast(&self, session_id: usize, auth_pubkey: Option<&String>, event: &Event) -> bool {
+        true
+    }
+
+    /// Whether `post_process_query_results` needs the stored results of a REQ, they are
+    /// held back until EOSE only when an extension asks for them
+    #[allow(unused_variables)]
+    fn post_processes(&self, session: &SessionInfo, subscription: &Subscription) -> bool {
+        false
+    }
+
+    /// Post-process query results before sending to client, see `post_processes`
     #[allow(unused_variables)]
     fn post_process_query_results(
         &self,
-        session_id: usize,
+        session: &SessionInfo,
         subscription: &Subscription,
         events: Vec<Event>,
     ) -> PostProcessResult {
@@ -89,38 +226,175 @@ pub trait Extension: Send + Sync {
             consumed_events: vec![],
         }
     }
+
+    /// Called once when the relay shuts down after sessions were notified and pending events written,
+    /// e.g. to wait for background writes. New connections are no longer accepted.
+    fn shutdown(&self) -> Option<ShutdownFuture> {
+        None
+    }
+
+    /// Check the dependencies of the extension for `/readyz`, `None` if it has none
+    fn ready(&self) -> Option<ReadyFuture> {
+        None
+    }
+}
+
+/// Panic counters of an extension
+#[derive(Default)]
+struct Health {
+    /// consecutive panics, reset by a successful call
+    failures: AtomicU32,
+    /// panics that open the circuit, 0 never opens it
+    max_failures: u32,
+    /// skipped until the next setting reload
+    open: AtomicBool,
 }
 
 /// extensions
 #[derive(Default)]
 pub struct Extensions {
+    /// all extensions in registration order
     list: Vec<Box<dyn Extension>>,
+    /// panic counters, by registration order
+    health: Vec<Health>,
+    /// indexes of the enabled extensions in run order
+    order: Vec<usize>,
+}
+
+impl std::fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list()
+            .entries(self.enabled().map(|(_, ext)| ext.name()))
+            .finish()
+    }
+}
+
+fn panic_message(err: &(dyn Any + Send)) -> &str {
+    err.downcast_ref::<&str>()
+        .copied()
+        .or_else(|| err.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown panic")
+}
+
+/// Reply to a message whose extension panicked
+fn internal_error(msg: &ClientMessage) -> OutgoingMessage {
+    match &msg.msg {
+        IncomingMessage::Event(event) => {
+            OutgoingMessage::rejected(&event.id_str(), Prefix::Error, "internal error")
+        }
+        IncomingMessage::Req(sub) | IncomingMessage::Count(sub) => {
+            OutgoingMessage::refused(&sub.id, Prefix::Error, "internal error")
+        }
+        _ => OutgoingMessage::notice(&Prefix::Error.with("internal error")),
+    }
 }
 
 impl Extensions {
     pub fn add<E: Extension + 'static>(&mut self, ext: E) {
+        self.order.push(self.list.len());
         self.list.push(Box::new(ext));
+        self.health.push(Health::default());
+    }
+
+    /// Enabled extensions in run order, with their registration index
+    fn enabled(&self) -> impl Iterator<Item = (usize, &dyn Extension)> {
+        self.order
+            .iter()
+            .filter(|&&i| !self.health[i].open.load(Ordering::Relaxed))
+            .map(|&i| (i, self.list[i].as_ref()))
+    }
+
+    /// Whether the extension of registration index `i` is enabled
+    fn is_enabled(&self, i: usize) -> bool {
+        self.order.contains(&i) && !self.health[i].open.load(Ordering::Relaxed)
+    }
+
+    /// Run a hook of an extension, `None` if it panicked
+    fn guard<T>(&self, i: usize, hook: &'static str, f: impl FnOnce() -> T) -> Option<T> {
+        let health = &self.health[i];
+        match panic::catch_unwind(AssertUnwindSafe(f)) {
+            Ok(val) => {
+                health.failures.store(0, Ordering::Relaxed);
+                Some(val)
+            }
+            Err(err) => {
+                let name = self.list[i].name();
+                let failures = health.failures.fetch_add(1, Ordering::Relaxed) + 1;
+                error!(
+                    extension = name,
+                    hook,
+                    failures,
+                    "extension panicked: {}",
+                    panic_message(err.as_ref())
+                );
+                counter!("nostr_relay_extension_panics", "extension" => name, "hook" => hook)
+                    .increment(1);
+                if health.max_failures > 0 && failures >= health.max_failures {
+                    health.open.store(true, Ordering::Relaxed);
+                    error!(
+                        extension = name,
+                        "extension disabled until the next reload after {} panics", failures
+                    );
+                    counter!("nostr_relay_extension_circuit_open", "extension" => name)
+                        .increment(1);
+                }
+                None
+            }
+        }
+    }
+
+    /// Apply `[extensions.<name>]` enable flags, priorities and panic budgets, closes open circuits
+    pub fn arrange(&mut self, setting: &SettingWrapper) {
+        let r = setting.read();
+        let mut order = Vec::with_capacity(self.list.len());
+        for (i, ext) in self.list.iter().enumerate() {
+            let control = r.extension_control(ext.name());
+            self.health[i] = Health {
+                max_failures: control.max_failures,
+                ..Default::default()
+            };
+            if control.enabled {
+                order.push((control.priority, i));
+            }
+        }
+        // stable, ties keep the registration order
+        order.sort_by_key(|(priority, _)| *priority);
+        self.order = order.into_iter().map(|(_, i)| i).collect();
     }
 
+    /// Run `setting` of every extension, disabled ones included so they can be enabled on reload
     pub fn call_setting(&mut self, setting: &SettingWrapper) {
         for ext in &mut self.list {
             ext.setting(setting);
         }
+        self.arrange(setting);
     }
 
+    /// Routes are registered once, extensions enabled later serve no routes until restart
     pub fn call_config_web(&mut self, cfg: &mut ServiceConfig) {
-        for ext in &mut self.list {
-            ext.config_web(cfg);
+        for &i in &self.order {
+            self.list[i].config_web(cfg);
         }
     }
 
+    /// First refusal of the extensions, a panicking extension accepts
+    pub fn call_connection(&self, ip: &str, req: &HttpRequest) -> ConnectionResult {
+        self.enabled()
+            .map(|(i, ext)| {
+                self.guard(i, "connection", || ext.connection(ip, req))
+                    .unwrap_or(ConnectionResult::Accept)
+            })
+            .find(|result| *result != ConnectionResult::Accept)
+            .unwrap_or(ConnectionResult::Accept)
+    }
+
     pub fn call_connected(
         &self,
         session: &mut Session,
         ctx: &mut <Session as actix::Actor>::Context,
     ) {
-        for ext in &self.list {
-            ext.connected(session, ctx);
+        for (i, ext) in self.enabled() {
+            self.guard(i, "connected", || ext.connected(session, ctx));
         }
     }
 
@@ -129,8 +403,8 @@ impl Extensions {
         session: &mut Session,
         ctx: &mut <Session as actix::Actor>::Context,
     ) {
-        for ext in &self.list {
-            ext.disconnected(session, ctx);
+        for (i, ext) in self.enabled() {
+            self.guard(i, "disconnected", || ext.disconnected(session, ctx));
         }
     }
 
@@ -140,66 +414,317 @@ impl Extensions {
         session: &mut Session,
         ctx: &mut <Session as actix::Actor>::Context,
     ) -> ExtensionMessageResult {
+        self.call_message_from(None, msg, session, ctx).1
+    }
+
+    /// Run the message methods of every enabled extension, or of the `remaining` ones of a
+    /// message that was pending. Returns the extensions left to run once a `Pending` result
+    /// resolves, a reload or an opened circuit meanwhile skips them but never reorders them.
+    pub fn call_message_from(
+        &self,
+        remaining: Option<Vec<usize>>,
+        msg: ClientMessage,
+        session: &mut Session,
+        ctx: &mut <Session as actix::Actor>::Context,
+    ) -> (Vec<usize>, ExtensionMessageResult) {
+        let run = match remaining {
+            Some(remaining) => remaining
+                .into_iter()
+                .filter(|i| self.is_enabled(*i))
+                .collect(),
+            None => self.enabled().map(|(i, _)| i).collect::<Vec<_>>(),
+        };
+        let failed = internal_error(&msg);
         let mut msg = msg;
-        for ext in &self.list {
-            match ext.message(msg, session, ctx) {
+        for (position, &i) in run.iter().enumerate() {
+            let ext = self.list[i].as_ref();
+            // the message is lost with a panicking extension
+            let Some(result) = self.guard(i, "message", || ext.message(msg, session, ctx)) else {
+                return (
+                    run[position + 1..].to_vec(),
+                    ExtensionMessageResult::Stop(failed),
+                );
+            };
+            match result {
                 ExtensionMessageResult::Continue(m) => {
                     msg = m;
                 }
-                ExtensionMessageResult::Stop(o) => {
-                    return ExtensionMessageResult::Stop(o);
-                }
-                ExtensionMessageResult::Ignore => {
-                    return ExtensionMessageResult::Ignore;
-                }
+                other => return (run[position + 1..].to_vec(), other),
             };
         }
-        ExtensionMessageResult::Continue(msg)
+        (vec![], ExtensionMessageResult::Continue(msg))
     }
 
+    /// Run `process_req` of every extension, rewrites apply in place and are seen by later extensions,
+    /// events added by several extensions are returned in one `AddEvents`
     pub fn call_process_req(
         &self,
-        session_id: usize,
-        subscription: &Subscription,
-    ) -> (ExtensionReqResult, Vec<Event>) {
+        session: &SessionInfo,
+        subscription: &mut Subscription,
+    ) -> ExtensionReqResult {
         let mut additional_events = Vec::new();
-        
-        for ext in &self.list {
-            match ext.process_req(session_id, subscription) {
+
+        for (i, ext) in self.enabled() {
+            let result = self
+                .guard(i, "process_req", || ext.process_req(session, subscription))
+                .unwrap_or(ExtensionReqResult::Continue);
+            match result {
                 ExtensionReqResult::Continue => continue,
                 ExtensionReqResult::AddEvents(mut events) => {
                     additional_events.append(&mut events);
                 }
                 ExtensionReqResult::Handle(events) => {
-                    return (ExtensionReqResult::Handle(events), vec![]);
+                    return ExtensionReqResult::Handle(events);
+                }
+                ExtensionReqResult::Close(reason) => {
+                    return ExtensionReqResult::Close(reason);
+                }
+                ExtensionReqResult::Rewrite(rewritten) => {
+                    subscription.filters = rewritten.filters;
                 }
             }
         }
-        
+
         if !additional_events.is_empty() {
-            (ExtensionReqResult::AddEvents(additional_events.clone()), additional_events)
+            ExtensionReqResult::AddEvents(additional_events)
         } else {
-            (ExtensionReqResult::Continue, vec![])
+            ExtensionReqResult::Continue
         }
     }
 
-    pub fn call_post_process_query_results(
+    pub fn call_event_stored(&self, event: &Event) {
+        for (i, ext) in self.enabled() {
+            self.guard(i, "event_stored", || ext.event_stored(event));
+        }
+    }
+
+    /// A panicking extension withholds the event
+    pub fn call_broadcast(
         &self,
         session_id: usize,
+        auth_pubkey: Option<&String>,
+        event: &Event,
+    ) -> bool {
+        self.enabled().all(|(i, ext)| {
+            self.guard(i, "broadcast", || {
+                ext.broadcast(session_id, auth_pubkey, event)
+            })
+            .unwrap_or(false)
+        })
+    }
+
+    pub fn call_process_count(
+        &self,
+        session: &SessionInfo,
+        subscription: &Subscription,
+    ) -> Option<u64> {
+        self.enabled().find_map(|(i, ext)| {
+            self.guard(i, "process_count", || {
+                ext.process_count(session, subscription)
+            })
+            .flatten()
+        })
+    }
+
+    /// A panicking extension has the results held back, its post-processing withholds them
+    pub fn call_post_processes(&self, session: &SessionInfo, subscription: &Subscription) -> bool {
+        self.enabled().any(|(i, ext)| {
+            self.guard(i, "post_processes", || {
+                ext.post_processes(session, subscription)
+            })
+            .unwrap_or(true)
+        })
+    }
+
+    /// A panicking extension withholds the remaining results
+    pub fn call_post_process_query_results(
+        &self,
+        session: &SessionInfo,
         subscription: &Subscription,
         mut events: Vec<Event>,
     ) -> PostProcessResult {
         let mut all_consumed_events = Vec::new();
-        
-        for ext in &self.list {
-            let result = ext.post_process_query_results(session_id, subscription, events);
+
+        for (i, ext) in self.enabled() {
+            let Some(result) = self.guard(i, "post_process_query_results", || {
+                ext.post_process_query_results(session, subscription, events)
+            }) else {
+                return PostProcessResult {
+                    events: vec![],
+                    consumed_events: all_consumed_events,
+                };
+            };
             events = result.events;
             all_consumed_events.extend(result.consumed_events);
         }
-        
+
         PostProcessResult {
             events,
             consumed_events: all_consumed_events,
         }
     }
+
+    /// Readiness checks by extension name, a panicking check fails
+    pub fn call_ready(&self) -> Vec<(&'static str, ReadyFuture)> {
+        self.enabled()
+            .filter_map(|(i, ext)| {
+                let fut = self.guard(i, "ready", || ext.ready()).unwrap_or_else(|| {
+                    Some(Box::pin(async { Err("check panicked".to_owned()) }))
+                })?;
+                Some((ext.name(), fut))
+            })
+            .collect()
+    }
+
+    pub fn call_shutdown(&self) -> Vec<ShutdownFuture> {
+        self.enabled()
+            .filter_map(|(i, ext)| self.guard(i, "shutdown", || ext.shutdown()).flatten())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Setting;
+    use anyhow::Result;
+
+    struct Named(&'static str);
+    impl Extension for Named {
+        fn name(&self) -> &'static str {
+            self.0
+        }
+    }
+
+    #[test]
+    fn arrange() -> Result<()> {
+        let mut extensions = Extensions::default();
+        extensions.add(Named("auth"));
+        extensions.add(Named("rate_limiter"));
+        extensions.add(Named("mls-gateway"));
+        extensions.add(Named("search"));
+        assert_eq!(
+            format!("{:?}", extensions),
+            r#"["auth", "rate_limiter", "mls-gateway", "search"]"#
+        );
+
+        let setting: SettingWrapper = Setting::default().into();
+        setting.write().extra = serde_json::from_str(
+            r#"{ "extensions": {
+                "rate_limiter": { "priority": -10 },
+                "mls_gateway": { "enabled": false, "store_backend": "sql" },
+                "search": { "priority": -10 }
+            } }"#,
+        )?;
+        extensions.call_setting(&setting);
+        assert_eq!(
+            format!("{:?}", extensions),
+            r#"["rate_limiter", "search", "auth"]"#
+        );
+
+        // enabled again on reload
+        setting.write().extra = Default::default();
+        extensions.call_setting(&setting);
+        assert_eq!(extensions.order, vec![0, 1, 2, 3]);
+        Ok(())
+    }
+
+    struct Panicky;
+    impl Extension for Panicky {
+        fn name(&self) -> &'static str {
+            "panicky"
+        }
+
+        fn broadcast(
+            &self,
+            _session_id: usize,
+            _auth_pubkey: Option<&String>,
+            _event: &Event,
+        ) -> bool {
+            panic!("broadcast failed")
+        }
+    }
+
+    #[test]
+    fn circuit() -> Result<()> {
+        let mut extensions = Extensions::default();
+        extensions.add(Named("auth"));
+        extensions.add(Panicky);
+        let setting: SettingWrapper = Setting::default().into();
+        setting.write().extra =
+            serde_json::from_str(r#"{ "extensions": { "panicky": { "max_failures": 2 } } }"#)?;
+        extensions.call_setting(&setting);
+
+        let key = nostr_db::secp256k1::Keypair::from_seckey_str(
+            nostr_db::secp256k1::SECP256K1,
+            &"01".repeat(32),
+        )?;
+        let event = Event::create(&key, 0, 1, vec![], "".to_owned())?;
+
+        // a panicking filter withholds the event until its circuit opens
+        assert!(!extensions.call_broadcast(1, None, &event));
+        assert!(!extensions.call_broadcast(1, None, &event));
+        assert!(extensions.call_broadcast(1, None, &event));
+        assert_eq!(format!("{:?}", extensions), r#"["auth"]"#);
+
+        // closed again on reload
+        extensions.call_setting(&setting);
+        assert_eq!(format!("{:?}", extensions), r#"["auth", "panicky"]"#);
+        Ok(())
+    }
+
+    #[test]
+    fn merge() -> Result<()> {
+        let key = nostr_db::secp256k1::Keypair::from_seckey_str(
+            nostr_db::secp256k1::SECP256K1,
+            &"02".repeat(32),
+        )?;
+        let mut events = (0..4)
+            .map(|t| Event::create(&key, t * 10, 1, vec![], "".to_owned()))
+            .collect::<Result<Vec<_>, _>>()?;
+        events.push(Event::create(&key, 5, 2, vec![], "".to_owned())?);
+        events.push(Event::create(&key, 15, 3, vec![], "".to_owned())?);
+        let times = |events: Vec<Event>| events.iter().map(|e| e.created_at()).collect::<Vec<_>>();
+        let subscription = |filters: &[&str]| -> Result<Subscription> {
+            Ok(Subscription {
+                id: "sub".to_owned(),
+                filters: filters
+                    .iter()
+                    .map(|f| f.parse())
+                    .collect::<Result<_, _>>()?,
+            })
+        };
+
+        // stored results are untouched without added events
+        let sub = subscription(&[r#"{"limit":1}"#])?;
+        let stored = vec![events[0].clone(), events[2].clone()];
+        assert_eq!(
+            times(merge_req_events(&sub, stored.clone(), vec![])),
+            vec![0, 20]
+        );
+
+        // duplicates dropped, newest first within the limit of each filter,
+        // events matching no filter are kept
+        let sub = subscription(&[r#"{"kinds":[1],"limit":2}"#, r#"{"kinds":[2],"limit":1}"#])?;
+        let added = vec![
+            events[3].clone(),
+            events[2].clone(),
+            events[1].clone(),
+            events[3].clone(),
+            events[4].clone(),
+            events[5].clone(),
+        ];
+        assert_eq!(
+            times(merge_req_events(&sub, stored.clone(), added.clone())),
+            vec![30, 20, 15, 5]
+        );
+
+        // oldest first without limits
+        let sub = subscription(&[r#"{"kinds":[1]}"#, r#"{"kinds":[2],"limit":1}"#])?;
+        assert_eq!(
+            times(merge_req_events(&sub, stored, added)),
+            vec![0, 5, 10, 15, 20, 30]
+        );
+        Ok(())
+    }
 }
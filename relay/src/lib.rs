@@ -12,6 +12,8 @@ pub enum Error {
     Json(#[from] serde_json::Error),
     #[error("invalid: {0}")]
     Invalid(String),
+    #[error("pow: {0}")]
+    Pow(String),
     #[error("{0}")]
     Message(String),
     #[error("{0}")]
@@ -22,24 +24,38 @@ impl actix_web::ResponseError for Error {}
 
 pub type Result<T, E = Error> = core::result::Result<T, E>;
 
+pub mod admin;
 mod app;
+mod connections;
+mod deflate;
 pub mod duration;
 mod extension;
 mod hash;
 mod list;
 pub mod message;
 mod reader;
+pub mod secret;
 mod server;
 mod session;
 pub mod setting;
 mod subscriber;
+mod verifier;
 mod writer;
 
 pub use metrics;
 pub use nostr_db as db;
 pub use {
-    app::*, extension::*, list::List, reader::Reader, server::Server, session::Session,
-    setting::Setting, subscriber::Subscriber, writer::Writer,
+    app::*,
+    connections::{Connection, Connections},
+    extension::*,
+    list::List,
+    reader::Reader,
+    server::Server,
+    session::{Session, SessionInfo},
+    setting::Setting,
+    subscriber::Subscriber,
+    verifier::{Verifier, VerifyEvent},
+    writer::Writer,
 };
 
 #[cfg(test)]
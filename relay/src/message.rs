@@ -1,21 +1,36 @@
 use actix::{Message, MessageResponse, Recipient};
 use bytestring::ByteString;
 use nostr_db::{now, CheckEventResult, Event, Filter};
+use parking_lot::RwLock;
 use serde::{
     de::{self, SeqAccess, Visitor},
     Deserialize, Deserializer,
 };
 use serde_json::{json, Value};
 use std::fmt::Display;
-use std::{fmt, marker::PhantomData};
+use std::{collections::HashMap, fmt, marker::PhantomData, sync::Arc};
 
-use crate::{setting::Limitation, Error};
+use crate::{
+    setting::{Limitation, Limits, Pow},
+    Error, Extensions,
+};
 
 /// New session is created
 #[derive(Message, Clone, Debug)]
 #[rtype(usize)]
 pub struct Connect {
     pub addr: Recipient<OutgoingMessage>,
+    /// Disconnects the session when it cannot keep up
+    pub kick: Option<Recipient<Kick>>,
+}
+
+/// Close a session with a NOTICE
+#[derive(Message, Clone, Debug)]
+#[rtype(result = "()")]
+pub struct Kick {
+    pub reason: String,
+    /// `nostr_relay_session_stop_total` reason label
+    pub cause: &'static str,
 }
 
 /// Session is disconnected
@@ -25,6 +40,14 @@ pub struct Disconnect {
     pub id: usize,
 }
 
+/// Session authenticated with NIP-42
+#[derive(Message, Clone, Debug)]
+#[rtype(result = "()")]
+pub struct Authenticated {
+    pub id: usize,
+    pub pubkey: String,
+}
+
 /// Message from client
 #[derive(Message, Clone, Debug)]
 #[rtype(result = "()")]
@@ -37,6 +60,9 @@ pub struct ClientMessage {
     pub msg: IncomingMessage,
     /// is nip70 checked
     pub nip70_checked: bool,
+    /// NIP-42 pubkey the message was sent under, for events of other instances
+    /// the one authenticated where the client is connected
+    pub auth_pubkey: Option<String>,
 }
 
 impl ClientMessage {
@@ -46,6 +72,7 @@ impl ClientMessage {
             text,
             msg,
             nip70_checked: false,
+            auth_pubkey: None,
         }
     }
 }
@@ -82,22 +109,77 @@ impl ClientMessage {
         Ok(())
     }
 
+    /// Check the per-kind content size of events
+    pub fn validate_limits(&self, limits: &Limits) -> Result<(), Error> {
+        if let IncomingMessage::Event(event) = &self.msg {
+            if let Some(max) = limits.max_content_length(event.kind()) {
+                if event.content().len() > max {
+                    return Err(Error::Invalid(format!(
+                        "content of kind {} exceeds {} bytes",
+                        event.kind(),
+                        max
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Check the nip-13 proof of work of events
+    pub fn validate_pow(&self, pow: &Pow) -> Result<(), Error> {
+        if let IncomingMessage::Event(event) = &self.msg {
+            let min = pow.min_difficulty(event.kind());
+            if min == 0 {
+                return Ok(());
+            }
+            let difficulty = event.pow_difficulty();
+            if difficulty < min {
+                return Err(Error::Pow(format!(
+                    "difficulty {} is less than {}",
+                    difficulty, min
+                )));
+            }
+            // a lucky id does not count for a lower committed target
+            if let Some(target) = event.pow_target() {
+                if target < min {
+                    return Err(Error::Pow(format!(
+                        "committed target {} is less than {}",
+                        target, min
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
     pub fn validate(&mut self, limitation: &Limitation) -> Result<(), Error> {
+        self.validate_with(limitation, true)
+    }
+
+    /// validate, leaving the event signature to a verification worker when `verify_sign` is false
+    pub fn validate_with(
+        &mut self,
+        limitation: &Limitation,
+        verify_sign: bool,
+    ) -> Result<(), Error> {
         check_max!(self.text.as_bytes().len(), limitation.max_message_length);
 
         match &mut self.msg {
             IncomingMessage::Event(event) => {
                 check_max!(event.tags().len(), limitation.max_event_tags);
-                event.validate(
+                event.validate_unsigned(
                     now(),
                     limitation.max_event_time_older_than_now,
                     limitation.max_event_time_newer_than_now,
                 )?;
+                if verify_sign {
+                    event.verify_sign()?;
+                }
             }
 
             IncomingMessage::Req(sub) => {
-                check_max!(sub.filters.len(), limitation.max_filters);
                 check_max!(sub.id.len(), limitation.max_subid_length);
+                validate_filters(&sub.filters, limitation)?;
 
                 for f in &mut sub.filters {
                     // Fill default limit, Override the incoming limit if it is too large
@@ -113,12 +195,41 @@ impl ClientMessage {
                     }
                 }
             }
+            IncomingMessage::Count(sub) => {
+                check_max!(sub.id.len(), limitation.max_subid_length);
+                validate_filters(&sub.filters, limitation)?;
+            }
             _ => {}
         }
         Ok(())
     }
 }
 
+/// Bound the work a single query can cause in the reader
+fn validate_filters(filters: &[Filter], limitation: &Limitation) -> Result<(), Error> {
+    if filters.len() > limitation.max_filters {
+        return Err(Error::Invalid(format!(
+            "more than {} filters",
+            limitation.max_filters
+        )));
+    }
+    for f in filters {
+        if f.ids.len() > limitation.max_filter_ids {
+            return Err(Error::Invalid(format!(
+                "more than {} ids in a filter",
+                limitation.max_filter_ids
+            )));
+        }
+        if f.authors.len() > limitation.max_filter_authors {
+            return Err(Error::Invalid(format!(
+                "more than {} authors in a filter",
+                limitation.max_filter_authors
+            )));
+        }
+    }
+    Ok(())
+}
+
 // #[derive(Deserialize, Clone, Debug)]
 // #[serde(rename_all = "UPPERCASE", tag = "0")]
 // pub enum IncomingMessage {
@@ -143,6 +254,8 @@ pub enum IncomingMessage {
     Auth(Event),
     /// nip-45
     Count(Subscription),
+    /// operator command, see `admin`
+    Admin(Vec<Value>),
     Unknown(String, Vec<Value>),
 }
 
@@ -154,6 +267,7 @@ impl IncomingMessage {
             IncomingMessage::Req(_) => "REQ",
             IncomingMessage::Auth(_) => "AUTH",
             IncomingMessage::Count(_) => "COUNT",
+            IncomingMessage::Admin(_) => "ADMIN",
             IncomingMessage::Unknown(cmd, _) => cmd,
         }
     }
@@ -165,6 +279,7 @@ impl IncomingMessage {
             IncomingMessage::Req(_) => Some("REQ"),
             IncomingMessage::Auth(_) => Some("AUTH"),
             IncomingMessage::Count(_) => Some("COUNT"),
+            IncomingMessage::Admin(_) => Some("ADMIN"),
             IncomingMessage::Unknown(_, _) => None,
         }
     }
@@ -215,6 +330,9 @@ impl<'de> Visitor<'de> for MessageVisitor {
                 let r = Vec::<Filter>::deserialize(de::value::SeqAccessDeserializer::new(seq))?;
                 Ok(IncomingMessage::Count(Subscription { id: t, filters: r }))
             }
+            "ADMIN" => Ok(IncomingMessage::Admin(Vec::<Value>::deserialize(
+                de::value::SeqAccessDeserializer::new(seq),
+            )?)),
             _ => Ok(IncomingMessage::Unknown(
                 t.to_string(),
                 Vec::<Value>::deserialize(de::value::SeqAccessDeserializer::new(seq))?,
@@ -281,6 +399,62 @@ pub struct Subscription {
 //     }
 // }
 
+/// NIP-01 machine-readable prefixes of rejected OK and CLOSED messages
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Prefix {
+    Duplicate,
+    Pow,
+    Blocked,
+    RateLimited,
+    Invalid,
+    Restricted,
+    AuthRequired,
+    Error,
+}
+
+impl Prefix {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Prefix::Duplicate => "duplicate",
+            Prefix::Pow => "pow",
+            Prefix::Blocked => "blocked",
+            Prefix::RateLimited => "rate-limited",
+            Prefix::Invalid => "invalid",
+            Prefix::Restricted => "restricted",
+            Prefix::AuthRequired => "auth-required",
+            Prefix::Error => "error",
+        }
+    }
+
+    /// Prefix a human readable message, `rate-limited: slow down`
+    pub fn with(&self, message: &str) -> String {
+        format!("{}: {}", self.as_str(), message)
+    }
+
+    /// The prefix a message already starts with
+    pub fn of(message: &str) -> Option<Prefix> {
+        let (prefix, _) = message.split_once(':')?;
+        [
+            Prefix::Duplicate,
+            Prefix::Pow,
+            Prefix::Blocked,
+            Prefix::RateLimited,
+            Prefix::Invalid,
+            Prefix::Restricted,
+            Prefix::AuthRequired,
+            Prefix::Error,
+        ]
+        .into_iter()
+        .find(|p| p.as_str() == prefix)
+    }
+}
+
+impl Display for Prefix {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
 /// The message sent to the client
 #[derive(Message, Clone, Debug)]
 #[rtype(result = "()")]
@@ -306,6 +480,28 @@ impl OutgoingMessage {
     pub fn ok(event_id: &str, saved: bool, message: &str) -> Self {
         Self(json!(["OK", event_id, saved, message]).to_string())
     }
+
+    /// Answer of an admin command, the error carries a NIP-01 prefix
+    pub fn admin(verb: &str, result: Result<Value, String>) -> Self {
+        match result {
+            Ok(value) => Self(json!(["ADMIN", verb, true, value]).to_string()),
+            Err(reason) => Self(json!(["ADMIN", verb, false, reason]).to_string()),
+        }
+    }
+
+    /// OK false for a rejected event
+    pub fn rejected(event_id: &str, prefix: Prefix, message: &str) -> Self {
+        Self::ok(event_id, false, &prefix.with(message))
+    }
+
+    /// CLOSED for a refused subscription
+    pub fn refused(sub_id: &str, prefix: Prefix, message: &str) -> Self {
+        Self::closed(sub_id, &prefix.with(message))
+    }
+
+    pub fn count(sub_id: &str, count: u64) -> Self {
+        Self(json!(["COUNT", sub_id, {"count": count}]).to_string())
+    }
 }
 
 impl Display for OutgoingMessage {
@@ -401,12 +597,76 @@ pub struct Dispatch {
     pub event: Event,
 }
 
+/// Event accepted from a client, sent to the registered fanout bus
+#[derive(Message, Clone, Debug)]
+#[rtype(result = "()")]
+pub struct Accepted {
+    pub event: Event,
+    /// NIP-42 pubkey of the session that sent the event
+    pub auth_pubkey: Option<String>,
+}
+
+/// Event accepted by another relay instance, received from the fanout bus
+#[derive(Message, Clone, Debug)]
+#[rtype(result = "()")]
+pub struct RemoteEvent {
+    pub event: Event,
+    /// NIP-42 pubkey of the session that sent the event to the other instance
+    pub auth_pubkey: Option<String>,
+}
+
+/// Event created by the relay itself, e.g. service notifications.
+/// It is written, dispatched and fanned out like a client event.
+#[derive(Message, Clone, Debug)]
+#[rtype(result = "()")]
+pub struct RelayEvent {
+    pub event: Event,
+}
+
+/// Message sent by an extension to a single session, e.g. a NOTICE
+#[derive(Message, Clone, Debug)]
+#[rtype(result = "()")]
+pub struct SessionMessage {
+    pub id: usize,
+    pub msg: OutgoingMessage,
+}
+
+/// Stop serving sessions before the relay exits: NOTICE every session,
+/// CLOSED every subscription and write the pending events
+#[derive(Message, Clone, Debug)]
+#[rtype(result = "()")]
+pub struct Shutdown {
+    pub message: String,
+}
+
+/// Number of subscriptions of every session with subscriptions
+#[derive(Message, Clone, Debug)]
+#[rtype(result = "HashMap<usize, usize>")]
+pub struct SubscriptionCounts;
+
+/// Write the pending events now
+#[derive(Message, Clone, Debug)]
+#[rtype(result = "()")]
+pub struct Flush;
+
+/// Register the fanout bus receiving accepted events
+#[derive(Message, Clone, Debug)]
+#[rtype(result = "()")]
+pub struct SetFanout(pub Recipient<Accepted>);
+
+/// Register the extensions notified of stored events
+#[derive(Message, Clone, Debug)]
+#[rtype(result = "()")]
+pub struct SetExtensions(pub Arc<RwLock<Extensions>>);
+
 #[derive(Message, Clone, Debug)]
 #[rtype(result = "()")]
 pub struct SubscribeResult {
     pub id: usize,
     pub sub_id: String,
     pub msg: OutgoingMessage,
+    /// The dispatched event, checked by extensions before it reaches the session
+    pub event: Option<Arc<Event>>,
 }
 
 #[cfg(test)]
@@ -471,6 +731,12 @@ mod tests {
         let msg: IncomingMessage = serde_json::from_str(r#"["COUNT", "sub_id1", {}]"#)?;
         assert!(matches!(msg, IncomingMessage::Count(sub) if sub.id == "sub_id1"));
 
+        // admin
+        let msg: IncomingMessage = serde_json::from_str(r#"["ADMIN", "kick", 3]"#)?;
+        assert!(
+            matches!(msg, IncomingMessage::Admin(ref args) if args.len() == 2 && args[0] == "kick")
+        );
+
         Ok(())
     }
 
@@ -489,6 +755,21 @@ mod tests {
         let msg = OutgoingMessage::closed("1", "hello");
         let json = msg.to_string();
         assert_eq!(json, r#"["CLOSED","1","hello"]"#);
+        let msg = OutgoingMessage::refused("1", Prefix::RateLimited, "slow down");
+        assert_eq!(
+            msg.to_string(),
+            r#"["CLOSED","1","rate-limited: slow down"]"#
+        );
+        let msg = OutgoingMessage::rejected("id", Prefix::AuthRequired, "sign in");
+        assert_eq!(
+            msg.to_string(),
+            r#"["OK","id",false,"auth-required: sign in"]"#
+        );
+        assert_eq!(
+            Prefix::of("blocked: event marked as protected"),
+            Some(Prefix::Blocked)
+        );
+        assert_eq!(Prefix::of("lmdb: map full"), None);
 
         // let event = Event::default();
         // let msg = OutgoingMessage("id".to_owned(), Some(event));
@@ -551,6 +832,93 @@ mod tests {
             matches!(msg.msg, IncomingMessage::Req(sub) if sub.filters.get(0).unwrap().limit.unwrap() == 200)
         );
 
+        // filter complexity
+        limitation.max_filters = 2;
+        limitation.max_filter_authors = 2;
+        let msg: IncomingMessage = serde_json::from_str(r#"["REQ", "sub_id1", {}, {}, {}]"#)?;
+        let mut msg = ClientMessage::new(1, "text".to_string(), msg);
+        assert_eq!(
+            msg.validate(&limitation).unwrap_err().to_string(),
+            "invalid: more than 2 filters"
+        );
+        let authors = format!(
+            r#"["{}","{}","{}"]"#,
+            "a".repeat(64),
+            "b".repeat(64),
+            "c".repeat(64)
+        );
+        let msg: IncomingMessage = serde_json::from_str(&format!(
+            r#"["COUNT", "sub_id1", {{"authors": {}}}]"#,
+            authors
+        ))?;
+        let mut msg = ClientMessage::new(1, "text".to_string(), msg);
+        assert_eq!(
+            msg.validate(&limitation).unwrap_err().to_string(),
+            "invalid: more than 2 authors in a filter"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn validate_limits() -> Result<()> {
+        let msg: IncomingMessage = serde_json::from_str(
+            r#"["EVENT", {
+            "content": "Good morning everyone 😃",
+            "created_at": 1680690006,
+            "id": "332747c0fab8a1a92def4b0937e177be6df4382ce6dd7724f86dc4710b7d4d7d",
+            "kind": 1,
+            "pubkey": "7abf57d516b1ff7308ca3bd5650ea6a4674d469c7c5057b1d005fb13d218bfef",
+            "sig": "ef4ff4f69ac387239eb1401fb07d7a44a5d5d57127e0dc3466a0403cf7d5486b668608ebfcbe9ff1f8d3b5d710545999fe08ee767284ec0b474e4cf92537678f",
+            "tags": []
+          }]"#,
+        )?;
+        let msg = ClientMessage::new(1, "text".to_string(), msg);
+        let mut limits = Limits::default();
+        assert!(msg.validate_limits(&limits).is_ok());
+
+        limits.max_content_length = Some(10);
+        assert!(msg.validate_limits(&limits).is_err());
+
+        // a kind rule overrides the default
+        limits.kinds.insert("kind_1".to_owned(), 64);
+        assert!(msg.validate_limits(&limits).is_ok());
+        limits.kinds.insert("kind_1".to_owned(), 4);
+        let err = msg.validate_limits(&limits).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid: content of kind 1 exceeds 4 bytes"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn validate_pow() -> Result<()> {
+        // nip-13 example with 21 leading zero bits, committed target 20
+        let msg: IncomingMessage = serde_json::from_str(
+            r#"["EVENT", {
+            "id": "000006d8c378af1779d2feebc7603a125d99eca0ccf1085959b307f64e5dd358",
+            "pubkey": "a48380f4cfcc1ad5378294fcac36439770f9c878dd880ffa94bb74ea54a6f243",
+            "created_at": 1651794653,
+            "kind": 1,
+            "tags": [["nonce", "776797", "20"]],
+            "content": "It's just me mining my own business",
+            "sig": "284622fc0a3f4f1303455d5175f7ba962a3300d136085b9566801bc2e0699de0c7e31e44c81fb40ad9049173742e904713c3594a1da0fc5d2382a25c11aba977"
+          }]"#,
+        )?;
+        let msg = ClientMessage::new(1, "text".to_string(), msg);
+        let mut pow = Pow::default();
+        assert!(msg.validate_pow(&pow).is_ok());
+
+        pow.min_difficulty = 20;
+        assert!(msg.validate_pow(&pow).is_ok());
+        // a kind rule overrides the default
+        pow.kinds.insert("kind_1".to_owned(), 21);
+        let err = msg.validate_pow(&pow).unwrap_err();
+        assert_eq!(err.to_string(), "pow: committed target 20 is less than 21");
+        pow.kinds.insert("kind_1".to_owned(), 22);
+        let err = msg.validate_pow(&pow).unwrap_err();
+        assert_eq!(err.to_string(), "pow: difficulty 21 is less than 22");
         Ok(())
     }
 }
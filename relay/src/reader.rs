@@ -1,8 +1,13 @@
 use crate::{message::*, setting::SettingWrapper, Result};
 use actix::prelude::*;
-use metrics::histogram;
-use nostr_db::Db;
-use std::{sync::Arc, time::Instant};
+use metrics::{counter, histogram};
+use nostr_db::{now, Db, Filter, Stats};
+use std::{
+    collections::BTreeSet,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tracing::warn;
 
 /// Requst by filter
 /// Concurrent read events from db
@@ -40,22 +45,42 @@ impl Reader {
 
         // Otherwise, perform normal database query
         let reader = self.db.reader()?;
-        let timeout = self.setting.read().data.db_query_timeout;
+        let (timeout, slow_time, slow_keys, slow_kinds) = {
+            let r = self.setting.read();
+            (
+                r.data.db_query_timeout,
+                r.data.slow_query_time,
+                r.data.slow_query_keys,
+                r.data.slow_query_kinds.clone(),
+            )
+        };
+        let now = now();
         for filter in &msg.subscription.filters {
             let start = Instant::now();
             let mut iter = self.db.iter::<String, _>(&reader, filter)?;
+            // NIP-40: expired events may remain until the writer sweeps them
+            iter.skip_expired(now);
             if let Some(time) = timeout {
                 iter.scan_time(time.into(), 2000);
             }
-            for event in iter {
+            let mut sent = 0;
+            for event in iter.by_ref() {
                 let event = event?;
+                sent += 1;
                 self.addr.do_send(ReadEventResult {
                     id: msg.id,
                     sub_id: msg.subscription.id.clone(),
                     msg: OutgoingMessage::event(&msg.subscription.id, &event),
                 });
             }
-            histogram!("nostr_relay_db_get").record(start.elapsed());
+            let elapsed = start.elapsed();
+            histogram!("nostr_relay_db_get").record(elapsed);
+            let stats = iter.stats();
+            if slow_time.map_or(false, |t| elapsed > *t)
+                || slow_keys.map_or(false, |k| stats.scan_index > k)
+            {
+                slow_query(filter, elapsed, &stats, sent, &slow_kinds);
+            }
         }
         self.addr.do_send(ReadEventResult {
             id: msg.id,
@@ -67,6 +92,42 @@ impl Reader {
     }
 }
 
+/// Counter labels of the kinds of a filter, `any` when it has none and `other`
+/// for kinds outside `known` so clients cannot grow the label set
+fn kind_labels(filter: &Filter, known: &[u16]) -> BTreeSet<String> {
+    if filter.kinds.is_empty() {
+        return BTreeSet::from(["any".to_owned()]);
+    }
+    filter
+        .kinds
+        .iter()
+        .map(|k| {
+            if known.contains(k) {
+                k.to_string()
+            } else {
+                "other".to_owned()
+            }
+        })
+        .collect()
+}
+
+/// Log an expensive filter, a high scan count for few events points to a missing index
+fn slow_query(filter: &Filter, elapsed: Duration, stats: &Stats, sent: u64, known: &[u16]) {
+    warn!(
+        kinds = ?filter.kinds,
+        elapsed = ?elapsed,
+        scan_index = stats.scan_index,
+        get_index = stats.get_index,
+        get_data = stats.get_data,
+        events = sent,
+        "slow query: {:?}",
+        filter
+    );
+    for kind in kind_labels(filter, known) {
+        counter!("nostr_relay_db_slow_query", "kind" => kind).increment(1);
+    }
+}
+
 impl Actor for Reader {
     type Context = SyncContext<Self>;
     fn started(&mut self, _ctx: &mut Self::Context) {}
@@ -76,8 +137,9 @@ impl Handler<ReadEvent> for Reader {
     type Result = ();
     fn handle(&mut self, msg: ReadEvent, _: &mut Self::Context) {
         if let Err(err) = self.read(&msg) {
-            let m = OutgoingMessage::closed(
+            let m = OutgoingMessage::refused(
                 msg.subscription.id.as_str(),
+                Prefix::Error,
                 &format!("get event error: {}", err),
             );
             self.addr.do_send(ReadEventResult {
@@ -159,4 +221,21 @@ mod tests {
         assert_eq!(r.len(), 8);
         Ok(())
     }
+
+    #[test]
+    fn kinds() -> Result<()> {
+        let filter = Filter::from_str(r#"{"kinds": [445, 1, 31234, 5000]}"#)?;
+        assert_eq!(
+            kind_labels(&filter, &[1, 445])
+                .into_iter()
+                .collect::<Vec<_>>(),
+            vec!["1", "445", "other"]
+        );
+        let filter = Filter::from_str("{}")?;
+        assert_eq!(
+            kind_labels(&filter, &[1]).into_iter().collect::<Vec<_>>(),
+            vec!["any"]
+        );
+        Ok(())
+    }
 }
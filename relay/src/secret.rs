@@ -0,0 +1,132 @@
+//! `secret://` references in the config
+//!
+//! Extension settings and a few env vars may hold `secret://<name>` instead of
+//! a sensitive value. A secrets provider (`[secrets] provider`) loads the
+//! referenced values before the relay starts and refreshes them when they
+//! rotate; reading the config then replaces every reference with its loaded
+//! value and fails on a reference that was not loaded.
+
+use crate::{Error, Result};
+use parking_lot::RwLock;
+use serde_json::Value;
+use std::{collections::HashMap, sync::OnceLock};
+use tracing::error;
+
+pub const SCHEME: &str = "secret://";
+
+fn values() -> &'static RwLock<HashMap<String, String>> {
+    static VALUES: OnceLock<RwLock<HashMap<String, String>>> = OnceLock::new();
+    VALUES.get_or_init(Default::default)
+}
+
+/// Is the value a `secret://` reference
+pub fn is_reference(value: &str) -> bool {
+    value.starts_with(SCHEME)
+}
+
+/// Store the value loaded for a reference, returns true if it changed
+pub fn set(reference: &str, value: String) -> bool {
+    let mut values = values().write();
+    if values.get(reference) == Some(&value) {
+        return false;
+    }
+    values.insert(reference.to_owned(), value);
+    true
+}
+
+/// Loaded value of a reference
+pub fn get(reference: &str) -> Option<String> {
+    values().read().get(reference).cloned()
+}
+
+/// The value itself, or the loaded value of a reference
+pub fn resolve(value: &str) -> Result<String> {
+    if !is_reference(value) {
+        return Ok(value.to_owned());
+    }
+    get(value).ok_or_else(|| Error::Message(format!("config secret {} is not loaded", value)))
+}
+
+/// Replace the references in a json value
+pub fn resolve_json(value: &mut Value) -> Result<()> {
+    match value {
+        Value::String(s) if is_reference(s) => *s = resolve(s)?,
+        Value::Array(list) => list.iter_mut().try_for_each(resolve_json)?,
+        Value::Object(map) => map.values_mut().try_for_each(resolve_json)?,
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Collect the references in a json value
+pub fn references(value: &Value, out: &mut Vec<String>) {
+    match value {
+        Value::String(s) if is_reference(s) => {
+            if !out.contains(s) {
+                out.push(s.clone());
+            }
+        }
+        Value::Array(list) => list.iter().for_each(|v| references(v, out)),
+        Value::Object(map) => map.values().for_each(|v| references(v, out)),
+        _ => {}
+    }
+}
+
+/// Env var with a reference resolved, an unloaded reference is logged and ignored
+pub fn env(name: &str) -> Option<String> {
+    let value = std::env::var(name).ok()?;
+    match resolve(&value) {
+        Ok(value) => Some(value),
+        Err(e) => {
+            error!("{}: {}", name, e);
+            None
+        }
+    }
+}
+
+/// Mask the loaded secret values in a text, for logging the setting
+pub fn redact(text: &str) -> String {
+    let mut text = text.to_owned();
+    for value in values().read().values().filter(|v| !v.is_empty()) {
+        let escaped = format!("{:?}", value);
+        text = text
+            .replace(&escaped[1..escaped.len() - 1], "<secret>")
+            .replace(value.as_str(), "<secret>");
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn resolve_references() {
+        set("secret://test-db-url", "postgres://u:p@db/relay".to_owned());
+        assert!(!set(
+            "secret://test-db-url",
+            "postgres://u:p@db/relay".to_owned()
+        ));
+
+        let mut value = json!({
+            "database_url": "secret://test-db-url",
+            "nested": { "list": ["plain", "secret://test-db-url"] },
+            "port": 1
+        });
+        let mut found = vec![];
+        references(&value, &mut found);
+        assert_eq!(found, vec!["secret://test-db-url".to_owned()]);
+
+        resolve_json(&mut value).unwrap();
+        assert_eq!(value["database_url"], "postgres://u:p@db/relay");
+        assert_eq!(value["nested"]["list"][1], "postgres://u:p@db/relay");
+        assert_eq!(value["nested"]["list"][0], "plain");
+        assert!(resolve_json(&mut json!(["secret://test-missing"])).is_err());
+
+        assert_eq!(
+            redact(&format!("{:?}", value)).matches("<secret>").count(),
+            2
+        );
+    }
+}
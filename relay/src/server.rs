@@ -1,8 +1,17 @@
-use crate::{message::*, setting::SettingWrapper, Reader, Subscriber, Writer};
+use crate::{
+    admin::AdminCommand, message::*, setting::SettingWrapper, Extensions, Reader, Subscriber,
+    Writer,
+};
 use actix::prelude::*;
+use metrics::counter;
 use nostr_db::{CheckEventResult, Db};
+use parking_lot::RwLock;
+use serde_json::{json, Value};
 use std::{collections::HashMap, sync::Arc};
-use tracing::info;
+use tracing::{info, warn};
+
+/// Write id of events created by the relay itself, never assigned to a session
+const RELAY_ID: usize = usize::MAX;
 
 /// Server
 #[derive(Debug)]
@@ -12,11 +21,19 @@ pub struct Server {
     reader: Addr<Reader>,
     subscriber: Addr<Subscriber>,
     sessions: HashMap<usize, Recipient<OutgoingMessage>>,
+    fanout: Option<Recipient<Accepted>>,
+    extensions: Option<Arc<RwLock<Extensions>>>,
+    /// NIP-42 pubkeys of authenticated sessions
+    auth: HashMap<usize, String>,
+    kicks: HashMap<usize, Recipient<Kick>>,
+    setting: SettingWrapper,
 }
 
 impl Server {
     pub fn create_with(db: Arc<Db>, setting: SettingWrapper) -> Addr<Server> {
         let r = setting.read();
+        let retention = r.retention.clone();
+        let data = r.data.clone();
         let num = if r.thread.reader == 0 {
             num_cpus::get()
         } else {
@@ -25,7 +42,13 @@ impl Server {
         drop(r);
 
         Server::create(|ctx| {
-            let writer = Writer::new(Arc::clone(&db), ctx.address().recipient()).start();
+            let mut writer = Writer::new(Arc::clone(&db), ctx.address().recipient());
+            writer.retention = retention;
+            writer.setting = Some(setting.clone());
+            writer.write_batch_size = data.write_batch_size;
+            writer.write_interval_ms = data.write_interval.as_millis() as u64;
+            writer.sync_interval = data.sync_interval.map(Into::into);
+            let writer = writer.start();
             let subscriber = Subscriber::new(ctx.address().recipient(), setting.clone()).start();
             let addr = ctx.address().recipient();
             info!("starting {} reader workers", num);
@@ -39,6 +62,11 @@ impl Server {
                 reader,
                 subscriber,
                 sessions: HashMap::new(),
+                fanout: None,
+                extensions: None,
+                auth: HashMap::new(),
+                kicks: HashMap::new(),
+                setting,
             }
         })
     }
@@ -48,6 +76,27 @@ impl Server {
             addr.do_send(msg);
         }
     }
+
+    /// Send a live event within the session's mailbox capacity,
+    /// a session that cannot keep up misses it or is disconnected
+    fn send_live(&mut self, id: usize, msg: OutgoingMessage) {
+        let Some(addr) = self.sessions.get(&id) else {
+            return;
+        };
+        if let Err(SendError::Full(_)) = addr.try_send(msg) {
+            counter!("nostr_relay_outbound_dropped").increment(1);
+            if self.setting.read().network.disconnect_slow_consumers {
+                if let Some(kick) = self.kicks.remove(&id) {
+                    counter!("nostr_relay_slow_consumer_disconnected").increment(1);
+                    kick.do_send(Kick {
+                        reason: "slow consumer: outbound queue full".to_owned(),
+                        cause: "slow consumer",
+                    });
+                    self.sessions.remove(&id);
+                }
+            }
+        }
+    }
 }
 
 /// Make actor from `Server`
@@ -67,11 +116,14 @@ impl Actor for Server {
 impl Handler<Connect> for Server {
     type Result = usize;
     fn handle(&mut self, msg: Connect, _ctx: &mut Self::Context) -> Self::Result {
-        if self.id == usize::MAX {
+        if self.id >= RELAY_ID - 1 {
             self.id = 0;
         }
         self.id += 1;
         self.sessions.insert(self.id, msg.addr);
+        if let Some(kick) = msg.kick {
+            self.kicks.insert(self.id, kick);
+        }
         // send id back
         self.id
     }
@@ -84,6 +136,8 @@ impl Handler<Disconnect> for Server {
     fn handle(&mut self, msg: Disconnect, _: &mut Self::Context) {
         // remove address
         self.sessions.remove(&msg.id);
+        self.auth.remove(&msg.id);
+        self.kicks.remove(&msg.id);
 
         // clear subscriptions
         self.subscriber.do_send(Unsubscribe {
@@ -131,23 +185,24 @@ impl Handler<ClientMessage> for Server {
                                 Subscribed::Overlimit => {
                                     act.send_to_client(
                                         session_id,
-                                        OutgoingMessage::closed(
+                                        OutgoingMessage::refused(
                                             &sub_id,
-                                            "Number of subscriptions exceeds limit",
+                                            Prefix::RateLimited,
+                                            "number of subscriptions exceeds limit",
                                         ),
                                     );
                                 }
                                 Subscribed::InvalidIdLength => {
                                     act.send_to_client(
                                         session_id,
-                                        OutgoingMessage::closed(&sub_id, "Subscription id should be non-empty string of max length 64 chars"),
+                                        OutgoingMessage::refused(&sub_id, Prefix::Invalid, "subscription id should be non-empty string of max length 64 chars"),
                                     );
                                 }
                             },
                             Err(_err) => {
                                 act.send_to_client(
                                     session_id,
-                                    OutgoingMessage::closed(&sub_id, "Something is wrong"),
+                                    OutgoingMessage::refused(&sub_id, Prefix::Error, "subscription failed"),
                                 );
                             }
                         }
@@ -183,9 +238,33 @@ impl Handler<WriteEventResult> for Server {
                         OutgoingMessage::ok(&event_id, false, "replaced: have newer event")
                     }
                 };
-                self.send_to_client(id, out_msg);
+                if id == RELAY_ID {
+                    if !matches!(
+                        result,
+                        CheckEventResult::Ok(_) | CheckEventResult::Duplicate
+                    ) {
+                        warn!("relay event {} rejected: {:?}", event_id, result);
+                    }
+                } else {
+                    self.send_to_client(id, out_msg);
+                }
                 // dispatch event to subscriber
                 if let CheckEventResult::Ok(_num) = result {
+                    // session ids start at 1, id 0 is an event from another instance
+                    if id != 0 {
+                        if let Some(fanout) = &self.fanout {
+                            fanout.do_send(Accepted {
+                                event: event.clone(),
+                                auth_pubkey: self.auth.get(&id).cloned(),
+                            });
+                        }
+                    }
+                    // the instance that accepted an event from its client or emitted it processes it
+                    if id != 0 {
+                        if let Some(extensions) = &self.extensions {
+                            extensions.read().call_event_stored(&event);
+                        }
+                    }
                     self.subscriber.do_send(Dispatch { id, event });
                 }
             }
@@ -206,16 +285,187 @@ impl Handler<ReadEventResult> for Server {
 impl Handler<SubscribeResult> for Server {
     type Result = ();
     fn handle(&mut self, msg: SubscribeResult, _: &mut Self::Context) {
+        if let (Some(event), Some(extensions)) = (&msg.event, &self.extensions) {
+            if !extensions
+                .read()
+                .call_broadcast(msg.id, self.auth.get(&msg.id), event)
+            {
+                counter!("nostr_relay_broadcast_filtered", "kind" => event.kind().to_string())
+                    .increment(1);
+                return;
+            }
+        }
+        if msg.event.is_some() {
+            self.send_live(msg.id, msg.msg);
+        } else {
+            self.send_to_client(msg.id, msg.msg);
+        }
+    }
+}
+
+/// Handler for Shutdown message.
+///
+/// Resolves after the pending events were written
+impl Handler<Shutdown> for Server {
+    type Result = ResponseFuture<()>;
+
+    fn handle(&mut self, msg: Shutdown, _: &mut Self::Context) -> Self::Result {
+        info!("Shutdown {} sessions", self.sessions.len());
+        let notice = OutgoingMessage::notice(&msg.message);
+        for addr in self.sessions.values() {
+            addr.do_send(notice.clone());
+        }
+        self.subscriber.do_send(msg);
+        let writer = self.writer.clone();
+        Box::pin(async move {
+            if let Err(err) = writer.send(Flush).await {
+                warn!("Failed to write pending events on shutdown: {}", err);
+            }
+        })
+    }
+}
+
+/// Handler for admin commands of operator sessions.
+impl Handler<AdminCommand> for Server {
+    type Result = ResponseFuture<Result<Value, String>>;
+
+    fn handle(&mut self, msg: AdminCommand, _: &mut Self::Context) -> Self::Result {
+        match msg {
+            AdminCommand::Stats => {
+                let sessions = self.sessions.len();
+                let authenticated = self.auth.len();
+                let counts = self.subscriber.send(SubscriptionCounts);
+                Box::pin(async move {
+                    let counts = counts
+                        .await
+                        .map_err(|e| Prefix::Error.with(&e.to_string()))?;
+                    Ok(json!({
+                        "sessions": sessions,
+                        "authenticated": authenticated,
+                        "subscriptions": counts.values().sum::<usize>(),
+                    }))
+                })
+            }
+            AdminCommand::Sessions => {
+                let mut sessions = self
+                    .sessions
+                    .keys()
+                    .map(|id| (*id, self.auth.get(id).cloned()))
+                    .collect::<Vec<_>>();
+                sessions.sort_unstable();
+                let counts = self.subscriber.send(SubscriptionCounts);
+                Box::pin(async move {
+                    let counts = counts
+                        .await
+                        .map_err(|e| Prefix::Error.with(&e.to_string()))?;
+                    Ok(sessions
+                        .into_iter()
+                        .map(|(id, pubkey)| {
+                            json!({
+                                "id": id,
+                                "pubkey": pubkey,
+                                "subscriptions": counts.get(&id).copied().unwrap_or_default(),
+                            })
+                        })
+                        .collect())
+                })
+            }
+            AdminCommand::Kick { id, reason } => {
+                let result = match self.kicks.remove(&id) {
+                    Some(kick) => {
+                        info!("Admin kicked session {}: {}", id, reason);
+                        kick.do_send(Kick {
+                            reason,
+                            cause: "admin",
+                        });
+                        self.sessions.remove(&id);
+                        Ok(json!({ "id": id }))
+                    }
+                    None => Err(Prefix::Invalid.with(&format!("no session {}", id))),
+                };
+                Box::pin(async move { result })
+            }
+            AdminCommand::BroadcastNotice(message) => {
+                let notice = OutgoingMessage::notice(&message);
+                for addr in self.sessions.values() {
+                    addr.do_send(notice.clone());
+                }
+                let sent = self.sessions.len();
+                Box::pin(async move { Ok(json!({ "sessions": sent })) })
+            }
+            AdminCommand::ReloadConfig => {
+                Box::pin(async { Err(Prefix::Error.with("reload-config runs in the session")) })
+            }
+        }
+    }
+}
+
+/// Handler for Authenticated message.
+impl Handler<Authenticated> for Server {
+    type Result = ();
+    fn handle(&mut self, msg: Authenticated, _: &mut Self::Context) {
+        if self.sessions.contains_key(&msg.id) {
+            self.auth.insert(msg.id, msg.pubkey);
+        }
+    }
+}
+
+/// Handler for SetFanout message.
+impl Handler<SetFanout> for Server {
+    type Result = ();
+    fn handle(&mut self, msg: SetFanout, _: &mut Self::Context) {
+        self.fanout = Some(msg.0);
+    }
+}
+
+/// Handler for SetExtensions message.
+impl Handler<SetExtensions> for Server {
+    type Result = ();
+    fn handle(&mut self, msg: SetExtensions, _: &mut Self::Context) {
+        self.extensions = Some(msg.0);
+    }
+}
+
+/// Handler for RemoteEvent message.
+///
+/// Store the event locally, it is dispatched to local subscribers once written
+/// and never published back to the fanout bus.
+impl Handler<RemoteEvent> for Server {
+    type Result = ();
+    fn handle(&mut self, msg: RemoteEvent, _: &mut Self::Context) {
+        self.writer.do_send(WriteEvent {
+            id: 0,
+            event: msg.event,
+        })
+    }
+}
+
+/// Handler for SessionMessage message.
+impl Handler<SessionMessage> for Server {
+    type Result = ();
+    fn handle(&mut self, msg: SessionMessage, _: &mut Self::Context) {
         self.send_to_client(msg.id, msg.msg);
     }
 }
 
+/// Handler for RelayEvent message.
+impl Handler<RelayEvent> for Server {
+    type Result = ();
+    fn handle(&mut self, msg: RelayEvent, _: &mut Self::Context) {
+        self.writer.do_send(WriteEvent {
+            id: RELAY_ID,
+            event: msg.event,
+        })
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::{temp_data_path, Setting};
     use actix_rt::time::sleep;
     use anyhow::Result;
+    use nostr_db::Event;
     use parking_lot::RwLock;
     use std::time::Duration;
 
@@ -232,6 +482,18 @@ mod tests {
         }
     }
 
+    /// Records the ids of stored events
+    struct Stored(Arc<RwLock<Vec<String>>>);
+    impl crate::Extension for Stored {
+        fn name(&self) -> &'static str {
+            "stored"
+        }
+
+        fn event_stored(&self, event: &nostr_db::Event) {
+            self.0.write().push(event.id_str());
+        }
+    }
+
     #[actix_rt::test]
     async fn message() -> Result<()> {
         let db = Arc::new(Db::open(temp_data_path("server")?)?);
@@ -264,8 +526,12 @@ mod tests {
         let addr = receiver.recipient();
 
         let server = Server::create_with(db, Setting::default().into());
+        let stored = Arc::new(RwLock::new(Vec::new()));
+        let mut extensions = Extensions::default();
+        extensions.add(Stored(stored.clone()));
+        server.do_send(SetExtensions(Arc::new(RwLock::new(extensions))));
 
-        let id = server.send(Connect { addr }).await?;
+        let id = server.send(Connect { addr, kick: None }).await?;
         assert_eq!(id, 1);
 
         // Unsupported
@@ -311,6 +577,7 @@ mod tests {
                 assert!(w.get(1).unwrap().0.contains("EVENT"));
                 w.clear();
             }
+            assert_eq!(stored.read().len(), 1);
             // repeat write
             server.send(client_msg.clone()).await?;
             sleep(Duration::from_millis(200)).await;
@@ -321,6 +588,8 @@ mod tests {
                 // No subscription message because the message is duplicated
                 w.clear();
             }
+            // nor a stored notification
+            assert_eq!(stored.read().len(), 1);
 
             // ephemeral event
             {
@@ -383,4 +652,231 @@ mod tests {
 
         Ok(())
     }
+
+    #[actix_rt::test]
+    async fn shutdown() -> Result<()> {
+        let db = Arc::new(Db::open(temp_data_path("server_shutdown")?)?);
+        let receiver = Receiver::default();
+        let messages = receiver.0.clone();
+        let addr = receiver.start().recipient();
+        let server = Server::create_with(db, Setting::default().into());
+        let id = server.send(Connect { addr, kick: None }).await?;
+
+        let text = r#"["REQ", "1", {}]"#.to_owned();
+        let msg = serde_json::from_str::<IncomingMessage>(&text)?;
+        server.send(ClientMessage::new(id, text, msg)).await?;
+        sleep(Duration::from_millis(50)).await;
+        messages.write().clear();
+
+        // pending events are written before the shutdown resolves, not on the next interval
+        let key = nostr_db::secp256k1::Keypair::from_seckey_str(
+            nostr_db::secp256k1::SECP256K1,
+            &"01".repeat(32),
+        )?;
+        let event = Event::create(&key, nostr_db::now(), 1, vec![], "bye".to_owned())?;
+        let text = format!(r#"["EVENT", {}]"#, event);
+        let msg = serde_json::from_str::<IncomingMessage>(&text)?;
+        server.send(ClientMessage::new(id, text, msg)).await?;
+        server
+            .send(Shutdown {
+                message: "relay is shutting down".to_owned(),
+            })
+            .await?;
+        sleep(Duration::from_millis(20)).await;
+
+        let r = messages.read();
+        assert!(r
+            .iter()
+            .any(|m| m.0 == r#"["NOTICE","relay is shutting down"]"#));
+        assert!(r
+            .iter()
+            .any(|m| m.0 == r#"["CLOSED","1","error: relay is shutting down"]"#));
+        assert!(r
+            .iter()
+            .any(|m| m.0.starts_with(r#"["OK""#) && m.0.contains("true")));
+        Ok(())
+    }
+
+    #[derive(Default)]
+    struct Bus(Arc<RwLock<Vec<Event>>>);
+    impl Actor for Bus {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<Accepted> for Bus {
+        type Result = ();
+        fn handle(&mut self, msg: Accepted, _ctx: &mut Self::Context) {
+            self.0.write().push(msg.event);
+        }
+    }
+
+    /// Session with a tiny mailbox
+    struct Slow(Arc<RwLock<Vec<String>>>);
+    impl Actor for Slow {
+        type Context = Context<Self>;
+        fn started(&mut self, ctx: &mut Self::Context) {
+            ctx.set_mailbox_capacity(2);
+        }
+    }
+
+    impl Handler<OutgoingMessage> for Slow {
+        type Result = ();
+        fn handle(&mut self, msg: OutgoingMessage, _ctx: &mut Self::Context) {
+            self.0.write().push(msg.0);
+        }
+    }
+
+    impl Handler<Kick> for Slow {
+        type Result = ();
+        fn handle(&mut self, msg: Kick, _ctx: &mut Self::Context) {
+            self.0.write().push(format!("kick {}", msg.reason));
+        }
+    }
+
+    #[actix_rt::test]
+    async fn slow_consumer() -> Result<()> {
+        let db = Arc::new(Db::open(temp_data_path("server_slow")?)?);
+        let mut setting = Setting::default();
+        setting.network.disconnect_slow_consumers = true;
+        let server = Server::create_with(db, setting.into());
+
+        let received = Arc::new(RwLock::new(Vec::new()));
+        let arbiter = Arbiter::new();
+        let r = received.clone();
+        let slow = Slow::start_in_arbiter(&arbiter.handle(), move |_| Slow(r));
+        let id = server
+            .send(Connect {
+                addr: slow.clone().recipient(),
+                kick: Some(slow.recipient()),
+            })
+            .await?;
+
+        // the session's thread is busy while events arrive
+        arbiter.spawn(async { std::thread::sleep(Duration::from_millis(300)) });
+        let key = nostr_db::secp256k1::Keypair::from_seckey_str(
+            nostr_db::secp256k1::SECP256K1,
+            &"02".repeat(32),
+        )?;
+        let event = Arc::new(Event::create(
+            &key,
+            nostr_db::now(),
+            1,
+            vec![],
+            "live".to_owned(),
+        )?);
+        for i in 0..5 {
+            server
+                .send(SubscribeResult {
+                    id,
+                    sub_id: "1".to_owned(),
+                    msg: OutgoingMessage(format!("live {}", i)),
+                    event: Some(event.clone()),
+                })
+                .await?;
+        }
+        sleep(Duration::from_millis(500)).await;
+
+        let r = received.read();
+        assert!(r.len() < 5);
+        assert_eq!(r.last().unwrap(), "kick slow consumer: outbound queue full");
+        arbiter.stop();
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn fanout() -> Result<()> {
+        let db = Arc::new(Db::open(temp_data_path("server_fanout")?)?);
+        let note = r#"
+        {
+            "content": "Good morning everyone 😃",
+            "created_at": 1680690006,
+            "id": "332747c0fab8a1a92def4b0937e177be6df4382ce6dd7724f86dc4710b7d4d7d",
+            "kind": 1,
+            "pubkey": "7abf57d516b1ff7308ca3bd5650ea6a4674d469c7c5057b1d005fb13d218bfef",
+            "sig": "ef4ff4f69ac387239eb1401fb07d7a44a5d5d57127e0dc3466a0403cf7d5486b668608ebfcbe9ff1f8d3b5d710545999fe08ee767284ec0b474e4cf92537678f",
+            "tags": [["t", "nostr"]]
+          }
+        "#;
+        let event: Event = serde_json::from_str(note)?;
+
+        let receiver = Receiver::default();
+        let messages = receiver.0.clone();
+        let addr = receiver.start().recipient();
+        let bus = Bus::default();
+        let published = bus.0.clone();
+
+        let server = Server::create_with(db, Setting::default().into());
+        server.send(SetFanout(bus.start().recipient())).await?;
+        let id = server.send(Connect { addr, kick: None }).await?;
+
+        let text = r#"["REQ", "1", {}]"#.to_owned();
+        let msg = serde_json::from_str::<IncomingMessage>(&text)?;
+        server.send(ClientMessage::new(id, text, msg)).await?;
+        sleep(Duration::from_millis(50)).await;
+        messages.write().clear();
+
+        // remote events are dispatched to local subscribers but not published again
+        server
+            .send(RemoteEvent {
+                event,
+                auth_pubkey: None,
+            })
+            .await?;
+        sleep(Duration::from_millis(200)).await;
+        {
+            let mut w = messages.write();
+            assert_eq!(w.len(), 1);
+            assert!(w.get(0).unwrap().0.contains("EVENT"));
+            w.clear();
+        }
+        assert!(published.read().is_empty());
+
+        // the same event from a client is a duplicate
+        let text = format!(r#"["EVENT", {}]"#, note);
+        let msg = serde_json::from_str::<IncomingMessage>(&text)?;
+        server.send(ClientMessage::new(id, text, msg)).await?;
+        sleep(Duration::from_millis(200)).await;
+        assert_eq!(messages.read().len(), 1);
+        assert!(published.read().is_empty());
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn relay_event() -> Result<()> {
+        use nostr_db::secp256k1::{rand::thread_rng, Keypair};
+        let db = Arc::new(Db::open(temp_data_path("server_relay_event")?)?);
+        let key_pair = Keypair::new_global(&mut thread_rng());
+        let event = Event::create(&key_pair, nostr_db::now(), 1, vec![], "notify".to_owned())?;
+
+        let receiver = Receiver::default();
+        let messages = receiver.0.clone();
+        let addr = receiver.start().recipient();
+        let bus = Bus::default();
+        let published = bus.0.clone();
+
+        let server = Server::create_with(db, Setting::default().into());
+        server.send(SetFanout(bus.start().recipient())).await?;
+        let stored = Arc::new(RwLock::new(Vec::new()));
+        let mut extensions = Extensions::default();
+        extensions.add(Stored(stored.clone()));
+        server.do_send(SetExtensions(Arc::new(RwLock::new(extensions))));
+        let id = server.send(Connect { addr, kick: None }).await?;
+
+        let text = r#"["REQ", "1", {}]"#.to_owned();
+        let msg = serde_json::from_str::<IncomingMessage>(&text)?;
+        server.send(ClientMessage::new(id, text, msg)).await?;
+        sleep(Duration::from_millis(50)).await;
+        messages.write().clear();
+
+        // relay events reach subscribers, the bus and the stored hook, no OK is sent to anyone
+        let event_id = event.id_str();
+        server.send(RelayEvent { event }).await?;
+        sleep(Duration::from_millis(200)).await;
+        let w = messages.read();
+        assert_eq!(w.len(), 1);
+        assert!(w.get(0).unwrap().0.contains("EVENT"));
+        assert_eq!(published.read().len(), 1);
+        assert_eq!(*stored.read(), vec![event_id]);
+        Ok(())
+    }
 }
@@ -1,23 +1,45 @@
-use crate::{hash::NoOpHasherDefault, message::*, App, Server};
+use crate::{admin::AdminCommand, hash::NoOpHasherDefault, message::*, App, Server, VerifyEvent};
 use actix::prelude::*;
 use actix_http::ws::Item;
 use actix_web::web;
 use actix_web_actors::ws;
 use bytes::BytesMut;
-use metrics::{counter, gauge};
-use nostr_db::Event;
+use metrics::{counter, gauge, histogram};
+use nostr_db::{now, Event};
 use std::{
     any::{Any, TypeId},
     collections::HashMap,
     time::{Duration, Instant},
 };
-use tracing::{debug, info, error};
+use tracing::{debug, error, info};
 use ws::Message;
 
+/// NIP-42 AUTH events must be created within this many seconds of now
+const AUTH_TIME_WINDOW: u64 = 600;
+
+/// NIP-42 auth event kind
+const AUTH_KIND: u16 = 22242;
+
+/// Session details passed to extension hooks that run outside the session actor
+#[derive(Debug, Clone, Default)]
+pub struct SessionInfo {
+    pub id: usize,
+    pub ip: String,
+    /// NIP-42 authenticated pubkey
+    pub auth_pubkey: Option<String>,
+}
+
 #[derive(Clone)]
 struct SubscriptionState {
     subscription: Subscription,
     extension_events: Vec<Event>,
+    /// Whether stored events are held back until EOSE, for post-processing by
+    /// extensions or to merge the extension events
+    held: bool,
+    /// Stored events held back until EOSE
+    stored_events: Vec<Event>,
+    /// When the REQ was received, for the latency until EOSE
+    started: Instant,
 }
 
 pub struct Session {
@@ -43,7 +65,7 @@ pub struct Session {
 
     pub app: web::Data<App>,
 
-    /// Simple store for save extension data
+    /// Per-connection extension state, one value per type, dropped with the session
     data: HashMap<TypeId, Box<dyn Any>, NoOpHasherDefault>,
 
     /// Buffer for constructing continuation messages
@@ -51,10 +73,28 @@ pub struct Session {
 
     /// Active subscriptions with extension data
     subscriptions: HashMap<String, SubscriptionState>,
+
+    /// NIP-42 challenge sent to the client
+    auth_challenge: Option<String>,
+
+    /// NIP-42 authenticated pubkey
+    auth_pubkey: Option<String>,
+
+    /// last message from the client, pings excluded
+    last_message: Instant,
+
+    /// close the session after this long without messages
+    idle_timeout: Option<Duration>,
+
+    /// connection slot, released with the session
+    pub(crate) connection: Option<crate::Connection>,
+
+    /// carries events accepted by other instances, without a client behind it
+    remote: bool,
 }
 
 impl Session {
-    /// save extension data
+    /// save extension data, extensions should use a private type to avoid collisions
     pub fn set<T: 'static>(&mut self, val: T) {
         self.data.insert(TypeId::of::<T>(), Box::new(val));
     }
@@ -66,6 +106,30 @@ impl Session {
             .and_then(|boxed| boxed.downcast_ref())
     }
 
+    /// get mutable extension data
+    pub fn get_mut<T: 'static>(&mut self) -> Option<&mut T> {
+        self.data
+            .get_mut(&TypeId::of::<T>())
+            .and_then(|boxed| boxed.downcast_mut())
+    }
+
+    /// get mutable extension data, inserting it first if missing
+    pub fn get_or_insert_with<T: 'static, F: FnOnce() -> T>(&mut self, f: F) -> &mut T {
+        self.data
+            .entry(TypeId::of::<T>())
+            .or_insert_with(|| Box::new(f()))
+            .downcast_mut()
+            .expect("extension data stored under its own type")
+    }
+
+    /// remove extension data
+    pub fn remove<T: 'static>(&mut self) -> Option<T> {
+        self.data
+            .remove(&TypeId::of::<T>())
+            .and_then(|boxed| boxed.downcast().ok())
+            .map(|boxed| *boxed)
+    }
+
     /// Get session id
     pub fn id(&self) -> usize {
         self.id
@@ -76,10 +140,67 @@ impl Session {
         &self.ip
     }
 
+    /// Session details for extension hooks
+    pub fn info(&self) -> SessionInfo {
+        SessionInfo {
+            id: self.id,
+            ip: self.ip.clone(),
+            auth_pubkey: self.auth_pubkey.clone(),
+        }
+    }
+
+    /// NIP-42 challenge sent to the client
+    pub fn auth_challenge(&self) -> Option<&String> {
+        self.auth_challenge.as_ref()
+    }
+
+    /// NIP-42 authenticated pubkey, always `None` on the remote session.
+    /// Message methods check the pubkey of the message, `ClientMessage::auth_pubkey`.
+    pub fn auth_pubkey(&self) -> Option<&String> {
+        self.auth_pubkey.as_ref()
+    }
+
+    /// Send a NIP-42 AUTH challenge to the client
+    pub fn send_auth_challenge(&mut self, challenge: String, ctx: &mut <Self as Actor>::Context) {
+        ctx.text(serde_json::json!(["AUTH", challenge]).to_string());
+        self.auth_challenge = Some(challenge);
+    }
+
+    /// Verify a NIP-42 AUTH event against the challenge and store the authenticated pubkey
+    pub fn authenticate(&mut self, event: &Event) -> Result<&String, String> {
+        let Some(challenge) = &self.auth_challenge else {
+            return Err("need reconnect".to_owned());
+        };
+        let now = now();
+        event.validate(now, 0, 0).map_err(|e| e.to_string())?;
+        if event.kind() != AUTH_KIND
+            || !event
+                .tags()
+                .iter()
+                .any(|tag| tag.len() > 1 && tag[0] == "challenge" && &tag[1] == challenge)
+        {
+            return Err("need reconnect".to_owned());
+        }
+        if event.created_at().abs_diff(now) > AUTH_TIME_WINDOW {
+            return Err("auth event created_at too far from now".to_owned());
+        }
+        counter!("nostr_relay_auth_total").increment(1);
+        if self.auth_pubkey.is_none() {
+            gauge!("nostr_relay_session_auth", "state" => "anonymous").decrement(1.0);
+            gauge!("nostr_relay_session_auth", "state" => "authenticated").increment(1.0);
+        }
+        self.server.do_send(Authenticated {
+            id: self.id,
+            pubkey: event.pubkey_str(),
+        });
+        Ok(self.auth_pubkey.insert(event.pubkey_str()))
+    }
+
     pub fn new(ip: String, app: web::Data<App>) -> Session {
         let setting = app.setting.read();
         let heartbeat_timeout = setting.network.heartbeat_timeout.into();
         let heartbeat_interval = setting.network.heartbeat_interval.into();
+        let idle_timeout = setting.network.idle_timeout.map(Into::into);
         drop(setting);
         Self {
             id: 0,
@@ -92,9 +213,35 @@ impl Session {
             data: HashMap::default(),
             cont: None,
             subscriptions: HashMap::new(),
+            auth_challenge: None,
+            auth_pubkey: None,
+            last_message: Instant::now(),
+            idle_timeout,
+            connection: None,
+            remote: false,
         }
     }
 
+    /// Start the session that runs fanout events from other instances through the
+    /// extension message methods before they are written
+    pub fn start_remote(app: web::Data<App>) -> Addr<Session> {
+        let mut session = Session::new("remote".to_string(), app);
+        session.remote = true;
+        let (addr, mut output) =
+            ws::WebsocketContext::create_with_addr(session, futures_util::stream::pending());
+        // replies have no client to go to
+        actix_rt::spawn(async move {
+            while futures_util::StreamExt::next(&mut output).await.is_some() {}
+        });
+        addr
+    }
+
+    /// Whether the session carries events accepted by other instances,
+    /// their connection checks were made where the client is connected
+    pub fn is_remote(&self) -> bool {
+        self.remote
+    }
+
     /// helper method that sends ping to client.
     /// also this method checks heartbeats from client
     fn hb(&self, ctx: &mut ws::WebsocketContext<Self>) {
@@ -109,6 +256,15 @@ impl Session {
                 // don't try to send a ping
                 return;
             }
+            if act
+                .idle_timeout
+                .is_some_and(|idle| act.last_message.elapsed() > idle)
+            {
+                ctx.text(OutgoingMessage::notice("closing idle connection"));
+                counter!("nostr_relay_session_stop_total", "reason" => "idle timeout").increment(1);
+                ctx.stop();
+                return;
+            }
 
             ctx.ping(b"");
         });
@@ -120,20 +276,148 @@ impl Session {
         msg: &ClientMessage,
         ctx: &mut ws::WebsocketContext<Self>,
     ) {
+        // rejections carry a NIP-01 machine-readable prefix
+        let text = err.to_string();
+        let text = match Prefix::of(&text) {
+            Some(_) => text,
+            None => Prefix::Error.with(&text),
+        };
         if let IncomingMessage::Event(event) = &msg.msg {
-            ctx.text(OutgoingMessage::ok(
-                &event.id_str(),
-                false,
-                &err.to_string(),
-            ));
-        } else if let IncomingMessage::Req(sub) = &msg.msg {
-            ctx.text(OutgoingMessage::closed(&sub.id, &err.to_string()));
+            ctx.text(OutgoingMessage::ok(&event.id_str(), false, &text));
+        } else if let IncomingMessage::Req(sub) | IncomingMessage::Count(sub) = &msg.msg {
+            ctx.text(OutgoingMessage::closed(&sub.id, &text));
         } else {
-            ctx.text(OutgoingMessage::notice(&err.to_string()));
+            ctx.text(OutgoingMessage::notice(&text));
+        }
+    }
+
+    /// Go on with the result of the extension message methods, resuming after pending ones
+    fn handle_extension_result(
+        &mut self,
+        (remaining, result): (Vec<usize>, crate::ExtensionMessageResult),
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        match result {
+            crate::ExtensionMessageResult::Continue(msg) => self.forward(msg, ctx),
+            crate::ExtensionMessageResult::Stop(out) => {
+                ctx.text(out);
+            }
+            crate::ExtensionMessageResult::Ignore => {
+                // ignore
+            }
+            crate::ExtensionMessageResult::Pending(fut) => {
+                ctx.spawn(fut.into_actor(self).map(move |result, act, ctx| {
+                    let result = match result {
+                        crate::ExtensionMessageResult::Continue(msg) => act
+                            .app
+                            .clone()
+                            .extensions
+                            .read()
+                            .call_message_from(Some(remaining), msg, act, ctx),
+                        other => (remaining, other),
+                    };
+                    act.handle_extension_result(result, ctx);
+                }));
+            }
+        }
+    }
+
+    /// Pass a message accepted by the extensions to the server
+    fn forward(&mut self, mut msg: ClientMessage, ctx: &mut ws::WebsocketContext<Self>) {
+        if let Err(err) = msg.validate_nip70() {
+            self.send_error(err, &msg, ctx);
+            return;
+        }
+
+        // COUNTs accepted by the extensions may be answered from their storage
+        if let crate::message::IncomingMessage::Count(subscription) = &msg.msg {
+            let count = self
+                .app
+                .extensions
+                .read()
+                .call_process_count(&self.info(), subscription);
+            if let Some(count) = count {
+                ctx.text(OutgoingMessage::count(&subscription.id, count));
+                return;
+            }
         }
+
+        // Process REQ messages through extensions
+        if let crate::message::IncomingMessage::Req(subscription) = &mut msg.msg {
+            let req_result = self
+                .app
+                .extensions
+                .read()
+                .call_process_req(&self.info(), subscription);
+            let post_processed = self
+                .app
+                .extensions
+                .read()
+                .call_post_processes(&self.info(), subscription);
+
+            match req_result {
+                crate::extension::ExtensionReqResult::Handle(events) => {
+                    // Extension fully handled the request
+                    for event in crate::extension::merge_req_events(subscription, vec![], events) {
+                        let event_json = serde_json::to_string(&event).unwrap_or_default();
+                        ctx.text(crate::message::OutgoingMessage::event(
+                            &subscription.id,
+                            &event_json,
+                        ));
+                    }
+                    ctx.text(crate::message::OutgoingMessage::eose(&subscription.id));
+                    return;
+                }
+                crate::extension::ExtensionReqResult::Close(reason) => {
+                    ctx.text(crate::message::OutgoingMessage::closed(
+                        &subscription.id,
+                        &reason,
+                    ));
+                    return;
+                }
+                crate::extension::ExtensionReqResult::AddEvents(events) => {
+                    // Store subscription state with extension events
+                    self.subscriptions.insert(
+                        subscription.id.clone(),
+                        SubscriptionState {
+                            subscription: subscription.clone(),
+                            extension_events: events,
+                            held: true,
+                            stored_events: vec![],
+                            started: Instant::now(),
+                        },
+                    );
+                }
+                _ => {
+                    // Normal processing, no extension events
+                    self.subscriptions.insert(
+                        subscription.id.clone(),
+                        SubscriptionState {
+                            subscription: subscription.clone(),
+                            extension_events: vec![],
+                            held: post_processed,
+                            stored_events: vec![],
+                            started: Instant::now(),
+                        },
+                    );
+                }
+            }
+        }
+
+        if self.remote {
+            if let IncomingMessage::Event(event) = msg.msg {
+                self.server.do_send(RemoteEvent {
+                    event,
+                    auth_pubkey: msg.auth_pubkey,
+                });
+            }
+            return;
+        }
+        self.server.do_send(msg);
     }
 
     fn handle_message(&mut self, text: String, ctx: &mut ws::WebsocketContext<Self>) {
+        self.last_message = Instant::now();
         let msg = serde_json::from_str::<IncomingMessage>(&text);
         match msg {
             Ok(msg) => {
@@ -141,76 +425,164 @@ impl Session {
                     // only insert known command metrics
                     counter!("nostr_relay_message_total", "command" => cmd).increment(1);
                 }
+                if let IncomingMessage::Admin(args) = &msg {
+                    self.handle_admin(args, ctx);
+                    return;
+                }
 
                 let mut msg = ClientMessage::new(self.id, text, msg);
                 {
                     let r = self.app.setting.read();
-                    if let Err(err) = msg.validate(&r.limitation) {
+                    // with verification workers the signature is checked last, off this thread
+                    if let Err(err) = msg.validate_with(&r.limitation, self.app.verifier.is_none())
+                    {
                         self.send_error(err, &msg, ctx);
                         return;
                     }
-                }
-
-                match self
-                    .app
-                    .clone()
-                    .extensions
-                    .read()
-                    .call_message(msg, self, ctx)
-                {
-                    crate::ExtensionMessageResult::Continue(mut msg) => {
-                        if let Err(err) = msg.validate_nip70() {
-                            self.send_error(err, &msg, ctx);
-                            return;
-                        }
-                        
-                        // Process REQ messages through extensions
-                        if let crate::message::IncomingMessage::Req(ref subscription) = &msg.msg {
-                            let (req_result, extension_events) = self.app.extensions.read()
-                                .call_process_req(msg.id, subscription);
-                            
-                            match req_result {
-                                crate::extension::ExtensionReqResult::Handle(events) => {
-                                    // Extension fully handled the request
-                                    for event in events {
-                                        let event_json = serde_json::to_string(&event).unwrap_or_default();
-                                        ctx.text(crate::message::OutgoingMessage::event(&subscription.id, &event_json));
-                                    }
-                                    ctx.text(crate::message::OutgoingMessage::eose(&subscription.id));
-                                    return;
-                                }
-                                crate::extension::ExtensionReqResult::AddEvents(events) => {
-                                    // Store subscription state with extension events
-                                    self.subscriptions.insert(subscription.id.clone(), SubscriptionState {
-                                        subscription: subscription.clone(),
-                                        extension_events: events.clone(),
-                                    });
-                                }
-                                _ => {
-                                    // Normal processing, no extension events
-                                    self.subscriptions.insert(subscription.id.clone(), SubscriptionState {
-                                        subscription: subscription.clone(),
-                                        extension_events: vec![],
-                                    });
-                                }
-                            }
+                    if let Err(err) = msg.validate_limits(&r.limits) {
+                        if let IncomingMessage::Event(event) = &msg.msg {
+                            counter!(
+                                "nostr_relay_event_rejected_total",
+                                "reason" => "content_size",
+                                "kind" => event.kind().to_string()
+                            )
+                            .increment(1);
                         }
-                        
-                        self.server.do_send(msg);
-                    }
-                    crate::ExtensionMessageResult::Stop(out) => {
-                        ctx.text(out);
+                        self.send_error(err, &msg, ctx);
+                        return;
                     }
-                    crate::ExtensionMessageResult::Ignore => {
-                        // ignore
+                    if let Err(err) = msg.validate_pow(&r.pow) {
+                        if let IncomingMessage::Event(event) = &msg.msg {
+                            counter!(
+                                "nostr_relay_event_rejected_total",
+                                "reason" => "pow",
+                                "kind" => event.kind().to_string()
+                            )
+                            .increment(1);
+                        }
+                        self.send_error(err, &msg, ctx);
+                        return;
                     }
+                }
+
+                let event_id = match &msg.msg {
+                    IncomingMessage::Event(event) => Some(event.id_str()),
+                    _ => None,
                 };
+                if let (Some(verifier), Some(event_id)) = (self.app.verifier.clone(), event_id) {
+                    // wait keeps the order of the session's messages
+                    verifier
+                        .send(VerifyEvent(msg))
+                        .into_actor(self)
+                        .map(move |res, act, ctx| match res {
+                            Ok(Ok(msg)) => act.call_extensions(msg, ctx),
+                            Ok(Err((err, msg))) => act.send_error(err, &msg, ctx),
+                            Err(err) => {
+                                error!("signature verification worker unavailable: {}", err);
+                                ctx.text(OutgoingMessage::ok(
+                                    &event_id,
+                                    false,
+                                    &Prefix::Error.with("could not verify signature"),
+                                ));
+                            }
+                        })
+                        .wait(ctx);
+                    return;
+                }
+                self.call_extensions(msg, ctx);
             }
             Err(err) => {
                 ctx.text(OutgoingMessage::notice(&format!("json error: {}", err)));
             }
         };
     }
+
+    /// Run an admin command of a session authenticated as an operator pubkey
+    fn handle_admin(&mut self, args: &[serde_json::Value], ctx: &mut ws::WebsocketContext<Self>) {
+        let verb = args
+            .first()
+            .and_then(|v| v.as_str())
+            .unwrap_or_default()
+            .to_owned();
+        let allowed = {
+            let r = self.app.setting.read();
+            match &self.auth_pubkey {
+                _ if r.admin.pubkeys.is_empty() => {
+                    Err((Prefix::Restricted, "admin commands are disabled"))
+                }
+                Some(pubkey) if r.admin.pubkeys.contains(pubkey) => Ok(pubkey.clone()),
+                Some(_) => Err((Prefix::Restricted, "not an admin pubkey")),
+                None => Err((Prefix::AuthRequired, "admin commands need NIP-42 auth")),
+            }
+        };
+        let pubkey = match allowed {
+            Ok(pubkey) => pubkey,
+            Err((prefix, reason)) => {
+                counter!("nostr_relay_auth_unauthorized", "command" => "ADMIN", "reason" => reason)
+                    .increment(1);
+                ctx.text(OutgoingMessage::admin(&verb, Err(prefix.with(reason))));
+                return;
+            }
+        };
+        let cmd = match AdminCommand::parse(args) {
+            Ok(cmd) => cmd,
+            Err(err) => {
+                ctx.text(OutgoingMessage::admin(
+                    &verb,
+                    Err(Prefix::Invalid.with(&err)),
+                ));
+                return;
+            }
+        };
+        let verb = cmd.verb();
+        info!(
+            "Admin command {} from {} in session {}: {:?}",
+            verb, pubkey, self.id, cmd
+        );
+        counter!("nostr_relay_admin_commands_total", "verb" => verb).increment(1);
+        if cmd == AdminCommand::ReloadConfig {
+            let result = self
+                .app
+                .reload_setting()
+                .map(|_| serde_json::json!({}))
+                .map_err(|e| Prefix::Error.with(&e.to_string()));
+            ctx.text(OutgoingMessage::admin(verb, result));
+            return;
+        }
+        self.server
+            .send(cmd)
+            .into_actor(self)
+            .map(move |res, _, ctx| {
+                let result = res.unwrap_or_else(|e| Err(Prefix::Error.with(&e.to_string())));
+                ctx.text(OutgoingMessage::admin(verb, result));
+            })
+            .spawn(ctx);
+    }
+
+    fn call_extensions(&mut self, mut msg: ClientMessage, ctx: &mut ws::WebsocketContext<Self>) {
+        if !self.remote {
+            msg.auth_pubkey = self.auth_pubkey.clone();
+        }
+        let result = self
+            .app
+            .clone()
+            .extensions
+            .read()
+            .call_message_from(None, msg, self, ctx);
+        self.handle_extension_result(result, ctx);
+    }
+}
+
+/// Events accepted by another instance, each carries the auth of the client that sent it there
+impl Handler<RemoteEvent> for Session {
+    type Result = ();
+
+    fn handle(&mut self, msg: RemoteEvent, ctx: &mut Self::Context) {
+        let text = format!(r#"["EVENT",{}]"#, msg.event);
+        let mut event = ClientMessage::new(self.id, text, IncomingMessage::Event(msg.event));
+        event.auth_pubkey = msg.auth_pubkey;
+        self.call_extensions(event, ctx);
+    }
 }
 
 /// Handle messages from server, we simply send it to peer websocket
@@ -220,28 +592,62 @@ impl Handler<OutgoingMessage> for Session {
     fn handle(&mut self, msg: OutgoingMessage, ctx: &mut Self::Context) {
         // Check if this is an EVENT message for a subscription we're tracking
         if let Some(sub_id) = extract_event_subscription_id(&msg.0) {
-            if let Some(state) = self.subscriptions.get(&sub_id) {
-                // This is an event for a tracked subscription
-                // First, send any extension events that haven't been sent yet
-                if !state.extension_events.is_empty() {
-                    let mut state = self.subscriptions.remove(&sub_id).unwrap();
-                    for event in state.extension_events.drain(..) {
-                        let event_json = serde_json::to_string(&event).unwrap_or_default();
-                        ctx.text(OutgoingMessage::event(&sub_id, &event_json));
-                    }
-                    // Put it back without extension events
-                    self.subscriptions.insert(sub_id.clone(), state);
+            if let Some(state) = self
+                .subscriptions
+                .get_mut(&sub_id)
+                .filter(|state| state.held)
+            {
+                // Hold back stored events until EOSE so extensions see the whole result
+                if let Some(event) = extract_event(&msg.0) {
+                    state.stored_events.push(event);
+                    return;
                 }
             }
         } else if let Some(sub_id) = extract_eose_subscription_id(&msg.0) {
-            // This is an EOSE, remove the subscription tracking
-            self.subscriptions.remove(&sub_id);
+            // This is an EOSE, flush and remove the subscription tracking
+            if let Some(state) = self.subscriptions.remove(&sub_id) {
+                histogram!("nostr_relay_req_duration").record(state.started.elapsed());
+                if state.held {
+                    let result = self.app.extensions.read().call_post_process_query_results(
+                        &self.info(),
+                        &state.subscription,
+                        state.stored_events,
+                    );
+                    // Extension events are merged into the results, all of them precede EOSE
+                    let events = crate::extension::merge_req_events(
+                        &state.subscription,
+                        result.events,
+                        state.extension_events,
+                    );
+                    for event in events {
+                        ctx.text(OutgoingMessage::event(&sub_id, &event.to_string()));
+                    }
+                }
+            }
         }
-        
+
         ctx.text(msg);
     }
 }
 
+impl Handler<Kick> for Session {
+    type Result = ();
+
+    fn handle(&mut self, msg: Kick, ctx: &mut Self::Context) {
+        info!(
+            "Disconnect session {} from {}: {}",
+            self.id, self.ip, msg.reason
+        );
+        ctx.text(OutgoingMessage::notice(&msg.reason));
+        ctx.close(Some(ws::CloseReason {
+            code: ws::CloseCode::Policy,
+            description: Some(msg.reason),
+        }));
+        counter!("nostr_relay_session_stop_total", "reason" => msg.cause).increment(1);
+        ctx.stop();
+    }
+}
+
 // Helper functions to parse subscription IDs from messages
 fn extract_event_subscription_id(msg: &str) -> Option<String> {
     if msg.starts_with(r#"["EVENT","#) {
@@ -253,6 +659,12 @@ fn extract_event_subscription_id(msg: &str) -> Option<String> {
     None
 }
 
+fn extract_event(msg: &str) -> Option<Event> {
+    serde_json::from_str::<(String, String, Event)>(msg)
+        .ok()
+        .map(|(_, _, event)| event)
+}
+
 fn extract_eose_subscription_id(msg: &str) -> Option<String> {
     if msg.starts_with(r#"["EOSE","#) {
         let parts: Vec<&str> = msg.split('"').collect();
@@ -268,16 +680,23 @@ impl Actor for Session {
 
     /// Method is called on actor start. We start the heartbeat process here.
     fn started(&mut self, ctx: &mut Self::Context) {
+        if self.remote {
+            return;
+        }
         counter!("nostr_relay_session_total").increment(1);
         gauge!("nostr_relay_session").increment(1.0);
+        gauge!("nostr_relay_session_auth", "state" => "anonymous").increment(1.0);
 
         // we'll start heartbeat process on session start.
         self.hb(ctx);
+        // live events beyond the mailbox capacity are dropped by the server
+        ctx.set_mailbox_capacity(self.app.setting.read().network.max_outbound_queue);
         // register self in server.
         let addr = ctx.address();
         self.server
             .send(Connect {
-                addr: addr.recipient(),
+                addr: addr.clone().recipient(),
+                kick: Some(addr.recipient()),
             })
             .into_actor(self)
             .then(|res, act, ctx| {
@@ -285,11 +704,17 @@ impl Actor for Session {
                     Ok(res) => {
                         act.id = res;
                         act.app.clone().extensions.read().call_connected(act, ctx);
-                        info!("🔌 WebSocket client connected from {} - Session ID: {}", act.ip, act.id);
+                        info!(
+                            "🔌 WebSocket client connected from {} - Session ID: {}",
+                            act.ip, act.id
+                        );
                     }
                     // something is wrong with server
                     Err(err) => {
-                        error!("❌ Failed to register session for client {}: {}", act.ip, err);
+                        error!(
+                            "❌ Failed to register session for client {}: {}",
+                            act.ip, err
+                        );
                         counter!("nostr_relay_session_stop_total", "reason" => "server error")
                             .increment(1);
                         ctx.stop()
@@ -301,19 +726,34 @@ impl Actor for Session {
     }
 
     fn stopping(&mut self, _: &mut Self::Context) -> Running {
+        if self.remote {
+            return Running::Stop;
+        }
         // notify server
         self.server.do_send(Disconnect { id: self.id });
         Running::Stop
     }
 
     fn stopped(&mut self, ctx: &mut Self::Context) {
+        if self.remote {
+            return;
+        }
         gauge!("nostr_relay_session").decrement(1.0);
+        let state = if self.auth_pubkey.is_some() {
+            "authenticated"
+        } else {
+            "anonymous"
+        };
+        gauge!("nostr_relay_session_auth", "state" => state).decrement(1.0);
         self.app
             .clone()
             .extensions
             .read()
             .call_disconnected(self, ctx);
-        info!("🔌 WebSocket client disconnected from {} - Session ID: {}", self.ip, self.id);
+        info!(
+            "🔌 WebSocket client disconnected from {} - Session ID: {}",
+            self.ip, self.id
+        );
     }
 }
 
@@ -401,6 +841,7 @@ mod tests {
     use anyhow::Result;
     use bytes::Bytes;
     use futures_util::{SinkExt as _, StreamExt as _};
+    use std::sync::Arc;
 
     #[actix_rt::test]
     async fn pingpong() -> Result<()> {
@@ -507,6 +948,294 @@ mod tests {
         Ok(())
     }
 
+    /// Waits before passing the message on
+    struct Later;
+    impl Extension for Later {
+        fn message(
+            &self,
+            msg: ClientMessage,
+            _session: &mut Session,
+            _ctx: &mut <Session as actix::Actor>::Context,
+        ) -> ExtensionMessageResult {
+            ExtensionMessageResult::pending(async move {
+                sleep(Duration::from_millis(10)).await;
+                ExtensionMessageResult::Continue(msg)
+            })
+        }
+
+        fn name(&self) -> &'static str {
+            "Later"
+        }
+    }
+
+    #[actix_rt::test]
+    async fn pending() -> Result<()> {
+        let text = r#"["REQ", "1", {}]"#;
+        let mut srv = actix_test::start(|| {
+            let data = create_test_app("pending").unwrap();
+            data.add_extension(Later).add_extension(Echo).web_app()
+        });
+        let mut framed = srv.ws_at("/").await.unwrap();
+        framed.send(ws::Message::Text(text.into())).await?;
+        let item = framed.next().await.unwrap()?;
+        assert_eq!(
+            item,
+            ws::Frame::Text(Bytes::copy_from_slice(text.as_bytes()))
+        );
+        Ok(())
+    }
+
+    /// Messages received on the connection
+    struct Received(usize);
+
+    struct Counter;
+    impl Extension for Counter {
+        fn message(
+            &self,
+            _msg: ClientMessage,
+            session: &mut Session,
+            _ctx: &mut <Session as actix::Actor>::Context,
+        ) -> ExtensionMessageResult {
+            let received = session.get_or_insert_with(|| Received(0));
+            received.0 += 1;
+            ExtensionMessageResult::Stop(OutgoingMessage::notice(&received.0.to_string()))
+        }
+
+        fn name(&self) -> &'static str {
+            "Counter"
+        }
+    }
+
+    #[actix_rt::test]
+    async fn state() -> Result<()> {
+        let text = r#"["REQ", "1", {}]"#;
+        let mut srv = actix_test::start(|| {
+            let data = create_test_app("state").unwrap();
+            data.add_extension(Counter).web_app()
+        });
+        let mut first = srv.ws_at("/").await.unwrap();
+        let mut second = srv.ws_at("/").await.unwrap();
+        first.send(ws::Message::Text(text.into())).await?;
+        first.send(ws::Message::Text(text.into())).await?;
+        second.send(ws::Message::Text(text.into())).await?;
+        let item = first.next().await.unwrap()?;
+        assert_eq!(
+            item,
+            ws::Frame::Text(Bytes::copy_from_slice(br#"["NOTICE","1"]"#))
+        );
+        let item = first.next().await.unwrap()?;
+        assert_eq!(
+            item,
+            ws::Frame::Text(Bytes::copy_from_slice(br#"["NOTICE","2"]"#))
+        );
+        let item = second.next().await.unwrap()?;
+        assert_eq!(
+            item,
+            ws::Frame::Text(Bytes::copy_from_slice(br#"["NOTICE","1"]"#))
+        );
+        Ok(())
+    }
+
+    /// Answers every COUNT from its own storage
+    struct Counts;
+    impl Extension for Counts {
+        fn process_count(
+            &self,
+            _session: &SessionInfo,
+            _subscription: &Subscription,
+        ) -> Option<u64> {
+            Some(7)
+        }
+
+        fn name(&self) -> &'static str {
+            "Counts"
+        }
+    }
+
+    /// Refuses COUNT like an auth or rate limit extension
+    struct CountGate;
+    impl Extension for CountGate {
+        fn message(
+            &self,
+            msg: ClientMessage,
+            _session: &mut Session,
+            _ctx: &mut <Session as actix::Actor>::Context,
+        ) -> ExtensionMessageResult {
+            match &msg.msg {
+                IncomingMessage::Count(sub) => ExtensionMessageResult::Stop(
+                    OutgoingMessage::refused(&sub.id, Prefix::AuthRequired, "auth first"),
+                ),
+                _ => ExtensionMessageResult::Continue(msg),
+            }
+        }
+
+        fn name(&self) -> &'static str {
+            "CountGate"
+        }
+    }
+
+    #[actix_rt::test]
+    async fn count_hook_after_message_chain() -> Result<()> {
+        let text = r#"["COUNT", "1", {"kinds":[443]}]"#;
+        let mut srv = actix_test::start(|| {
+            let data = create_test_app("count_hook").unwrap();
+            data.add_extension(Counts).web_app()
+        });
+        let mut framed = srv.ws_at("/").await.unwrap();
+        framed.send(ws::Message::Text(text.into())).await?;
+        let item = framed.next().await.unwrap()?;
+        assert_eq!(
+            item,
+            ws::Frame::Text(Bytes::copy_from_slice(br#"["COUNT","1",{"count":7}]"#))
+        );
+
+        // a refusal earlier in the chain is not bypassed by the count hook
+        let mut srv = actix_test::start(|| {
+            let data = create_test_app("count_hook_gate").unwrap();
+            data.add_extension(CountGate)
+                .add_extension(Counts)
+                .web_app()
+        });
+        let mut framed = srv.ws_at("/").await.unwrap();
+        framed.send(ws::Message::Text(text.into())).await?;
+        let item = framed.next().await.unwrap()?;
+        assert_eq!(
+            item,
+            ws::Frame::Text(Bytes::copy_from_slice(
+                br#"["CLOSED","1","auth-required: auth first"]"#
+            ))
+        );
+        Ok(())
+    }
+
+    /// Challenges on connect and answers AUTH like the auth extension
+    struct Authenticator;
+    impl Extension for Authenticator {
+        fn connected(&self, session: &mut Session, ctx: &mut <Session as actix::Actor>::Context) {
+            session.send_auth_challenge("challenge".to_owned(), ctx);
+        }
+
+        fn message(
+            &self,
+            msg: ClientMessage,
+            session: &mut Session,
+            _ctx: &mut <Session as actix::Actor>::Context,
+        ) -> ExtensionMessageResult {
+            match &msg.msg {
+                IncomingMessage::Auth(event) => {
+                    let ok = session.authenticate(event).is_ok();
+                    ExtensionMessageResult::Stop(OutgoingMessage::ok(&event.id_str(), ok, ""))
+                }
+                _ => ExtensionMessageResult::Continue(msg),
+            }
+        }
+
+        fn name(&self) -> &'static str {
+            "Authenticator"
+        }
+    }
+
+    #[actix_rt::test]
+    async fn admin() -> Result<()> {
+        use nostr_db::secp256k1::{rand::thread_rng, Keypair};
+
+        let key_pair = Keypair::new_global(&mut thread_rng());
+        let admin = key_pair.x_only_public_key().0.to_string();
+        let mut srv = actix_test::start(move || {
+            let data = create_test_app("admin").unwrap();
+            data.setting.write().admin.pubkeys = vec![admin.clone()];
+            data.add_extension(Authenticator).web_app()
+        });
+        let text = |frame: ws::Frame| -> Result<serde_json::Value> {
+            match frame {
+                ws::Frame::Text(text) => Ok(serde_json::from_slice(&text)?),
+                other => Err(anyhow::anyhow!("unexpected frame {:?}", other)),
+            }
+        };
+
+        let mut operator = srv.ws_at("/").await.unwrap();
+        let mut other = srv.ws_at("/").await.unwrap();
+        text(operator.next().await.unwrap()?)?;
+        text(other.next().await.unwrap()?)?;
+
+        operator
+            .send(ws::Message::Text(r#"["ADMIN", "stats"]"#.into()))
+            .await?;
+        let answer = text(operator.next().await.unwrap()?)?;
+        assert_eq!(answer[2], false);
+        assert!(answer[3].as_str().unwrap().starts_with("auth-required:"));
+
+        let event = Event::create(
+            &key_pair,
+            now(),
+            AUTH_KIND,
+            vec![vec!["challenge".to_owned(), "challenge".to_owned()]],
+            "".to_owned(),
+        )?;
+        operator
+            .send(ws::Message::Text(format!(r#"["AUTH", {}]"#, event).into()))
+            .await?;
+        assert_eq!(text(operator.next().await.unwrap()?)?[2], true);
+
+        operator
+            .send(ws::Message::Text(r#"["ADMIN", "stats"]"#.into()))
+            .await?;
+        let answer = text(operator.next().await.unwrap()?)?;
+        assert_eq!(answer[2], true);
+        assert_eq!(answer[3]["sessions"], 2);
+        assert_eq!(answer[3]["authenticated"], 1);
+
+        operator
+            .send(ws::Message::Text(
+                r#"["ADMIN", "broadcast-notice", "maintenance"]"#.into(),
+            ))
+            .await?;
+        // the NOTICE and the answer take different paths to the operator
+        let mut frames = vec![
+            text(operator.next().await.unwrap()?)?,
+            text(operator.next().await.unwrap()?)?,
+        ];
+        frames.sort_by_key(|f| f[0] != "NOTICE");
+        assert_eq!(frames[0], serde_json::json!(["NOTICE", "maintenance"]));
+        assert_eq!(frames[1][3]["sessions"], 2);
+        assert_eq!(
+            text(other.next().await.unwrap()?)?,
+            serde_json::json!(["NOTICE", "maintenance"])
+        );
+
+        operator
+            .send(ws::Message::Text(r#"["ADMIN", "sessions"]"#.into()))
+            .await?;
+        let answer = text(operator.next().await.unwrap()?)?;
+        let other_id = answer[3]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|s| s["pubkey"].is_null())
+            .unwrap()["id"]
+            .clone();
+        operator
+            .send(ws::Message::Text(
+                serde_json::json!(["ADMIN", "kick", other_id, "bye"])
+                    .to_string()
+                    .into(),
+            ))
+            .await?;
+        assert_eq!(text(operator.next().await.unwrap()?)?[2], true);
+        assert_eq!(
+            text(other.next().await.unwrap()?)?,
+            serde_json::json!(["NOTICE", "bye"])
+        );
+        assert!(matches!(other.next().await.unwrap()?, ws::Frame::Close(_)));
+
+        operator
+            .send(ws::Message::Text(r#"["ADMIN", "reload-config"]"#.into()))
+            .await?;
+        let answer = text(operator.next().await.unwrap()?)?;
+        assert_eq!(answer[2], false);
+        Ok(())
+    }
+
     #[actix_rt::test]
     async fn max_size() -> Result<()> {
         let text = r#"["REQ", "1", {}]"#;
@@ -571,4 +1300,57 @@ mod tests {
 
         Ok(())
     }
+
+    /// Records the sessions events arrive on and the auth they were sent under
+    struct Seen(Arc<parking_lot::RwLock<Vec<(bool, Option<String>)>>>);
+    impl Extension for Seen {
+        fn message(
+            &self,
+            msg: ClientMessage,
+            session: &mut Session,
+            _ctx: &mut <Session as actix::Actor>::Context,
+        ) -> ExtensionMessageResult {
+            self.0
+                .write()
+                .push((session.is_remote(), msg.auth_pubkey.clone()));
+            ExtensionMessageResult::Continue(msg)
+        }
+
+        fn name(&self) -> &'static str {
+            "Seen"
+        }
+    }
+
+    #[actix_rt::test]
+    async fn remote_event() -> Result<()> {
+        use nostr_db::secp256k1::{rand::thread_rng, Keypair};
+        let seen = Arc::new(parking_lot::RwLock::new(Vec::new()));
+        let app = create_test_app("remote_event")?.add_extension(Seen(seen.clone()));
+        let remote = Session::start_remote(web::Data::new(app.clone()));
+
+        let key_pair = Keypair::new_global(&mut thread_rng());
+        let event = Event::create(&key_pair, now(), 1, vec![], "remote".to_owned())?;
+        let other = Event::create(&key_pair, now(), 1, vec![], "anonymous".to_owned())?;
+        let auth = "ab".repeat(32);
+        remote
+            .send(RemoteEvent {
+                event: event.clone(),
+                auth_pubkey: Some(auth.clone()),
+            })
+            .await?;
+        remote
+            .send(RemoteEvent {
+                event: other,
+                auth_pubkey: None,
+            })
+            .await?;
+        sleep(Duration::from_millis(200)).await;
+
+        // each event keeps the auth of its sender, the remote session itself is never authenticated
+        assert_eq!(*seen.read(), vec![(true, Some(auth)), (true, None)]);
+        let reader = app.db.reader()?;
+        let stored: Option<Event> = app.db.get(&reader, event.id())?;
+        assert_eq!(stored.map(|e| e.id_str()), Some(event.id_str()));
+        Ok(())
+    }
 }
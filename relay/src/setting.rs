@@ -1,6 +1,7 @@
-use crate::Error;
 use crate::{duration::NonZeroDuration, hash::NoOpHasherDefault, Result};
+use crate::{secret, Error};
 use config::{Config, Environment, File, FileFormat};
+use nostr_db::{Options, SyncMode, TREES};
 use notify::{event::ModifyKind, Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
 use parking_lot::RwLock;
 use serde::de::DeserializeOwned;
@@ -19,12 +20,23 @@ use tracing::{error, info};
 
 pub const CARGO_PKG_VERSION: Option<&'static str> = option_env!("CARGO_PKG_VERSION");
 
+/// Smallest LMDB map accepted, 10MB
+const MIN_MAP_SIZE: usize = 10 * 1024 * 1024;
+
 fn default_version() -> String {
     CARGO_PKG_VERSION.map(ToOwned::to_owned).unwrap_or_default()
 }
 
+fn default_slow_query_kinds() -> Vec<u16> {
+    vec![
+        0, 1, 3, 5, 7, 443, 444, 445, 446, 449, 450, 1059, 10050, 10051,
+    ]
+}
+
 fn default_nips() -> Vec<u32> {
-    vec![1, 2, 4, 9, 11, 12, 15, 16, 20, 22, 25, 26, 28, 33, 40, 70]
+    vec![
+        1, 2, 4, 9, 11, 12, 13, 15, 16, 20, 22, 25, 26, 28, 33, 40, 70,
+    ]
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
@@ -34,6 +46,7 @@ pub struct Information {
     pub description: String,
     pub pubkey: Option<String>,
     pub contact: Option<String>,
+    pub icon: Option<String>,
     pub software: String,
     #[serde(skip_deserializing)]
     pub version: String,
@@ -48,6 +61,7 @@ impl Default for Information {
             description: Default::default(),
             pubkey: Default::default(),
             contact: Default::default(),
+            icon: Default::default(),
             software: Default::default(),
             version: default_version(),
             supported_nips: default_nips(),
@@ -62,13 +76,66 @@ pub struct Data {
 
     /// Query filter timeout time
     pub db_query_timeout: Option<NonZeroDuration>,
+
+    /// Log filters whose scan takes longer than this
+    pub slow_query_time: Option<NonZeroDuration>,
+
+    /// Log filters whose scan examines more index keys than this
+    pub slow_query_keys: Option<u64>,
+
+    /// Kinds labelled on the slow query counter, the others count as `other`
+    pub slow_query_kinds: Vec<u16>,
+
+    /// LMDB map size in bytes, writes fail with MDB_MAP_FULL beyond it (default 1TB, restart required)
+    pub map_size: usize,
+
+    /// LMDB reader slots for concurrent read transactions (default 100, restart required)
+    pub max_readers: u32,
+
+    /// LMDB named databases, at least the 15 of the event store (default 20, restart required)
+    pub max_dbs: u32,
+
+    /// LMDB commit durability: full, nometasync or nosync (default full, restart required)
+    pub sync: SyncMode,
+
+    /// events written in one transaction, larger bursts are split (default 1000, restart required)
+    pub write_batch_size: usize,
+
+    /// time accepted events wait to be committed together, a full batch commits early (default 100ms, restart required)
+    pub write_interval: NonZeroDuration,
+
+    /// with sync nometasync or nosync, flush commits to disk this often, unset leaves it to the OS (restart required)
+    pub sync_interval: Option<NonZeroDuration>,
 }
 
 impl Default for Data {
     fn default() -> Self {
+        let options = Options::default();
         Self {
             path: PathBuf::from("./data"),
             db_query_timeout: None,
+            slow_query_time: None,
+            slow_query_keys: None,
+            slow_query_kinds: default_slow_query_kinds(),
+            map_size: options.map_size,
+            max_readers: options.max_readers,
+            max_dbs: options.max_dbs,
+            sync: options.sync,
+            write_batch_size: 1000,
+            write_interval: Duration::from_millis(100).try_into().unwrap(),
+            sync_interval: None,
+        }
+    }
+}
+
+impl Data {
+    /// LMDB environment options
+    pub fn db_options(&self) -> Options {
+        Options {
+            map_size: self.map_size,
+            max_readers: self.max_readers,
+            max_dbs: self.max_dbs,
+            sync: self.sync,
         }
     }
 }
@@ -81,6 +148,8 @@ pub struct Thread {
     pub http: usize,
     /// number of read event threads
     pub reader: usize,
+    /// number of event signature verification threads, 0 verifies in the session
+    pub verifier: usize,
 }
 
 /// network config
@@ -101,8 +170,44 @@ pub struct Network {
 
     pub real_ip_header: Option<String>,
 
+    /// proxies in front of the relay that append to a list `real_ip_header`
+    /// such as x-forwarded-for, the client ip is this many entries from the right (default 1)
+    pub real_ip_trusted_hops: usize,
+
     /// redirect to other site when user access the http index page
     pub index_redirect_to: Option<String>,
+
+    /// websocket connections accepted in total, 0 is unlimited (default 0)
+    pub max_connections: usize,
+
+    /// websocket connections accepted from one ip, 0 is unlimited (default 0)
+    pub max_connections_per_ip: usize,
+
+    /// close sessions that sent no message for this long, pings do not count (default none)
+    pub idle_timeout: Option<NonZeroDuration>,
+
+    /// time a client may take to send the upgrade request headers (default 5 seconds, restart required)
+    pub handshake_timeout: NonZeroDuration,
+
+    /// messages queued for a session before its live events are dropped (default 1000)
+    pub max_outbound_queue: usize,
+
+    /// disconnect a session with a NOTICE when its queue is full instead of dropping live events (default false)
+    pub disconnect_slow_consumers: bool,
+
+    /// negotiate permessage-deflate with clients that offer it (default false)
+    pub compression: bool,
+
+    /// outgoing messages shorter than this many bytes are sent uncompressed (default 1024)
+    pub compression_threshold: usize,
+
+    /// keep the compression context between outgoing messages, better ratio for
+    /// about 300K of memory per session (default false)
+    pub compression_context_takeover: bool,
+
+    /// shutdown timeout (default 8 seconds)
+    /// How long SIGTERM waits for sessions and extensions to drain before exiting
+    pub shutdown_timeout: NonZeroDuration,
 }
 
 impl Default for Network {
@@ -113,7 +218,18 @@ impl Default for Network {
             heartbeat_interval: Duration::from_secs(60).try_into().unwrap(),
             heartbeat_timeout: Duration::from_secs(120).try_into().unwrap(),
             real_ip_header: None,
+            real_ip_trusted_hops: 1,
             index_redirect_to: None,
+            max_connections: 0,
+            max_connections_per_ip: 0,
+            idle_timeout: None,
+            handshake_timeout: Duration::from_secs(5).try_into().unwrap(),
+            max_outbound_queue: 1000,
+            disconnect_slow_consumers: false,
+            compression: false,
+            compression_threshold: 1024,
+            compression_context_takeover: false,
+            shutdown_timeout: Duration::from_secs(8).try_into().unwrap(),
         }
     }
 }
@@ -127,6 +243,10 @@ pub struct Limitation {
     pub max_subscriptions: usize,
     /// maximum number of filter values in each subscription. default 10
     pub max_filters: usize,
+    /// maximum number of ids in each filter. default 500
+    pub max_filter_ids: usize,
+    /// maximum number of authors in each filter. default 500
+    pub max_filter_authors: usize,
     /// the relay server will clamp each filter's limit value to this number. This means the client won't be able to get more than this number of events from a single subscription filter. default 300
     pub max_limit: u64,
     /// maximum length of subscription id as a string. default 100
@@ -147,6 +267,8 @@ impl Default for Limitation {
             max_message_length: 524288,
             max_subscriptions: 20,
             max_filters: 10,
+            max_filter_ids: 500,
+            max_filter_authors: 500,
             max_limit: 300,
             max_subid_length: 100,
             min_prefix: 10,
@@ -157,6 +279,260 @@ impl Default for Limitation {
     }
 }
 
+/// LMDB retention by kind, the firestore archive remains the long-term store
+///
+/// ```toml
+/// [retention]
+/// interval = "10m"
+/// default = "90d"
+/// kind_445 = "30d"
+/// ```
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct Retention {
+    /// how often old events are deleted. default 10 minutes
+    pub interval: NonZeroDuration,
+    /// retention of kinds without a rule, keep forever if not set
+    pub default: Option<NonZeroDuration>,
+    /// maximum number of events deleted per run. default 10000
+    pub max_delete_per_run: usize,
+    /// per-kind rules as `kind_<number> = "<duration>"`
+    #[serde(flatten)]
+    pub kinds: HashMap<String, NonZeroDuration>,
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(600).try_into().unwrap(),
+            default: None,
+            max_delete_per_run: 10000,
+            kinds: HashMap::new(),
+        }
+    }
+}
+
+impl Retention {
+    /// whether any rule is configured
+    pub fn is_enabled(&self) -> bool {
+        self.default.is_some() || !self.kinds.is_empty()
+    }
+
+    /// parsed per-kind rules, invalid keys are ignored
+    pub fn rules(&self) -> Vec<(u16, Duration)> {
+        let mut rules = self
+            .kinds
+            .iter()
+            .filter_map(
+                |(key, ttl)| match key.strip_prefix("kind_").and_then(|k| k.parse().ok()) {
+                    Some(kind) => Some((kind, **ttl)),
+                    None => {
+                        error!("ignore invalid retention rule: {}", key);
+                        None
+                    }
+                },
+            )
+            .collect::<Vec<_>>();
+        rules.sort();
+        rules
+    }
+
+    /// retention of a kind
+    pub fn ttl(&self, kind: u16) -> Option<Duration> {
+        self.kinds
+            .get(&format!("kind_{}", kind))
+            .or(self.default.as_ref())
+            .map(|d| **d)
+    }
+}
+
+/// Per-kind maximum content size in bytes, oversized events are rejected with `invalid:`
+///
+/// ```toml
+/// [limits]
+/// max_content_length = 262144
+/// kind_443 = 4096
+/// kind_1059 = 65536
+/// ```
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Default)]
+#[serde(default)]
+pub struct Limits {
+    /// content limit of kinds without a rule, unlimited if not set
+    pub max_content_length: Option<usize>,
+    /// per-kind rules as `kind_<number> = <bytes>`
+    #[serde(flatten)]
+    pub kinds: HashMap<String, usize>,
+}
+
+impl Limits {
+    /// content limit of a kind
+    pub fn max_content_length(&self, kind: u16) -> Option<usize> {
+        self.kinds
+            .get(&format!("kind_{}", kind))
+            .copied()
+            .or(self.max_content_length)
+    }
+}
+
+/// NIP-13 minimum proof of work in leading zero bits of the event id, 0 disables
+///
+/// Events below the difficulty of their kind, or committing to a lower target in
+/// their `nonce` tag, are rejected with `pow:`.
+///
+/// ```toml
+/// [pow]
+/// min_difficulty = 16
+/// kind_1 = 20
+/// kind_443 = 0
+/// ```
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Default)]
+#[serde(default)]
+pub struct Pow {
+    /// difficulty of kinds without a rule
+    pub min_difficulty: u8,
+    /// per-kind rules as `kind_<number> = <bits>`
+    #[serde(flatten)]
+    pub kinds: HashMap<String, u8>,
+}
+
+impl Pow {
+    /// minimum difficulty of a kind
+    pub fn min_difficulty(&self, kind: u16) -> u8 {
+        self.kinds
+            .get(&format!("kind_{}", kind))
+            .copied()
+            .unwrap_or(self.min_difficulty)
+    }
+}
+
+/// `/healthz` and `/readyz` probes
+///
+/// `/readyz` fails while the database cannot be read, and with `strict` also
+/// while an extension dependency (e.g. the MLS storage backend) is unavailable.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct Health {
+    /// extension checks fail the probe, otherwise they are only reported. default true
+    pub strict: bool,
+    /// time each check may take before it counts as failed. default 3 seconds
+    pub timeout: NonZeroDuration,
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Self {
+            strict: true,
+            timeout: Duration::from_secs(3).try_into().unwrap(),
+        }
+    }
+}
+
+/// Format of the relay log
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    /// one JSON object per line with Cloud Logging `severity` and `message` fields
+    Json,
+}
+
+/// Relay log output, read once when the relay starts
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct Log {
+    /// text or json. default text
+    pub format: LogFormat,
+    /// mask pubkeys, ids and signatures to a prefix and drop content fields. default false
+    pub redact: bool,
+    /// characters kept of a masked value. default 8
+    pub prefix: usize,
+}
+
+impl Default for Log {
+    fn default() -> Self {
+        Self {
+            format: LogFormat::Text,
+            redact: false,
+            prefix: 8,
+        }
+    }
+}
+
+/// Operator commands over the websocket
+///
+/// Sessions authenticated with NIP-42 (`[extensions.auth] enabled = true`) as
+/// one of `pubkeys` may send `["ADMIN", <verb>, ...]` commands.
+///
+/// ```toml
+/// [admin]
+/// pubkeys = ["<hex pubkey>"]
+/// ```
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Default)]
+#[serde(default)]
+pub struct Admin {
+    /// hex pubkeys allowed to run admin commands, empty disables them
+    pub pubkeys: Vec<String>,
+}
+
+/// Provider loading the `secret://` references of the config, see [`crate::secret`]
+///
+/// ```toml
+/// [secrets]
+/// provider = "gcp"
+/// ```
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct Secrets {
+    /// "gcp" for Secret Manager. default gcp
+    pub provider: String,
+    /// project of secret names given without `projects/`, defaults to GOOGLE_CLOUD_PROJECT
+    pub project_id: Option<String>,
+    /// seconds between checks for new versions of unpinned secrets, 0 disables. default 300
+    pub refresh_secs: u64,
+}
+
+impl Default for Secrets {
+    fn default() -> Self {
+        Self {
+            provider: "gcp".to_owned(),
+            project_id: None,
+            refresh_secs: 300,
+        }
+    }
+}
+
+/// Run order, enable flag and panic budget of an extension, read from `[extensions.<name>]`
+///
+/// Lower priorities run first, ties keep the registration order. An extension
+/// panicking `max_failures` times in a row is skipped until the next reload.
+///
+/// ```toml
+/// [extensions.rate_limiter]
+/// priority = -10
+/// max_failures = 5
+/// [extensions.search]
+/// enabled = false
+/// ```
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct ExtensionControl {
+    pub enabled: bool,
+    pub priority: i32,
+    /// consecutive panics before the extension is disabled, 0 never disables it
+    pub max_failures: u32,
+}
+
+impl Default for ExtensionControl {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            priority: 0,
+            max_failures: 0,
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Default)]
 #[serde(default)]
 pub struct Setting {
@@ -165,6 +541,13 @@ pub struct Setting {
     pub thread: Thread,
     pub network: Network,
     pub limitation: Limitation,
+    pub retention: Retention,
+    pub limits: Limits,
+    pub pow: Pow,
+    pub health: Health,
+    pub log: Log,
+    pub admin: Admin,
+    pub secrets: Secrets,
 
     /// flatten extensions setting to json::Value
     #[serde(flatten)]
@@ -190,6 +573,13 @@ impl PartialEq for Setting {
             && self.thread == other.thread
             && self.network == other.network
             && self.limitation == other.limitation
+            && self.retention == other.retention
+            && self.limits == other.limits
+            && self.pow == other.pow
+            && self.health == other.health
+            && self.log == other.log
+            && self.admin == other.admin
+            && self.secrets == other.secrets
             && self.extra == other.extra
     }
 }
@@ -261,7 +651,7 @@ impl SettingWrapper {
                         match c_setting.reload(&c_file, env_prefix.clone()) {
                             Ok(_) => {
                                 info!("Reload config success {:?}", c_file);
-                                info!("{:?}", c_setting.read());
+                                info!("{}", secret::redact(&format!("{:?}", c_setting.read())));
                                 f(&c_setting);
                             }
                             Err(e) => {
@@ -288,6 +678,49 @@ impl SettingWrapper {
     }
 }
 
+/// Replace `${VAR}` and `${VAR:-default}` with environment variables, `$${` is a literal `${`.
+/// Comment lines are left as they are.
+pub fn interpolate(content: &str) -> Result<String> {
+    let mut out = String::with_capacity(content.len());
+    for line in content.split_inclusive('\n') {
+        if line.trim_start().starts_with('#') {
+            out.push_str(line);
+            continue;
+        }
+        let mut rest = line;
+        while let Some(start) = rest.find("${") {
+            if rest[..start].ends_with('$') {
+                out.push_str(&rest[..start - 1]);
+                out.push_str("${");
+                rest = &rest[start + 2..];
+                continue;
+            }
+            out.push_str(&rest[..start]);
+            let end = rest[start..].find('}').ok_or_else(|| {
+                Error::Message(format!("unclosed ${{ in config: {}", line.trim()))
+            })?;
+            let expr = &rest[start + 2..start + end];
+            let (name, default) = match expr.split_once(":-") {
+                Some((name, default)) => (name, Some(default)),
+                None => (expr, None),
+            };
+            match (std::env::var(name), default) {
+                (Ok(val), _) => out.push_str(&val),
+                (Err(_), Some(default)) => out.push_str(default),
+                (Err(_), None) => {
+                    return Err(Error::Message(format!(
+                        "config references unset environment variable {}",
+                        name
+                    )))
+                }
+            }
+            rest = &rest[start + end + 1..];
+        }
+        out.push_str(rest);
+    }
+    Ok(out)
+}
+
 impl Setting {
     /// add supported nips for nip-11 information
     pub fn add_nip(&mut self, nip: u32) {
@@ -324,7 +757,10 @@ impl Setting {
         if let Some(ext) = self.extra.get("extensions") {
             if let Some(v) = ext.get(key) {
                 return serde_json::from_value::<T>(v.clone()).unwrap_or_else(|err| {
-                    error!(error = err.to_string(), "failed to parse extensions.{:?} setting", key);
+                    error!(
+                        error = err.to_string(),
+                        "failed to parse extensions.{:?} setting", key
+                    );
                     T::default()
                 });
             }
@@ -333,6 +769,37 @@ impl Setting {
         T::default()
     }
 
+    /// Like `parse_extension`, but an invalid setting is an error instead of the default
+    pub fn try_parse_extension<T: DeserializeOwned + Default>(&self, key: &str) -> Result<T> {
+        let value = self
+            .extra
+            .get(key)
+            .or_else(|| self.extra.get("extensions").and_then(|ext| ext.get(key)));
+        match value {
+            Some(v) => serde_json::from_value(v.clone())
+                .map_err(|err| Error::Message(format!("invalid {} setting: {}", key, err))),
+            None => Ok(T::default()),
+        }
+    }
+
+    /// run order and enable flag of an extension, `-` in the name matches `_` in the table name
+    pub fn extension_control(&self, name: &str) -> ExtensionControl {
+        let key = name.replace('-', "_");
+        self.extra
+            .get("extensions")
+            .and_then(|ext| ext.get(&key))
+            .map(|v| {
+                serde_json::from_value(v.clone()).unwrap_or_else(|err| {
+                    error!(
+                        error = err.to_string(),
+                        "failed to parse extensions.{:?} control", key
+                    );
+                    ExtensionControl::default()
+                })
+            })
+            .unwrap_or_default()
+    }
+
     /// save extension setting
     pub fn set_extension<T: Send + Sync + 'static>(&mut self, val: T) {
         self.extensions.insert(TypeId::of::<T>(), Box::new(val));
@@ -353,11 +820,15 @@ impl Setting {
             "description": info.description,
             "pubkey": info.pubkey,
             "contact": info.contact,
+            "icon": info.icon,
             "software": info.software,
             "version": info.version,
             "supported_nips": info.supported_nips,
             "limitation": &self.limitation,
         });
+        if self.pow.min_difficulty > 0 {
+            val["limitation"]["min_pow_difficulty"] = json!(self.pow.min_difficulty);
+        }
         self.ext_limitation.iter().for_each(|(k, v)| {
             val["limitation"][k] = v.clone();
         });
@@ -367,15 +838,28 @@ impl Setting {
         Ok(serde_json::to_string_pretty(&val)?)
     }
 
-    /// read config from file and env
+    /// read config from file and env, `secret://` references are replaced by their loaded values
     pub fn read<P: AsRef<Path>>(file: P, env_prefix: Option<String>) -> Result<Self> {
+        let mut setting = Self::read_unresolved(file, env_prefix)?;
+        setting.resolve_secrets()?;
+        Ok(setting)
+    }
+
+    /// read config from file and env, keeping the `secret://` references
+    pub fn read_unresolved<P: AsRef<Path>>(file: P, env_prefix: Option<String>) -> Result<Self> {
+        let file = file.as_ref();
+        let format = match file.extension().and_then(|e| e.to_str()) {
+            Some("json") => FileFormat::Json,
+            _ => FileFormat::Toml,
+        };
+        let content = interpolate(&fs::read_to_string(file)?)?;
         let builder = Config::builder();
         let mut config = builder
             // Use serde default feature, ignore the following code
             // // use defaults
             // .add_source(Config::try_from(&Self::default())?)
             // override with file contents
-            .add_source(File::with_name(file.as_ref().to_str().unwrap()));
+            .add_source(File::from_str(&content, format));
         if let Some(prefix) = env_prefix {
             config = config.add_source(Self::env_source(&prefix));
         }
@@ -402,6 +886,7 @@ impl Setting {
         let config = config.build()?;
         let mut setting: Setting = config.try_deserialize()?;
         setting.correct();
+        setting.resolve_secrets()?;
         Ok(setting)
     }
 
@@ -414,12 +899,45 @@ impl Setting {
         Ok(setting)
     }
 
+    /// `secret://` references of the extension settings
+    pub fn secret_references(&self) -> Vec<String> {
+        let mut references = vec![];
+        self.extra
+            .values()
+            .for_each(|value| secret::references(value, &mut references));
+        references
+    }
+
+    fn resolve_secrets(&mut self) -> Result<()> {
+        self.extra.values_mut().try_for_each(secret::resolve_json)
+    }
+
     fn correct(&mut self) {
         if self.network.heartbeat_timeout <= self.network.heartbeat_interval {
             error!("network heartbeat_timeout must bigger than heartbeat_interval, use defaults");
             self.network.heartbeat_interval = Duration::from_secs(60).try_into().unwrap();
             self.network.heartbeat_timeout = Duration::from_secs(120).try_into().unwrap();
         }
+        let default = Data::default();
+        if self.data.map_size < MIN_MAP_SIZE {
+            error!(
+                "data map_size must be at least {} bytes, use default",
+                MIN_MAP_SIZE
+            );
+            self.data.map_size = default.map_size;
+        }
+        if self.data.max_readers == 0 {
+            error!("data max_readers must not be 0, use default");
+            self.data.max_readers = default.max_readers;
+        }
+        if self.data.max_dbs < TREES {
+            error!("data max_dbs must be at least {}, use default", TREES);
+            self.data.max_dbs = default.max_dbs;
+        }
+        if self.data.write_batch_size == 0 {
+            error!("data write_batch_size must not be 0, use default");
+            self.data.write_batch_size = default.write_batch_size;
+        }
     }
 }
 
@@ -455,6 +973,33 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn correct_data() -> Result<()> {
+        let toml = r#"
+        [data]
+        map_size = 1024
+        max_readers = 500
+        max_dbs = 2
+        sync = "nometasync"
+        write_batch_size = 0
+        write_interval = "20ms"
+        sync_interval = "1s"
+        "#;
+        let setting = Setting::from_str(toml, FileFormat::Toml)?;
+        let default = Data::default();
+        assert_eq!(setting.data.map_size, default.map_size);
+        assert_eq!(setting.data.max_readers, 500);
+        assert_eq!(setting.data.max_dbs, default.max_dbs);
+        assert_eq!(setting.data.sync, SyncMode::NoMetaSync);
+        assert_eq!(setting.data.write_batch_size, default.write_batch_size);
+        assert_eq!(*setting.data.write_interval, Duration::from_millis(20));
+        assert_eq!(
+            setting.data.sync_interval.map(Into::into),
+            Some(Duration::from_secs(1))
+        );
+        Ok(())
+    }
+
     #[test]
     fn render() -> Result<()> {
         let mut def = Setting::default();
@@ -517,6 +1062,37 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn interpolate_env() -> Result<()> {
+        let content = r#"
+        # ${NOT_INTERPOLATED}
+        [information]
+        name = "${NOSTR_TEST_NAME}"
+        description = "${NOSTR_TEST_UNSET:-a relay}"
+        contact = "$${literal}"
+        [network]
+        port = ${NOSTR_TEST_PORT:-7707}
+        "#;
+        temp_env::with_vars(
+            [
+                ("NOSTR_TEST_NAME", Some("nostr")),
+                ("NOSTR_TEST_UNSET", None),
+                ("NOSTR_TEST_PORT", None),
+            ],
+            || {
+                let setting =
+                    Setting::from_str(&interpolate(content).unwrap(), FileFormat::Toml).unwrap();
+                assert_eq!(setting.information.name, "nostr");
+                assert_eq!(setting.information.description, "a relay");
+                assert_eq!(setting.information.contact, Some("${literal}".to_owned()));
+                assert_eq!(setting.network.port, 7707);
+                assert!(interpolate(r#"name = "${NOSTR_TEST_UNSET}""#).is_err());
+                assert!(interpolate(r#"name = "${NOSTR_TEST_NAME""#).is_err());
+            },
+        );
+        Ok(())
+    }
+
     #[test]
     fn watch() -> Result<()> {
         let file = Builder::new()
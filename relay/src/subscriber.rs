@@ -1,6 +1,8 @@
+use metrics::gauge;
 use std::{
-    collections::HashMap,
+    collections::{BTreeSet, HashMap},
     rc::{Rc, Weak},
+    sync::Arc,
 };
 
 use crate::{message::*, setting::SettingWrapper};
@@ -32,6 +34,27 @@ where
     [key.as_ref(), val.as_ref()].concat()
 }
 
+/// Kinds a subscription asks for, `any` when one of its filters has no kinds
+fn kind_labels(filters: &[Rc<Filter>]) -> BTreeSet<String> {
+    let mut labels = BTreeSet::new();
+    for filter in filters {
+        if filter.kinds.is_empty() {
+            labels.insert("any".to_owned());
+        } else {
+            labels.extend(filter.kinds.iter().map(|k| k.to_string()));
+        }
+    }
+    labels
+}
+
+/// Update the subscription gauges for an installed (`delta` 1) or removed (-1) subscription
+fn track(filters: &[Rc<Filter>], delta: f64) {
+    gauge!("nostr_relay_subscriptions").increment(delta);
+    for kind in kind_labels(filters) {
+        gauge!("nostr_relay_subscriptions_by_kind", "kind" => kind).increment(delta);
+    }
+}
+
 // index for fast filter
 #[derive(Debug, Default)]
 pub struct SubscriberIndex {
@@ -46,6 +69,7 @@ pub struct SubscriberIndex {
 
 impl SubscriberIndex {
     fn install_index(&mut self, session_id: usize, sub_id: String, filters: &[Rc<Filter>]) {
+        track(filters, 1.0);
         for (index, filter) in filters.iter().enumerate() {
             if !filter.ids.is_empty() {
                 for key in filter.ids.iter() {
@@ -94,6 +118,7 @@ impl SubscriberIndex {
                         continue;
                     }
                 }
+                track(filters, -1.0);
                 for (index, filter) in filters.iter().enumerate() {
                     if !filter.ids.is_empty() {
                         for key in filter.ids.iter() {
@@ -156,7 +181,8 @@ impl SubscriberIndex {
         }
 
         if let Some(subs) = self.subscriptions.get(&session_id) {
-            if subs.len() >= limit {
+            // replacing an existing subscription does not count against the limit
+            if subs.len() >= limit && !subs.contains_key(&sub_id) {
                 return Subscribed::Overlimit;
             }
         }
@@ -295,37 +321,51 @@ impl Handler<Unsubscribe> for Subscriber {
     }
 }
 
+impl Handler<SubscriptionCounts> for Subscriber {
+    type Result = MessageResult<SubscriptionCounts>;
+    fn handle(&mut self, _: SubscriptionCounts, _: &mut Self::Context) -> Self::Result {
+        MessageResult(
+            self.index
+                .subscriptions
+                .iter()
+                .map(|(id, subs)| (*id, subs.len()))
+                .collect(),
+        )
+    }
+}
+
+impl Handler<Shutdown> for Subscriber {
+    type Result = ();
+    fn handle(&mut self, msg: Shutdown, _: &mut Self::Context) {
+        let index = std::mem::take(&mut self.index);
+        for (session_id, subs) in index.subscriptions {
+            for sub_id in subs.into_keys() {
+                self.addr.do_send(SubscribeResult {
+                    id: session_id,
+                    msg: OutgoingMessage::refused(&sub_id, Prefix::Error, &msg.message),
+                    sub_id,
+                    event: None,
+                });
+            }
+        }
+    }
+}
+
 impl Handler<Dispatch> for Subscriber {
     type Result = ();
     fn handle(&mut self, msg: Dispatch, _: &mut Self::Context) {
-        let event = &msg.event;
+        let event = Arc::new(msg.event);
         let index = event.index();
         let event_str = event.to_string();
-        
-        // Log when sending keypackage events (kind 443) to clients
-        if event.kind() == 443 {
-            let event_id = event.id_str();
-            let author = hex::encode(event.pubkey());
-            self.index.lookup(index, |session_id, sub_id| {
-                tracing::info!(
-                    "Sending keypackage event (kind:443) {} from author {} to session {} subscription {}",
-                    event_id, author, session_id, sub_id
-                );
-                self.addr.do_send(SubscribeResult {
-                    id: *session_id,
-                    msg: OutgoingMessage::event(sub_id, &event_str),
-                    sub_id: sub_id.clone(),
-                });
-            });
-        } else {
-            self.index.lookup(index, |session_id, sub_id| {
-                self.addr.do_send(SubscribeResult {
-                    id: *session_id,
-                    msg: OutgoingMessage::event(sub_id, &event_str),
-                    sub_id: sub_id.clone(),
-                });
+
+        self.index.lookup(index, |session_id, sub_id| {
+            self.addr.do_send(SubscribeResult {
+                id: *session_id,
+                msg: OutgoingMessage::event(sub_id, &event_str),
+                sub_id: sub_id.clone(),
+                event: Some(Arc::clone(&event)),
             });
-        }
+        });
     }
 }
 
@@ -711,4 +751,22 @@ mod tests {
         assert_eq!(index.tags.len(), 0);
         Ok(())
     }
+
+    #[test]
+    fn kinds() -> Result<()> {
+        let filters = vec![
+            Rc::new(Filter::from_str(r#"{"kinds": [445, 1]}"#)?),
+            Rc::new(Filter::from_str(r#"{"kinds": [1]}"#)?),
+        ];
+        assert_eq!(
+            kind_labels(&filters).into_iter().collect::<Vec<_>>(),
+            vec!["1", "445"]
+        );
+        let filters = vec![Rc::new(Filter::from_str("{}")?)];
+        assert_eq!(
+            kind_labels(&filters).into_iter().collect::<Vec<_>>(),
+            vec!["any"]
+        );
+        Ok(())
+    }
 }
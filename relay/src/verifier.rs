@@ -0,0 +1,58 @@
+use crate::{message::*, Error};
+use actix::prelude::*;
+use metrics::histogram;
+use std::time::Instant;
+
+/// Verify the signature of an event message off the session thread
+#[derive(Message, Debug)]
+#[rtype(result = "Result<ClientMessage, (Error, ClientMessage)>")]
+pub struct VerifyEvent(pub ClientMessage);
+
+/// Signature verification worker
+/// Sessions validate events except for the schnorr signature when `thread.verifier` > 0,
+/// so signature checks of busy connections run on this pool instead of the http workers
+pub struct Verifier;
+
+impl Actor for Verifier {
+    type Context = SyncContext<Self>;
+}
+
+impl Handler<VerifyEvent> for Verifier {
+    type Result = Result<ClientMessage, (Error, ClientMessage)>;
+    fn handle(&mut self, msg: VerifyEvent, _: &mut Self::Context) -> Self::Result {
+        let start = Instant::now();
+        let result = match &msg.0.msg {
+            IncomingMessage::Event(event) => event.verify_sign(),
+            _ => Ok(()),
+        };
+        histogram!("nostr_relay_verify_sign").record(start.elapsed());
+        match result {
+            Ok(()) => Ok(msg.0),
+            Err(e) => Err((e.into(), msg.0)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nostr_db::{
+        secp256k1::{rand::thread_rng, Keypair},
+        Event,
+    };
+
+    #[actix_rt::test]
+    async fn verify() -> anyhow::Result<()> {
+        let verifier = SyncArbiter::start(2, || Verifier);
+        let key_pair = Keypair::new_global(&mut thread_rng());
+        let event = Event::create(&key_pair, 0, 1, vec![], "".to_owned())?;
+        let text = format!(r#"["EVENT", {}]"#, event);
+        let msg = ClientMessage::new(0, text.clone(), serde_json::from_str(&text)?);
+        assert!(verifier.send(VerifyEvent(msg)).await?.is_ok());
+
+        let text = text.replace(&hex::encode(event.sig()), &"00".repeat(64));
+        let msg = ClientMessage::new(0, text.clone(), serde_json::from_str(&text)?);
+        assert!(verifier.send(VerifyEvent(msg)).await?.is_err());
+        Ok(())
+    }
+}
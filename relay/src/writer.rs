@@ -1,26 +1,41 @@
-use crate::{message::*, Result};
+use crate::{
+    message::*,
+    setting::{Retention, SettingWrapper},
+    Result,
+};
 use actix::prelude::*;
-use metrics::{counter, histogram};
-use nostr_db::{now, CheckEventResult, Db};
+use metrics::{counter, gauge, histogram};
+use nostr_db::{now, CheckEventResult, Db, Event, Filter};
 use std::{
     sync::Arc,
     time::{Duration, Instant},
 };
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
 
 /// Single-threaded write events, delete expired events
 /// Batch write can improve tps
 
 const WRITE_INTERVAL_MS: u64 = 100;
+const WRITE_BATCH_SIZE: usize = 1000;
 const DEL_INTERVAL_SECONDS: u64 = 60;
 const EPHEMERAL_EXPIRED_SECONDS: u64 = 60 * 5;
+const USAGE_INTERVAL_SECONDS: u64 = 30;
+/// Map usage ratio above which every usage check warns
+const MAP_USAGE_WARN: f64 = 0.9;
 
 pub struct Writer {
     pub db: Arc<Db>,
     pub addr: Recipient<WriteEventResult>,
     pub events: Vec<WriteEvent>,
     pub write_interval_ms: u64,
+    /// events written in one transaction, a full batch is written before the interval
+    pub write_batch_size: usize,
+    /// flush commits to disk, for environments opened without sync
+    pub sync_interval: Option<Duration>,
     pub del_interval_seconds: u64,
+    pub retention: Retention,
+    /// reloaded settings, retention is reapplied from it before every run
+    pub setting: Option<SettingWrapper>,
 }
 
 impl Writer {
@@ -30,15 +45,24 @@ impl Writer {
             addr,
             events: Vec::new(),
             write_interval_ms: WRITE_INTERVAL_MS,
+            write_batch_size: WRITE_BATCH_SIZE,
+            sync_interval: None,
             del_interval_seconds: DEL_INTERVAL_SECONDS,
+            retention: Retention::default(),
+            setting: None,
         }
     }
 
     pub fn write(&mut self) -> Result<()> {
-        if !self.events.is_empty() {
+        while !self.events.is_empty() {
             let start = Instant::now();
             let mut writer = self.db.writer()?;
-            while let Some(event) = self.events.pop() {
+            let batch = self
+                .events
+                .len()
+                .saturating_sub(self.write_batch_size.max(1));
+            let mut events = self.events.split_off(batch);
+            while let Some(event) = events.pop() {
                 let res = self.db.put(&mut writer, &event.event);
                 debug!(
                     "write event: {} {} {:?}",
@@ -64,7 +88,11 @@ impl Writer {
                         self.addr.do_send(WriteEventResult::Message {
                             id: event.id,
                             event: event.event,
-                            msg: OutgoingMessage::ok(&eid, false, "write event error"),
+                            msg: OutgoingMessage::rejected(
+                                &eid,
+                                Prefix::Error,
+                                "write event error",
+                            ),
                         });
                     }
                 }
@@ -81,6 +109,44 @@ impl Writer {
         }
     }
 
+    /// Flush commits to disk
+    pub fn do_sync(&self) {
+        let start = Instant::now();
+        match self.db.flush() {
+            Ok(()) => histogram!("nostr_relay_db_sync").record(start.elapsed()),
+            Err(err) => error!(error = err.to_string(), "sync database error"),
+        }
+    }
+
+    /// Storage gauges, warns before writes fail with MDB_MAP_FULL
+    pub fn record_usage(&self) {
+        match self.db.storage_stats() {
+            Ok(stats) => {
+                let info = stats.info;
+                gauge!("nostr_relay_db_map_size_bytes").set(info.map_size as f64);
+                gauge!("nostr_relay_db_map_used_bytes").set(info.used_size as f64);
+                gauge!("nostr_relay_db_readers").set(info.num_readers as f64);
+                gauge!("nostr_relay_db_max_readers").set(info.max_readers as f64);
+                gauge!("nostr_relay_db_free_pages").set(stats.free_pages as f64);
+                for (tree, stat) in &stats.trees {
+                    gauge!("nostr_relay_db_entries", "index" => *tree).set(stat.entries as f64);
+                    gauge!("nostr_relay_db_index_bytes", "index" => *tree)
+                        .set((stat.pages() * info.page_size as usize) as f64);
+                }
+                let usage = info.used_size as f64 / info.map_size as f64;
+                if usage > MAP_USAGE_WARN {
+                    warn!(
+                        "database map {:.1}% full ({} of {} bytes), raise data.map_size",
+                        usage * 100.0,
+                        info.used_size,
+                        info.map_size
+                    );
+                }
+            }
+            Err(err) => error!(error = err.to_string(), "read database stats error"),
+        }
+    }
+
     pub fn del_expired(&self) -> Result<()> {
         let reader = self.db.reader()?;
         let iter = self
@@ -91,6 +157,7 @@ impl Writer {
             let id = id?;
             ids.push(id);
         }
+        counter!("nostr_relay_expired_deleted").increment(ids.len() as u64);
         self.db.batch_del(ids)?;
         Ok(())
     }
@@ -109,6 +176,80 @@ impl Writer {
         Ok(())
     }
 
+    /// Delete events older than the retention of their kind
+    pub fn del_retention(&self) -> Result<usize> {
+        let now = now();
+        let max = self.retention.max_delete_per_run;
+        let rules = self.retention.rules();
+        let mut ids = vec![];
+        {
+            let reader = self.db.reader()?;
+            for (kind, ttl) in &rules {
+                let filter = Filter {
+                    kinds: vec![*kind].into(),
+                    until: Some(now.saturating_sub(ttl.as_secs())),
+                    ..Default::default()
+                };
+                let start = ids.len();
+                for id in self.db.iter::<Vec<u8>, _>(&reader, &filter)? {
+                    if ids.len() >= max {
+                        break;
+                    }
+                    ids.push(id?);
+                }
+                counter!("nostr_relay_retention_deleted", "kind" => kind.to_string())
+                    .increment((ids.len() - start) as u64);
+            }
+            if let Some(ttl) = &self.retention.default {
+                let filter = Filter {
+                    until: Some(now.saturating_sub(ttl.as_secs())),
+                    ..Default::default()
+                };
+                let start = ids.len();
+                for event in self.db.iter::<Event, _>(&reader, &filter)? {
+                    if ids.len() >= max {
+                        break;
+                    }
+                    let event = event?;
+                    if rules.iter().all(|(k, _)| *k != event.kind()) {
+                        ids.push(event.id().to_vec());
+                    }
+                }
+                counter!("nostr_relay_retention_deleted", "kind" => "default")
+                    .increment((ids.len() - start) as u64);
+            }
+        }
+        let len = ids.len();
+        self.db.batch_del(ids)?;
+        Ok(len)
+    }
+
+    pub fn do_retention(&mut self) {
+        if let Some(setting) = &self.setting {
+            let retention = setting.read().retention.clone();
+            if retention != self.retention {
+                info!("retention changed: {:?}", retention.rules());
+                self.retention = retention;
+            }
+        }
+        if !self.retention.is_enabled() {
+            return;
+        }
+        match self.del_retention() {
+            Ok(0) => {}
+            Ok(len) => info!("retention deleted {} events", len),
+            Err(err) => error!(error = err.to_string(), "delete retention events error"),
+        }
+    }
+
+    /// Run retention after the current interval, a reload may change both
+    fn schedule_retention(&self, ctx: &mut Context<Self>) {
+        ctx.run_later(*self.retention.interval, |act, ctx| {
+            act.do_retention();
+            act.schedule_retention(ctx);
+        });
+    }
+
     pub fn do_del(&self) {
         if let Err(err) = self.del_expired() {
             error!(error = err.to_string(), "delete expired events error");
@@ -123,13 +264,23 @@ impl Actor for Writer {
     type Context = Context<Self>;
     fn started(&mut self, ctx: &mut Self::Context) {
         info!("Actor writer started");
-        // save event every 100ms
+        // save event every write interval, 100ms by default
         ctx.run_interval(
             Duration::from_millis(self.write_interval_ms),
             |act, _ctx| {
                 act.do_write();
             },
         );
+        if let Some(interval) = self.sync_interval {
+            ctx.run_interval(interval, |act, _ctx| {
+                act.do_sync();
+            });
+        }
+        // database map usage
+        self.record_usage();
+        ctx.run_interval(Duration::from_secs(USAGE_INTERVAL_SECONDS), |act, _ctx| {
+            act.record_usage();
+        });
         // delete expired and ephemeral events
         ctx.run_interval(
             Duration::from_secs(self.del_interval_seconds),
@@ -137,12 +288,20 @@ impl Actor for Writer {
                 act.do_del();
             },
         );
+        // delete events past their kind retention
+        if self.retention.is_enabled() {
+            info!("retention enabled: {:?}", self.retention.rules());
+        }
+        self.schedule_retention(ctx);
     }
 
     fn stopped(&mut self, _ctx: &mut Self::Context) {
         info!("Actor writer stopped");
         // save event when stopped
         self.do_write();
+        if self.sync_interval.is_some() {
+            self.do_sync();
+        }
     }
 }
 
@@ -150,6 +309,20 @@ impl Handler<WriteEvent> for Writer {
     type Result = ();
     fn handle(&mut self, msg: WriteEvent, _: &mut Self::Context) {
         self.events.push(msg);
+        // group commit, a full batch does not wait for the interval
+        if self.events.len() >= self.write_batch_size {
+            self.do_write();
+        }
+    }
+}
+
+impl Handler<Flush> for Writer {
+    type Result = ();
+    fn handle(&mut self, _: Flush, _: &mut Self::Context) {
+        self.do_write();
+        if self.sync_interval.is_some() {
+            self.do_sync();
+        }
     }
 }
 
@@ -158,7 +331,7 @@ mod tests {
     use std::{str::FromStr, time::Duration};
 
     use super::*;
-    use crate::temp_data_path;
+    use crate::{duration::NonZeroDuration, temp_data_path};
     use actix_rt::time::sleep;
     use anyhow::Result;
     use nostr_db::{Event, Filter};
@@ -201,6 +374,9 @@ mod tests {
         let mut writer = Writer::new(Arc::clone(&db), addr.clone());
         writer.del_interval_seconds = 1;
         writer.write_interval_ms = 100;
+        // bursts are split into transactions, full batches commit early
+        writer.write_batch_size = 2;
+        writer.sync_interval = Some(Duration::from_millis(100));
         let writer = writer.start();
 
         for i in 0..4 {
@@ -293,4 +469,58 @@ mod tests {
 
         Ok(())
     }
+
+    #[actix_rt::test]
+    async fn retention() -> Result<()> {
+        let db = Arc::new(Db::open(temp_data_path("writer_retention")?)?);
+        let event = |id: u8, kind: u16, created_at: u64| -> Result<Event> {
+            Ok(Event::from_str(&format!(
+                r#"{{"content": "", "created_at": {}, "id": "{:064x}", "kind": {}, "pubkey": "7abf57d516b1ff7308ca3bd5650ea6a4674d469c7c5057b1d005fb13d218bfef", "sig": "ef4ff4f69ac387239eb1401fb07d7a44a5d5d57127e0dc3466a0403cf7d5486b668608ebfcbe9ff1f8d3b5d710545999fe08ee767284ec0b474e4cf92537678f", "tags": []}}"#,
+                created_at, id, kind
+            ))?)
+        };
+        let old = now() - 3600 * 48;
+        db.batch_put(vec![
+            event(1, 445, old)?,
+            event(2, 445, now())?,
+            event(3, 1, old)?,
+            event(4, 7, old)?,
+        ])?;
+
+        let receiver = Receiver::default().start();
+        let mut writer = Writer::new(Arc::clone(&db), receiver.recipient());
+        writer.retention =
+            serde_json::from_str(r#"{"default": "7d", "kind_445": "1d", "kind_1": "30d"}"#)?;
+        assert_eq!(writer.retention.ttl(445), Some(Duration::from_secs(86400)));
+        assert_eq!(
+            writer.retention.ttl(3),
+            Some(Duration::from_secs(86400 * 7))
+        );
+
+        // 445 past 1 day is deleted, kind 1 and kind 7 are within their retention
+        assert_eq!(writer.del_retention()?, 1);
+        writer.retention.default = NonZeroDuration::new(Duration::from_secs(3600));
+        assert_eq!(writer.del_retention()?, 1);
+
+        let txn = db.reader()?;
+        let kinds = db
+            .iter::<Event, _>(&txn, &Filter::default())?
+            .map(|e| e.map(|e| e.kind()))
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(kinds.len(), 2);
+        assert!(kinds.contains(&445) && kinds.contains(&1));
+        drop(txn);
+
+        // a reloaded setting replaces the retention before the next run
+        let setting: SettingWrapper = crate::Setting::default().into();
+        writer.setting = Some(setting.clone());
+        writer.do_retention();
+        assert!(!writer.retention.is_enabled());
+        setting.write().retention = serde_json::from_str(r#"{"kind_1": "1h"}"#)?;
+        writer.do_retention();
+        assert_eq!(writer.retention.ttl(1), Some(Duration::from_secs(3600)));
+        let txn = db.reader()?;
+        assert_eq!(db.iter::<Event, _>(&txn, &Filter::default())?.count(), 1);
+        Ok(())
+    }
 }
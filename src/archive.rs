@@ -0,0 +1,392 @@
+//! Firestore message archive import/export
+//!
+//! Moves events between the local LMDB store and the `archived_events`
+//! collection used by the MLS gateway for offline delivery.
+
+use crate::{ExportOpts, ImportOpts, RebuildOpts};
+use anyhow::{bail, Context, Result};
+use nostr_db::{Db, Filter};
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// Kinds pulled from the archive when no `--kinds` selection is given
+#[cfg(feature = "mls_gateway_firestore")]
+pub(crate) const DEFAULT_ARCHIVE_KINDS: [u16; 3] = [445, 446, 1059];
+
+/// Export events matching the filter from LMDB into the archive
+#[cfg(feature = "mls_gateway_firestore")]
+pub async fn export_to_archive(opts: ExportOpts) -> Result<usize> {
+    use nostr_db::Event;
+    use nostr_extensions::mls_gateway::MessageArchive;
+    use tracing::info;
+
+    let mut opts = opts;
+    opts.select.apply(&mut opts.filter)?;
+    if let Some(desc) = opts.desc {
+        opts.filter.desc = desc;
+    }
+
+    let archive = MessageArchive::new().await?;
+    let db = Db::open(&opts.path)?;
+    let events = {
+        let reader = db.reader()?;
+        db.iter::<Event, _>(&reader, &opts.filter)?
+            .collect::<Result<Vec<Event>, nostr_db::Error>>()?
+    };
+
+    info!(
+        "Archiving {} events with {} day TTL",
+        events.len(),
+        opts.archive_ttl_days
+    );
+    let mut count = 0;
+    for event in &events {
+        archive
+            .archive_event(event, Some(opts.archive_ttl_days))
+            .await?;
+        count += 1;
+        if count % 1000 == 0 {
+            info!("Archived {}/{} events", count, events.len());
+        }
+    }
+    Ok(count)
+}
+
+/// Import non-expired archived events into LMDB
+#[cfg(feature = "mls_gateway_firestore")]
+pub async fn import_from_archive(opts: ImportOpts) -> Result<usize> {
+    use nostr_extensions::mls_gateway::MessageArchive;
+    use tracing::info;
+
+    let filter = opts.select.to_filter()?;
+    let kinds: Vec<u16> = if filter.kinds.is_empty() {
+        DEFAULT_ARCHIVE_KINDS.to_vec()
+    } else {
+        filter.kinds.to_vec()
+    };
+    let since = opts.select.since.unwrap_or(0) as i64;
+    let until = opts.select.until.map(|t| t as i64);
+
+    let archive = MessageArchive::new().await?;
+    let db = Db::open(&opts.path)?;
+    db.check_schema()?;
+
+    let mut count = 0;
+    for kind in kinds {
+        let mut cursor = None;
+        loop {
+            let page = archive
+                .list_events_page(kind as u32, since, until, cursor.take(), 500)
+                .await?;
+            let Some(last) = page.last() else {
+                break;
+            };
+            cursor = Some((last.created_at() as i64, last.id_str()));
+
+            let page = if opts.skip_verify {
+                page
+            } else {
+                crate::verified(page)
+            };
+            let events = page
+                .into_iter()
+                .filter(|e| filter.r#match(e.index()))
+                .map(|mut e| {
+                    if opts.search {
+                        e.build_note_words();
+                    }
+                    e
+                })
+                .collect::<Vec<_>>();
+            count += events.len();
+            db.batch_put(events)?;
+        }
+        info!(
+            "Imported kind {} from archive, {} events so far",
+            kind, count
+        );
+    }
+    db.flush()?;
+    Ok(count)
+}
+
+/// Events of a kind handled by a rebuild
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RebuildKind {
+    /// Events read from the archive
+    pub fetched: usize,
+    pub inserted: usize,
+    pub duplicate: usize,
+    /// Events failing verification or rejected by the db
+    pub invalid: usize,
+    pub ignored: usize,
+    /// Events of the kind in the rebuilt db
+    pub stored: u64,
+}
+
+/// Result of a rebuild, per kind
+#[derive(Debug, Clone, Default)]
+pub struct RebuildReport {
+    pub kinds: BTreeMap<u16, RebuildKind>,
+    /// Where the replaced database was moved
+    pub backup: Option<PathBuf>,
+}
+
+/// Build a fresh LMDB next to `opts.path` from the archive, verify it holds every inserted
+/// event and swap it in. The relay must be stopped, a failed rebuild leaves PATH untouched.
+#[cfg(feature = "mls_gateway_firestore")]
+pub async fn rebuild_from_archive(opts: RebuildOpts) -> Result<RebuildReport> {
+    use nostr_extensions::mls_gateway::MessageArchive;
+    use tracing::info;
+
+    if !opts.from_archive {
+        bail!("choose a source, --from-archive");
+    }
+    ensure_closed(&opts.path)?;
+    if opts.path.join("data.mdb").exists() && !opts.force {
+        bail!(
+            "{} holds a database, pass --force to replace it",
+            opts.path.display()
+        );
+    }
+    let filter = opts.select.to_filter()?;
+    let kinds: Vec<u16> = if filter.kinds.is_empty() {
+        DEFAULT_ARCHIVE_KINDS.to_vec()
+    } else {
+        filter.kinds.to_vec()
+    };
+    let since = opts.select.since.unwrap_or(0) as i64;
+    let until = opts.select.until.map(|t| t as i64);
+
+    let archive = MessageArchive::new().await?;
+    let mut rebuild_path = opts.path.clone().into_os_string();
+    rebuild_path.push(".rebuild");
+    let rebuild_path = PathBuf::from(rebuild_path);
+    // leftovers of an interrupted rebuild
+    if rebuild_path.exists() {
+        fs::remove_dir_all(&rebuild_path)?;
+    }
+    let db = Db::open(&rebuild_path)?;
+    db.check_schema()?;
+
+    let mut report = RebuildReport::default();
+    for kind in kinds {
+        let stats = report.kinds.entry(kind).or_default();
+        let mut cursor = None;
+        loop {
+            let page = archive
+                .list_events_page(kind as u32, since, until, cursor.take(), opts.page_size)
+                .await?;
+            let Some(last) = page.last() else {
+                break;
+            };
+            cursor = Some((last.created_at() as i64, last.id_str()));
+            let full = page.len() as u32 >= opts.page_size;
+            stats.fetched += page.len();
+
+            let len = page.len();
+            let page = if opts.skip_verify {
+                page
+            } else {
+                crate::verified(page)
+            };
+            stats.invalid += len - page.len();
+            let events = page
+                .into_iter()
+                .filter(|e| filter.r#match(e.index()))
+                .map(|mut e| {
+                    if opts.search {
+                        e.build_note_words();
+                    }
+                    e
+                })
+                .collect::<Vec<_>>();
+            let put = db.batch_put_stats(&events)?;
+            stats.inserted += put.inserted;
+            stats.duplicate += put.duplicate;
+            stats.invalid += put.invalid;
+            stats.ignored += put.ignored;
+            if !full {
+                break;
+            }
+        }
+        info!(
+            "Rebuilt kind {} from archive: {} fetched, {} inserted",
+            kind, stats.fetched, stats.inserted
+        );
+    }
+    db.flush()?;
+
+    verify_and_swap(db, &filter, &mut report, &rebuild_path, &opts.path)?;
+    Ok(report)
+}
+
+/// Check every inserted event is readable by the relay's query path, then move the
+/// rebuild at `rebuild_path` into `path`, keeping a replaced database aside
+#[cfg_attr(not(feature = "mls_gateway_firestore"), allow(dead_code))]
+fn verify_and_swap(
+    db: Db,
+    filter: &Filter,
+    report: &mut RebuildReport,
+    rebuild_path: &Path,
+    path: &Path,
+) -> Result<()> {
+    {
+        let reader = db.reader()?;
+        for (kind, stats) in report.kinds.iter_mut() {
+            let mut kind_filter = filter.clone();
+            kind_filter.kinds = vec![*kind].into();
+            stats.stored = db.iter::<String, _>(&reader, &kind_filter)?.size()?.0;
+            if stats.stored != stats.inserted as u64 {
+                bail!(
+                    "verification failed for kind {}: {} inserted, {} stored, the rebuild is left in {}",
+                    kind,
+                    stats.inserted,
+                    stats.stored,
+                    rebuild_path.display()
+                );
+            }
+        }
+    }
+    drop(db);
+
+    // the relay may have been started while the archive was read
+    ensure_closed(path)?;
+    if path.exists() {
+        let mut backup = path.to_path_buf().into_os_string();
+        backup.push(format!(".old-{}", nostr_db::now()));
+        let backup = PathBuf::from(backup);
+        fs::rename(path, &backup).with_context(|| format!("moving {} aside", path.display()))?;
+        report.backup = Some(backup);
+    }
+    fs::rename(rebuild_path, path)
+        .with_context(|| format!("moving the rebuild into {}", path.display()))?;
+    Ok(())
+}
+
+/// Refuse to replace a database another process has open. LMDB holds a shared
+/// fcntl lock on the first byte of lock.mdb for as long as an environment is open,
+/// readers included.
+#[cfg_attr(not(feature = "mls_gateway_firestore"), allow(dead_code))]
+fn ensure_closed(path: &Path) -> Result<()> {
+    if in_use(&path.join("lock.mdb"))? {
+        bail!(
+            "{} is open in another process, stop the relay before rebuilding",
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+#[cfg_attr(not(feature = "mls_gateway_firestore"), allow(dead_code))]
+fn in_use(lock_file: &Path) -> Result<bool> {
+    use std::os::unix::io::AsRawFd;
+
+    let file = match fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(lock_file)
+    {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e.into()),
+    };
+    // SAFETY: flock is plain data, F_GETLK only fills it in
+    let mut lock: libc::flock = unsafe { std::mem::zeroed() };
+    lock.l_type = libc::F_WRLCK as _;
+    lock.l_whence = libc::SEEK_SET as _;
+    lock.l_start = 0;
+    lock.l_len = 1;
+    // reports a conflicting lock of another process without taking one
+    if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETLK, &mut lock) } == -1 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(lock.l_type != libc::F_UNLCK as _)
+}
+
+#[cfg(not(unix))]
+#[cfg_attr(not(feature = "mls_gateway_firestore"), allow(dead_code))]
+fn in_use(_lock_file: &Path) -> Result<bool> {
+    Ok(false)
+}
+
+#[cfg(not(feature = "mls_gateway_firestore"))]
+pub async fn rebuild_from_archive(_opts: RebuildOpts) -> Result<RebuildReport> {
+    Err(anyhow::anyhow!(
+        "Archive rebuild requires mls_gateway_firestore feature to be enabled"
+    ))
+}
+
+#[cfg(not(feature = "mls_gateway_firestore"))]
+pub async fn export_to_archive(_opts: ExportOpts) -> Result<usize> {
+    Err(anyhow::anyhow!(
+        "Archive export requires mls_gateway_firestore feature to be enabled"
+    ))
+}
+
+#[cfg(not(feature = "mls_gateway_firestore"))]
+pub async fn import_from_archive(_opts: ImportOpts) -> Result<usize> {
+    Err(anyhow::anyhow!(
+        "Archive import requires mls_gateway_firestore feature to be enabled"
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nostr_db::{
+        secp256k1::{Keypair, SECP256K1},
+        Event,
+    };
+
+    fn events(db: &Db, kind: u16, count: u64) -> Result<usize> {
+        let key = Keypair::from_seckey_str(SECP256K1, &"07".repeat(32))?;
+        let events = (0..count)
+            .map(|i| Event::create(&key, i + 1, kind, vec![], i.to_string()))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(db.batch_put_stats(&events)?.inserted)
+    }
+
+    #[test]
+    fn verify_and_swap_rebuild() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("events");
+        let rebuild_path = dir.path().join("events.rebuild");
+        {
+            let old = Db::open(&path)?;
+            events(&old, 1, 1)?;
+        }
+
+        // a count mismatch leaves both databases in place
+        let db = Db::open(&rebuild_path)?;
+        let mut report = RebuildReport::default();
+        report.kinds.entry(445).or_default().inserted = events(&db, 445, 3)? + 1;
+        let swapped = verify_and_swap(db, &Filter::default(), &mut report, &rebuild_path, &path);
+        assert!(swapped.is_err());
+        assert!(rebuild_path.exists() && path.exists());
+
+        let db = Db::open(&rebuild_path)?;
+        report.kinds.entry(445).or_default().inserted = 3;
+        verify_and_swap(db, &Filter::default(), &mut report, &rebuild_path, &path)?;
+        assert_eq!(report.kinds[&445].stored, 3);
+        assert!(!rebuild_path.exists());
+        assert!(report
+            .backup
+            .as_ref()
+            .is_some_and(|b| b.join("data.mdb").exists()));
+
+        let db = Db::open(&path)?;
+        let reader = db.reader()?;
+        assert_eq!(
+            db.iter::<String, _>(&reader, &Filter::default())?.size()?.0,
+            3
+        );
+        // closed in this process, locks of the process itself never conflict
+        assert!(!in_use(&path.join("lock.mdb"))?);
+        Ok(())
+    }
+}
@@ -1,6 +1,7 @@
-use crate::Result;
+use crate::{load, Result};
+use anyhow::Context;
 use clap::Parser;
-use nostr_db::{Db, Filter, Stats};
+use nostr_db::{verify_batch, Db, Event, Filter, Stats};
 use rayon::prelude::*;
 use std::{
     path::PathBuf,
@@ -11,8 +12,8 @@ use std::{
 #[derive(Debug, Clone, Parser)]
 pub struct BenchOpts {
     /// Nostr events data directory path. The "rnostr.example.toml" default setting is "data/events"
-    #[arg(value_name = "PATH")]
-    pub path: PathBuf,
+    #[arg(value_name = "PATH", required_unless_present = "url")]
+    pub path: Option<PathBuf>,
 
     /// [NIP-01](https://nips.be/1) Filter
     #[arg(short = 'f', long, value_name = "FILTER", default_value = "{}")]
@@ -21,11 +22,67 @@ pub struct BenchOpts {
     /// only bench the count method
     #[arg(long, value_name = "BOOL")]
     pub count: bool,
+
+    /// bench signature verification of the matching events, serial and batched
+    #[arg(long, conflicts_with = "count")]
+    pub verify: bool,
+
+    #[command(flatten)]
+    pub load: load::LoadOpts,
 }
 
 pub fn bench_opts(mut opts: BenchOpts) -> anyhow::Result<u64> {
+    if let Some(url) = &opts.load.url {
+        let rt = tokio::runtime::Runtime::new()?;
+        return rt.block_on(load::run(url, &opts.load));
+    }
+    let path = opts.path.as_ref().context("missing data directory path")?;
     opts.filter.build_words();
-    let count = bench(&opts.path, &opts.filter, opts.count)?;
+    let count = if opts.verify {
+        bench_verify(path, &opts.filter)?
+    } else {
+        bench(path, &opts.filter, opts.count)?
+    };
+    Ok(count)
+}
+
+pub fn bench_verify(path: &PathBuf, filter: &Filter) -> Result<u64> {
+    let db = Db::open(path)?;
+    let reader = db.reader()?;
+    let events = db
+        .iter::<Event, _>(&reader, filter)?
+        .collect::<Result<Vec<_>, _>>()?;
+    let count = events.len() as u64;
+    println!("{:?}", filter);
+    println!("Events: {}", count);
+    if events.is_empty() {
+        return Ok(0);
+    }
+
+    println!("Bench serial verification");
+    let now = Instant::now();
+    let invalid = events
+        .iter()
+        .filter(|e| e.verify_id().and_then(|_| e.verify_sign()).is_err())
+        .count();
+    let elapsed = now.elapsed();
+    println!(
+        "Time: {:?}, {}, invalid {}",
+        elapsed,
+        fmt_per_sec(count, &elapsed),
+        invalid
+    );
+
+    println!("Bench batched verification");
+    let now = Instant::now();
+    let invalid = verify_batch(&events).iter().filter(|r| r.is_err()).count();
+    let elapsed = now.elapsed();
+    println!(
+        "Time: {:?}, {}, invalid {}",
+        elapsed,
+        fmt_per_sec(count, &elapsed),
+        invalid
+    );
     Ok(count)
 }
 
@@ -4,44 +4,54 @@
 //! to remove expired keypackages from Firestore.
 
 use anyhow::Result;
-use tracing::{info, error};
+use tracing::{error, info};
+
+/// Resolve the Firestore project ID from the environment
+pub fn firestore_project_id() -> Result<String> {
+    if let Ok(pid) = std::env::var("MLS_FIRESTORE_PROJECT_ID") {
+        Ok(pid)
+    } else if let Ok(pid) = std::env::var("GOOGLE_CLOUD_PROJECT") {
+        Ok(pid)
+    } else if let Ok(pid) = std::env::var("GCP_PROJECT") {
+        Ok(pid)
+    } else {
+        error!("Firestore project ID not configured");
+        Err(anyhow::anyhow!("Firestore project ID not configured"))
+    }
+}
 
 /// Run cleanup of expired keypackages
 #[cfg(feature = "mls_gateway_firestore")]
 pub async fn run_cleanup() -> Result<()> {
     use nostr_extensions::mls_gateway::firestore::FirestoreStorage;
-    
+
     info!("Starting keypackage cleanup job");
-    
-    // Get project ID from environment
-    let project_id = if let Ok(pid) = std::env::var("MLS_FIRESTORE_PROJECT_ID") {
-        pid
-    } else if let Ok(pid) = std::env::var("GOOGLE_CLOUD_PROJECT") {
-        pid
-    } else if let Ok(pid) = std::env::var("GCP_PROJECT") {
-        pid
-    } else {
-        error!("Firestore project ID not configured");
-        return Err(anyhow::anyhow!("Firestore project ID not configured"));
-    };
-    
+
+    let project_id = firestore_project_id()?;
+
     info!("Connecting to Firestore project: {}", project_id);
-    
+
     // Initialize Firestore storage
     let storage = FirestoreStorage::new(&project_id).await?;
-    
+
     // Get max keypackages per user from environment or use default
     let max_per_user = std::env::var("MLS_MAX_KEYPACKAGES_PER_USER")
         .ok()
         .and_then(|v| v.parse::<u32>().ok())
         .unwrap_or(15);
-    
-    info!("Running cleanup with max_keypackages_per_user: {}", max_per_user);
-    
+
+    info!(
+        "Running cleanup with max_keypackages_per_user: {}",
+        max_per_user
+    );
+
     // Run cleanup
     match storage.cleanup_expired_keypackages(max_per_user).await {
         Ok(deleted_count) => {
-            info!("Cleanup complete: deleted {} expired keypackages", deleted_count);
+            info!(
+                "Cleanup complete: deleted {} expired keypackages",
+                deleted_count
+            );
             Ok(())
         }
         Err(e) => {
@@ -54,5 +64,7 @@ pub async fn run_cleanup() -> Result<()> {
 #[cfg(not(feature = "mls_gateway_firestore"))]
 pub async fn run_cleanup() -> Result<()> {
     error!("Cleanup command requires mls_gateway_firestore feature");
-    Err(anyhow::anyhow!("Cleanup command requires mls_gateway_firestore feature to be enabled"))
-}
\ No newline at end of file
+    Err(anyhow::anyhow!(
+        "Cleanup command requires mls_gateway_firestore feature to be enabled"
+    ))
+}
@@ -0,0 +1,314 @@
+//! Publish and query commands against a live relay
+//!
+//! `rnostr publish` signs an event and sends it to a relay, `rnostr req` runs
+//! filters and prints the matching events as jsonl, so operators can exercise
+//! the MLS flows (bootstrap a group, upload a KeyPackage) from a terminal. A
+//! relay asking for NIP-42 auth gets an AUTH event signed with `--key`, and a
+//! message refused with `auth-required` is sent again once authenticated.
+
+use crate::keys;
+use anyhow::{anyhow, Result};
+use clap::Parser;
+use futures_util::{SinkExt, StreamExt};
+use nostr_db::{now, secp256k1::Keypair, Event};
+use serde_json::{json, Value as JsonValue};
+use std::{io::Read, time::Duration};
+use tokio::{net::TcpStream, time::timeout};
+use tokio_tungstenite::{connect_async, tungstenite::Message, MaybeTlsStream, WebSocketStream};
+
+/// NIP-42 auth event kind
+const AUTH_KIND: u16 = 22242;
+
+/// Subscription id of `rnostr req`
+const SUB_ID: &str = "rnostr";
+
+/// publish options
+#[derive(Debug, Parser)]
+pub struct PublishOpts {
+    /// Relay websocket url
+    #[arg(value_name = "URL")]
+    pub url: String,
+
+    /// Secret key signing the event: nsec, hex, or a file holding one
+    #[arg(short, long, value_name = "KEY")]
+    pub key: String,
+
+    /// Unsigned event `{"kind", "tags", "content"}` from a json file, use '-' for stdin
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["kind", "tags"])]
+    pub event: Option<clio::Input>,
+
+    /// Event kind
+    #[arg(long, required_unless_present = "event")]
+    pub kind: Option<u16>,
+
+    /// Event content
+    #[arg(short, long, default_value = "", conflicts_with = "event")]
+    pub content: String,
+
+    /// Tag as comma separated values, e.g. `-t h,<group id>`, repeatable
+    #[arg(short = 't', long = "tag", value_name = "TAG")]
+    pub tags: Vec<String>,
+
+    /// Seconds to wait for each relay answer
+    #[arg(long, value_name = "SECONDS", default_value_t = 10)]
+    pub timeout: u64,
+}
+
+/// req options
+#[derive(Debug, Parser)]
+pub struct ReqOpts {
+    /// Relay websocket url
+    #[arg(value_name = "URL")]
+    pub url: String,
+
+    /// [NIP-01](https://nips.be/1) Filter, repeatable
+    #[arg(
+        short = 'f',
+        long = "filter",
+        value_name = "FILTER",
+        default_value = "{}"
+    )]
+    pub filters: Vec<String>,
+
+    /// Secret key answering NIP-42 auth: nsec, hex, or a file holding one
+    #[arg(short, long, value_name = "KEY")]
+    pub key: Option<String>,
+
+    /// Keep printing live events after EOSE until interrupted
+    #[arg(long)]
+    pub stream: bool,
+
+    /// Seconds to wait for each relay answer before EOSE
+    #[arg(long, value_name = "SECONDS", default_value_t = 10)]
+    pub timeout: u64,
+}
+
+/// Websocket connection to a relay
+struct Relay {
+    url: String,
+    ws: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    /// id of the AUTH event waiting for its OK
+    auth_id: Option<String>,
+    authenticated: bool,
+}
+
+impl Relay {
+    async fn connect(url: &str) -> Result<Self> {
+        let (ws, _) = connect_async(url).await?;
+        Ok(Self {
+            url: url.to_owned(),
+            ws,
+            auth_id: None,
+            authenticated: false,
+        })
+    }
+
+    async fn send(&mut self, msg: JsonValue) -> Result<()> {
+        self.ws.send(Message::Text(msg.to_string())).await?;
+        Ok(())
+    }
+
+    /// Next relay message, waiting at most `wait`
+    async fn recv(&mut self, wait: Option<Duration>) -> Result<Vec<JsonValue>> {
+        loop {
+            let next = match wait {
+                Some(wait) => timeout(wait, self.ws.next())
+                    .await
+                    .map_err(|_| anyhow!("no answer from {} within {:?}", self.url, wait))?,
+                None => self.ws.next().await,
+            };
+            match next {
+                Some(Ok(Message::Text(text))) => match serde_json::from_str(&text) {
+                    Ok(msg) => return Ok(msg),
+                    Err(_) => eprintln!("invalid relay message: {}", text),
+                },
+                Some(Ok(Message::Close(frame))) => {
+                    return Err(anyhow!("relay closed the connection: {:?}", frame))
+                }
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Err(e.into()),
+                None => return Err(anyhow!("relay closed the connection")),
+            }
+        }
+    }
+
+    /// Answer an AUTH challenge with an event signed by `key`
+    async fn authenticate(&mut self, key: &Keypair, challenge: &str) -> Result<()> {
+        let tags = vec![
+            vec!["relay".to_owned(), self.url.clone()],
+            vec!["challenge".to_owned(), challenge.to_owned()],
+        ];
+        let event = Event::create(key, now(), AUTH_KIND, tags, String::new())?;
+        self.auth_id = Some(event.id_str());
+        self.send(json!(["AUTH", event])).await
+    }
+
+    /// Handle the NIP-42 messages, returns true if `msg` was an AUTH OK that authenticated
+    async fn handle_auth(&mut self, msg: &[JsonValue], key: Option<&Keypair>) -> Result<bool> {
+        match head(msg) {
+            (Some("AUTH"), Some(challenge)) => {
+                if let Some(key) = key {
+                    self.authenticate(key, challenge).await?;
+                }
+                Ok(false)
+            }
+            (Some("OK"), Some(id)) if self.auth_id.as_deref() == Some(id) => {
+                self.auth_id = None;
+                if msg.get(2).and_then(|v| v.as_bool()) != Some(true) {
+                    return Err(anyhow!(
+                        "auth rejected: {}",
+                        msg.get(3).and_then(|v| v.as_str()).unwrap_or_default()
+                    ));
+                }
+                self.authenticated = true;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+}
+
+/// Message type and first argument of a relay message
+fn head(msg: &[JsonValue]) -> (Option<&str>, Option<&str>) {
+    (
+        msg.first().and_then(JsonValue::as_str),
+        msg.get(1).and_then(JsonValue::as_str),
+    )
+}
+
+fn is_auth_required(reason: &str) -> bool {
+    reason.starts_with("auth-required:")
+}
+
+/// Kind, tags and content of the event to publish
+fn unsigned_event(opts: &mut PublishOpts) -> Result<(u16, Vec<Vec<String>>, String)> {
+    if let Some(input) = &mut opts.event {
+        let mut text = String::new();
+        input.read_to_string(&mut text)?;
+        let template: JsonValue = serde_json::from_str(&text)?;
+        let kind = template["kind"]
+            .as_u64()
+            .and_then(|k| u16::try_from(k).ok())
+            .ok_or_else(|| anyhow!("event needs a kind"))?;
+        let tags = match template.get("tags") {
+            Some(tags) => serde_json::from_value(tags.clone())?,
+            None => vec![],
+        };
+        let content = template["content"].as_str().unwrap_or_default().to_owned();
+        return Ok((kind, tags, content));
+    }
+    let kind = opts
+        .kind
+        .ok_or_else(|| anyhow!("--kind or --event required"))?;
+    let tags = opts
+        .tags
+        .iter()
+        .map(|tag| tag.split(',').map(ToOwned::to_owned).collect())
+        .collect();
+    Ok((kind, tags, opts.content.clone()))
+}
+
+/// Sign an event, publish it and print it once the relay accepted it
+pub async fn run_publish(mut opts: PublishOpts) -> Result<()> {
+    let key = keys::load_key(&opts.key)?;
+    let (kind, tags, content) = unsigned_event(&mut opts)?;
+    let event = Event::create(&key, now(), kind, tags, content)?;
+    let event_id = event.id_str();
+    let wait = Some(Duration::from_secs(opts.timeout));
+
+    let mut relay = Relay::connect(&opts.url).await?;
+    relay.send(json!(["EVENT", event])).await?;
+    let mut retry = false;
+    loop {
+        let msg = relay.recv(wait).await?;
+        if relay.handle_auth(&msg, Some(&key)).await? {
+            if retry {
+                relay.send(json!(["EVENT", event])).await?;
+            }
+            continue;
+        }
+        match head(&msg) {
+            (Some("OK"), Some(id)) if id == event_id => {
+                let accepted = msg.get(2).and_then(|v| v.as_bool()) == Some(true);
+                let reason = msg.get(3).and_then(|v| v.as_str()).unwrap_or_default();
+                if !accepted && is_auth_required(reason) && !retry {
+                    // sent again after the AUTH OK, at once if already authenticated
+                    retry = true;
+                    if relay.authenticated {
+                        relay.send(json!(["EVENT", event])).await?;
+                    }
+                    continue;
+                }
+                if !accepted {
+                    return Err(anyhow!("event {} rejected: {}", event_id, reason));
+                }
+                println!("{}", event);
+                eprintln!("published {} as {} {}", event_id, keys::npub(&key)?, reason);
+                return Ok(());
+            }
+            (Some("NOTICE"), Some(notice)) => eprintln!("notice: {}", notice),
+            _ => {}
+        }
+    }
+}
+
+/// Run the filters and print the events as jsonl until EOSE, or until interrupted with `--stream`
+pub async fn run_req(opts: ReqOpts) -> Result<()> {
+    let key = opts.key.as_deref().map(keys::load_key).transpose()?;
+    let mut filters = Vec::with_capacity(opts.filters.len());
+    for filter in &opts.filters {
+        let filter: JsonValue = serde_json::from_str(filter)?;
+        if !filter.is_object() {
+            return Err(anyhow!("filter must be a json object: {}", filter));
+        }
+        filters.push(filter);
+    }
+    let mut req = vec![json!("REQ"), json!(SUB_ID)];
+    req.extend(filters);
+    let req = JsonValue::Array(req);
+
+    let mut relay = Relay::connect(&opts.url).await?;
+    relay.send(req.clone()).await?;
+    let mut retry = false;
+    let mut eose = false;
+    let mut count = 0;
+    loop {
+        let wait = (!eose).then(|| Duration::from_secs(opts.timeout));
+        let msg = relay.recv(wait).await?;
+        if relay.handle_auth(&msg, key.as_ref()).await? {
+            if retry {
+                relay.send(req.clone()).await?;
+            }
+            continue;
+        }
+        match head(&msg) {
+            (Some("EVENT"), Some(SUB_ID)) => {
+                if let Some(event) = msg.get(2) {
+                    println!("{}", event);
+                    count += 1;
+                }
+            }
+            (Some("EOSE"), Some(SUB_ID)) => {
+                eprintln!("{} stored events", count);
+                if !opts.stream {
+                    relay.send(json!(["CLOSE", SUB_ID])).await?;
+                    return Ok(());
+                }
+                eose = true;
+            }
+            (Some("CLOSED"), Some(SUB_ID)) => {
+                let reason = msg.get(2).and_then(|v| v.as_str()).unwrap_or_default();
+                if is_auth_required(reason) && key.is_some() && !retry {
+                    retry = true;
+                    if relay.authenticated {
+                        relay.send(req.clone()).await?;
+                    }
+                    continue;
+                }
+                return Err(anyhow!("subscription closed: {}", reason));
+            }
+            (Some("NOTICE"), Some(notice)) => eprintln!("notice: {}", notice),
+            _ => {}
+        }
+    }
+}
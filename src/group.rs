@@ -0,0 +1,254 @@
+//! Group administration command
+//!
+//! Inspect and repair MLS group state kept by the MLS gateway. The storage
+//! backend is taken from `[extensions.mls_gateway]` in the relay config and all
+//! operations go through the `MlsStorage` trait, so they work against Firestore
+//! and SQL alike.
+
+use anyhow::Result;
+use clap::{Parser, Subcommand};
+use std::path::PathBuf;
+
+/// group options
+#[derive(Debug, Clone, Parser)]
+pub struct GroupOpts {
+    /// Nostr relay config path
+    #[arg(
+        short = 'c',
+        value_name = "PATH",
+        default_value = "./config/rnostr.toml"
+    )]
+    pub config: PathBuf,
+
+    #[command(subcommand)]
+    pub command: GroupCommand,
+}
+
+/// group subcommands
+#[derive(Debug, Clone, Subcommand)]
+pub enum GroupCommand {
+    /// Show group info and admins
+    Show {
+        /// Nostr group id (h tag)
+        group_id: String,
+    },
+    /// Replay the roster/policy history of a group
+    History {
+        /// Nostr group id (h tag)
+        group_id: String,
+    },
+    /// List roster sequence gaps and forks
+    Anomalies {
+        /// Only list anomalies of this group
+        group_id: Option<String>,
+    },
+    /// List roster operations waiting for an admin quorum
+    Pending {
+        /// Only list operations of this group
+        group_id: Option<String>,
+    },
+    /// Force-set the owner of a group
+    SetOwner {
+        /// Nostr group id (h tag)
+        group_id: String,
+        /// New owner pubkey (hex)
+        owner: String,
+    },
+    /// Delete a group, its roster history and its archived messages
+    Delete {
+        /// Nostr group id (h tag)
+        group_id: String,
+        /// Keep archived messages of the group
+        #[arg(long)]
+        keep_archive: bool,
+    },
+}
+
+/// Run a group subcommand against the configured storage backend
+#[cfg(feature = "mls_gateway")]
+pub async fn run_group(opts: GroupOpts) -> Result<()> {
+    use nostr_extensions::mls_gateway::{MessageArchive, MlsGatewayConfig, StorageType};
+    use nostr_extensions::MlsGateway;
+    use nostr_relay::Setting;
+
+    let setting = Setting::read(&opts.config, Some("RNOSTR".to_owned()))?;
+    let config: MlsGatewayConfig = setting.try_parse_extension("mls_gateway")?;
+    let storage = MlsGateway::connect(&config).await?;
+    // messages are only archived next to the Firestore backend
+    let archive = match (&opts.command, &config.storage_backend) {
+        (
+            GroupCommand::Delete {
+                keep_archive: false,
+                ..
+            },
+            StorageType::Firestore,
+        ) if config.enable_message_archive => Some(MessageArchive::new().await?),
+        _ => None,
+    };
+    execute(&storage, archive.as_ref(), opts.command).await
+}
+
+#[cfg(not(feature = "mls_gateway"))]
+pub async fn run_group(_opts: GroupOpts) -> Result<()> {
+    tracing::error!("Group command requires mls_gateway feature");
+    Err(anyhow::anyhow!(
+        "Group command requires mls_gateway feature to be enabled"
+    ))
+}
+
+#[cfg(feature = "mls_gateway")]
+async fn execute(
+    storage: &dyn nostr_extensions::mls_gateway::MlsStorage,
+    archive: Option<&nostr_extensions::mls_gateway::MessageArchive>,
+    command: GroupCommand,
+) -> Result<()> {
+    match command {
+        GroupCommand::Show { group_id } => {
+            let group = storage
+                .get_group(&group_id)
+                .await?
+                .ok_or_else(|| anyhow::anyhow!("Group {} not found", group_id))?;
+            println!("group_id:       {}", group.group_id);
+            println!(
+                "display_name:   {}",
+                group.display_name.as_deref().unwrap_or("-")
+            );
+            println!("owner:          {}", group.owner_pubkey);
+            println!(
+                "last_epoch:     {}",
+                group.last_epoch.map_or("-".to_string(), |e| e.to_string())
+            );
+            println!("service_member: {}", group.service_member);
+            println!("created_at:     {}", group.created_at);
+            println!("updated_at:     {}", group.updated_at);
+            println!("admins ({}):", group.admin_pubkeys.len());
+            for admin in &group.admin_pubkeys {
+                println!("  {}", admin);
+            }
+            let last_seq = storage.get_last_roster_sequence(&group_id).await?;
+            println!(
+                "roster_seq:     {}",
+                last_seq.map_or("-".to_string(), |s| s.to_string())
+            );
+        }
+        GroupCommand::History { group_id } => {
+            let history = storage.list_roster_history(&group_id).await?;
+            if history.is_empty() {
+                println!("No roster/policy history for group {}", group_id);
+                return Ok(());
+            }
+            let mut members = std::collections::BTreeSet::new();
+            let mut admins = std::collections::BTreeSet::new();
+            for record in &history {
+                println!(
+                    "seq={} op={} by={} at={} members={:?}",
+                    record.sequence,
+                    record.operation,
+                    record.admin_pubkey,
+                    record.created_at,
+                    record.member_pubkeys
+                );
+                match record.operation.as_str() {
+                    "bootstrap" => {
+                        members.insert(record.admin_pubkey.clone());
+                        members.extend(record.member_pubkeys.iter().cloned());
+                        admins.insert(record.admin_pubkey.clone());
+                    }
+                    "add" => members.extend(record.member_pubkeys.iter().cloned()),
+                    "remove" => {
+                        for p in &record.member_pubkeys {
+                            members.remove(p);
+                            admins.remove(p);
+                        }
+                    }
+                    "replace" => members = record.member_pubkeys.iter().cloned().collect(),
+                    "promote" => admins.extend(record.member_pubkeys.iter().cloned()),
+                    "demote" => {
+                        for p in &record.member_pubkeys {
+                            admins.remove(p);
+                        }
+                    }
+                    op => println!("  warning: unknown operation {}", op),
+                }
+            }
+            println!("replayed {} records", history.len());
+            println!("members ({}):", members.len());
+            for m in &members {
+                println!("  {}", m);
+            }
+            println!("admins ({}):", admins.len());
+            for a in &admins {
+                println!("  {}", a);
+            }
+        }
+        GroupCommand::Anomalies { group_id } => {
+            let anomalies = storage.list_roster_anomalies(group_id.as_deref()).await?;
+            if anomalies.is_empty() {
+                println!("No roster anomalies");
+                return Ok(());
+            }
+            for a in &anomalies {
+                println!(
+                    "group={} {} seq={} last={} op={} by={} event={} {} at={}",
+                    a.group_id,
+                    a.kind,
+                    a.sequence,
+                    a.last_sequence.map_or("-".to_string(), |s| s.to_string()),
+                    a.operation,
+                    a.admin_pubkey,
+                    a.event_id,
+                    a.action,
+                    a.created_at
+                );
+                if let Some(stored) = &a.stored {
+                    println!(
+                        "  stored: op={} by={} members={:?}",
+                        stored.operation, stored.admin_pubkey, stored.member_pubkeys
+                    );
+                }
+            }
+        }
+        GroupCommand::Pending { group_id } => {
+            let pending = storage.list_pending_roster_ops(group_id.as_deref()).await?;
+            if pending.is_empty() {
+                println!("No pending roster operations");
+                return Ok(());
+            }
+            for op in &pending {
+                println!(
+                    "group={} seq={} op={} by={} event={} {} approvals={}/{} expires_at={}",
+                    op.group_id,
+                    op.sequence,
+                    op.operation,
+                    op.proposer,
+                    op.event_id,
+                    op.status,
+                    op.approvals.len(),
+                    op.required,
+                    op.expires_at
+                );
+                println!(
+                    "  members={:?} approved_by={:?}",
+                    op.member_pubkeys, op.approvals
+                );
+            }
+        }
+        GroupCommand::SetOwner { group_id, owner } => {
+            storage.set_group_owner(&group_id, &owner).await?;
+            storage.add_admins(&group_id, &[owner.clone()]).await?;
+            println!("Set owner of group {} to {}", group_id, owner);
+        }
+        GroupCommand::Delete { group_id, .. } => {
+            if !storage.group_exists(&group_id).await? {
+                return Err(anyhow::anyhow!("Group {} not found", group_id));
+            }
+            let roster = storage.delete_group(&group_id).await?;
+            println!("Deleted group {} ({} roster records)", group_id, roster);
+            if let Some(archive) = archive {
+                let archived = archive.delete_group_events(&group_id).await?;
+                println!("Deleted {} archived events", archived);
+            }
+        }
+    }
+    Ok(())
+}
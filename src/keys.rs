@@ -0,0 +1,187 @@
+//! Nostr keys for the CLI
+//!
+//! Secret keys are given as an `nsec` (NIP-19), 64 hex characters, or the
+//! path of a file holding one of them. Files may also hold an `ncryptsec`
+//! (NIP-49), decrypted with the `RNOSTR_KEY_PASSPHRASE` env var or a
+//! passphrase typed at the prompt. Passphrases are never read from argv.
+
+use anyhow::{anyhow, Result};
+use bech32::{FromBase32, ToBase32, Variant};
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    Key, XChaCha20Poly1305, XNonce,
+};
+use nostr_db::secp256k1::{
+    rand::{thread_rng, RngCore},
+    Keypair, SECP256K1,
+};
+use std::{fs, path::Path};
+use unicode_normalization::UnicodeNormalization;
+
+/// Env var holding the passphrase of encrypted keys
+pub const PASSPHRASE_ENV: &str = "RNOSTR_KEY_PASSPHRASE";
+
+/// NIP-49 version byte
+const NCRYPTSEC_VERSION: u8 = 0x02;
+
+/// NIP-49 key security byte: not known to have been handled insecurely
+const KEY_SECURITY: u8 = 0x01;
+
+/// NIP-19 bech32 encoding of a key, `hrp` is `npub` or `nsec`
+pub fn encode(hrp: &str, key: &[u8]) -> Result<String> {
+    Ok(bech32::encode(hrp, key.to_base32(), Variant::Bech32)?)
+}
+
+/// Key bytes of a NIP-19 bech32 string with the expected `hrp`
+pub fn decode(hrp: &str, value: &str) -> Result<Vec<u8>> {
+    let (found, data, variant) = bech32::decode(value)?;
+    if found != hrp || variant != Variant::Bech32 {
+        return Err(anyhow!("expected an {} key, got {}", hrp, found));
+    }
+    Ok(Vec::<u8>::from_base32(&data)?)
+}
+
+/// Keypair of an nsec or hex secret key, or of a file holding one
+pub fn load_key(key: &str) -> Result<Keypair> {
+    let path = Path::new(key);
+    let secret = if path.is_file() {
+        fs::read_to_string(path)?.trim().to_owned()
+    } else {
+        key.trim().to_owned()
+    };
+    let keypair = if secret.starts_with("ncryptsec1") {
+        decrypt(&secret, &passphrase(false)?)?
+    } else if secret.starts_with("nsec1") {
+        Keypair::from_seckey_slice(SECP256K1, &decode("nsec", &secret)?)?
+    } else {
+        Keypair::from_seckey_str(SECP256K1, &secret)
+            .map_err(|_| anyhow!("invalid secret key, expected nsec or hex"))?
+    };
+    Ok(keypair)
+}
+
+/// Passphrase of an encrypted key from the env var, else typed at the prompt,
+/// twice when `confirm` is set
+pub fn passphrase(confirm: bool) -> Result<String> {
+    if let Some(passphrase) = std::env::var(PASSPHRASE_ENV).ok().filter(|p| !p.is_empty()) {
+        return Ok(passphrase);
+    }
+    let passphrase = rpassword::prompt_password("Key passphrase: ").map_err(|e| {
+        anyhow!(
+            "{} is not set and no passphrase could be read: {}",
+            PASSPHRASE_ENV,
+            e
+        )
+    })?;
+    if confirm && rpassword::prompt_password("Repeat passphrase: ")? != passphrase {
+        return Err(anyhow!("passphrases do not match"));
+    }
+    if passphrase.is_empty() {
+        return Err(anyhow!("empty passphrase"));
+    }
+    Ok(passphrase)
+}
+
+/// npub of a keypair
+pub fn npub(keypair: &Keypair) -> Result<String> {
+    encode("npub", &keypair.x_only_public_key().0.serialize())
+}
+
+/// NIP-49 `ncryptsec` of a secret key, scrypt with `2^log_n` rounds and XChaCha20-Poly1305
+pub fn encrypt(keypair: &Keypair, passphrase: &str, log_n: u8) -> Result<String> {
+    let mut salt = [0u8; 16];
+    let mut nonce = [0u8; 24];
+    thread_rng().fill_bytes(&mut salt);
+    thread_rng().fill_bytes(&mut nonce);
+    let cipher = XChaCha20Poly1305::new(Key::from_slice(&symmetric_key(passphrase, &salt, log_n)?));
+    let ciphertext = cipher
+        .encrypt(
+            XNonce::from_slice(&nonce),
+            Payload {
+                msg: &keypair.secret_bytes(),
+                aad: &[KEY_SECURITY],
+            },
+        )
+        .map_err(|_| anyhow!("failed to encrypt the secret key"))?;
+
+    let mut data = Vec::with_capacity(91);
+    data.push(NCRYPTSEC_VERSION);
+    data.push(log_n);
+    data.extend_from_slice(&salt);
+    data.extend_from_slice(&nonce);
+    data.push(KEY_SECURITY);
+    data.extend_from_slice(&ciphertext);
+    encode("ncryptsec", &data)
+}
+
+/// Keypair of a NIP-49 `ncryptsec`
+pub fn decrypt(ncryptsec: &str, passphrase: &str) -> Result<Keypair> {
+    let data = decode("ncryptsec", ncryptsec)?;
+    if data.len() != 91 || data[0] != NCRYPTSEC_VERSION {
+        return Err(anyhow!("unsupported ncryptsec"));
+    }
+    let (log_n, salt, nonce, key_security, ciphertext) =
+        (data[1], &data[2..18], &data[18..42], data[42], &data[43..]);
+    let cipher = XChaCha20Poly1305::new(Key::from_slice(&symmetric_key(passphrase, salt, log_n)?));
+    let secret = cipher
+        .decrypt(
+            XNonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad: &[key_security],
+            },
+        )
+        .map_err(|_| anyhow!("wrong passphrase"))?;
+    Ok(Keypair::from_seckey_slice(SECP256K1, &secret)?)
+}
+
+fn symmetric_key(passphrase: &str, salt: &[u8], log_n: u8) -> Result<[u8; 32]> {
+    let passphrase = passphrase.nfkc().collect::<String>();
+    let params =
+        scrypt::Params::new(log_n, 8, 1, 32).map_err(|e| anyhow!("invalid scrypt log_n: {}", e))?;
+    let mut key = [0u8; 32];
+    scrypt::scrypt(passphrase.as_bytes(), salt, &params, &mut key)
+        .map_err(|e| anyhow!("scrypt: {}", e))?;
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decrypt_spec_vector() -> Result<()> {
+        // NIP-49 test vector, log_n 16
+        let keypair = decrypt(
+            "ncryptsec1qgg9947rlpvqu76pj5ecreduf9jxhselq2nae2kghhvd5g7dgjtcxfqtd67p9m0w57lspw8gsq6yphnm8623nsl8xn9j4jdzz84zm3frztj3z7s35vpzmqf6ksu8r89qk5z2zxfmu5gv8th8wclt0h4p",
+            "nostr",
+        )?;
+        assert_eq!(
+            keypair.display_secret().to_string(),
+            "3501454135014541350145413501453fefb02227e449e57cf4d3a3ce05378683"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn roundtrip() -> Result<()> {
+        let keypair = Keypair::new(SECP256K1, &mut thread_rng());
+        let ncryptsec = encrypt(&keypair, "passphrase", 4)?;
+        assert!(ncryptsec.starts_with("ncryptsec1"));
+        assert_eq!(decrypt(&ncryptsec, "passphrase")?, keypair);
+        assert!(decrypt(&ncryptsec, "other").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn normalized_passphrase() -> Result<()> {
+        // the NIP-49 example passphrase and a decomposed spelling of it derive the same key
+        let keypair = Keypair::new(SECP256K1, &mut thread_rng());
+        let ncryptsec = encrypt(&keypair, "\u{212b}\u{2126}\u{1e9b}\u{323}", 4)?;
+        assert_eq!(
+            decrypt(&ncryptsec, "A\u{30a}\u{3a9}\u{17f}\u{323}\u{307}")?,
+            keypair
+        );
+        Ok(())
+    }
+}
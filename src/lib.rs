@@ -1,17 +1,33 @@
-use clap::Parser;
+use clap::{Args, Parser};
 use clio::{Input, Output};
+use flate2::{read::MultiGzDecoder, write::GzEncoder, Compression};
 use indicatif::{ProgressBar, ProgressState, ProgressStyle};
 use nostr_db::{Db, Event, Filter, FromEventData};
 use rayon::prelude::*;
 use std::{
+    cell::Cell,
+    collections::BTreeMap,
     fs::File,
-    io::{BufRead, BufReader, Write},
+    io::{BufRead, BufReader, Read, Write},
     path::{Path, PathBuf},
+    time::{Duration, Instant},
 };
 
+pub mod archive;
 mod bench;
-mod relay;
 pub mod cleanup;
+pub mod client;
+pub mod group;
+pub mod keys;
+pub mod load;
+mod logging;
+pub mod preflight;
+pub mod privacy;
+pub mod purge;
+mod relay;
+pub mod rotations;
+pub mod service_key;
+pub mod snapshot;
 
 pub use bench::*;
 pub use relay::*;
@@ -34,6 +50,61 @@ pub enum Error {
 
 pub type Result<T, E = Error> = core::result::Result<T, E>;
 
+/// event selection options shared by import and export
+#[derive(Debug, Clone, Default, Args)]
+pub struct SelectOpts {
+    /// Only include events of these kinds, comma separated
+    #[arg(long, value_name = "KINDS", value_delimiter = ',')]
+    pub kinds: Vec<u16>,
+
+    /// Only include events from this author (hex pubkey), can be repeated
+    #[arg(long = "author", value_name = "PUBKEY")]
+    pub authors: Vec<String>,
+
+    /// Only include events created at or after this unix timestamp
+    #[arg(long, value_name = "TIMESTAMP")]
+    pub since: Option<u64>,
+
+    /// Only include events created at or before this unix timestamp
+    #[arg(long, value_name = "TIMESTAMP")]
+    pub until: Option<u64>,
+}
+
+impl SelectOpts {
+    /// Merge the selection into a filter, the selection takes precedence
+    pub fn apply(&self, filter: &mut Filter) -> Result<()> {
+        let select: Filter = serde_json::from_value(serde_json::json!({
+            "kinds": self.kinds,
+            "authors": self.authors,
+        }))
+        .map_err(|e| Error::Message(format!("invalid selection: {}", e)))?;
+        if !select.kinds.is_empty() {
+            filter.kinds = select.kinds;
+        }
+        if !select.authors.is_empty() {
+            filter.authors = select.authors;
+        }
+        if self.since.is_some() {
+            filter.since = self.since;
+        }
+        if self.until.is_some() {
+            filter.until = self.until;
+        }
+        Ok(())
+    }
+
+    /// Build a filter from the selection only
+    pub fn to_filter(&self) -> Result<Filter> {
+        let mut filter = Filter::default();
+        self.apply(&mut filter)?;
+        Ok(filter)
+    }
+}
+
+fn is_gzip(path: &Path, flag: bool) -> bool {
+    flag || path.extension().map_or(false, |e| e == "gz")
+}
+
 /// import options
 #[derive(Debug, Clone, Parser)]
 pub struct ImportOpts {
@@ -45,6 +116,25 @@ pub struct ImportOpts {
     #[arg(long, value_name = "BOOL")]
     pub search: bool,
 
+    #[command(flatten)]
+    pub select: SelectOpts,
+
+    /// Input is gzip compressed, implied by a ".gz" file extension
+    #[arg(long)]
+    pub gzip: bool,
+
+    /// Import from the Firestore message archive instead of the input file, expired events are skipped
+    #[arg(long)]
+    pub from_archive: bool,
+
+    /// Skip event id and signature verification, for trusted dumps
+    #[arg(long)]
+    pub skip_verify: bool,
+
+    /// Seconds between progress reports (0 disables)
+    #[arg(long, value_name = "SECONDS", default_value_t = 10)]
+    pub progress_secs: u64,
+
     /// input jsonl data file, use '-' for stdin
     #[clap(value_parser, default_value = "-")]
     pub input: Input,
@@ -65,6 +155,21 @@ pub struct ExportOpts {
     #[arg(long, value_name = "BOOL")]
     pub desc: Option<bool>,
 
+    #[command(flatten)]
+    pub select: SelectOpts,
+
+    /// Compress output with gzip, implied by a ".gz" file extension
+    #[arg(long)]
+    pub gzip: bool,
+
+    /// Export to the Firestore message archive instead of the output file
+    #[arg(long)]
+    pub to_archive: bool,
+
+    /// Archive TTL in days used with --to-archive
+    #[arg(long, value_name = "DAYS", default_value_t = 7)]
+    pub archive_ttl_days: u32,
+
     /// output jsonl data file, use '-' for stdout
     #[clap(value_parser, default_value = "-")]
     pub output: Output,
@@ -84,101 +189,355 @@ pub struct DeleteOpts {
     /// Dry run
     #[arg(long)]
     pub dry_run: bool,
+
+    /// Also delete matching events from the Firestore message archive
+    #[arg(long)]
+    pub include_archive: bool,
+
+    /// Also delete matching KeyPackages (443) from the MLS gateway store
+    #[arg(long)]
+    pub include_keypackages: bool,
+
+    /// Also delete the groups named by the filter #h tag, with their roster history
+    #[arg(long)]
+    pub include_groups: bool,
+}
+
+/// backfill status options
+#[derive(Debug, Clone, Parser)]
+pub struct BackfillStatusOpts {
+    /// Nostr events data directory path. The "rnostr.example.toml" default setting is "data/events"
+    #[arg(value_name = "PATH")]
+    pub path: PathBuf,
+}
+
+/// rebuild options
+#[derive(Debug, Clone, Parser)]
+pub struct RebuildOpts {
+    /// Nostr events data directory path. The "rnostr.example.toml" default setting is "data/events"
+    #[arg(value_name = "PATH")]
+    pub path: PathBuf,
+
+    /// Repopulate from the Firestore message archive, the only source for now
+    #[arg(long)]
+    pub from_archive: bool,
+
+    #[command(flatten)]
+    pub select: SelectOpts,
+
+    /// Replace an existing database, it is kept as PATH.old-TIMESTAMP
+    #[arg(long)]
+    pub force: bool,
+
+    /// Support search
+    #[arg(long)]
+    pub search: bool,
+
+    /// Skip event id and signature verification
+    #[arg(long)]
+    pub skip_verify: bool,
+
+    /// Archive page size
+    #[arg(long, value_name = "EVENTS", default_value_t = 500)]
+    pub page_size: u32,
+}
+
+/// Print the backfill checkpoint stored in the data directory
+#[cfg(feature = "mls_gateway")]
+pub fn backfill_status(path: &Path) -> anyhow::Result<()> {
+    let checkpoint = nostr_extensions::mls_gateway::backfill::BackfillCheckpoint::load(path);
+    let mut kinds = checkpoint.kinds.iter().collect::<Vec<_>>();
+    kinds.sort_by_key(|(k, _)| **k);
+    for (kind, c) in kinds {
+        println!("kind {}: created_at={} id={}", kind, c.created_at, c.id);
+    }
+    match &checkpoint.last_report {
+        Some(report) => println!("{}", serde_json::to_string_pretty(report)?),
+        None => println!("No backfill run recorded"),
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "mls_gateway"))]
+pub fn backfill_status(_path: &Path) -> anyhow::Result<()> {
+    Err(anyhow::anyhow!(
+        "Backfill status requires mls_gateway feature to be enabled"
+    ))
 }
 
 /// import
 pub fn import_opts(opts: ImportOpts) -> anyhow::Result<usize> {
-    fn run_import_opts<F: Fn(usize)>(opts: ImportOpts, f: F) -> anyhow::Result<usize> {
-        let count = import(&opts.path, opts.input, 10000, opts.search, f)?;
-        Ok(count)
+    fn run_import_opts<F: Fn(&ImportStats)>(
+        opts: ImportOpts,
+        gzip: bool,
+        f: F,
+    ) -> anyhow::Result<ImportStats> {
+        let filter = opts.select.to_filter()?;
+        let verify = !opts.skip_verify;
+        let stats = if gzip {
+            import(
+                &opts.path,
+                MultiGzDecoder::new(opts.input),
+                10000,
+                opts.search,
+                verify,
+                &filter,
+                f,
+            )?
+        } else {
+            import(
+                &opts.path,
+                opts.input,
+                10000,
+                opts.search,
+                verify,
+                &filter,
+                f,
+            )?
+        };
+        Ok(stats)
     }
 
     let path = opts.input.path();
-    if path.is_local() {
-        let total_size = count_lines(path.path())? as u64;
+    let gzip = is_gzip(path.path(), opts.gzip);
+    let interval = Duration::from_secs(opts.progress_secs);
+    let started = Instant::now();
+    let last = Cell::new(started);
+    let progress = |stats: &ImportStats| {
+        if interval.is_zero() || last.get().elapsed() < interval {
+            return None;
+        }
+        last.set(Instant::now());
+        Some(format!(
+            "{}, {}",
+            stats,
+            fmt_per_sec(stats.lines as u64, &started.elapsed())
+        ))
+    };
+    let stats = if path.is_local() {
+        let total_size = count_lines(path.path(), gzip)? as u64;
         let pb = create_pb(total_size);
-        let total = run_import_opts(opts, |c| {
-            if c % 1000 == 0 {
-                pb.set_position(c as u64);
+        let stats = run_import_opts(opts, gzip, |stats| {
+            pb.set_position(stats.lines as u64);
+            if let Some(line) = progress(stats) {
+                pb.println(line);
             }
         })?;
         pb.finish_with_message("finished");
-        Ok(total)
+        stats
     } else {
-        run_import_opts(opts, |_| {})
-    }
+        run_import_opts(opts, gzip, |stats| {
+            if let Some(line) = progress(stats) {
+                eprintln!("{}", line);
+            }
+        })?
+    };
+    eprintln!(
+        "{}, {}",
+        stats,
+        fmt_per_sec(stats.lines as u64, &started.elapsed())
+    );
+    Ok(stats.imported)
 }
 
-fn count_lines<P: AsRef<Path>>(path: P) -> std::io::Result<usize> {
+fn count_lines<P: AsRef<Path>>(path: P, gzip: bool) -> std::io::Result<usize> {
     let file = File::open(path)?;
-    let reader = BufReader::new(file);
-    let lines = reader.lines();
-    Ok(lines.count())
+    let lines = if gzip {
+        BufReader::new(MultiGzDecoder::new(file)).lines().count()
+    } else {
+        BufReader::new(file).lines().count()
+    };
+    Ok(lines)
+}
+
+/// Import counters
+#[derive(Debug, Clone, Default)]
+pub struct ImportStats {
+    /// lines read
+    pub lines: usize,
+    /// events written
+    pub imported: usize,
+    /// events outside the selection
+    pub skipped: usize,
+    /// rejected lines by reason
+    pub rejected: BTreeMap<&'static str, usize>,
 }
 
-pub fn import<F: Fn(usize)>(
+impl ImportStats {
+    fn reject(&mut self, reason: &'static str) {
+        *self.rejected.entry(reason).or_default() += 1;
+    }
+
+    fn merge(&mut self, other: &ImportStats) {
+        self.lines += other.lines;
+        self.imported += other.imported;
+        self.skipped += other.skipped;
+        for (reason, count) in &other.rejected {
+            *self.rejected.entry(reason).or_default() += count;
+        }
+    }
+}
+
+impl std::fmt::Display for ImportStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "read {}, imported {}, skipped {}, rejected {}",
+            self.lines,
+            self.imported,
+            self.skipped,
+            self.rejected.values().sum::<usize>()
+        )?;
+        if !self.rejected.is_empty() {
+            let reasons = self
+                .rejected
+                .iter()
+                .map(|(reason, count)| format!("{} {}", reason, count))
+                .collect::<Vec<_>>();
+            write!(f, " ({})", reasons.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Lines parsed per chunk, one chunk per thread is in flight
+const IMPORT_CHUNK: usize = 1000;
+
+/// Parse, select and verify a chunk of jsonl lines
+fn parse_chunk(
+    lines: &[String],
+    search: bool,
+    verify: bool,
+    filter: &Filter,
+) -> (Vec<Event>, ImportStats) {
+    let mut stats = ImportStats {
+        lines: lines.len(),
+        ..Default::default()
+    };
+    let mut events = Vec::with_capacity(lines.len());
+    for line in lines {
+        let mut event = match Event::from_data(line.as_bytes()) {
+            Ok(event) => event,
+            Err(e) => {
+                println!("error: {} {}", line, e);
+                stats.reject("parse");
+                continue;
+            }
+        };
+        if !filter.r#match(event.index()) {
+            stats.skipped += 1;
+            continue;
+        }
+        if verify {
+            let reason = if event.verify_id().is_err() {
+                Some("id")
+            } else if event.verify_sign().is_err() {
+                Some("signature")
+            } else {
+                None
+            };
+            if let Some(reason) = reason {
+                println!("error: {} invalid {}", event.id_str(), reason);
+                stats.reject(reason);
+                continue;
+            }
+        }
+        if search {
+            event.build_note_words();
+        }
+        events.push(event);
+    }
+    stats.imported = events.len();
+    (events, stats)
+}
+
+/// Import a jsonl stream, chunks are parsed and verified in parallel while a writer thread commits every `batch` events
+pub fn import<R: Read, F: Fn(&ImportStats)>(
     path: &PathBuf,
-    input: Input,
+    input: R,
     batch: usize,
     search: bool,
+    verify: bool,
+    filter: &Filter,
     f: F,
-) -> Result<usize> {
+) -> Result<ImportStats> {
     let db = Db::open(path)?;
     db.check_schema()?;
-    let reader = BufReader::new(input);
-    let lines = reader.lines();
-    let mut batches = vec![];
-    let mut count = 0;
-
-    fn parse_events(batches: &Vec<String>, search: bool) -> Vec<Event> {
-        batches
-            .par_iter()
-            .filter_map(|s| {
-                let event = Event::from_data(s.as_bytes());
-                match event {
-                    Ok(mut event) => {
-                        if search {
-                            event.build_note_words();
-                        }
-                        Some(event)
-                    }
-                    Err(e) => {
-                        println!("error: {} {}", s, e);
-                        None
-                    }
+    let in_flight = rayon::current_num_threads();
+    let mut stats = ImportStats::default();
+
+    std::thread::scope(|scope| -> Result<()> {
+        // bounded so reading stalls when the writer falls behind
+        let (sender, receiver) = std::sync::mpsc::sync_channel::<Vec<Event>>(in_flight);
+        let db = &db;
+        let writer = scope.spawn(move || -> Result<()> {
+            let mut writer = db.writer()?;
+            let mut uncommitted = 0;
+            for events in receiver {
+                uncommitted += events.len();
+                for event in events {
+                    db.put(&mut writer, event)?;
+                }
+                if uncommitted >= batch {
+                    db.commit(writer)?;
+                    writer = db.writer()?;
+                    uncommitted = 0;
                 }
-            })
-            .collect()
-    }
-    let parse_batch = 30;
-    let mut writer = db.writer()?;
-    for item in lines.enumerate() {
-        let line = item.1?;
-        let index = item.0;
-        if index > 0 && index % parse_batch == 0 {
-            // batch write
-            // count += db.batch_put()?;
-            let events = parse_events(&batches, search);
-            for event in events {
-                db.put(&mut writer, event)?;
-                count += 1;
             }
-            batches.clear();
-        }
-        batches.push(line);
-        if index > 0 && index % batch == 0 {
             db.commit(writer)?;
-            writer = db.writer()?;
-        }
-        f(index);
-    }
+            db.flush()?;
+            Ok(())
+        });
 
-    db.commit(writer)?;
+        let mut lines = BufReader::new(input).lines();
+        'read: loop {
+            let mut chunks = Vec::with_capacity(in_flight);
+            for _ in 0..in_flight {
+                let chunk = lines
+                    .by_ref()
+                    .take(IMPORT_CHUNK)
+                    .collect::<Result<Vec<_>, _>>()?;
+                if chunk.is_empty() {
+                    break;
+                }
+                chunks.push(chunk);
+            }
+            if chunks.is_empty() {
+                break;
+            }
+            let parsed = chunks
+                .par_iter()
+                .map(|chunk| parse_chunk(chunk, search, verify, filter))
+                .collect::<Vec<_>>();
+            for (events, chunk_stats) in parsed {
+                stats.merge(&chunk_stats);
+                // a failed writer drops the receiver, its error is returned below
+                if sender.send(events).is_err() {
+                    break 'read;
+                }
+            }
+            f(&stats);
+        }
+        drop(sender);
+        writer.join().expect("import writer panicked")
+    })?;
+    Ok(stats)
+}
 
-    db.batch_put(parse_events(&batches, search))?;
-    count += batches.len();
-    db.flush()?;
-    Ok(count)
+/// Drop events with an invalid id or signature, verified as one batch
+pub fn verified(events: Vec<Event>) -> Vec<Event> {
+    let results = nostr_db::verify_batch(&events);
+    events
+        .into_iter()
+        .zip(results)
+        .filter_map(|(event, result)| match result {
+            Ok(()) => Some(event),
+            Err(e) => {
+                println!("error: {} {}", event.id_str(), e);
+                None
+            }
+        })
+        .collect()
 }
 
 fn create_pb(total: u64) -> ProgressBar {
@@ -201,19 +560,23 @@ fn create_pb(total: u64) -> ProgressBar {
 }
 
 pub fn export_opts(opts: ExportOpts) -> anyhow::Result<usize> {
-    fn run_export_opts<F: Fn(usize)>(mut opts: ExportOpts, f: F) -> anyhow::Result<usize> {
-        opts.filter.build_words();
-        if let Some(desc) = opts.desc {
-            opts.filter.desc = desc;
-        }
-        let count = export(&opts.path, opts.output, &opts.filter, f)?;
+    fn run_export_opts<F: Fn(usize)>(opts: ExportOpts, gzip: bool, f: F) -> anyhow::Result<usize> {
+        let count = export(&opts.path, opts.output, &opts.filter, gzip, f)?;
         Ok(count)
     }
 
+    let mut opts = opts;
+    opts.select.apply(&mut opts.filter)?;
+    opts.filter.build_words();
+    if let Some(desc) = opts.desc {
+        opts.filter.desc = desc;
+    }
+    let gzip = is_gzip(opts.output.path().path(), opts.gzip);
+
     if opts.output.path().is_local() {
         let total_size = count(&opts.path, &opts.filter)?;
         let pb = create_pb(total_size);
-        let total = run_export_opts(opts, |c| {
+        let total = run_export_opts(opts, gzip, |c| {
             if c % 1000 == 0 {
                 pb.set_position(c as u64);
             }
@@ -221,7 +584,7 @@ pub fn export_opts(opts: ExportOpts) -> anyhow::Result<usize> {
         pb.finish_with_message("finished");
         Ok(total)
     } else {
-        run_export_opts(opts, |_| {})
+        run_export_opts(opts, gzip, |_| {})
     }
 }
 
@@ -236,19 +599,37 @@ pub fn export<F: Fn(usize)>(
     path: &PathBuf,
     mut output: Output,
     filter: &Filter,
+    gzip: bool,
     f: F,
 ) -> Result<usize> {
-    let db = Db::open(path)?;
-    let reader = db.reader()?;
-    let iter = db.iter::<String, _>(&reader, filter)?;
-    let mut count = 0;
-    for event in iter {
-        count += 1;
-        let mut json: String = event?;
-        json.push('\n');
-        output.write_all(json.as_bytes())?;
-        f(count);
+    fn write_events<W: Write, F: Fn(usize)>(
+        db: &Db,
+        filter: &Filter,
+        writer: &mut W,
+        f: F,
+    ) -> Result<usize> {
+        let reader = db.reader()?;
+        let iter = db.iter::<String, _>(&reader, filter)?;
+        let mut count = 0;
+        for event in iter {
+            count += 1;
+            let mut json: String = event?;
+            json.push('\n');
+            writer.write_all(json.as_bytes())?;
+            f(count);
+        }
+        Ok(count)
     }
+
+    let db = Db::open(path)?;
+    let count = if gzip {
+        let mut encoder = GzEncoder::new(output, Compression::default());
+        let count = write_events(&db, filter, &mut encoder, f)?;
+        output = encoder.finish()?;
+        count
+    } else {
+        write_events(&db, filter, &mut output, f)?
+    };
     output.finish()?;
     Ok(count)
 }
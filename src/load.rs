@@ -0,0 +1,292 @@
+//! Load test against a live relay
+//!
+//! `rnostr bench --url wss://relay` opens `--clients` websocket connections,
+//! each publishing synthetic MLS events (group messages 445 in one shared
+//! group and KeyPackages 443) at `--event-rate` and issuing group message REQs
+//! at `--req-rate` per second. OK and EOSE latencies are reported as
+//! percentiles with the throughput, to size relay instances for MLS traffic.
+//! Synthetic KeyPackages are not valid MLS, relays validating them answer OK
+//! false, which is counted as rejected.
+
+use anyhow::Result;
+use clap::Args;
+use futures_util::{SinkExt, StreamExt};
+use nostr_db::{
+    now,
+    secp256k1::{
+        rand::{thread_rng, Rng, RngCore},
+        Keypair,
+    },
+    Event,
+};
+use serde_json::{json, Value as JsonValue};
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+use tokio::time::{interval, sleep_until, Interval};
+use tokio_tungstenite::{connect_async, tungstenite::Message};
+
+use crate::fmt_per_sec;
+
+/// Seconds to wait for outstanding OK and EOSE after the run
+const DRAIN_SECS: u64 = 5;
+
+/// load test options
+#[derive(Debug, Clone, Args)]
+pub struct LoadOpts {
+    /// Relay websocket url, load tests the live relay instead of benchmarking a data directory
+    #[arg(long, value_name = "URL")]
+    pub url: Option<String>,
+
+    /// Concurrent websocket clients
+    #[arg(long, value_name = "COUNT", default_value_t = 10, requires = "url")]
+    pub clients: usize,
+
+    /// Events published per second by each client (0 disables)
+    #[arg(long, value_name = "RATE", default_value_t = 10.0, requires = "url")]
+    pub event_rate: f64,
+
+    /// REQs issued per second by each client (0 disables)
+    #[arg(long, value_name = "RATE", default_value_t = 1.0, requires = "url")]
+    pub req_rate: f64,
+
+    /// Share of published events that are group messages (445), the rest are KeyPackages (443)
+    #[arg(long, value_name = "RATIO", default_value_t = 0.9, requires = "url")]
+    pub group_share: f64,
+
+    /// Content size in bytes of published events
+    #[arg(long, value_name = "BYTES", default_value_t = 512, requires = "url")]
+    pub content_size: usize,
+
+    /// Load test duration in seconds
+    #[arg(long, value_name = "SECONDS", default_value_t = 30, requires = "url")]
+    pub duration: u64,
+}
+
+/// Counters and latencies of one client
+#[derive(Debug, Default)]
+struct ClientStats {
+    events: u64,
+    accepted: u64,
+    rejected: u64,
+    reqs: u64,
+    eose: u64,
+    received: u64,
+    unanswered: u64,
+    ok_latency: Vec<Duration>,
+    eose_latency: Vec<Duration>,
+}
+
+impl ClientStats {
+    fn merge(&mut self, other: ClientStats) {
+        self.events += other.events;
+        self.accepted += other.accepted;
+        self.rejected += other.rejected;
+        self.reqs += other.reqs;
+        self.eose += other.eose;
+        self.received += other.received;
+        self.unanswered += other.unanswered;
+        self.ok_latency.extend(other.ok_latency);
+        self.eose_latency.extend(other.eose_latency);
+    }
+}
+
+/// Latency at percentile `p` (0..=100) of sorted latencies
+fn percentile(sorted: &[Duration], p: usize) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    sorted[(sorted.len() - 1) * p / 100]
+}
+
+fn fmt_latency(latency: &mut [Duration]) -> String {
+    latency.sort();
+    format!(
+        "p50 {:?}, p90 {:?}, p99 {:?}, max {:?}",
+        percentile(latency, 50),
+        percentile(latency, 90),
+        percentile(latency, 99),
+        percentile(latency, 100)
+    )
+}
+
+/// Ticks `rate` times per second, never when the rate is 0
+fn ticker(rate: f64) -> Option<Interval> {
+    (rate > 0.0).then(|| interval(Duration::from_secs_f64(1.0 / rate)))
+}
+
+async fn tick(ticker: &mut Option<Interval>) {
+    match ticker {
+        Some(ticker) => {
+            ticker.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Random hex filler of `size` bytes
+fn filler(size: usize) -> String {
+    let mut rng = thread_rng();
+    let mut content = String::with_capacity(size + 16);
+    while content.len() < size {
+        content.push_str(&format!("{:016x}", rng.next_u64()));
+    }
+    content.truncate(size);
+    content
+}
+
+fn synthetic_event(key: &Keypair, group: &str, opts: &LoadOpts, url: &str) -> Result<Event> {
+    let tag = |values: &[&str]| values.iter().map(|v| v.to_string()).collect::<Vec<_>>();
+    let (kind, tags) = if thread_rng().gen_bool(opts.group_share.clamp(0.0, 1.0)) {
+        (445, vec![tag(&["h", group])])
+    } else {
+        (
+            443,
+            vec![
+                tag(&["mls_protocol_version", "1.0"]),
+                tag(&["ciphersuite", "0x0001"]),
+                tag(&["extensions", "0x000a"]),
+                tag(&["relays", url]),
+                tag(&["encoding", "base64"]),
+            ],
+        )
+    };
+    Ok(Event::create(
+        key,
+        now(),
+        kind,
+        tags,
+        filler(opts.content_size),
+    )?)
+}
+
+/// Run one client until `deadline`, then wait for outstanding answers
+async fn client(
+    url: String,
+    group: String,
+    opts: LoadOpts,
+    deadline: tokio::time::Instant,
+) -> Result<ClientStats> {
+    let (ws, _) = connect_async(url.as_str()).await?;
+    let (mut sink, mut stream) = ws.split();
+    let key = Keypair::new_global(&mut thread_rng());
+    let mut events = ticker(opts.event_rate);
+    let mut reqs = ticker(opts.req_rate);
+    let mut pending_ok: HashMap<String, Instant> = HashMap::new();
+    let mut pending_eose: HashMap<String, Instant> = HashMap::new();
+    let mut stats = ClientStats::default();
+    let drain = deadline + Duration::from_secs(DRAIN_SECS);
+
+    loop {
+        let running = tokio::time::Instant::now() < deadline;
+        if !running && pending_ok.is_empty() && pending_eose.is_empty() {
+            break;
+        }
+        tokio::select! {
+            _ = sleep_until(drain) => break,
+            _ = tick(&mut events), if running => {
+                let event = synthetic_event(&key, &group, &opts, &url)?;
+                pending_ok.insert(event.id_str(), Instant::now());
+                sink.send(Message::Text(format!(r#"["EVENT",{}]"#, event))).await?;
+                stats.events += 1;
+            }
+            _ = tick(&mut reqs), if running => {
+                stats.reqs += 1;
+                let id = format!("bench-{}", stats.reqs);
+                pending_eose.insert(id.clone(), Instant::now());
+                let req = json!(["REQ", id, {"kinds": [445], "#h": [group], "limit": 20}]);
+                sink.send(Message::Text(req.to_string())).await?;
+            }
+            msg = stream.next() => {
+                let text = match msg {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(_)) => continue,
+                    Some(Err(e)) => return Err(e.into()),
+                    None => break,
+                };
+                let Ok(msg) = serde_json::from_str::<Vec<JsonValue>>(&text) else {
+                    continue;
+                };
+                match (msg.first().and_then(|v| v.as_str()), msg.get(1).and_then(|v| v.as_str())) {
+                    (Some("OK"), Some(id)) => {
+                        if let Some(sent) = pending_ok.remove(id) {
+                            stats.ok_latency.push(sent.elapsed());
+                            if msg.get(2).and_then(|v| v.as_bool()) == Some(true) {
+                                stats.accepted += 1;
+                            } else {
+                                stats.rejected += 1;
+                            }
+                        }
+                    }
+                    (Some("EOSE"), Some(id)) | (Some("CLOSED"), Some(id)) => {
+                        if let Some(sent) = pending_eose.remove(id) {
+                            stats.eose_latency.push(sent.elapsed());
+                            stats.eose += 1;
+                            sink.send(Message::Text(json!(["CLOSE", id]).to_string())).await?;
+                        }
+                    }
+                    (Some("EVENT"), _) => stats.received += 1,
+                    _ => {}
+                }
+            }
+        }
+    }
+    stats.unanswered = (pending_ok.len() + pending_eose.len()) as u64;
+    let _ = sink.send(Message::Close(None)).await;
+    Ok(stats)
+}
+
+/// Run the load test and print the report, returns the accepted events
+pub async fn run(url: &str, opts: &LoadOpts) -> Result<u64> {
+    let group = format!("bench-{}", filler(16));
+    let started = Instant::now();
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(opts.duration);
+    let clients = (0..opts.clients)
+        .map(|_| {
+            tokio::spawn(client(
+                url.to_owned(),
+                group.clone(),
+                opts.clone(),
+                deadline,
+            ))
+        })
+        .collect::<Vec<_>>();
+
+    let mut stats = ClientStats::default();
+    let mut failed = 0;
+    for client in clients {
+        match client.await? {
+            Ok(client) => stats.merge(client),
+            Err(e) => {
+                failed += 1;
+                eprintln!("client error: {}", e);
+            }
+        }
+    }
+    let elapsed = started.elapsed();
+
+    println!("Relay: {}, group {}", url, group);
+    println!(
+        "Clients: {} ({} failed), {:?}",
+        opts.clients, failed, elapsed
+    );
+    println!(
+        "Events: sent {}, accepted {}, rejected {}, {} OK",
+        stats.events,
+        stats.accepted,
+        stats.rejected,
+        fmt_per_sec(stats.accepted + stats.rejected, &elapsed)
+    );
+    println!("OK latency: {}", fmt_latency(&mut stats.ok_latency));
+    println!(
+        "REQs: sent {}, EOSE {}, events received {}, {} EOSE",
+        stats.reqs,
+        stats.eose,
+        stats.received,
+        fmt_per_sec(stats.eose, &elapsed)
+    );
+    println!("EOSE latency: {}", fmt_latency(&mut stats.eose_latency));
+    println!("Unanswered: {}", stats.unanswered);
+    Ok(stats.accepted)
+}
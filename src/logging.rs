@@ -0,0 +1,226 @@
+//! Relay log setup
+//!
+//! The `[log]` section selects text or JSON output. JSON writes one object per
+//! line with the `severity`, `message` and `timestamp` fields Cloud Logging
+//! reads. With `redact`, hex values of 64 characters and more (pubkeys, event
+//! ids, signatures) and npubs are masked to a prefix and content fields are
+//! never written, in both formats and in span fields.
+
+use nostr_relay::{
+    setting::{Log, LogFormat},
+    Setting,
+};
+use serde_json::{Map, Value};
+use std::{fmt, path::Path};
+use tracing::{field::Field, Event, Level, Subscriber};
+use tracing_subscriber::{
+    field::{RecordFields, Visit},
+    fmt::{
+        format::{self, FormatEvent, FormatFields, Writer},
+        FmtContext, FormattedFields,
+    },
+    registry::LookupSpan,
+    EnvFilter,
+};
+
+/// Fields dropped when redacting
+const CONTENT_FIELDS: &[&str] = &["content", "plaintext", "ciphertext"];
+
+/// Masks identifying values, passes everything through when disabled
+#[derive(Debug, Clone)]
+struct Redactor {
+    enabled: bool,
+    prefix: usize,
+}
+
+impl Redactor {
+    fn skip(&self, field: &Field) -> bool {
+        self.enabled && CONTENT_FIELDS.contains(&field.name())
+    }
+
+    fn mask(&self, word: &str) -> bool {
+        (word.len() >= 64 && word.bytes().all(|b| b.is_ascii_hexdigit()))
+            || (word.len() == 63 && word.starts_with("npub1"))
+    }
+
+    fn redact(&self, value: String) -> String {
+        if !self.enabled {
+            return value;
+        }
+        let mut out = String::with_capacity(value.len());
+        let mut rest = value.as_str();
+        while let Some(start) = rest.find(|c: char| c.is_ascii_alphanumeric()) {
+            out.push_str(&rest[..start]);
+            rest = &rest[start..];
+            let end = rest
+                .find(|c: char| !c.is_ascii_alphanumeric())
+                .unwrap_or(rest.len());
+            let word = &rest[..end];
+            if self.mask(word) {
+                out.push_str(&word[..self.prefix.min(word.len())]);
+                out.push('…');
+            } else {
+                out.push_str(word);
+            }
+            rest = &rest[end..];
+        }
+        out.push_str(rest);
+        out
+    }
+}
+
+/// Text fields like the default formatter, `message` first and `name=value` pairs
+struct TextVisitor<'a, 'w> {
+    redactor: &'a Redactor,
+    writer: Writer<'w>,
+    empty: bool,
+    result: fmt::Result,
+}
+
+impl Visit for TextVisitor<'_, '_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if self.result.is_err() || self.redactor.skip(field) {
+            return;
+        }
+        let value = self.redactor.redact(format!("{:?}", value));
+        let sep = if self.empty { "" } else { " " };
+        self.empty = false;
+        self.result = if field.name() == "message" {
+            write!(self.writer, "{}{}", sep, value)
+        } else {
+            write!(self.writer, "{}{}={}", sep, field.name(), value)
+        };
+    }
+}
+
+impl<'w> FormatFields<'w> for Redactor {
+    fn format_fields<R: RecordFields>(&self, writer: Writer<'w>, fields: R) -> fmt::Result {
+        let mut visitor = TextVisitor {
+            redactor: self,
+            writer,
+            empty: true,
+            result: Ok(()),
+        };
+        fields.record(&mut visitor);
+        visitor.result
+    }
+}
+
+/// Event fields as JSON values
+struct JsonVisitor<'a> {
+    redactor: &'a Redactor,
+    fields: Map<String, Value>,
+}
+
+impl JsonVisitor<'_> {
+    fn insert(&mut self, field: &Field, value: Value) {
+        if !self.redactor.skip(field) {
+            self.fields.insert(field.name().to_owned(), value);
+        }
+    }
+}
+
+impl Visit for JsonVisitor<'_> {
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.insert(field, value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        let value = self.redactor.redact(value.to_owned());
+        self.insert(field, value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        let value = self.redactor.redact(format!("{:?}", value));
+        self.insert(field, value.into());
+    }
+}
+
+/// One JSON object per event
+struct Json(Redactor);
+
+impl<S, N> FormatEvent<S, N> for Json
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: format::Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let meta = event.metadata();
+        let mut visitor = JsonVisitor {
+            redactor: &self.0,
+            fields: Map::new(),
+        };
+        event.record(&mut visitor);
+        let mut entry = visitor.fields;
+        let severity = match *meta.level() {
+            Level::ERROR => "ERROR",
+            Level::WARN => "WARNING",
+            Level::INFO => "INFO",
+            _ => "DEBUG",
+        };
+        entry.insert("severity".to_owned(), severity.into());
+        entry.insert(
+            "timestamp".to_owned(),
+            chrono::Utc::now()
+                .to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
+                .into(),
+        );
+        entry.insert("target".to_owned(), meta.target().into());
+        if let Some(scope) = ctx.event_scope() {
+            // span fields were formatted, and redacted, by the field formatter
+            let spans: Vec<Value> = scope
+                .from_root()
+                .map(|span| {
+                    let ext = span.extensions();
+                    match ext.get::<FormattedFields<N>>() {
+                        Some(fields) if !fields.is_empty() => {
+                            format!("{}{{{}}}", span.name(), fields).into()
+                        }
+                        _ => span.name().into(),
+                    }
+                })
+                .collect();
+            if !spans.is_empty() {
+                entry.insert("spans".to_owned(), spans.into());
+            }
+        }
+        writeln!(writer, "{}", Value::Object(entry))
+    }
+}
+
+/// `RUST_LOG`, info when unset
+fn filter() -> EnvFilter {
+    EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"))
+}
+
+/// Install the global subscriber from the `[log]` section of the config,
+/// defaults when the config cannot be read
+pub fn init(config: &Path) {
+    let log: Log = Setting::read_unresolved(config, Some("RNOSTR".to_owned()))
+        .map(|setting| setting.log)
+        .unwrap_or_default();
+    let redactor = Redactor {
+        enabled: log.redact,
+        prefix: log.prefix,
+    };
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter())
+        .fmt_fields(redactor.clone());
+    match log.format {
+        LogFormat::Text => builder.init(),
+        LogFormat::Json => builder.event_format(Json(redactor)).with_ansi(false).init(),
+    }
+}
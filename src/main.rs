@@ -22,7 +22,7 @@ enum Commands {
     /// Export data to jsonl file
     #[command(arg_required_else_help = true)]
     Export(ExportOpts),
-    /// Benchmark filter
+    /// Benchmark filter, or load test a live relay with --url
     #[command(arg_required_else_help = true)]
     Bench(BenchOpts),
     /// Start nostr relay server
@@ -31,25 +31,88 @@ enum Commands {
     Delete(DeleteOpts),
     /// Clean up expired keypackages
     Cleanup,
+    /// Inspect and repair MLS group state
+    #[command(arg_required_else_help = true)]
+    Group(group::GroupOpts),
+    /// Show the NIP-KR rotation history of a client from a running relay
+    #[command(arg_required_else_help = true)]
+    Rotations(rotations::RotationsOpts),
+    /// Export or erase all data held for a pubkey through a running relay
+    #[command(arg_required_else_help = true)]
+    Privacy(privacy::PrivacyOpts),
+    /// Sign an event and publish it to a relay
+    #[command(arg_required_else_help = true)]
+    Publish(client::PublishOpts),
+    /// Query a relay with filters and print the events as jsonl
+    #[command(arg_required_else_help = true)]
+    Req(client::ReqOpts),
+    /// Generate, show or rotate the relay service key
+    #[command(arg_required_else_help = true)]
+    Key(service_key::KeyOpts),
+    /// Show the archive backfill checkpoint and last run report
+    #[command(arg_required_else_help = true)]
+    BackfillStatus(BackfillStatusOpts),
+    /// Rebuild the events database from the message archive, with the relay stopped
+    #[command(arg_required_else_help = true)]
+    Rebuild(RebuildOpts),
+    /// Take, list or restore LMDB snapshots in object storage
+    #[command(arg_required_else_help = true)]
+    Snapshot(snapshot::SnapshotOpts),
 }
 
 fn main() -> anyhow::Result<()> {
     let args = Cli::parse();
     match args.command {
         Commands::Import(opts) => {
-            let total = import_opts(opts)?;
+            let total = if opts.from_archive {
+                tracing_subscriber::fmt::init();
+                let system = actix_rt::System::new();
+                system.block_on(rnostr::archive::import_from_archive(opts))?
+            } else {
+                import_opts(opts)?
+            };
             println!("imported {} events", total);
         }
         Commands::Export(opts) => {
-            export_opts(opts)?;
+            if opts.to_archive {
+                tracing_subscriber::fmt::init();
+                let system = actix_rt::System::new();
+                let total = system.block_on(rnostr::archive::export_to_archive(opts))?;
+                println!("archived {} events", total);
+            } else {
+                export_opts(opts)?;
+            }
         }
         Commands::Bench(opts) => {
             bench_opts(opts)?;
         }
         Commands::Relay(opts) => {
-            relay(&opts.config, opts.watch)?;
+            if opts.check {
+                let system = actix_rt::System::new();
+                let report = system.block_on(rnostr::preflight::run(&opts.config));
+                print!("{}", report);
+                if !report.passed() {
+                    std::process::exit(1);
+                }
+            } else {
+                relay(&opts.config, opts.watch)?;
+            }
         }
         Commands::Delete(opts) => {
+            if opts.include_archive || opts.include_keypackages || opts.include_groups {
+                tracing_subscriber::fmt::init();
+                let system = actix_rt::System::new();
+                let purged = system.block_on(rnostr::purge::purge(&opts))?;
+                let verb = if opts.dry_run {
+                    "Would delete"
+                } else {
+                    "Deleted"
+                };
+                println!(
+                    "{} {} archived events, {} keypackages, {} groups",
+                    verb, purged.archived, purged.keypackages, purged.groups
+                );
+            }
             let count = delete(&opts.path, &opts.filter, opts.dry_run)?;
             if opts.dry_run {
                 println!("Would delete {} events", count);
@@ -62,21 +125,70 @@ fn main() -> anyhow::Result<()> {
             {
                 // Initialize tracing for logging
                 tracing_subscriber::fmt::init();
-                
+
                 // Run cleanup in async context using actix-rt
                 let system = actix_rt::System::new();
-                system.block_on(async {
-                    rnostr::cleanup::run_cleanup().await
-                })?;
+                system.block_on(async { rnostr::cleanup::run_cleanup().await })?;
             }
-            
+
             #[cfg(not(feature = "mls_gateway_firestore"))]
             {
-                eprintln!("Error: Cleanup command requires mls_gateway_firestore feature to be enabled");
+                eprintln!(
+                    "Error: Cleanup command requires mls_gateway_firestore feature to be enabled"
+                );
                 eprintln!("Build with: cargo build --features mls_gateway_firestore");
                 std::process::exit(1);
             }
         }
+        Commands::BackfillStatus(opts) => {
+            backfill_status(&opts.path)?;
+        }
+        Commands::Group(opts) => {
+            tracing_subscriber::fmt::init();
+            let system = actix_rt::System::new();
+            system.block_on(rnostr::group::run_group(opts))?;
+        }
+        Commands::Rotations(opts) => {
+            let system = actix_rt::System::new();
+            system.block_on(rnostr::rotations::run_rotations(opts))?;
+        }
+        Commands::Privacy(opts) => {
+            let system = actix_rt::System::new();
+            system.block_on(rnostr::privacy::run_privacy(opts))?;
+        }
+        Commands::Publish(opts) => {
+            let system = actix_rt::System::new();
+            system.block_on(rnostr::client::run_publish(opts))?;
+        }
+        Commands::Req(opts) => {
+            let system = actix_rt::System::new();
+            system.block_on(rnostr::client::run_req(opts))?;
+        }
+        Commands::Key(opts) => {
+            let system = actix_rt::System::new();
+            system.block_on(rnostr::service_key::run_key(opts))?;
+        }
+        Commands::Rebuild(opts) => {
+            tracing_subscriber::fmt::init();
+            let path = opts.path.clone();
+            let system = actix_rt::System::new();
+            let report = system.block_on(rnostr::archive::rebuild_from_archive(opts))?;
+            for (kind, stats) in &report.kinds {
+                println!(
+                    "kind {}: {} fetched, {} inserted, {} duplicate, {} invalid, {} ignored, {} stored",
+                    kind, stats.fetched, stats.inserted, stats.duplicate, stats.invalid, stats.ignored, stats.stored
+                );
+            }
+            if let Some(backup) = &report.backup {
+                println!("previous database moved to {}", backup.display());
+            }
+            println!("rebuilt {}", path.display());
+        }
+        Commands::Snapshot(opts) => {
+            tracing_subscriber::fmt::init();
+            let system = actix_rt::System::new();
+            system.block_on(rnostr::snapshot::run_snapshot(opts))?;
+        }
     }
     Ok(())
 }
@@ -0,0 +1,156 @@
+//! Startup preflight checks
+//!
+//! Verifies what a deployment needs before it takes traffic: the config, a
+//! writable LMDB path, the MLS storage backend, the fanout Pub/Sub topic and
+//! the NIP-KR KMS key. `rnostr relay --check` prints the report and exits
+//! non-zero on a failure, the relay logs the same report on boot.
+
+use nostr_extensions::{
+    fanout::FanoutSetting, mls_gateway::MlsGatewayConfig, nip_service::config::NipServiceConfig,
+    snapshot::SnapshotSetting,
+};
+use nostr_relay::Setting;
+use std::{fmt::Display, fs, future::Future, path::Path};
+use tracing::{error, info};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Pass,
+    Fail,
+    Skip,
+}
+
+#[derive(Debug, Clone)]
+pub struct Check {
+    pub name: &'static str,
+    pub status: Status,
+    pub detail: String,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Report {
+    pub checks: Vec<Check>,
+}
+
+impl Report {
+    fn push(&mut self, name: &'static str, status: Status, detail: impl Display) {
+        self.checks.push(Check {
+            name,
+            status,
+            detail: detail.to_string(),
+        });
+    }
+
+    fn result<T: Display>(&mut self, name: &'static str, res: anyhow::Result<T>) {
+        match res {
+            Ok(detail) => self.push(name, Status::Pass, detail),
+            Err(e) => self.push(name, Status::Fail, e),
+        }
+    }
+
+    async fn run<T, F>(&mut self, name: &'static str, enabled: bool, fut: F)
+    where
+        T: Display,
+        F: Future<Output = anyhow::Result<T>>,
+    {
+        if enabled {
+            self.result(name, fut.await);
+        } else {
+            self.push(name, Status::Skip, "disabled");
+        }
+    }
+
+    pub fn passed(&self) -> bool {
+        self.checks.iter().all(|c| c.status != Status::Fail)
+    }
+
+    /// Log the checks, failures as errors
+    pub fn log(&self) {
+        for c in &self.checks {
+            match c.status {
+                Status::Fail => error!("Preflight {} failed: {}", c.name, c.detail),
+                _ => info!("Preflight {} {:?}: {}", c.name, c.status, c.detail),
+            }
+        }
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for c in &self.checks {
+            let status = match c.status {
+                Status::Pass => "PASS",
+                Status::Fail => "FAIL",
+                Status::Skip => "SKIP",
+            };
+            writeln!(f, "{:<5} {:<12} {}", status, c.name, c.detail)?;
+        }
+        Ok(())
+    }
+}
+
+/// Settings that load but cannot work, extension tables that would silently fall back to defaults
+fn sanity(setting: &Setting) -> anyhow::Result<&'static str> {
+    if *setting.network.heartbeat_timeout <= *setting.network.heartbeat_interval {
+        anyhow::bail!("network.heartbeat_timeout must be bigger than heartbeat_interval");
+    }
+    setting.try_parse_extension::<MlsGatewayConfig>("mls_gateway")?;
+    setting.try_parse_extension::<FanoutSetting>("fanout")?;
+    let snapshot = setting.try_parse_extension::<SnapshotSetting>("snapshot")?;
+    if snapshot.enabled && snapshot.bucket.is_none() {
+        anyhow::bail!("snapshot is enabled without a bucket");
+    }
+    Ok("ok")
+}
+
+/// Create the events directory and write a probe file
+fn writable(path: &Path) -> anyhow::Result<String> {
+    fs::create_dir_all(path)?;
+    let probe = path.join(".preflight");
+    fs::write(&probe, b"ok")?;
+    fs::remove_file(&probe)?;
+    Ok(path.display().to_string())
+}
+
+/// Run all checks, the config is read like the relay reads it
+pub async fn run(config: &Path) -> Report {
+    let mut report = Report::default();
+    match nostr_extensions::secrets::Secrets::load(config, Some("RNOSTR".to_owned())).await {
+        Ok(Some(secrets)) => {
+            report.push("secrets", Status::Pass, format!("{} loaded", secrets.len()))
+        }
+        Ok(None) => report.push("secrets", Status::Skip, "no secret:// references"),
+        Err(e) => report.push("secrets", Status::Fail, e),
+    }
+    let setting = match Setting::read(config, Some("RNOSTR".to_owned())) {
+        Ok(setting) => setting,
+        Err(e) => {
+            report.push("config", Status::Fail, e);
+            return report;
+        }
+    };
+    let mls: MlsGatewayConfig = setting.parse_extension("mls_gateway");
+    let fanout: FanoutSetting = setting.parse_extension("fanout");
+    report.result("config", sanity(&setting));
+    report.result("lmdb", writable(&setting.data.path.join("events")));
+    report
+        .run(
+            "storage",
+            setting.extension_control("mls_gateway").enabled,
+            nostr_extensions::MlsGateway::preflight(&mls),
+        )
+        .await;
+    report
+        .run(
+            "pubsub",
+            fanout.enabled,
+            nostr_extensions::Fanout::check_topic(&fanout),
+        )
+        .await;
+    match NipServiceConfig::default().check_kms().await {
+        Ok(Some(key)) => report.push("kms", Status::Pass, key),
+        Ok(None) => report.push("kms", Status::Skip, "NIP_SERVICE_KMS_MAC_KEY not set"),
+        Err(e) => report.push("kms", Status::Fail, e),
+    }
+    report
+}
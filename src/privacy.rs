@@ -0,0 +1,101 @@
+//! Per-user data export and erasure command
+//!
+//! Erasure has to reach the LMDB of the running relay, so both operations go
+//! through the authenticated admin API (`{api_prefix}/admin/users/{pubkey}`).
+
+use anyhow::{anyhow, Result};
+use clap::{Parser, Subcommand};
+use serde_json::Value as JsonValue;
+use std::path::PathBuf;
+
+/// privacy options
+#[derive(Debug, Clone, Parser)]
+pub struct PrivacyOpts {
+    #[command(subcommand)]
+    pub command: PrivacyCommand,
+
+    /// Relay API base url including the MLS gateway api_prefix
+    #[arg(
+        long,
+        value_name = "URL",
+        default_value = "http://127.0.0.1:8080/api/v1",
+        global = true
+    )]
+    pub url: String,
+
+    /// Admin bearer token, defaults to the MLS_ADMIN_TOKEN env var
+    #[arg(long, global = true)]
+    pub token: Option<String>,
+}
+
+/// privacy subcommands
+#[derive(Debug, Clone, Subcommand)]
+pub enum PrivacyCommand {
+    /// Export all data held for a pubkey as a JSON bundle
+    Export {
+        /// User pubkey (hex)
+        pubkey: String,
+        /// Write the bundle to a file instead of stdout
+        #[arg(short, long, value_name = "PATH")]
+        output: Option<PathBuf>,
+    },
+    /// Erase all data held for a pubkey
+    Erase {
+        /// User pubkey (hex)
+        pubkey: String,
+        /// Confirm the erasure, it cannot be undone
+        #[arg(long)]
+        yes: bool,
+    },
+}
+
+/// Export or erase the data of a pubkey through a running relay
+pub async fn run_privacy(opts: PrivacyOpts) -> Result<()> {
+    let token = opts
+        .token
+        .clone()
+        .or_else(|| std::env::var("MLS_ADMIN_TOKEN").ok())
+        .ok_or_else(|| anyhow!("admin token required (--token or MLS_ADMIN_TOKEN)"))?;
+    let base = opts.url.trim_end_matches('/');
+    let client = reqwest::Client::new();
+    match opts.command {
+        PrivacyCommand::Export { pubkey, output } => {
+            let url = format!("{}/admin/users/{}/export", base, pubkey);
+            let body = check(client.get(&url).bearer_auth(token).send().await?).await?;
+            let bundle = serde_json::to_string_pretty(&body["data"])?;
+            match output {
+                Some(path) => {
+                    std::fs::write(&path, bundle)?;
+                    println!("exported {} to {}", pubkey, path.display());
+                }
+                None => println!("{}", bundle),
+            }
+        }
+        PrivacyCommand::Erase { pubkey, yes } => {
+            if !yes {
+                return Err(anyhow!(
+                    "erasing {} cannot be undone, confirm with --yes",
+                    pubkey
+                ));
+            }
+            let url = format!("{}/admin/users/{}", base, pubkey);
+            let body = check(client.delete(&url).bearer_auth(token).send().await?).await?;
+            println!("{}", serde_json::to_string_pretty(&body["erased"])?);
+        }
+    }
+    Ok(())
+}
+
+/// JSON body of a successful response
+async fn check(res: reqwest::Response) -> Result<JsonValue> {
+    let status = res.status();
+    let body: JsonValue = res.json().await?;
+    if !status.is_success() {
+        return Err(anyhow!(
+            "{}: {}",
+            status,
+            body["error"].as_str().unwrap_or("request failed")
+        ));
+    }
+    Ok(body)
+}
@@ -0,0 +1,182 @@
+//! Backend cascade of the delete command
+//!
+//! `rnostr delete` removes events from the local LMDB store. With
+//! `--include-archive`, `--include-keypackages` and `--include-groups` the same
+//! filter is applied to the MLS gateway collections through the Firestore
+//! backend before the local delete, so a failed cascade can be rerun with the
+//! same filter:
+//! - archive: archived events (`archived_events`) of the filter kinds (445,
+//!   446 and 1059 without kinds) matching the filter
+//! - keypackages: KeyPackages (443) in `mls_keypackages` of the matching local
+//!   events, and with an `authors` filter those of the authors matching ids,
+//!   since and until
+//! - groups: the groups named by the filter `#h` tag with their roster history
+
+use crate::DeleteOpts;
+use anyhow::Result;
+
+/// Backend records removed (or matched in a dry run)
+#[derive(Debug, Default)]
+pub struct PurgeStats {
+    pub archived: u64,
+    pub keypackages: u64,
+    pub groups: u64,
+}
+
+/// Cascade the delete filter into the backend collections selected by the options
+#[cfg(feature = "mls_gateway_firestore")]
+pub async fn purge(opts: &DeleteOpts) -> Result<PurgeStats> {
+    use nostr_db::{Db, Event};
+    use nostr_extensions::mls_gateway::{FirestoreStorage, MessageArchive, MlsStorage};
+    use std::collections::BTreeSet;
+    use tracing::info;
+
+    const KEYPACKAGE_KIND: u16 = 443;
+    const PAGE_SIZE: u32 = 500;
+
+    fn to_hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    let filter = &opts.filter;
+    let dry_run = opts.dry_run;
+    let mut stats = PurgeStats::default();
+
+    let group_ids = filter
+        .tags
+        .get(b"h".as_slice())
+        .map(|ids| {
+            ids.iter()
+                .map(|id| String::from_utf8_lossy(id).into_owned())
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    if opts.include_groups && group_ids.is_empty() {
+        return Err(anyhow::anyhow!(
+            "--include-groups requires a filter with an #h tag naming the groups"
+        ));
+    }
+
+    if opts.include_archive {
+        let archive = MessageArchive::new().await?;
+        let kinds: Vec<u16> = if filter.kinds.is_empty() {
+            crate::archive::DEFAULT_ARCHIVE_KINDS.to_vec()
+        } else {
+            filter.kinds.to_vec()
+        };
+        let since = filter.since.unwrap_or(0) as i64;
+        let until = filter.until.map(|t| t as i64);
+        for kind in kinds {
+            let mut cursor = None;
+            loop {
+                let page = archive
+                    .list_events_page(kind as u32, since, until, cursor.take(), PAGE_SIZE)
+                    .await?;
+                let Some(last) = page.last() else {
+                    break;
+                };
+                cursor = Some((last.created_at() as i64, last.id_str()));
+                for event in page.iter().filter(|e| filter.r#match(e.index())) {
+                    if dry_run
+                        || archive
+                            .delete_event(kind as u32, &event.id_str(), &event.pubkey_str())
+                            .await?
+                    {
+                        stats.archived += 1;
+                    }
+                }
+            }
+            info!(
+                "Purged kind {} from archive, {} events so far",
+                kind, stats.archived
+            );
+        }
+    }
+
+    if !opts.include_keypackages && !opts.include_groups {
+        return Ok(stats);
+    }
+    let project_id = crate::cleanup::firestore_project_id()?;
+    let storage = FirestoreStorage::new(&project_id).await?;
+
+    if opts.include_keypackages
+        && (filter.kinds.is_empty() || filter.kinds.contains(&KEYPACKAGE_KIND))
+    {
+        let mut ids = BTreeSet::new();
+        let db = Db::open(&opts.path)?;
+        {
+            let reader = db.reader()?;
+            for event in db.iter::<Event, _>(&reader, filter)? {
+                let event = event?;
+                if event.kind() == KEYPACKAGE_KIND {
+                    ids.insert(event.id_str());
+                }
+            }
+        }
+        // Stored keypackages carry no tags, the backend is only searched without tag or search conditions
+        if !filter.authors.is_empty() && filter.tags.is_empty() && filter.search.is_none() {
+            let authors = filter.authors.iter().map(|a| to_hex(a)).collect::<Vec<_>>();
+            let since = filter.since.unwrap_or(0) as i64;
+            let until = filter.until.map_or(i64::MAX, |t| t as i64);
+            for chunk in authors.chunks(30) {
+                // Page oldest first from the last seen created_at, rows sharing that
+                // second come back again so a page without unseen ids ends the chunk
+                let mut seen = BTreeSet::new();
+                let mut from = since;
+                while from <= until {
+                    let page = storage
+                        .query_keypackages(
+                            Some(chunk),
+                            Some(from),
+                            Some(1000),
+                            Some("created_at_asc"),
+                        )
+                        .await?;
+                    let mut fresh = false;
+                    for (id, _, _, created_at) in page {
+                        from = from.max(created_at);
+                        if created_at > until || !seen.insert(id.clone()) {
+                            continue;
+                        }
+                        fresh = true;
+                        if filter.ids.is_empty() || filter.ids.iter().any(|i| to_hex(i) == id) {
+                            ids.insert(id);
+                        }
+                    }
+                    if !fresh {
+                        break;
+                    }
+                }
+            }
+        }
+        for id in &ids {
+            if storage.keypackage_exists(id).await? {
+                if !dry_run {
+                    storage.delete_keypackage_by_id(id).await?;
+                }
+                stats.keypackages += 1;
+            }
+        }
+    }
+
+    if opts.include_groups {
+        for group_id in &group_ids {
+            if !storage.group_exists(group_id).await? {
+                continue;
+            }
+            if !dry_run {
+                let roster = storage.delete_group(group_id).await?;
+                info!("Deleted group {} ({} roster records)", group_id, roster);
+            }
+            stats.groups += 1;
+        }
+    }
+    Ok(stats)
+}
+
+#[cfg(not(feature = "mls_gateway_firestore"))]
+pub async fn purge(_opts: &DeleteOpts) -> Result<PurgeStats> {
+    Err(anyhow::anyhow!(
+        "Backend delete requires mls_gateway_firestore feature to be enabled"
+    ))
+}
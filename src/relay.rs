@@ -19,12 +19,21 @@ pub struct RelayOpts {
     /// Auto reload when config changed
     #[arg(long, value_name = "BOOL")]
     pub watch: bool,
+
+    /// Run the preflight checks, print the report and exit
+    #[arg(long)]
+    pub check: bool,
 }
 
 #[actix_rt::main]
 pub async fn relay(config: &PathBuf, watch: bool) -> Result<()> {
-    tracing_subscriber::fmt::init();
+    crate::logging::init(config);
     info!("Start relay server");
+    crate::preflight::run(config).await.log();
+    // secret:// references must be loaded before the config is read
+    let secrets = nostr_extensions::secrets::Secrets::load(config, Some("RNOSTR".to_owned()))
+        .await
+        .map_err(|e| crate::Error::Message(format!("loading config secrets failed: {}", e)))?;
 
     // actix_rt::System::new().block_on(async {
     // });
@@ -32,10 +41,24 @@ pub async fn relay(config: &PathBuf, watch: bool) -> Result<()> {
     let app_data = App::create(Some(config), watch, Some("RNOSTR".to_owned()), None)?;
     let db = app_data.db.clone();
 
+    // Rotated secrets are applied like a config change
+    if let Some(secrets) = secrets {
+        let setting = app_data.setting.clone();
+        let extensions = app_data.extensions.clone();
+        let config = config.clone();
+        secrets.spawn_refresh(
+            move || match setting.reload(&config, Some("RNOSTR".to_owned())) {
+                Ok(()) => extensions.write().call_setting(&setting),
+                Err(e) => warn!("Reloading config after a secret rotation failed: {}", e),
+            },
+        );
+    }
+
     // Startup Firestore -> LMDB backfill if configured (no REST dependency)
     {
         let r = app_data.setting.read();
-        let mgcfg: nostr_extensions::mls_gateway::MlsGatewayConfig = r.parse_extension("mls_gateway");
+        let mgcfg: nostr_extensions::mls_gateway::MlsGatewayConfig =
+            r.parse_extension("mls_gateway");
         drop(r);
 
         if mgcfg.backfill_on_startup {
@@ -45,29 +68,31 @@ pub async fn relay(config: &PathBuf, watch: bool) -> Result<()> {
             );
             match nostr_extensions::mls_gateway::MessageArchive::new().await {
                 Ok(archive) => {
+                    let data_path = app_data.setting.read().data.path.clone();
+                    let mut backfill = nostr_extensions::mls_gateway::backfill::Backfill::new(
+                        archive,
+                        db.clone(),
+                        data_path,
+                    );
                     let since = chrono::Utc::now().timestamp()
                         - (mgcfg.message_archive_ttl_days as i64) * 86_400;
-                    match archive
-                        .list_recent_events_by_kinds(
-                            &mgcfg.backfill_kinds,
-                            since,
-                            mgcfg.backfill_max_events,
-                        )
+                    match backfill
+                        .run(&mgcfg.backfill_kinds, since, mgcfg.backfill_max_events)
                         .await
                     {
-                        Ok(events) => {
-                            if !events.is_empty() {
-                                match db.batch_put(events) {
-                                    Ok(count) => info!("Backfilled {} events into LMDB", count),
-                                    Err(e) => warn!("Backfill batch_put error: {}", e),
-                                }
-                            } else {
-                                info!(
-                                    "No events to backfill from Firestore (within TTL window)"
-                                );
-                            }
-                        }
-                        Err(e) => warn!("Backfill query failed: {}", e),
+                        Ok(report) => info!(
+                            "Backfill fetched {} events: {} inserted, {} duplicate, {} invalid, {} ignored",
+                            report.fetched, report.inserted, report.duplicate, report.invalid, report.ignored
+                        ),
+                        Err(e) => warn!("Backfill failed: {}", e),
+                    }
+                    if mgcfg.backfill_interval_secs > 0 {
+                        backfill.spawn_periodic(
+                            mgcfg.backfill_kinds.clone(),
+                            mgcfg.message_archive_ttl_days,
+                            mgcfg.backfill_max_events,
+                            std::time::Duration::from_secs(mgcfg.backfill_interval_secs),
+                        );
                     }
                 }
                 Err(e) => warn!("MessageArchive init failed; skipping backfill: {}", e),
@@ -75,6 +100,48 @@ pub async fn relay(config: &PathBuf, watch: bool) -> Result<()> {
         } else {
             info!("Startup backfill disabled by configuration");
         }
+
+        nostr_extensions::mls_gateway::keypackage_expiry::spawn_sweep(
+            db.clone(),
+            std::time::Duration::from_secs(60),
+        );
+
+        if mgcfg.enable_message_archive && mgcfg.reconcile_interval_secs > 0 {
+            match nostr_extensions::mls_gateway::MessageArchive::new().await {
+                Ok(archive) => {
+                    nostr_extensions::mls_gateway::reconcile::Reconciler::new(
+                        archive,
+                        db.clone(),
+                        mgcfg.backfill_kinds.clone(),
+                        mgcfg.message_archive_ttl_days,
+                        std::time::Duration::from_secs(mgcfg.reconcile_lookback_secs),
+                    )
+                    .spawn(std::time::Duration::from_secs(
+                        mgcfg.reconcile_interval_secs,
+                    ));
+                }
+                Err(e) => warn!(
+                    "MessageArchive init failed; skipping archive reconciliation: {}",
+                    e
+                ),
+            }
+        }
+
+        if let Some(export) =
+            nostr_extensions::mls_gateway::export::ExportConfig::from_gateway(&mgcfg)
+        {
+            let exporter = nostr_extensions::mls_gateway::MessageArchive::new()
+                .await
+                .and_then(|archive| {
+                    nostr_extensions::mls_gateway::export::Exporter::new(archive, export)
+                });
+            match exporter {
+                Ok(exporter) => exporter.spawn(std::time::Duration::from_secs(
+                    mgcfg.export_interval_secs.max(60),
+                )),
+                Err(e) => warn!("Archive export disabled: {}", e),
+            }
+        }
     }
     // Initialize MLS Gateway with loaded settings before adding the extension
     let mut mls_gateway = nostr_extensions::MlsGateway::new(Default::default());
@@ -84,14 +151,30 @@ pub async fn relay(config: &PathBuf, watch: bool) -> Result<()> {
         warn!("MLS Gateway initialization failed: {}", e);
     }
 
+    // Cross-instance fanout over Pub/Sub if configured
+    nostr_extensions::Fanout::start_with(&app_data);
+
+    // Scheduled LMDB snapshots to object storage if configured
+    nostr_extensions::snapshot::Snapshotter::start_with(&app_data);
+
+    // Service events (notify, ack) are emitted through the relay's own pipeline
+    nostr_extensions::nip_service::emit::set_server(app_data.server.clone());
+    // Low keypackage NOTICEs are delivered to sessions through the server
+    nostr_extensions::mls_gateway::low_keypackages::set_server(app_data.server.clone());
+
     app_data
         .add_extension(nostr_extensions::Metrics::new())
+        .add_extension(nostr_extensions::IpReputation::new())
+        .add_extension(nostr_extensions::Attestation::new())
         .add_extension(nostr_extensions::Auth::new())
         .add_extension(nostr_extensions::Ratelimiter::new())
+        .add_extension(nostr_extensions::Spam::new())
         .add_extension(nostr_extensions::Count::new(db))
         .add_extension(nostr_extensions::Search::new())
         .add_extension(mls_gateway)
         .add_extension(nostr_extensions::NipService::new())
+        .add_extension(nostr_extensions::Federation::new())
+        .add_extension(nostr_extensions::Webhook::new())
         .web_server()?
         .await?;
     info!("Relay server shutdown");
@@ -0,0 +1,93 @@
+//! NIP-KR rotation audit command
+//!
+//! Rotation history lives in the running relay, so it is read through the
+//! authenticated admin API (`{api_prefix}/admin/nip-service/clients/{id}/rotations`).
+
+use anyhow::{anyhow, Result};
+use clap::Parser;
+use serde_json::Value as JsonValue;
+
+/// rotations options
+#[derive(Debug, Clone, Parser)]
+pub struct RotationsOpts {
+    /// NIP-KR client id
+    pub client_id: String,
+
+    /// Relay API base url including the MLS gateway api_prefix
+    #[arg(
+        long,
+        value_name = "URL",
+        default_value = "http://127.0.0.1:8080/api/v1"
+    )]
+    pub url: String,
+
+    /// Admin bearer token, defaults to the MLS_ADMIN_TOKEN env var
+    #[arg(long)]
+    pub token: Option<String>,
+
+    /// Print the raw JSON response
+    #[arg(long)]
+    pub json: bool,
+}
+
+/// Fetch and print the rotation history of a client
+pub async fn run_rotations(opts: RotationsOpts) -> Result<()> {
+    let token = opts
+        .token
+        .clone()
+        .or_else(|| std::env::var("MLS_ADMIN_TOKEN").ok())
+        .ok_or_else(|| anyhow!("admin token required (--token or MLS_ADMIN_TOKEN)"))?;
+    let url = format!(
+        "{}/admin/nip-service/clients/{}/rotations",
+        opts.url.trim_end_matches('/'),
+        opts.client_id
+    );
+    let res = reqwest::Client::new()
+        .get(&url)
+        .bearer_auth(token)
+        .send()
+        .await?;
+    let status = res.status();
+    let body: JsonValue = res.json().await?;
+    if !status.is_success() {
+        return Err(anyhow!(
+            "{}: {}",
+            status,
+            body["error"].as_str().unwrap_or("request failed")
+        ));
+    }
+    if opts.json {
+        println!("{}", serde_json::to_string_pretty(&body)?);
+        return Ok(());
+    }
+    let rotations = body["rotations"].as_array().cloned().unwrap_or_default();
+    if rotations.is_empty() {
+        println!("no rotations for client {}", opts.client_id);
+        return Ok(());
+    }
+    for line in format_rotations(&rotations) {
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+fn format_rotations(rotations: &[JsonValue]) -> Vec<String> {
+    let ts = |v: &JsonValue| v.as_i64().map_or("-".to_owned(), |t| t.to_string());
+    let mut lines = vec![format!(
+        "{:<28} {:<10} {:<8} {:<16} {:<16} {:<16} {:<16}",
+        "rotation_id", "outcome", "acks", "new_version", "prepared_at", "ack_deadline", "closed_at"
+    )];
+    for r in rotations {
+        lines.push(format!(
+            "{:<28} {:<10} {:<8} {:<16} {:<16} {:<16} {:<16}",
+            r["action_id"].as_str().unwrap_or("-"),
+            r["outcome"].as_str().unwrap_or("-"),
+            format!("{}/{}", r["quorum_acks"], r["quorum_required"]),
+            r["new_version"].as_str().unwrap_or("-"),
+            ts(&r["prepared_at_ms"]),
+            ts(&r["ack_deadline_ms"]),
+            ts(&r["closed_at_ms"]),
+        ));
+    }
+    lines
+}
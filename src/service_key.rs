@@ -0,0 +1,243 @@
+//! Relay service key command
+//!
+//! The service key signs the events emitted by the relay itself (service-acks,
+//! service-notify, roster snapshots, NIP-29 group events). `rnostr key` creates
+//! and rotates it in one of two stores:
+//! - `--secret`: a Secret Manager secret holding the hex secret key, ready to be
+//!   mounted as `NIP_SERVICE_SECRET_KEY` on Cloud Run. Rotation adds a version.
+//! - `--file`: a NIP-49 `ncryptsec` file, its passphrase comes from the
+//!   `RNOSTR_KEY_PASSPHRASE` env var or the prompt.
+//!   Rotation keeps the previous key as `<file>.prev`.
+//!
+//! Secret Manager calls go through `nostr_extensions::gcp`, with
+//! `GOOGLE_OAUTH_ACCESS_TOKEN` when set (e.g. from `gcloud auth print-access-token`),
+//! else the metadata server token.
+
+use crate::keys;
+use anyhow::{anyhow, Result};
+use clap::{Args, Parser, Subcommand};
+use nostr_db::secp256k1::{rand::thread_rng, Keypair, SECP256K1};
+use nostr_extensions::gcp;
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+/// key options
+#[derive(Debug, Clone, Parser)]
+pub struct KeyOpts {
+    #[command(subcommand)]
+    pub command: KeyCommand,
+
+    #[command(flatten)]
+    pub store: KeyStoreOpts,
+
+    /// scrypt cost (2^N rounds) of a new key file
+    #[arg(long, value_name = "N", default_value_t = 16, global = true)]
+    pub log_n: u8,
+}
+
+/// where the service key is kept
+#[derive(Debug, Clone, Args)]
+#[group(multiple = false)]
+pub struct KeyStoreOpts {
+    /// Secret Manager secret, `projects/<project>/secrets/<name>` or a name in GOOGLE_CLOUD_PROJECT
+    #[arg(long, value_name = "SECRET", global = true)]
+    pub secret: Option<String>,
+
+    /// NIP-49 encrypted key file
+    #[arg(long, value_name = "PATH", global = true)]
+    pub file: Option<PathBuf>,
+}
+
+/// key subcommands
+#[derive(Debug, Clone, Subcommand)]
+pub enum KeyCommand {
+    /// Create the service keypair and print its npub
+    Generate {
+        /// Replace an existing key
+        #[arg(long)]
+        force: bool,
+    },
+    /// Print the npub of the stored service key
+    Show {
+        /// Also print the hex secret key
+        #[arg(long)]
+        reveal: bool,
+    },
+    /// Replace the service key with a new keypair, the previous one is kept
+    Rotate,
+}
+
+/// Service key store
+enum KeyStore {
+    Secret {
+        name: String,
+    },
+    File {
+        path: PathBuf,
+        passphrase: String,
+        log_n: u8,
+    },
+}
+
+impl KeyStore {
+    fn new(opts: &KeyOpts) -> Result<Self> {
+        if let Some(secret) = &opts.store.secret {
+            let name = if secret.starts_with("projects/") {
+                secret.trim_end_matches('/').to_owned()
+            } else {
+                format!(
+                    "projects/{}/secrets/{}",
+                    crate::cleanup::firestore_project_id()?,
+                    secret
+                )
+            };
+            return Ok(Self::Secret { name });
+        }
+        let path = opts
+            .store
+            .file
+            .clone()
+            .ok_or_else(|| anyhow!("--secret or --file required"))?;
+        // a new key file gets its passphrase typed twice
+        let passphrase = keys::passphrase(!path.exists())?;
+        Ok(Self::File {
+            path,
+            passphrase,
+            log_n: opts.log_n,
+        })
+    }
+
+    /// Stored keypair with a description of where it was read
+    async fn load(&self) -> Result<Option<(Keypair, String)>> {
+        match self {
+            Self::Secret { name } => {
+                let Some(payload) =
+                    gcp::access_secret(&format!("{}/versions/latest", name)).await?
+                else {
+                    return Ok(None);
+                };
+                let secret = String::from_utf8(payload.data)?;
+                let key = Keypair::from_seckey_str(SECP256K1, secret.trim())
+                    .map_err(|_| anyhow!("secret {} does not hold a hex secret key", name))?;
+                Ok(Some((key, payload.version)))
+            }
+            Self::File {
+                path, passphrase, ..
+            } => {
+                if !path.exists() {
+                    return Ok(None);
+                }
+                let ncryptsec = fs::read_to_string(path)?;
+                let key = keys::decrypt(ncryptsec.trim(), passphrase)?;
+                Ok(Some((key, path.display().to_string())))
+            }
+        }
+    }
+
+    /// Store a keypair as the current key, returns where it was written
+    async fn store(&self, key: &Keypair) -> Result<String> {
+        match self {
+            Self::Secret { name } => {
+                gcp::add_secret_version(name, key.display_secret().to_string().as_bytes()).await
+            }
+            Self::File {
+                path,
+                passphrase,
+                log_n,
+            } => {
+                let ncryptsec = keys::encrypt(key, passphrase, *log_n)?;
+                write_private(path, &ncryptsec)?;
+                Ok(path.display().to_string())
+            }
+        }
+    }
+
+    /// Keep the current key aside before a rotation, secret versions are kept by Secret Manager
+    fn keep_previous(&self) -> Result<Option<String>> {
+        match self {
+            Self::Secret { .. } => Ok(None),
+            Self::File { path, .. } => {
+                let mut prev = path.clone().into_os_string();
+                prev.push(".prev");
+                fs::rename(path, &prev)?;
+                Ok(Some(Path::new(&prev).display().to_string()))
+            }
+        }
+    }
+}
+
+/// Write a file readable by the owner only
+fn write_private(path: &Path, content: &str) -> Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)?;
+    writeln!(file, "{}", content)?;
+    Ok(())
+}
+
+fn print_key(label: &str, key: &Keypair) -> Result<()> {
+    println!("{}npub: {}", label, keys::npub(key)?);
+    println!("{}pubkey: {}", label, key.x_only_public_key().0);
+    Ok(())
+}
+
+/// Run a key subcommand
+pub async fn run_key(opts: KeyOpts) -> Result<()> {
+    let store = KeyStore::new(&opts)?;
+    match opts.command {
+        KeyCommand::Generate { force } => {
+            if !force {
+                if let Some((current, source)) = store.load().await? {
+                    return Err(anyhow!(
+                        "{} already holds the service key {}, use rotate or --force",
+                        source,
+                        keys::npub(&current)?
+                    ));
+                }
+            }
+            let key = Keypair::new(SECP256K1, &mut thread_rng());
+            let stored = store.store(&key).await?;
+            eprintln!("stored in {}", stored);
+            print_key("", &key)?;
+        }
+        KeyCommand::Show { reveal } => {
+            let (key, source) = store
+                .load()
+                .await?
+                .ok_or_else(|| anyhow!("no service key stored"))?;
+            eprintln!("read from {}", source);
+            print_key("", &key)?;
+            if reveal {
+                println!("secret: {}", key.display_secret());
+            }
+        }
+        KeyCommand::Rotate => {
+            let (previous, source) = store
+                .load()
+                .await?
+                .ok_or_else(|| anyhow!("no service key stored, use generate"))?;
+            if let Some(kept) = store.keep_previous()? {
+                eprintln!("previous key kept in {}", kept);
+            } else {
+                eprintln!("previous key stays in {}", source);
+            }
+            let key = Keypair::new(SECP256K1, &mut thread_rng());
+            let stored = store.store(&key).await?;
+            eprintln!(
+                "stored in {}, relays read it on restart or on their next secret:// refresh",
+                stored
+            );
+            print_key("previous ", &previous)?;
+            print_key("", &key)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,93 @@
+//! LMDB snapshot command
+//!
+//! Takes, lists and restores the database snapshots of `[extensions.snapshot]`
+//! in the relay config. `create` works next to a running relay, the copy is
+//! consistent while it writes. `restore` writes into a directory without a
+//! database, start the relay on it afterwards.
+
+use anyhow::Result;
+use clap::{Parser, Subcommand};
+use nostr_db::Db;
+use nostr_extensions::snapshot::{self, SnapshotSetting, Snapshotter};
+use nostr_relay::Setting;
+use std::{path::PathBuf, sync::Arc};
+
+/// snapshot options
+#[derive(Debug, Clone, Parser)]
+pub struct SnapshotOpts {
+    /// Nostr relay config path
+    #[arg(
+        short = 'c',
+        value_name = "PATH",
+        default_value = "./config/rnostr.toml"
+    )]
+    pub config: PathBuf,
+
+    #[command(subcommand)]
+    pub command: SnapshotCommand,
+}
+
+/// snapshot subcommands
+#[derive(Debug, Clone, Subcommand)]
+pub enum SnapshotCommand {
+    /// Take a snapshot now and upload it
+    Create,
+    /// List the snapshots in the bucket
+    List,
+    /// Download a snapshot and write it as a database
+    Restore {
+        /// Snapshot name, the latest by default
+        name: Option<String>,
+        /// Directory to restore into, defaults to the events path of the config
+        #[arg(long, value_name = "PATH")]
+        path: Option<PathBuf>,
+    },
+}
+
+/// Run a snapshot subcommand
+pub async fn run_snapshot(opts: SnapshotOpts) -> Result<()> {
+    let setting = Setting::read(&opts.config, Some("RNOSTR".to_owned()))?;
+    let snapshot_setting: SnapshotSetting = setting.try_parse_extension("snapshot")?;
+    let events_path = setting.data.path.join("events");
+    match opts.command {
+        SnapshotCommand::Create => {
+            let db = Db::open_with(&events_path, &setting.data.db_options())?;
+            let work_dir = snapshot_setting.work_dir(&setting.data.path);
+            let snapshotter = Snapshotter::new(Arc::new(db), work_dir, snapshot_setting)?;
+            let meta = snapshotter.run_once().await?;
+            println!(
+                "uploaded {}: {} events, {} bytes, {} compressed",
+                meta.name, meta.events, meta.size, meta.compressed_size
+            );
+        }
+        SnapshotCommand::List => {
+            let manifest = snapshot::list(&snapshot_setting).await?;
+            if manifest.snapshots.is_empty() {
+                println!("no snapshots");
+            }
+            for meta in &manifest.snapshots {
+                println!(
+                    "{}  {}  {} events  {} bytes  {} compressed  {}",
+                    meta.name,
+                    meta.created_at.to_rfc3339(),
+                    meta.events,
+                    meta.size,
+                    meta.compressed_size,
+                    meta.version
+                );
+            }
+        }
+        SnapshotCommand::Restore { name, path } => {
+            let path = path.unwrap_or(events_path);
+            let meta = snapshot::restore(&snapshot_setting, name.as_deref(), &path).await?;
+            println!(
+                "restored {} ({} events, taken {}) into {}",
+                meta.name,
+                meta.events,
+                meta.created_at.to_rfc3339(),
+                path.display()
+            );
+        }
+    }
+    Ok(())
+}
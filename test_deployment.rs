@@ -1,5 +1,5 @@
 //! Integration test for MLS Gateway Extension
-//! 
+//!
 //! This test validates:
 //! - WebSocket connectivity
 //! - Nostr protocol compliance
@@ -47,41 +47,41 @@ async fn main() -> Result<()> {
 
 async fn test_websocket_connection(relay_url: &str) -> Result<()> {
     println!("\n🔌 Testing WebSocket connection...");
-    
+
     let url = Url::parse(relay_url)?;
     let (ws_stream, _) = connect_async(url).await?;
-    
+
     println!("✅ WebSocket connection established");
     Ok(())
 }
 
 async fn test_nostr_protocol(relay_url: &str) -> Result<()> {
     println!("\n📝 Testing Nostr protocol compliance...");
-    
+
     let url = Url::parse(relay_url)?;
     let (mut ws_stream, _) = connect_async(url).await?;
-    
+
     // Test REQ message
     let req_msg = json!(["REQ", "test-sub", {"kinds": [445, 446], "limit": 10}]);
     let msg = Message::Text(req_msg.to_string());
-    
+
     use futures_util::SinkExt;
     ws_stream.send(msg).await?;
-    
+
     // Test CLOSE message
     let close_msg = json!(["CLOSE", "test-sub"]);
     let msg = Message::Text(close_msg.to_string());
     ws_stream.send(msg).await?;
-    
+
     println!("✅ Nostr protocol messages sent successfully");
     Ok(())
 }
 
 async fn test_api_endpoints(api_url: &str) -> Result<()> {
     println!("\n🌐 Testing REST API endpoints...");
-    
+
     let client = Client::new();
-    
+
     // Test health endpoint
     let health_url = format!("{}/api/v1/health", api_url);
     match client.get(&health_url).send().await {
@@ -96,7 +96,7 @@ async fn test_api_endpoints(api_url: &str) -> Result<()> {
             println!("⚠️  Health endpoint not accessible (relay may not be running)");
         }
     }
-    
+
     // Test groups endpoint
     let groups_url = format!("{}/api/v1/groups", api_url);
     match client.get(&groups_url).send().await {
@@ -111,7 +111,7 @@ async fn test_api_endpoints(api_url: &str) -> Result<()> {
             println!("⚠️  Groups endpoint not accessible (relay may not be running)");
         }
     }
-    
+
     println!("✅ API endpoint tests completed");
     Ok(())
 }
@@ -156,7 +156,8 @@ async fn test_group_creation(relay_url: &str) -> Result<()> {
     // Attempt to read an OK notice back from the relay (best-effort)
     // Server typically replies with ["OK", "<event_id>", true/false, "<message>"]
     // Give it up to 2 seconds.
-    let maybe_resp = actix_rt::time::timeout(std::time::Duration::from_secs(2), ws_stream.next()).await;
+    let maybe_resp =
+        actix_rt::time::timeout(std::time::Duration::from_secs(2), ws_stream.next()).await;
     match maybe_resp {
         Ok(Some(Ok(Message::Text(txt)))) => {
             println!("✅ Bootstrap event sent. Relay response: {}", txt);
@@ -181,10 +182,10 @@ async fn test_group_creation(relay_url: &str) -> Result<()> {
 
 async fn test_mls_message_processing(relay_url: &str) -> Result<()> {
     println!("\n🔐 Testing MLS message processing...");
-    
+
     let url = Url::parse(relay_url)?;
     let (mut ws_stream, _) = connect_async(url).await?;
-    
+
     // Create a mock MLS group message (kind 445)
     let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
     let mock_event = json!({
@@ -201,13 +202,13 @@ async fn test_mls_message_processing(relay_url: &str) -> Result<()> {
         ],
         "sig": "mock-signature"
     });
-    
+
     let event_msg = json!(["EVENT", mock_event]);
     let msg = Message::Text(event_msg.to_string());
-    
+
     use futures_util::SinkExt;
     ws_stream.send(msg).await?;
-    
+
     // Create a mock Noise DM (kind 446)
     let mock_dm = json!({
         "id": "mock-event-id-446",
@@ -220,11 +221,11 @@ async fn test_mls_message_processing(relay_url: &str) -> Result<()> {
         ],
         "sig": "mock-signature-dm"
     });
-    
+
     let dm_msg = json!(["EVENT", mock_dm]);
     let msg = Message::Text(dm_msg.to_string());
     ws_stream.send(msg).await?;
-    
+
     println!("✅ MLS messages sent for processing");
     Ok(())
 }
@@ -241,7 +242,7 @@ mod tests {
         // 2. Simulate server restart
         // 3. Query for missed messages via API
         // 4. Verify the message was retrieved
-        
+
         println!("📦 Message archive functionality test would go here");
     }
 
@@ -249,12 +250,13 @@ mod tests {
     async fn test_nip42_authentication() {
         // NIP-42 authentication is handled by rust-nostr-relay core
         // Our MLS Gateway Extension focuses on kinds 445/446 processing
-        // Client attestation is handled by the REST loxation server
-        
+        // Client attestation is handled by the REST loxation server, the
+        // attestation extension verifies the token it issues
+
         println!("🔐 NIP-42 authentication is handled by the core relay");
         println!("📱 Client attestation handled by REST loxation server");
         println!("🔒 MLS provides end-to-end encryption at protocol level");
-        println!("🎯 Future: Can restrict pubkeys to attested clients");
+        println!("🎯 Pubkeys can be restricted to attested clients with [attestation]");
     }
 
     #[tokio::test]
@@ -263,7 +265,7 @@ mod tests {
         // 1. Send messages rapidly
         // 2. Verify rate limiting kicks in
         // 3. Verify rate limiting resets after time window
-        
+
         println!("⏱️  Rate limiting test would go here");
     }
 }